@@ -0,0 +1,160 @@
+//! Micro-benchmarks for the per-request hot path: path rewriting, route lookup and
+//! header copying. Run with `cargo bench --bench hot_path`; heap allocations per call
+//! are printed first.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use rustproxy::normalize::{self, PathNormalization};
+use rustproxy::{DatabaseManager, Mapping, ProxyServer};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// System allocator that counts allocations
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Average heap allocations of one `f()` call.
+fn allocations<R>(mut f: impl FnMut() -> R) -> f64 {
+    const CALLS: usize = 1000;
+    black_box(f());
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..CALLS {
+        black_box(f());
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / CALLS as f64
+}
+
+fn mapping(front_uri: &str, back_uri: &str) -> Mapping {
+    Mapping {
+        id: "bench".to_string(),
+        domain: "example.com".to_string(),
+        front_uri: front_uri.to_string(),
+        back_port: 3000,
+        back_uri: back_uri.to_string(),
+        ..Default::default()
+    }
+}
+
+fn bench_rewrite_path(c: &mut Criterion) {
+    let root = mapping("", "");
+    let prefixed = mapping("api/v1", "v2/internal");
+    let mut group = c.benchmark_group("rewrite_path");
+    group.bench_function("passthrough", |b| {
+        b.iter(|| ProxyServer::rewrite_path(black_box("/static/app.js"), black_box(&root)))
+    });
+    group.bench_function("front_to_back", |b| {
+        b.iter(|| ProxyServer::rewrite_path(black_box("/api/v1/users/42/orders"), black_box(&prefixed)))
+    });
+    group.bench_function("build_backend_url", |b| {
+        b.iter(|| ProxyServer::build_backend_url(black_box(&prefixed), black_box("/api/v1/users/42"), black_box(Some("page=2"))))
+    });
+    group.bench_function("normalize_path", |b| {
+        let opts = PathNormalization::default();
+        b.iter(|| normalize::normalize_path(black_box("/api//v1/./users/%7Ejane/../42"), &opts).map(|p| p.len()))
+    });
+    group.finish();
+}
+
+fn report_allocations(_: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = routes(&dir);
+    let prefixed = mapping("api/v1", "v2/internal");
+    let headers = request_headers();
+    let report = [
+        ("rewrite_path/front_to_back", allocations(|| ProxyServer::rewrite_path("/api/v1/users/42/orders", &prefixed))),
+        ("rewrite_path/build_backend_url", allocations(|| ProxyServer::build_backend_url(&prefixed, "/api/v1/users/42", Some("page=2")))),
+        ("route_lookup/longest_prefix", allocations(|| db.find_mapping("site150.example.com", "/api/v2/users").unwrap())),
+        ("route_lookup/miss", allocations(|| db.find_mapping("unknown.test", "/").unwrap())),
+        ("copy_forwarded_headers", allocations(|| {
+            let mut to = HeaderMap::with_capacity(headers.len() + 4);
+            ProxyServer::copy_forwarded_headers(&headers, &mut to);
+            to
+        })),
+    ];
+    for (name, n) in report {
+        println!("{:<40} {:>6.1} allocations/call", name, n);
+    }
+}
+
+/// 600 mappings over 200 domains plus a wildcard
+fn routes(dir: &tempfile::TempDir) -> DatabaseManager {
+    let db = DatabaseManager::new(dir.path().join("bench.db")).unwrap();
+    for i in 0..200 {
+        let domain = format!("site{}.example.com", i);
+        db.add_mapping(&domain, "", 3000, "", None, None, None, None, None).unwrap();
+        db.add_mapping(&domain, "api", 3001, "", None, None, None, None, None).unwrap();
+        db.add_mapping(&domain, "api/v2", 3002, "", None, None, None, None, None).unwrap();
+    }
+    db.add_mapping("*.wild.example.com", "", 3003, "", None, None, None, None, None).unwrap();
+    db
+}
+
+fn bench_route_lookup(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = routes(&dir);
+    let mut group = c.benchmark_group("route_lookup");
+    group.bench_function("longest_prefix", |b| {
+        b.iter(|| db.find_mapping(black_box("site150.example.com"), black_box("/api/v2/users")).unwrap())
+    });
+    group.bench_function("wildcard", |b| {
+        b.iter(|| db.find_mapping(black_box("tenant.wild.example.com"), black_box("/")).unwrap())
+    });
+    group.bench_function("miss", |b| {
+        b.iter(|| db.find_mapping(black_box("unknown.test"), black_box("/")).unwrap())
+    });
+    group.finish();
+}
+
+fn request_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in [
+        ("host", "example.com"),
+        ("user-agent", "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko)"),
+        ("accept", "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+        ("accept-language", "en-US,en;q=0.5"),
+        ("accept-encoding", "gzip, deflate, br"),
+        ("connection", "keep-alive"),
+        ("cookie", "session=0123456789abcdef; theme=dark; uid=42"),
+        ("cache-control", "no-cache"),
+        ("content-length", "0"),
+        ("x-request-id", "7f1c9a1e-3a44-4b8e-9a51-0f1d2c3b4a59"),
+        ("referer", "https://example.com/dashboard"),
+    ] {
+        headers.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+    }
+    headers
+}
+
+fn bench_header_copy(c: &mut Criterion) {
+    let headers = request_headers();
+    c.bench_function("copy_forwarded_headers", |b| {
+        b.iter(|| {
+            let mut to = HeaderMap::with_capacity(headers.len() + 4);
+            ProxyServer::copy_forwarded_headers(black_box(&headers), &mut to);
+            to
+        })
+    });
+}
+
+criterion_group!(benches, report_allocations, bench_rewrite_path, bench_route_lookup, bench_header_copy);
+criterion_main!(benches);
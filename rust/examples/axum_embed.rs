@@ -143,5 +143,5 @@ async fn main() -> Result<()> {
     println!("  GET /hello   → Axum route");
     println!("  GET /status  → Axum route");
 
-    server.run().await
+    server.run().await?.wait().await
 }
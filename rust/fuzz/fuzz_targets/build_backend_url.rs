@@ -0,0 +1,34 @@
+//! `ProxyServer::build_backend_url` on arbitrary mappings, paths and queries.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rustproxy::{Mapping, ProxyServer};
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    backend: Option<String>,
+    back_port: u16,
+    front_uri: String,
+    back_uri: String,
+    path: String,
+    query: Option<String>,
+}
+
+fuzz_target!(|input: Input| {
+    let mapping = Mapping {
+        backend: input.backend,
+        back_port: input.back_port,
+        front_uri: input.front_uri,
+        back_uri: input.back_uri,
+        ..Default::default()
+    };
+    let url = ProxyServer::build_backend_url(&mapping, &input.path, input.query.as_deref());
+    let origin = format!("{}:{}/", mapping.backend.as_deref().unwrap_or("http://localhost"), mapping.back_port);
+    assert!(url.starts_with(&origin), "{:?} does not start with {:?}", url, origin);
+    if let Some(query) = &input.query {
+        assert!(url.ends_with(&format!("?{}", query)));
+    }
+    // Whatever the input, the proxy parses the result rather than trusting it
+    let _ = url.parse::<url::Url>();
+});
@@ -0,0 +1,21 @@
+//! Host header parsing and host/domain normalization on arbitrary input.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustproxy::host::{normalize_domain, normalize_host, parse_authority};
+
+fuzz_target!(|data: &str| {
+    if let Some(authority) = parse_authority(data) {
+        assert!(!authority.host.is_empty());
+        assert!(data.contains(authority.host));
+        assert!(!authority.host.contains(['@', '/', ' ']));
+        if let Some(host) = normalize_host(authority.host) {
+            assert!(host.is_ascii(), "{:?} -> {:?}", authority.host, host);
+        }
+    }
+    for normalized in [normalize_host(data), normalize_domain(data)].into_iter().flatten() {
+        assert!(!normalized.is_empty());
+        assert!(normalized.is_ascii(), "{:?} -> {:?}", data, normalized);
+        assert!(!normalized.bytes().any(|b| b.is_ascii_uppercase()), "{:?} -> {:?}", data, normalized);
+    }
+});
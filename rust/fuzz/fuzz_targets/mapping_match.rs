@@ -0,0 +1,51 @@
+//! Mapping lookup (`DatabaseManager::find_mapping` and the prefix matching behind it)
+//! with arbitrary hosts, paths and mapping prefixes.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rustproxy::normalize::{decoded_octets, match_prefix};
+use rustproxy::DatabaseManager;
+use std::sync::OnceLock;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    host: String,
+    path: String,
+    prefix: String,
+}
+
+/// A small routing table shared by all runs: exact, prefixed, wildcard and catch-all
+fn db() -> &'static DatabaseManager {
+    static DB: OnceLock<(tempfile::TempDir, DatabaseManager)> = OnceLock::new();
+    &DB.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("fuzz.db")).unwrap();
+        for (domain, front_uri) in [
+            ("example.com", ""),
+            ("example.com", "api"),
+            ("example.com", "api/v2"),
+            ("example.com", "caf%C3%A9"),
+            ("*.example.com", ""),
+            ("*.example.com", "static"),
+            ("*", "catch"),
+        ] {
+            db.add_mapping(domain, front_uri, 3000, "", None, None, None, None, None).unwrap();
+        }
+        (dir, db)
+    })
+    .1
+}
+
+fuzz_target!(|input: Input| {
+    if let Some(end) = match_prefix(&input.path, &input.prefix) {
+        assert!(input.path.is_char_boundary(end));
+        assert_eq!(decoded_octets(&input.path[..end]), decoded_octets(&input.prefix));
+    }
+
+    if let Ok(Some(m)) = db().find_mapping(&input.host, &input.path) {
+        if !m.front_uri.is_empty() {
+            assert!(match_prefix(&input.path, &format!("/{}", m.front_uri)).is_some());
+        }
+    }
+});
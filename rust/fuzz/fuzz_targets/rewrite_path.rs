@@ -0,0 +1,28 @@
+//! `ProxyServer::rewrite_path` on arbitrary request paths and mapping prefixes.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rustproxy::{Mapping, ProxyServer};
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    front_uri: String,
+    back_uri: String,
+    path: String,
+}
+
+fuzz_target!(|input: Input| {
+    let mapping = Mapping {
+        front_uri: input.front_uri,
+        back_uri: input.back_uri,
+        ..Default::default()
+    };
+    let rewritten = ProxyServer::rewrite_path(&input.path, &mapping);
+    assert!(rewritten.starts_with('/'), "{:?} -> {:?}", input.path, rewritten);
+    assert!(!rewritten.contains("//"), "{:?} -> {:?}", input.path, rewritten);
+    if mapping.back_uri.is_empty() && mapping.front_uri.is_empty() {
+        // Passthrough only collapses slashes
+        assert_eq!(rewritten.replace('/', ""), input.path.replace('/', ""));
+    }
+});
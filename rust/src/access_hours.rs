@@ -0,0 +1,210 @@
+//! Access hours
+//! A mapping's `access_hours` limits when its route is reachable, as a cron expression
+//! whose matching minutes are the open ones: `* 9-17 * * mon-fri` is weekdays from 09:00
+//! to 17:59. Outside them requests get `403` (or the `status=` given) without reaching the
+//! backend, with the `page=` file as body if one is set. Times are UTC unless `tz=` gives
+//! an offset, which is fixed: daylight saving time is not followed
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Offset, Timelike, Utc};
+use hyper::StatusCode;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Furthest ahead the next opening is looked for
+const LOOKAHEAD_DAYS: i64 = 8;
+
+/// Cron fields: name, lowest and highest value, value names
+const FIELDS: [(&str, u32, u32, &[&str]); 5] = [
+    ("minute", 0, 59, &[]),
+    ("hour", 0, 23, &[]),
+    ("day of month", 1, 31, &[]),
+    ("month", 1, 12, &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"]),
+    ("day of week", 0, 7, &["sun", "mon", "tue", "wed", "thu", "fri", "sat"]),
+];
+
+/// When a mapping is reachable, and what it answers otherwise
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessHours {
+    /// The five cron fields as given (lowercased)
+    fields: Vec<String>,
+    /// Matching values per field, as bits
+    sets: [u64; 5],
+    offset: FixedOffset,
+    /// Status outside the open hours
+    pub status: StatusCode,
+    /// File served as the body outside the open hours
+    pub page: Option<PathBuf>,
+}
+
+/// `1`, `mon`
+fn parse_value(s: &str, field: usize) -> Option<u32> {
+    let (_, lo, hi, names) = FIELDS[field];
+    let n = match names.iter().position(|n| *n == s) {
+        Some(i) => i as u32 + if field == 3 { 1 } else { 0 },
+        None => s.parse().ok()?,
+    };
+    (lo..=hi).contains(&n).then_some(n)
+}
+
+/// `*`, `5`, `1-5`, `*/15`, `8-18/2` and comma lists of them, as bits
+fn parse_field(s: &str, field: usize) -> Option<u64> {
+    let (_, lo, hi, _) = FIELDS[field];
+    let mut bits = 0u64;
+    for item in s.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&s| s > 0)?),
+            None => (item, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (lo, hi),
+            Some((from, to)) => (parse_value(from, field)?, parse_value(to, field)?),
+            None => {
+                let n = parse_value(range, field)?;
+                (n, if step > 1 { hi } else { n })
+            }
+        };
+        if from > to {
+            return None;
+        }
+        for n in (from..=to).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    // Sunday is both 0 and 7
+    if field == 4 && bits & (1 << 7) != 0 {
+        bits |= 1;
+    }
+    Some(bits)
+}
+
+/// `+02:00`, `-0530` or `UTC`
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    if s.eq_ignore_ascii_case("utc") || s == "Z" {
+        return Some(Utc.fix());
+    }
+    let sign = match s.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (h, m): (i32, i32) = (digits[..2].parse().ok()?, digits[2..].parse().ok()?);
+    FixedOffset::east_opt(sign * (h * 3600 + m * 60))
+}
+
+impl FromStr for AccessHours {
+    type Err = String;
+
+    /// `MIN HOUR DAY MONTH WEEKDAY [tz=+01:00] [status=503] [page=/path/closed.html]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        if words.len() < 5 {
+            return Err("access hours need five cron fields, e.g. '* 9-17 * * mon-fri'".to_string());
+        }
+        let mut hours = Self {
+            fields: words[..5].iter().map(|w| w.to_ascii_lowercase()).collect(),
+            sets: [0; 5],
+            offset: Utc.fix(),
+            status: StatusCode::FORBIDDEN,
+            page: None,
+        };
+        for (i, field) in hours.fields.iter().enumerate() {
+            hours.sets[i] = parse_field(field, i).ok_or_else(|| format!("invalid {} '{}'", FIELDS[i].0, field))?;
+        }
+        for word in &words[5..] {
+            let (name, value) = word.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", word))?;
+            match name {
+                "tz" => hours.offset = parse_offset(value).ok_or_else(|| format!("'tz' needs a UTC offset like +01:00, got '{}'", value))?,
+                "status" => {
+                    hours.status = value.parse::<u16>().ok().and_then(|s| StatusCode::from_u16(s).ok())
+                        .filter(|s| s.as_u16() >= 400)
+                        .ok_or_else(|| format!("'status' needs an error status (4xx or 5xx), got '{}'", value))?;
+                }
+                "page" if !value.is_empty() => hours.page = Some(PathBuf::from(value)),
+                other => return Err(format!("unknown access hours option '{}' (tz, status, page)", other)),
+            }
+        }
+        Ok(hours)
+    }
+}
+
+impl fmt::Display for AccessHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.fields.join(" "))?;
+        if self.offset.local_minus_utc() != 0 {
+            write!(f, " tz={}", self.offset)?;
+        }
+        if self.status != StatusCode::FORBIDDEN {
+            write!(f, " status={}", self.status.as_u16())?;
+        }
+        if let Some(page) = &self.page {
+            write!(f, " page={}", page.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl AccessHours {
+    /// Whether the route is reachable at `now`. As in cron, a day matches when either
+    /// day field does if both are restricted.
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let t = now.with_timezone(&self.offset);
+        let has = |field: usize, n: u32| self.sets[field] & (1 << n) != 0;
+        let any = |field: usize| self.fields[field] == "*";
+        let day = match (any(2), any(4)) {
+            (false, false) => has(2, t.day()) || has(4, t.weekday().num_days_from_sunday()),
+            _ => has(2, t.day()) && has(4, t.weekday().num_days_from_sunday()),
+        };
+        day && has(0, t.minute()) && has(1, t.hour()) && has(3, t.month())
+    }
+
+    /// Start of the next open minute after `now`, if there is one within a week or so.
+    pub fn next_open(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = now.with_second(0)?.with_nanosecond(0)?;
+        (1..=LOOKAHEAD_DAYS * 24 * 60)
+            .map(|m| start + Duration::minutes(m))
+            .find(|&t| self.is_open(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_access_hours() {
+        // 2026-03-02 is a Monday
+        let office: AccessHours = "* 9-17 * * MON-FRI tz=+01:00".parse().unwrap();
+        assert_eq!(office.to_string(), "* 9-17 * * mon-fri tz=+01:00");
+        assert_eq!(office.to_string().parse::<AccessHours>().unwrap(), office);
+        assert_eq!(office.status, StatusCode::FORBIDDEN);
+        assert!(office.is_open(at("2026-03-02T08:00:00Z")));
+        assert!(office.is_open(at("2026-03-02T16:59:59Z")));
+        assert!(!office.is_open(at("2026-03-02T17:00:00Z")));
+        assert!(!office.is_open(at("2026-03-07T10:00:00Z")));
+        assert_eq!(office.next_open(at("2026-03-06T17:30:00Z")), Some(at("2026-03-09T08:00:00Z")));
+
+        // Either day field matches when both are set; Sunday is 0 and 7
+        let days: AccessHours = "*/30 0 1 * 7 status=503 page=/srv/closed.html".parse().unwrap();
+        assert_eq!(days.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(days.is_open(at("2026-03-01T00:30:00Z")));
+        assert!(days.is_open(at("2026-03-08T00:00:00Z")));
+        assert!(!days.is_open(at("2026-03-08T00:15:00Z")));
+        assert!(!days.is_open(at("2026-03-09T00:00:00Z")));
+
+        assert!("* 9-17 * *".parse::<AccessHours>().is_err());
+        assert!("* 25 * * *".parse::<AccessHours>().is_err());
+        assert!("* 17-9 * * *".parse::<AccessHours>().is_err());
+        assert!("* * * * * status=200".parse::<AccessHours>().is_err());
+        assert!("* * * * * tz=Europe/Paris".parse::<AccessHours>().is_err());
+        assert_eq!("0 0 30 2 *".parse::<AccessHours>().unwrap().next_open(Utc::now()), None);
+    }
+}
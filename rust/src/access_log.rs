@@ -0,0 +1,319 @@
+//! Access log
+//! One `key=value` line per request, emitted through `tracing` under the
+//! `rustproxy::access` target, at a per-mapping level and sampled per status class
+
+use crate::analytics;
+use crate::events;
+use crate::tls_info::TlsInfo;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error, info, trace, warn};
+
+/// Level access lines are written at; `Off` writes none
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for AccessLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err(format!("unknown log level '{}' (off, error, warn, info, debug, trace)", s)),
+        }
+    }
+}
+
+/// Share of requests logged per status class, e.g. `2xx=1%,3xx=0.1,*=1`.
+/// Classes not listed fall back to `*`, which defaults to everything.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampling([f64; 5]);
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self([1.0; 5])
+    }
+}
+
+impl Sampling {
+    /// Share of responses with `status` that are logged (0.0–1.0).
+    pub fn rate(&self, status: u16) -> f64 {
+        self.0[(status / 100).clamp(1, 5) as usize - 1]
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (class, rate) = part.split_once('=').ok_or_else(|| format!("expected class=rate, got '{}'", part))?;
+            let rate = rate.trim();
+            let rate = match rate.strip_suffix('%') {
+                Some(pct) => pct.trim().parse::<f64>().map(|p| p / 100.0),
+                None => rate.parse::<f64>(),
+            }
+            .ok()
+            .filter(|r| (0.0..=1.0).contains(r))
+            .ok_or_else(|| format!("rate must be 0..1 or 0%..100%, got '{}'", rate))?;
+            let class = match class.trim().to_ascii_lowercase().as_str() {
+                "*" => None,
+                c => match c.as_bytes() {
+                    [d @ b'1'..=b'5', b'x', b'x'] => Some((d - b'1') as usize),
+                    _ => return Err(format!("unknown status class '{}' (1xx..5xx or *)", class)),
+                },
+            };
+            rules.push((class, rate));
+        }
+        let mut rates = [1.0; 5];
+        // `*` first, so specific classes win wherever they are listed
+        for (_, rate) in rules.iter().filter(|(c, _)| c.is_none()) {
+            rates = [*rate; 5];
+        }
+        for (class, rate) in &rules {
+            if let Some(i) = class {
+                rates[*i] = *rate;
+            }
+        }
+        Ok(Self(rates))
+    }
+}
+
+/// How access lines of a request are written
+#[derive(Debug, Clone, Default)]
+pub struct LogPolicy {
+    pub level: AccessLevel,
+    pub sampling: Sampling,
+}
+
+impl LogPolicy {
+    /// This policy with a mapping's stored overrides applied. Values that don't parse
+    /// (edited by hand, say) leave the default in place.
+    pub fn with_overrides(&self, level: Option<&str>, sample: Option<&str>) -> Self {
+        Self {
+            level: level.and_then(|l| l.parse().ok()).unwrap_or(self.level),
+            sampling: sample.and_then(|s| s.parse().ok()).unwrap_or(self.sampling),
+        }
+    }
+}
+
+/// Whether a request logged at `rate` is picked. Cheap and lock-free; it need not be a
+/// good random source, only spread evenly.
+fn sampled(rate: f64) -> bool {
+    static STATE: AtomicU64 = AtomicU64::new(0x9E37_79B9_7F4A_7C15);
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+    // splitmix64
+    let mut z = STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    ((z >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+/// Per-request access log record. Created when the request arrives, filled in while it is
+/// processed, and written once the response status is known.
+pub struct AccessLog {
+    started: Instant,
+    method: String,
+    host: String,
+    path: String,
+    client: String,
+    /// Matched mapping ID, if any
+    pub mapping_id: Option<String>,
+    /// `<experiment>=<variant>` for requests in an A/B experiment
+    pub experiment: Option<String>,
+    /// ID of the API key that authorized the request
+    pub api_key: Option<String>,
+    /// Level and sampling; the server default until a mapping with overrides matches
+    pub policy: LogPolicy,
+    /// What the connection negotiated, for requests over TLS
+    pub tls: Option<Arc<TlsInfo>>,
+}
+
+impl AccessLog {
+    pub fn new<T>(req: &hyper::Request<T>, remote_addr: SocketAddr) -> Self {
+        Self {
+            started: Instant::now(),
+            method: req.method().to_string(),
+            host: req.headers()
+                .get(hyper::header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("-")
+                .to_string(),
+            path: req.uri().path().to_string(),
+            client: remote_addr.ip().to_string(),
+            mapping_id: None,
+            experiment: None,
+            api_key: None,
+            policy: LogPolicy::default(),
+            tls: None,
+        }
+    }
+
+    /// Write the record for a finished request, unless its level is off or it is sampled out.
+    /// Sampled lines carry `sample_rate=` so counts can be scaled back up.
+    pub fn finish(&self, status: u16) {
+        let rate = self.policy.sampling.rate(status);
+        if self.policy.level == AccessLevel::Off || !sampled(rate) {
+            return;
+        }
+        let sample = if rate < 1.0 { format!(" sample_rate={}", rate) } else { String::new() };
+        let tls = match &self.tls {
+            Some(t) => format!(
+                " tls={} cipher={} sni={} client_dn={}",
+                t.version,
+                t.cipher,
+                t.sni.as_deref().unwrap_or("-"),
+                t.client_dn.as_deref().map_or("-".to_string(), quote),
+            ),
+            None => String::new(),
+        };
+        macro_rules! emit {
+            ($level:ident) => {
+                $level!(
+                    target: "rustproxy::access",
+                    "access method={} host={} path={} status={} duration_ms={} client={} mapping={} experiment={} api_key={}{}{}",
+                    self.method,
+                    self.host,
+                    quote(&self.path),
+                    status,
+                    self.started.elapsed().as_millis(),
+                    self.client,
+                    self.mapping_id.as_deref().unwrap_or("-"),
+                    self.experiment.as_deref().unwrap_or("-"),
+                    self.api_key.as_deref().unwrap_or("-"),
+                    tls,
+                    sample,
+                )
+            };
+        }
+        match self.policy.level {
+            AccessLevel::Off => {}
+            AccessLevel::Error => emit!(error),
+            AccessLevel::Warn => emit!(warn),
+            AccessLevel::Info => emit!(info),
+            AccessLevel::Debug => emit!(debug),
+            AccessLevel::Trace => emit!(trace),
+        }
+    }
+}
+
+impl AccessLog {
+    /// The requested host without its port, `-` if there is none.
+    fn domain(&self) -> String {
+        crate::host::parse_authority(&self.host)
+            .and_then(|a| crate::host::normalize_host(a.host).map(|h| h.into_owned()))
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    /// The record as a row for the analytics sink, whatever the level and sampling.
+    pub fn analytics_row(&self, status: u16, bytes_in: u64, bytes_out: u64) -> analytics::Row {
+        analytics::Row {
+            ts_ms: chrono::Utc::now().timestamp_millis(),
+            host: self.domain(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            status,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            bytes_in,
+            bytes_out,
+            client: self.client.clone(),
+            mapping_id: self.mapping_id.clone(),
+        }
+    }
+
+    /// The record for subscribers following the traffic; `backend` is the one that answered.
+    pub fn traffic(&self, status: u16, backend: Option<String>) -> events::Request {
+        events::Request {
+            time: chrono::Utc::now().to_rfc3339(),
+            domain: self.domain(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            status,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            backend,
+            mapping_id: self.mapping_id.clone(),
+            client: self.client.clone(),
+        }
+    }
+}
+
+/// Quote values that would break `key=value` parsing.
+fn quote(v: &str) -> String {
+    if v.is_empty() || v.contains([' ', '"', '=']) {
+        format!("{:?}", v)
+    } else {
+        v.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/plain/path"), "/plain/path");
+        assert_eq!(quote("/a b"), "\"/a b\"");
+        assert_eq!(quote(""), "\"\"");
+    }
+
+    #[test]
+    fn test_sampling_parse() {
+        let s: Sampling = "2xx=1%, 3xx=0.5".parse().unwrap();
+        assert_eq!(s.rate(200), 0.01);
+        assert_eq!(s.rate(304), 0.5);
+        assert_eq!(s.rate(503), 1.0);
+
+        let s: Sampling = "5xx=1,*=0.1".parse().unwrap();
+        assert_eq!(s.rate(404), 0.1);
+        assert_eq!(s.rate(500), 1.0);
+
+        assert_eq!("".parse::<Sampling>().unwrap(), Sampling::default());
+        assert!("2xx".parse::<Sampling>().is_err());
+        assert!("6xx=1".parse::<Sampling>().is_err());
+        assert!("2xx=150%".parse::<Sampling>().is_err());
+    }
+
+    #[test]
+    fn test_sampled_rate_is_roughly_honoured() {
+        assert!(sampled(1.0));
+        assert!(!sampled(0.0));
+        let hits = (0..10_000).filter(|_| sampled(0.1)).count();
+        assert!((700..1300).contains(&hits), "{} of 10000", hits);
+    }
+
+    #[test]
+    fn test_policy_overrides() {
+        let base = LogPolicy::default();
+        let p = base.with_overrides(Some("warn"), Some("2xx=0"));
+        assert_eq!(p.level, AccessLevel::Warn);
+        assert_eq!(p.sampling.rate(200), 0.0);
+        // Broken stored values keep the default
+        let p = base.with_overrides(Some("loud"), Some("2xx="));
+        assert_eq!(p.level, AccessLevel::Info);
+        assert_eq!(p.sampling, Sampling::default());
+    }
+}
@@ -0,0 +1,809 @@
+//! Internal ACME server
+//! With `ACME_SERVER`, the proxy speaks ACME (RFC 8555) under `/_proxy/acme/` and signs
+//! certificates with its own internal CA once an http-01 challenge passes, so internal
+//! services can get certificates from it with certbot, lego, acme.sh or Caddy
+
+use crate::der::{element, elements};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine as _};
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, SecondsFormat, Utc};
+use dashmap::DashMap;
+use http_body_util::Full;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use hyper::{Method, Response, StatusCode};
+use rand::RngCore;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, RcgenError, RemoteKeyPair, SerialNumber, SignatureAlgorithm,
+};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const PREFIX: &str = "/_proxy/acme/";
+
+/// Lifetime of issued certificates
+const CERT_DAYS: i64 = 30;
+/// Lifetime of the CA certificate created on first start
+const CA_YEARS: i32 = 10;
+/// How long an order and its authorizations can be completed
+const ORDER_TTL: chrono::Duration = chrono::Duration::hours(24);
+const NONCE_TTL: Duration = Duration::from_secs(3600);
+const MAX_NONCES: usize = 10_000;
+/// Largest JWS accepted
+pub const MAX_REQUEST: usize = 64 * 1024;
+const VALIDATION_TIMEOUT: Duration = Duration::from_secs(10);
+const CA_NAME: &str = "RustProxy Internal CA";
+
+// OIDs (DER contents)
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const OID_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+
+// ── Internal CA ───────────────────────────────────────────────────────────────
+
+/// The CA certificates are signed with; `ca.crt`/`ca.key` in its directory
+pub struct InternalCa {
+    cert: Certificate,
+    pem: String,
+}
+
+impl InternalCa {
+    /// Load the CA from `dir`, creating it on first use.
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let (crt_path, key_path) = (dir.join("ca.crt"), dir.join("ca.key"));
+        if crt_path.exists() && key_path.exists() {
+            let key = KeyPair::from_pem(&fs::read_to_string(&key_path)?)
+                .with_context(|| format!("bad CA key {}", key_path.display()))?;
+            // Only the issuer name and key matter for signing
+            let cert = Certificate::from_params(Self::params(Some(key)))?;
+            return Ok(Self { cert, pem: fs::read_to_string(&crt_path)? });
+        }
+        fs::create_dir_all(dir)?;
+        let cert = Certificate::from_params(Self::params(None))?;
+        let pem = cert.serialize_pem()?;
+        fs::write(&key_path, cert.serialize_private_key_pem())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::write(&crt_path, &pem)?;
+        info!("Created internal CA in {}", dir.display());
+        Ok(Self { cert, pem })
+    }
+
+    fn params(key: Option<KeyPair>) -> CertificateParams {
+        let today = Utc::now().date_naive();
+        let mut params = CertificateParams::default();
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, CA_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+        params.not_before = rcgen::date_time_ymd(today.year(), today.month() as u8, today.day() as u8);
+        params.not_after = rcgen::date_time_ymd(today.year() + CA_YEARS, today.month() as u8, today.day().min(28) as u8);
+        params.key_pair = key;
+        params
+    }
+
+    /// The CA certificate, for clients' trust stores.
+    pub fn pem(&self) -> &str {
+        &self.pem
+    }
+
+    /// Sign a certificate for a CSR's key and names; returns the chain (leaf, then CA) as PEM.
+    fn issue(&self, csr: &Csr) -> Result<String> {
+        let today = Utc::now().date_naive();
+        let start = today.pred_opt().unwrap_or(today);
+        let end = today + chrono::Duration::days(CERT_DAYS);
+        let mut params = CertificateParams::new(csr.names.clone());
+        params.alg = csr.key.algorithm;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, csr.names[0].as_str());
+        params.not_before = rcgen::date_time_ymd(start.year(), start.month() as u8, start.day() as u8);
+        params.not_after = rcgen::date_time_ymd(end.year(), end.month() as u8, end.day() as u8);
+        let mut serial = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut serial);
+        serial[0] &= 0x7f;
+        params.serial_number = Some(SerialNumber::from_slice(&serial));
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth, ExtendedKeyUsagePurpose::ClientAuth];
+        params.key_pair = Some(KeyPair::from_remote(Box::new(csr.key.clone()))?);
+        let cert = Certificate::from_params(params)?;
+        Ok(format!("{}{}", cert.serialize_pem_with_signer(&self.cert)?, self.pem))
+    }
+}
+
+// ── CSRs ──────────────────────────────────────────────────────────────────────
+
+/// The public key of a CSR, standing in for the subject key when rcgen builds the certificate
+#[derive(Clone)]
+struct CsrKey {
+    raw: Vec<u8>,
+    algorithm: &'static SignatureAlgorithm,
+}
+
+impl RemoteKeyPair for CsrKey {
+    fn public_key(&self) -> &[u8] {
+        &self.raw
+    }
+
+    fn sign(&self, _msg: &[u8]) -> Result<Vec<u8>, RcgenError> {
+        // Certificates are signed by the CA, never by the subject
+        Err(RcgenError::RemoteKeyError)
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        self.algorithm
+    }
+}
+
+/// A parsed, signature-checked PKCS#10 request
+struct Csr {
+    /// Lowercased DNS names (SAN, else the common name), deduplicated
+    names: Vec<String>,
+    key: CsrKey,
+}
+
+impl Csr {
+    fn parse(der: &[u8]) -> Result<Self> {
+        let bad = || anyhow!("malformed CSR");
+        let request = element(der).ok_or_else(bad)?;
+        let mut parts = elements(request.contents);
+        let info = parts.next().ok_or_else(bad)?;
+        let signature_algorithm = parts.next().ok_or_else(bad)?;
+        let signature = parts.next().filter(|e| e.tag == 0x03).ok_or_else(bad)?;
+
+        let mut fields = elements(info.contents);
+        let _version = fields.next().ok_or_else(bad)?;
+        let subject = fields.next().ok_or_else(bad)?;
+        let spki = fields.next().ok_or_else(bad)?;
+        let attributes = fields.next().filter(|e| e.tag == 0xa0);
+
+        // Public key: algorithm (and curve), then the key as a BIT STRING
+        let mut key_parts = elements(spki.contents);
+        let mut algorithm = elements(key_parts.next().ok_or_else(bad)?.contents);
+        let key_type = algorithm.next().ok_or_else(bad)?.contents;
+        let curve = algorithm.next().map(|e| e.contents);
+        let raw = bit_string(key_parts.next().ok_or_else(bad)?).ok_or_else(bad)?;
+        let (rcgen_alg, verify_alg, expected_sig): (_, &'static dyn signature::VerificationAlgorithm, _) = match (key_type, curve) {
+            (OID_EC_PUBLIC_KEY, Some(OID_P256)) => (&rcgen::PKCS_ECDSA_P256_SHA256, &signature::ECDSA_P256_SHA256_ASN1, OID_ECDSA_SHA256),
+            (OID_EC_PUBLIC_KEY, Some(OID_P384)) => (&rcgen::PKCS_ECDSA_P384_SHA384, &signature::ECDSA_P384_SHA384_ASN1, OID_ECDSA_SHA384),
+            (OID_RSA, _) => (&rcgen::PKCS_RSA_SHA256, &signature::RSA_PKCS1_2048_8192_SHA256, OID_RSA_SHA256),
+            _ => bail!("unsupported CSR key type (P-256, P-384 or RSA)"),
+        };
+        let sig_oid = elements(signature_algorithm.contents).next().ok_or_else(bad)?.contents;
+        if sig_oid != expected_sig {
+            bail!("unsupported CSR signature algorithm for its key");
+        }
+        let sig = bit_string(signature).ok_or_else(bad)?;
+        UnparsedPublicKey::new(verify_alg, raw).verify(info.whole, sig)
+            .map_err(|_| anyhow!("CSR signature does not verify"))?;
+
+        let mut names = Vec::new();
+        for attribute in attributes.map(|a| elements(a.contents)).into_iter().flatten() {
+            let mut attr = elements(attribute.contents);
+            if attr.next().map(|e| e.contents) != Some(OID_EXTENSION_REQUEST) {
+                continue;
+            }
+            let values = attr.next().ok_or_else(bad)?;
+            for extensions in elements(values.contents) {
+                for extension in elements(extensions.contents) {
+                    let mut ext = elements(extension.contents);
+                    if ext.next().map(|e| e.contents) != Some(OID_SUBJECT_ALT_NAME) {
+                        continue;
+                    }
+                    let value = ext.find(|e| e.tag == 0x04).ok_or_else(bad)?;
+                    let general_names = element(value.contents).ok_or_else(bad)?;
+                    for name in elements(general_names.contents) {
+                        match name.tag {
+                            0x82 => names.push(String::from_utf8_lossy(name.contents).to_ascii_lowercase()),
+                            _ => bail!("only DNS names can be requested"),
+                        }
+                    }
+                }
+            }
+        }
+        if names.is_empty() {
+            names.extend(common_name(subject.contents).map(|n| n.to_ascii_lowercase()));
+        }
+        names.dedup();
+        if names.is_empty() {
+            bail!("CSR names no domain");
+        }
+        Ok(Self { names, key: CsrKey { raw: raw.to_vec(), algorithm: rcgen_alg } })
+    }
+}
+
+/// The bytes of a BIT STRING without unused bits.
+fn bit_string(e: crate::der::Element<'_>) -> Option<&[u8]> {
+    match e.contents.split_first() {
+        Some((0, bits)) if e.tag == 0x03 => Some(bits),
+        _ => None,
+    }
+}
+
+/// The CN of a Name (SEQUENCE of SETs of type-value pairs).
+fn common_name(name: &[u8]) -> Option<String> {
+    elements(name)
+        .flat_map(|set| elements(set.contents))
+        .find_map(|pair| {
+            let mut pair = elements(pair.contents);
+            (pair.next()?.contents == OID_COMMON_NAME).then(|| pair.next())?
+        })
+        .map(|value| String::from_utf8_lossy(value.contents).into_owned())
+}
+
+// ── JWS ───────────────────────────────────────────────────────────────────────
+
+/// An account key (RFC 7517), EC or RSA
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwk {
+    kty: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    crv: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    x: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    y: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    n: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    e: String,
+}
+
+impl Jwk {
+    /// RFC 7638 thumbprint: the account id, and the second half of key authorizations.
+    pub fn thumbprint(&self) -> Result<String> {
+        let canonical = match self.kty.as_str() {
+            "EC" => format!(r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#, self.crv, self.x, self.y),
+            "RSA" => format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, self.e, self.n),
+            other => bail!("unsupported key type '{}'", other),
+        };
+        Ok(B64.encode(ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes())))
+    }
+
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Result<()> {
+        let decode = |v: &str| B64.decode(v).map_err(|_| anyhow!("bad key encoding"));
+        let ok = match (alg, self.kty.as_str(), self.crv.as_str()) {
+            ("ES256", "EC", "P-256") | ("ES384", "EC", "P-384") => {
+                let algorithm = match alg {
+                    "ES256" => &signature::ECDSA_P256_SHA256_FIXED,
+                    _ => &signature::ECDSA_P384_SHA384_FIXED,
+                };
+                let mut point = vec![0x04];
+                point.extend(decode(&self.x)?);
+                point.extend(decode(&self.y)?);
+                UnparsedPublicKey::new(algorithm, point).verify(message, sig).is_ok()
+            }
+            ("RS256", "RSA", _) => {
+                let key = RsaPublicKeyComponents { n: decode(&self.n)?, e: decode(&self.e)? };
+                key.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig).is_ok()
+            }
+            _ => bail!("unsupported algorithm '{}' for this key", alg),
+        };
+        if !ok {
+            bail!("JWS signature does not verify");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Jws {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct Protected {
+    alg: String,
+    nonce: String,
+    url: String,
+    jwk: Option<Jwk>,
+    kid: Option<String>,
+}
+
+// ── Protocol state ────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pending,
+    Processing,
+    Ready,
+    Valid,
+    Invalid,
+}
+
+struct Authz {
+    account: String,
+    domain: String,
+    token: String,
+    status: Status,
+    challenge: Status,
+    error: Option<String>,
+    expires: DateTime<Utc>,
+}
+
+struct Order {
+    account: String,
+    domains: Vec<String>,
+    authzs: Vec<String>,
+    expires: DateTime<Utc>,
+    certificate: Option<String>,
+}
+
+/// An ACME error (RFC 8555 §6.7)
+struct Problem {
+    status: StatusCode,
+    kind: &'static str,
+    detail: String,
+}
+
+fn problem(status: StatusCode, kind: &'static str, detail: impl Into<String>) -> Problem {
+    Problem { status, kind, detail: detail.into() }
+}
+
+fn malformed(detail: impl Into<String>) -> Problem {
+    problem(StatusCode::BAD_REQUEST, "malformed", detail)
+}
+
+enum Body {
+    Json(Value),
+    Pem(String),
+    Empty,
+}
+
+struct Reply {
+    status: StatusCode,
+    location: Option<String>,
+    body: Body,
+}
+
+fn json(status: StatusCode, value: Value) -> Reply {
+    Reply { status, location: None, body: Body::Json(value) }
+}
+
+fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    B64.encode(bytes)
+}
+
+fn timestamp(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The ACME server: CA, accounts (kept as files next to the CA), orders and nonces
+pub struct AcmeServer {
+    ca: InternalCa,
+    accounts_dir: PathBuf,
+    /// Name suffixes certificates may be issued for (empty: any)
+    domains: Vec<String>,
+    challenge_port: u16,
+    accounts: DashMap<String, Jwk>,
+    nonces: DashMap<String, Instant>,
+    orders: DashMap<String, Order>,
+    authzs: DashMap<String, Authz>,
+    http: reqwest::Client,
+}
+
+impl AcmeServer {
+    /// Serve ACME with the CA in `dir`. `domains` restricts the names certificates may be
+    /// issued for; http-01 challenges are fetched on `challenge_port`.
+    pub fn new(dir: &Path, domains: Vec<String>, challenge_port: u16) -> Result<Self> {
+        let ca = InternalCa::load_or_create(dir)?;
+        let accounts_dir = dir.join("accounts");
+        fs::create_dir_all(&accounts_dir)?;
+        Ok(Self {
+            ca,
+            accounts_dir,
+            domains: domains.iter().map(|d| d.trim().trim_start_matches('.').to_ascii_lowercase()).filter(|d| !d.is_empty()).collect(),
+            challenge_port,
+            accounts: DashMap::new(),
+            nonces: DashMap::new(),
+            orders: DashMap::new(),
+            authzs: DashMap::new(),
+            http: reqwest::Client::builder().timeout(VALIDATION_TIMEOUT).build()?,
+        })
+    }
+
+    pub fn ca(&self) -> &InternalCa {
+        &self.ca
+    }
+
+    /// Answer a request for `what` (the path after [`PREFIX`]); `base` is the absolute URL
+    /// of [`PREFIX`] as the client reached it.
+    pub async fn handle(self: &Arc<Self>, method: &Method, what: &str, base: &str, body: Bytes) -> Response<Full<Bytes>> {
+        let reply = match (method, what) {
+            (&Method::GET, "directory" | "") => Ok(json(StatusCode::OK, json!({
+                "newNonce": format!("{}new-nonce", base),
+                "newAccount": format!("{}new-account", base),
+                "newOrder": format!("{}new-order", base),
+                "meta": { "website": format!("{}ca.pem", base) },
+            }))),
+            (&Method::HEAD, "new-nonce") => Ok(Reply { status: StatusCode::OK, location: None, body: Body::Empty }),
+            (&Method::GET, "new-nonce") => Ok(Reply { status: StatusCode::NO_CONTENT, location: None, body: Body::Empty }),
+            (&Method::GET, "ca.pem") => Ok(Reply { status: StatusCode::OK, location: None, body: Body::Pem(self.ca.pem.clone()) }),
+            (&Method::POST, _) if body.len() > MAX_REQUEST => Err(malformed("request too large")),
+            (&Method::POST, _) => self.post(what, base, &body).await,
+            _ => Err(problem(StatusCode::METHOD_NOT_ALLOWED, "malformed", "method not allowed")),
+        };
+
+        let mut builder = Response::builder()
+            .header("Replay-Nonce", self.new_nonce())
+            .header("Link", format!("<{}directory>;rel=\"index\"", base))
+            .header(CACHE_CONTROL, "no-store");
+        let body = match reply {
+            Ok(reply) => {
+                builder = builder.status(reply.status);
+                if let Some(location) = reply.location {
+                    builder = builder.header(LOCATION, location);
+                }
+                match reply.body {
+                    Body::Json(v) => {
+                        builder = builder.header(CONTENT_TYPE, "application/json");
+                        Bytes::from(v.to_string())
+                    }
+                    Body::Pem(pem) => {
+                        builder = builder.header(CONTENT_TYPE, "application/pem-certificate-chain");
+                        Bytes::from(pem)
+                    }
+                    Body::Empty => Bytes::new(),
+                }
+            }
+            Err(p) => {
+                builder = builder.status(p.status).header(CONTENT_TYPE, "application/problem+json");
+                let value = json!({ "type": format!("urn:ietf:params:acme:error:{}", p.kind), "detail": p.detail });
+                Bytes::from(value.to_string())
+            }
+        };
+        builder.body(Full::new(body)).unwrap()
+    }
+
+    fn new_nonce(&self) -> String {
+        if self.nonces.len() >= MAX_NONCES {
+            self.nonces.retain(|_, issued| issued.elapsed() < NONCE_TTL);
+            if self.nonces.len() >= MAX_NONCES {
+                self.nonces.clear();
+            }
+        }
+        let nonce = random_id();
+        self.nonces.insert(nonce.clone(), Instant::now());
+        nonce
+    }
+
+    fn account_key(&self, id: &str) -> Option<Jwk> {
+        if let Some(jwk) = self.accounts.get(id) {
+            return Some(jwk.clone());
+        }
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return None;
+        }
+        let jwk: Jwk = serde_json::from_slice(&fs::read(self.accounts_dir.join(format!("{}.json", id))).ok()?).ok()?;
+        self.accounts.insert(id.to_string(), jwk.clone());
+        Some(jwk)
+    }
+
+    /// Check a JWS POST; returns the account id (or the new key for `new-account`) and payload.
+    fn verify(&self, what: &str, base: &str, body: &[u8]) -> Result<(String, Option<Jwk>, Vec<u8>), Problem> {
+        let jws: Jws = serde_json::from_slice(body).map_err(|_| malformed("expected a flattened JWS"))?;
+        let protected: Protected = B64.decode(&jws.protected).ok()
+            .and_then(|p| serde_json::from_slice(&p).ok())
+            .ok_or_else(|| malformed("bad protected header"))?;
+        if self.nonces.remove(&protected.nonce).filter(|(_, issued)| issued.elapsed() < NONCE_TTL).is_none() {
+            return Err(problem(StatusCode::BAD_REQUEST, "badNonce", "unknown or used nonce"));
+        }
+        if protected.url != format!("{}{}", base, what) {
+            return Err(problem(StatusCode::UNAUTHORIZED, "unauthorized", "url in the JWS does not match the request"));
+        }
+        let (account, jwk, new_key) = match (protected.jwk, protected.kid) {
+            (Some(jwk), None) if what == "new-account" => {
+                let id = jwk.thumbprint().map_err(|e| problem(StatusCode::BAD_REQUEST, "badPublicKey", e.to_string()))?;
+                (id, jwk.clone(), Some(jwk))
+            }
+            (None, Some(kid)) if what != "new-account" => {
+                let id = kid.strip_prefix(&format!("{}account/", base)).unwrap_or("").to_string();
+                let jwk = self.account_key(&id)
+                    .ok_or_else(|| problem(StatusCode::BAD_REQUEST, "accountDoesNotExist", "unknown account"))?;
+                (id, jwk, None)
+            }
+            _ => return Err(malformed("new-account needs a jwk, other requests a kid")),
+        };
+        let signature = B64.decode(&jws.signature).map_err(|_| malformed("bad signature encoding"))?;
+        jwk.verify(&protected.alg, format!("{}.{}", jws.protected, jws.payload).as_bytes(), &signature)
+            .map_err(|e| problem(StatusCode::BAD_REQUEST, "badSignatureAlgorithm", e.to_string()))?;
+        let payload = B64.decode(&jws.payload).map_err(|_| malformed("bad payload encoding"))?;
+        Ok((account, new_key, payload))
+    }
+
+    async fn post(self: &Arc<Self>, what: &str, base: &str, body: &[u8]) -> Result<Reply, Problem> {
+        let (account, new_key, payload) = self.verify(what, base, body)?;
+        let payload_json = || -> Result<Value, Problem> {
+            serde_json::from_slice(&payload).map_err(|_| malformed("payload is not JSON"))
+        };
+        let parts: Vec<&str> = what.split('/').collect();
+        match parts.as_slice() {
+            ["new-account"] => self.new_account(&account, new_key, &payload_json()?, base),
+            ["account", id] if *id == account => Ok(json(StatusCode::OK, json!({ "status": "valid", "orders": format!("{}account/{}/orders", base, id) }))),
+            ["new-order"] => self.new_order(&account, &payload_json()?, base),
+            ["order", id] => self.with_order(id, &account, |_| Ok(())).map(|_| json(StatusCode::OK, self.order_json(id, base))),
+            ["order", id, "finalize"] => self.finalize(id, &account, &payload_json()?, base),
+            ["authz", id] => self.authz_json(id, &account, base).map(|v| json(StatusCode::OK, v)),
+            ["chall", id] => self.start_challenge(id, &account, base),
+            ["cert", id] => {
+                let certificate = self.with_order(id, &account, |o| Ok(o.certificate.clone()))?;
+                let pem = certificate.ok_or_else(|| problem(StatusCode::NOT_FOUND, "malformed", "no certificate yet"))?;
+                Ok(Reply { status: StatusCode::OK, location: None, body: Body::Pem(pem) })
+            }
+            _ => Err(problem(StatusCode::NOT_FOUND, "malformed", "no such resource")),
+        }
+    }
+
+    fn new_account(&self, id: &str, key: Option<Jwk>, payload: &Value, base: &str) -> Result<Reply, Problem> {
+        let location = Some(format!("{}account/{}", base, id));
+        let body = Body::Json(json!({ "status": "valid", "orders": format!("{}account/{}/orders", base, id) }));
+        if self.account_key(id).is_some() {
+            return Ok(Reply { status: StatusCode::OK, location, body });
+        }
+        if payload["onlyReturnExisting"].as_bool() == Some(true) {
+            return Err(problem(StatusCode::BAD_REQUEST, "accountDoesNotExist", "no account for this key"));
+        }
+        let key = key.ok_or_else(|| malformed("missing key"))?;
+        let file = serde_json::to_vec(&key).unwrap_or_default();
+        fs::write(self.accounts_dir.join(format!("{}.json", id)), file)
+            .map_err(|e| problem(StatusCode::INTERNAL_SERVER_ERROR, "serverInternal", e.to_string()))?;
+        self.accounts.insert(id.to_string(), key);
+        info!("ACME server: new account {}", id);
+        Ok(Reply { status: StatusCode::CREATED, location, body })
+    }
+
+    fn allowed(&self, domain: &str) -> bool {
+        self.domains.is_empty() || self.domains.iter().any(|s| domain == s || domain.ends_with(&format!(".{}", s)))
+    }
+
+    fn new_order(&self, account: &str, payload: &Value, base: &str) -> Result<Reply, Problem> {
+        let now = Utc::now();
+        self.orders.retain(|_, o| o.expires > now);
+        self.authzs.retain(|_, a| a.expires > now);
+
+        let identifiers = payload["identifiers"].as_array().filter(|i| !i.is_empty())
+            .ok_or_else(|| malformed("no identifiers"))?;
+        let mut domains = Vec::new();
+        for identifier in identifiers {
+            let domain = match (identifier["type"].as_str(), identifier["value"].as_str()) {
+                (Some("dns"), Some(v)) => v.trim_end_matches('.').to_ascii_lowercase(),
+                _ => return Err(problem(StatusCode::BAD_REQUEST, "unsupportedIdentifier", "only dns identifiers")),
+            };
+            if domain.contains('*') || crate::host::normalize_domain(&domain).is_none() {
+                return Err(problem(StatusCode::BAD_REQUEST, "rejectedIdentifier", format!("'{}' cannot be issued (no wildcards)", domain)));
+            }
+            if !self.allowed(&domain) {
+                return Err(problem(StatusCode::BAD_REQUEST, "rejectedIdentifier", format!("'{}' is outside ACME_SERVER_DOMAINS", domain)));
+            }
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+
+        let expires = now + ORDER_TTL;
+        let authzs = domains.iter().map(|domain| {
+            let id = random_id();
+            self.authzs.insert(id.clone(), Authz {
+                account: account.to_string(),
+                domain: domain.clone(),
+                token: random_id(),
+                status: Status::Pending,
+                challenge: Status::Pending,
+                error: None,
+                expires,
+            });
+            id
+        }).collect();
+        let id = random_id();
+        self.orders.insert(id.clone(), Order { account: account.to_string(), domains, authzs, expires, certificate: None });
+        Ok(Reply { status: StatusCode::CREATED, location: Some(format!("{}order/{}", base, id)), body: Body::Json(self.order_json(&id, base)) })
+    }
+
+    fn with_order<T>(&self, id: &str, account: &str, f: impl FnOnce(&mut Order) -> Result<T, Problem>) -> Result<T, Problem> {
+        let mut order = self.orders.get_mut(id).ok_or_else(|| problem(StatusCode::NOT_FOUND, "malformed", "no such order"))?;
+        if order.account != account {
+            return Err(problem(StatusCode::FORBIDDEN, "unauthorized", "order of another account"));
+        }
+        f(&mut order)
+    }
+
+    fn order_status(&self, order: &Order) -> Status {
+        if order.certificate.is_some() {
+            return Status::Valid;
+        }
+        if order.expires <= Utc::now() {
+            return Status::Invalid;
+        }
+        let statuses: Vec<Status> = order.authzs.iter()
+            .map(|id| self.authzs.get(id).map_or(Status::Invalid, |a| a.status))
+            .collect();
+        if statuses.contains(&Status::Invalid) {
+            Status::Invalid
+        } else if statuses.iter().all(|s| *s == Status::Valid) {
+            Status::Ready
+        } else {
+            Status::Pending
+        }
+    }
+
+    fn order_json(&self, id: &str, base: &str) -> Value {
+        let Some(order) = self.orders.get(id) else { return Value::Null };
+        let mut value = json!({
+            "status": self.order_status(&order),
+            "expires": timestamp(order.expires),
+            "identifiers": order.domains.iter().map(|d| json!({ "type": "dns", "value": d })).collect::<Vec<_>>(),
+            "authorizations": order.authzs.iter().map(|a| format!("{}authz/{}", base, a)).collect::<Vec<_>>(),
+            "finalize": format!("{}order/{}/finalize", base, id),
+        });
+        if order.certificate.is_some() {
+            value["certificate"] = json!(format!("{}cert/{}", base, id));
+        }
+        value
+    }
+
+    fn authz_json(&self, id: &str, account: &str, base: &str) -> Result<Value, Problem> {
+        let authz = self.authzs.get(id).ok_or_else(|| problem(StatusCode::NOT_FOUND, "malformed", "no such authorization"))?;
+        if authz.account != account {
+            return Err(problem(StatusCode::FORBIDDEN, "unauthorized", "authorization of another account"));
+        }
+        let mut challenge = json!({
+            "type": "http-01",
+            "url": format!("{}chall/{}", base, id),
+            "token": authz.token,
+            "status": authz.challenge,
+        });
+        if let Some(error) = &authz.error {
+            challenge["error"] = json!({ "type": "urn:ietf:params:acme:error:incorrectResponse", "detail": error });
+        }
+        Ok(json!({
+            "status": authz.status,
+            "expires": timestamp(authz.expires),
+            "identifier": { "type": "dns", "value": authz.domain },
+            "challenges": [challenge],
+        }))
+    }
+
+    fn start_challenge(self: &Arc<Self>, id: &str, account: &str, base: &str) -> Result<Reply, Problem> {
+        let key_authorization = {
+            let mut authz = self.authzs.get_mut(id).ok_or_else(|| problem(StatusCode::NOT_FOUND, "malformed", "no such challenge"))?;
+            if authz.account != account {
+                return Err(problem(StatusCode::FORBIDDEN, "unauthorized", "challenge of another account"));
+            }
+            let pending = authz.challenge == Status::Pending;
+            authz.challenge = if pending { Status::Processing } else { authz.challenge };
+            let thumbprint = self.account_key(account).and_then(|k| k.thumbprint().ok()).unwrap_or_default();
+            pending.then(|| (authz.domain.clone(), authz.token.clone(), format!("{}.{}", authz.token, thumbprint)))
+        };
+        if let Some((domain, token, expected)) = key_authorization {
+            let this = self.clone();
+            let id = id.to_string();
+            tokio::spawn(async move {
+                let result = this.validate(&domain, &token, &expected).await;
+                if let Some(mut authz) = this.authzs.get_mut(&id) {
+                    match result {
+                        Ok(()) => {
+                            info!("ACME server: {} validated", domain);
+                            authz.status = Status::Valid;
+                            authz.challenge = Status::Valid;
+                        }
+                        Err(e) => {
+                            warn!("ACME server: validation of {} failed: {:#}", domain, e);
+                            authz.status = Status::Invalid;
+                            authz.challenge = Status::Invalid;
+                            authz.error = Some(format!("{:#}", e));
+                        }
+                    }
+                }
+            });
+        }
+        let authz = self.authz_json(id, account, base)?;
+        Ok(json(StatusCode::OK, authz["challenges"][0].clone()))
+    }
+
+    /// http-01: the key authorization must be served at the well-known path of the domain.
+    async fn validate(&self, domain: &str, token: &str, expected: &str) -> Result<()> {
+        let authority = match self.challenge_port {
+            80 => domain.to_string(),
+            port => format!("{}:{}", domain, port),
+        };
+        let url = format!("http://{}/.well-known/acme-challenge/{}", authority, token);
+        let resp = self.http.get(&url).send().await.with_context(|| format!("fetching {}", url))?;
+        if !resp.status().is_success() {
+            bail!("{} answered {}", url, resp.status());
+        }
+        let body = resp.text().await?;
+        if body.trim() != expected {
+            bail!("{} did not return the key authorization", url);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, id: &str, account: &str, payload: &Value, base: &str) -> Result<Reply, Problem> {
+        let csr = payload["csr"].as_str().and_then(|c| B64.decode(c).ok())
+            .ok_or_else(|| problem(StatusCode::BAD_REQUEST, "badCSR", "missing csr"))?;
+        let csr = Csr::parse(&csr).map_err(|e| problem(StatusCode::BAD_REQUEST, "badCSR", e.to_string()))?;
+        let status = self.with_order(id, account, |o| Ok(self.order_status(o)))?;
+        if status != Status::Ready {
+            return Err(problem(StatusCode::FORBIDDEN, "orderNotReady", format!("order is {:?}", status).to_lowercase()));
+        }
+        self.with_order(id, account, |order| {
+            let mut requested = csr.names.clone();
+            let mut ordered = order.domains.clone();
+            requested.sort();
+            ordered.sort();
+            if requested != ordered {
+                return Err(problem(StatusCode::BAD_REQUEST, "badCSR", "CSR names differ from the order's identifiers"));
+            }
+            let chain = self.ca.issue(&csr)
+                .map_err(|e| problem(StatusCode::INTERNAL_SERVER_ERROR, "serverInternal", format!("{:#}", e)))?;
+            info!("ACME server: issued a certificate for {}", order.domains.join(", "));
+            order.certificate = Some(chain);
+            Ok(())
+        })?;
+        Ok(Reply { status: StatusCode::OK, location: Some(format!("{}order/{}", base, id)), body: Body::Json(self.order_json(id, base)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csr_parsed_and_issued() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = InternalCa::load_or_create(dir.path()).unwrap();
+        let mut params = CertificateParams::new(vec!["svc.internal".to_string(), "API.svc.internal".to_string()]);
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        let subject = Certificate::from_params(params).unwrap();
+        let csr = Csr::parse(&subject.serialize_request_der().unwrap()).unwrap();
+        assert_eq!(csr.names, ["svc.internal", "api.svc.internal"]);
+        assert_eq!(csr.key.raw, subject.get_key_pair().public_key_raw());
+
+        let chain = ca.issue(&csr).unwrap();
+        assert_eq!(chain.matches("BEGIN CERTIFICATE").count(), 2);
+
+        // Reloaded CA keeps its certificate
+        let reloaded = InternalCa::load_or_create(dir.path()).unwrap();
+        assert_eq!(reloaded.pem(), ca.pem());
+
+        // Tampered request
+        let mut der = subject.serialize_request_der().unwrap();
+        let n = der.len();
+        der[n - 5] ^= 1;
+        assert!(Csr::parse(&der).is_err());
+    }
+
+    #[test]
+    fn test_jwk_thumbprint() {
+        // RFC 7638 §3.1
+        let jwk = Jwk {
+            kty: "RSA".into(),
+            e: "AQAB".into(),
+            n: "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".into(),
+            ..Default::default()
+        };
+        assert_eq!(jwk.thumbprint().unwrap(), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+    }
+}
@@ -0,0 +1,267 @@
+//! Admin API
+//! Operator levers for incidents, served under [`PREFIX`] when an admin token is configured:
+//!
+//! - `POST {PREFIX}cache/purge[?prefix=/path]` — drop cached responses
+//! - `POST {PREFIX}ratelimits/reset[?key=domain[/ip]]` — empty rate-limit buckets
+//! - `POST {PREFIX}dns/flush[?backend=srv://...]` — forget resolved discovery/SRV instances
+//! - `POST {PREFIX}breakers/reset[?backend=host:port][&mapping=id]` — mark HA targets healthy again
+//! - `GET {PREFIX}connections` — open client/backend connection gauges
+//! - `GET {PREFIX}status` — connection gauges, HA targets ejected as outliers and cache counters
+//! - `GET {PREFIX}cache/entries[?sort=size|age][&limit=n]` — the largest or oldest cached responses
+//! - `GET {PREFIX}events[?since=id][&limit=n]` — recent events from the event log
+//! - `GET {PREFIX}events/stream[?requests=1]` — live events as Server-Sent Events, with
+//!   finished requests too when asked for
+//! - `GET {PREFIX}usage[?period=YYYY-MM]` — requests and bytes per mapping in a month (default: this one)
+//! - `GET {PREFIX}snapshot` — configuration, mappings, certificates and backend health in one document
+//! - `POST {PREFIX}match` — which mapping a [`MatchQuery`] would be routed to, and where
+//! - `GET {PREFIX}mappings` — mappings with their secrets redacted
+//! - `PUT {PREFIX}mappings:batch` — apply a [`Batch`] of mapping adds, updates and deletes,
+//!   all of them or none
+//!
+//! A tenant's token (see `rustproxy-mapping tenant`) works too, but only for `mappings`,
+//! `usage` and `breakers/reset?mapping=id`, and only ever sees or touches the mappings the
+//! tenant owns.
+
+use crate::cache::SortBy;
+use crate::database::MappingChange;
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, Method, StatusCode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Path prefix of the admin endpoints
+pub const PREFIX: &str = "/_proxy/admin/";
+
+/// An admin operation; `None` filters mean "everything"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    PurgeCache { prefix: Option<String> },
+    ResetRateLimits { key: Option<String> },
+    FlushDns { backend: Option<String> },
+    ResetBreakers { backend: Option<String>, mapping: Option<String> },
+    /// Read-only: connection gauges
+    Connections,
+    /// Read-only: connection gauges, outlier ejections and cache counters
+    Status,
+    /// Read-only: the first `limit` cached responses by `sort`
+    CacheEntries { sort: SortBy, limit: usize },
+    /// Read-only: logged events after `since`, the last `limit` of them
+    Events { since: Option<u64>, limit: usize },
+    /// Read-only: event stream, with finished requests when `requests`
+    EventStream { requests: bool },
+    /// Read-only: usage per mapping in `period` (see [`crate::usage`])
+    Usage { period: Option<String> },
+    /// Read-only: everything this instance runs with (see [`crate::snapshot`])
+    Snapshot,
+    /// Routing dry run for the [`MatchQuery`] in the request body
+    Match,
+    /// Read-only: mappings, secrets redacted
+    Mappings,
+    /// Apply the [`Batch`] in the request body (`PUT`)
+    ApplyMappings,
+}
+
+impl Action {
+    fn read_only(&self) -> bool {
+        matches!(
+            self,
+            Action::Connections
+                | Action::Status
+                | Action::CacheEntries { .. }
+                | Action::Events { .. }
+                | Action::EventStream { .. }
+                | Action::Usage { .. }
+                | Action::Snapshot
+                | Action::Mappings
+        )
+    }
+
+    /// Whether a tenant token may perform it (on its own mappings)
+    pub fn tenant_allowed(&self) -> bool {
+        matches!(self, Action::Mappings | Action::Usage { .. } | Action::ResetBreakers { mapping: Some(_), .. })
+    }
+}
+
+/// Who an admin request acts as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caller {
+    /// Holder of the admin token: everything
+    Operator,
+    /// Holder of a tenant's token: the mappings it owns
+    Tenant(String),
+}
+
+impl Caller {
+    /// Whether the caller may see and manage a mapping with this `owner`.
+    pub fn owns(&self, owner: Option<&str>) -> bool {
+        match self {
+            Caller::Operator => true,
+            Caller::Tenant(id) => owner == Some(id.as_str()),
+        }
+    }
+}
+
+/// Body of `match`: the request to route, nothing of which is sent anywhere
+#[derive(Debug, Deserialize)]
+pub struct MatchQuery {
+    pub host: String,
+    /// Path with optional query
+    pub path: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Connecting address of the request, for `$remote_addr` (default: 127.0.0.1); experiments
+    /// go by the `X-Forwarded-For` header when there is one, as for live traffic
+    pub client_ip: Option<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// Largest `match` body accepted
+pub const MAX_MATCH_BODY: usize = 64 * 1024;
+
+/// Body of `mappings:batch`: changes applied in order, in one transaction
+#[derive(Debug, Deserialize)]
+pub struct Batch {
+    pub changes: Vec<MappingChange>,
+}
+
+/// Largest `mappings:batch` body accepted
+pub const MAX_BATCH_BODY: usize = 4 * 1024 * 1024;
+
+/// Events returned by `events` without a `limit`
+const DEFAULT_EVENT_LIMIT: usize = 100;
+
+/// Entries listed by `cache/entries` without a `limit`
+const DEFAULT_CACHE_ENTRY_LIMIT: usize = 20;
+
+/// Parse an admin request. `path` is the part after [`PREFIX`].
+pub fn parse(method: &Method, path: &str, query: Option<&str>) -> Result<Action, (StatusCode, &'static str)> {
+    let param = |name: &str| {
+        url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+            .filter(|v| !v.is_empty())
+    };
+    let action = match path.trim_end_matches('/') {
+        "cache/purge" => Action::PurgeCache { prefix: param("prefix") },
+        "ratelimits/reset" => Action::ResetRateLimits { key: param("key") },
+        "dns/flush" => Action::FlushDns { backend: param("backend") },
+        "breakers/reset" => Action::ResetBreakers { backend: param("backend"), mapping: param("mapping") },
+        "connections" => Action::Connections,
+        "status" => Action::Status,
+        "cache/entries" => Action::CacheEntries {
+            sort: match param("sort").as_deref() {
+                None | Some("size") => SortBy::Size,
+                Some("age") => SortBy::Age,
+                Some(_) => return Err((StatusCode::BAD_REQUEST, "sort must be size or age")),
+            },
+            limit: param("limit").and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_CACHE_ENTRY_LIMIT),
+        },
+        "events" => Action::Events {
+            since: param("since").and_then(|s| s.parse().ok()),
+            limit: param("limit").and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_EVENT_LIMIT),
+        },
+        "events/stream" => Action::EventStream {
+            requests: param("requests").is_some_and(|v| matches!(v.as_str(), "1" | "true" | "yes")),
+        },
+        "usage" => Action::Usage { period: param("period") },
+        "snapshot" => Action::Snapshot,
+        "match" => Action::Match,
+        "mappings" => Action::Mappings,
+        "mappings:batch" => Action::ApplyMappings,
+        _ => return Err((StatusCode::NOT_FOUND, "Unknown admin operation")),
+    };
+    match action {
+        _ if action.read_only() && method != Method::GET => Err((StatusCode::METHOD_NOT_ALLOWED, "Use GET")),
+        _ if action.read_only() => Ok(action),
+        Action::ApplyMappings if method != Method::PUT => Err((StatusCode::METHOD_NOT_ALLOWED, "Use PUT")),
+        Action::ApplyMappings => Ok(action),
+        _ if method != Method::POST => Err((StatusCode::METHOD_NOT_ALLOWED, "Admin operations require POST")),
+        _ => Ok(action),
+    }
+}
+
+/// The token of `Authorization: Bearer <token>`, if present.
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Whether the request carries `Authorization: Bearer <token>`.
+pub fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    bearer(headers).is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions() {
+        assert_eq!(
+            parse(&Method::POST, "cache/purge", Some("prefix=%2Fapi")),
+            Ok(Action::PurgeCache { prefix: Some("/api".to_string()) })
+        );
+        assert_eq!(
+            parse(&Method::POST, "breakers/reset/", Some("backend=10.0.0.1:3000&mapping=")),
+            Ok(Action::ResetBreakers { backend: Some("10.0.0.1:3000".to_string()), mapping: None })
+        );
+        assert_eq!(parse(&Method::POST, "dns/flush", None), Ok(Action::FlushDns { backend: None }));
+        assert_eq!(parse(&Method::GET, "dns/flush", None).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(parse(&Method::POST, "nope", None).unwrap_err().0, StatusCode::NOT_FOUND);
+        assert_eq!(parse(&Method::GET, "connections", None), Ok(Action::Connections));
+        assert_eq!(parse(&Method::POST, "connections", None).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(parse(&Method::GET, "status/", None), Ok(Action::Status));
+        assert_eq!(parse(&Method::GET, "events", Some("since=7")), Ok(Action::Events { since: Some(7), limit: 100 }));
+        assert_eq!(parse(&Method::GET, "events/stream", None), Ok(Action::EventStream { requests: false }));
+        assert_eq!(parse(&Method::GET, "events/stream", Some("requests=1")), Ok(Action::EventStream { requests: true }));
+        assert_eq!(
+            parse(&Method::GET, "cache/entries", Some("sort=age&limit=5")),
+            Ok(Action::CacheEntries { sort: SortBy::Age, limit: 5 })
+        );
+        assert_eq!(parse(&Method::GET, "cache/entries", Some("sort=hits")).unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(parse(&Method::GET, "usage", Some("period=2024-05")), Ok(Action::Usage { period: Some("2024-05".to_string()) }));
+        assert_eq!(parse(&Method::GET, "snapshot", None), Ok(Action::Snapshot));
+        assert_eq!(parse(&Method::POST, "match", None), Ok(Action::Match));
+        assert_eq!(parse(&Method::GET, "match", None).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(parse(&Method::GET, "mappings", None), Ok(Action::Mappings));
+        assert_eq!(parse(&Method::PUT, "mappings:batch", None), Ok(Action::ApplyMappings));
+        assert_eq!(parse(&Method::POST, "mappings:batch", None).unwrap_err().0, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_tenant_scope() {
+        assert!(Action::Usage { period: None }.tenant_allowed());
+        assert!(Action::ResetBreakers { backend: None, mapping: Some("m1".to_string()) }.tenant_allowed());
+        assert!(!Action::ResetBreakers { backend: None, mapping: None }.tenant_allowed());
+        assert!(!Action::PurgeCache { prefix: None }.tenant_allowed());
+        assert!(!Action::Snapshot.tenant_allowed());
+        assert!(!Action::ApplyMappings.tenant_allowed());
+
+        let tenant = Caller::Tenant("acme".to_string());
+        assert!(tenant.owns(Some("acme")));
+        assert!(!tenant.owns(Some("other")) && !tenant.owns(None));
+        assert!(Caller::Operator.owns(None));
+    }
+
+    #[test]
+    fn test_authorized() {
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, "s3cret"));
+        headers.insert(AUTHORIZATION, "Bearer s3cre".parse().unwrap());
+        assert!(!authorized(&headers, "s3cret"));
+        headers.insert(AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        assert!(authorized(&headers, "s3cret"));
+    }
+}
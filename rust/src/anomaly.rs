@@ -0,0 +1,230 @@
+//! Anomaly alerts
+//! Each mapping's error rate (5xx) and mean latency over the last five minutes are compared
+//! with its own baseline, the hour before that. A mapping doing markedly worse than usual —
+//! a broken deploy behind the proxy — raises an alert (logged, and an event for the stream
+//! and webhooks) once, and another when it is back to normal. Mappings with little traffic
+//! in either span are not judged
+
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Minutes of recent traffic judged
+const WINDOW_MINUTES: u64 = 5;
+/// Minutes before those that make the baseline
+const BASELINE_MINUTES: u64 = 60;
+/// Requests both spans need for a mapping to be judged
+const MIN_REQUESTS: u64 = 20;
+/// Error rate, as a multiple of the baseline's, that raises an alert…
+const ERROR_FACTOR: f64 = 3.0;
+/// …if it is also this much (in points) above it
+const ERROR_MARGIN: f64 = 0.05;
+/// Mean latency, as a multiple of the baseline's, that raises an alert…
+const LATENCY_FACTOR: f64 = 2.0;
+/// …if it is also this much slower
+const LATENCY_MARGIN: Duration = Duration::from_millis(100);
+
+/// What is compared with the baseline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    ErrorRate,
+    Latency,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Metric::ErrorRate => "error_rate",
+            Metric::Latency => "latency",
+        })
+    }
+}
+
+/// What an evaluation changed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Detected { mapping_id: String, metric: Metric, summary: String },
+    Resolved { mapping_id: String, metric: Metric, summary: String },
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Minute {
+    requests: u64,
+    errors: u64,
+    latency: Duration,
+}
+
+impl Minute {
+    fn add(&mut self, other: &Minute) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.latency += other.latency;
+    }
+
+    fn error_rate(&self) -> f64 {
+        self.errors as f64 / self.requests.max(1) as f64
+    }
+
+    fn mean_latency(&self) -> Duration {
+        self.latency / self.requests.max(1) as u32
+    }
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    /// Counts per minute since the detector started, oldest first
+    minutes: VecDeque<(u64, Minute)>,
+    /// Metrics currently alerted on
+    alerting: Vec<Metric>,
+}
+
+/// Per-mapping traffic by minute, and the alerts raised on it
+pub struct AnomalyDetector {
+    started: Instant,
+    series: DashMap<String, Series>,
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> Self {
+        Self { started: Instant::now(), series: DashMap::new() }
+    }
+}
+
+impl AnomalyDetector {
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+
+    /// Count one finished request of mapping `id`.
+    pub fn record(&self, id: &str, status: u16, latency: Duration) {
+        self.record_at(self.minute(), id, status, latency);
+    }
+
+    fn record_at(&self, minute: u64, id: &str, status: u16, latency: Duration) {
+        let mut series = self.series.entry(id.to_string()).or_default();
+        if series.minutes.back().is_none_or(|(m, _)| *m != minute) {
+            series.minutes.push_back((minute, Minute::default()));
+        }
+        let (_, counts) = series.minutes.back_mut().expect("just pushed");
+        counts.add(&Minute { requests: 1, errors: u64::from(status >= 500), latency });
+    }
+
+    /// Judge the complete minutes; alerts raised and cleared since the last call.
+    pub fn evaluate(&self) -> Vec<Event> {
+        self.evaluate_at(self.minute())
+    }
+
+    fn evaluate_at(&self, now: u64) -> Vec<Event> {
+        let mut events = Vec::new();
+        let window_start = now.saturating_sub(WINDOW_MINUTES);
+        let baseline_start = window_start.saturating_sub(BASELINE_MINUTES);
+        for mut entry in self.series.iter_mut() {
+            let id = entry.key().clone();
+            let series = entry.value_mut();
+            while series.minutes.front().is_some_and(|(m, _)| *m < baseline_start) {
+                series.minutes.pop_front();
+            }
+            let (mut window, mut baseline) = (Minute::default(), Minute::default());
+            for (minute, counts) in &series.minutes {
+                match *minute {
+                    m if m >= now => {}
+                    m if m >= window_start => window.add(counts),
+                    _ => baseline.add(counts),
+                }
+            }
+            if window.requests < MIN_REQUESTS || baseline.requests < MIN_REQUESTS {
+                continue;
+            }
+
+            let (rate, usual_rate) = (window.error_rate(), baseline.error_rate());
+            let (latency, usual_latency) = (window.mean_latency(), baseline.mean_latency());
+            let judged = [
+                (
+                    Metric::ErrorRate,
+                    rate >= usual_rate * ERROR_FACTOR && rate >= usual_rate + ERROR_MARGIN,
+                    format!("error rate {:.1}% over {}m vs {:.1}% before", rate * 100.0, WINDOW_MINUTES, usual_rate * 100.0),
+                ),
+                (
+                    Metric::Latency,
+                    latency.as_secs_f64() >= usual_latency.as_secs_f64() * LATENCY_FACTOR && latency >= usual_latency + LATENCY_MARGIN,
+                    format!("mean latency {}ms over {}m vs {}ms before", latency.as_millis(), WINDOW_MINUTES, usual_latency.as_millis()),
+                ),
+            ];
+            for (metric, anomalous, summary) in judged {
+                let alerting = series.alerting.contains(&metric);
+                if anomalous && !alerting {
+                    series.alerting.push(metric);
+                    events.push(Event::Detected { mapping_id: id.clone(), metric, summary });
+                } else if !anomalous && alerting {
+                    series.alerting.retain(|m| *m != metric);
+                    events.push(Event::Resolved { mapping_id: id.clone(), metric, summary });
+                }
+            }
+        }
+        // Mappings without traffic for the whole span
+        self.series.retain(|_, s| !s.minutes.is_empty() || !s.alerting.is_empty());
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(d: &AnomalyDetector, minute: u64, id: &str, requests: u64, errors: u64, latency_ms: u64) {
+        for i in 0..requests {
+            d.record_at(minute, id, if i < errors { 502 } else { 200 }, Duration::from_millis(latency_ms));
+        }
+    }
+
+    #[test]
+    fn test_error_spike() {
+        let d = AnomalyDetector::default();
+        for minute in 0..30 {
+            feed(&d, minute, "m1", 20, 0, 20);
+            feed(&d, minute, "quiet", 1, 1, 20);
+        }
+        assert_eq!(d.evaluate_at(30), vec![]);
+
+        // A broken deploy: a minute of mostly errors is enough
+        feed(&d, 30, "m1", 20, 15, 20);
+        let events = d.evaluate_at(31);
+        assert_eq!(events.len(), 1, "{:?}", events);
+        let Event::Detected { mapping_id, metric, summary } = &events[0] else { panic!("{:?}", events) };
+        assert_eq!((mapping_id.as_str(), *metric), ("m1", Metric::ErrorRate));
+        assert!(summary.starts_with("error rate 15.0% over 5m vs 0.0% before"), "{}", summary);
+        // Raised once, not every evaluation
+        feed(&d, 31, "m1", 20, 15, 20);
+        assert_eq!(d.evaluate_at(32), vec![]);
+
+        // Fixed: once the bad minutes leave the window
+        for minute in 32..38 {
+            feed(&d, minute, "m1", 20, 0, 20);
+        }
+        let events = d.evaluate_at(38);
+        assert!(matches!(&events[..], [Event::Resolved { metric: Metric::ErrorRate, .. }]), "{:?}", events);
+    }
+
+    #[test]
+    fn test_latency_and_thresholds() {
+        let d = AnomalyDetector::default();
+        for minute in 0..10 {
+            feed(&d, minute, "m1", 30, 3, 100);
+        }
+        // Errors as usual, latency up but not by enough
+        feed(&d, 10, "m1", 30, 3, 180);
+        assert_eq!(d.evaluate_at(11), vec![]);
+        feed(&d, 11, "m1", 30, 3, 2000);
+        let events = d.evaluate_at(12);
+        assert!(matches!(&events[..], [Event::Detected { metric: Metric::Latency, .. }]), "{:?}", events);
+        assert_eq!(Metric::Latency.to_string(), "latency");
+
+        // Without a baseline, nothing is judged
+        let d = AnomalyDetector::default();
+        feed(&d, 0, "new", 100, 100, 20);
+        assert_eq!(d.evaluate_at(1), vec![]);
+        assert_eq!(d.evaluate_at(100), vec![]);
+        assert!(d.series.is_empty());
+    }
+}
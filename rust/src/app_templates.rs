@@ -0,0 +1,110 @@
+//! Mapping templates
+//! `rustproxy-mapping add --template nextjs|rails|grafana <domain> <port>` adds the set of
+//! mappings a popular application wants behind the proxy instead of one: the app itself,
+//! its hashed assets (kept to answer while it restarts, successes not logged), its WebSocket
+//! path (apart, so limits set on the app later don't cut it off) and its health check (only
+//! failures logged). The templates are data, in `app_templates.json` next to this file; each
+//! route passes its path through unchanged, and all of a template's mappings are added in
+//! one transaction or not at all
+
+use crate::database::MappingChange;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// One mapping of a template
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// `front_uri` and `back_uri` both; empty for the app itself
+    pub path: String,
+    pub about: String,
+    /// Mapping fields set on it, as in a batch change
+    #[serde(default)]
+    pub set: Map<String, Value>,
+}
+
+/// An application's mappings; the first route is the app itself, at the domain's root
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    pub about: String,
+    pub routes: Vec<Route>,
+}
+
+static TEMPLATES: Lazy<BTreeMap<String, Template>> =
+    Lazy::new(|| serde_json::from_str(include_str!("app_templates.json")).expect("app_templates.json is valid"));
+
+/// The templates by name.
+pub fn all() -> &'static BTreeMap<String, Template> {
+    &TEMPLATES
+}
+
+/// The template called `name`, or what the choices are.
+pub fn get(name: &str) -> Result<&'static Template, String> {
+    TEMPLATES.get(&name.trim().to_ascii_lowercase()).ok_or_else(|| {
+        let names: Vec<&str> = TEMPLATES.keys().map(String::as_str).collect();
+        format!("unknown template '{}' ({})", name, names.join(", "))
+    })
+}
+
+impl Template {
+    /// The adds of this template's mappings, each with the `target` fields (domain,
+    /// back_port, backend, ...) and its own path and settings.
+    pub fn changes(&self, target: &Map<String, Value>) -> Vec<MappingChange> {
+        self.routes
+            .iter()
+            .map(|route| {
+                let mut mapping = target.clone();
+                mapping.insert("front_uri".into(), route.path.clone().into());
+                mapping.insert("back_uri".into(), route.path.clone().into());
+                mapping.extend(route.set.clone());
+                MappingChange::Add { mapping }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_log::Sampling;
+    use crate::database::DatabaseManager;
+    use serde_json::json;
+
+    #[test]
+    fn test_templates_are_valid() {
+        assert_eq!(all().keys().collect::<Vec<_>>(), vec!["grafana", "nextjs", "rails"]);
+        for (name, template) in all() {
+            assert_eq!(template.routes[0].path, "", "{}: the app comes first", name);
+            for route in &template.routes {
+                assert_eq!(route.path.trim_matches('/'), route.path, "{}: {}", name, route.path);
+                if let Some(sample) = route.set.get("log_sample") {
+                    assert!(sample.as_str().unwrap().parse::<Sampling>().is_ok(), "{}: {}", name, sample);
+                }
+            }
+        }
+        assert!(get("Rails").is_ok());
+        assert_eq!(get("django").unwrap_err(), "unknown template 'django' (grafana, nextjs, rails)");
+    }
+
+    #[test]
+    fn test_template_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("t.db")).unwrap();
+        let target = json!({ "domain": "shop.example.com", "back_port": 3000, "backend": "http://10.0.0.5" });
+        let changes = get("nextjs").unwrap().changes(target.as_object().unwrap());
+        let applied = db.apply_changes(&changes).unwrap().unwrap();
+        assert_eq!(applied.len(), 4);
+
+        let assets = db.find_by_domain_and_uri("shop.example.com", "_next/static").unwrap().unwrap();
+        assert_eq!((assets.back_uri.as_str(), assets.back_port, assets.backend.as_deref()), ("_next/static", 3000, Some("http://10.0.0.5")));
+        assert_eq!((assets.stale_if_error, assets.log_sample.as_deref()), (Some(86400), Some("2xx=0,3xx=0")));
+
+        // All or nothing: the app is there already
+        let rejected = db.apply_changes(&get("grafana").unwrap().changes(target.as_object().unwrap())).unwrap().unwrap_err();
+        assert_eq!(rejected.index, 0);
+        assert!(db.find_by_domain_and_uri("shop.example.com", "public").unwrap().is_none());
+    }
+}
@@ -0,0 +1,365 @@
+//! Client bans
+//! Explicit bans of an IP or CIDR range, for a while or until lifted, and automatic ones:
+//! with `RATE_LIMIT_BAN` of clients that keep running into their rate limit, and with
+//! `AUTH_FAILURE_BAN` (fail2ban-style) of clients that keep getting 401 or 403 from one
+//! mapping. Bans and the rate-limit strikes leading to them live in SQLite, so they survive
+//! restarts and reach every instance sharing the database within [`SYNC_INTERVAL`]; auth
+//! failures are counted per instance, in memory. A banned client gets 403 on every domain,
+//! known by its connecting address or, behind a trusted proxy, its `X-Forwarded-For` one.
+//! Expired bans are dropped at the next sync
+
+use crate::database::{Ban, DatabaseManager};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often bans are reloaded and strike counts stored
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `source` of bans given by hand
+pub const MANUAL: &str = "manual";
+/// `source` of bans given for repeated rate-limit hits
+pub const RATE_LIMIT: &str = "rate_limit";
+/// `source` of bans given for repeated 401/403 answers
+pub const AUTH_FAILURE: &str = "auth_failure";
+
+/// An address or CIDR range, IPv4 or IPv6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask_v4(u32::from(ip), self.prefix) == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask_v6(u128::from(ip), self.prefix) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { bits & (!0u32 << (32 - u32::from(prefix))) }
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    if prefix == 0 { 0 } else { bits & (!0u128 << (128 - u32::from(prefix))) }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// `203.0.113.7`, `10.0.0.0/8` or `2001:db8::/32`; host bits are cleared.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid IP or CIDR '{}'", s);
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s.trim(), None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(invalid());
+        }
+        let addr = match addr {
+            IpAddr::V4(a) => IpAddr::from(mask_v4(u32::from(a), prefix).to_be_bytes()),
+            IpAddr::V6(a) => IpAddr::from(mask_v6(u128::from(a), prefix).to_be_bytes()),
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.addr, self.prefix) {
+            (IpAddr::V4(_), 32) | (IpAddr::V6(_), 128) => write!(f, "{}", self.addr),
+            _ => write!(f, "{}/{}", self.addr, self.prefix),
+        }
+    }
+}
+
+/// When offenders are banned: after `strikes` offending requests (refused with 429, or
+/// answered 401/403) within `within`, for `duration`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    pub strikes: u32,
+    pub within: Duration,
+    pub duration: Duration,
+}
+
+/// `90`, `30s`, `10m`, `1h` or `7d` in seconds
+pub(crate) fn parse_secs(s: &str) -> Option<u64> {
+    let (digits, unit) = match s.char_indices().last()? {
+        (i, 's') => (&s[..i], 1),
+        (i, 'm') => (&s[..i], 60),
+        (i, 'h') => (&s[..i], 3600),
+        (i, 'd') => (&s[..i], 86400),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+impl FromStr for BanPolicy {
+    type Err = String;
+
+    /// `strikes=20,within=10m,duration=1h`; `within` defaults to 10m, `duration` to 1h.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = Self { strikes: 0, within: Duration::from_secs(600), duration: Duration::from_secs(3600) };
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", item))?;
+            let value = value.trim();
+            let secs = || parse_secs(value).filter(|&n| n > 0).map(Duration::from_secs)
+                .ok_or_else(|| format!("'{}' needs a duration (e.g. 10m), got '{}'", name.trim(), value));
+            match name.trim() {
+                "strikes" => policy.strikes = value.parse().map_err(|_| format!("'strikes' needs a count, got '{}'", value))?,
+                "within" => policy.within = secs()?,
+                "duration" => policy.duration = secs()?,
+                other => return Err(format!("unknown ban setting '{}' (strikes, within, duration)", other)),
+            }
+        }
+        if policy.strikes == 0 {
+            return Err("a ban policy needs strikes=N".to_string());
+        }
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for BanPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "strikes={},within={}s,duration={}s", self.strikes, self.within.as_secs(), self.duration.as_secs())
+    }
+}
+
+/// Offending requests of one client in the current `within` window
+#[derive(Debug, Clone, Copy)]
+struct Strikes {
+    count: u32,
+    /// Unix seconds the window started
+    since: i64,
+    /// Changed since the last sync
+    dirty: bool,
+}
+
+/// A banned network and when the ban ends (`None`: when lifted)
+type Active = (Network, Option<DateTime<Utc>>);
+
+/// Bans in force and strike counts of this instance. Clones share them.
+#[derive(Clone, Default)]
+pub struct Bans {
+    active: Arc<RwLock<Vec<Active>>>,
+    strikes: Arc<DashMap<IpAddr, Strikes>>,
+    /// Clients whose strikes were used up by a ban, to clear in the database
+    cleared: Arc<Mutex<Vec<IpAddr>>>,
+    policy: Option<BanPolicy>,
+    /// 401/403 answers by client and mapping ID
+    auth_failures: Arc<DashMap<(IpAddr, String), Strikes>>,
+    auth_policy: Option<BanPolicy>,
+}
+
+impl Strikes {
+    fn new(now: i64) -> Self {
+        Self { count: 0, since: now, dirty: true }
+    }
+
+    /// Count one more within `policy`'s window (starting a new one if the last ran out);
+    /// whether that makes the strikes for a ban.
+    fn add(&mut self, policy: &BanPolicy, now: i64) -> bool {
+        if now - self.since >= policy.within.as_secs() as i64 {
+            *self = Self::new(now);
+        }
+        self.count += 1;
+        self.dirty = true;
+        self.count >= policy.strikes
+    }
+}
+
+impl Bans {
+    /// Bans with automatic ones for rate-limit and auth-failure offenders, if given.
+    pub fn new(rate_limit: Option<BanPolicy>, auth_failure: Option<BanPolicy>) -> Self {
+        Self { policy: rate_limit, auth_policy: auth_failure, ..Self::default() }
+    }
+
+    /// Whether any unexpired ban covers `ip`.
+    pub fn banned(&self, ip: &str) -> bool {
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return false;
+        };
+        let now = Utc::now();
+        self.active.read().iter().any(|(net, until)| net.contains(ip) && until.is_none_or(|u| u > now))
+    }
+
+    /// Count a request of `ip` refused by its rate limit. Once that makes the policy's
+    /// strikes, the client is banned (at once here, and stored for the others); the new
+    /// ban is returned.
+    pub fn strike(&self, ip: &str, db: &DatabaseManager) -> Result<Option<Ban>> {
+        let (Some(policy), Ok(ip)) = (self.policy, ip.parse::<IpAddr>()) else {
+            return Ok(None);
+        };
+        let (ip, now) = (ip.to_canonical(), Utc::now().timestamp());
+        if !self.strikes.entry(ip).or_insert(Strikes::new(now)).add(&policy, now) {
+            return Ok(None);
+        }
+        self.strikes.remove(&ip);
+        self.cleared.lock().push(ip);
+        let reason = format!("{} rate-limited requests within {}s", policy.strikes, policy.within.as_secs());
+        self.ban(ip, &policy, &reason, RATE_LIMIT, db).map(Some)
+    }
+
+    /// Count a 401 or 403 answer to `ip` from `mapping_id`; as [`Bans::strike`], but the
+    /// failures are counted per mapping and only in this instance.
+    pub fn auth_failure(&self, ip: &str, mapping_id: &str, db: &DatabaseManager) -> Result<Option<Ban>> {
+        let (Some(policy), Ok(ip)) = (self.auth_policy, ip.parse::<IpAddr>()) else {
+            return Ok(None);
+        };
+        let (ip, now) = (ip.to_canonical(), Utc::now().timestamp());
+        let key = (ip, mapping_id.to_string());
+        if !self.auth_failures.entry(key.clone()).or_insert(Strikes::new(now)).add(&policy, now) {
+            return Ok(None);
+        }
+        self.auth_failures.remove(&key);
+        let reason = format!("{} auth failures on mapping {} within {}s", policy.strikes, mapping_id, policy.within.as_secs());
+        self.ban(ip, &policy, &reason, AUTH_FAILURE, db).map(Some)
+    }
+
+    /// Ban `ip` for `policy.duration`, here at once and in the database for the others.
+    fn ban(&self, ip: IpAddr, policy: &BanPolicy, reason: &str, source: &str, db: &DatabaseManager) -> Result<Ban> {
+        let until = Utc::now() + chrono::Duration::from_std(policy.duration).unwrap_or(chrono::Duration::hours(1));
+        let network = Network { addr: ip, prefix: if ip.is_ipv4() { 32 } else { 128 } };
+        self.active.write().push((network, Some(until)));
+        db.add_ban(&network.to_string(), Some(reason), Some(&until.to_rfc3339()), source)
+    }
+
+    /// Store changed strike counts, drop expired bans, and load the bans in force (given by
+    /// anyone using the database).
+    pub fn sync(&self, db: &DatabaseManager) -> Result<()> {
+        let now = Utc::now();
+        let within = self.policy.map_or(0, |p| p.within.as_secs() as i64);
+        self.strikes.retain(|_, s| now.timestamp() - s.since < within);
+        let auth_within = self.auth_policy.map_or(0, |p| p.within.as_secs() as i64);
+        self.auth_failures.retain(|_, s| now.timestamp() - s.since < auth_within);
+        let changed: Vec<(String, u32, i64)> = self.strikes.iter_mut()
+            .filter(|e| e.dirty)
+            .map(|mut e| {
+                e.dirty = false;
+                (e.key().to_string(), e.count, e.since)
+            })
+            .collect();
+        let cleared: Vec<String> = self.cleared.lock().drain(..).map(|ip| ip.to_string()).collect();
+        db.save_offenders(&changed, &cleared, now.timestamp() - within)?;
+
+        db.delete_expired_bans()?;
+        let active = db.list_bans()?.into_iter()
+            .filter_map(|b| {
+                let until = match b.expires_at.as_deref() {
+                    Some(t) => Some(DateTime::parse_from_rfc3339(t).ok()?.to_utc()),
+                    None => None,
+                };
+                Some((b.network.parse().ok()?, until))
+            })
+            .collect();
+        *self.active.write() = active;
+        Ok(())
+    }
+
+    /// Pick up the strike counts stored before a restart (still within the window).
+    pub fn load_offenders(&self, db: &DatabaseManager) -> Result<()> {
+        let Some(policy) = self.policy else {
+            return Ok(());
+        };
+        let since = Utc::now().timestamp() - policy.within.as_secs() as i64;
+        for (ip, count, started) in db.offenders(since)? {
+            if let Ok(ip) = ip.parse() {
+                self.strikes.insert(ip, Strikes { count, since: started, dirty: false });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_network() {
+        let net: Network = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains("10.200.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let v6: Network = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::5".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::5".parse().unwrap()));
+        assert_eq!("203.0.113.7".parse::<Network>().unwrap().to_string(), "203.0.113.7");
+        assert!("0.0.0.0/0".parse::<Network>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_policy_parse() {
+        let policy: BanPolicy = "strikes=20, duration=2h".parse().unwrap();
+        assert_eq!(policy, BanPolicy { strikes: 20, within: Duration::from_secs(600), duration: Duration::from_secs(7200) });
+        assert_eq!(policy.to_string(), "strikes=20,within=600s,duration=7200s");
+        assert!("within=1m".parse::<BanPolicy>().is_err());
+        assert!("strikes=5,duration=forever".parse::<BanPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_strikes_ban_and_survive_restart() {
+        let dir = tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+        let policy = "strikes=3,within=1h,duration=1h".parse().ok();
+
+        let bans = Bans::new(policy, None);
+        assert!(bans.strike("203.0.113.7", &db).unwrap().is_none());
+        assert!(bans.strike("203.0.113.7", &db).unwrap().is_none());
+        bans.sync(&db).unwrap();
+
+        // Counted on after a restart
+        let restarted = Bans::new(policy, None);
+        restarted.load_offenders(&db).unwrap();
+        let ban = restarted.strike("203.0.113.7", &db).unwrap().unwrap();
+        assert_eq!((ban.network.as_str(), ban.source.as_str()), ("203.0.113.7", RATE_LIMIT));
+        assert!(restarted.banned("203.0.113.7"));
+        assert!(!restarted.banned("203.0.113.8"));
+
+        // Other instances see it after their sync; the strikes are used up
+        assert!(!bans.banned("203.0.113.7"));
+        bans.sync(&db).unwrap();
+        assert!(bans.banned("203.0.113.7"));
+        restarted.sync(&db).unwrap();
+        assert!(db.offenders(0).unwrap().is_empty());
+
+        db.add_ban("10.0.0.0/8", None, Some("2020-01-01T00:00:00+00:00"), MANUAL).unwrap();
+        bans.sync(&db).unwrap();
+        assert!(!bans.banned("10.1.1.1"));
+        assert_eq!(db.list_bans().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_auth_failures_counted_per_mapping() {
+        let dir = tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+        let bans = Bans::new(None, "strikes=2".parse().ok());
+        assert!(bans.strike("203.0.113.7", &db).unwrap().is_none());
+        assert!(bans.auth_failure("203.0.113.7", "m1", &db).unwrap().is_none());
+        assert!(bans.auth_failure("203.0.113.7", "m2", &db).unwrap().is_none());
+        let ban = bans.auth_failure("203.0.113.7", "m1", &db).unwrap().unwrap();
+        assert_eq!(ban.source, AUTH_FAILURE);
+        assert!(ban.reason.unwrap().contains("m1"));
+        assert!(bans.banned("203.0.113.7"));
+    }
+}
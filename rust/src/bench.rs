@@ -0,0 +1,189 @@
+//! Load generator
+//! Drives keep-alive HTTP/1.1 load at a running proxy (`rustproxy bench --target ...`) and
+//! reports throughput and latency percentiles, so hot-path regressions show up as numbers
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Request, Uri};
+use hyper_util::rt::TokioIo;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// What to send, and how hard
+#[derive(Debug, Clone)]
+pub struct LoadConfig {
+    /// `http://host:port/path?query` of the proxy
+    pub target: Uri,
+    /// Host header to send (selects the mapping); defaults to the target authority
+    pub host: Option<String>,
+    pub method: Method,
+    pub body: Bytes,
+    /// Concurrent keep-alive connections
+    pub connections: usize,
+    pub duration: Duration,
+}
+
+/// Outcome of a run
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub elapsed: Duration,
+    /// Request latencies in microseconds, sorted
+    pub latencies_us: Vec<u64>,
+    /// Responses by status code
+    pub statuses: BTreeMap<u16, u64>,
+    /// Connect, send or read failures
+    pub errors: u64,
+}
+
+impl LoadReport {
+    pub fn requests(&self) -> u64 {
+        self.latencies_us.len() as u64
+    }
+
+    pub fn rps(&self) -> f64 {
+        self.requests() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Latency at percentile `p` (0–100).
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies_us.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0) * (self.latencies_us.len() - 1) as f64).round() as usize;
+        Duration::from_micros(self.latencies_us[rank.min(self.latencies_us.len() - 1)])
+    }
+
+    fn merge(&mut self, other: LoadReport) {
+        self.latencies_us.extend(other.latencies_us);
+        for (status, n) in other.statuses {
+            *self.statuses.entry(status).or_default() += n;
+        }
+        self.errors += other.errors;
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(f, "Requests:     {} in {:.2}s", self.requests(), self.elapsed.as_secs_f64())?;
+        writeln!(f, "Requests/sec: {:.1}", self.rps())?;
+        writeln!(
+            f,
+            "Latency:      p50 {:.3}ms  p90 {:.3}ms  p99 {:.3}ms  max {:.3}ms",
+            ms(self.percentile(50.0)),
+            ms(self.percentile(90.0)),
+            ms(self.percentile(99.0)),
+            ms(self.percentile(100.0)),
+        )?;
+        let statuses: Vec<String> = self.statuses.iter().map(|(s, n)| format!("{}={}", s, n)).collect();
+        writeln!(f, "Statuses:     {}", statuses.join(" "))?;
+        write!(f, "Errors:       {}", self.errors)
+    }
+}
+
+/// Run the load for `config.duration` and collect the results.
+pub async fn run(config: LoadConfig) -> Result<LoadReport> {
+    if config.target.scheme_str() != Some("http") {
+        return Err(anyhow!("only http:// targets are supported"));
+    }
+    let authority = config.target.authority().context("target has no host")?.clone();
+    let addr = format!("{}:{}", authority.host(), authority.port_u16().unwrap_or(80));
+    let host = config.host.clone().unwrap_or_else(|| authority.to_string());
+    let path = config.target.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string();
+
+    let started = Instant::now();
+    let deadline = started + config.duration;
+    let workers: Vec<_> = (0..config.connections.max(1))
+        .map(|_| {
+            let (addr, host, path, method, body) =
+                (addr.clone(), host.clone(), path.clone(), config.method.clone(), config.body.clone());
+            tokio::spawn(async move { worker(&addr, &host, &path, method, body, deadline).await })
+        })
+        .collect();
+
+    let mut report = LoadReport::default();
+    for w in workers {
+        report.merge(w.await?);
+    }
+    report.elapsed = started.elapsed();
+    report.latencies_us.sort_unstable();
+    Ok(report)
+}
+
+/// One connection's request loop; reconnects after failures.
+async fn worker(addr: &str, host: &str, path: &str, method: Method, body: Bytes, deadline: Instant) -> LoadReport {
+    let mut report = LoadReport::default();
+    'connect: while Instant::now() < deadline {
+        let mut sender = match TcpStream::connect(addr).await {
+            Ok(stream) => match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await {
+                Ok((sender, conn)) => {
+                    tokio::spawn(conn);
+                    sender
+                }
+                Err(_) => {
+                    report.errors += 1;
+                    continue;
+                }
+            },
+            Err(_) => {
+                report.errors += 1;
+                // Don't spin on a refused port
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+        };
+        while Instant::now() < deadline {
+            let req = Request::builder()
+                .method(method.clone())
+                .uri(path)
+                .header(hyper::header::HOST, host)
+                .body(Full::new(body.clone()))
+                .expect("valid request");
+            let sent = Instant::now();
+            let status = match sender.send_request(req).await {
+                Ok(resp) => {
+                    let status = resp.status().as_u16();
+                    match resp.into_body().collect().await {
+                        Ok(_) => status,
+                        Err(_) => {
+                            report.errors += 1;
+                            continue 'connect;
+                        }
+                    }
+                }
+                Err(_) => {
+                    report.errors += 1;
+                    continue 'connect;
+                }
+            };
+            report.latencies_us.push(sent.elapsed().as_micros() as u64);
+            *report.statuses.entry(status).or_default() += 1;
+            if sender.ready().await.is_err() {
+                continue 'connect;
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let report = LoadReport {
+            elapsed: Duration::from_secs(2),
+            latencies_us: (1..=100).collect(),
+            ..Default::default()
+        };
+        assert_eq!(report.rps(), 50.0);
+        assert_eq!(report.percentile(50.0), Duration::from_micros(51));
+        assert_eq!(report.percentile(99.0), Duration::from_micros(99));
+        assert_eq!(report.percentile(100.0), Duration::from_micros(100));
+        assert_eq!(LoadReport::default().percentile(99.0), Duration::ZERO);
+    }
+}
@@ -2,12 +2,60 @@
 //!
 //! Usage:
 //!   rustproxy-mapping add <domain> <port> [options]
-//!   rustproxy-mapping delete <domain> [--frontend <path>]
-//!   rustproxy-mapping list [--domain <domain>]
-//!   rustproxy-mapping update <domain> <port> [options]
+//!   rustproxy-mapping add --from-url <public URL>=<backend URL> [options]
+//!   rustproxy-mapping add --template nextjs|rails|grafana <domain> <port> [options]
+//!   rustproxy-mapping delete <domain> [--frontend <path>] [--purge]
+//!   rustproxy-mapping restore <domain> [--frontend <path>]
+//!   rustproxy-mapping list [--domain <domain>] [--deleted]
+//!   rustproxy-mapping export [--domain <domain>] [--format csv|nginx|caddy] [<file>]
+//!   rustproxy-mapping import <file> [--domain <domain>] [--prune] [--apply]
+//!   rustproxy-mapping lint [--json]
+//!   rustproxy-mapping usage [--period YYYY-MM] [--json]
+//!   rustproxy-mapping update <domain> [<port>] --if-version <n> [options]
+//!   rustproxy-mapping switch <domain> [--frontend <path>] --to <blue|green>
+//!   rustproxy-mapping challenge <token> [<key-authorization> | --delete]
+//!   rustproxy-mapping api-key add|list|revoke ...
+//!   rustproxy-mapping tenant add|list|token|remove ...
+//!   rustproxy-mapping verify [<domain> [--check dns|http | --trust]]
+//!   rustproxy-mapping ban add|list|remove ...
+//!   rustproxy-mapping --tenant <id> <command> ...
+//!   rustproxy-mapping --output table|plain|json [--quiet] <command> ...
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use rustproxy::access_hours::AccessHours;
+use rustproxy::access_log::{AccessLevel, Sampling};
+use rustproxy::app_templates;
+use rustproxy::bans::{self, Network};
+use rustproxy::cli_output::{self, Failure, Format, Output};
+use rustproxy::config_export;
+use rustproxy::body_rewrite::BodyRewrites;
+use rustproxy::contract::Contract;
+use rustproxy::database::MappingChange;
+use rustproxy::domain_verify::{self, Method};
+use rustproxy::early_hints::EarlyHints;
+use rustproxy::egress::{self, EgressProxy};
+use rustproxy::experiment::Experiment;
+use rustproxy::forward_headers::ForwardHeaders;
+use rustproxy::header_limits::HeaderLimits;
+use rustproxy::hold::Hold;
+use rustproxy::html_base::BasePathMode;
+use rustproxy::image_filter::ImageFilter;
+use rustproxy::lint::{self, Listeners};
+use rustproxy::mapping_csv;
+use rustproxy::minify::Minify;
+use rustproxy::normalize::PathNormalization;
+use rustproxy::oidc::OidcSettings;
+use rustproxy::pool::PoolSettings;
+use rustproxy::ratelimit::RateLimit;
+use rustproxy::route_url::RouteUrl;
+use rustproxy::scrub::ScrubProfile;
+use rustproxy::signed_url;
+use rustproxy::startup_page::StartupPage;
+use rustproxy::upstream_protocol::UpstreamProtocol;
+use rustproxy::upstream_signing::UpstreamSigning;
+use rustproxy::upstream_tls::{self, UpstreamTls};
+use rustproxy::usage::{self, Quota};
 use rustproxy::DatabaseManager;
 use std::path::PathBuf;
 
@@ -22,6 +70,18 @@ struct Args {
     #[arg(long, env = "DB_PATH", default_value = "./data/current.db")]
     db_path: PathBuf,
 
+    /// Act for a tenant: only its mappings are listed or changed, and new ones belong to it
+    #[arg(long, global = true, env = "RUSTPROXY_TENANT")]
+    tenant: Option<String>,
+
+    /// How results are printed: table (for people), plain (tab-separated fields, for scripts) or json
+    #[arg(short = 'o', long, global = true, env = "RUSTPROXY_OUTPUT", default_value = "table", value_parser = ["table", "plain", "json"])]
+    output: String,
+
+    /// Leave out confirmations and hints; listings, new keys and tokens, and signed URLs are still printed
+    #[arg(short = 'q', long, global = true)]
+    quiet: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -31,10 +91,22 @@ enum Commands {
     /// Add a new domain mapping
     Add {
         /// Domain name (e.g., api.example.com)
-        domain: String,
+        #[arg(required_unless_present = "from_url", conflicts_with = "from_url")]
+        domain: Option<String>,
 
         /// Backend port (used when --ports is not set)
-        port: u16,
+        #[arg(required_unless_present = "from_url", conflicts_with = "from_url")]
+        port: Option<u16>,
+
+        /// The route as <public URL>=<backend URL>, instead of domain, port and paths
+        /// (e.g., https://app.example.com/api=http://10.0.0.5:3000/v1)
+        #[arg(long, conflicts_with_all = ["frontend", "backend", "both", "server"])]
+        from_url: Option<RouteUrl>,
+
+        /// Add the mappings an application wants (nextjs, rails, grafana): the app at the root,
+        /// its assets, WebSocket and health check paths; other options apply to the app's
+        #[arg(long, value_parser = parse_template, conflicts_with_all = ["frontend", "backend", "both", "from_url"])]
+        template: Option<String>,
 
         /// Frontend URI path (without leading slash)
         #[arg(short = 'f', long)]
@@ -48,13 +120,181 @@ enum Commands {
         #[arg(long)]
         both: Option<String>,
 
-        /// External backend server URL (e.g., https://api.external.com)
+        /// External backend server URL (e.g., https://api.external.com, fcgi://127.0.0.1 for PHP-FPM, or s3://<endpoint> for a bucket-hosted static site)
         #[arg(short = 's', long)]
         server: Option<String>,
 
         /// HA: comma-separated list of backend ports for round-robin (e.g., 3000,3001,3002)
         #[arg(long)]
         ports: Option<String>,
+
+        /// Blue/green: backend port of the green slot
+        #[arg(long)]
+        green_port: Option<u16>,
+
+        /// Blue/green: backend server URL of the green slot (defaults to the blue one)
+        #[arg(long, requires = "green_port")]
+        green_server: Option<String>,
+
+        /// A/B experiment as JSON, e.g. '{"name":"x","key":"cookie:uid","variants":[...]}'
+        #[arg(long, value_parser = parse_experiment)]
+        experiment: Option<String>,
+
+        /// Accepted request Content-Types, comma-separated (e.g. image/*,application/pdf)
+        #[arg(long)]
+        content_types: Option<String>,
+
+        /// Largest accepted request body, e.g. 512K, 10M, 1G
+        #[arg(long, value_parser = parse_size)]
+        max_body: Option<u64>,
+
+        /// Forward the path exactly as the client encoded it (e.g. keep %2F)
+        #[arg(long)]
+        preserve_path: bool,
+
+        /// Access-log level for this mapping: off, error, warn, info, debug, trace
+        #[arg(long, value_parser = parse_log_level)]
+        log_level: Option<String>,
+
+        /// Access-log sampling per status class, e.g. "2xx=1%,5xx=100%"
+        #[arg(long, value_parser = parse_log_sample)]
+        log_sample: Option<String>,
+
+        /// Response body substitutions as JSON, e.g. '[{"find":"http://10.0.0.5:3000","replace":"https://example.com"}]'
+        #[arg(long, value_parser = parse_body_rewrites)]
+        body_rewrites: Option<String>,
+
+        /// Keep HTML links under the frontend path: base (inject <base href>) or links (prefix /-links)
+        #[arg(long, value_parser = parse_html_base)]
+        html_base: Option<String>,
+
+        /// Requests per client IP, e.g. 100/1m, or "off"; unset uses the server's RATE_LIMIT
+        #[arg(long, value_parser = parse_rate_limit)]
+        rate_limit: Option<String>,
+
+        /// Secondary target tried when the backend is down or answers 5xx, e.g. http://standby:8080
+        #[arg(long, value_parser = parse_fallback_backend)]
+        fallback_backend: Option<String>,
+
+        /// Serve the last good GET response for this long when the backend fails (e.g. 300, 10m, 1h, 1d)
+        #[arg(long, value_parser = parse_duration_secs)]
+        stale_if_error: Option<u64>,
+
+        /// PEM bundle of the CAs trusted for an https:// backend instead of the public roots
+        #[arg(long)]
+        tls_ca: Option<String>,
+
+        /// Accepted public key of an https:// backend as sha256//<base64> (repeatable)
+        #[arg(long, value_parser = parse_tls_pin)]
+        tls_pin: Vec<String>,
+
+        /// Name sent as SNI and checked against the backend certificate instead of its host
+        #[arg(long, value_parser = parse_tls_server_name)]
+        tls_server_name: Option<String>,
+
+        /// Do not verify the backend certificate chain or name (pins are still checked)
+        #[arg(long)]
+        tls_insecure: bool,
+
+        /// Reach the backend through socks5://, socks5h:// or http:// (CONNECT) proxy, or "direct" to bypass EGRESS_PROXY
+        #[arg(long, value_parser = parse_egress_proxy)]
+        egress_proxy: Option<String>,
+
+        /// fcgi:// backends: directory scripts resolve in (as the FastCGI server sees it) and static files are served from
+        #[arg(long)]
+        document_root: Option<String>,
+
+        /// Require an OpenID Connect login; issuer URL of the identity provider
+        #[arg(long, requires = "oidc_client_id")]
+        oidc_issuer: Option<String>,
+
+        /// OIDC client ID registered for <scheme>://<domain>/_proxy/oidc/callback
+        #[arg(long)]
+        oidc_client_id: Option<String>,
+
+        /// OIDC client secret; ${ENV_VAR} keeps it out of the database
+        #[arg(long)]
+        oidc_client_secret: Option<String>,
+
+        /// OIDC scopes (default "openid email profile")
+        #[arg(long)]
+        oidc_scopes: Option<String>,
+
+        /// Only let in users with a verified email in these domains (comma-separated)
+        #[arg(long, value_delimiter = ',')]
+        oidc_allowed_domain: Vec<String>,
+
+        /// Only serve signed URLs (see sign-url), checked with this HMAC key; ${ENV_VAR} keeps it out of the database
+        #[arg(long)]
+        url_signing_secret: Option<String>,
+
+        /// Gzip request bodies of at least this size before sending them to the backend, e.g. 1K
+        #[arg(long, value_parser = parse_size)]
+        compress_requests: Option<u64>,
+
+        /// Backend connection pool settings instead of the global ones, e.g. max_connections=200,max_idle=64,idle_timeout=30,max_lifetime=600
+        #[arg(long, value_parser = parse_pool)]
+        pool: Option<String>,
+
+        /// Monthly quota, e.g. requests=1000000,bytes=50G; requests beyond it get 429 until the month ends
+        #[arg(long, value_parser = parse_quota)]
+        quota: Option<String>,
+
+        /// Checks on backend responses, e.g. headers=x-request-id,status=2xx;304,latency=500ms[,enforce]
+        #[arg(long, value_parser = parse_contract)]
+        contract: Option<String>,
+
+        /// Start routing to the mapping at this time: RFC 3339, YYYY-MM-DD, or a delay like 2h
+        #[arg(long, value_parser = parse_schedule)]
+        activate_at: Option<String>,
+
+        /// Stop routing to the mapping at this time: RFC 3339, YYYY-MM-DD, or a delay like 2h
+        #[arg(long, value_parser = parse_schedule)]
+        deactivate_at: Option<String>,
+
+        /// Header limits tighter than the listener's, e.g. count=30,bytes=8K,field=2K; requests over them get 431
+        #[arg(long, value_parser = parse_header_limits)]
+        header_limits: Option<String>,
+
+        /// When the mapping is reachable, as a cron expression, e.g. '* 9-17 * * mon-fri tz=+01:00 [status=503] [page=FILE]'
+        #[arg(long, value_parser = parse_access_hours)]
+        access_hours: Option<String>,
+
+        /// Forward only these request headers to the backend, e.g. accept,x-app-*; cookies and auth headers are dropped unless listed
+        #[arg(long, value_parser = parse_forward_headers)]
+        forward_headers: Option<String>,
+
+        /// Fingerprinting headers removed from responses (off, basic, strict) instead of SCRUB_RESPONSE_HEADERS
+        #[arg(long, value_parser = parse_scrub_headers)]
+        scrub_headers: Option<String>,
+
+        /// How requests reach the backend: http/1.0, or h2c, close and/or no-expect
+        #[arg(long, value_parser = parse_upstream_protocol)]
+        upstream_protocol: Option<String>,
+
+        /// Sign backend requests: aws4,access_key=..,secret_key=..,region=..,service=.. or hmac,secret=..[,key_id=..]; secrets may be ${ENV_VAR}
+        #[arg(long, value_parser = parse_upstream_signing)]
+        upstream_signing: Option<String>,
+
+        /// Show browsers an auto-refreshing "starting up" page while the backend is slow: on, or after=5,refresh=5,max_wait=120[,page=file.html]
+        #[arg(long, value_parser = parse_startup_page)]
+        startup_page: Option<String>,
+
+        /// Resize images by w=, h=, format=, q= query parameters (on, or max_width=,max_height=,quality=,max_bytes=)
+        #[arg(long, value_parser = parse_image_filter)]
+        image_filter: Option<String>,
+
+        /// Minify HTML, CSS and JavaScript responses (on, or html,css,js plus max_bytes=,max_ms=)
+        #[arg(long, value_parser = parse_minify)]
+        minify: Option<String>,
+
+        /// 103 Early Hints for page loads: Link values like '</app.css>; rel=preload; as=style', and/or 'forward' to relay the backend's
+        #[arg(long, value_parser = parse_early_hints)]
+        early_hints: Option<String>,
+
+        /// Tenant the mapping belongs to (see `tenant add`); defaults to --tenant
+        #[arg(long)]
+        owner: Option<String>,
     },
 
     /// Update an existing mapping
@@ -84,9 +324,222 @@ enum Commands {
         /// Current frontend URI to identify the mapping
         #[arg(long)]
         current_frontend: Option<String>,
+
+        /// Version of the mapping the change is based on (see `list`); refused if it has
+        /// changed since
+        #[arg(long)]
+        if_version: u64,
+
+        /// Blue/green: backend port of the green slot
+        #[arg(long)]
+        green_port: Option<u16>,
+
+        /// Blue/green: backend server URL of the green slot (defaults to the blue one)
+        #[arg(long, requires = "green_port")]
+        green_server: Option<String>,
+
+        /// A/B experiment as JSON; an empty string removes it
+        #[arg(long, value_parser = parse_experiment)]
+        experiment: Option<String>,
+
+        /// Accepted request Content-Types, comma-separated; an empty string removes the rule
+        #[arg(long)]
+        content_types: Option<String>,
+
+        /// Largest accepted request body, e.g. 512K, 10M, 1G; 0 removes the limit
+        #[arg(long, value_parser = parse_size)]
+        max_body: Option<u64>,
+
+        /// Forward the path exactly as the client encoded it (true/false)
+        #[arg(long)]
+        preserve_path: Option<bool>,
+
+        /// Access-log level for this mapping; an empty string restores the server default
+        #[arg(long, value_parser = parse_log_level)]
+        log_level: Option<String>,
+
+        /// Access-log sampling per status class; an empty string restores the server default
+        #[arg(long, value_parser = parse_log_sample)]
+        log_sample: Option<String>,
+
+        /// Response body substitutions as JSON; an empty string removes them
+        #[arg(long, value_parser = parse_body_rewrites)]
+        body_rewrites: Option<String>,
+
+        /// Keep HTML links under the frontend path: base or links; an empty string turns it off
+        #[arg(long, value_parser = parse_html_base)]
+        html_base: Option<String>,
+
+        /// Requests per client IP, e.g. 100/1m, or "off"; an empty string restores the server default
+        #[arg(long, value_parser = parse_rate_limit)]
+        rate_limit: Option<String>,
+
+        /// Secondary target tried when the backend is down or answers 5xx; an empty string removes it
+        #[arg(long, value_parser = parse_fallback_backend)]
+        fallback_backend: Option<String>,
+
+        /// Serve the last good GET response for this long when the backend fails; 0 turns it off
+        #[arg(long, value_parser = parse_duration_secs)]
+        stale_if_error: Option<u64>,
+
+        /// PEM bundle of the CAs trusted for an https:// backend; an empty string restores the public roots
+        #[arg(long)]
+        tls_ca: Option<String>,
+
+        /// Accepted public keys of an https:// backend (repeatable, replaces the list); an empty string removes them
+        #[arg(long, value_parser = parse_tls_pin)]
+        tls_pin: Vec<String>,
+
+        /// Name checked against the backend certificate; an empty string restores the backend host
+        #[arg(long, value_parser = parse_tls_server_name)]
+        tls_server_name: Option<String>,
+
+        /// Skip verification of the backend certificate chain and name (true/false)
+        #[arg(long)]
+        tls_insecure: Option<bool>,
+
+        /// Egress proxy URL for the backend, or "direct"; an empty string restores the server's EGRESS_PROXY
+        #[arg(long, value_parser = parse_egress_proxy)]
+        egress_proxy: Option<String>,
+
+        /// Document root of an fcgi:// backend; an empty string removes it
+        #[arg(long)]
+        document_root: Option<String>,
+
+        /// OpenID Connect issuer URL; an empty string removes the login requirement
+        #[arg(long)]
+        oidc_issuer: Option<String>,
+
+        /// OIDC client ID
+        #[arg(long)]
+        oidc_client_id: Option<String>,
+
+        /// OIDC client secret (or ${ENV_VAR})
+        #[arg(long)]
+        oidc_client_secret: Option<String>,
+
+        /// OIDC scopes; an empty string restores the default
+        #[arg(long)]
+        oidc_scopes: Option<String>,
+
+        /// Allowed email domains (comma-separated, replaces the list); an empty string allows anyone
+        #[arg(long, value_delimiter = ',')]
+        oidc_allowed_domain: Vec<String>,
+
+        /// HMAC key for signed URLs (or ${ENV_VAR}); an empty string serves unsigned requests again
+        #[arg(long)]
+        url_signing_secret: Option<String>,
+
+        /// Gzip request bodies of at least this size for the backend; 0 sends them as received
+        #[arg(long, value_parser = parse_size)]
+        compress_requests: Option<u64>,
+
+        /// Backend connection pool settings (max_connections, max_idle, idle_timeout, max_lifetime); an empty string uses the global ones
+        #[arg(long, value_parser = parse_pool)]
+        pool: Option<String>,
+
+        /// Monthly quota (requests=N, bytes=SIZE); an empty string removes it
+        #[arg(long, value_parser = parse_quota)]
+        quota: Option<String>,
+
+        /// Response contract (headers=, status=, latency=, enforce); an empty string removes it
+        #[arg(long, value_parser = parse_contract)]
+        contract: Option<String>,
+
+        /// Time routing to the mapping starts; an empty string routes it right away
+        #[arg(long, value_parser = parse_schedule)]
+        activate_at: Option<String>,
+
+        /// Time routing to the mapping stops; an empty string keeps it routed
+        #[arg(long, value_parser = parse_schedule)]
+        deactivate_at: Option<String>,
+
+        /// Header limits (count=N, bytes=SIZE, field=SIZE); an empty string leaves only the listener's
+        #[arg(long, value_parser = parse_header_limits)]
+        header_limits: Option<String>,
+
+        /// When the mapping is reachable (cron expression, tz=, status=, page=); an empty string means always
+        #[arg(long, value_parser = parse_access_hours)]
+        access_hours: Option<String>,
+
+        /// Request headers forwarded to the backend (names, prefix-*); an empty string forwards all
+        #[arg(long, value_parser = parse_forward_headers)]
+        forward_headers: Option<String>,
+
+        /// Response header scrubbing profile (off, basic, strict); an empty string follows SCRUB_RESPONSE_HEADERS
+        #[arg(long, value_parser = parse_scrub_headers)]
+        scrub_headers: Option<String>,
+
+        /// Upstream protocol (http/1.0, h2c, close, no-expect); an empty string goes back to pooled HTTP/1.1
+        #[arg(long, value_parser = parse_upstream_protocol)]
+        upstream_protocol: Option<String>,
+
+        /// Backend request signing (aws4,... or hmac,...); an empty string sends requests unsigned again
+        #[arg(long, value_parser = parse_upstream_signing)]
+        upstream_signing: Option<String>,
+
+        /// Startup page settings (on, or after=,refresh=,max_wait=,page=); an empty string removes it
+        #[arg(long, value_parser = parse_startup_page)]
+        startup_page: Option<String>,
+
+        /// Image filter settings (on, or max_width=,max_height=,quality=,max_bytes=); an empty string removes it
+        #[arg(long, value_parser = parse_image_filter)]
+        image_filter: Option<String>,
+
+        /// Minification settings (on, or html,css,js plus max_bytes=,max_ms=); an empty string removes it
+        #[arg(long, value_parser = parse_minify)]
+        minify: Option<String>,
+
+        /// Early Hints (Link values and/or 'forward'); an empty string removes them
+        #[arg(long, value_parser = parse_early_hints)]
+        early_hints: Option<String>,
+
+        /// Hand the mapping to a tenant; an empty string gives it back to the operator
+        #[arg(long)]
+        owner: Option<String>,
+    },
+
+    /// Flip live traffic of a mapping between its blue and green slot
+    Switch {
+        /// Domain name
+        domain: String,
+
+        /// Frontend URI path of the mapping
+        #[arg(short = 'f', long)]
+        frontend: Option<String>,
+
+        /// Slot that should receive traffic
+        #[arg(long, value_parser = ["blue", "green"])]
+        to: String,
+
+        /// Seconds after the switch during which an error spike rolls it back (0 = off)
+        #[arg(long, default_value = "300")]
+        probation: u64,
+    },
+
+    /// Queue a mapping's requests during a deploy instead of failing them, or release them
+    Hold {
+        /// Domain name
+        domain: String,
+
+        /// Frontend URI path of the mapping
+        #[arg(short = 'f', long)]
+        frontend: Option<String>,
+
+        /// How long requests may be held at most (e.g. 30s, 2m)
+        #[arg(long = "for", default_value = "60s", value_parser = parse_duration_secs)]
+        duration: u64,
+
+        /// Requests that may wait at once; further ones get a 503
+        #[arg(long, default_value_t = rustproxy::hold::DEFAULT_MAX_QUEUE)]
+        max_queue: usize,
+
+        /// Let held requests through now and end the hold
+        #[arg(long, conflicts_with_all = ["duration", "max_queue"])]
+        release: bool,
     },
 
-    /// Delete a domain mapping
+    /// Delete a domain mapping; it stops matching but can be restored until purged
     Delete {
         /// Domain name
         domain: String,
@@ -94,6 +547,82 @@ enum Commands {
         /// Frontend URI path (to delete specific mapping)
         #[arg(short = 'f', long)]
         frontend: Option<String>,
+
+        /// Remove for good, with API keys and earlier deleted mappings of the domain (or path)
+        #[arg(long)]
+        purge: bool,
+    },
+
+    /// Bring back deleted mappings: the last one deleted at each path, or at --frontend
+    Restore {
+        /// Domain name
+        domain: String,
+
+        /// Frontend URI path (to restore a specific mapping)
+        #[arg(short = 'f', long)]
+        frontend: Option<String>,
+    },
+
+    /// Publish (or remove) an ACME HTTP-01 token for every proxy using this database,
+    /// e.g. from certbot's --manual-auth-hook / --manual-cleanup-hook
+    Challenge {
+        /// Token (CERTBOT_TOKEN)
+        token: String,
+
+        /// Key authorization to answer with (CERTBOT_VALIDATION)
+        #[arg(required_unless_present = "delete")]
+        key_authorization: Option<String>,
+
+        /// Remove the token instead
+        #[arg(long, conflicts_with = "key_authorization")]
+        delete: bool,
+    },
+
+    /// Manage a mapping's API keys (checked in X-Api-Key or the api_key query parameter)
+    ApiKey {
+        #[command(subcommand)]
+        action: ApiKeyAction,
+    },
+
+    /// Manage tenants: customers with an admin API token limited to the mappings they own
+    Tenant {
+        #[command(subcommand)]
+        action: TenantAction,
+    },
+
+    /// Prove a tenant's control of a domain; under REQUIRE_DOMAIN_VERIFICATION its mappings
+    /// are only routed once this has passed. Without a domain, list claims and their state
+    Verify {
+        /// Domain name
+        domain: Option<String>,
+
+        /// Tenant making the claim (default: --tenant)
+        #[arg(long)]
+        owner: Option<String>,
+
+        /// Look for the token now: dns (TXT record) or http (file); without it, print where to publish it
+        #[arg(long)]
+        check: Option<Method>,
+
+        /// Record the claim as proven without checking (operator only)
+        #[arg(long, conflicts_with = "check")]
+        trust: bool,
+    },
+
+    /// Ban IPs or CIDR ranges from every domain, for a while or until removed
+    Ban {
+        #[command(subcommand)]
+        action: BanAction,
+    },
+
+    /// Print a time-limited link to a mapping that has a URL signing secret
+    SignUrl {
+        /// Full URL to sign, e.g. https://files.example.com/dl/report.pdf
+        url: String,
+
+        /// How long the link works, e.g. 3600, 30m, 7d
+        #[arg(long, default_value = "1h", value_parser = parse_duration_secs)]
+        expires: u64,
     },
 
     /// List all mappings
@@ -102,151 +631,1896 @@ enum Commands {
         #[arg(short = 'd', long)]
         domain: Option<String>,
 
-        /// Output as JSON
+        /// List deleted mappings (that can be restored) instead
+        #[arg(long)]
+        deleted: bool,
+
+        /// Output as JSON (same as --output json)
         #[arg(long)]
         json: bool,
     },
-}
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+    /// Write mappings as a spreadsheet-friendly file (to review or bulk edit and import again),
+    /// or as nginx server blocks or a Caddyfile that route the same way
+    Export {
+        /// Only this domain's mappings
+        #[arg(short = 'd', long)]
+        domain: Option<String>,
 
-    // Initialize database
-    let db = DatabaseManager::new(&args.db_path)?;
+        /// File format
+        #[arg(long, default_value = "csv", value_parser = ["csv", "nginx", "caddy"])]
+        format: String,
 
-    match args.command {
-        Commands::Add {
-            domain,
-            port,
-            frontend,
-            backend,
-            both,
-            server,
-            ports,
-        } => {
-            let front_uri = both.as_ref().or(frontend.as_ref()).map(|s| s.as_str()).unwrap_or("");
-            let back_uri = both.as_ref().or(backend.as_ref()).map(|s| s.as_str()).unwrap_or("");
+        /// File to write instead of stdout
+        file: Option<PathBuf>,
+    },
 
-            let mapping = db.add_mapping(&domain, front_uri, port, back_uri, server.as_deref(), ports.as_deref(), None, None, None)?;
+    /// Show what importing an exported (and edited) file would change; --apply changes it
+    Import {
+        /// File to read; `-` reads stdin
+        file: PathBuf,
 
-            println!("Added mapping:");
-            print_mapping(&mapping);
-        }
+        /// File format
+        #[arg(long, default_value = "csv", value_parser = ["csv"])]
+        format: String,
 
-        Commands::Update {
-            domain,
-            port,
-            frontend,
-            backend,
-            both,
-            server,
-            current_frontend,
-        } => {
-            let front_uri_for_lookup = current_frontend.as_ref().or(frontend.as_ref()).map(|s| s.as_str()).unwrap_or("");
+        /// Only this domain's mappings: rows must be for it, and --prune only deletes its mappings
+        #[arg(short = 'd', long)]
+        domain: Option<String>,
 
-            // Find existing mapping
-            let existing = db.find_by_domain_and_uri(&domain, front_uri_for_lookup)?;
+        /// Make the changes, all of them or none; without it nothing is changed
+        #[arg(long)]
+        apply: bool,
 
-            match existing {
-                Some(mapping) => {
-                    let new_front = both.as_ref().or(frontend.as_ref()).map(|s| s.as_str());
-                    let new_back = both.as_ref().or(backend.as_ref()).map(|s| s.as_str());
+        /// Also delete the mappings the file doesn't list (they stay restorable)
+        #[arg(long)]
+        prune: bool,
+    },
 
-                    db.update_mapping(&mapping.id, new_front, new_back, port, server.as_deref())?;
-                    println!("Updated mapping for {} ({})", domain, front_uri_for_lookup);
-                }
-                None => {
-                    eprintln!("No mapping found for {} with frontend URI '{}'", domain, front_uri_for_lookup);
-                    std::process::exit(1);
-                }
-            }
-        }
+    /// Requests and bytes per mapping in a month, as counted by the proxies using this database
+    Usage {
+        /// Month as YYYY-MM (default: the current one)
+        #[arg(long)]
+        period: Option<String>,
 
-        Commands::Delete { domain, frontend } => {
-            let deleted = db.delete_mapping(&domain, frontend.as_deref())?;
+        /// Output as JSON (same as --output json)
+        #[arg(long)]
+        json: bool,
+    },
 
-            if deleted > 0 {
-                println!("Deleted {} mapping(s) for {}", deleted, domain);
-            } else {
-                eprintln!("No mappings found for {}", domain);
-                std::process::exit(1);
-            }
-        }
+    /// Report duplicate, unreachable and self-looping mappings (exits 1 if there are any)
+    Lint {
+        /// Address the proxy binds
+        #[arg(long, env = "HTTP_HOST", default_value = "0.0.0.0")]
+        http_host: String,
 
-        Commands::List { domain, json } => {
-            let mappings = db.list_mappings(domain.as_deref())?;
+        /// Proxy HTTP port
+        #[arg(long, env = "HTTP_PORT", default_value = "8080")]
+        http_port: u16,
 
-            if mappings.is_empty() {
-                if domain.is_some() {
-                    println!("No mappings found for domain: {}", domain.unwrap());
-                } else {
-                    println!("No mappings found");
-                }
-                return Ok(());
-            }
+        /// Proxy HTTPS port
+        #[arg(long, env = "HTTPS_PORT", default_value = "8443")]
+        https_port: u16,
 
-            if json {
-                let json_output: Vec<serde_json::Value> = mappings
-                    .iter()
-                    .map(|m| {
-                        serde_json::json!({
-                            "id": m.id,
-                            "domain": m.domain,
-                            "front_uri": m.front_uri,
-                            "back_port": m.back_port,
-                            "back_uri": m.back_uri,
-                            "backend": m.backend,
-                            "back_ports": m.back_ports,
-                            "allowed_ips": m.allowed_ips,
-                            "auth_type": m.auth_type,
-                            "created_at": m.created_at,
-                            "updated_at": m.updated_at,
-                        })
-                    })
-                    .collect();
-                println!("{}", serde_json::to_string_pretty(&json_output)?);
-            } else {
-                println!("{:<40} {:<15} {:<8} {:<15} {:<30}",
-                    "DOMAIN", "FRONT_URI", "PORT", "BACK_URI", "BACKEND");
-                println!("{}", "-".repeat(108));
+        /// Whether the proxy collapses repeated slashes in request paths
+        #[arg(long, env = "MERGE_SLASHES", default_value = "true", action = clap::ArgAction::Set)]
+        merge_slashes: bool,
 
-                for mapping in &mappings {
-                    let backend = mapping.backend.as_deref().unwrap_or("localhost");
-                    println!("{:<40} {:<15} {:<8} {:<15} {:<30}",
-                        mapping.domain,
-                        if mapping.front_uri.is_empty() { "/" } else { &mapping.front_uri },
-                        mapping.back_port,
-                        if mapping.back_uri.is_empty() { "/" } else { &mapping.back_uri },
-                        backend
-                    );
-                }
+        /// Output as JSON (same as --output json)
+        #[arg(long)]
+        json: bool,
+    },
+}
 
-                println!("\nTotal: {} mapping(s)", mappings.len());
-            }
-        }
-    }
+#[derive(Subcommand, Debug)]
+enum ApiKeyAction {
+    /// Create a key; the mapping then only accepts requests with a valid key
+    Add {
+        /// Domain name
+        domain: String,
 
-    Ok(())
-}
+        /// Frontend URI path of the mapping
+        #[arg(short = 'f', long)]
+        frontend: Option<String>,
 
-fn print_mapping(mapping: &rustproxy::Mapping) {
-    println!("  ID:         {}", mapping.id);
-    println!("  Domain:     {}", mapping.domain);
-    println!("  Front URI:  /{}", mapping.front_uri);
-    if let Some(ref ports) = mapping.back_ports {
-        println!("  HA Ports:   {} (round-robin)", ports);
-    } else {
-        println!("  Back Port:  {}", mapping.back_port);
-    }
-    println!("  Back URI:   /{}", mapping.back_uri);
-    if let Some(ref backend) = mapping.backend {
-        println!("  Backend:    {}", backend);
-    }
-    if let Some(ref ips) = mapping.allowed_ips {
-        println!("  Allowed IPs: {}", ips);
-    }
-    if let Some(ref auth) = mapping.auth_type {
-        println!("  Auth Type:  {}", auth);
+        /// Who or what the key is for, e.g. ci or partner-acme
+        #[arg(long)]
+        name: String,
+
+        /// Expiry: RFC 3339 time, YYYY-MM-DD, or a lifetime like 90d
+        #[arg(long, value_parser = parse_expiry)]
+        expires: Option<String>,
+    },
+
+    /// List keys, of one domain or all
+    List {
+        /// Domain name
+        domain: Option<String>,
+
+        /// Output as JSON (same as --output json)
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Revoke a key by its ID
+    Revoke {
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TenantAction {
+    /// Create a tenant and print its admin API token
+    Add {
+        /// Short ID mappings are owned by, e.g. acme
+        id: String,
+
+        /// Display name
+        #[arg(long)]
+        name: Option<String>,
+    },
+
+    /// List tenants with the number of mappings each owns
+    List {
+        /// Output as JSON (same as --output json)
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Issue a new admin API token; the old one stops working
+    Token {
+        id: String,
+    },
+
+    /// Remove a tenant that owns no mappings
+    Remove {
+        id: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BanAction {
+    /// Ban an IP (203.0.113.7) or range (198.51.100.0/24, 2001:db8::/32)
+    Add {
+        network: Network,
+
+        /// How long the ban lasts, e.g. 3600, 30m, 7d (default: until removed)
+        #[arg(long, value_parser = parse_duration_secs)]
+        duration: Option<u64>,
+
+        /// Why, for the list
+        #[arg(long)]
+        reason: Option<String>,
+    },
+
+    /// List bans in force, given by hand or for rate-limit hits
+    List {
+        /// Output as JSON (same as --output json)
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Lift a ban by its ID, or every ban of an IP or range
+    Remove {
+        id_or_network: String,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+    let out = Output { format: args.output.parse().unwrap_or(Format::Table), quiet: args.quiet };
+    if let Err(e) = run(args, out) {
+        out.fail(Failure::of(&e), &format!("Error: {:#}", e));
+    }
+}
+
+fn run(args: Args, out: Output) -> Result<()> {
+    // Initialize database
+    let db = DatabaseManager::new(&args.db_path)?;
+
+    let tenant = args.tenant.as_deref().filter(|t| !t.is_empty());
+    if let Some(t) = tenant {
+        if db.get_tenant(t)?.is_none() {
+            out.fail(Failure::NotFound, &format!("No tenant {}", t));
+        }
+    }
+
+    match args.command {
+        Commands::Add {
+            domain,
+            port,
+            from_url,
+            template,
+            frontend,
+            backend,
+            both,
+            server,
+            ports,
+            green_port,
+            green_server,
+            experiment,
+            content_types,
+            max_body,
+            preserve_path,
+            log_level,
+            log_sample,
+            body_rewrites,
+            html_base,
+            rate_limit,
+            fallback_backend,
+            stale_if_error,
+            tls_ca,
+            tls_pin,
+            tls_server_name,
+            tls_insecure,
+            egress_proxy,
+            document_root,
+            oidc_issuer,
+            oidc_client_id,
+            oidc_client_secret,
+            oidc_scopes,
+            oidc_allowed_domain,
+            url_signing_secret,
+            compress_requests,
+            pool,
+            quota,
+            contract,
+            activate_at,
+            deactivate_at,
+            header_limits,
+            access_hours,
+            forward_headers,
+            scrub_headers,
+            upstream_protocol,
+            upstream_signing,
+            startup_page,
+            image_filter,
+            minify,
+            early_hints,
+            owner,
+        } => {
+            let (domain, port, front_uri, back_uri, server) = match from_url {
+                Some(route) => (route.domain, route.back_port, route.front_uri, route.back_uri, route.backend),
+                None => (
+                    domain.expect("required without --from-url"),
+                    port.expect("required without --from-url"),
+                    both.clone().or(frontend).unwrap_or_default(),
+                    both.or(backend).unwrap_or_default(),
+                    server,
+                ),
+            };
+            let owner = match (tenant, owner.filter(|o| !o.is_empty())) {
+                (Some(t), Some(o)) if o != t => operator_only(out, "--owner"),
+                (Some(t), _) => Some(t.to_string()),
+                (None, owner) => owner,
+            };
+            if let Some(ref o) = owner {
+                if db.get_tenant(o)?.is_none() {
+                    out.fail(Failure::NotFound, &format!("No tenant {}", o));
+                }
+            }
+            // A tenant can't add routes under a domain someone else serves
+            if let Some(t) = tenant {
+                if db.list_mappings(Some(&domain))?.iter().any(|m| m.owner.as_deref() != Some(t)) {
+                    out.fail(Failure::Forbidden, &format!("{} is served by another tenant or the operator", domain));
+                }
+            }
+            let (mut mapping, companions) = match template.as_deref() {
+                Some(name) => {
+                    let target = serde_json::json!({
+                        "domain": domain,
+                        "back_port": port,
+                        "backend": server,
+                        "back_ports": ports,
+                        "owner": owner,
+                    });
+                    let template = app_templates::get(name).expect("checked by clap");
+                    let applied = match db.apply_changes(&template.changes(target.as_object().expect("an object")))? {
+                        Ok(applied) => applied,
+                        Err(rejected) => out.fail(Failure::Conflict, &format!("Nothing added: {}", rejected.reason)),
+                    };
+                    let mut added = Vec::new();
+                    for id in applied.iter().flat_map(|a| &a.ids) {
+                        added.extend(db.get_mapping_by_id(id)?);
+                    }
+                    let app = added.remove(0);
+                    (app, added)
+                }
+                None => (db.add_mapping(&domain, &front_uri, port, &back_uri, server.as_deref(), ports.as_deref(), None, None, None)?, Vec::new()),
+            };
+            if let Some(gp) = green_port {
+                db.set_green_target(&mapping.id, green_server.as_deref(), gp)?;
+                mapping.green_port = Some(gp);
+                mapping.green_backend = green_server;
+            }
+            if let Some(exp) = experiment.filter(|e| !e.is_empty()) {
+                db.set_experiment(&mapping.id, Some(&exp))?;
+                mapping.experiment = Some(exp);
+            }
+            if content_types.is_some() || max_body.is_some() {
+                mapping.allowed_content_types = content_types.filter(|c| !c.trim().is_empty());
+                mapping.max_body_bytes = max_body.filter(|&n| n > 0);
+                db.set_body_limits(&mapping.id, mapping.allowed_content_types.as_deref(), mapping.max_body_bytes)?;
+            }
+            if preserve_path {
+                db.set_preserve_path(&mapping.id, true)?;
+                mapping.preserve_path = true;
+            }
+            if log_level.is_some() || log_sample.is_some() {
+                mapping.log_level = log_level.filter(|l| !l.is_empty());
+                mapping.log_sample = log_sample.filter(|s| !s.is_empty());
+                db.set_log_policy(&mapping.id, mapping.log_level.as_deref(), mapping.log_sample.as_deref())?;
+            }
+            if let Some(rules) = body_rewrites.filter(|r| !r.is_empty()) {
+                db.set_body_rewrites(&mapping.id, Some(&rules))?;
+                mapping.body_rewrites = Some(rules);
+            }
+            if let Some(mode) = html_base.filter(|m| !m.is_empty()) {
+                db.set_html_base(&mapping.id, Some(&mode))?;
+                mapping.html_base = Some(mode);
+            }
+            if let Some(limit) = rate_limit.filter(|l| !l.is_empty()) {
+                db.set_rate_limit(&mapping.id, Some(&limit))?;
+                mapping.rate_limit = Some(limit);
+            }
+            if let Some(target) = fallback_backend.filter(|t| !t.is_empty()) {
+                db.set_fallback_backend(&mapping.id, Some(&target))?;
+                mapping.fallback_backend = Some(target);
+            }
+            if let Some(secs) = stale_if_error.filter(|&s| s > 0) {
+                db.set_stale_if_error(&mapping.id, Some(secs))?;
+                mapping.stale_if_error = Some(secs);
+            }
+            let upstream_tls = merge_upstream_tls(None, tls_ca, tls_pin, tls_server_name, Some(tls_insecure));
+            if upstream_tls.is_some() {
+                db.set_upstream_tls(&mapping.id, upstream_tls.as_deref())?;
+                mapping.upstream_tls = upstream_tls;
+            }
+            if let Some(egress) = egress_proxy.filter(|e| !e.is_empty()) {
+                db.set_egress_proxy(&mapping.id, Some(&egress))?;
+                mapping.egress_proxy = Some(egress);
+            }
+            if let Some(root) = document_root.filter(|r| !r.is_empty()) {
+                db.set_document_root(&mapping.id, Some(&root))?;
+                mapping.document_root = Some(root);
+            }
+            if oidc_issuer.is_some() {
+                let oidc = merge_oidc(None, oidc_issuer, oidc_client_id, oidc_client_secret, oidc_scopes, oidc_allowed_domain)?;
+                db.set_oidc(&mapping.id, oidc.as_deref())?;
+                mapping.oidc = oidc;
+            }
+            if let Some(secret) = url_signing_secret.filter(|s| !s.is_empty()) {
+                db.set_url_signing_secret(&mapping.id, Some(&secret))?;
+                mapping.url_signing_secret = Some(secret);
+            }
+            if let Some(min) = compress_requests.filter(|&n| n > 0) {
+                db.set_compress_requests(&mapping.id, Some(min))?;
+                mapping.compress_requests = Some(min);
+            }
+            if let Some(pool) = pool.filter(|p| !p.is_empty()) {
+                db.set_pool(&mapping.id, Some(&pool))?;
+                mapping.pool = Some(pool);
+            }
+            if let Some(quota) = quota.filter(|q| !q.is_empty()) {
+                db.set_quota(&mapping.id, Some(&quota))?;
+                mapping.quota = Some(quota);
+            }
+            if let Some(contract) = contract.filter(|c| !c.is_empty()) {
+                db.set_contract(&mapping.id, Some(&contract))?;
+                mapping.contract = Some(contract);
+            }
+            if let Some(at) = activate_at.filter(|t| !t.is_empty()) {
+                db.set_activate_at(&mapping.id, Some(&at))?;
+                mapping.activate_at = Some(at);
+            }
+            if let Some(at) = deactivate_at.filter(|t| !t.is_empty()) {
+                db.set_deactivate_at(&mapping.id, Some(&at))?;
+                mapping.deactivate_at = Some(at);
+            }
+            if let Some(limits) = header_limits.filter(|l| !l.is_empty()) {
+                db.set_header_limits(&mapping.id, Some(&limits))?;
+                mapping.header_limits = Some(limits);
+            }
+            if let Some(hours) = access_hours.filter(|h| !h.is_empty()) {
+                db.set_access_hours(&mapping.id, Some(&hours))?;
+                mapping.access_hours = Some(hours);
+            }
+            if let Some(list) = forward_headers.filter(|l| !l.is_empty()) {
+                db.set_forward_headers(&mapping.id, Some(&list))?;
+                mapping.forward_headers = Some(list);
+            }
+            if let Some(profile) = scrub_headers.filter(|p| !p.is_empty()) {
+                db.set_scrub_headers(&mapping.id, Some(&profile))?;
+                mapping.scrub_headers = Some(profile);
+            }
+            if let Some(protocol) = upstream_protocol.filter(|p| !p.is_empty()) {
+                db.set_upstream_protocol(&mapping.id, Some(&protocol))?;
+                mapping.upstream_protocol = Some(protocol);
+            }
+            if let Some(signing) = upstream_signing.filter(|s| !s.is_empty()) {
+                db.set_upstream_signing(&mapping.id, Some(&signing))?;
+                mapping.upstream_signing = Some(signing);
+            }
+            if let Some(startup) = startup_page.filter(|s| !s.is_empty()) {
+                db.set_startup_page(&mapping.id, Some(&startup))?;
+                mapping.startup_page = Some(startup);
+            }
+            if let Some(images) = image_filter.filter(|i| !i.is_empty()) {
+                db.set_image_filter(&mapping.id, Some(&images))?;
+                mapping.image_filter = Some(images);
+            }
+            if let Some(minify) = minify.filter(|m| !m.is_empty()) {
+                db.set_minify(&mapping.id, Some(&minify))?;
+                mapping.minify = Some(minify);
+            }
+            if let Some(hints) = early_hints.filter(|h| !h.is_empty()) {
+                db.set_early_hints(&mapping.id, Some(&hints))?;
+                mapping.early_hints = Some(hints);
+            }
+            if let Some(owner) = owner {
+                db.set_owner(&mapping.id, Some(&owner))?;
+                mapping.owner = Some(owner);
+            }
+
+            if companions.is_empty() {
+                out.done(format_args!("Added mapping:\n{}", describe_mapping(&mapping)), &mapping.id, mapping_json(&mapping));
+            } else {
+                let template = app_templates::get(template.as_deref().unwrap_or_default()).expect("checked by clap");
+                let mut text = format!("Added mapping:\n{}\n\nand for {}:", describe_mapping(&mapping), template.about);
+                for (m, route) in companions.iter().zip(&template.routes[1..]) {
+                    text.push_str(&format!("\n  /{:<20} {}", m.front_uri, route.about));
+                }
+                let all: Vec<&rustproxy::Mapping> = std::iter::once(&mapping).chain(&companions).collect();
+                let ids: Vec<&str> = all.iter().map(|m| m.id.as_str()).collect();
+                out.done(text, ids.join("\n"), serde_json::Value::Array(all.into_iter().map(mapping_json).collect()));
+            }
+            if let Some(ref owner) = mapping.owner {
+                verification_hint(&db, out, &mapping.domain, owner)?;
+            }
+        }
+
+        Commands::Update {
+            domain,
+            port,
+            frontend,
+            backend,
+            both,
+            server,
+            current_frontend,
+            if_version,
+            green_port,
+            green_server,
+            experiment,
+            content_types,
+            max_body,
+            preserve_path,
+            log_level,
+            log_sample,
+            body_rewrites,
+            html_base,
+            rate_limit,
+            fallback_backend,
+            stale_if_error,
+            tls_ca,
+            tls_pin,
+            tls_server_name,
+            tls_insecure,
+            egress_proxy,
+            document_root,
+            oidc_issuer,
+            oidc_client_id,
+            oidc_client_secret,
+            oidc_scopes,
+            oidc_allowed_domain,
+            url_signing_secret,
+            compress_requests,
+            pool,
+            quota,
+            contract,
+            activate_at,
+            deactivate_at,
+            header_limits,
+            access_hours,
+            forward_headers,
+            scrub_headers,
+            upstream_protocol,
+            upstream_signing,
+            startup_page,
+            image_filter,
+            minify,
+            early_hints,
+            owner,
+        } => {
+            if owner.is_some() && tenant.is_some() {
+                operator_only(out, "--owner");
+            }
+            let front_uri_for_lookup = current_frontend.as_ref().or(frontend.as_ref()).map(|s| s.as_str()).unwrap_or("");
+
+            // Find existing mapping
+            let existing = db.find_by_domain_and_uri(&domain, front_uri_for_lookup)?;
+
+            match existing {
+                Some(mapping) => {
+                    check_owner(out, &mapping, tenant);
+                    if let Some(owner) = owner.as_deref().filter(|o| !o.is_empty()) {
+                        if db.get_tenant(owner)?.is_none() {
+                            anyhow::bail!("no tenant {}", owner);
+                        }
+                    }
+                    // Every field goes in one change, checked against --if-version and
+                    // written in one transaction: the mapping moves on by one version
+                    let mut set = serde_json::Map::new();
+                    let mut put = |field: &str, value: serde_json::Value| {
+                        set.insert(field.to_string(), value);
+                    };
+                    let text = |value: &str| serde_json::json!(Some(value).filter(|v| !v.is_empty()));
+                    if let Some(front) = both.as_ref().or(frontend.as_ref()) {
+                        put("front_uri", front.as_str().into());
+                    }
+                    if let Some(back) = both.as_ref().or(backend.as_ref()) {
+                        put("back_uri", back.as_str().into());
+                    }
+                    if let Some(port) = port {
+                        put("back_port", port.into());
+                    }
+                    if let Some(srv) = server.as_deref() {
+                        put("backend", srv.into());
+                    }
+                    if let Some(gp) = green_port {
+                        put("green_backend", serde_json::json!(green_server));
+                        put("green_port", gp.into());
+                    }
+                    if let Some(exp) = experiment {
+                        put("experiment", text(&exp));
+                    }
+                    if content_types.is_some() || max_body.is_some() {
+                        let types = match content_types {
+                            Some(c) => Some(c).filter(|c| !c.trim().is_empty()),
+                            None => mapping.allowed_content_types.clone(),
+                        };
+                        let max = match max_body {
+                            Some(n) => Some(n).filter(|&n| n > 0),
+                            None => mapping.max_body_bytes,
+                        };
+                        put("allowed_content_types", serde_json::json!(types));
+                        put("max_body_bytes", serde_json::json!(max));
+                    }
+                    if let Some(preserve) = preserve_path {
+                        put("preserve_path", preserve.into());
+                    }
+                    if log_level.is_some() || log_sample.is_some() {
+                        let level = match log_level {
+                            Some(l) => Some(l).filter(|l| !l.is_empty()),
+                            None => mapping.log_level.clone(),
+                        };
+                        let sample = match log_sample {
+                            Some(s) => Some(s).filter(|s| !s.is_empty()),
+                            None => mapping.log_sample.clone(),
+                        };
+                        put("log_level", serde_json::json!(level));
+                        put("log_sample", serde_json::json!(sample));
+                    }
+                    if let Some(rules) = body_rewrites {
+                        put("body_rewrites", text(&rules));
+                    }
+                    if let Some(mode) = html_base {
+                        put("html_base", text(&mode));
+                    }
+                    if let Some(limit) = rate_limit {
+                        put("rate_limit", text(&limit));
+                    }
+                    if let Some(target) = fallback_backend {
+                        put("fallback_backend", text(&target));
+                    }
+                    if let Some(secs) = stale_if_error {
+                        put("stale_if_error", serde_json::json!(Some(secs).filter(|&s| s > 0)));
+                    }
+                    if tls_ca.is_some() || !tls_pin.is_empty() || tls_server_name.is_some() || tls_insecure.is_some() {
+                        let merged = merge_upstream_tls(mapping.upstream_tls.as_deref(), tls_ca, tls_pin, tls_server_name, tls_insecure);
+                        put("upstream_tls", serde_json::json!(merged));
+                    }
+                    if let Some(egress) = egress_proxy {
+                        put("egress_proxy", text(&egress));
+                    }
+                    if let Some(root) = document_root {
+                        put("document_root", text(&root));
+                    }
+                    if oidc_issuer.is_some() || oidc_client_id.is_some() || oidc_client_secret.is_some()
+                        || oidc_scopes.is_some() || !oidc_allowed_domain.is_empty() {
+                        let merged = merge_oidc(mapping.oidc.as_deref(), oidc_issuer, oidc_client_id, oidc_client_secret, oidc_scopes, oidc_allowed_domain)?;
+                        put("oidc", serde_json::json!(merged));
+                    }
+                    if let Some(secret) = url_signing_secret {
+                        put("url_signing_secret", text(&secret));
+                    }
+                    if let Some(min) = compress_requests {
+                        put("compress_requests", serde_json::json!(Some(min).filter(|&n| n > 0)));
+                    }
+                    if let Some(pool) = pool {
+                        put("pool", text(&pool));
+                    }
+                    if let Some(quota) = quota {
+                        put("quota", text(&quota));
+                    }
+                    if let Some(contract) = contract {
+                        put("contract", text(&contract));
+                    }
+                    if let Some(at) = activate_at {
+                        put("activate_at", text(&at));
+                    }
+                    if let Some(at) = deactivate_at {
+                        put("deactivate_at", text(&at));
+                    }
+                    if let Some(limits) = header_limits {
+                        put("header_limits", text(&limits));
+                    }
+                    if let Some(hours) = access_hours {
+                        put("access_hours", text(&hours));
+                    }
+                    if let Some(list) = forward_headers {
+                        put("forward_headers", text(&list));
+                    }
+                    if let Some(profile) = scrub_headers {
+                        put("scrub_headers", text(&profile));
+                    }
+                    if let Some(protocol) = upstream_protocol {
+                        put("upstream_protocol", text(&protocol));
+                    }
+                    if let Some(signing) = upstream_signing {
+                        put("upstream_signing", text(&signing));
+                    }
+                    if let Some(startup) = startup_page {
+                        put("startup_page", text(&startup));
+                    }
+                    if let Some(images) = image_filter {
+                        put("image_filter", text(&images));
+                    }
+                    if let Some(minify) = minify {
+                        put("minify", text(&minify));
+                    }
+                    if let Some(hints) = early_hints {
+                        put("early_hints", text(&hints));
+                    }
+                    if let Some(owner) = owner.as_deref() {
+                        put("owner", text(owner));
+                    }
+                    let change = MappingChange::Update { id: Some(mapping.id.clone()), domain: None, front_uri: String::new(), version: if_version, set };
+                    if let Err(rejected) = db.apply_changes(&[change])? {
+                        match db.get_mapping_by_id(&mapping.id)?.map_or(mapping.version, |m| m.version) {
+                            now if now != if_version => out.fail(Failure::Conflict, &format!("{} ({}) changed since version {}: it is at version {}; check it and retry",
+                                domain, front_uri_for_lookup, if_version, now)),
+                            _ => out.fail(Failure::Conflict, &format!("{} ({}): {}", domain, front_uri_for_lookup, rejected.reason)),
+                        }
+                    }
+                    let updated = db.get_mapping_by_id(&mapping.id)?.unwrap_or(mapping);
+                    out.done(
+                        format_args!("Updated mapping for {} ({}), now at version {}", domain, front_uri_for_lookup, updated.version),
+                        cli_output::record([&updated.id, &updated.version.to_string()]),
+                        mapping_json(&updated),
+                    );
+                    if let Some(owner) = owner.filter(|o| !o.is_empty()) {
+                        verification_hint(&db, out, &updated.domain, &owner)?;
+                    }
+                }
+                None => out.fail(Failure::NotFound, &format!("No mapping found for {} with frontend URI '{}'", domain, front_uri_for_lookup)),
+            }
+        }
+
+        Commands::Switch { domain, frontend, to, probation } => {
+            let front_uri = frontend.as_deref().unwrap_or("");
+            let mapping = match db.find_by_domain_and_uri(&domain, front_uri)? {
+                Some(m) => m,
+                None => out.fail(Failure::NotFound, &format!("No mapping found for {} with frontend URI '{}'", domain, front_uri)),
+            };
+            check_owner(out, &mapping, tenant);
+
+            let probation = (probation > 0).then(|| std::time::Duration::from_secs(probation));
+            refused(out, db.switch_slot(&mapping.id, &to, probation));
+
+            out.done(
+                format_args!("Switched {} (/{}) to {}", domain, mapping.front_uri, to),
+                cli_output::record([&mapping.id, &to]),
+                serde_json::json!({
+                    "id": mapping.id,
+                    "domain": mapping.domain,
+                    "front_uri": mapping.front_uri,
+                    "active_slot": to,
+                    "probation_secs": probation.map(|p| p.as_secs()),
+                }),
+            );
+            if let Some(p) = probation {
+                out.hint(format_args!("Probation: automatic rollback on error spike for the next {}s", p.as_secs()));
+            }
+        }
+
+        Commands::Hold { domain, frontend, duration, max_queue, release } => {
+            let front_uri = frontend.as_deref().unwrap_or("");
+            let mapping = match db.find_by_domain_and_uri(&domain, front_uri)? {
+                Some(m) => m,
+                None => out.fail(Failure::NotFound, &format!("No mapping found for {} with frontend URI '{}'", domain, front_uri)),
+            };
+            check_owner(out, &mapping, tenant);
+
+            let json = |until: Option<String>| serde_json::json!({
+                "id": mapping.id,
+                "domain": mapping.domain,
+                "front_uri": mapping.front_uri,
+                "held_until": until,
+                "max_queue": max_queue,
+            });
+            if release {
+                db.set_hold(&mapping.id, None)?;
+                out.done(format_args!("Released {} (/{})", domain, mapping.front_uri), &mapping.id, json(None));
+            } else {
+                let until = chrono::Utc::now() + chrono::Duration::seconds(duration.min(86400) as i64);
+                let hold = Hold { until, max_queue };
+                db.set_hold(&mapping.id, Some(&hold.to_string()))?;
+                out.done(
+                    format_args!("Holding requests for {} (/{}) until {} (queue up to {})", domain, mapping.front_uri, until.to_rfc3339(), max_queue),
+                    cli_output::record([&mapping.id, &until.to_rfc3339()]),
+                    json(Some(until.to_rfc3339())),
+                );
+            }
+        }
+
+        Commands::Delete { domain, frontend, purge } => {
+            if let Some(t) = tenant {
+                let front_uri = frontend.as_deref().map(|f| f.trim_matches('/'));
+                let mut affected = db.list_mappings(Some(&domain))?;
+                if purge {
+                    affected.extend(db.list_deleted_mappings(Some(&domain))?.into_iter().map(|(m, _)| m));
+                }
+                let foreign = affected.into_iter()
+                    .filter(|m| front_uri.is_none_or(|f| m.front_uri == f))
+                    .any(|m| m.owner.as_deref() != Some(t));
+                if foreign {
+                    out.fail(Failure::Forbidden, &format!("{} has mappings not owned by tenant {}", domain, t));
+                }
+            }
+            let deleted = match purge {
+                true => db.purge_mapping(&domain, frontend.as_deref())?,
+                false => db.delete_mapping(&domain, frontend.as_deref())?,
+            };
+
+            if deleted == 0 {
+                out.fail(Failure::NotFound, &format!("No mappings found for {}", domain));
+            }
+            let json = serde_json::json!({ "domain": domain, "front_uri": frontend, "deleted": deleted, "purged": purge });
+            if purge {
+                out.done(format_args!("Purged {} mapping(s) for {}", deleted, domain), deleted, json);
+            } else {
+                out.done(format_args!("Deleted {} mapping(s) for {}", deleted, domain), deleted, json);
+                let frontend = frontend.map(|f| format!(" -f {}", f)).unwrap_or_default();
+                out.hint(format_args!("Undo with: rustproxy-mapping restore {}{}", domain, frontend));
+            }
+        }
+
+        Commands::Restore { domain, frontend } => {
+            if let Some(t) = tenant {
+                let front_uri = frontend.as_deref().map(|f| f.trim_matches('/'));
+                let foreign = db.list_deleted_mappings(Some(&domain))?.into_iter()
+                    .filter(|(m, _)| front_uri.is_none_or(|f| m.front_uri == f))
+                    .any(|(m, _)| m.owner.as_deref() != Some(t));
+                if foreign {
+                    out.fail(Failure::Forbidden, &format!("{} has deleted mappings not owned by tenant {}", domain, t));
+                }
+            }
+            match db.restore_mapping(&domain, frontend.as_deref()) {
+                Ok(0) => out.fail(Failure::NotFound, &format!("No deleted mappings found for {}", domain)),
+                Ok(restored) => out.done(
+                    format_args!("Restored {} mapping(s) for {}", restored, domain),
+                    restored,
+                    serde_json::json!({ "domain": domain, "front_uri": frontend, "restored": restored }),
+                ),
+                Err(e) => out.fail(Failure::Conflict, &format!("Not restored: {}", e)),
+            }
+        }
+
+        Commands::Challenge { token, key_authorization, delete } => {
+            if tenant.is_some() {
+                operator_only(out, "challenge");
+            }
+            if delete {
+                db.delete_acme_challenge(&token)?;
+                out.done(format_args!("Removed challenge {}", token), &token, serde_json::json!({ "token": token, "published": false }));
+            } else if let Some(key_authorization) = key_authorization {
+                db.put_acme_challenge(&token, &key_authorization)?;
+                out.done(format_args!("Published challenge {}", token), &token, serde_json::json!({ "token": token, "published": true }));
+            }
+        }
+
+        Commands::ApiKey { action } => match action {
+            ApiKeyAction::Add { domain, frontend, name, expires } => {
+                let front_uri = frontend.as_deref().unwrap_or("");
+                let mapping = match db.find_by_domain_and_uri(&domain, front_uri)? {
+                    Some(m) => m,
+                    None => out.fail(Failure::NotFound, &format!("No mapping found for {} with frontend URI '{}'", domain, front_uri)),
+                };
+                check_owner(out, &mapping, tenant);
+                let (key, secret) = refused(out, db.add_api_key(&mapping.id, &name, expires.as_deref()));
+                // The key is the result, so printed even with --quiet
+                match out.format {
+                    Format::Json => out.json(&serde_json::json!({
+                        "id": key.id,
+                        "name": key.name,
+                        "mapping_id": key.mapping_id,
+                        "expires_at": key.expires_at,
+                        "key": secret,
+                    })),
+                    Format::Plain => println!("{}", cli_output::record([&key.id, &secret])),
+                    Format::Table => {
+                        println!("Created API key '{}' for {} (/{})", key.name, domain, mapping.front_uri);
+                        println!("  ID:      {}", key.id);
+                        println!("  Expires: {}", key.expires_at.as_deref().unwrap_or("never"));
+                        println!("  Key:     {}", secret);
+                        out.hint("\nThe key is stored hashed and will not be shown again.");
+                    }
+                }
+            }
+            ApiKeyAction::List { domain, json } => {
+                let out = json_flag(out, json);
+                let mappings: std::collections::HashMap<String, rustproxy::Mapping> = db.list_mappings(domain.as_deref())?
+                    .into_iter()
+                    .filter(|m| owned(m, tenant))
+                    .map(|m| (m.id.clone(), m))
+                    .collect();
+                let keys: Vec<_> = db.list_api_keys(None)?
+                    .into_iter()
+                    .filter(|k| mappings.contains_key(&k.mapping_id))
+                    .collect();
+                if out.is_json() {
+                    out.json(&keys);
+                } else if out.format == Format::Plain {
+                    for key in &keys {
+                        let m = &mappings[&key.mapping_id];
+                        println!("{}", cli_output::record([&key.id, &key.name, &m.domain, &m.front_uri, key.expires_at.as_deref().unwrap_or("")]));
+                    }
+                } else if keys.is_empty() {
+                    println!("No API keys found");
+                } else {
+                    println!("{:<36} {:<20} {:<40} {:<25}", "ID", "NAME", "MAPPING", "EXPIRES");
+                    println!("{}", "-".repeat(124));
+                    for key in &keys {
+                        let m = &mappings[&key.mapping_id];
+                        let expires = match key.expires_at.as_deref() {
+                            Some(e) if key.is_expired() => format!("{} (expired)", e),
+                            Some(e) => e.to_string(),
+                            None => "never".to_string(),
+                        };
+                        println!("{:<36} {:<20} {:<40} {:<25}", key.id, key.name, format!("{}/{}", m.domain, m.front_uri), expires);
+                    }
+                }
+            }
+            ApiKeyAction::Revoke { id } => {
+                if tenant.is_some() {
+                    let key = db.list_api_keys(None)?.into_iter().find(|k| k.id == id);
+                    if let Some(mapping) = key.and_then(|k| db.get_mapping_by_id(&k.mapping_id).transpose()) {
+                        check_owner(out, &mapping?, tenant);
+                    }
+                }
+                if db.delete_api_key(&id)? {
+                    out.done(format_args!("Revoked API key {}", id), &id, serde_json::json!({ "id": id, "revoked": true }));
+                } else {
+                    out.fail(Failure::NotFound, &format!("No API key with ID {}", id));
+                }
+            }
+        },
+
+        Commands::Tenant { action } => {
+            if tenant.is_some() {
+                operator_only(out, "tenant");
+            }
+            match action {
+                TenantAction::Add { id, name } => {
+                    let (record, token) = refused(out, db.add_tenant(&id, name.as_deref().unwrap_or(&id)));
+                    match out.format {
+                        Format::Json => out.json(&serde_json::json!({ "id": record.id, "name": record.name, "token": token })),
+                        Format::Plain => println!("{}", cli_output::record([&record.id, &token])),
+                        Format::Table => {
+                            println!("Created tenant {} ({})", record.id, record.name);
+                            println!("  Admin token: {}", token);
+                            out.hint(format_args!("\nThe token is stored hashed and will not be shown again.\nGive mappings to the tenant with --owner {}.", record.id));
+                        }
+                    }
+                }
+                TenantAction::List { json } => {
+                    let out = json_flag(out, json);
+                    let tenants = db.list_tenants()?;
+                    let mappings = db.list_mappings(None)?;
+                    let count = |id: &str| mappings.iter().filter(|m| m.owner.as_deref() == Some(id)).count();
+                    if out.is_json() {
+                        let rows: Vec<serde_json::Value> = tenants.iter().map(|t| serde_json::json!({
+                            "id": t.id,
+                            "name": t.name,
+                            "mappings": count(&t.id),
+                            "created_at": t.created_at,
+                        })).collect();
+                        out.json(&rows);
+                    } else if out.format == Format::Plain {
+                        for t in &tenants {
+                            println!("{}", cli_output::record([&t.id, &t.name, &count(&t.id).to_string(), &t.created_at]));
+                        }
+                    } else if tenants.is_empty() {
+                        println!("No tenants");
+                    } else {
+                        println!("{:<20} {:<30} {:>8}  CREATED", "ID", "NAME", "MAPPINGS");
+                        println!("{}", "-".repeat(90));
+                        for t in &tenants {
+                            println!("{:<20} {:<30} {:>8}  {}", t.id, t.name, count(&t.id), t.created_at);
+                        }
+                    }
+                }
+                TenantAction::Token { id } => match db.rotate_tenant_token(&id)? {
+                    Some(token) => match out.format {
+                        Format::Json => out.json(&serde_json::json!({ "id": id, "token": token })),
+                        Format::Plain => println!("{}", token),
+                        Format::Table => {
+                            println!("New admin token for {}: {}", id, token);
+                            out.hint("\nThe previous token no longer works.");
+                        }
+                    },
+                    None => out.fail(Failure::NotFound, &format!("No tenant {}", id)),
+                },
+                TenantAction::Remove { id } => {
+                    if refused(out, db.delete_tenant(&id)) {
+                        out.done(format_args!("Removed tenant {}", id), &id, serde_json::json!({ "id": id, "removed": true }));
+                    } else {
+                        out.fail(Failure::NotFound, &format!("No tenant {}", id));
+                    }
+                }
+            }
+        }
+
+        Commands::Verify { domain, owner, check, trust } => {
+            let owner = match (tenant, owner) {
+                (Some(t), Some(o)) if o != t => operator_only(out, "--owner"),
+                (Some(_), _) if trust => operator_only(out, "--trust"),
+                (Some(t), _) => Some(t.to_string()),
+                (None, owner) => owner,
+            };
+            let Some(domain) = domain else {
+                let claims = db.list_domain_verifications(owner.as_deref())?;
+                match out.format {
+                    Format::Json => out.json(&claims),
+                    Format::Plain => {
+                        for c in &claims {
+                            println!("{}", cli_output::record([&c.domain, &c.owner, c.verified_at.as_deref().unwrap_or("")]));
+                        }
+                    }
+                    Format::Table => {
+                        if claims.is_empty() {
+                            println!("No domain claims");
+                        }
+                        for c in &claims {
+                            println!("{:<40} {:<20} {}", c.domain, c.owner, c.verified_at.as_deref().map_or("pending".to_string(), |t| format!("verified {}", t)));
+                        }
+                    }
+                }
+                return Ok(());
+            };
+            let Some(owner) = owner else {
+                out.fail(Failure::Usage, &format!("Which tenant claims {}? Pass --owner or --tenant", domain));
+            };
+            if db.get_tenant(&owner)?.is_none() {
+                out.fail(Failure::NotFound, &format!("No tenant {}", owner));
+            }
+            let claim = db.start_domain_verification(&domain, &owner)?;
+            let json = |verified: bool| serde_json::json!({ "domain": claim.domain, "owner": owner, "verified": verified });
+            if claim.verified_at.is_some() {
+                out.done(format_args!("{} has already proven control of {}", owner, claim.domain), "verified", json(true));
+            } else if trust {
+                db.mark_domain_verified(&claim.domain, &owner)?;
+                out.done(format_args!("Recorded {} as controlled by {} (not checked)", claim.domain, owner), "verified", json(true));
+            } else if let Some(method) = check {
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+                if let Err(e) = runtime.block_on(domain_verify::check(&claim.domain, &claim.token, method)) {
+                    out.fail(Failure::Failed, &format!("Not verified: {:#}", e));
+                }
+                db.mark_domain_verified(&claim.domain, &owner)?;
+                out.done(format_args!("Verified: {} controls {} ({} check passed)", owner, claim.domain, method), "verified", json(true));
+            } else {
+                // The token to publish is the result, so printed even with --quiet
+                let txt_name = domain_verify::txt_name(&claim.domain);
+                let url = domain_verify::http_url(&claim.domain, &claim.token);
+                match out.format {
+                    Format::Json => out.json(&serde_json::json!({
+                        "domain": claim.domain,
+                        "owner": owner,
+                        "verified": false,
+                        "token": claim.token,
+                        "txt_record": txt_name,
+                        "http_url": url,
+                    })),
+                    Format::Plain => println!("{}", cli_output::record([&claim.token, &txt_name, url.as_deref().unwrap_or("")])),
+                    Format::Table => {
+                        println!("To prove that {} controls {}, publish this token, then run again with --check dns or --check http:", owner, claim.domain);
+                        println!("  DNS:   TXT record {} = \"{}\"", txt_name, claim.token);
+                        if let Some(url) = url {
+                            println!("  HTTP:  {} answering {} (from the server the domain points at now)", url, claim.token);
+                        }
+                    }
+                }
+            }
+        }
+
+        Commands::Ban { action } => {
+            if tenant.is_some() {
+                operator_only(out, "ban");
+            }
+            match action {
+                BanAction::Add { network, duration, reason } => {
+                    let expires_at = duration.map(|secs| chrono::Utc::now() + chrono::Duration::seconds(secs.min(i64::MAX as u64) as i64));
+                    let ban = db.add_ban(&network.to_string(), reason.as_deref(), expires_at.map(|t| t.to_rfc3339()).as_deref(), bans::MANUAL)?;
+                    out.done(
+                        format_args!("Banned {} until {} ({})", ban.network, ban.expires_at.as_deref().unwrap_or("removed"), ban.id),
+                        &ban.id,
+                        serde_json::to_value(&ban)?,
+                    );
+                    out.hint(format_args!("Running proxies pick it up within {}s.", bans::SYNC_INTERVAL.as_secs()));
+                }
+                BanAction::List { json } => {
+                    let out = json_flag(out, json);
+                    db.delete_expired_bans()?;
+                    let bans = db.list_bans()?;
+                    if out.is_json() {
+                        out.json(&bans);
+                    } else if out.format == Format::Plain {
+                        for b in &bans {
+                            println!("{}", cli_output::record([&b.id, &b.network, &b.source, b.expires_at.as_deref().unwrap_or(""), b.reason.as_deref().unwrap_or("")]));
+                        }
+                    } else if bans.is_empty() {
+                        println!("No bans");
+                    } else {
+                        println!("{:<36} {:<24} {:<10} {:<26} REASON", "ID", "NETWORK", "SOURCE", "EXPIRES");
+                        println!("{}", "-".repeat(120));
+                        for b in &bans {
+                            println!("{:<36} {:<24} {:<10} {:<26} {}", b.id, b.network, b.source,
+                                b.expires_at.as_deref().unwrap_or("never"), b.reason.as_deref().unwrap_or(""));
+                        }
+                    }
+                }
+                BanAction::Remove { id_or_network } => {
+                    // 203.0.113.7/32 is stored as 203.0.113.7
+                    let key = id_or_network.parse::<Network>().map_or(id_or_network.clone(), |n| n.to_string());
+                    match db.delete_ban(&key)? {
+                        0 => out.fail(Failure::NotFound, &format!("No ban {}", id_or_network)),
+                        n => out.done(format_args!("Lifted {} ban(s) of {}", n, key), n, serde_json::json!({ "network": key, "lifted": n })),
+                    }
+                }
+            }
+        }
+
+        Commands::SignUrl { url, expires } => {
+            let url = url::Url::parse(&url).map_err(|e| anyhow::anyhow!("invalid URL '{}': {}", url, e))?;
+            let host = url.host_str().unwrap_or_default();
+            let secret = match db.find_mapping(host, url.path())? {
+                Some(m) => {
+                    check_owner(out, &m, tenant);
+                    match m.url_signing_secret {
+                        Some(secret) => signed_url::key(&secret),
+                        None => out.fail(Failure::Conflict, &format!("Mapping {}/{} has no URL signing secret (set --url-signing-secret)", m.domain, m.front_uri)),
+                    }
+                }
+                None => out.fail(Failure::NotFound, &format!("No mapping found for {}", url)),
+            };
+            let expires_at = chrono::Utc::now().timestamp().max(0) as u64 + expires;
+            let path_and_query = signed_url::sign(&secret, url.path(), url.query(), expires_at);
+            let signed = format!("{}{}", &url[..url::Position::BeforePath], path_and_query);
+            match out.format {
+                Format::Json => out.json(&serde_json::json!({ "url": signed, "expires_at": expires_at })),
+                _ => println!("{}", signed),
+            }
+        }
+
+        Commands::List { domain, deleted: true, json } => {
+            let out = json_flag(out, json);
+            let mut deleted = db.list_deleted_mappings(domain.as_deref())?;
+            deleted.retain(|(m, _)| owned(m, tenant));
+            if out.is_json() {
+                let json_output: Vec<serde_json::Value> = deleted.iter().map(|(m, at)| serde_json::json!({
+                    "id": m.id,
+                    "domain": m.domain,
+                    "front_uri": m.front_uri,
+                    "back_port": m.back_port,
+                    "back_uri": m.back_uri,
+                    "backend": m.backend,
+                    "deleted_at": at,
+                })).collect();
+                out.json(&json_output);
+            } else if out.format == Format::Plain {
+                for (m, at) in &deleted {
+                    println!("{}", cli_output::record([&m.id, &m.domain, &m.front_uri, &m.back_port.to_string(), at]));
+                }
+            } else if deleted.is_empty() {
+                println!("No deleted mappings found");
+            } else {
+                println!("{:<40} {:<15} {:<8} {:<20}", "DOMAIN", "FRONT_URI", "PORT", "DELETED_AT");
+                println!("{}", "-".repeat(86));
+                for (mapping, at) in &deleted {
+                    println!("{:<40} {:<15} {:<8} {:<20}",
+                        mapping.domain,
+                        if mapping.front_uri.is_empty() { "/" } else { &mapping.front_uri },
+                        mapping.back_port,
+                        at,
+                    );
+                }
+                println!("\nTotal: {} deleted mapping(s); bring one back with: rustproxy-mapping restore <domain> -f <path>", deleted.len());
+            }
+        }
+
+        Commands::List { domain, deleted: false, json } => {
+            let out = json_flag(out, json);
+            let mut mappings = db.list_mappings(domain.as_deref())?;
+            mappings.retain(|m| owned(m, tenant));
+
+            if mappings.is_empty() && out.format == Format::Table {
+                if let Some(d) = domain {
+                    println!("No mappings found for domain: {}", d);
+                } else {
+                    println!("No mappings found");
+                }
+                return Ok(());
+            }
+
+            if out.is_json() {
+                let json_output: Vec<serde_json::Value> = mappings.iter().map(mapping_json).collect();
+                out.json(&json_output);
+            } else if out.format == Format::Plain {
+                for m in &mappings {
+                    println!("{}", cli_output::record([&m.id, &m.domain, &m.front_uri, &m.back_port.to_string(), &m.back_uri, m.backend.as_deref().unwrap_or(""), &m.version.to_string()]));
+                }
+            } else {
+                println!("{:<40} {:<15} {:<8} {:<15} {:<30} {:<7}",
+                    "DOMAIN", "FRONT_URI", "PORT", "BACK_URI", "BACKEND", "VERSION");
+                println!("{}", "-".repeat(116));
+
+                for mapping in &mappings {
+                    let backend = mapping.backend.as_deref().unwrap_or("localhost");
+                    println!("{:<40} {:<15} {:<8} {:<15} {:<30} {:<7}",
+                        mapping.domain,
+                        if mapping.front_uri.is_empty() { "/" } else { &mapping.front_uri },
+                        mapping.back_port,
+                        if mapping.back_uri.is_empty() { "/" } else { &mapping.back_uri },
+                        backend,
+                        mapping.version,
+                    );
+                }
+
+                println!("\nTotal: {} mapping(s)", mappings.len());
+            }
+        }
+
+        Commands::Export { domain, format, file } => {
+            let mut mappings = db.list_mappings(domain.as_deref())?;
+            mappings.retain(|m| owned(m, tenant));
+            // The file is in --format whatever --output says; that is for the summary
+            let file = file.filter(|f| f.as_os_str() != "-");
+            let mut writer: Box<dyn std::io::Write> = match &file {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            match format.as_str() {
+                "nginx" => writer.write_all(config_export::nginx(&mappings).as_bytes())?,
+                "caddy" => writer.write_all(config_export::caddy(&mappings).as_bytes())?,
+                _ => mapping_csv::write(&mappings, &mut writer)?,
+            }
+            writer.flush()?;
+            if let Some(path) = file {
+                out.done(
+                    format_args!("Exported {} mapping(s) to {}", mappings.len(), path.display()),
+                    mappings.len(),
+                    serde_json::json!({ "file": path, "format": format, "exported": mappings.len() }),
+                );
+            }
+        }
+
+        Commands::Import { file, format: _, domain, apply, prune } => {
+            let input: Box<dyn std::io::Read> = if file.as_os_str() == "-" {
+                Box::new(std::io::stdin())
+            } else {
+                Box::new(std::fs::File::open(&file)?)
+            };
+            let mut current = db.list_mappings(domain.as_deref())?;
+            current.retain(|m| owned(m, tenant));
+            let planned = mapping_csv::read(input).and_then(|mut rows| {
+                let mut problems = Vec::new();
+                for row in &mut rows {
+                    let row_domain = row.cells.iter().find(|(c, _, _)| *c == "domain").map(|(_, d, _)| d.clone());
+                    if let Some(d) = domain.as_deref().filter(|d| row_domain.as_deref() != rustproxy::host::normalize_domain(d).as_deref()) {
+                        problems.push(format!("line {}: not a mapping of {}", row.line, d));
+                    }
+                    // A tenant's rows are its own
+                    if let Some(t) = tenant {
+                        match row.cells.iter().find(|(c, _, _)| *c == "owner") {
+                            Some((_, owner, _)) if owner != t => problems.push(format!("line {}: owner must be {}", row.line, t)),
+                            Some(_) => {}
+                            None => row.cells.push(("owner", t.to_string(), serde_json::Value::String(t.to_string()))),
+                        }
+                    }
+                }
+                if !problems.is_empty() {
+                    return Err(problems);
+                }
+                mapping_csv::plan(&rows, &current, prune)
+            });
+            let plan = match planned {
+                Ok(plan) => plan,
+                Err(problems) => out.fail_with(Failure::Invalid, &format!("Nothing imported: {} problem(s)", problems.len()), &problems),
+            };
+
+            // The preview is the result, so printed even with --quiet
+            let applied = match apply && !plan.changes.is_empty() {
+                true => match db.apply_changes(&plan.changes)? {
+                    Ok(applied) => Some(applied.len()),
+                    Err(rejected) => {
+                        let why = format!("Nothing imported: {}: {}", plan.sources[rejected.index], rejected.reason);
+                        out.fail_with(Failure::Conflict, &why, &plan.preview);
+                    }
+                },
+                false => None,
+            };
+            match out.format {
+                Format::Json => out.json(&serde_json::json!({
+                    "changes": plan.preview,
+                    "applied": applied.is_some(),
+                })),
+                _ => {
+                    for line in &plan.preview {
+                        println!("{}", line);
+                    }
+                    if plan.changes.is_empty() {
+                        out.hint("No changes");
+                    } else if let Some(n) = applied {
+                        out.hint(format_args!("\nApplied {} change(s)", n));
+                    } else {
+                        out.hint(format_args!("\n{} change(s); run again with --apply to make them", plan.changes.len()));
+                    }
+                }
+            }
+        }
+
+        Commands::Usage { period, json } => {
+            let out = json_flag(out, json);
+            let period = period.unwrap_or_else(|| usage::period(chrono::Utc::now()));
+            let mut rows = db.usage_report(Some(&period))?;
+            if let Some(t) = tenant {
+                rows.retain(|r| r.owner.as_deref() == Some(t));
+            }
+            if out.is_json() {
+                out.json(&rows);
+            } else if out.format == Format::Plain {
+                for row in &rows {
+                    println!("{}", cli_output::record([
+                        row.domain.as_deref().unwrap_or(""),
+                        row.front_uri.as_deref().unwrap_or(""),
+                        &row.usage.requests.to_string(),
+                        &row.usage.bytes_in.to_string(),
+                        &row.usage.bytes_out.to_string(),
+                        row.quota.as_deref().unwrap_or(""),
+                    ]));
+                }
+            } else if rows.is_empty() {
+                println!("No usage recorded for {}", period);
+            } else {
+                println!("{:<40} {:<15} {:>12} {:>14} {:>14}  QUOTA",
+                    "DOMAIN", "FRONT_URI", "REQUESTS", "BYTES_IN", "BYTES_OUT");
+                println!("{}", "-".repeat(120));
+                for row in &rows {
+                    let front_uri = match row.front_uri.as_deref() {
+                        Some("") => "/",
+                        Some(uri) => uri,
+                        None => "-",
+                    };
+                    println!("{:<40} {:<15} {:>12} {:>14} {:>14}  {}",
+                        row.domain.as_deref().unwrap_or("(deleted)"),
+                        front_uri,
+                        row.usage.requests,
+                        row.usage.bytes_in,
+                        row.usage.bytes_out,
+                        row.quota.as_deref().unwrap_or("-"),
+                    );
+                }
+                println!("\nPeriod: {} (counts reach the database every USAGE_FLUSH_INTERVAL)", period);
+            }
+        }
+
+        Commands::Lint { http_host, http_port, https_port, merge_slashes, json } => {
+            let out = json_flag(out, json);
+            let listeners = Listeners { host: &http_host, ports: &[http_port, https_port] };
+            let normalization = PathNormalization { merge_slashes, ..PathNormalization::default() };
+            let mappings = db.list_mappings(None)?;
+            let mut findings = lint::check(&mappings, &listeners, &normalization);
+            findings.retain(|f| mappings.iter().any(|m| m.id == f.mapping && owned(m, tenant)));
+            if out.is_json() {
+                out.json(&findings);
+            } else if out.format == Format::Plain {
+                for finding in &findings {
+                    println!("{}", cli_output::record([&finding.problem.to_string(), &finding.mapping, &finding.domain, &finding.front_uri, &finding.detail]));
+                }
+            } else if findings.is_empty() {
+                println!("No route conflicts");
+            } else {
+                for finding in &findings {
+                    println!("{}", finding);
+                }
+                println!("\n{} problem(s)", findings.len());
+            }
+            if !findings.is_empty() {
+                std::process::exit(Failure::Failed.code());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The mapping as `list --output json` shows it.
+fn mapping_json(m: &rustproxy::Mapping) -> serde_json::Value {
+    // In parts: one json! this long exceeds the macro recursion limit
+    let policies = serde_json::json!({
+        "contract": m.contract,
+        "activate_at": m.activate_at,
+        "deactivate_at": m.deactivate_at,
+        "header_limits": m.header_limits,
+        "access_hours": m.access_hours,
+        "forward_headers": m.forward_headers,
+        "scrub_headers": m.scrub_headers,
+        "upstream_protocol": m.upstream_protocol,
+        "upstream_signing": m.upstream_signing.as_deref().and_then(|s| s.parse::<UpstreamSigning>().ok()).map(|s| s.describe()),
+        "startup_page": m.startup_page,
+        "hold": m.hold,
+        "image_filter": m.image_filter,
+        "minify": m.minify,
+        "early_hints": m.early_hints,
+    });
+    let mut entry = serde_json::json!({
+        "id": m.id,
+        "domain": m.domain,
+        "front_uri": m.front_uri,
+        "back_port": m.back_port,
+        "back_uri": m.back_uri,
+        "backend": m.backend,
+        "back_ports": m.back_ports,
+        "allowed_ips": m.allowed_ips,
+        "auth_type": m.auth_type,
+        "active_slot": m.active_slot.as_deref().unwrap_or("blue"),
+        "green_backend": m.green_backend,
+        "green_port": m.green_port,
+        "allowed_content_types": m.allowed_content_types,
+        "max_body_bytes": m.max_body_bytes,
+        "preserve_path": m.preserve_path,
+        "log_level": m.log_level,
+        "log_sample": m.log_sample,
+        "experiment": m.experiment.as_deref().and_then(|e| serde_json::from_str::<serde_json::Value>(e).ok()),
+        "html_base": m.html_base,
+        "rate_limit": m.rate_limit,
+        "fallback_backend": m.fallback_backend,
+        "stale_if_error": m.stale_if_error,
+        "document_root": m.document_root,
+        "egress_proxy": m.egress_proxy.as_deref().map(egress_display),
+        "signed_urls": m.url_signing_secret.is_some(),
+        "compress_requests": m.compress_requests,
+        "pool": m.pool,
+        "quota": m.quota,
+        "owner": m.owner,
+        "oidc": m.oidc.as_deref().and_then(|o| OidcSettings::parse(o).ok()).map(|o| serde_json::json!({
+            "issuer": o.issuer,
+            "client_id": o.client_id,
+            "scopes": o.scopes,
+            "allowed_domains": o.allowed_domains,
+        })),
+        "upstream_tls": m.upstream_tls.as_deref().and_then(|t| serde_json::from_str::<serde_json::Value>(t).ok()),
+        "body_rewrites": m.body_rewrites.as_deref().and_then(|r| serde_json::from_str::<serde_json::Value>(r).ok()),
+        "created_at": m.created_at,
+        "updated_at": m.updated_at,
+        "version": m.version,
+    });
+    if let (Some(all), serde_json::Value::Object(more)) = (entry.as_object_mut(), policies) {
+        all.extend(more);
+    }
+    entry
+}
+
+/// The mapping's settings, a line each.
+fn describe_mapping(mapping: &rustproxy::Mapping) -> String {
+    use std::fmt::Write as _;
+    let mut s = String::new();
+    let _ = writeln!(s, "  ID:         {}", mapping.id);
+    let _ = writeln!(s, "  Domain:     {}", mapping.domain);
+    let _ = writeln!(s, "  Front URI:  /{}", mapping.front_uri);
+    if let Some(ref ports) = mapping.back_ports {
+        let _ = writeln!(s, "  HA Ports:   {} (round-robin)", ports);
+    } else {
+        let _ = writeln!(s, "  Back Port:  {}", mapping.back_port);
+    }
+    let _ = writeln!(s, "  Back URI:   /{}", mapping.back_uri);
+    if let Some(ref backend) = mapping.backend {
+        let _ = writeln!(s, "  Backend:    {}", backend);
+    }
+    if let Some(ref ips) = mapping.allowed_ips {
+        let _ = writeln!(s, "  Allowed IPs: {}", ips);
+    }
+    if let Some(ref auth) = mapping.auth_type {
+        let _ = writeln!(s, "  Auth Type:  {}", auth);
+    }
+    if let Some(ref types) = mapping.allowed_content_types {
+        let _ = writeln!(s, "  Body Types: {}", types);
+    }
+    if let Some(max) = mapping.max_body_bytes {
+        let _ = writeln!(s, "  Max Body:   {} bytes", max);
+    }
+    if mapping.preserve_path {
+        let _ = writeln!(s, "  Path:       forwarded as sent (encoding preserved)");
+    }
+    if mapping.log_level.is_some() || mapping.log_sample.is_some() {
+        let _ = writeln!(s, "  Access Log: level {}, sampling {}",
+            mapping.log_level.as_deref().unwrap_or("default"),
+            mapping.log_sample.as_deref().unwrap_or("default"));
+    }
+    if let Some(port) = mapping.green_port {
+        let server = mapping.green_backend.as_deref().or(mapping.backend.as_deref()).unwrap_or("localhost");
+        let _ = writeln!(s, "  Green Slot: {} port {} ({})", server, port,
+            if mapping.is_green() { "live" } else { "standby" });
+    }
+    if let Some(exp) = mapping.experiment.as_deref().and_then(|e| Experiment::parse(e).ok()) {
+        let variants: Vec<String> = exp.variants.iter().map(|v| format!("{}:{}", v.name, v.weight)).collect();
+        let _ = writeln!(s, "  Experiment: {} by {} ({})", exp.name, exp.key, variants.join(", "));
+    }
+    if let Some(ref rules) = mapping.body_rewrites {
+        let _ = writeln!(s, "  Rewrites:   {}", rules);
+    }
+    if let Some(ref limit) = mapping.rate_limit {
+        let _ = writeln!(s, "  Rate Limit: {} per client", limit);
+    }
+    if let Some(ref target) = mapping.fallback_backend {
+        let _ = writeln!(s, "  Fallback:   {}", target);
+    }
+    if let Some(secs) = mapping.stale_if_error {
+        let _ = writeln!(s, "  Stale:      up to {}s old on backend errors", secs);
+    }
+    if let Some(tls) = mapping.upstream_tls.as_deref().and_then(|t| UpstreamTls::parse(t).ok()) {
+        if let Some(ref ca) = tls.ca {
+            let _ = writeln!(s, "  TLS CA:     {}", ca);
+        }
+        if let Some(ref name) = tls.server_name {
+            let _ = writeln!(s, "  TLS Name:   {}", name);
+        }
+        if !tls.pins.is_empty() {
+            let _ = writeln!(s, "  TLS Pins:   {}", tls.pins.join(", "));
+        }
+        if tls.insecure {
+            let _ = writeln!(s, "  TLS:        WARNING: backend certificate NOT verified");
+        }
+    }
+    if let Some(ref root) = mapping.document_root {
+        let _ = writeln!(s, "  Doc Root:   {}", root);
+    }
+    if let Some(ref egress) = mapping.egress_proxy {
+        let _ = writeln!(s, "  Egress:     {}", egress_display(egress));
+    }
+    if let Some(oidc) = mapping.oidc.as_deref().and_then(|o| OidcSettings::parse(o).ok()) {
+        let _ = writeln!(s, "  OIDC:       {} (client {})", oidc.issuer, oidc.client_id);
+        if !oidc.allowed_domains.is_empty() {
+            let _ = writeln!(s, "  OIDC Users: @{}", oidc.allowed_domains.join(", @"));
+        }
+    }
+    if mapping.url_signing_secret.is_some() {
+        let _ = writeln!(s, "  Signed:     URLs must be signed (sign-url)");
+    }
+    if let Some(min) = mapping.compress_requests {
+        let _ = writeln!(s, "  Compress:   request bodies from {} bytes (gzip)", min);
+    }
+    if let Some(ref pool) = mapping.pool {
+        let _ = writeln!(s, "  Pool:       {}", pool);
+    }
+    if let Some(ref quota) = mapping.quota {
+        let _ = writeln!(s, "  Quota:      {} per month", quota);
+    }
+    if let Some(ref owner) = mapping.owner {
+        let _ = writeln!(s, "  Owner:      {}", owner);
+    }
+    if let Some(ref contract) = mapping.contract {
+        let _ = writeln!(s, "  Contract:   {}", contract);
+    }
+    if let Some(ref at) = mapping.activate_at {
+        let _ = writeln!(s, "  Activates:  {}", at);
+    }
+    if let Some(ref at) = mapping.deactivate_at {
+        let _ = writeln!(s, "  Deactivates: {}", at);
+    }
+    if let Some(ref limits) = mapping.header_limits {
+        let _ = writeln!(s, "  Headers:    at most {}", limits);
+    }
+    if let Some(ref hours) = mapping.access_hours {
+        let _ = writeln!(s, "  Open:       {}", hours);
+    }
+    if let Some(ref list) = mapping.forward_headers {
+        let _ = writeln!(s, "  Forwards:   only {}", list);
+    }
+    if let Some(ref profile) = mapping.scrub_headers {
+        let _ = writeln!(s, "  Scrubbing:  {}", profile);
+    }
+    if let Some(ref protocol) = mapping.upstream_protocol {
+        let _ = writeln!(s, "  Upstream:   {}", protocol);
+    }
+    if let Some(signing) = mapping.upstream_signing.as_deref().and_then(|s| s.parse::<UpstreamSigning>().ok()) {
+        let _ = writeln!(s, "  Signing:    {}", signing.describe());
+    }
+    if let Some(ref startup) = mapping.startup_page {
+        let _ = writeln!(s, "  Startup:    {}", startup);
+    }
+    if let Some(hold) = mapping.hold.as_deref().and_then(|h| h.parse::<Hold>().ok()).filter(|h| h.active(chrono::Utc::now())) {
+        let _ = writeln!(s, "  Held:       until {} (queue up to {})", hold.until.to_rfc3339(), hold.max_queue);
+    }
+    if let Some(ref images) = mapping.image_filter {
+        let _ = writeln!(s, "  Images:     {}", images);
+    }
+    if let Some(ref minify) = mapping.minify {
+        let _ = writeln!(s, "  Minify:     {}", minify);
+    }
+    if let Some(ref hints) = mapping.early_hints {
+        let _ = writeln!(s, "  Hints:      {}", hints);
+    }
+    if let Some(ref mode) = mapping.html_base {
+        let _ = writeln!(s, "  HTML Base:  {} (under /{})", mode, mapping.front_uri);
+    }
+    let _ = writeln!(s, "  Created:    {}", mapping.created_at);
+    s.pop();
+    s
+}
+
+/// Whether `--tenant` (if given) owns the mapping.
+fn owned(mapping: &rustproxy::Mapping, tenant: Option<&str>) -> bool {
+    tenant.is_none_or(|t| mapping.owner.as_deref() == Some(t))
+}
+
+/// Stop unless `--tenant` (if given) owns the mapping.
+fn check_owner(out: Output, mapping: &rustproxy::Mapping, tenant: Option<&str>) {
+    if let Some(t) = tenant.filter(|_| !owned(mapping, tenant)) {
+        out.fail(Failure::Forbidden, &format!("{}/{} is not owned by tenant {}", mapping.domain, mapping.front_uri, t));
+    }
+}
+
+/// Tell how to prove control of `domain` if `owner` hasn't yet.
+fn verification_hint(db: &DatabaseManager, out: Output, domain: &str, owner: &str) -> Result<()> {
+    if !db.is_domain_verified(domain, owner)? {
+        out.hint(format_args!("\nProxies with REQUIRE_DOMAIN_VERIFICATION route this only once {} has proven control of {}:", owner, domain));
+        out.hint(format_args!("  rustproxy-mapping verify {} --owner {}", domain, owner));
+    }
+    Ok(())
+}
+
+/// Stop: `what` is for the operator, not a tenant.
+fn operator_only(out: Output, what: &str) -> ! {
+    out.fail(Failure::Forbidden, &format!("{} is not available with --tenant", what))
+}
+
+/// The value of a change the database may turn down (a taken name, a missing green slot),
+/// or stop with a conflict.
+fn refused<T>(out: Output, result: Result<T>) -> T {
+    result.unwrap_or_else(|e| out.fail(Failure::Conflict, &format!("Error: {:#}", e)))
+}
+
+/// `out`, or JSON output for a command's own `--json`.
+fn json_flag(out: Output, json: bool) -> Output {
+    match json {
+        true => Output { format: Format::Json, ..out },
+        false => out,
+    }
+}
+
+/// Parse a byte size with an optional K/M/G suffix (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, mult) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.trim().parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+/// Validate `--experiment` up front; the empty string is passed through (clears it).
+fn parse_log_level(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Ok(String::new());
+    }
+    s.parse::<AccessLevel>()?;
+    Ok(s.trim().to_ascii_lowercase())
+}
+
+fn parse_log_sample(s: &str) -> Result<String, String> {
+    if !s.trim().is_empty() {
+        s.parse::<Sampling>()?;
+    }
+    Ok(s.trim().to_string())
+}
+
+fn parse_body_rewrites(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Ok(String::new());
+    }
+    BodyRewrites::parse(s).map_err(|e| e.to_string())?;
+    Ok(s.to_string())
+}
+
+fn parse_html_base(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Ok(String::new());
+    }
+    s.parse::<BasePathMode>()?;
+    Ok(s.trim().to_ascii_lowercase())
+}
+
+fn parse_rate_limit(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() || s.eq_ignore_ascii_case("off") {
+        return Ok(s.to_ascii_lowercase());
+    }
+    s.parse::<RateLimit>()?;
+    Ok(s.to_string())
+}
+
+/// Pool overrides as `name=value` pairs, checked and written back without spaces.
+fn parse_pool(s: &str) -> Result<String, String> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    PoolSettings::default().with_overrides(&s).map_err(|e| e.to_string())?;
+    Ok(s)
+}
+
+/// Quota as `name=value` pairs, checked and written back in canonical form.
+fn parse_quota(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<Quota>()?.to_string())
+}
+
+/// Response contract, checked and written back in canonical form.
+fn parse_contract(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<Contract>()?.to_string())
+}
+
+/// Header limits, checked and written back in canonical form.
+fn parse_header_limits(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<HeaderLimits>()?.to_string())
+}
+
+/// Access hours, checked and written back in canonical form.
+fn parse_access_hours(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<AccessHours>()?.to_string())
+}
+
+/// Request header allow-list, checked and written back in canonical form.
+fn parse_forward_headers(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<ForwardHeaders>()?.to_string())
+}
+
+/// Scrubbing profile name, checked and lowercased.
+fn parse_scrub_headers(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<ScrubProfile>()?.to_string())
+}
+
+/// Upstream protocol settings, checked and normalized.
+fn parse_upstream_protocol(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<UpstreamProtocol>()?.to_string())
+}
+
+/// Upstream signing settings, checked and normalized.
+fn parse_upstream_signing(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<UpstreamSigning>()?.to_string())
+}
+
+/// Startup page settings, checked and normalized.
+fn parse_startup_page(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<StartupPage>()?.to_string())
+}
+
+/// Image filter settings, checked and normalized.
+fn parse_image_filter(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<ImageFilter>()?.to_string())
+}
+
+/// Minification settings, checked and normalized.
+fn parse_minify(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<Minify>()?.to_string())
+}
+
+/// Early Hints, checked and normalized.
+fn parse_early_hints(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(s.parse::<EarlyHints>()?.to_string())
+}
+
+/// A scheduled time like an expiry; an empty string clears it.
+fn parse_schedule(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Ok(String::new());
+    }
+    parse_expiry(s).map_err(|_| format!("invalid time '{}' (RFC 3339, YYYY-MM-DD or a delay like 2h)", s.trim()))
+}
+
+/// An RFC 3339 time, a date (midnight UTC) or a lifetime from now, as RFC 3339.
+fn parse_expiry(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(t.to_rfc3339());
+    }
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(d.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().to_rfc3339());
+    }
+    let secs = parse_duration_secs(s).map_err(|_| format!("invalid expiry '{}' (RFC 3339, YYYY-MM-DD or e.g. 90d)", s))?;
+    let lifetime = chrono::Duration::try_seconds(secs as i64).ok_or_else(|| format!("expiry '{}' too far out", s))?;
+    Ok((chrono::Utc::now() + lifetime).to_rfc3339())
+}
+
+/// Parse seconds with an optional s/m/h/d suffix.
+fn parse_duration_secs(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, mult) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        Some('d') => (&s[..s.len() - 1], 86400),
+        _ => (s, 1),
+    };
+    digits.trim().parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("invalid duration '{}' (e.g. 300, 10m, 1h)", s))
+}
+
+fn parse_fallback_backend(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(String::new());
+    }
+    let probe = rustproxy::Mapping { fallback_backend: Some(s.to_string()), ..Default::default() };
+    if probe.fallback().is_none() {
+        return Err(format!("'{}' is not a host:port or http(s) URL", s));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_tls_pin(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if !s.is_empty() {
+        upstream_tls::parse_pin(s).map_err(|e| e.to_string())?;
+    }
+    Ok(s.to_string())
+}
+
+fn parse_tls_server_name(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if !s.is_empty() {
+        let probe = UpstreamTls { server_name: Some(s.to_string()), ..Default::default() };
+        UpstreamTls::parse(&probe.to_json()).map_err(|e| e.to_string())?;
+    }
+    Ok(s.to_string())
+}
+
+/// Apply TLS flags to a mapping's stored settings; empty values clear. `None` when nothing
+/// is left to store.
+fn merge_upstream_tls(
+    current: Option<&str>,
+    ca: Option<String>,
+    pins: Vec<String>,
+    server_name: Option<String>,
+    insecure: Option<bool>,
+) -> Option<String> {
+    let mut tls = current.and_then(|t| UpstreamTls::parse(t).ok()).unwrap_or_default();
+    if let Some(ca) = ca {
+        tls.ca = Some(ca).filter(|c| !c.is_empty());
+    }
+    if !pins.is_empty() {
+        tls.pins = pins.into_iter().filter(|p| !p.is_empty()).collect();
+    }
+    if let Some(name) = server_name {
+        tls.server_name = Some(name).filter(|n| !n.is_empty());
+    }
+    if let Some(insecure) = insecure {
+        tls.insecure = insecure;
+    }
+    (!tls.is_default()).then(|| tls.to_json())
+}
+
+/// Apply OIDC flags to a mapping's settings; an empty issuer removes the login.
+fn merge_oidc(
+    current: Option<&str>,
+    issuer: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    scopes: Option<String>,
+    allowed_domains: Vec<String>,
+) -> Result<Option<String>> {
+    if issuer.as_deref() == Some("") {
+        return Ok(None);
+    }
+    let mut oidc = current.and_then(|o| OidcSettings::parse(o).ok()).unwrap_or_default();
+    if let Some(issuer) = issuer {
+        oidc.issuer = issuer.trim_end_matches('/').to_string();
+    }
+    if let Some(id) = client_id {
+        oidc.client_id = id;
+    }
+    if let Some(secret) = client_secret {
+        oidc.client_secret = secret;
+    }
+    if let Some(scopes) = scopes {
+        oidc.scopes = Some(scopes).filter(|s| !s.trim().is_empty());
+    }
+    if !allowed_domains.is_empty() {
+        oidc.allowed_domains = allowed_domains.into_iter().map(|d| d.trim().to_ascii_lowercase()).filter(|d| !d.is_empty()).collect();
+    }
+    OidcSettings::parse(&oidc.to_json())?;
+    Ok(Some(oidc.to_json()))
+}
+
+fn parse_egress_proxy(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty() || s == egress::DIRECT {
+        return Ok(s.to_string());
+    }
+    s.parse::<EgressProxy>()?;
+    Ok(s.to_string())
+}
+
+/// An egress proxy URL without its password.
+fn egress_display(s: &str) -> String {
+    match url::Url::parse(s) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        _ => s.to_string(),
+    }
+}
+
+fn parse_template(s: &str) -> Result<String, String> {
+    app_templates::get(s)?;
+    Ok(s.trim().to_ascii_lowercase())
+}
+
+fn parse_experiment(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Ok(String::new());
     }
-    println!("  Created:    {}", mapping.created_at);
+    Experiment::parse(s).map_err(|e| e.to_string())?;
+    Ok(s.to_string())
 }
@@ -0,0 +1,271 @@
+//! Response body substitution per mapping
+//! Literal or regex replacements in text responses — typically absolute backend URLs in
+//! HTML turned into the public domain — for bodies up to a size cap
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use hyper::{Response, StatusCode};
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::sync::Arc;
+use tracing::warn;
+
+/// One rule as stored in the mapping's `body_rewrites` column (a JSON array of these):
+///
+/// ```json
+/// [{"find": "http://10.0.0.5:3000", "replace": "https://app.example.com"},
+///  {"regex": "https?://backend\\.internal(:\\d+)?", "replace": "https://app.example.com"}]
+/// ```
+///
+/// Regex replacements may refer to groups as `$1` or `${name}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    find: Option<String>,
+    regex: Option<String>,
+    replace: String,
+}
+
+#[derive(Debug)]
+enum Rule {
+    Literal { find: String, replace: String },
+    Regex { re: Regex, replace: String },
+}
+
+/// A mapping's compiled substitution rules, applied in order
+#[derive(Debug)]
+pub struct BodyRewrites {
+    rules: Vec<Rule>,
+}
+
+impl BodyRewrites {
+    /// Parse and compile a rule list.
+    pub fn parse(json: &str) -> Result<Self> {
+        let specs: Vec<RuleSpec> = serde_json::from_str(json)?;
+        if specs.is_empty() {
+            bail!("body rewrites need at least one rule");
+        }
+        let rules = specs.into_iter().map(|spec| match (spec.find, spec.regex) {
+            (Some(find), None) if !find.is_empty() => Ok(Rule::Literal { find, replace: spec.replace }),
+            (None, Some(re)) => Ok(Rule::Regex { re: Regex::new(&re)?, replace: spec.replace }),
+            (Some(_), None) => bail!("body rewrite 'find' must not be empty"),
+            _ => bail!("each body rewrite needs exactly one of 'find' or 'regex'"),
+        }).collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// `text` with every rule applied; borrowed when nothing matched.
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut out = Cow::Borrowed(text);
+        for rule in &self.rules {
+            let replaced = match rule {
+                Rule::Literal { find, replace } if out.contains(find.as_str()) => out.replace(find.as_str(), replace),
+                Rule::Literal { .. } => continue,
+                Rule::Regex { re, replace } => match re.replace_all(&out, replace.as_str()) {
+                    Cow::Owned(s) => s,
+                    Cow::Borrowed(_) => continue,
+                },
+            };
+            out = Cow::Owned(replaced);
+        }
+        out
+    }
+}
+
+/// Compiled rules by their JSON, so a mapping's regexes are built once
+#[derive(Default)]
+pub struct RewriteCache {
+    compiled: DashMap<String, Option<Arc<BodyRewrites>>>,
+}
+
+impl RewriteCache {
+    /// Rules for a mapping's `body_rewrites` value; `None` (logged once) when invalid.
+    pub fn get(&self, json: &str) -> Option<Arc<BodyRewrites>> {
+        if let Some(rules) = self.compiled.get(json) {
+            return rules.clone();
+        }
+        // Edited rules leave their old version behind; don't let that grow unbounded
+        if self.compiled.len() >= 1024 {
+            self.compiled.clear();
+        }
+        let rules = match BodyRewrites::parse(json) {
+            Ok(r) => Some(Arc::new(r)),
+            Err(e) => {
+                warn!("Ignoring invalid body rewrites {}: {}", json, e);
+                None
+            }
+        };
+        self.compiled.insert(json.to_string(), rules.clone());
+        rules
+    }
+}
+
+/// Text media types rules apply to: `text/*`, JavaScript, JSON and XML (including
+/// `+json`/`+xml` suffixes). Other charsets than UTF-8 are left alone.
+pub fn is_text_content_type(content_type: &str) -> bool {
+    let mut params = content_type.split(';');
+    let media = params.next().unwrap_or("").trim().to_ascii_lowercase();
+    let utf8 = params.filter_map(|p| p.split_once('=')).all(|(k, v)| {
+        !k.trim().eq_ignore_ascii_case("charset")
+            || matches!(v.trim().trim_matches('"').to_ascii_lowercase().as_str(), "utf-8" | "utf8" | "us-ascii")
+    });
+    let text = media.starts_with("text/")
+        || matches!(media.as_str(), "application/javascript" | "application/json" | "application/xml")
+        || media.ends_with("+json")
+        || media.ends_with("+xml");
+    text && utf8
+}
+
+fn is_rewritable(status: StatusCode, headers: &HeaderMap, content_type: fn(&str) -> bool) -> bool {
+    let identity = headers.get(CONTENT_ENCODING)
+        .is_none_or(|v| v.as_bytes().eq_ignore_ascii_case(b"identity"));
+    let matches = headers.get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(content_type);
+    !matches!(status, StatusCode::SWITCHING_PROTOCOLS | StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+        && identity
+        && matches
+}
+
+/// Apply `rules` to a response body of at most `max_bytes`. Anything else — binary or
+/// compressed content, bigger bodies, invalid UTF-8 — passes through unchanged.
+pub async fn rewrite_response(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    rules: &BodyRewrites,
+    max_bytes: usize,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    edit_text_body(resp, max_bytes, is_text_content_type, |text| rules.apply(text)).await
+}
+
+/// Run `edit` over a response body of at most `max_bytes` whose Content-Type passes
+/// `content_type`; other responses pass through unchanged (see [`rewrite_response`]).
+pub async fn edit_text_body(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    max_bytes: usize,
+    content_type: fn(&str) -> bool,
+    edit: impl for<'a> FnOnce(&'a str) -> Cow<'a, str>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if !is_rewritable(resp.status(), resp.headers(), content_type) {
+        return resp;
+    }
+    let declared = resp.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|n| n > max_bytes) {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let buf = match read_bounded(body, max_bytes, declared.unwrap_or(0)).await {
+        Ok(buf) => buf,
+        Err(body) => return Response::from_parts(parts, body),
+    };
+
+    let body = match std::str::from_utf8(&buf).map(edit) {
+        Ok(Cow::Owned(text)) => Bytes::from(text),
+        _ => Bytes::from(buf),
+    };
+    parts.headers.remove(TRANSFER_ENCODING);
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Response::from_parts(parts, Full::new(body).map_err(|never| match never {}).boxed())
+}
+
+/// The whole body when it is at most `max_bytes`. Otherwise — also on read errors and
+/// trailers — a body yielding what was read followed by the rest, to pass on unchanged.
+pub async fn read_bounded(
+    mut body: BoxBody<Bytes, hyper::Error>,
+    max_bytes: usize,
+    capacity: usize,
+) -> Result<Vec<u8>, BoxBody<Bytes, hyper::Error>> {
+    let mut buf = Vec::with_capacity(capacity.min(max_bytes));
+    loop {
+        let frame = match body.frame().await {
+            None => return Ok(buf),
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => return Err(replay(buf, Some(Err(e)), body)),
+        };
+        match frame.into_data() {
+            Ok(data) if buf.len() + data.len() <= max_bytes => buf.extend_from_slice(&data),
+            Ok(data) => return Err(replay(buf, Some(Ok(Frame::data(data))), body)),
+            // Trailers: not worth editing around
+            Err(frame) => return Err(replay(buf, Some(Ok(frame)), body)),
+        }
+    }
+}
+
+/// Body yielding what was already read, then `next`, then the rest of `body`.
+fn replay(
+    read: Vec<u8>,
+    next: Option<Result<Frame<Bytes>, hyper::Error>>,
+    body: BoxBody<Bytes, hyper::Error>,
+) -> BoxBody<Bytes, hyper::Error> {
+    let head = (!read.is_empty()).then(|| Ok(Frame::data(Bytes::from(read))));
+    let head = futures_util::stream::iter(head.into_iter().chain(next));
+    BodyExt::boxed(StreamBody::new(futures_util::StreamExt::chain(head, BodyStream::new(body))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content_type: &str, body: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, body.len())
+            .body(Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed())
+            .unwrap()
+    }
+
+    async fn text(resp: Response<BoxBody<Bytes, hyper::Error>>) -> String {
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_apply_in_order() {
+        let rules = BodyRewrites::parse(r#"[
+            {"find": "http://10.0.0.5:3000", "replace": "https://app.example.com"},
+            {"regex": "src=\"/(\\w+)\\.js\"", "replace": "src=\"/static/$1.js\""}
+        ]"#).unwrap();
+        let html = r#"<a href="http://10.0.0.5:3000/login"><script src="/app.js">"#;
+        assert_eq!(rules.apply(html), r#"<a href="https://app.example.com/login"><script src="/static/app.js">"#);
+        assert!(matches!(rules.apply("nothing here"), Cow::Borrowed(_)));
+
+        assert!(BodyRewrites::parse("[]").is_err());
+        assert!(BodyRewrites::parse(r#"[{"find": "", "replace": "x"}]"#).is_err());
+        assert!(BodyRewrites::parse(r#"[{"find": "a", "regex": "b", "replace": "x"}]"#).is_err());
+        assert!(BodyRewrites::parse(r#"[{"regex": "(", "replace": "x"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_text_content_types() {
+        assert!(is_text_content_type("text/html; charset=UTF-8"));
+        assert!(is_text_content_type("application/ld+json"));
+        assert!(is_text_content_type("application/javascript"));
+        assert!(!is_text_content_type("text/html; charset=iso-8859-1"));
+        assert!(!is_text_content_type("image/png"));
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_response_respects_type_and_cap() {
+        let rules = BodyRewrites::parse(r#"[{"find": "backend:3000", "replace": "www.example.com"}]"#).unwrap();
+
+        let resp = rewrite_response(response("text/html", "see http://backend:3000/"), &rules, 1024).await;
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "27");
+        assert_eq!(text(resp).await, "see http://www.example.com/");
+
+        let resp = rewrite_response(response("application/octet-stream", "backend:3000"), &rules, 1024).await;
+        assert_eq!(text(resp).await, "backend:3000");
+
+        // Over the cap, undeclared length: streamed through as read
+        let mut resp = response("text/plain", "backend:3000 backend:3000");
+        resp.headers_mut().remove(CONTENT_LENGTH);
+        let resp = rewrite_response(resp, &rules, 8).await;
+        assert_eq!(text(resp).await, "backend:3000 backend:3000");
+    }
+}
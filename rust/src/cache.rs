@@ -0,0 +1,540 @@
+//! Response cache
+//! Copies of good `GET` responses, kept for mappings with `stale_if_error` so a failing
+//! backend can be answered with the last good response (RFC 5861) instead of an error.
+//! Copies are kept per variant of the request headers the response `Vary`s on, and counted
+//! per mapping for the admin API
+
+use crate::disk_cache::{DiskCache, DiskStats, Lookup, Record};
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, CACHE_CONTROL, SET_COOKIE, VARY};
+use hyper::StatusCode;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+/// `Warning` on responses served stale (RFC 7234 §5.5.1)
+pub const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// Headers about the backend connection rather than the response
+const HOP_BY_HOP: [&str; 5] = ["transfer-encoding", "connection", "keep-alive", "upgrade", "trailer"];
+
+/// What a request's responses are kept under: its host, path and query, and the values of
+/// the request headers the response `Vary`s on
+pub struct CacheKey {
+    pub mapping_id: String,
+    /// Host, then path and query
+    pub base: String,
+    /// Path alone, for purges by prefix
+    pub path: String,
+    pub request: HeaderMap,
+}
+
+impl CacheKey {
+    pub fn new(mapping_id: &str, host: &str, path_and_query: &str, path: &str, request: &HeaderMap) -> Self {
+        Self {
+            mapping_id: mapping_id.to_string(),
+            base: format!("{}{}", host, path_and_query),
+            path: path.to_string(),
+            request: request.clone(),
+        }
+    }
+
+    /// `name=value` of each request header in `vary`, e.g. `accept-encoding=gzip`.
+    fn variant(&self, vary: &[HeaderName]) -> String {
+        let values = vary.iter().map(|name| {
+            let value: Vec<&str> = self.request.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+            format!("{}={}", name, value.join(","))
+        });
+        values.collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// The request headers named by a response's `Vary`, lowercased and sorted; `None` for
+/// `Vary: *`, which no later request matches.
+fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers.get_all(VARY).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+        match name.trim() {
+            "" => {}
+            "*" => return None,
+            name => names.extend(HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()).ok()),
+        }
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    Some(names)
+}
+
+pub(crate) fn full_key(base: &str, variant: &str) -> String {
+    match variant.is_empty() {
+        true => base.to_string(),
+        false => format!("{}\n{}", base, variant),
+    }
+}
+
+struct Entry {
+    mapping_id: String,
+    base: String,
+    variant: String,
+    path: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    /// How long after `stored` the copy may stand in for an error
+    usable_for: Duration,
+    size: usize,
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// Keys oldest-stored first, for eviction; `(seq, key)` pairs whose entry has since been
+    /// replaced or removed are skipped
+    order: VecDeque<(u64, String)>,
+    bytes: usize,
+    seq: u64,
+    /// Headers the last response for a base key varied on, and how many copies it has
+    vary: HashMap<String, (Vec<HeaderName>, usize)>,
+    counters: HashMap<String, Counters>,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let old = self.entries.remove(key)?;
+        self.bytes -= old.size;
+        if let Some((_, copies)) = self.vary.get_mut(&old.base) {
+            *copies -= 1;
+            if *copies == 0 {
+                self.vary.remove(&old.base);
+            }
+        }
+        Some(old)
+    }
+
+    fn counters(&mut self, mapping_id: &str) -> &mut Counters {
+        self.counters.entry(mapping_id.to_string()).or_default()
+    }
+}
+
+/// A kept response, ready to be served in place of an error
+pub struct Stale {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub age: Duration,
+}
+
+/// How a mapping's responses fared in the cache
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    /// Responses kept
+    pub stores: u64,
+    /// Backend failures answered with a kept (stale) copy
+    pub hits: u64,
+    /// Backend failures with no usable copy
+    pub misses: u64,
+    /// Misses where the copy was past its window
+    pub expired: u64,
+    /// Hits served from the disk tier
+    pub disk_hits: u64,
+}
+
+/// Totals, and counters by mapping ID
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub disk: Option<DiskStats>,
+    pub mappings: BTreeMap<String, Counters>,
+}
+
+/// Order of [`ResponseCache::entries`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Size,
+    Age,
+}
+
+/// A kept copy as listed by the admin API
+#[derive(Debug, Serialize)]
+pub struct EntryInfo {
+    pub key: String,
+    /// Values of the request headers the response varies on
+    pub variant: String,
+    pub mapping_id: String,
+    /// `memory` or `disk`; a copy can be in both
+    pub tier: &'static str,
+    pub status: u16,
+    pub bytes: u64,
+    pub age_secs: u64,
+    pub usable_for_secs: u64,
+}
+
+/// Responses by host, path and `Vary`ing request headers, up to a byte budget; the oldest
+/// copies go first. With a disk tier, copies are also written to disk.
+pub struct ResponseCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+    disk: Option<DiskCache>,
+}
+
+/// What a tier holds for a request
+enum Found {
+    Missing,
+    Expired,
+    Copy(Stale),
+}
+
+impl ResponseCache {
+    /// A cache of at most `max_bytes` (0 keeps nothing). A single response may take up to
+    /// a sixteenth of that.
+    pub fn new(max_bytes: usize) -> Self {
+        Self { max_bytes, inner: Mutex::new(Inner::default()), disk: None }
+    }
+
+    /// Also keep copies on disk, which takes responses too large for memory as well.
+    pub fn with_disk(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Keep a response for `usable_for`, replacing an older copy of the same variant.
+    pub fn store(&self, key: &CacheKey, status: StatusCode, headers: &HeaderMap, body: Bytes, usable_for: Duration) {
+        let Some(vary) = vary_names(headers) else { return };
+        let mut kept = HeaderMap::new();
+        for (name, value) in headers {
+            if !HOP_BY_HOP.contains(&name.as_str()) {
+                kept.append(name, value.clone());
+            }
+        }
+        let variant = key.variant(&vary);
+        let full = full_key(&key.base, &variant);
+        let size = full.len() + body.len() + kept.iter().map(|(n, v)| n.as_str().len() + v.len()).sum::<usize>();
+        let to_disk = self.disk.as_ref().filter(|disk| size as u64 <= disk.max_entry_bytes());
+        if size > self.max_bytes / 16 && to_disk.is_none() {
+            return;
+        }
+        self.inner.lock().counters(&key.mapping_id).stores += 1;
+
+        if let Some(disk) = to_disk {
+            let record = Record {
+                mapping_id: key.mapping_id.clone(),
+                base: key.base.clone(),
+                variant: variant.clone(),
+                path: key.path.clone(),
+                vary: vary.clone(),
+                status,
+                headers: kept.clone(),
+                body: body.clone(),
+                stored: SystemTime::now(),
+                usable_for,
+            };
+            let disk = disk.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = disk.write(&record) {
+                    warn!("Response cache: {:#}", e);
+                }
+            });
+        }
+        if size > self.max_bytes / 16 {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+        inner.seq += 1;
+        let seq = inner.seq;
+        let entry = Entry {
+            mapping_id: key.mapping_id.clone(),
+            base: key.base.clone(),
+            variant,
+            path: key.path.clone(),
+            status,
+            headers: kept,
+            body,
+            stored: Instant::now(),
+            usable_for,
+            size,
+            seq,
+        };
+        inner.remove(&full);
+        inner.order.push_back((seq, full.clone()));
+        inner.bytes += size;
+        inner.entries.insert(full, entry);
+        // A changed Vary leaves the copies under the old headers unreachable until evicted
+        let (names, copies) = inner.vary.entry(key.base.clone()).or_default();
+        *names = vary;
+        *copies += 1;
+        while inner.bytes > self.max_bytes {
+            let Some((seq, key)) = inner.order.pop_front() else { break };
+            if inner.entries.get(&key).is_some_and(|e| e.seq == seq) {
+                inner.remove(&key);
+            }
+        }
+        // Replaced copies leave their old position behind; drop those now and then
+        if inner.order.len() > 2 * inner.entries.len() + 64 {
+            let Inner { entries, order, .. } = &mut *inner;
+            order.retain(|(seq, key)| entries.get(key).is_some_and(|e| e.seq == *seq));
+        }
+    }
+
+    /// The kept copy for the request, from memory or else disk, if it may still stand in
+    /// for an error.
+    pub async fn stale(&self, key: &CacheKey) -> Option<Stale> {
+        let mut found = self.in_memory(key);
+        let mut from_disk = false;
+        if let (Found::Missing | Found::Expired, Some(disk)) = (&found, &self.disk) {
+            if let Some(names) = disk.vary(&key.base) {
+                let full = full_key(&key.base, &key.variant(&names));
+                let disk = disk.clone();
+                let lookup = tokio::task::spawn_blocking(move || disk.get(&full)).await;
+                match lookup.unwrap_or(Lookup::Missing) {
+                    Lookup::Found(record) => {
+                        let age = record.stored.elapsed().unwrap_or_default();
+                        let Record { status, headers, body, .. } = *record;
+                        found = Found::Copy(Stale { status, headers, body, age });
+                        from_disk = true;
+                    }
+                    Lookup::Expired => found = Found::Expired,
+                    Lookup::Missing => {}
+                }
+            }
+        }
+
+        let mut inner = self.inner.lock();
+        let counters = inner.counters(&key.mapping_id);
+        match found {
+            Found::Copy(stale) => {
+                counters.hits += 1;
+                counters.disk_hits += from_disk as u64;
+                Some(stale)
+            }
+            Found::Expired => {
+                counters.misses += 1;
+                counters.expired += 1;
+                None
+            }
+            Found::Missing => {
+                counters.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn in_memory(&self, key: &CacheKey) -> Found {
+        let mut inner = self.inner.lock();
+        let variant = inner.vary.get(&key.base).map(|(names, _)| key.variant(names)).unwrap_or_default();
+        let full = full_key(&key.base, &variant);
+        let Some(entry) = inner.entries.get(&full) else { return Found::Missing };
+        let age = entry.stored.elapsed();
+        if age > entry.usable_for {
+            inner.remove(&full);
+            return Found::Expired;
+        }
+        Found::Copy(Stale { status: entry.status, headers: entry.headers.clone(), body: entry.body.clone(), age })
+    }
+
+    /// Drop kept copies whose path starts with `prefix` (all without one). Returns how many.
+    pub fn purge(&self, prefix: Option<&str>) -> usize {
+        let mut inner = self.inner.lock();
+        let mut doomed: HashSet<String> = inner.entries.iter()
+            .filter(|(_, e)| prefix.is_none_or(|p| e.path.starts_with(p)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &doomed {
+            inner.remove(key);
+        }
+        if let Some(disk) = &self.disk {
+            doomed.extend(disk.purge(prefix));
+        }
+        doomed.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            max_bytes: self.max_bytes,
+            disk: self.disk.as_ref().map(DiskCache::stats),
+            mappings: inner.counters.iter().map(|(id, c)| (id.clone(), *c)).collect(),
+        }
+    }
+
+    /// The `limit` largest or oldest copies of either tier.
+    pub fn entries(&self, sort: SortBy, limit: usize) -> Vec<EntryInfo> {
+        let info = |age: Duration, info: EntryInfo| (age, EntryInfo { age_secs: age.as_secs(), ..info });
+        let mut entries: Vec<(Duration, EntryInfo)> = self.inner.lock().entries.values().map(|e| info(e.stored.elapsed(), EntryInfo {
+            key: e.base.clone(),
+            variant: e.variant.clone(),
+            mapping_id: e.mapping_id.clone(),
+            tier: "memory",
+            status: e.status.as_u16(),
+            bytes: e.size as u64,
+            age_secs: 0,
+            usable_for_secs: e.usable_for.as_secs(),
+        })).collect();
+        if let Some(disk) = &self.disk {
+            entries.extend(disk.list().into_iter().map(|e| info(e.age, EntryInfo {
+                key: e.base,
+                variant: e.variant,
+                mapping_id: e.mapping_id,
+                tier: "disk",
+                status: e.status,
+                bytes: e.size,
+                age_secs: 0,
+                usable_for_secs: e.usable_for.as_secs(),
+            })));
+        }
+        match sort {
+            SortBy::Size => entries.sort_by_key(|(_, e)| std::cmp::Reverse(e.bytes)),
+            SortBy::Age => entries.sort_by_key(|(age, _)| std::cmp::Reverse(*age)),
+        }
+        entries.into_iter().take(limit).map(|(_, e)| e).collect()
+    }
+}
+
+/// How long a response may stand in for errors: the `stale-if-error=N` it carries, else
+/// the mapping's window. `None` for responses not to be kept (`no-store`, `private`,
+/// `Set-Cookie`).
+pub fn stale_window(headers: &HeaderMap, mapping_window: Duration) -> Option<Duration> {
+    if headers.contains_key(SET_COOKIE) {
+        return None;
+    }
+    let mut window = mapping_window;
+    for directive in headers.get_all(CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+        let (name, value) = directive.trim().split_once('=').unwrap_or((directive.trim(), ""));
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "private" => return None,
+            "stale-if-error" => {
+                if let Ok(secs) = value.trim_matches('"').parse() {
+                    window = Duration::from_secs(secs);
+                }
+            }
+            _ => {}
+        }
+    }
+    (!window.is_zero()).then_some(window)
+}
+
+/// Backend outcomes a stale copy may replace (RFC 5861 §4).
+pub fn is_error_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 500 | 502 | 503 | 504)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (name, value) in pairs {
+            h.append(*name, value.parse().unwrap());
+        }
+        h
+    }
+
+    #[test]
+    fn test_stale_window() {
+        let window = Duration::from_secs(60);
+        assert_eq!(stale_window(&HeaderMap::new(), window), Some(window));
+        assert_eq!(stale_window(&headers(&[("cache-control", "max-age=10, stale-if-error=600")]), window), Some(Duration::from_secs(600)));
+        assert_eq!(stale_window(&headers(&[("cache-control", "stale-if-error=0")]), window), None);
+        assert_eq!(stale_window(&headers(&[("cache-control", "private")]), window), None);
+        assert_eq!(stale_window(&headers(&[("set-cookie", "a=b")]), window), None);
+    }
+
+    fn key(path: &str, request: &[(&'static str, &'static str)]) -> CacheKey {
+        let pq = path.to_string();
+        CacheKey::new("m1", "a.test", &pq, path.split('?').next().unwrap(), &headers(request))
+    }
+
+    #[tokio::test]
+    async fn test_store_expire_evict_and_purge() {
+        let cache = ResponseCache::new(16 * 100);
+        let h = headers(&[("content-type", "text/plain"), ("connection", "close")]);
+        cache.store(&key("/x", &[]), StatusCode::OK, &h, Bytes::from("one"), Duration::from_secs(60));
+        let stale = cache.stale(&key("/x", &[])).await.unwrap();
+        assert_eq!(stale.body, "one");
+        assert!(stale.headers.get("connection").is_none());
+
+        cache.store(&key("/gone", &[]), StatusCode::OK, &h, Bytes::from("old"), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.stale(&key("/gone", &[])).await.is_none());
+
+        // Too big for one entry
+        cache.store(&key("/big", &[]), StatusCode::OK, &h, Bytes::from(vec![0; 200]), Duration::from_secs(60));
+        assert!(cache.stale(&key("/big", &[])).await.is_none());
+
+        // Over budget: oldest first
+        for i in 0..40 {
+            cache.store(&key(&format!("/api/{}", i), &[]), StatusCode::OK, &h, Bytes::from(vec![0; 40]), Duration::from_secs(60));
+        }
+        assert!(cache.stale(&key("/x", &[])).await.is_none());
+        assert!(cache.stale(&key("/api/39", &[])).await.is_some());
+
+        let purged = cache.purge(Some("/api/3"));
+        assert!(purged > 0);
+        assert!(cache.stale(&key("/api/39", &[])).await.is_none());
+        assert!(cache.stale(&key("/api/29", &[])).await.is_some());
+        assert!(cache.purge(None) > 0);
+        assert!(cache.stale(&key("/api/29", &[])).await.is_none());
+        assert_eq!((cache.stats().entries, cache.stats().bytes), (0, 0));
+
+        let counters = cache.stats().mappings["m1"];
+        assert_eq!((counters.stores, counters.expired), (42, 1));
+        assert_eq!((counters.hits, counters.misses), (3, 5));
+    }
+
+    #[tokio::test]
+    async fn test_vary_and_listing() {
+        let cache = ResponseCache::new(1 << 20);
+        let vary = headers(&[("vary", "Accept-Encoding"), ("vary", "origin")]);
+        let gzip = [("accept-encoding", "gzip"), ("origin", "https://a.test")];
+        let plain = [("origin", "https://a.test")];
+        cache.store(&key("/v", &gzip), StatusCode::OK, &vary, Bytes::from("gzipped"), Duration::from_secs(60));
+        cache.store(&key("/v", &plain), StatusCode::OK, &vary, Bytes::from("plain text body"), Duration::from_secs(60));
+        assert_eq!(cache.stale(&key("/v", &gzip)).await.unwrap().body, "gzipped");
+        assert_eq!(cache.stale(&key("/v", &plain)).await.unwrap().body, "plain text body");
+        assert!(cache.stale(&key("/v", &[("accept-encoding", "br"), ("origin", "https://a.test")])).await.is_none());
+
+        // Vary: * is never reused
+        cache.store(&key("/star", &[]), StatusCode::OK, &headers(&[("vary", "*")]), Bytes::from("x"), Duration::from_secs(60));
+        assert!(cache.stale(&key("/star", &[])).await.is_none());
+
+        let largest = cache.entries(SortBy::Size, 1);
+        assert_eq!(largest.len(), 1);
+        assert_eq!((largest[0].key.as_str(), largest[0].variant.as_str()), ("a.test/v", "accept-encoding=, origin=https://a.test"));
+        assert_eq!(cache.entries(SortBy::Age, 5)[0].variant, "accept-encoding=gzip, origin=https://a.test");
+
+        let counters = cache.stats().mappings["m1"];
+        assert_eq!((counters.stores, counters.hits, counters.misses), (2, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_disk_tier() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || ResponseCache::new(16 * 100).with_disk(DiskCache::open(dir.path(), 1 << 20, 10).unwrap());
+        let cache = open();
+        let h = headers(&[("content-type", "image/png")]);
+        cache.store(&key("/logo.png", &[]), StatusCode::OK, &h, Bytes::from(vec![7; 1000]), Duration::from_secs(60));
+        while cache.stats().disk.unwrap().entries == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(cache.stats().entries, 0, "too big for memory");
+        assert_eq!(cache.stale(&key("/logo.png", &[])).await.unwrap().body.len(), 1000);
+        assert_eq!(cache.stats().mappings["m1"].disk_hits, 1);
+
+        // A new process finds it on disk
+        drop(cache);
+        let cache = open();
+        assert!(cache.stale(&key("/logo.png", &[])).await.is_some());
+        assert_eq!(cache.entries(SortBy::Size, 10)[0].tier, "disk");
+        assert_eq!(cache.purge(Some("/logo")), 1);
+        assert!(cache.stale(&key("/logo.png", &[])).await.is_none());
+    }
+}
@@ -0,0 +1,236 @@
+//! Certificate coordination across nodes
+//! Nodes serving the same domains elect one holder of the certificate lease: only it runs
+//! the renewal command, so a renewal is one ACME order rather than one per node, and in
+//! cluster mode the other nodes copy its certificate files
+
+use crate::lease::Lease;
+use crate::events::{Event, Events};
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Name of the lease
+pub const LEASE_NAME: &str = "certificates";
+/// How long the lease outlives its holder's last renewal; it is renewed every third of that
+pub const LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// A certificate or key file, by its path relative to `certs_dir`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CertFile {
+    pub path: String,
+    pub pem: String,
+}
+
+/// Whether `path` is a file of one of the layouts the TLS store reads: `<name>.crt`,
+/// `<name>.key`, or `<name>/fullchain.pem` and `<name>/privkey.pem`.
+fn is_cert_path(path: &str) -> bool {
+    let plain = |name: &str| !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']);
+    match path.split_once('/') {
+        Some((dir, file)) => plain(dir) && matches!(file, "fullchain.pem" | "privkey.pem"),
+        None => plain(path) && (path.ends_with(".crt") || path.ends_with(".key")),
+    }
+}
+
+/// Key files sort first, so a pair is never replaced as a new certificate with an old key.
+fn is_key(path: &str) -> bool {
+    path.ends_with(".key") || path.ends_with("privkey.pem")
+}
+
+pub struct CertLeader {
+    lease: Lease,
+    leader: AtomicBool,
+    certs_dir: PathBuf,
+    /// Shell command renewing certificates into `certs_dir` (e.g. `certbot renew ...`)
+    renew_command: Option<String>,
+    renew_interval: Duration,
+    last_renewal: Mutex<Option<Instant>>,
+    renewing: AtomicBool,
+    events: Events,
+}
+
+impl CertLeader {
+    pub fn new(lease: Lease, certs_dir: &Path, renew_command: Option<String>, renew_interval: Duration) -> Self {
+        Self {
+            lease,
+            leader: AtomicBool::new(false),
+            certs_dir: certs_dir.to_path_buf(),
+            renew_command,
+            renew_interval,
+            last_renewal: Mutex::new(None),
+            renewing: AtomicBool::new(false),
+            events: Events::default(),
+        }
+    }
+
+    /// Report failed renewals to `events`.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Whether this node held the lease at the last tick.
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    /// Take or keep the lease; as its holder, start a renewal when one is due.
+    pub async fn tick(self: &Arc<Self>) {
+        let leader = self.lease.acquire().await.unwrap_or_else(|e| {
+            warn!("Certificate lease unavailable: {:#}", e);
+            false
+        });
+        if leader != self.leader.swap(leader, Ordering::Relaxed) {
+            if leader {
+                info!("This node now coordinates certificate renewal");
+            } else {
+                info!("Another node coordinates certificate renewal now");
+                *self.last_renewal.lock() = None;
+            }
+        }
+        if leader {
+            self.renew_if_due();
+        }
+    }
+
+    /// Run the renewal command in the background, so the lease keeps being renewed
+    /// however long it takes.
+    fn renew_if_due(self: &Arc<Self>) {
+        let Some(command) = self.renew_command.clone() else {
+            return;
+        };
+        if self.last_renewal.lock().is_some_and(|t| t.elapsed() < self.renew_interval)
+            || self.renewing.swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+        *self.last_renewal.lock() = Some(Instant::now());
+        let this = self.clone();
+        tokio::spawn(async move {
+            info!("Renewing certificates: {}", command);
+            let status = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .env("CERTS_DIR", &this.certs_dir)
+                .kill_on_drop(true)
+                .status()
+                .await;
+            match status {
+                Ok(s) if s.success() => info!("Certificate renewal finished"),
+                Ok(s) => {
+                    warn!("Certificate renewal failed: {}", s);
+                    this.events.emit(Event::CertFailed { domain: None, error: format!("renewal command {}", s) });
+                }
+                Err(e) => {
+                    warn!("Could not run certificate renewal: {}", e);
+                    this.events.emit(Event::CertFailed { domain: None, error: format!("renewal command: {}", e) });
+                }
+            }
+            this.renewing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// The certificate and key files in `certs_dir`, sorted by path.
+    pub fn bundle(&self) -> Result<Vec<CertFile>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.certs_dir)?.flatten() {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else { continue };
+            let paths = if entry.path().is_dir() {
+                vec![format!("{}/fullchain.pem", name), format!("{}/privkey.pem", name)]
+            } else {
+                vec![name]
+            };
+            for path in paths.into_iter().filter(|p| is_cert_path(p)) {
+                if let Ok(pem) = fs::read_to_string(self.certs_dir.join(&path)) {
+                    files.push(CertFile { path, pem });
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+
+    /// Write the leader's files that differ from the local ones (keys first, each through a
+    /// rename so the TLS store never reads half a file). Returns how many were written.
+    pub fn install(&self, files: &[CertFile]) -> Result<usize> {
+        if let Some(bad) = files.iter().find(|f| !is_cert_path(&f.path)) {
+            bail!("refusing certificate path '{}'", bad.path);
+        }
+        let mut ordered: Vec<&CertFile> = files.iter().collect();
+        ordered.sort_by_key(|f| !is_key(&f.path));
+        let mut written = 0;
+        for file in ordered {
+            let path = self.certs_dir.join(&file.path);
+            if fs::read_to_string(&path).is_ok_and(|pem| pem == file.pem) {
+                continue;
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &file.pem)?;
+            fs::rename(&tmp, &path)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+/// Digest of a bundle, for followers to tell whether the leader's files changed.
+pub fn digest(files: &[CertFile]) -> u64 {
+    crate::cluster::fnv1a(&serde_json::to_vec(files).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseManager;
+    use crate::lease::LeaseStore;
+
+    fn leader(dir: &Path) -> CertLeader {
+        let db = Arc::new(DatabaseManager::new(dir.join("test.db")).unwrap());
+        let lease = Lease::new(LeaseStore::Database(db), LEASE_NAME, LEASE_TTL);
+        CertLeader::new(lease, dir, None, Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn test_cert_paths() {
+        assert!(is_cert_path("example.com.crt"));
+        assert!(is_cert_path("wildcard.example.com.key"));
+        assert!(is_cert_path("example.com/privkey.pem"));
+        assert!(!is_cert_path("../etc/passwd.crt"));
+        assert!(!is_cert_path("a/b/fullchain.pem"));
+        assert!(!is_cert_path(".hidden.crt"));
+        assert!(!is_cert_path("test.db"));
+    }
+
+    #[test]
+    fn test_bundle_and_install() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let manager = crate::CertificateManager::new(from.path(), None).unwrap();
+        manager.generate_self_signed("site.test", &["site.test"]).unwrap();
+        fs::create_dir(from.path().join("live.test")).unwrap();
+        fs::write(from.path().join("live.test/fullchain.pem"), "chain").unwrap();
+        fs::write(from.path().join("live.test/privkey.pem"), "key").unwrap();
+
+        let bundle = leader(from.path()).bundle().unwrap();
+        let paths: Vec<&str> = bundle.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, [
+            "live.test/fullchain.pem", "live.test/privkey.pem",
+            "localhost.crt", "localhost.key", "site.test.crt", "site.test.key",
+        ]);
+
+        let follower = leader(to.path());
+        assert_eq!(follower.install(&bundle).unwrap(), 6);
+        assert_eq!(follower.install(&bundle).unwrap(), 0);
+        assert_eq!(digest(&follower.bundle().unwrap()), digest(&bundle));
+
+        let evil = [CertFile { path: "../escape.crt".to_string(), pem: String::new() }];
+        assert!(follower.install(&evil).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! Certificate preflight
+//! `rustproxy cert preflight <domain>` checks what a CA checks before issuing over HTTP-01,
+//! so a misconfiguration shows up as a plain sentence instead of a failed ACME order (and a
+//! used-up attempt against Let's Encrypt's rate limits): that the domain resolves (to this
+//! host, when its addresses are local), that a token published in the database comes back
+//! from `http://<domain>/.well-known/acme-challenge/` — the path the CA takes, through DNS
+//! and any NAT or firewall on the way — that port 443 answers for the certificate to be of
+//! use, and that no CAA record keeps the CA from issuing
+
+use crate::database::DatabaseManager;
+use anyhow::{anyhow, Result};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::rr::rdata::caa::{Property, Value};
+use hickory_resolver::proto::rr::{RData, RecordType};
+use hickory_resolver::TokioAsyncResolver;
+use serde::Serialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a check came out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Issuance works, but something is likely off
+    Warn,
+    /// The CA would refuse
+    Fail,
+}
+
+/// One check and what to do about it
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self { name, status, detail: detail.into() }
+    }
+}
+
+/// The checks run for a domain
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub domain: String,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Whether issuance can be attempted: no check failed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != Status::Fail)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Preflight for {}", self.domain)?;
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "  {:<5} {:<6} {}", status, check.name, check.detail)?;
+        }
+        match self.passed() {
+            true => writeln!(f, "Ready for ACME issuance"),
+            false => writeln!(f, "Fix the failed checks before ordering a certificate"),
+        }
+    }
+}
+
+/// Run every check for `domain`, publishing the HTTP probe's token in `db` (which the
+/// proxy answering port 80 must use). `ca` is the CA's CAA identifier, e.g.
+/// `letsencrypt.org`.
+pub async fn run(db: &DatabaseManager, domain: &str, ca: &str) -> Result<Report> {
+    let domain = crate::host::normalize_domain(domain)
+        .ok_or_else(|| anyhow!("invalid domain '{}'", domain))?
+        .into_owned();
+    if let Some(base) = domain.strip_prefix("*.") {
+        return Ok(Report {
+            checks: vec![Check::new(
+                "domain",
+                Status::Fail,
+                format!("a wildcard certificate can only be validated over DNS-01; run the preflight for {} to check the HTTP-01 path", base),
+            )],
+            domain,
+        });
+    }
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| anyhow!("Failed to load system resolver config: {}", e))?;
+
+    let mut checks = Vec::new();
+    let addresses = match resolver.lookup_ip(domain.as_str()).await {
+        Ok(lookup) => lookup.iter().collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    checks.push(dns_check(&domain, &addresses));
+    if !addresses.is_empty() {
+        checks.push(http_check(db, &domain).await);
+        checks.push(https_check(&domain, &addresses).await);
+    }
+    checks.push(match caa_records(&resolver, &domain).await {
+        Ok((name, records)) => caa_check(&name, &records, ca),
+        Err(e) => Check::new("caa", Status::Fail, format!("{}; CAs refuse to issue while CAA can't be looked up, so fix the zone's name servers", e)),
+    });
+    Ok(Report { domain, checks })
+}
+
+/// Whether `ip` is one of this host's own: it can be bound to.
+fn is_local(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+fn dns_check(domain: &str, addresses: &[IpAddr]) -> Check {
+    if addresses.is_empty() {
+        return Check::new("dns", Status::Fail, format!("{} doesn't resolve; add an A (or AAAA) record with this host's public address", domain));
+    }
+    let list = addresses.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ");
+    if let Some(ip) = addresses.iter().find(|ip| ip.is_loopback() || is_private(**ip)) {
+        return Check::new("dns", Status::Fail, format!("{} resolves to {}, which the CA can't reach; {} must be a public address", domain, list, ip));
+    }
+    match addresses.iter().any(|ip| is_local(*ip)) {
+        true => Check::new("dns", Status::Ok, format!("{} -> {} (this host)", domain, list)),
+        // Behind NAT the public address isn't on an interface; the HTTP probe tells
+        false => Check::new("dns", Status::Warn, format!("{} -> {}, not an address of this host (fine behind NAT or a load balancer)", domain, list)),
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Publish a token and fetch it back the way the CA will.
+async fn http_check(db: &DatabaseManager, domain: &str) -> Check {
+    let token = format!("preflight-{}", uuid::Uuid::new_v4().simple());
+    let expected = format!("{}.rustproxy-preflight", token);
+    let url = crate::domain_verify::http_url(domain, &token).expect("not a wildcard");
+    if let Err(e) = db.put_acme_challenge(&token, &expected) {
+        return Check::new("http", Status::Fail, format!("can't publish the probe token: {:#}", e));
+    }
+    let check = match fetch(&url).await {
+        Ok((status, body)) if status.is_success() && body.trim() == expected => {
+            Check::new("http", Status::Ok, "port 80 reaches this proxy; challenge tokens are served")
+        }
+        Ok((status, _)) if status == reqwest::StatusCode::NOT_FOUND => Check::new(
+            "http",
+            Status::Fail,
+            "port 80 answers 404 for the token: the proxy there uses another database (see --db-path), or ACME_CHALLENGES_MAPPED_ONLY is set and the domain has no mapping",
+        ),
+        Ok((status, _)) => Check::new(
+            "http",
+            Status::Fail,
+            format!("{} answered {} instead of the token: something other than this proxy has port 80 (or redirects the challenge path)", url, status),
+        ),
+        Err(e) if e.is_timeout() => Check::new(
+            "http",
+            Status::Fail,
+            format!("{} timed out: a firewall or security group drops port 80; open it to the internet, the CA connects from several places", url),
+        ),
+        Err(e) if e.is_connect() => Check::new(
+            "http",
+            Status::Fail,
+            format!("nothing accepts connections on port 80 of {}: run the proxy with --production (or HTTP_PORT=80), or forward port 80 to it", domain),
+        ),
+        Err(e) => Check::new("http", Status::Fail, format!("fetching {}: {}", url, e)),
+    };
+    let _ = db.delete_acme_challenge(&token);
+    check
+}
+
+async fn fetch(url: &str) -> reqwest::Result<(reqwest::StatusCode, String)> {
+    let http = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+    let resp = http.get(url).send().await?;
+    let status = resp.status();
+    Ok((status, resp.text().await.unwrap_or_default()))
+}
+
+async fn https_check(domain: &str, addresses: &[IpAddr]) -> Check {
+    let addr = SocketAddr::new(addresses[0], 443);
+    match tokio::time::timeout(TIMEOUT, tokio::net::TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Check::new("https", Status::Ok, format!("port 443 of {} accepts connections", addr.ip())),
+        Ok(Err(e)) => Check::new("https", Status::Warn, format!("port 443 of {} refused ({}); the certificate can be issued, but browsers won't reach it until HTTPS is enabled (--production or --enable-https)", domain, e)),
+        Err(_) => Check::new("https", Status::Warn, format!("port 443 of {} timed out; open it in the firewall for the certificate to be of use", domain)),
+    }
+}
+
+/// A CAA record, as far as issuance cares
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caa {
+    pub critical: bool,
+    /// `issue`, `issuewild`, `iodef`, ...
+    pub tag: String,
+    /// CA identifier of `issue`/`issuewild`; `None` for `;` (no CA)
+    pub issuer: Option<String>,
+}
+
+/// The CAA records that apply to `domain`: those of the closest name up the tree that has
+/// any (RFC 8659), with that name.
+async fn caa_records(resolver: &TokioAsyncResolver, domain: &str) -> Result<(String, Vec<Caa>)> {
+    let labels: Vec<&str> = domain.split('.').collect();
+    for i in 0..labels.len() {
+        let name = labels[i..].join(".");
+        match resolver.lookup(format!("{}.", name), RecordType::CAA).await {
+            Ok(lookup) => {
+                let records: Vec<Caa> = lookup
+                    .iter()
+                    .filter_map(|rdata| match rdata {
+                        RData::CAA(caa) => Some(Caa {
+                            critical: caa.issuer_critical(),
+                            tag: caa.tag().as_str().to_ascii_lowercase(),
+                            issuer: match (caa.tag(), caa.value()) {
+                                (Property::Issue | Property::IssueWild, Value::Issuer(issuer, _)) => {
+                                    issuer.as_ref().map(|n| n.to_string().trim_end_matches('.').to_ascii_lowercase())
+                                }
+                                _ => None,
+                            },
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                if !records.is_empty() {
+                    return Ok((name, records));
+                }
+            }
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {}
+            Err(e) => return Err(anyhow!("CAA lookup for {} failed: {}", name, e)),
+        }
+    }
+    Ok((domain.to_string(), Vec::new()))
+}
+
+/// Whether the CAA `records` found at `name` let `ca` issue for it.
+pub fn caa_check(name: &str, records: &[Caa], ca: &str) -> Check {
+    if let Some(unknown) = records.iter().find(|r| r.critical && !matches!(r.tag.as_str(), "issue" | "issuewild" | "iodef")) {
+        return Check::new("caa", Status::Fail, format!("{} has a CAA record with the unknown critical tag '{}', which makes every CA refuse; remove it", name, unknown.tag));
+    }
+    let issuers: Vec<&Caa> = records.iter().filter(|r| r.tag == "issue").collect();
+    if issuers.is_empty() {
+        return Check::new("caa", Status::Ok, match records.is_empty() {
+            true => "no CAA records; any CA may issue".to_string(),
+            false => format!("CAA records at {} don't restrict issuance", name),
+        });
+    }
+    if issuers.iter().any(|r| r.issuer.as_deref() == Some(ca)) {
+        return Check::new("caa", Status::Ok, format!("CAA at {} allows {}", name, ca));
+    }
+    let allowed: Vec<&str> = issuers.iter().filter_map(|r| r.issuer.as_deref()).collect();
+    Check::new(
+        "caa",
+        Status::Fail,
+        match allowed.is_empty() {
+            true => format!("CAA at {} forbids every CA (issue \";\"); add: {} CAA 0 issue \"{}\"", name, name, ca),
+            false => format!("CAA at {} only allows {}; add: {} CAA 0 issue \"{}\"", name, allowed.join(", "), name, ca),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caa(tag: &str, issuer: Option<&str>) -> Caa {
+        Caa { critical: false, tag: tag.into(), issuer: issuer.map(String::from) }
+    }
+
+    #[test]
+    fn test_caa_check() {
+        let le = "letsencrypt.org";
+        assert_eq!(caa_check("example.com", &[], le).status, Status::Ok);
+        assert_eq!(caa_check("example.com", &[caa("iodef", None)], le).status, Status::Ok);
+        assert_eq!(caa_check("example.com", &[caa("issue", Some("digicert.com")), caa("issue", Some(le))], le).status, Status::Ok);
+
+        let other = caa_check("example.com", &[caa("issue", Some("digicert.com"))], le);
+        assert_eq!(other.status, Status::Fail);
+        assert!(other.detail.contains("only allows digicert.com; add: example.com CAA 0 issue \"letsencrypt.org\""), "{}", other.detail);
+        assert!(caa_check("example.com", &[caa("issue", None)], le).detail.contains("forbids every CA"));
+        let critical = Caa { critical: true, tag: "tbs".into(), issuer: None };
+        assert_eq!(caa_check("example.com", &[caa("issue", Some(le)), critical], le).status, Status::Fail);
+    }
+
+    #[test]
+    fn test_dns_check() {
+        assert_eq!(dns_check("example.com", &[]).status, Status::Fail);
+        let private = dns_check("example.com", &["10.0.0.5".parse().unwrap()]);
+        assert!(private.detail.contains("must be a public address"), "{}", private.detail);
+        assert_eq!(dns_check("example.com", &["203.0.113.5".parse().unwrap()]).status, Status::Warn);
+
+        let report = Report { domain: "example.com".into(), checks: vec![Check::new("https", Status::Warn, "closed")] };
+        assert!(report.passed());
+        assert!(report.to_string().ends_with("Ready for ACME issuance\n"));
+    }
+}
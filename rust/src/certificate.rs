@@ -1,8 +1,10 @@
 //! Certificate manager for SSL/TLS certificate handling
 //! Supports self-signed certificates and ACME (Let's Encrypt) integration
 
+use crate::database::DatabaseManager;
 use anyhow::Result;
 use dashmap::DashMap;
+use once_cell::sync::OnceCell;
 use rcgen::generate_simple_self_signed;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -34,6 +36,8 @@ struct RateLimitState {
 pub struct CertificateManager {
     certs_dir: PathBuf,
     acme_challenges: DashMap<String, AcmeChallenge>,
+    /// Shared challenge store, so any node behind round-robin DNS can answer a token
+    challenge_db: OnceCell<Arc<DatabaseManager>>,
     /// In-flight ACME HTTP-01 reachability test challenges: token -> value
     test_challenges: DashMap<String, String>,
     rate_limits: DashMap<String, RateLimitState>,
@@ -60,6 +64,7 @@ impl CertificateManager {
         let manager = Self {
             certs_dir,
             acme_challenges: DashMap::new(),
+            challenge_db: OnceCell::new(),
             test_challenges: DashMap::new(),
             rate_limits: DashMap::new(),
             acme_capable: DashMap::new(),
@@ -126,10 +131,10 @@ impl CertificateManager {
             }
 
             // Check weekly limit (5 per week)
-            if now.duration_since(state.week_start) < Duration::from_secs(7 * 24 * 60 * 60) {
-                if state.weekly_count >= 5 {
-                    return true;
-                }
+            if now.duration_since(state.week_start) < Duration::from_secs(7 * 24 * 60 * 60)
+                && state.weekly_count >= 5
+            {
+                return true;
             }
         }
         false
@@ -172,27 +177,48 @@ impl CertificateManager {
         self.test_challenges.remove(token);
     }
 
+    /// Keep ACME challenges in `db` too, where every process using it (and the mapping
+    /// CLI, for certbot hooks) can read and add them. Set once; later calls are ignored.
+    pub fn use_database(&self, db: Arc<DatabaseManager>) {
+        let _ = self.challenge_db.set(db);
+    }
+
     /// Store ACME challenge token
     pub fn store_acme_challenge(&self, token: &str, key_authorization: &str) {
         self.acme_challenges.insert(token.to_string(), AcmeChallenge {
             token: token.to_string(),
             key_authorization: key_authorization.to_string(),
         });
+        if let Some(db) = self.challenge_db.get() {
+            if let Err(e) = db.put_acme_challenge(token, key_authorization) {
+                warn!("Could not share ACME challenge {}: {:#}", token, e);
+            }
+        }
     }
 
-    /// Get ACME challenge response
+    /// Get ACME challenge response: from this process, else from the shared database
     pub fn get_acme_challenge(&self, token: &str) -> Option<String> {
-        self.acme_challenges.get(token).map(|c| c.key_authorization.clone())
+        if let Some(c) = self.acme_challenges.get(token) {
+            return Some(c.key_authorization.clone());
+        }
+        let db = self.challenge_db.get()?;
+        db.get_acme_challenge(token)
+            .map_err(|e| warn!("Could not read ACME challenge {}: {:#}", token, e))
+            .ok()
+            .flatten()
     }
 
     /// Remove ACME challenge
-    #[allow(dead_code)]
     pub fn remove_acme_challenge(&self, token: &str) {
         self.acme_challenges.remove(token);
+        if let Some(db) = self.challenge_db.get() {
+            if let Err(e) = db.delete_acme_challenge(token) {
+                warn!("Could not remove ACME challenge {}: {:#}", token, e);
+            }
+        }
     }
 
     /// Get certs directory path
-    #[allow(dead_code)]
     pub fn certs_dir(&self) -> &Path {
         &self.certs_dir
     }
@@ -231,7 +257,7 @@ impl CertificateManager {
             let capable = this.run_probe(&domain).await;
             if capable {
                 info!("ACME capability restored for {} — cleared self-signed block", domain);
-                // No in-memory cert cache exists in the Rust version; disk is authoritative.
+                // Disk is authoritative; the TLS cert store picks up files as they change.
             }
             this.reprobing_domains.remove(&domain);
         });
@@ -312,4 +338,24 @@ mod tests {
         manager.remove_acme_challenge("token123");
         assert!(manager.get_acme_challenge("token123").is_none());
     }
+
+    #[test]
+    fn test_acme_challenge_shared_through_database() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+        let issuing = CertificateManager::new(dir.path().join("a"), None).unwrap();
+        let answering = CertificateManager::new(dir.path().join("b"), None).unwrap();
+        issuing.use_database(db.clone());
+        answering.use_database(Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap()));
+
+        issuing.store_acme_challenge("tok", "tok.thumbprint");
+        assert_eq!(answering.get_acme_challenge("tok").as_deref(), Some("tok.thumbprint"));
+
+        // Written by a certbot hook through the CLI
+        db.put_acme_challenge("hook", "hook.thumbprint").unwrap();
+        assert_eq!(answering.get_acme_challenge("hook").as_deref(), Some("hook.thumbprint"));
+
+        issuing.remove_acme_challenge("tok");
+        assert!(answering.get_acme_challenge("tok").is_none());
+    }
 }
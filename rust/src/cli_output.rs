@@ -0,0 +1,209 @@
+//! CLI output
+//! `rustproxy-mapping` prints for people (`--output table`, the default), for scripts
+//! (`plain`: one record per line, fields tab-separated, no headers or hints) or as JSON, and
+//! exits with one code per class of failure, so a provisioning pipeline can tell a missing
+//! mapping from a stale version without parsing messages. `--quiet` leaves out
+//! confirmations and hints; what a command is run for — a listing, a new key or token, a
+//! signed URL — is printed regardless. Errors go to stderr, as a JSON object with `--output
+//! json`
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::str::FromStr;
+
+/// How results are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Plain,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "table" => Ok(Format::Table),
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected table, plain or json, got '{}'", s)),
+        }
+    }
+}
+
+/// Why a command failed; each class has its own exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Anything unexpected: the database, a file, the network
+    Error,
+    /// Bad or missing arguments (also what clap exits with)
+    Usage,
+    /// No such mapping, tenant, key or ban
+    NotFound,
+    /// The state doesn't allow it: changed since the given version, already taken
+    Conflict,
+    /// Not for this tenant, or for the operator only
+    Forbidden,
+    /// An input file was rejected
+    Invalid,
+    /// A check ran and didn't pass (lint findings, domain verification)
+    Failed,
+}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Error => 1,
+            Failure::Usage => 2,
+            Failure::NotFound => 3,
+            Failure::Conflict => 4,
+            Failure::Forbidden => 5,
+            Failure::Invalid => 6,
+            Failure::Failed => 7,
+        }
+    }
+
+    /// The class of an error that wasn't expected: constraint violations are conflicts.
+    pub fn of(e: &anyhow::Error) -> Failure {
+        match e.chain().find_map(|cause| cause.downcast_ref::<rusqlite::Error>()) {
+            Some(rusqlite::Error::SqliteFailure(err, _)) if err.code == rusqlite::ErrorCode::ConstraintViolation => Failure::Conflict,
+            _ => Failure::Error,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Error => "error",
+            Failure::Usage => "usage",
+            Failure::NotFound => "not_found",
+            Failure::Conflict => "conflict",
+            Failure::Forbidden => "forbidden",
+            Failure::Invalid => "invalid",
+            Failure::Failed => "failed",
+        })
+    }
+}
+
+/// `{"error": {"class": "not_found", "exit_code": 3, "message": ..., "details": [...]}}`;
+/// details only when there are some
+pub fn error_json(failure: Failure, message: &str, details: &[String]) -> Value {
+    let mut error = json!({ "class": failure.to_string(), "exit_code": failure.code(), "message": message });
+    if !details.is_empty() {
+        error["details"] = json!(details);
+    }
+    json!({ "error": error })
+}
+
+/// One `plain` record: the fields tab-separated, with tabs and line breaks in them escaped
+/// so a record stays one line.
+pub fn record<I, T>(fields: I) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: fmt::Display,
+{
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|f| f.to_string().replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r"))
+        .collect();
+    fields.join("\t")
+}
+
+/// Where a command's results go
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    pub format: Format,
+    pub quiet: bool,
+}
+
+impl Output {
+    pub fn is_json(&self) -> bool {
+        self.format == Format::Json
+    }
+
+    /// A finished change: `text` for people, `plain` as one record, or `json`. Nothing
+    /// with `--quiet`.
+    pub fn done(&self, text: impl fmt::Display, plain: impl fmt::Display, json: Value) {
+        if self.quiet {
+            return;
+        }
+        match self.format {
+            Format::Table => println!("{}", text),
+            Format::Plain => println!("{}", plain),
+            Format::Json => self.json(&json),
+        }
+    }
+
+    /// Advice for people, such as how to undo; table output only.
+    pub fn hint(&self, text: impl fmt::Display) {
+        if self.format == Format::Table && !self.quiet {
+            println!("{}", text);
+        }
+    }
+
+    /// Print `value` as JSON, whatever `--quiet` says: it's what the command is for.
+    pub fn json<T: Serialize + ?Sized>(&self, value: &T) {
+        match serde_json::to_string_pretty(value) {
+            Ok(text) => println!("{}", text),
+            Err(e) => self.fail(Failure::Error, &format!("can't print the result: {}", e)),
+        }
+    }
+
+    /// Stop with `failure`'s exit code, telling why on stderr.
+    pub fn fail(&self, failure: Failure, message: &str) -> ! {
+        self.fail_with(failure, message, &[])
+    }
+
+    /// Stop like [`Output::fail`], listing each of `details` (the problems found) first.
+    pub fn fail_with(&self, failure: Failure, message: &str, details: &[String]) -> ! {
+        match self.format {
+            Format::Json => eprintln!("{}", error_json(failure, message, details)),
+            _ => {
+                for detail in details {
+                    eprintln!("{}", detail);
+                }
+                if !details.is_empty() {
+                    eprintln!();
+                }
+                eprintln!("{}", message);
+            }
+        }
+        std::process::exit(failure.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures() {
+        let all = [Failure::Error, Failure::Usage, Failure::NotFound, Failure::Conflict, Failure::Forbidden, Failure::Invalid, Failure::Failed];
+        let codes: Vec<i32> = all.iter().map(|f| f.code()).collect();
+        assert_eq!(codes, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(
+            error_json(Failure::NotFound, "No tenant acme", &[]),
+            json!({ "error": { "class": "not_found", "exit_code": 3, "message": "No tenant acme" } })
+        );
+        assert_eq!(error_json(Failure::Invalid, "x", &["line 2: bad port".into()])["error"]["details"], json!(["line 2: bad port"]));
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE t (id TEXT PRIMARY KEY)", []).unwrap();
+        conn.execute("INSERT INTO t VALUES ('a')", []).unwrap();
+        let duplicate = anyhow::Error::from(conn.execute("INSERT INTO t VALUES ('a')", []).unwrap_err()).context("add tenant");
+        assert_eq!(Failure::of(&duplicate), Failure::Conflict);
+        assert_eq!(Failure::of(&anyhow::anyhow!("disk full")), Failure::Error);
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!("JSON".parse::<Format>(), Ok(Format::Json));
+        assert_eq!("plain".parse::<Format>(), Ok(Format::Plain));
+        assert!("yaml".parse::<Format>().is_err());
+        assert_eq!(record(["api.example.com", "", "3000"]), "api.example.com\t\t3000");
+        assert_eq!(record(["a\tb", "c\nd", "e\\f"]), "a\\tb\tc\\nd\te\\\\f");
+    }
+}
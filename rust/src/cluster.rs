@@ -0,0 +1,350 @@
+//! Cluster mode (experimental)
+//! Nodes listing each other as peers pull routing state every few seconds: the mapping
+//! table, replicated whole with the most recent edit winning, HA health of backend
+//! targets, and the certificate leader's certificates, so a change made on any node
+//! reaches every edge without copying databases
+
+use crate::admin;
+use crate::cert_leader::{self, CertFile, CertLeader};
+use crate::database::{DatabaseManager, Mapping};
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use hyper::HeaderMap;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Path prefix of the endpoints peers pull from (`state`, `snapshot`)
+pub const PREFIX: &str = "/_proxy/cluster/";
+
+/// Health reports older than this are no longer passed on
+const HEALTH_TTL: Duration = Duration::from_secs(600);
+
+/// Settings key the revision is persisted under, so restarts keep their place
+const REVISION_SETTING: &str = "cluster_revision";
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Version of a node's mapping table: when it last changed (Unix ms) and a digest of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revision {
+    pub at: u64,
+    pub digest: u64,
+}
+
+impl Revision {
+    /// Whether a node at `self` should take the table of a node at `other`: the later edit
+    /// wins, the larger digest breaks ties.
+    pub fn is_behind(&self, other: &Revision) -> bool {
+        self.digest != other.digest && (self.at, self.digest) < (other.at, other.digest)
+    }
+}
+
+/// Latest known health of one HA target, keyed `{mapping_id}:{host}:{port}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub target: String,
+    pub healthy: bool,
+    /// Unix ms of the observation
+    pub at: u64,
+}
+
+/// Answer to `GET {PREFIX}state`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub revision: Revision,
+    pub health: Vec<HealthReport>,
+    /// Digest of its certificate files, from the node coordinating certificates
+    #[serde(default)]
+    pub certs: Option<u64>,
+}
+
+/// Answer to `GET {PREFIX}snapshot`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub revision: Revision,
+    pub mappings: Vec<Mapping>,
+}
+
+/// FNV-1a over the mappings' JSON in id order: equal on every node holding the same table.
+pub fn digest(mappings: &[Mapping]) -> u64 {
+    let mut sorted: Vec<&Mapping> = mappings.iter().collect();
+    sorted.sort_by(|a, b| a.id.cmp(&b.id));
+    fnv1a(&serde_json::to_vec(&sorted).unwrap_or_default())
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ *b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// `host:port` or a URL, as the base URL of a peer.
+fn peer_url(peer: &str) -> String {
+    let peer = peer.trim().trim_end_matches('/');
+    if peer.contains("://") { peer.to_string() } else { format!("http://{}", peer) }
+}
+
+pub struct Cluster {
+    peers: Vec<String>,
+    secret: String,
+    db: Arc<DatabaseManager>,
+    client: reqwest::Client,
+    revision: Mutex<Revision>,
+    /// Health reports by target, own and adopted
+    health: DashMap<String, (bool, u64)>,
+    /// Peers whose last sync failed, so only changes are logged
+    unreachable: DashMap<String, ()>,
+    /// Certificate coordination, when this node takes part in it
+    certs: Option<Arc<CertLeader>>,
+    /// Digest of the leader's certificate files last installed (0: none yet)
+    installed_certs: AtomicU64,
+}
+
+impl Cluster {
+    /// Cluster membership for `peers` (`host:port` of their HTTP listeners), authenticated
+    /// with a secret all nodes share. With `certs`, a node that doesn't hold the
+    /// certificate lease copies the certificates of the one that does.
+    pub fn new(
+        peers: &[String],
+        secret: &str,
+        db: Arc<DatabaseManager>,
+        certs: Option<Arc<CertLeader>>,
+    ) -> Result<Self> {
+        let revision = match db.get_setting(REVISION_SETTING)? {
+            Some(v) => serde_json::from_str(&v).context("invalid stored cluster revision")?,
+            None => Revision::default(),
+        };
+        Ok(Self {
+            peers: peers.iter().map(|p| peer_url(p)).filter(|p| p != "http://").collect(),
+            secret: secret.to_string(),
+            db,
+            client: reqwest::Client::builder().timeout(Duration::from_secs(5)).build()?,
+            revision: Mutex::new(revision),
+            health: DashMap::new(),
+            unreachable: DashMap::new(),
+            certs,
+            installed_certs: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a request carries the cluster secret as bearer token.
+    pub fn authorized(&self, headers: &HeaderMap) -> bool {
+        admin::authorized(headers, &self.secret)
+    }
+
+    /// Record a health change of a target seen by this node.
+    pub fn report_health(&self, target: &str, healthy: bool) {
+        self.health.insert(target.to_string(), (healthy, now_ms()));
+    }
+
+    pub fn revision(&self) -> Revision {
+        *self.revision.lock()
+    }
+
+    pub fn state(&self) -> State {
+        let oldest = now_ms().saturating_sub(HEALTH_TTL.as_millis() as u64);
+        self.health.retain(|_, (_, at)| *at >= oldest);
+        let health = self.health.iter()
+            .map(|e| HealthReport { target: e.key().clone(), healthy: e.value().0, at: e.value().1 })
+            .collect();
+        let certs = self.certs.as_ref()
+            .filter(|c| c.is_leader())
+            .and_then(|c| c.bundle().ok())
+            .map(|files| cert_leader::digest(&files));
+        State { revision: self.revision(), health, certs }
+    }
+
+    /// The certificate files of this node, if it coordinates certificates.
+    pub fn certs(&self) -> Option<Result<Vec<CertFile>>> {
+        self.certs.as_ref().filter(|c| c.is_leader()).map(|c| c.bundle())
+    }
+
+    /// The local mapping table with its revision.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mappings = self.db.list_mappings(None)?;
+        let revision = self.observe(&mappings)?;
+        Ok(Snapshot { revision, mappings })
+    }
+
+    /// Bump the revision when the table no longer matches it: an edit made on this node
+    /// (e.g. through the mapping CLI) since it was last looked at. A new node starting
+    /// out empty stays at time 0, so it takes its peers' table instead of wiping it.
+    fn observe(&self, mappings: &[Mapping]) -> Result<Revision> {
+        let digest = digest(mappings);
+        let mut revision = self.revision.lock();
+        if revision.digest != digest {
+            let at = if *revision == Revision::default() && mappings.is_empty() {
+                0
+            } else {
+                now_ms().max(revision.at + 1)
+            };
+            *revision = Revision { at, digest };
+            self.db.set_setting(REVISION_SETTING, &serde_json::to_string(&*revision)?)?;
+            debug!("Cluster: local mappings changed, revision {}", revision.at);
+        }
+        Ok(*revision)
+    }
+
+    /// Pick up local edits, then sync with every peer in turn. Returns the health
+    /// reports adopted from peers, for the caller to apply.
+    pub async fn sync_all(self: &Arc<Self>) -> Vec<HealthReport> {
+        let this = self.clone();
+        match tokio::task::spawn_blocking(move || this.db.list_mappings(None).and_then(|m| this.observe(&m))).await {
+            Ok(Err(e)) => warn!("Cluster: could not read local mappings: {:#}", e),
+            Err(e) => warn!("Cluster: could not read local mappings: {}", e),
+            Ok(Ok(_)) => {}
+        }
+        let mut adopted = Vec::new();
+        for peer in &self.peers {
+            match self.sync(peer).await {
+                Ok(reports) => {
+                    if self.unreachable.remove(peer).is_some() {
+                        info!("Cluster: peer {} reachable again", peer);
+                    }
+                    adopted.extend(reports);
+                }
+                Err(e) => {
+                    if self.unreachable.insert(peer.clone(), ()).is_none() {
+                        warn!("Cluster: peer {} unreachable: {:#}", peer, e);
+                    }
+                }
+            }
+        }
+        adopted
+    }
+
+    /// Adopt a peer's newer health reports and, if it is ahead, its mapping table.
+    async fn sync(&self, peer: &str) -> Result<Vec<HealthReport>> {
+        let state: State = self.get(peer, "state").await?;
+        let adopted = self.merge_health(state.health);
+
+        let follower = self.certs.as_ref().filter(|c| !c.is_leader());
+        if let (Some(local), Some(digest)) = (follower, state.certs) {
+            if self.installed_certs.load(Ordering::Relaxed) != digest {
+                let files: Vec<CertFile> = self.get(peer, "certs").await?;
+                let written = local.install(&files)?;
+                self.installed_certs.store(cert_leader::digest(&files), Ordering::Relaxed);
+                if written > 0 {
+                    info!("Cluster: installed {} certificate file(s) from {}", written, peer);
+                }
+            }
+        }
+
+        if self.revision().is_behind(&state.revision) {
+            let snapshot: Snapshot = self.get(peer, "snapshot").await?;
+            if digest(&snapshot.mappings) != snapshot.revision.digest {
+                bail!("snapshot digest mismatch (is the peer running another version?)");
+            }
+            if self.revision().is_behind(&snapshot.revision) {
+                let db = self.db.clone();
+                let count = snapshot.mappings.len();
+                tokio::task::spawn_blocking(move || db.replace_mappings(&snapshot.mappings)).await??;
+                *self.revision.lock() = snapshot.revision;
+                self.db.set_setting(REVISION_SETTING, &serde_json::to_string(&snapshot.revision)?)?;
+                info!("Cluster: took {} mapping(s) from {} (revision {})", count, peer, snapshot.revision.at);
+            }
+        }
+        Ok(adopted)
+    }
+
+    /// Keep the reports that are newer than what this node knows; returns those.
+    fn merge_health(&self, reports: Vec<HealthReport>) -> Vec<HealthReport> {
+        let oldest = now_ms().saturating_sub(HEALTH_TTL.as_millis() as u64);
+        reports.into_iter()
+            .filter(|r| r.at >= oldest)
+            .filter(|r| {
+                let mut newer = false;
+                self.health.entry(r.target.clone())
+                    .and_modify(|known| if r.at > known.1 {
+                        newer = known.0 != r.healthy;
+                        *known = (r.healthy, r.at);
+                    })
+                    .or_insert_with(|| {
+                        newer = true;
+                        (r.healthy, r.at)
+                    });
+                newer
+            })
+            .collect()
+    }
+
+    /// Ask the peers for an ACME HTTP-01 token this node doesn't know.
+    pub async fn acme_challenge(&self, token: &str) -> Option<String> {
+        if token.is_empty() || !token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return None;
+        }
+        for peer in &self.peers {
+            let resp = self.client.get(format!("{}{}challenge/{}", peer, PREFIX, token))
+                .bearer_auth(&self.secret)
+                .send().await;
+            match resp {
+                Ok(r) if r.status().is_success() => return r.text().await.ok(),
+                Ok(_) => {}
+                Err(e) => debug!("Cluster: challenge lookup at {} failed: {}", peer, e),
+            }
+        }
+        None
+    }
+
+    async fn get<T: DeserializeOwned>(&self, peer: &str, what: &str) -> Result<T> {
+        let resp = self.client.get(format!("{}{}{}", peer, PREFIX, what))
+            .bearer_auth(&self.secret)
+            .send().await?
+            .error_for_status()?;
+        Ok(serde_json::from_str(&resp.text().await?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(dir: &tempfile::TempDir) -> Cluster {
+        let db = Arc::new(DatabaseManager::new(dir.path().join("node.db")).unwrap());
+        Cluster::new(&["10.0.0.2:8080".to_string()], "s3cret", db, None).unwrap()
+    }
+
+    #[test]
+    fn test_revision_order() {
+        let old = Revision { at: 1, digest: 9 };
+        let new = Revision { at: 2, digest: 5 };
+        assert!(old.is_behind(&new));
+        assert!(!new.is_behind(&old));
+        assert!(!new.is_behind(&Revision { at: 3, digest: 5 }), "same table");
+        assert!(Revision { at: 2, digest: 4 }.is_behind(&new), "tie goes to the larger digest");
+        assert_eq!(peer_url("10.0.0.2:8080/"), "http://10.0.0.2:8080");
+    }
+
+    #[test]
+    fn test_local_edits_bump_persisted_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = cluster(&dir);
+        let empty = node.snapshot().unwrap().revision;
+        node.db.add_mapping("a.com", "", 3000, "", None, None, None, None, None).unwrap();
+        let edited = node.snapshot().unwrap().revision;
+        assert!(empty.is_behind(&edited));
+        assert_eq!(node.snapshot().unwrap().revision, edited);
+
+        // A restarted node knows where it was
+        assert_eq!(cluster(&dir).revision(), edited);
+    }
+
+    #[test]
+    fn test_merge_health_keeps_newer_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = cluster(&dir);
+        let report = |healthy, at| HealthReport { target: "m:10.0.0.9:3000".to_string(), healthy, at };
+        let now = now_ms();
+        assert_eq!(node.merge_health(vec![report(false, now)]), vec![report(false, now)]);
+        assert!(node.merge_health(vec![report(true, now - 1)]).is_empty(), "older");
+        assert!(node.merge_health(vec![report(false, now + 1)]).is_empty(), "no change");
+        assert_eq!(node.merge_health(vec![report(true, now + 2)]).len(), 1);
+        assert!(node.merge_health(vec![report(false, 1)]).is_empty(), "expired");
+        assert_eq!(node.state().health, vec![report(true, now + 2)]);
+    }
+}
@@ -0,0 +1,307 @@
+//! nginx and Caddy configuration
+//! `rustproxy-mapping export --format nginx|caddy` renders the mappings as nginx server
+//! blocks (for `http {}`, e.g. a file in `conf.d/`) or a Caddyfile that route the same way:
+//! by domain, with the path prefix swapped for `back_uri`, HA ports balanced round-robin,
+//! IP allow-lists and body limits kept — for moving off the proxy or running one of those
+//! as a parallel fallback. Settings with no counterpart there (rate limits, OIDC,
+//! experiments, ...) are named in a comment on the route, and routes whose backend can't be
+//! expressed (discovery, FastCGI, S3) are left out with a comment, so nothing is dropped
+//! silently. The active blue/green slot is the one exported
+
+use crate::database::Mapping;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Whether a mapping has a setting
+type IsSet = fn(&Mapping) -> bool;
+
+/// Settings neither format translates, by column
+const UNTRANSLATED: &[(&str, IsSet)] = &[
+    ("auth", |m| m.auth_type.is_some()),
+    ("rate_limit", |m| m.rate_limit.as_deref().is_some_and(|r| r != "off")),
+    ("fallback_backend", |m| m.fallback_backend.is_some()),
+    ("experiment", |m| m.experiment.is_some()),
+    ("allowed_content_types", |m| m.allowed_content_types.is_some()),
+    ("body_rewrites", |m| m.body_rewrites.is_some()),
+    ("html_base", |m| m.html_base.is_some()),
+    ("stale_if_error", |m| m.stale_if_error.is_some()),
+    ("upstream_tls", |m| m.upstream_tls.is_some()),
+    ("egress_proxy", |m| m.egress_proxy.is_some()),
+    ("oidc", |m| m.oidc.is_some()),
+    ("url_signing_secret", |m| m.url_signing_secret.is_some()),
+    ("compress_requests", |m| m.compress_requests.is_some()),
+    ("pool", |m| m.pool.is_some()),
+    ("quota", |m| m.quota.is_some()),
+    ("contract", |m| m.contract.is_some()),
+    ("activate_at", |m| m.activate_at.is_some()),
+    ("deactivate_at", |m| m.deactivate_at.is_some()),
+    ("header_limits", |m| m.header_limits.is_some()),
+    ("access_hours", |m| m.access_hours.is_some()),
+    ("forward_headers", |m| m.forward_headers.is_some()),
+    ("scrub_headers", |m| m.scrub_headers.is_some()),
+    ("upstream_protocol", |m| m.upstream_protocol.is_some()),
+    ("upstream_signing", |m| m.upstream_signing.is_some()),
+    ("startup_page", |m| m.startup_page.is_some()),
+    ("hold", |m| m.hold.is_some()),
+    ("image_filter", |m| m.image_filter.is_some()),
+    ("minify", |m| m.minify.is_some()),
+    ("early_hints", |m| m.early_hints.is_some()),
+];
+
+/// One mapping, as far as another proxy can do the same
+struct Route {
+    /// `/api`, or empty for the whole domain
+    prefix: String,
+    /// `/v1`, or empty
+    back_path: String,
+    scheme: &'static str,
+    /// `host:port` of each target
+    targets: Vec<String>,
+    allowed_ips: Vec<String>,
+    max_body_bytes: Option<u64>,
+    untranslated: Vec<&'static str>,
+    id: String,
+}
+
+impl Route {
+    /// The route of `m`, or why it has none.
+    fn of(m: &Mapping) -> Result<Route, String> {
+        let mut m = m.clone();
+        m.apply_active_slot();
+        if let Some(root) = &m.document_root {
+            return Err(format!("serves files from {}", root));
+        }
+        let backend = m.backend.as_deref().unwrap_or("http://localhost").trim_end_matches('/');
+        let (scheme, host) = match backend.split_once("://") {
+            Some(("http", host)) => ("http", host),
+            Some(("https", host)) => ("https", host),
+            Some(_) => return Err(format!("backend {} has no equivalent", backend)),
+            None => ("http", backend),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("backend {} has no equivalent", backend));
+        }
+        let ports: Vec<&str> = match m.back_ports.as_deref() {
+            Some(ports) => ports.split(',').map(str::trim).filter(|p| !p.is_empty()).collect(),
+            None => Vec::new(),
+        };
+        let targets = match ports.is_empty() {
+            true => vec![format!("{}:{}", host, m.back_port)],
+            false => ports.iter().map(|p| format!("{}:{}", host, p)).collect(),
+        };
+        let path = |p: &str| match p.trim_matches('/') {
+            "" => String::new(),
+            p => format!("/{}", p),
+        };
+        Ok(Route {
+            prefix: path(&m.front_uri),
+            back_path: path(&m.back_uri),
+            scheme,
+            targets,
+            allowed_ips: m.allowed_ips.as_deref().unwrap_or("").split(',').map(str::trim).filter(|ip| !ip.is_empty()).map(String::from).collect(),
+            max_body_bytes: m.max_body_bytes,
+            untranslated: UNTRANSLATED.iter().filter(|(_, set)| set(&m)).map(|(name, _)| *name).collect(),
+            id: m.id.clone(),
+        })
+    }
+}
+
+/// Mappings by domain, each domain's longest prefix first; routes that can't be exported
+/// as comments.
+fn by_domain(mappings: &[Mapping]) -> BTreeMap<&str, Vec<Result<Route, String>>> {
+    let mut domains: BTreeMap<&str, Vec<Result<Route, String>>> = BTreeMap::new();
+    for m in mappings {
+        let route = Route::of(m).map_err(|why| format!("/{} ({}): {}; not exported", m.front_uri, m.id, why));
+        domains.entry(m.domain.as_str()).or_default().push(route);
+    }
+    for routes in domains.values_mut() {
+        routes.sort_by_key(|r| std::cmp::Reverse(r.as_ref().map_or(0, |r| r.prefix.len())));
+    }
+    domains
+}
+
+fn header(out: &mut String, note: &str) {
+    let _ = writeln!(out, "# Generated by rustproxy-mapping export on {}", chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"));
+    let _ = writeln!(out, "# {}", note);
+}
+
+/// nginx server blocks for `http {}`.
+pub fn nginx(mappings: &[Mapping]) -> String {
+    let mut out = String::new();
+    header(&mut out, "Plain HTTP on port 80; add `listen 443 ssl` and certificates as needed");
+    out.push_str("\nmap $http_upgrade $connection_upgrade {\n    default upgrade;\n    ''      close;\n}\n");
+    for (domain, routes) in by_domain(mappings) {
+        let _ = writeln!(out, "\nserver {{");
+        match domain {
+            "*" => out.push_str("    listen 80 default_server;\n    server_name _;\n"),
+            _ => {
+                let _ = writeln!(out, "    listen 80;\n    server_name {};", domain);
+            }
+        }
+        out.push_str("    proxy_http_version 1.1;\n");
+        out.push_str("    proxy_set_header Host $host;\n");
+        out.push_str("    proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;\n");
+        out.push_str("    proxy_set_header X-Forwarded-Host $host;\n");
+        out.push_str("    proxy_set_header Upgrade $http_upgrade;\n");
+        out.push_str("    proxy_set_header Connection $connection_upgrade;\n");
+        let mut upstreams = String::new();
+        for route in routes {
+            let route = match route {
+                Ok(r) => r,
+                Err(skipped) => {
+                    let _ = writeln!(out, "\n    # {}", skipped);
+                    continue;
+                }
+            };
+            let target = match &route.targets[..] {
+                [one] => one.clone(),
+                many => {
+                    let name = format!("rustproxy_{}", route.id.replace('-', "_"));
+                    let _ = writeln!(upstreams, "upstream {} {{", name);
+                    for t in many {
+                        let _ = writeln!(upstreams, "    server {};", t);
+                    }
+                    upstreams.push_str("}\n\n");
+                    name
+                }
+            };
+            let body = |out: &mut String, pass: String| {
+                for ip in &route.allowed_ips {
+                    let _ = writeln!(out, "        allow {};", ip);
+                }
+                if !route.allowed_ips.is_empty() {
+                    out.push_str("        deny all;\n");
+                }
+                if let Some(max) = route.max_body_bytes {
+                    let _ = writeln!(out, "        client_max_body_size {};", max);
+                }
+                if route.scheme == "https" {
+                    out.push_str("        proxy_ssl_server_name on;\n");
+                }
+                let _ = writeln!(out, "        proxy_pass {}://{}{};", route.scheme, target, pass);
+            };
+            out.push('\n');
+            if !route.untranslated.is_empty() {
+                let _ = writeln!(out, "    # not translated: {}", route.untranslated.join(", "));
+            }
+            // The prefix itself, and everything under it, like the proxy's prefix match
+            if !route.prefix.is_empty() {
+                let _ = writeln!(out, "    location = {} {{", route.prefix);
+                body(&mut out, if route.back_path.is_empty() { "/".to_string() } else { route.back_path.clone() });
+                out.push_str("    }\n");
+            }
+            let _ = writeln!(out, "    location {}/ {{", route.prefix);
+            body(&mut out, format!("{}/", route.back_path));
+            out.push_str("    }\n");
+        }
+        out.push_str("}\n");
+        if !upstreams.is_empty() {
+            let at = out.rfind("\nserver {").unwrap_or(out.len());
+            out.insert_str(at + 1, &upstreams);
+        }
+    }
+    out
+}
+
+/// A Caddyfile.
+pub fn caddy(mappings: &[Mapping]) -> String {
+    let mut out = String::new();
+    header(&mut out, "Caddy gets certificates for the named sites itself; `*` is served as plain HTTP on :80");
+    for (domain, routes) in by_domain(mappings) {
+        let _ = writeln!(out, "\n{} {{", if domain == "*" { ":80" } else { domain });
+        for (i, route) in routes.iter().enumerate() {
+            let route = match route {
+                Ok(r) => r,
+                Err(skipped) => {
+                    let _ = writeln!(out, "\t# {}", skipped);
+                    continue;
+                }
+            };
+            if !route.untranslated.is_empty() {
+                let _ = writeln!(out, "\t# not translated: {}", route.untranslated.join(", "));
+            }
+            let indent = if route.prefix.is_empty() {
+                out.push_str("\thandle {\n");
+                "\t\t"
+            } else {
+                let _ = writeln!(out, "\t@route{} path {} {}/*", i, route.prefix, route.prefix);
+                let _ = writeln!(out, "\thandle @route{} {{", i);
+                let _ = writeln!(out, "\t\turi strip_prefix {}", route.prefix);
+                "\t\t"
+            };
+            if !route.back_path.is_empty() {
+                let _ = writeln!(out, "{}rewrite * {}{{uri}}", indent, route.back_path);
+            }
+            if !route.allowed_ips.is_empty() {
+                let _ = writeln!(out, "{}@denied not remote_ip {}", indent, route.allowed_ips.join(" "));
+                let _ = writeln!(out, "{}respond @denied 403", indent);
+            }
+            if let Some(max) = route.max_body_bytes {
+                let _ = writeln!(out, "{}request_body {{\n{}\tmax_size {}\n{}}}", indent, indent, max, indent);
+            }
+            let targets: Vec<String> = route.targets.iter().map(|t| match route.scheme {
+                "https" => format!("https://{}", t),
+                _ => t.clone(),
+            }).collect();
+            match targets.len() {
+                1 => {
+                    let _ = writeln!(out, "{}reverse_proxy {}", indent, targets[0]);
+                }
+                _ => {
+                    let _ = writeln!(out, "{}reverse_proxy {} {{\n{}\tlb_policy round_robin\n{}}}", indent, targets.join(" "), indent, indent);
+                }
+            }
+            out.push_str("\t}\n");
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings() -> Vec<Mapping> {
+        let m = |id: &str, domain: &str, front_uri: &str, back_uri: &str, port: u16| Mapping {
+            id: id.into(),
+            domain: domain.into(),
+            front_uri: front_uri.into(),
+            back_uri: back_uri.into(),
+            back_port: port,
+            ..Default::default()
+        };
+        vec![
+            m("m1", "example.com", "", "", 3000),
+            Mapping {
+                back_ports: Some("3001,3002".into()),
+                allowed_ips: Some("10.0.0.0/8".into()),
+                rate_limit: Some("100/1m".into()),
+                ..m("m2", "example.com", "api", "v1", 0)
+            },
+            Mapping { backend: Some("https://shop.internal".into()), max_body_bytes: Some(1024), ..m("m3", "shop.example.com", "", "", 443) },
+            Mapping { backend: Some("srv://_http._tcp.app".into()), ..m("m4", "shop.example.com", "svc", "", 0) },
+        ]
+    }
+
+    #[test]
+    fn test_nginx() {
+        let conf = nginx(&mappings());
+        assert!(conf.contains("upstream rustproxy_m2 {\n    server localhost:3001;\n    server localhost:3002;\n}\n\nserver {\n    listen 80;\n    server_name example.com;"), "{}", conf);
+        assert!(conf.contains("    # not translated: rate_limit\n    location = /api {\n        allow 10.0.0.0/8;\n        deny all;\n        proxy_pass http://rustproxy_m2/v1;\n    }"), "{}", conf);
+        assert!(conf.contains("    location /api/ {\n        allow 10.0.0.0/8;\n        deny all;\n        proxy_pass http://rustproxy_m2/v1/;"), "{}", conf);
+        // The longer prefix first; the root route passes paths on as they are
+        assert!(conf.find("location /api/").unwrap() < conf.find("location / {").unwrap());
+        assert!(conf.contains("    location / {\n        proxy_pass http://localhost:3000/;"), "{}", conf);
+        assert!(conf.contains("client_max_body_size 1024;\n        proxy_ssl_server_name on;\n        proxy_pass https://shop.internal:443/;"), "{}", conf);
+        assert!(conf.contains("# /svc (m4): backend srv://_http._tcp.app has no equivalent; not exported"), "{}", conf);
+    }
+
+    #[test]
+    fn test_caddy() {
+        let conf = caddy(&mappings());
+        assert!(conf.contains("\nexample.com {\n\t# not translated: rate_limit\n\t@route0 path /api /api/*\n\thandle @route0 {\n\t\turi strip_prefix /api\n\t\trewrite * /v1{uri}\n\t\t@denied not remote_ip 10.0.0.0/8\n\t\trespond @denied 403\n\t\treverse_proxy localhost:3001 localhost:3002 {\n\t\t\tlb_policy round_robin\n\t\t}\n\t}\n\thandle {\n\t\treverse_proxy localhost:3000\n\t}\n}\n"), "{}", conf);
+        assert!(conf.contains("\t\trequest_body {\n\t\t\tmax_size 1024\n\t\t}\n\t\treverse_proxy https://shop.internal:443\n"), "{}", conf);
+        assert!(conf.contains("\t# /svc (m4): backend srv://_http._tcp.app has no equivalent; not exported\n"), "{}", conf);
+    }
+}
@@ -0,0 +1,198 @@
+//! Connection accounting
+//! Counts open client and backend connections and keeps the process under a global
+//! connection cap and its file-descriptor limit, turning clients away at `accept()`
+//! instead of failing with EMFILE under load
+
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// What the accept loop does when no connection slot is free
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Accept and close at once (plain HTTP gets a 503 first)
+    #[default]
+    Reject,
+    /// Stop accepting until a connection closes; clients wait in the listen backlog
+    Pause,
+}
+
+impl FromStr for LimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "pause" => Ok(Self::Pause),
+            other => Err(format!("unknown connection limit action '{}' (reject, pause)", other)),
+        }
+    }
+}
+
+/// Point-in-time gauges
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConnectionStats {
+    /// Open client connections (HTTP and HTTPS)
+    pub client: usize,
+    /// Open connections to backends
+    pub backend: usize,
+    /// Client connection cap, if any
+    pub max_client: Option<usize>,
+    /// Cap on client plus backend connections derived from the FD limit, if known
+    pub max_total: Option<usize>,
+    /// Client connections turned away since start
+    pub rejected: u64,
+}
+
+/// Open connection counts, shared by all listeners and workers
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    client: AtomicUsize,
+    backend: AtomicUsize,
+    rejected: AtomicU64,
+    max_client: Option<usize>,
+    max_total: Option<usize>,
+    freed: Notify,
+}
+
+/// Counts one open connection until dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    client: bool,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let counter = if self.client { &self.tracker.client } else { &self.tracker.backend };
+        counter.fetch_sub(1, Ordering::Relaxed);
+        self.tracker.freed.notify_waiters();
+    }
+}
+
+impl ConnectionTracker {
+    /// `max_connections` caps client connections (0: no cap). When the process FD limit
+    /// is known, client plus backend connections are also kept `fd_reserve` descriptors
+    /// below it, leaving room for the database, certificates, logs and listeners.
+    pub fn new(max_connections: usize, fd_reserve: usize) -> Self {
+        Self::with_fd_limit(max_connections, fd_reserve, fd_limit())
+    }
+
+    fn with_fd_limit(max_connections: usize, fd_reserve: usize, fd_limit: Option<u64>) -> Self {
+        Self {
+            max_client: (max_connections > 0).then_some(max_connections),
+            max_total: fd_limit.map(|l| (l as usize).saturating_sub(fd_reserve).max(1)),
+            ..Self::default()
+        }
+    }
+
+    /// Whether another client connection fits.
+    pub fn has_room(&self) -> bool {
+        let client = self.client.load(Ordering::Relaxed);
+        let total = client + self.backend.load(Ordering::Relaxed);
+        self.max_client.is_none_or(|m| client < m) && self.max_total.is_none_or(|m| total < m)
+    }
+
+    /// Count a new client connection, or `None` (counted as rejected) when it doesn't fit.
+    pub fn try_admit(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        if !self.has_room() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.client.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionGuard { tracker: self.clone(), client: true })
+    }
+
+    /// Resolves once a client connection would fit.
+    pub async fn wait_for_room(&self) {
+        loop {
+            let freed = self.freed.notified();
+            if self.has_room() {
+                return;
+            }
+            freed.await;
+        }
+    }
+
+    /// Count a backend connection. These are never refused: their client is already in.
+    pub fn backend(self: &Arc<Self>) -> ConnectionGuard {
+        self.backend.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { tracker: self.clone(), client: false }
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            client: self.client.load(Ordering::Relaxed),
+            backend: self.backend.load(Ordering::Relaxed),
+            max_client: self.max_client,
+            max_total: self.max_total,
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Soft limit on open files, from `/proc/self/limits` (Linux only).
+fn fd_limit() -> Option<u64> {
+    parse_fd_limit(&std::fs::read_to_string("/proc/self/limits").ok()?)
+}
+
+fn parse_fd_limit(limits: &str) -> Option<u64> {
+    let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+    line["Max open files".len()..].split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_cap_and_release() {
+        let tracker = Arc::new(ConnectionTracker::with_fd_limit(2, 0, None));
+        let a = tracker.try_admit().unwrap();
+        let _b = tracker.try_admit().unwrap();
+        assert!(tracker.try_admit().is_none());
+        // Backend connections don't count against the client cap
+        let _backend = tracker.backend();
+        drop(a);
+        assert!(tracker.try_admit().is_some());
+
+        let stats = tracker.stats();
+        assert_eq!((stats.client, stats.backend, stats.rejected), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_fd_budget_counts_backends() {
+        let tracker = Arc::new(ConnectionTracker::with_fd_limit(0, 8, Some(10)));
+        let _client = tracker.try_admit().unwrap();
+        let backend = tracker.backend();
+        assert!(!tracker.has_room());
+        drop(backend);
+        assert!(tracker.has_room());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_room() {
+        let tracker = Arc::new(ConnectionTracker::with_fd_limit(1, 0, None));
+        let guard = tracker.try_admit().unwrap();
+        let waiter = tokio::spawn({
+            let tracker = tracker.clone();
+            async move { tracker.wait_for_room().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        drop(guard);
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiter).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_parse_fd_limit() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             63448                63448                processes \n\
+                      Max open files            1024                 524288               files     \n";
+        assert_eq!(parse_fd_limit(limits), Some(1024));
+        assert_eq!(parse_fd_limit("Max open files            unlimited            unlimited            files"), None);
+        assert_eq!("Pause".parse::<LimitAction>(), Ok(LimitAction::Pause));
+    }
+}
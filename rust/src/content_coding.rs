@@ -0,0 +1,241 @@
+//! Content codings
+//! gzip, deflate and brotli backend responses decoded for the features that edit bodies
+//! (body rewrites, HTML base paths) and encoded again for clients that accept the coding.
+//! Decoding stops at the editing size cap, so oversized or bomb-like bodies pass through as sent
+
+use crate::body_rewrite::{is_text_content_type, read_bounded};
+use bytes::Bytes;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING, VARY};
+use hyper::{Response, StatusCode};
+use std::io::{Read, Write};
+
+/// A content coding bodies can be decoded from and encoded to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Coding {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+        }
+    }
+
+    /// `data` decoded, unless it is corrupt or decodes to more than `max_bytes`.
+    pub fn decode(self, data: &[u8], max_bytes: usize) -> Option<Vec<u8>> {
+        let limit = max_bytes as u64 + 1;
+        let mut out = Vec::new();
+        let read = match self {
+            Self::Gzip => flate2::read::MultiGzDecoder::new(data).take(limit).read_to_end(&mut out),
+            // "deflate" means zlib-wrapped, but some servers send raw deflate
+            Self::Deflate => flate2::read::ZlibDecoder::new(data).take(limit).read_to_end(&mut out).or_else(|_| {
+                out.clear();
+                flate2::read::DeflateDecoder::new(data).take(limit).read_to_end(&mut out)
+            }),
+            Self::Brotli => brotli::Decompressor::new(data, 4096).take(limit).read_to_end(&mut out),
+        };
+        (read.is_ok() && out.len() <= max_bytes).then_some(out)
+    }
+
+    pub fn encode(self, data: &[u8]) -> Vec<u8> {
+        let written = match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            Self::Brotli => {
+                // Quality 5: most of the gain at a fraction of the default's CPU time
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(data).map(|_| encoder.into_inner())
+            }
+        };
+        written.expect("writing to a Vec")
+    }
+}
+
+/// Whether an `Accept-Encoding` value allows `coding`; `q=0` refuses it.
+pub fn accepts(accept_encoding: &str, coding: Coding) -> bool {
+    let mut wildcard = false;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = q > 0.0;
+        } else if Coding::parse(name) == Some(coding) {
+            return q > 0.0;
+        }
+    }
+    wildcard
+}
+
+/// A client's `Accept-Encoding` narrowed to the codings that can be decoded, for the
+/// backend; `None` when nothing is left.
+pub fn decodable(accept_encoding: &str) -> Option<HeaderValue> {
+    let kept: Vec<&str> = accept_encoding
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| Coding::parse(entry.split(';').next().unwrap_or("")).is_some())
+        .collect();
+    if kept.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&kept.join(", ")).ok()
+}
+
+fn full(data: impl Into<Bytes>) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(data.into()).map_err(|never| match never {}).boxed()
+}
+
+/// Decode an encoded text response of at most `max_bytes` (compressed and decoded), and
+/// return the coding it had. Any other response comes back as it was, with `None`.
+pub async fn decode_response(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    max_bytes: usize,
+) -> (Response<BoxBody<Bytes, hyper::Error>>, Option<Coding>) {
+    let Some(coding) = resp.headers().get(CONTENT_ENCODING).and_then(|v| v.to_str().ok()).and_then(Coding::parse) else {
+        return (resp, None);
+    };
+    let text = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(is_text_content_type);
+    if !text || matches!(resp.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) {
+        return (resp, None);
+    }
+    let declared = resp.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|n| n > max_bytes) {
+        return (resp, None);
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let raw = match read_bounded(body, max_bytes, declared.unwrap_or(0)).await {
+        Ok(raw) => raw,
+        Err(body) => return (Response::from_parts(parts, body), None),
+    };
+    let Some(decoded) = coding.decode(&raw, max_bytes) else {
+        return (Response::from_parts(parts, full(raw)), None);
+    };
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(TRANSFER_ENCODING);
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(decoded.len()));
+    (Response::from_parts(parts, full(decoded)), Some(coding))
+}
+
+/// Encode a response decoded by [`decode_response`] with its coding again when the
+/// client's `Accept-Encoding` allows it; otherwise it is sent uncompressed.
+pub async fn encode_response(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    coding: Coding,
+    accept_encoding: Option<&str>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if !accept_encoding.is_some_and(|a| accepts(a, coding)) || resp.headers().contains_key(CONTENT_ENCODING) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let data = match read_bounded(body, usize::MAX, 0).await {
+        Ok(data) => data,
+        Err(body) => return Response::from_parts(parts, body),
+    };
+    let encoded = coding.encode(&data);
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding.name()));
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+    let varies = parts.headers.get_all(VARY).iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|h| h.trim() == "*" || h.trim().eq_ignore_ascii_case("accept-encoding")));
+    if !varies {
+        parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    Response::from_parts(parts, full(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(encoding: &str, body: Vec<u8>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CONTENT_ENCODING, encoding)
+            .body(full(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_limits() {
+        let text = b"<a href=\"http://backend:3000/\">home</a>".repeat(50);
+        for coding in [Coding::Gzip, Coding::Deflate, Coding::Brotli] {
+            let encoded = coding.encode(&text);
+            assert!(encoded.len() < text.len());
+            assert_eq!(coding.decode(&encoded, text.len()).as_deref(), Some(&text[..]));
+            assert_eq!(coding.decode(&encoded, text.len() - 1), None, "{:?} over the cap", coding);
+            assert_eq!(coding.decode(b"not compressed", 1024), None);
+        }
+        // Raw deflate is accepted as "deflate" too
+        let mut raw = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        raw.write_all(&text).unwrap();
+        assert_eq!(Coding::Deflate.decode(&raw.finish().unwrap(), 1 << 20).as_deref(), Some(&text[..]));
+        assert_eq!(Coding::parse("X-GZIP"), Some(Coding::Gzip));
+        assert_eq!(Coding::parse("zstd"), None);
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        assert!(accepts("gzip, deflate, br", Coding::Brotli));
+        assert!(!accepts("gzip;q=0, *", Coding::Gzip));
+        assert!(accepts("identity, *;q=0.1", Coding::Deflate));
+        assert!(!accepts("identity", Coding::Gzip));
+        assert_eq!(decodable("zstd, br;q=1.0, gzip").unwrap(), "br;q=1.0, gzip");
+        assert_eq!(decodable("zstd, *"), None);
+    }
+
+    #[tokio::test]
+    async fn test_decode_and_encode_response() {
+        let text = "<p>hello</p>".repeat(20);
+        let (resp, coding) = decode_response(response("gzip", Coding::Gzip.encode(text.as_bytes())), 1024).await;
+        assert_eq!(coding, Some(Coding::Gzip));
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(resp.headers()[CONTENT_LENGTH], text.len().to_string());
+
+        let resp = encode_response(resp, Coding::Gzip, Some("br, gzip")).await;
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(Coding::Gzip.decode(&body, 1024).unwrap(), text.as_bytes());
+
+        // Over the cap, or not decodable: passed on as sent
+        let encoded = Coding::Brotli.encode(text.as_bytes());
+        let (resp, coding) = decode_response(response("br", encoded.clone()), 100).await;
+        assert_eq!((coding, &resp.headers()[CONTENT_ENCODING]), (None, &HeaderValue::from_static("br")));
+        assert_eq!(resp.into_body().collect().await.unwrap().to_bytes(), encoded);
+        let (_, coding) = decode_response(response("zstd", encoded), 1024).await;
+        assert_eq!(coding, None);
+
+        // A client that only takes identity gets the decoded body
+        let (resp, _) = decode_response(response("deflate", Coding::Deflate.encode(text.as_bytes())), 1024).await;
+        let resp = encode_response(resp, Coding::Deflate, None).await;
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+    }
+}
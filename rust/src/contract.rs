@@ -0,0 +1,235 @@
+//! Response contracts
+//! A mapping's `contract` states what its backend's responses must look like: headers
+//! they must carry, statuses they may have, and how long the response head may take.
+//! Responses breaking it are logged and counted per mapping (`GET /_proxy/admin/status`);
+//! with `enforce` the client gets 502 instead. A lightweight check that a backend keeps
+//! its promises, not a schema validator: bodies are never looked at
+
+use dashmap::DashMap;
+use hyper::header::HeaderName;
+use hyper::{HeaderMap, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// What a mapping's backend responses must satisfy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Contract {
+    /// Headers every response must include
+    pub headers: Vec<HeaderName>,
+    /// Allowed statuses as inclusive ranges (empty: any)
+    pub statuses: Vec<(u16, u16)>,
+    /// Longest wait for the response head
+    pub max_latency: Option<Duration>,
+    /// Answer violations with 502 rather than passing them on
+    pub enforce: bool,
+}
+
+/// One way a response broke its contract
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    Status(u16),
+    MissingHeader(HeaderName),
+    Latency(Duration),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Status(s) => write!(f, "status {} not allowed", s),
+            Violation::MissingHeader(h) => write!(f, "missing header {}", h),
+            Violation::Latency(d) => write!(f, "response head took {}ms", d.as_millis()),
+        }
+    }
+}
+
+/// `500ms`, `2s` or a bare number of milliseconds
+fn parse_latency(s: &str) -> Option<Duration> {
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms.parse().ok().map(Duration::from_millis);
+    }
+    if let Some(secs) = s.strip_suffix('s') {
+        return secs.parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0).map(Duration::from_secs_f64);
+    }
+    s.parse().ok().map(Duration::from_millis)
+}
+
+/// `200`, `200-299` or `2xx`
+fn parse_statuses(s: &str) -> Option<(u16, u16)> {
+    let range = match (s.split_once('-'), s.strip_suffix("xx")) {
+        (Some((lo, hi)), _) => (lo.parse().ok()?, hi.parse().ok()?),
+        (None, Some(class)) if class.len() == 1 => {
+            let class: u16 = class.parse().ok()?;
+            (class * 100, class * 100 + 99)
+        }
+        _ => {
+            let status = s.parse().ok()?;
+            (status, status)
+        }
+    };
+    (100 <= range.0 && range.0 <= range.1 && range.1 <= 599).then_some(range)
+}
+
+impl FromStr for Contract {
+    type Err = String;
+
+    /// `headers=content-type;x-request-id,status=2xx;304,latency=500ms,enforce`; lists are
+    /// separated by `;`, and any item may be left out.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut contract = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = item.split_once('=').map_or((item, ""), |(n, v)| (n.trim(), v.trim()));
+            let list = || value.split(';').map(str::trim).filter(|v| !v.is_empty());
+            match name {
+                "headers" => for h in list() {
+                    contract.headers.push(h.parse().map_err(|_| format!("invalid header name '{}'", h))?);
+                },
+                "status" => for s in list() {
+                    contract.statuses.push(parse_statuses(s).ok_or_else(|| format!("invalid status '{}' (e.g. 200, 2xx, 200-204)", s))?);
+                },
+                "latency" => {
+                    contract.max_latency = Some(parse_latency(value).ok_or_else(|| format!("'latency' needs a duration (e.g. 500ms), got '{}'", value))?);
+                }
+                "enforce" if value.is_empty() => contract.enforce = true,
+                other => return Err(format!("unknown contract item '{}' (headers, status, latency, enforce)", other)),
+            }
+        }
+        if contract.headers.is_empty() && contract.statuses.is_empty() && contract.max_latency.is_none() {
+            return Err("a contract needs headers=, status= and/or latency=".to_string());
+        }
+        Ok(contract)
+    }
+}
+
+impl fmt::Display for Contract {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items = Vec::new();
+        if !self.headers.is_empty() {
+            items.push(format!("headers={}", self.headers.iter().map(HeaderName::as_str).collect::<Vec<_>>().join(";")));
+        }
+        if !self.statuses.is_empty() {
+            let ranges: Vec<String> = self.statuses.iter()
+                .map(|&(lo, hi)| if lo == hi { lo.to_string() } else { format!("{}-{}", lo, hi) })
+                .collect();
+            items.push(format!("status={}", ranges.join(";")));
+        }
+        if let Some(d) = self.max_latency {
+            items.push(format!("latency={}ms", d.as_millis()));
+        }
+        if self.enforce {
+            items.push("enforce".to_string());
+        }
+        f.write_str(&items.join(","))
+    }
+}
+
+impl Contract {
+    /// Every way a response with this head, which took `latency`, breaks the contract.
+    pub fn check(&self, status: StatusCode, headers: &HeaderMap, latency: Duration) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let code = status.as_u16();
+        if !self.statuses.is_empty() && !self.statuses.iter().any(|&(lo, hi)| (lo..=hi).contains(&code)) {
+            violations.push(Violation::Status(code));
+        }
+        for name in &self.headers {
+            if !headers.contains_key(name) {
+                violations.push(Violation::MissingHeader(name.clone()));
+            }
+        }
+        if self.max_latency.is_some_and(|max| latency > max) {
+            violations.push(Violation::Latency(latency));
+        }
+        violations
+    }
+}
+
+/// Checked responses and violations of one mapping
+#[derive(Debug, Default)]
+struct Counts {
+    checked: AtomicU64,
+    violated: AtomicU64,
+    status: AtomicU64,
+    headers: AtomicU64,
+    latency: AtomicU64,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ContractStats {
+    pub checked: u64,
+    /// Responses with at least one violation
+    pub violated: u64,
+    pub status: u64,
+    pub headers: u64,
+    pub latency: u64,
+}
+
+/// Contract results per mapping ID, since this instance started
+#[derive(Default)]
+pub struct Monitor {
+    counts: DashMap<String, Counts>,
+}
+
+impl Monitor {
+    pub fn record(&self, mapping_id: &str, violations: &[Violation]) {
+        let counts = self.counts.entry(mapping_id.to_string()).or_default();
+        counts.checked.fetch_add(1, Ordering::Relaxed);
+        if !violations.is_empty() {
+            counts.violated.fetch_add(1, Ordering::Relaxed);
+        }
+        for v in violations {
+            let counter = match v {
+                Violation::Status(_) => &counts.status,
+                Violation::MissingHeader(_) => &counts.headers,
+                Violation::Latency(_) => &counts.latency,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, ContractStats> {
+        self.counts.iter().map(|e| {
+            let c = e.value();
+            (e.key().clone(), ContractStats {
+                checked: c.checked.load(Ordering::Relaxed),
+                violated: c.violated.load(Ordering::Relaxed),
+                status: c.status.load(Ordering::Relaxed),
+                headers: c.headers.load(Ordering::Relaxed),
+                latency: c.latency.load(Ordering::Relaxed),
+            })
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contract_parse_and_check() {
+        let contract: Contract = "headers=Content-Type; x-request-id, status=2xx;304, latency=0.5s, enforce".parse().unwrap();
+        assert_eq!(contract.to_string(), "headers=content-type;x-request-id,status=200-299;304,latency=500ms,enforce");
+        assert_eq!(contract.to_string().parse::<Contract>().unwrap(), contract);
+        assert!("status=2xx".parse::<Contract>().is_ok_and(|c| !c.enforce && c.headers.is_empty()));
+        assert!("enforce".parse::<Contract>().is_err());
+        assert!("status=700".parse::<Contract>().is_err());
+        assert!("headers=bad header".parse::<Contract>().is_err());
+        assert!("latency=soon".parse::<Contract>().is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        let fast = Duration::from_millis(20);
+        assert_eq!(contract.check(StatusCode::NOT_MODIFIED, &headers, fast), [Violation::MissingHeader("x-request-id".parse().unwrap())]);
+        headers.insert("x-request-id", "1".parse().unwrap());
+        assert!(contract.check(StatusCode::OK, &headers, fast).is_empty());
+        let broken = contract.check(StatusCode::INTERNAL_SERVER_ERROR, &headers, Duration::from_secs(1));
+        assert_eq!(broken, [Violation::Status(500), Violation::Latency(Duration::from_secs(1))]);
+
+        let monitor = Monitor::default();
+        monitor.record("m1", &[]);
+        monitor.record("m1", &broken);
+        assert_eq!(monitor.stats()["m1"], ContractStats { checked: 2, violated: 1, status: 1, headers: 0, latency: 1 });
+    }
+}
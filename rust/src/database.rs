@@ -1,15 +1,136 @@
 //! Database manager for SQLite operations
 //! Handles the mappings table with domain routing configurations
 
+use crate::hex::hex;
 use anyhow::Result;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use rusqlite::{Connection, params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 use uuid::Uuid;
 
+/// Canonical domain used for storage and lookups (see [`crate::host::normalize_domain`]).
+/// Values that don't normalize are used as given, so they still match themselves.
+fn domain_key(domain: &str) -> Cow<'_, str> {
+    crate::host::normalize_domain(domain).unwrap_or(Cow::Borrowed(domain))
+}
+
+/// `auth_type` of mappings that take keys from the `api_keys` table
+pub const API_KEY_AUTH: &str = "api_key";
+
+/// How long a stored ACME challenge token stays answerable
+pub const ACME_CHALLENGE_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Setting recording that stored domains have been normalized
+const DOMAINS_NORMALIZED: &str = "migration.domains_normalized";
+
+/// Columns selected for a [`Mapping`], in the order [`row_to_mapping`] reads them.
+/// `back_port` is CAST so TEXT-affinity values (JS-created DBs) work too.
+const MAPPING_COLUMNS: &str = "id, domain, front_uri, CAST(back_port AS INTEGER), back_uri, backend, back_ports,
+     allowed_ips, auth_type, auth_credentials, created_at, updated_at,
+     active_slot, green_backend, CAST(green_port AS INTEGER), switched_at, probation_until,
+     experiment, allowed_content_types, CAST(max_body_bytes AS INTEGER), preserve_path,
+     log_level, log_sample, body_rewrites, html_base, rate_limit, fallback_backend,
+     CAST(stale_if_error AS INTEGER), upstream_tls, egress_proxy, document_root, oidc,
+     url_signing_secret, CAST(compress_requests AS INTEGER), pool, quota, owner,
+     contract, activate_at, deactivate_at, header_limits, access_hours,
+     forward_headers, scrub_headers, upstream_protocol, upstream_signing, startup_page, hold, image_filter, minify, early_hints, version";
+
+/// Columns [`write_mapping`] stores, `id` first
+const WRITTEN_COLUMNS: &str = "id, domain, front_uri, back_port, back_uri, backend, back_ports,
+     allowed_ips, auth_type, auth_credentials, created_at, updated_at,
+     active_slot, green_backend, green_port, switched_at, probation_until,
+     experiment, allowed_content_types, max_body_bytes, preserve_path,
+     log_level, log_sample, body_rewrites, html_base, rate_limit, fallback_backend,
+     stale_if_error, upstream_tls, egress_proxy, document_root, oidc,
+     url_signing_secret, compress_requests, pool, quota, owner,
+     contract, activate_at, deactivate_at, header_limits, access_hours,
+     forward_headers, scrub_headers, upstream_protocol, upstream_signing, startup_page, hold, image_filter, minify, early_hints, version";
+
+/// `?from, …, ?52`: the parameters of [`write_mapping`] from `from` on
+fn placeholders(from: usize) -> String {
+    (from..=52).map(|i| format!("?{}", i)).collect::<Vec<_>>().join(", ")
+}
+
+/// Run `stmt` with every column of `m` as parameters, in [`WRITTEN_COLUMNS`] order.
+fn write_mapping(stmt: &mut rusqlite::Statement<'_>, m: &Mapping) -> rusqlite::Result<usize> {
+    stmt.execute(params![
+        m.id, domain_key(&m.domain), m.front_uri, m.back_port, m.back_uri, m.backend, m.back_ports,
+        m.allowed_ips, m.auth_type, m.auth_credentials, m.created_at, m.updated_at,
+        m.active_slot, m.green_backend, m.green_port, m.switched_at, m.probation_until,
+        m.experiment, m.allowed_content_types, m.max_body_bytes.map(|v| v.min(i64::MAX as u64) as i64),
+        m.preserve_path, m.log_level, m.log_sample, m.body_rewrites, m.html_base, m.rate_limit,
+        m.fallback_backend, m.stale_if_error.map(|v| v.min(i64::MAX as u64) as i64),
+        m.upstream_tls, m.egress_proxy, m.document_root, m.oidc, m.url_signing_secret,
+        m.compress_requests.map(|v| v.min(i64::MAX as u64) as i64), m.pool, m.quota, m.owner,
+        m.contract, m.activate_at, m.deactivate_at, m.header_limits, m.access_hours,
+        m.forward_headers, m.scrub_headers, m.upstream_protocol, m.upstream_signing, m.startup_page, m.hold, m.image_filter, m.minify, m.early_hints,
+        m.version.min(i64::MAX as u64) as i64,
+    ])
+}
+
+fn row_to_mapping(row: &rusqlite::Row<'_>) -> rusqlite::Result<Mapping> {
+    Ok(Mapping {
+        id: row.get(0)?,
+        domain: row.get(1)?,
+        front_uri: row.get(2)?,
+        back_port: row.get(3)?,
+        back_uri: row.get(4)?,
+        backend: row.get(5)?,
+        back_ports: row.get(6)?,
+        allowed_ips: row.get(7)?,
+        auth_type: row.get(8)?,
+        auth_credentials: row.get(9)?,
+        created_at: row.get(10)?,
+        updated_at: row.get(11)?,
+        active_slot: row.get(12)?,
+        green_backend: row.get(13)?,
+        green_port: row.get(14)?,
+        switched_at: row.get(15)?,
+        probation_until: row.get(16)?,
+        experiment: row.get(17)?,
+        allowed_content_types: row.get(18)?,
+        max_body_bytes: row.get::<_, Option<i64>>(19)?.map(|v| v.max(0) as u64),
+        preserve_path: row.get::<_, Option<bool>>(20)?.unwrap_or(false),
+        log_level: row.get(21)?,
+        log_sample: row.get(22)?,
+        body_rewrites: row.get(23)?,
+        html_base: row.get(24)?,
+        rate_limit: row.get(25)?,
+        fallback_backend: row.get(26)?,
+        stale_if_error: row.get::<_, Option<i64>>(27)?.map(|v| v.max(0) as u64),
+        upstream_tls: row.get(28)?,
+        egress_proxy: row.get(29)?,
+        document_root: row.get(30)?,
+        oidc: row.get(31)?,
+        url_signing_secret: row.get(32)?,
+        compress_requests: row.get::<_, Option<i64>>(33)?.map(|v| v.max(0) as u64),
+        pool: row.get(34)?,
+        quota: row.get(35)?,
+        owner: row.get(36)?,
+        contract: row.get(37)?,
+        activate_at: row.get(38)?,
+        deactivate_at: row.get(39)?,
+        header_limits: row.get(40)?,
+        access_hours: row.get(41)?,
+        forward_headers: row.get(42)?,
+        scrub_headers: row.get(43)?,
+        upstream_protocol: row.get(44)?,
+        upstream_signing: row.get(45)?,
+        startup_page: row.get(46)?,
+        hold: row.get(47)?,
+        image_filter: row.get(48)?,
+        minify: row.get(49)?,
+        early_hints: row.get(50)?,
+        version: row.get::<_, Option<i64>>(51)?.map_or(1, |v| v.max(0) as u64),
+    })
+}
+
 /// Represents a domain mapping configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Mapping {
     pub id: String,
     pub domain: String,
@@ -25,12 +146,436 @@ pub struct Mapping {
     pub auth_credentials: Option<String>,
     pub created_at: String,
     pub updated_at: String,
+    /// Blue/green: which slot receives traffic ("blue" or "green"; NULL means blue).
+    /// The blue slot is `backend`/`back_port`/`back_ports`, the green slot is
+    /// `green_backend`/`green_port`.
+    pub active_slot: Option<String>,
+    pub green_backend: Option<String>,
+    pub green_port: Option<u16>,
+    /// RFC 3339 time of the last slot switch
+    pub switched_at: Option<String>,
+    /// RFC 3339 end of the post-switch probation window; an error spike before
+    /// this time rolls the switch back automatically.
+    pub probation_until: Option<String>,
+    /// A/B experiment definition (JSON, see [`crate::experiment::Experiment`])
+    pub experiment: Option<String>,
+    /// Comma-separated media types accepted in request bodies (`image/*` allowed);
+    /// anything else gets 415.
+    pub allowed_content_types: Option<String>,
+    /// Largest accepted request body in bytes; bigger declared bodies get 413.
+    pub max_body_bytes: Option<u64>,
+    /// Forward the path exactly as the client encoded it (`%2F` stays `%2F`) instead of
+    /// the normalized form used for matching.
+    pub preserve_path: bool,
+    /// Access-log level override (`off`, `error` … `trace`)
+    pub log_level: Option<String>,
+    /// Access-log sampling override per status class (e.g. `2xx=1%,5xx=100%`)
+    pub log_sample: Option<String>,
+    /// Response body substitutions (JSON, see [`crate::body_rewrite::BodyRewrites`])
+    pub body_rewrites: Option<String>,
+    /// Keep HTML links under front_uri: `base` or `links` (see [`crate::html_base::BasePathMode`])
+    pub html_base: Option<String>,
+    /// Requests per client IP, e.g. `100/1m`, or `off`; unset uses the server default
+    pub rate_limit: Option<String>,
+    /// Secondary target (e.g. `http://standby:8080`) retried when the primary backend is
+    /// unreachable, failing its health checks, or answers 5xx
+    pub fallback_backend: Option<String>,
+    /// Seconds a kept copy of a `GET` response may be served (with a `Warning`) when the
+    /// backend fails; unset keeps no copies
+    pub stale_if_error: Option<u64>,
+    /// Verification of an `https://` backend (JSON, see [`crate::upstream_tls::UpstreamTls`]);
+    /// unset verifies against the public roots
+    pub upstream_tls: Option<String>,
+    /// Egress proxy for backend connections (`socks5://`, `socks5h://`, `http://`), or
+    /// `direct` to bypass the server-wide one; unset uses the server's `EGRESS_PROXY`
+    pub egress_proxy: Option<String>,
+    /// `fcgi://` backends: directory scripts are resolved in (as the FastCGI server sees
+    /// it) and static files served from
+    pub document_root: Option<String>,
+    /// OpenID Connect login in front of the mapping (JSON, see [`crate::oidc::OidcSettings`])
+    pub oidc: Option<String>,
+    /// HMAC key signed URLs are checked with (may be a `${ENV_VAR}` reference); when set,
+    /// only requests carrying a valid, unexpired signature get through
+    pub url_signing_secret: Option<String>,
+    /// Request bodies of at least this many bytes are sent to the backend gzip-compressed
+    pub compress_requests: Option<u64>,
+    /// Backend connection pool settings overriding the global ones, e.g.
+    /// `max_connections=200,max_idle=64` (see [`PoolSettings`](crate::pool::PoolSettings))
+    pub pool: Option<String>,
+    /// Monthly cap on requests and/or bytes, e.g. `requests=1000000,bytes=50G`
+    /// (see [`Quota`](crate::usage::Quota))
+    pub quota: Option<String>,
+    /// Tenant the mapping belongs to; unset is managed by the operator only
+    pub owner: Option<String>,
+    /// Checks on backend responses, e.g. `headers=x-request-id,status=2xx,latency=500ms`
+    /// (see [`Contract`](crate::contract::Contract))
+    pub contract: Option<String>,
+    /// RFC 3339 time before which the mapping isn't routed (a scheduled launch)
+    pub activate_at: Option<String>,
+    /// RFC 3339 time from which the mapping isn't routed (a scheduled retirement)
+    pub deactivate_at: Option<String>,
+    /// Header count and size limits tighter than the listener's, e.g. `count=30,bytes=8K`
+    /// (see [`HeaderLimits`](crate::header_limits::HeaderLimits))
+    pub header_limits: Option<String>,
+    /// Cron expression of when the mapping is reachable, e.g. `* 9-17 * * mon-fri tz=+01:00`
+    /// (see [`AccessHours`](crate::access_hours::AccessHours))
+    pub access_hours: Option<String>,
+    /// Allow-list of request headers passed to the backend, e.g. `accept,x-app-*`; unset
+    /// forwards all (see [`ForwardHeaders`](crate::forward_headers::ForwardHeaders))
+    pub forward_headers: Option<String>,
+    /// Response header scrubbing profile (`off`, `basic`, `strict`) instead of the global
+    /// one (see [`ScrubProfile`](crate::scrub::ScrubProfile))
+    pub scrub_headers: Option<String>,
+    /// How requests reach the backend: `http/1.0`, `h2c`, `close`, `no-expect` (see
+    /// [`UpstreamProtocol`](crate::upstream_protocol::UpstreamProtocol))
+    pub upstream_protocol: Option<String>,
+    /// Requests signed for the backend: `aws4,...` or `hmac,...` (see
+    /// [`UpstreamSigning`](crate::upstream_signing::UpstreamSigning))
+    pub upstream_signing: Option<String>,
+    /// "Starting up" page for slow page loads: `after=5,refresh=5,max_wait=120` (see
+    /// [`StartupPage`](crate::startup_page::StartupPage))
+    pub startup_page: Option<String>,
+    /// Deploy hold (see `hold`): `until=<RFC 3339>,max_queue=N`
+    pub hold: Option<String>,
+    /// Image resizing limits (see `image_filter`): `on` or `max_width=,max_height=,quality=,max_bytes=`
+    pub image_filter: Option<String>,
+    /// Response minification (see `minify`): `on` or types like `html,css` and `max_bytes=,max_ms=`
+    pub minify: Option<String>,
+    /// Early Hints (see `early_hints`): `Link` values for a 103 to page loads, and/or `forward`
+    pub early_hints: Option<String>,
+    /// Moves on with every change, so an update made against an older one can be refused
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Mapping {
+    /// Whether `now` lies within the mapping's schedule; unset or unreadable bounds are open.
+    pub fn is_scheduled_at(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        let at = |t: &Option<String>| t.as_deref().and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+        at(&self.activate_at).is_none_or(|t| t <= now) && at(&self.deactivate_at).is_none_or(|t| now < t)
+    }
+
+    /// True when live traffic goes to the green slot.
+    pub fn is_green(&self) -> bool {
+        self.active_slot.as_deref() == Some("green")
+    }
+
+    /// Point backend/back_port at the active slot, so the rest of the pipeline only
+    /// ever sees one target.
+    pub fn apply_active_slot(&mut self) {
+        if !self.is_green() {
+            return;
+        }
+        if let Some(port) = self.green_port {
+            if self.green_backend.is_some() {
+                self.backend = self.green_backend.clone();
+            }
+            self.back_port = port;
+            self.back_ports = None;
+        }
+    }
+
+    /// A copy pointed at the fallback backend as its single target, if one is set and valid.
+    pub fn fallback(&self) -> Option<Mapping> {
+        let target = self.fallback_backend.as_deref()?.trim();
+        let url: url::Url = if target.contains("://") {
+            target.parse().ok()?
+        } else {
+            format!("http://{}", target).parse().ok()?
+        };
+        let host = url.host_str().filter(|h| !h.is_empty())?;
+        Some(Mapping {
+            backend: Some(format!("{}://{}", url.scheme(), host)),
+            back_port: url.port_or_known_default()?,
+            back_ports: None,
+            fallback_backend: None,
+            ..self.clone()
+        })
+    }
+}
+
+/// A named key of a mapping with `auth_type = api_key`; the key itself is only stored hashed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Logged with each request the key authorizes
+    pub id: String,
+    pub mapping_id: String,
+    pub name: String,
+    /// RFC 3339; unset never expires
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.as_deref()
+            .and_then(|e| chrono::DateTime::parse_from_rfc3339(e).ok())
+            .is_some_and(|e| e < chrono::Utc::now())
+    }
+}
+
+fn row_to_api_key(row: &rusqlite::Row<'_>) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.get(0)?,
+        mapping_id: row.get(1)?,
+        name: row.get(2)?,
+        expires_at: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+/// A customer of a shared proxy, holding an admin API token that reaches only the mappings
+/// it owns; the token itself is only stored hashed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tenant {
+    /// Short name mappings refer to in `owner`, e.g. `acme`
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+}
+
+fn row_to_tenant(row: &rusqlite::Row<'_>) -> rusqlite::Result<Tenant> {
+    Ok(Tenant {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+    })
+}
+
+/// A tenant's claim on a domain (see [`crate::domain_verify`]); `verified_at` is set once
+/// the token has been found where it should be published
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainVerification {
+    pub domain: String,
+    pub owner: String,
+    pub token: String,
+    pub verified_at: Option<String>,
+    pub created_at: String,
+}
+
+fn row_to_domain_verification(row: &rusqlite::Row<'_>) -> rusqlite::Result<DomainVerification> {
+    Ok(DomainVerification {
+        domain: row.get(0)?,
+        owner: row.get(1)?,
+        token: row.get(2)?,
+        verified_at: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+/// A client kept out of every domain (see [`crate::bans`])
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub id: String,
+    /// IP or CIDR range
+    pub network: String,
+    pub reason: Option<String>,
+    /// `manual`, `rate_limit` or `auth_failure`
+    pub source: String,
+    /// RFC 3339; unset lasts until removed
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+impl Ban {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.as_deref()
+            .and_then(|e| chrono::DateTime::parse_from_rfc3339(e).ok())
+            .is_some_and(|e| e <= chrono::Utc::now())
+    }
+}
+
+/// Outcome of one synthetic probe of a backend target (see [`crate::probes`])
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Probe {
+    pub mapping_id: String,
+    /// `host:port`
+    pub target: String,
+    /// Unix seconds
+    pub at: i64,
+    pub ok: bool,
+    pub latency_ms: f64,
+    pub error: Option<String>,
+}
+
+/// Probes of one target over a time bucket
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeStats {
+    pub mapping_id: String,
+    pub target: String,
+    /// Unix seconds the bucket starts
+    pub bucket: i64,
+    pub probes: u64,
+    pub up: u64,
+    /// Average of the successful probes
+    pub latency_ms: Option<f64>,
+}
+
+fn row_to_ban(row: &rusqlite::Row<'_>) -> rusqlite::Result<Ban> {
+    Ok(Ban {
+        id: row.get(0)?,
+        network: row.get(1)?,
+        reason: row.get(2)?,
+        source: row.get(3)?,
+        expires_at: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+/// Requests and body bytes counted for a mapping (see [`crate::usage`])
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: Usage) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+}
+
+/// One line of the usage report; domain and path are unset once the mapping is deleted
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRow {
+    pub mapping_id: String,
+    pub domain: Option<String>,
+    pub front_uri: Option<String>,
+    pub quota: Option<String>,
+    pub owner: Option<String>,
+    pub period: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Keys are random, so an unsalted SHA-256 is enough to keep them out of the database.
+fn api_key_hash(secret: &str) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, secret.as_bytes()).as_ref())
+}
+
+/// A new random tenant admin token
+fn tenant_token() -> String {
+    let mut bytes = [0u8; 24];
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
+    format!("rpt_{}", hex(&bytes))
+}
+
+/// Mappings held in memory for [`DatabaseManager::find_mapping`], so a lookup doesn't
+/// query and decode rows on every request
+#[derive(Default)]
+struct RouteTable {
+    /// `(data_version, total_changes())` when built: changes through this connection or
+    /// any other (e.g. the mapping CLI) move one of them
+    version: (i64, i64),
+    /// Mappings by stored domain (`*.parent` ones under `parent` in `wildcards`), each
+    /// list longest front_uri first and otherwise in insertion order
+    domains: HashMap<String, Vec<Mapping>>,
+    wildcards: HashMap<String, Vec<Mapping>>,
+}
+
+impl RouteTable {
+    fn build(version: (i64, i64), mappings: Vec<Mapping>) -> Self {
+        let mut table = Self { version, ..Self::default() };
+        for m in mappings {
+            let (map, key) = match m.domain.strip_prefix("*.") {
+                Some(parent) => (&mut table.wildcards, parent.to_string()),
+                None => (&mut table.domains, m.domain.clone()),
+            };
+            map.entry(key).or_default().push(m);
+        }
+        for list in table.domains.values_mut().chain(table.wildcards.values_mut()) {
+            list.sort_by_cached_key(|m| std::cmp::Reverse(crate::normalize::decoded_octets(&m.front_uri).len()));
+        }
+        table
+    }
+
+    /// Priority: exact domain → wildcard *.parent.com → global catch-all '*'. Mappings
+    /// outside their schedule at `now` are passed over.
+    fn find<'t>(&'t self, domain: &str, path: &str, now: chrono::DateTime<chrono::Utc>) -> Option<&'t Mapping> {
+        let longest_match = |list: Option<&'t Vec<Mapping>>| {
+            list?.iter().find(|m| {
+                (m.front_uri.is_empty() || crate::normalize::match_front_uri(path, &m.front_uri).is_some())
+                    && m.is_scheduled_at(now)
+            })
+        };
+        longest_match(self.domains.get(domain))
+            .or_else(|| longest_match(self.wildcards.get(domain.split_once('.')?.1)))
+            .or_else(|| longest_match(self.domains.get("*")))
+    }
+}
+
+/// One change of [`DatabaseManager::apply_changes`]. A mapping is named by `id`, or by
+/// `domain` and `front_uri`; a delete naming only a domain deletes all its mappings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum MappingChange {
+    /// A new mapping with these [`Mapping`] fields; `domain` is required, the ID and
+    /// timestamps are generated
+    Add { mapping: serde_json::Map<String, serde_json::Value> },
+    /// Set these fields of one mapping, which must still be at `version`
+    Update {
+        id: Option<String>,
+        domain: Option<String>,
+        #[serde(default)]
+        front_uri: String,
+        version: u64,
+        set: serde_json::Map<String, serde_json::Value>,
+    },
+    /// Delete, or with `purge` remove for good
+    Delete {
+        id: Option<String>,
+        domain: Option<String>,
+        front_uri: Option<String>,
+        #[serde(default)]
+        purge: bool,
+    },
+}
+
+/// What one change did: the IDs of the mappings it added, updated or deleted, and the
+/// version an added or updated one is at now
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Applied {
+    pub op: &'static str,
+    pub ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+/// Why a batch was not applied: change `index` (from 0) doesn't fit the mapping table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejected {
+    pub index: usize,
+    pub reason: String,
+}
+
+/// `mapping` with `fields` set over it. Only [`Mapping`] fields may be set, and not the ID,
+/// timestamps or version.
+fn with_fields(mapping: &Mapping, fields: &serde_json::Map<String, serde_json::Value>) -> Result<Mapping, String> {
+    let mut value = serde_json::to_value(mapping).map_err(|e| e.to_string())?;
+    let object = value.as_object_mut().expect("a mapping serializes to an object");
+    for (key, v) in fields {
+        if matches!(key.as_str(), "id" | "created_at" | "updated_at" | "version") || !object.contains_key(key) {
+            return Err(format!("'{}' is not a mapping field that can be set", key));
+        }
+        object.insert(key.clone(), v.clone());
+    }
+    let mut m: Mapping = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    m.domain = crate::host::normalize_domain(&m.domain)
+        .ok_or_else(|| format!("invalid domain '{}'", m.domain))?
+        .into_owned();
+    m.front_uri = m.front_uri.trim_start_matches('/').trim_end_matches('/').to_string();
+    m.back_uri = m.back_uri.trim_start_matches('/').trim_end_matches('/').to_string();
+    Ok(m)
 }
 
 /// Thread-safe database manager for SQLite operations
 pub struct DatabaseManager {
     conn: Arc<Mutex<Connection>>,
     db_path: String,
+    routes: RwLock<Option<RouteTable>>,
 }
 
 unsafe impl Send for DatabaseManager {}
@@ -51,6 +596,7 @@ impl DatabaseManager {
         let manager = Self {
             conn: Arc::new(Mutex::new(conn)),
             db_path: db_path_str,
+            routes: RwLock::new(None),
         };
 
         manager.initialize()?;
@@ -58,7 +604,7 @@ impl DatabaseManager {
     }
 
     fn initialize(&self) -> Result<()> {
-        let conn = self.conn.lock();
+        let mut conn = self.conn.lock();
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS mappings (
@@ -72,6 +618,47 @@ impl DatabaseManager {
                 allowed_ips TEXT DEFAULT NULL,
                 auth_type TEXT DEFAULT NULL,
                 auth_credentials TEXT DEFAULT NULL,
+                active_slot TEXT DEFAULT NULL,
+                green_backend TEXT DEFAULT NULL,
+                green_port INTEGER DEFAULT NULL,
+                switched_at TEXT DEFAULT NULL,
+                probation_until TEXT DEFAULT NULL,
+                experiment TEXT DEFAULT NULL,
+                allowed_content_types TEXT DEFAULT NULL,
+                max_body_bytes INTEGER DEFAULT NULL,
+                preserve_path INTEGER DEFAULT 0,
+                log_level TEXT DEFAULT NULL,
+                log_sample TEXT DEFAULT NULL,
+                body_rewrites TEXT DEFAULT NULL,
+                html_base TEXT DEFAULT NULL,
+                rate_limit TEXT DEFAULT NULL,
+                fallback_backend TEXT DEFAULT NULL,
+                stale_if_error INTEGER DEFAULT NULL,
+                upstream_tls TEXT DEFAULT NULL,
+                egress_proxy TEXT DEFAULT NULL,
+                document_root TEXT DEFAULT NULL,
+                oidc TEXT DEFAULT NULL,
+                url_signing_secret TEXT DEFAULT NULL,
+                compress_requests INTEGER DEFAULT NULL,
+                pool TEXT DEFAULT NULL,
+                quota TEXT DEFAULT NULL,
+                owner TEXT DEFAULT NULL,
+                contract TEXT DEFAULT NULL,
+                activate_at TEXT DEFAULT NULL,
+                deactivate_at TEXT DEFAULT NULL,
+                header_limits TEXT DEFAULT NULL,
+                access_hours TEXT DEFAULT NULL,
+                forward_headers TEXT DEFAULT NULL,
+                scrub_headers TEXT DEFAULT NULL,
+                upstream_protocol TEXT DEFAULT NULL,
+                upstream_signing TEXT DEFAULT NULL,
+                startup_page TEXT DEFAULT NULL,
+                hold TEXT DEFAULT NULL,
+                image_filter TEXT DEFAULT NULL,
+                minify TEXT DEFAULT NULL,
+                early_hints TEXT DEFAULT NULL,
+                deleted_at DATETIME DEFAULT NULL,
+                version INTEGER NOT NULL DEFAULT 1,
                 created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
                 updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
             )",
@@ -84,6 +671,47 @@ impl DatabaseManager {
             ("allowed_ips",      "ALTER TABLE mappings ADD COLUMN allowed_ips TEXT DEFAULT NULL"),
             ("auth_type",        "ALTER TABLE mappings ADD COLUMN auth_type TEXT DEFAULT NULL"),
             ("auth_credentials", "ALTER TABLE mappings ADD COLUMN auth_credentials TEXT DEFAULT NULL"),
+            ("active_slot",      "ALTER TABLE mappings ADD COLUMN active_slot TEXT DEFAULT NULL"),
+            ("green_backend",    "ALTER TABLE mappings ADD COLUMN green_backend TEXT DEFAULT NULL"),
+            ("green_port",       "ALTER TABLE mappings ADD COLUMN green_port INTEGER DEFAULT NULL"),
+            ("switched_at",      "ALTER TABLE mappings ADD COLUMN switched_at TEXT DEFAULT NULL"),
+            ("probation_until",  "ALTER TABLE mappings ADD COLUMN probation_until TEXT DEFAULT NULL"),
+            ("experiment",       "ALTER TABLE mappings ADD COLUMN experiment TEXT DEFAULT NULL"),
+            ("allowed_content_types", "ALTER TABLE mappings ADD COLUMN allowed_content_types TEXT DEFAULT NULL"),
+            ("max_body_bytes",   "ALTER TABLE mappings ADD COLUMN max_body_bytes INTEGER DEFAULT NULL"),
+            ("preserve_path",    "ALTER TABLE mappings ADD COLUMN preserve_path INTEGER DEFAULT 0"),
+            ("log_level",        "ALTER TABLE mappings ADD COLUMN log_level TEXT DEFAULT NULL"),
+            ("log_sample",       "ALTER TABLE mappings ADD COLUMN log_sample TEXT DEFAULT NULL"),
+            ("body_rewrites",    "ALTER TABLE mappings ADD COLUMN body_rewrites TEXT DEFAULT NULL"),
+            ("html_base",        "ALTER TABLE mappings ADD COLUMN html_base TEXT DEFAULT NULL"),
+            ("rate_limit",       "ALTER TABLE mappings ADD COLUMN rate_limit TEXT DEFAULT NULL"),
+            ("fallback_backend", "ALTER TABLE mappings ADD COLUMN fallback_backend TEXT DEFAULT NULL"),
+            ("stale_if_error",   "ALTER TABLE mappings ADD COLUMN stale_if_error INTEGER DEFAULT NULL"),
+            ("upstream_tls",     "ALTER TABLE mappings ADD COLUMN upstream_tls TEXT DEFAULT NULL"),
+            ("egress_proxy",     "ALTER TABLE mappings ADD COLUMN egress_proxy TEXT DEFAULT NULL"),
+            ("document_root",    "ALTER TABLE mappings ADD COLUMN document_root TEXT DEFAULT NULL"),
+            ("oidc",             "ALTER TABLE mappings ADD COLUMN oidc TEXT DEFAULT NULL"),
+            ("url_signing_secret", "ALTER TABLE mappings ADD COLUMN url_signing_secret TEXT DEFAULT NULL"),
+            ("compress_requests", "ALTER TABLE mappings ADD COLUMN compress_requests INTEGER DEFAULT NULL"),
+            ("pool",             "ALTER TABLE mappings ADD COLUMN pool TEXT DEFAULT NULL"),
+            ("quota",            "ALTER TABLE mappings ADD COLUMN quota TEXT DEFAULT NULL"),
+            ("owner",            "ALTER TABLE mappings ADD COLUMN owner TEXT DEFAULT NULL"),
+            ("contract",         "ALTER TABLE mappings ADD COLUMN contract TEXT DEFAULT NULL"),
+            ("activate_at",      "ALTER TABLE mappings ADD COLUMN activate_at TEXT DEFAULT NULL"),
+            ("deactivate_at",    "ALTER TABLE mappings ADD COLUMN deactivate_at TEXT DEFAULT NULL"),
+            ("header_limits",    "ALTER TABLE mappings ADD COLUMN header_limits TEXT DEFAULT NULL"),
+            ("access_hours",     "ALTER TABLE mappings ADD COLUMN access_hours TEXT DEFAULT NULL"),
+            ("forward_headers",  "ALTER TABLE mappings ADD COLUMN forward_headers TEXT DEFAULT NULL"),
+            ("scrub_headers",    "ALTER TABLE mappings ADD COLUMN scrub_headers TEXT DEFAULT NULL"),
+            ("upstream_protocol", "ALTER TABLE mappings ADD COLUMN upstream_protocol TEXT DEFAULT NULL"),
+            ("upstream_signing", "ALTER TABLE mappings ADD COLUMN upstream_signing TEXT DEFAULT NULL"),
+            ("startup_page",  "ALTER TABLE mappings ADD COLUMN startup_page TEXT DEFAULT NULL"),
+            ("hold",          "ALTER TABLE mappings ADD COLUMN hold TEXT DEFAULT NULL"),
+            ("image_filter",  "ALTER TABLE mappings ADD COLUMN image_filter TEXT DEFAULT NULL"),
+            ("minify",        "ALTER TABLE mappings ADD COLUMN minify TEXT DEFAULT NULL"),
+            ("early_hints",   "ALTER TABLE mappings ADD COLUMN early_hints TEXT DEFAULT NULL"),
+            ("deleted_at",    "ALTER TABLE mappings ADD COLUMN deleted_at DATETIME DEFAULT NULL"),
+            ("version",       "ALTER TABLE mappings ADD COLUMN version INTEGER NOT NULL DEFAULT 1"),
         ];
 
         for (col, sql) in &migrations {
@@ -99,6 +727,123 @@ impl DatabaseManager {
             }
         }
 
+        // Small key/value state of the proxy itself (e.g. the cluster revision)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            [],
+        )?;
+        Self::normalize_stored_domains(&mut conn)?;
+        // Leader leases between processes sharing this database (see `crate::lease`)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS leases (name TEXT PRIMARY KEY, holder TEXT NOT NULL, expires_at INTEGER NOT NULL)",
+            [],
+        )?;
+        // ACME HTTP-01 tokens, answerable by every process sharing this database
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS acme_challenges (
+                token TEXT PRIMARY KEY,
+                key_authorization TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Per-mapping API keys, stored as SHA-256 hashes (see `add_api_key`)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                id TEXT PRIMARY KEY,
+                mapping_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                expires_at TEXT DEFAULT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Login sessions for SESSION_STORE=sqlite, keyed by the hash of the cookie's ID
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Requests and bytes per mapping and month (YYYY-MM), added to by every instance
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS usage (
+                mapping_id TEXT NOT NULL,
+                period TEXT NOT NULL,
+                requests INTEGER NOT NULL DEFAULT 0,
+                bytes_in INTEGER NOT NULL DEFAULT 0,
+                bytes_out INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (mapping_id, period)
+            )",
+            [],
+        )?;
+
+        // Tenants of a shared proxy; admin API tokens stored as SHA-256 hashes (see `add_tenant`)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tenants (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // Proof of domain control per tenant (see `crate::domain_verify`)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS domain_verifications (
+                domain TEXT NOT NULL,
+                owner TEXT NOT NULL,
+                token TEXT NOT NULL,
+                verified_at TEXT DEFAULT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (domain, owner)
+            )",
+            [],
+        )?;
+
+        // Banned IPs and ranges, and the rate-limit strikes of clients not banned yet
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS bans (
+                id TEXT PRIMARY KEY,
+                network TEXT NOT NULL,
+                reason TEXT DEFAULT NULL,
+                source TEXT NOT NULL,
+                expires_at TEXT DEFAULT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS offenders (
+                ip TEXT PRIMARY KEY,
+                strikes INTEGER NOT NULL,
+                since INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Synthetic probe history, trimmed to PROBE_RETENTION
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS probes (
+                mapping_id TEXT NOT NULL,
+                target TEXT NOT NULL,
+                at INTEGER NOT NULL,
+                ok INTEGER NOT NULL,
+                latency_ms REAL NOT NULL,
+                error TEXT DEFAULT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_probes_at ON probes(at)", [])?;
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_api_keys_mapping ON api_keys(mapping_id)", [])?;
         conn.execute("CREATE INDEX IF NOT EXISTS idx_mappings_domain ON mappings(domain)", [])?;
         conn.execute("CREATE INDEX IF NOT EXISTS idx_mappings_front_uri ON mappings(front_uri)", [])?;
         conn.execute("CREATE INDEX IF NOT EXISTS idx_mappings_domain_front_uri ON mappings(domain, front_uri)", [])?;
@@ -112,53 +857,31 @@ impl DatabaseManager {
 
     /// Find a mapping for a given domain and path.
     /// Priority: exact domain → wildcard *.parent.com → global catch-all '*'
+    ///
+    /// Served from an in-memory table that is rebuilt once the database has changed, so
+    /// a lookup costs one tiny version query instead of reading the domain's rows.
     pub fn find_mapping(&self, domain: &str, path: &str) -> Result<Option<Mapping>> {
+        let domain = domain_key(domain);
         let conn = self.conn.lock();
+        let version = conn
+            .prepare_cached("SELECT (SELECT data_version FROM pragma_data_version), total_changes()")?
+            .query_row([], |r| Ok((r.get(0)?, r.get(1)?)))?;
 
-        // CAST back_port so TEXT-affinity values (JS-created DBs) work too
-        let sql = "SELECT id, domain, front_uri, CAST(back_port AS INTEGER), back_uri, backend, back_ports,
-                          allowed_ips, auth_type, auth_credentials, created_at, updated_at
-                   FROM mappings
-                   WHERE domain = ?1
-                     AND (?2 LIKE '/' || front_uri || '%' OR front_uri = '')
-                   ORDER BY LENGTH(front_uri) DESC
-                   LIMIT 1";
-
-        let row_to_mapping = |row: &rusqlite::Row<'_>| -> rusqlite::Result<Mapping> {
-            Ok(Mapping {
-                id: row.get(0)?,
-                domain: row.get(1)?,
-                front_uri: row.get(2)?,
-                back_port: row.get(3)?,
-                back_uri: row.get(4)?,
-                backend: row.get(5)?,
-                back_ports: row.get(6)?,
-                allowed_ips: row.get(7)?,
-                auth_type: row.get(8)?,
-                auth_credentials: row.get(9)?,
-                created_at: row.get(10)?,
-                updated_at: row.get(11)?,
-            })
-        };
-
-        // 1. Exact domain match
-        let mapping = conn.prepare(sql)?.query_row(params![domain, path], row_to_mapping).optional()?;
-        if mapping.is_some() {
-            return Ok(mapping);
-        }
-
-        // 2. Wildcard domain match (*.parent.com)
-        if let Some(parent) = domain.splitn(2, '.').nth(1) {
-            let wildcard = format!("*.{}", parent);
-            let mapping = conn.prepare(sql)?.query_row(params![wildcard, path], row_to_mapping).optional()?;
-            if mapping.is_some() {
-                return Ok(mapping);
-            }
+        if let Some(table) = self.routes.read().as_ref().filter(|t| t.version == version) {
+            return Ok(table.find(&domain, path, chrono::Utc::now()).cloned());
         }
 
-        // 3. Global catch-all '*'
-        let mapping = conn.prepare(sql)?.query_row(params!["*", path], row_to_mapping).optional()?;
-        Ok(mapping)
+        // Prefix matching happens in `RouteTable` rather than with SQL LIKE, which would
+        // treat `%`/`_` in front_uri as wildcards and compare escapes byte-for-byte.
+        let mappings = conn
+            .prepare(&format!("SELECT {} FROM mappings WHERE deleted_at IS NULL ORDER BY rowid", MAPPING_COLUMNS))?
+            .query_map([], row_to_mapping)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(conn);
+        let table = RouteTable::build(version, mappings);
+        let found = table.find(&domain, path, chrono::Utc::now()).cloned();
+        *self.routes.write() = Some(table);
+        Ok(found)
     }
 
     /// Record a single use of a credential (for max_uses tracking).
@@ -217,21 +940,23 @@ impl DatabaseManager {
         };
 
         let _ = conn.execute(
-            "UPDATE mappings SET auth_credentials = ?1, updated_at = CURRENT_TIMESTAMP WHERE id = ?2",
+            "UPDATE mappings SET auth_credentials = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
             params![new_json, mapping_id],
         );
     }
 
     pub fn domain_exists(&self, domain: &str) -> Result<bool> {
+        let domain = domain_key(domain);
         let conn = self.conn.lock();
         let count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM mappings WHERE domain = ?1",
+            "SELECT COUNT(*) FROM mappings WHERE domain = ?1 AND deleted_at IS NULL",
             params![domain],
             |row| row.get(0),
         )?;
         Ok(count > 0)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn add_mapping(
         &self,
         domain: &str,
@@ -244,6 +969,8 @@ impl DatabaseManager {
         auth_type: Option<&str>,
         auth_credentials: Option<&str>,
     ) -> Result<Mapping> {
+        let domain = &crate::host::normalize_domain(domain)
+            .ok_or_else(|| anyhow::anyhow!("Invalid domain '{}'", domain))?;
         let conn = self.conn.lock();
         let id = Uuid::new_v4().to_string();
         let front_uri = front_uri.trim_start_matches('/').trim_end_matches('/');
@@ -270,6 +997,7 @@ impl DatabaseManager {
             auth_credentials: auth_credentials.map(|s| s.to_string()),
             created_at: chrono::Utc::now().to_rfc3339(),
             updated_at: chrono::Utc::now().to_rfc3339(),
+            ..Default::default()
         })
     }
 
@@ -311,7 +1039,7 @@ impl DatabaseManager {
             return Ok(false);
         }
 
-        updates.push("updated_at = CURRENT_TIMESTAMP".to_string());
+        updates.push("updated_at = CURRENT_TIMESTAMP, version = version + 1".to_string());
         let sql = format!("UPDATE mappings SET {} WHERE id = ?{}", updates.join(", "), idx);
         values.push(id.to_string());
 
@@ -320,166 +1048,1391 @@ impl DatabaseManager {
         Ok(affected > 0)
     }
 
-    pub fn delete_mapping(&self, domain: &str, front_uri: Option<&str>) -> Result<usize> {
+    /// Configure the green slot target of a mapping.
+    pub fn set_green_target(&self, id: &str, backend: Option<&str>, port: u16) -> Result<bool> {
         let conn = self.conn.lock();
-        let affected = if let Some(uri) = front_uri {
-            let uri = uri.trim_start_matches('/').trim_end_matches('/');
-            conn.execute("DELETE FROM mappings WHERE domain = ?1 AND front_uri = ?2", params![domain, uri])?
-        } else {
-            conn.execute("DELETE FROM mappings WHERE domain = ?1", params![domain])?
-        };
-        Ok(affected)
+        let affected = conn.execute(
+            "UPDATE mappings SET green_backend = ?1, green_port = ?2, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?3",
+            params![backend, port as i32, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    pub fn list_mappings(&self, domain: Option<&str>) -> Result<Vec<Mapping>> {
+    /// Atomically point live traffic at `slot` ("blue" or "green").
+    ///
+    /// With `probation`, an error spike before the window ends rolls the switch back;
+    /// `None` clears any pending probation (used for the rollback itself).
+    pub fn switch_slot(&self, id: &str, slot: &str, probation: Option<std::time::Duration>) -> Result<bool> {
+        if slot != "blue" && slot != "green" {
+            anyhow::bail!("Unknown slot '{}' (expected blue or green)", slot);
+        }
         let conn = self.conn.lock();
-        let sql = if domain.is_some() {
-            "SELECT id, domain, front_uri, CAST(back_port AS INTEGER), back_uri, backend, back_ports,
-                    allowed_ips, auth_type, auth_credentials, created_at, updated_at
-             FROM mappings WHERE domain = ?1 ORDER BY domain, front_uri"
-        } else {
-            "SELECT id, domain, front_uri, CAST(back_port AS INTEGER), back_uri, backend, back_ports,
-                    allowed_ips, auth_type, auth_credentials, created_at, updated_at
-             FROM mappings ORDER BY domain, front_uri"
-        };
-
-        let mut stmt = conn.prepare(sql)?;
-        let mut rows = if let Some(d) = domain {
-            stmt.query(params![d])?
-        } else {
-            stmt.query([])?
-        };
-
-        let mut mappings = Vec::new();
-        while let Some(row) = rows.next()? {
-            mappings.push(Mapping {
-                id: row.get(0)?,
-                domain: row.get(1)?,
-                front_uri: row.get(2)?,
-                back_port: row.get(3)?,
-                back_uri: row.get(4)?,
-                backend: row.get(5)?,
-                back_ports: row.get(6)?,
-                allowed_ips: row.get(7)?,
-                auth_type: row.get(8)?,
-                auth_credentials: row.get(9)?,
-                created_at: row.get(10)?,
-                updated_at: row.get(11)?,
-            });
+        if slot == "green" {
+            let green_port: Option<Option<i64>> = conn
+                .query_row("SELECT green_port FROM mappings WHERE id = ?1", params![id], |r| r.get(0))
+                .optional()?;
+            if matches!(green_port, Some(None)) {
+                anyhow::bail!("Mapping has no green slot configured");
+            }
         }
-        Ok(mappings)
+        let now = chrono::Utc::now();
+        let probation_until = probation
+            .and_then(|p| chrono::Duration::from_std(p).ok())
+            .map(|p| (now + p).to_rfc3339());
+        let affected = conn.execute(
+            "UPDATE mappings SET active_slot = ?1, switched_at = ?2, probation_until = ?3,
+                                 updated_at = CURRENT_TIMESTAMP, version = version + 1
+             WHERE id = ?4",
+            params![slot, now.to_rfc3339(), probation_until, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    pub fn get_mapping_by_id(&self, id: &str) -> Result<Option<Mapping>> {
+    /// Set or clear (`None`) the A/B experiment of a mapping.
+    pub fn set_experiment(&self, id: &str, experiment: Option<&str>) -> Result<bool> {
         let conn = self.conn.lock();
-        let mapping = conn.query_row(
-            "SELECT id, domain, front_uri, CAST(back_port AS INTEGER), back_uri, backend, back_ports,
-                    allowed_ips, auth_type, auth_credentials, created_at, updated_at
-             FROM mappings WHERE id = ?1",
-            params![id],
-            |row| Ok(Mapping {
-                id: row.get(0)?,
-                domain: row.get(1)?,
-                front_uri: row.get(2)?,
-                back_port: row.get(3)?,
-                back_uri: row.get(4)?,
-                backend: row.get(5)?,
-                back_ports: row.get(6)?,
-                allowed_ips: row.get(7)?,
-                auth_type: row.get(8)?,
-                auth_credentials: row.get(9)?,
-                created_at: row.get(10)?,
-                updated_at: row.get(11)?,
-            }),
-        ).optional()?;
-        Ok(mapping)
+        let affected = conn.execute(
+            "UPDATE mappings SET experiment = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![experiment, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    pub fn find_by_domain_and_uri(&self, domain: &str, front_uri: &str) -> Result<Option<Mapping>> {
+    /// Set the request body rules of a mapping; `None` removes a rule.
+    pub fn set_body_limits(&self, id: &str, content_types: Option<&str>, max_body_bytes: Option<u64>) -> Result<bool> {
         let conn = self.conn.lock();
-        let front_uri = front_uri.trim_start_matches('/').trim_end_matches('/');
-        let mapping = conn.query_row(
-            "SELECT id, domain, front_uri, CAST(back_port AS INTEGER), back_uri, backend, back_ports,
-                    allowed_ips, auth_type, auth_credentials, created_at, updated_at
-             FROM mappings WHERE domain = ?1 AND front_uri = ?2",
-            params![domain, front_uri],
-            |row| Ok(Mapping {
-                id: row.get(0)?,
-                domain: row.get(1)?,
-                front_uri: row.get(2)?,
-                back_port: row.get(3)?,
-                back_uri: row.get(4)?,
-                backend: row.get(5)?,
-                back_ports: row.get(6)?,
-                allowed_ips: row.get(7)?,
-                auth_type: row.get(8)?,
-                auth_credentials: row.get(9)?,
-                created_at: row.get(10)?,
-                updated_at: row.get(11)?,
-            }),
-        ).optional()?;
-        Ok(mapping)
+        let affected = conn.execute(
+            "UPDATE mappings SET allowed_content_types = ?1, max_body_bytes = ?2, updated_at = CURRENT_TIMESTAMP, version = version + 1
+             WHERE id = ?3",
+            params![content_types, max_body_bytes.map(|v| v.min(i64::MAX as u64) as i64), id],
+        )?;
+        Ok(affected > 0)
     }
-}
 
-impl Clone for DatabaseManager {
-    fn clone(&self) -> Self {
-        let conn = Connection::open(&self.db_path).expect("Failed to open database");
-        conn.execute_batch("PRAGMA journal_mode=WAL;").expect("Failed to set WAL mode");
-        Self {
-            conn: Arc::new(Mutex::new(conn)),
-            db_path: self.db_path.clone(),
-        }
+    /// Turn raw-path forwarding of a mapping on or off.
+    pub fn set_preserve_path(&self, id: &str, preserve: bool) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET preserve_path = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![preserve, id],
+        )?;
+        Ok(affected > 0)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
 
-    fn new_db(dir: &tempfile::TempDir) -> DatabaseManager {
-        DatabaseManager::new(dir.path().join("test.db")).unwrap()
+    /// Set the access-log overrides of a mapping; `None` falls back to the server default.
+    pub fn set_log_policy(&self, id: &str, level: Option<&str>, sample: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET log_level = ?1, log_sample = ?2, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?3",
+            params![level, sample, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    fn add(db: &DatabaseManager, domain: &str, front_uri: &str, back_port: u16, back_uri: &str) -> Mapping {
-        db.add_mapping(domain, front_uri, back_port, back_uri, None, None, None, None, None).unwrap()
+    /// Set or clear (`None`) the response body substitutions of a mapping.
+    pub fn set_body_rewrites(&self, id: &str, rules: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET body_rewrites = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![rules, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    #[test]
-    fn test_create_database() {
-        let dir = tempdir().unwrap();
-        let db_path = dir.path().join("test.db");
-        let _db = DatabaseManager::new(&db_path).unwrap();
-        assert!(db_path.exists());
+    /// Set or clear (`None`) the HTML base path mode of a mapping.
+    pub fn set_html_base(&self, id: &str, mode: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET html_base = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![mode, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    #[test]
-    fn test_add_and_find_mapping() {
-        let dir = tempdir().unwrap();
-        let db = new_db(&dir);
-        add(&db, "example.com", "api/v1", 3000, "v1");
-        let m = db.find_mapping("example.com", "/api/v1/users").unwrap().unwrap();
-        assert_eq!(m.domain, "example.com");
-        assert_eq!(m.front_uri, "api/v1");
-        assert_eq!(m.back_port, 3000);
+    /// Set or clear (`None`) the fallback backend of a mapping.
+    pub fn set_fallback_backend(&self, id: &str, target: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET fallback_backend = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![target, id],
+        )?;
+        Ok(affected > 0)
     }
 
-    #[test]
-    fn test_longest_match_first() {
-        let dir = tempdir().unwrap();
-        let db = new_db(&dir);
-        add(&db, "example.com", "api", 3000, "");
-        add(&db, "example.com", "api/v1", 3001, "v1");
-
-        let m = db.find_mapping("example.com", "/api/v1/users").unwrap().unwrap();
-        assert_eq!(m.back_port, 3001);
-
-        let m = db.find_mapping("example.com", "/api/v2/users").unwrap().unwrap();
-        assert_eq!(m.back_port, 3000);
+    /// Set or clear (`None`) how many seconds a mapping's responses may be served stale on errors.
+    pub fn set_stale_if_error(&self, id: &str, secs: Option<u64>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET stale_if_error = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![secs.map(|s| s.min(i64::MAX as u64) as i64), id],
+        )?;
+        Ok(affected > 0)
     }
 
-    #[test]
+    /// Set or clear (`None`) the upstream TLS verification settings (JSON) of a mapping.
+    pub fn set_upstream_tls(&self, id: &str, json: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET upstream_tls = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![json, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the egress proxy of a mapping.
+    pub fn set_egress_proxy(&self, id: &str, proxy: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET egress_proxy = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![proxy, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the FastCGI document root of a mapping.
+    pub fn set_document_root(&self, id: &str, root: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET document_root = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![root, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the OIDC login settings (JSON) of a mapping.
+    pub fn set_oidc(&self, id: &str, settings: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET oidc = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![settings, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the key signed URLs of a mapping are checked with.
+    pub fn set_url_signing_secret(&self, id: &str, secret: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET url_signing_secret = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![secret, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the body size from which requests to the backend are compressed.
+    pub fn set_compress_requests(&self, id: &str, min_bytes: Option<u64>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET compress_requests = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![min_bytes.map(|n| n.min(i64::MAX as u64) as i64), id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the connection pool overrides of a mapping.
+    pub fn set_pool(&self, id: &str, pool: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET pool = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![pool, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the monthly quota of a mapping.
+    pub fn set_quota(&self, id: &str, quota: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET quota = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![quota, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Hand a mapping to a tenant, or back to the operator (`None`). Unknown tenants are refused.
+    pub fn set_owner(&self, id: &str, owner: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        if let Some(tenant) = owner {
+            let known: bool = conn.query_row("SELECT COUNT(*) FROM tenants WHERE id = ?1", params![tenant], |r| r.get::<_, i64>(0))? > 0;
+            if !known {
+                anyhow::bail!("no tenant {}", tenant);
+            }
+        }
+        let affected = conn.execute(
+            "UPDATE mappings SET owner = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![owner, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the response contract of a mapping.
+    pub fn set_contract(&self, id: &str, contract: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET contract = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![contract, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) when a mapping starts being routed (RFC 3339).
+    pub fn set_activate_at(&self, id: &str, at: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET activate_at = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![at, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) when a mapping stops being routed (RFC 3339).
+    pub fn set_deactivate_at(&self, id: &str, at: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET deactivate_at = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![at, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the header limits of a mapping.
+    pub fn set_header_limits(&self, id: &str, limits: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET header_limits = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![limits, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the access hours of a mapping.
+    pub fn set_access_hours(&self, id: &str, hours: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET access_hours = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![hours, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the request header allow-list of a mapping.
+    pub fn set_forward_headers(&self, id: &str, headers: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET forward_headers = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![headers, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the response header scrubbing profile of a mapping.
+    pub fn set_scrub_headers(&self, id: &str, profile: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET scrub_headers = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![profile, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the upstream protocol settings of a mapping.
+    pub fn set_upstream_protocol(&self, id: &str, protocol: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET upstream_protocol = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![protocol, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) how requests to a mapping's backend are signed.
+    pub fn set_upstream_signing(&self, id: &str, signing: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET upstream_signing = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![signing, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the startup page settings of a mapping.
+    pub fn set_startup_page(&self, id: &str, startup: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET startup_page = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![startup, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Hold (`Some`) or release (`None`) the requests of a mapping.
+    pub fn set_hold(&self, id: &str, hold: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET hold = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![hold, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the image filter of a mapping.
+    pub fn set_image_filter(&self, id: &str, filter: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET image_filter = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![filter, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the minification settings of a mapping.
+    pub fn set_minify(&self, id: &str, minify: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET minify = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![minify, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the Early Hints of a mapping.
+    pub fn set_early_hints(&self, id: &str, hints: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET early_hints = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![hints, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Set or clear (`None`) the rate limit of a mapping.
+    pub fn set_rate_limit(&self, id: &str, limit: Option<&str>) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE mappings SET rate_limit = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+            params![limit, id],
+        )?;
+        Ok(affected > 0)
+    }
+
+    /// Replace every mapping with `mappings`, ids and timestamps included, in one transaction.
+    /// Deleted mappings stay restorable, unless `mappings` has them back.
+    pub fn replace_mappings(&self, mappings: &[Mapping]) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM mappings WHERE deleted_at IS NULL", [])?;
+        {
+            let mut insert = tx.prepare(&format!("INSERT OR REPLACE INTO mappings ({}) VALUES ({})", WRITTEN_COLUMNS, placeholders(1)))?;
+            for m in mappings {
+                write_mapping(&mut insert, m)?;
+            }
+        }
+        tx.commit()?;
+        // DELETE without WHERE may not move total_changes(); rebuild the route table anyway
+        *self.routes.write() = None;
+        Ok(())
+    }
+
+    /// Apply `changes` in order in one transaction: all of them, or — when one of them
+    /// can't be applied — none.
+    pub fn apply_changes(&self, changes: &[MappingChange]) -> Result<Result<Vec<Applied>, Rejected>> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let mut applied = Vec::with_capacity(changes.len());
+        for (index, change) in changes.iter().enumerate() {
+            match Self::apply_change(&tx, change)? {
+                Ok(a) => applied.push(a),
+                // Dropping the transaction rolls back what the batch did so far
+                Err(reason) => return Ok(Err(Rejected { index, reason })),
+            }
+        }
+        tx.commit()?;
+        *self.routes.write() = None;
+        Ok(Ok(applied))
+    }
+
+    fn apply_change(tx: &rusqlite::Transaction<'_>, change: &MappingChange) -> Result<Result<Applied, String>> {
+        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let live = |domain: &str, front_uri: &str| -> Result<Option<Mapping>> {
+            let mapping = tx.query_row(
+                &format!(
+                    "SELECT {} FROM mappings WHERE domain = ?1 AND front_uri = ?2 AND deleted_at IS NULL ORDER BY created_at, id LIMIT 1",
+                    MAPPING_COLUMNS,
+                ),
+                params![domain_key(domain), front_uri.trim_start_matches('/').trim_end_matches('/')],
+                row_to_mapping,
+            ).optional()?;
+            Ok(mapping)
+        };
+        let by_id = |id: &str| -> Result<Option<Mapping>> {
+            let mapping = tx.query_row(
+                &format!("SELECT {} FROM mappings WHERE id = ?1 AND deleted_at IS NULL", MAPPING_COLUMNS),
+                params![id],
+                row_to_mapping,
+            ).optional()?;
+            Ok(mapping)
+        };
+
+        Ok(Ok(match change {
+            MappingChange::Add { mapping } => {
+                if !mapping.contains_key("domain") {
+                    return Ok(Err("a new mapping needs a domain".to_string()));
+                }
+                let base = Mapping {
+                    id: Uuid::new_v4().to_string(),
+                    created_at: now.clone(),
+                    updated_at: now,
+                    version: 1,
+                    ..Default::default()
+                };
+                let m = match with_fields(&base, mapping) {
+                    Ok(m) => m,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                if live(&m.domain, &m.front_uri)?.is_some() {
+                    return Ok(Err(format!("{} (/{}) already has a mapping", m.domain, m.front_uri)));
+                }
+                let mut insert = tx.prepare_cached(&format!("INSERT INTO mappings ({}) VALUES ({})", WRITTEN_COLUMNS, placeholders(1)))?;
+                write_mapping(&mut insert, &m)?;
+                Applied { op: "add", ids: vec![m.id], version: Some(m.version) }
+            }
+            MappingChange::Update { id, domain, front_uri, version, set } => {
+                let found = match (id, domain) {
+                    (Some(id), None) => by_id(id)?,
+                    (None, Some(domain)) => live(domain, front_uri)?,
+                    _ => return Ok(Err("name the mapping by id, or by domain and front_uri".to_string())),
+                };
+                let Some(current) = found else {
+                    return Ok(Err("no such mapping".to_string()));
+                };
+                if current.version != *version {
+                    return Ok(Err(format!("mapping changed since version {}: it is at version {}", version, current.version)));
+                }
+                let mut m = match with_fields(&current, set) {
+                    Ok(m) => m,
+                    Err(reason) => return Ok(Err(reason)),
+                };
+                if (m.domain.as_str(), m.front_uri.as_str()) != (current.domain.as_str(), current.front_uri.as_str())
+                    && live(&m.domain, &m.front_uri)?.is_some()
+                {
+                    return Ok(Err(format!("{} (/{}) already has a mapping", m.domain, m.front_uri)));
+                }
+                m.updated_at = now;
+                m.version = current.version + 1;
+                let columns = WRITTEN_COLUMNS.split_once(',').expect("more than id").1;
+                let sql = format!("UPDATE mappings SET ({}) = ({}) WHERE id = ?1", columns, placeholders(2));
+                let mut update = tx.prepare_cached(&sql)?;
+                write_mapping(&mut update, &m)?;
+                Applied { op: "update", ids: vec![m.id], version: Some(m.version) }
+            }
+            MappingChange::Delete { id, domain, front_uri, purge } => {
+                let ids: Vec<String> = match (id, domain) {
+                    (Some(id), None) => by_id(id)?.map(|m| m.id).into_iter().collect(),
+                    (None, Some(domain)) => tx
+                        .prepare_cached(
+                            "SELECT id FROM mappings WHERE domain = ?1 AND (?2 IS NULL OR front_uri = ?2) AND deleted_at IS NULL",
+                        )?
+                        .query_map(
+                            params![domain_key(domain), front_uri.as_deref().map(|u| u.trim_start_matches('/').trim_end_matches('/'))],
+                            |r| r.get(0),
+                        )?
+                        .collect::<rusqlite::Result<_>>()?,
+                    _ => return Ok(Err("name the mappings by id, or by domain (and front_uri)".to_string())),
+                };
+                if ids.is_empty() {
+                    return Ok(Err("no such mapping".to_string()));
+                }
+                for id in &ids {
+                    if *purge {
+                        tx.execute("DELETE FROM mappings WHERE id = ?1", params![id])?;
+                        tx.execute("DELETE FROM api_keys WHERE mapping_id = ?1", params![id])?;
+                    } else {
+                        tx.execute("UPDATE mappings SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?1", params![id])?;
+                    }
+                }
+                Applied { op: "delete", ids, version: None }
+            }
+        }))
+    }
+
+    /// Once per database: store the domains of mappings written by older versions normalized
+    /// (see [`crate::host::normalize_domain`]), each rewrite logged and moving the mapping's
+    /// version on. A mapping whose normalized domain and front URI another live mapping has
+    /// (`Example.com` next to `example.com`) is left as it is, with a warning, and the
+    /// migration runs again at the next start until none is left.
+    fn normalize_stored_domains(conn: &mut Connection) -> Result<()> {
+        let done = conn
+            .query_row("SELECT 1 FROM settings WHERE key = ?1", params![DOMAINS_NORMALIZED], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if done {
+            return Ok(());
+        }
+        let tx = conn.transaction()?;
+        let rows: Vec<(String, String, String, bool)> = tx
+            .prepare("SELECT id, domain, front_uri, deleted_at IS NULL FROM mappings ORDER BY created_at, id")?
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut live: HashMap<(String, String), Vec<&str>> = HashMap::new();
+        for (id, domain, front_uri, is_live) in &rows {
+            if *is_live {
+                let key = crate::host::normalize_domain(domain).map_or_else(|| domain.clone(), Cow::into_owned);
+                live.entry((key, front_uri.clone())).or_default().push(id);
+            }
+        }
+        let mut collisions = 0;
+        for (id, domain, front_uri, is_live) in &rows {
+            let Some(normalized) = crate::host::normalize_domain(domain).filter(|n| n != domain) else { continue };
+            if *is_live {
+                let taken = &live[&(normalized.to_string(), front_uri.clone())];
+                if taken.len() > 1 {
+                    tracing::warn!(
+                        "Mapping {} for {} (/{}) not normalized to {}: mapping(s) {} have that domain and front URI; delete or change all but one",
+                        id, domain, front_uri, normalized, taken.iter().filter(|other| **other != id).copied().collect::<Vec<_>>().join(", "),
+                    );
+                    collisions += 1;
+                    continue;
+                }
+            }
+            tx.execute(
+                "UPDATE mappings SET domain = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2",
+                params![normalized, id],
+            )?;
+            tracing::info!("Normalized the domain of mapping {}: {} -> {}", id, domain, normalized);
+        }
+        if collisions == 0 {
+            tx.execute("INSERT OR REPLACE INTO settings (key, value) VALUES (?1, '1')", params![DOMAINS_NORMALIZED])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock();
+        let value = conn
+            .query_row("SELECT value FROM settings WHERE key = ?1", params![key], |r| r.get(0))
+            .optional()?;
+        Ok(value)
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.conn.lock();
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Take or extend lease `name` for `holder` for `ttl`, unless another holder's lease is
+    /// still running. Returns whether `holder` has it now.
+    pub fn try_lease(&self, name: &str, holder: &str, ttl: std::time::Duration) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let conn = self.conn.lock();
+        let changed = conn.execute(
+            "INSERT INTO leases (name, holder, expires_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+             WHERE leases.holder = excluded.holder OR leases.expires_at <= ?4",
+            params![name, holder, now + ttl.as_millis() as i64, now],
+        )?;
+        Ok(changed == 1)
+    }
+
+    /// Store an ACME HTTP-01 token; tokens older than [`ACME_CHALLENGE_TTL`] are dropped.
+    pub fn put_acme_challenge(&self, token: &str, key_authorization: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let conn = self.conn.lock();
+        conn.execute(
+            "DELETE FROM acme_challenges WHERE created_at < ?1",
+            params![now - ACME_CHALLENGE_TTL.as_secs() as i64],
+        )?;
+        conn.execute(
+            "INSERT OR REPLACE INTO acme_challenges (token, key_authorization, created_at) VALUES (?1, ?2, ?3)",
+            params![token, key_authorization, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_acme_challenge(&self, token: &str) -> Result<Option<String>> {
+        let oldest = chrono::Utc::now().timestamp() - ACME_CHALLENGE_TTL.as_secs() as i64;
+        let conn = self.conn.lock();
+        let value = conn
+            .prepare_cached("SELECT key_authorization FROM acme_challenges WHERE token = ?1 AND created_at >= ?2")?
+            .query_row(params![token, oldest], |r| r.get(0))
+            .optional()?;
+        Ok(value)
+    }
+
+    pub fn delete_acme_challenge(&self, token: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        Ok(conn.execute("DELETE FROM acme_challenges WHERE token = ?1", params![token])? > 0)
+    }
+
+    /// Create an API key for a mapping and switch the mapping to API key auth (mappings
+    /// with another `auth_type` are refused). Returns the stored record and the key itself,
+    /// which is only kept as a hash and can't be shown again.
+    pub fn add_api_key(&self, mapping_id: &str, name: &str, expires_at: Option<&str>) -> Result<(ApiKey, String)> {
+        let mut bytes = [0u8; 24];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
+        let secret = format!("rpk_{}", hex(&bytes));
+        let record = ApiKey {
+            id: Uuid::new_v4().to_string(),
+            mapping_id: mapping_id.to_string(),
+            name: name.to_string(),
+            expires_at: expires_at.map(|s| s.to_string()),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        };
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let auth_type: Option<Option<String>> = tx
+            .query_row("SELECT auth_type FROM mappings WHERE id = ?1", params![mapping_id], |r| r.get(0))
+            .optional()?;
+        match auth_type {
+            None => anyhow::bail!("no mapping with id {}", mapping_id),
+            Some(Some(t)) if t != API_KEY_AUTH => anyhow::bail!("mapping already uses {} auth", t),
+            _ => {}
+        }
+        tx.execute(
+            "UPDATE mappings SET auth_type = ?1, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?2 AND auth_type IS NULL",
+            params![API_KEY_AUTH, mapping_id],
+        )?;
+        tx.execute(
+            "INSERT INTO api_keys (id, mapping_id, name, key_hash, expires_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![record.id, record.mapping_id, record.name, api_key_hash(&secret), record.expires_at, record.created_at],
+        )?;
+        tx.commit()?;
+        Ok((record, secret))
+    }
+
+    /// The unexpired key of `mapping_id` matching `secret`, if any.
+    pub fn verify_api_key(&self, mapping_id: &str, secret: &str) -> Result<Option<ApiKey>> {
+        let conn = self.conn.lock();
+        let key = conn
+            .prepare_cached(
+                "SELECT id, mapping_id, name, expires_at, created_at FROM api_keys WHERE key_hash = ?1 AND mapping_id = ?2",
+            )?
+            .query_row(params![api_key_hash(secret), mapping_id], row_to_api_key)
+            .optional()?;
+        Ok(key.filter(|k| !k.is_expired()))
+    }
+
+    /// Keys of one mapping, or of all mappings, oldest first.
+    pub fn list_api_keys(&self, mapping_id: Option<&str>) -> Result<Vec<ApiKey>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT id, mapping_id, name, expires_at, created_at FROM api_keys
+             WHERE ?1 IS NULL OR mapping_id = ?1 ORDER BY created_at, rowid",
+        )?;
+        let keys = stmt.query_map(params![mapping_id], row_to_api_key)?.collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    /// Revoke a key. The mapping keeps API key auth, so revoking its last key locks it.
+    pub fn delete_api_key(&self, id: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        Ok(conn.execute("DELETE FROM api_keys WHERE id = ?1", params![id])? > 0)
+    }
+
+    /// Create a tenant with a fresh admin API token. Returns the stored record and the token,
+    /// which is only kept as a hash and can't be shown again.
+    pub fn add_tenant(&self, id: &str, name: &str) -> Result<(Tenant, String)> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            anyhow::bail!("tenant IDs are letters, digits, - and _");
+        }
+        let secret = tenant_token();
+        let record = Tenant { id: id.to_string(), name: name.to_string(), created_at: chrono::Utc::now().to_rfc3339() };
+        let conn = self.conn.lock();
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO tenants (id, name, token_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![record.id, record.name, api_key_hash(&secret), record.created_at],
+        )?;
+        if inserted == 0 {
+            anyhow::bail!("tenant {} already exists", id);
+        }
+        Ok((record, secret))
+    }
+
+    /// Replace a tenant's token; the old one stops working. `None` if there is no such tenant.
+    pub fn rotate_tenant_token(&self, id: &str) -> Result<Option<String>> {
+        let secret = tenant_token();
+        let conn = self.conn.lock();
+        let affected = conn.execute("UPDATE tenants SET token_hash = ?1 WHERE id = ?2", params![api_key_hash(&secret), id])?;
+        Ok((affected > 0).then_some(secret))
+    }
+
+    /// The tenant whose token is `secret`, if any.
+    pub fn verify_tenant_token(&self, secret: &str) -> Result<Option<Tenant>> {
+        let conn = self.conn.lock();
+        let tenant = conn
+            .prepare_cached("SELECT id, name, created_at FROM tenants WHERE token_hash = ?1")?
+            .query_row(params![api_key_hash(secret)], row_to_tenant)
+            .optional()?;
+        Ok(tenant)
+    }
+
+    pub fn get_tenant(&self, id: &str) -> Result<Option<Tenant>> {
+        let conn = self.conn.lock();
+        let tenant = conn
+            .query_row("SELECT id, name, created_at FROM tenants WHERE id = ?1", params![id], row_to_tenant)
+            .optional()?;
+        Ok(tenant)
+    }
+
+    pub fn list_tenants(&self) -> Result<Vec<Tenant>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT id, name, created_at FROM tenants ORDER BY id")?;
+        let tenants = stmt.query_map([], row_to_tenant)?.collect::<rusqlite::Result<_>>()?;
+        Ok(tenants)
+    }
+
+    /// Remove a tenant that no longer owns any mapping.
+    pub fn delete_tenant(&self, id: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        // Deleted mappings count: restored, they would belong to nobody
+        let owned: i64 = conn.query_row("SELECT COUNT(*) FROM mappings WHERE owner = ?1", params![id], |r| r.get(0))?;
+        if owned > 0 {
+            anyhow::bail!("tenant {} still owns {} mapping(s), deleted ones included (remove those with delete --purge)", id, owned);
+        }
+        // A tenant created later under the same ID proves its domains again
+        conn.execute("DELETE FROM domain_verifications WHERE owner = ?1", params![id])?;
+        Ok(conn.execute("DELETE FROM tenants WHERE id = ?1", params![id])? > 0)
+    }
+
+    /// The claim of `owner` on `domain`, created with a fresh token if there is none yet.
+    pub fn start_domain_verification(&self, domain: &str, owner: &str) -> Result<DomainVerification> {
+        let domain = domain_key(domain);
+        let mut bytes = [0u8; 16];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
+        let conn = self.conn.lock();
+        conn.execute(
+            "INSERT OR IGNORE INTO domain_verifications (domain, owner, token, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![domain, owner, format!("rpv_{}", hex(&bytes)), chrono::Utc::now().to_rfc3339()],
+        )?;
+        let claim = conn.query_row(
+            "SELECT domain, owner, token, verified_at, created_at FROM domain_verifications WHERE domain = ?1 AND owner = ?2",
+            params![domain, owner],
+            row_to_domain_verification,
+        )?;
+        Ok(claim)
+    }
+
+    /// Record that `owner` proved control of `domain`.
+    pub fn mark_domain_verified(&self, domain: &str, owner: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        let affected = conn.execute(
+            "UPDATE domain_verifications SET verified_at = ?1 WHERE domain = ?2 AND owner = ?3",
+            params![chrono::Utc::now().to_rfc3339(), domain_key(domain), owner],
+        )?;
+        Ok(affected > 0)
+    }
+
+    pub fn is_domain_verified(&self, domain: &str, owner: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        let verified: Option<Option<String>> = conn
+            .prepare_cached("SELECT verified_at FROM domain_verifications WHERE domain = ?1 AND owner = ?2")?
+            .query_row(params![domain_key(domain), owner], |r| r.get(0))
+            .optional()?;
+        Ok(verified.flatten().is_some())
+    }
+
+    /// Claims of one tenant, or of all, by domain.
+    pub fn list_domain_verifications(&self, owner: Option<&str>) -> Result<Vec<DomainVerification>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT domain, owner, token, verified_at, created_at FROM domain_verifications
+             WHERE ?1 IS NULL OR owner = ?1 ORDER BY domain, owner",
+        )?;
+        let claims = stmt.query_map(params![owner], row_to_domain_verification)?.collect::<rusqlite::Result<_>>()?;
+        Ok(claims)
+    }
+
+    /// Ban an IP or CIDR range (checked by the caller), until `expires_at` or for good.
+    pub fn add_ban(&self, network: &str, reason: Option<&str>, expires_at: Option<&str>, source: &str) -> Result<Ban> {
+        let ban = Ban {
+            id: Uuid::new_v4().to_string(),
+            network: network.to_string(),
+            reason: reason.map(str::to_string),
+            source: source.to_string(),
+            expires_at: expires_at.map(str::to_string),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        };
+        let conn = self.conn.lock();
+        conn.execute(
+            "INSERT INTO bans (id, network, reason, source, expires_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![ban.id, ban.network, ban.reason, ban.source, ban.expires_at, ban.created_at],
+        )?;
+        Ok(ban)
+    }
+
+    /// All bans, oldest first, expired ones included until [`Self::delete_expired_bans`].
+    pub fn list_bans(&self) -> Result<Vec<Ban>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT id, network, reason, source, expires_at, created_at FROM bans ORDER BY created_at, rowid",
+        )?;
+        let bans = stmt.query_map([], row_to_ban)?.collect::<rusqlite::Result<_>>()?;
+        Ok(bans)
+    }
+
+    /// Lift bans by ID or by network (all bans of that IP or range).
+    pub fn delete_ban(&self, id_or_network: &str) -> Result<usize> {
+        let conn = self.conn.lock();
+        Ok(conn.execute("DELETE FROM bans WHERE id = ?1 OR network = ?1", params![id_or_network])?)
+    }
+
+    pub fn delete_expired_bans(&self) -> Result<usize> {
+        let expired: Vec<String> = self.list_bans()?.into_iter().filter(Ban::is_expired).map(|b| b.id).collect();
+        let conn = self.conn.lock();
+        for id in &expired {
+            conn.execute("DELETE FROM bans WHERE id = ?1", params![id])?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Store strike counts `(ip, strikes, since)`, forget `cleared` clients and windows
+    /// that started before `stale_before` (Unix seconds).
+    pub fn save_offenders(&self, changed: &[(String, u32, i64)], cleared: &[String], stale_before: i64) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare("INSERT OR REPLACE INTO offenders (ip, strikes, since) VALUES (?1, ?2, ?3)")?;
+            for (ip, strikes, since) in changed {
+                upsert.execute(params![ip, strikes, since])?;
+            }
+            let mut delete = tx.prepare("DELETE FROM offenders WHERE ip = ?1")?;
+            for ip in cleared {
+                delete.execute(params![ip])?;
+            }
+        }
+        tx.execute("DELETE FROM offenders WHERE since < ?1", params![stale_before])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Strike counts `(ip, strikes, since)` of windows started at `since` or later.
+    pub fn offenders(&self, since: i64) -> Result<Vec<(String, u32, i64)>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT ip, strikes, since FROM offenders WHERE since >= ?1")?;
+        let rows = stmt.query_map(params![since], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Store a round of probe results and drop those from before `keep_since`.
+    pub fn record_probes(&self, probes: &[Probe], keep_since: i64) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO probes (mapping_id, target, at, ok, latency_ms, error) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for p in probes {
+                insert.execute(params![p.mapping_id, p.target, p.at, p.ok, p.latency_ms, p.error])?;
+            }
+        }
+        tx.execute("DELETE FROM probes WHERE at < ?1", params![keep_since])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Probe counts per target since `since`, in buckets of `bucket_secs` (`i64::MAX`: one
+    /// bucket for the whole span), oldest first.
+    pub fn probe_stats(&self, since: i64, bucket_secs: i64) -> Result<Vec<ProbeStats>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT mapping_id, target, (at / ?2) * ?2, COUNT(*), SUM(ok), AVG(CASE WHEN ok THEN latency_ms END)
+             FROM probes WHERE at >= ?1 GROUP BY mapping_id, target, at / ?2 ORDER BY 3, mapping_id, target",
+        )?;
+        let rows = stmt.query_map(params![since, bucket_secs.max(1)], |r| {
+            Ok(ProbeStats {
+                mapping_id: r.get(0)?,
+                target: r.get(1)?,
+                bucket: r.get(2)?,
+                probes: r.get::<_, i64>(3)? as u64,
+                up: r.get::<_, i64>(4)? as u64,
+                latency_ms: r.get(5)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// The latest probe of every target.
+    pub fn last_probes(&self) -> Result<Vec<Probe>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT mapping_id, target, at, ok, latency_ms, error FROM probes
+             WHERE rowid IN (SELECT MAX(rowid) FROM probes GROUP BY mapping_id, target)
+             ORDER BY mapping_id, target",
+        )?;
+        let rows = stmt.query_map([], |r| {
+            Ok(Probe { mapping_id: r.get(0)?, target: r.get(1)?, at: r.get(2)?, ok: r.get(3)?, latency_ms: r.get(4)?, error: r.get(5)? })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Keep a login session for `ttl`, dropping sessions that have expired meanwhile.
+    pub fn put_session(&self, id: &str, data: &str, ttl: std::time::Duration) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let conn = self.conn.lock();
+        conn.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])?;
+        conn.execute(
+            "INSERT OR REPLACE INTO sessions (id, data, expires_at) VALUES (?1, ?2, ?3)",
+            params![id, data, now + ttl.as_millis() as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_session(&self, id: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock();
+        let data = conn
+            .prepare_cached("SELECT data FROM sessions WHERE id = ?1 AND expires_at > ?2")?
+            .query_row(params![id, chrono::Utc::now().timestamp_millis()], |r| r.get(0))
+            .optional()?;
+        Ok(data)
+    }
+
+    pub fn delete_session(&self, id: &str) -> Result<bool> {
+        let conn = self.conn.lock();
+        Ok(conn.execute("DELETE FROM sessions WHERE id = ?1", params![id])? > 0)
+    }
+
+    /// Add counts to the `period` totals of each mapping, in one transaction.
+    pub fn add_usage(&self, period: &str, counts: &[(String, Usage)]) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO usage (mapping_id, period, requests, bytes_in, bytes_out) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (mapping_id, period) DO UPDATE SET requests = requests + excluded.requests,
+                     bytes_in = bytes_in + excluded.bytes_in, bytes_out = bytes_out + excluded.bytes_out",
+            )?;
+            let clamp = |n: u64| n.min(i64::MAX as u64) as i64;
+            for (id, u) in counts {
+                upsert.execute(params![id, period, clamp(u.requests), clamp(u.bytes_in), clamp(u.bytes_out)])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Stored totals of `period` by mapping ID.
+    pub fn usage(&self, period: &str) -> Result<HashMap<String, Usage>> {
+        Ok(self.usage_report(Some(period))?.into_iter().map(|r| (r.mapping_id, r.usage)).collect())
+    }
+
+    /// Stored totals of one period, or of all, with the mapping each belongs to.
+    pub fn usage_report(&self, period: Option<&str>) -> Result<Vec<UsageRow>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(
+            "SELECT u.mapping_id, m.domain, m.front_uri, m.quota, m.owner, u.period, u.requests, u.bytes_in, u.bytes_out
+             FROM usage u LEFT JOIN mappings m ON m.id = u.mapping_id
+             WHERE ?1 IS NULL OR u.period = ?1 ORDER BY u.period, m.domain, m.front_uri, u.mapping_id",
+        )?;
+        let rows = stmt.query_map(params![period], |r| {
+            Ok(UsageRow {
+                mapping_id: r.get(0)?,
+                domain: r.get(1)?,
+                front_uri: r.get(2)?,
+                quota: r.get(3)?,
+                owner: r.get(4)?,
+                period: r.get(5)?,
+                usage: Usage {
+                    requests: r.get::<_, i64>(6)?.max(0) as u64,
+                    bytes_in: r.get::<_, i64>(7)?.max(0) as u64,
+                    bytes_out: r.get::<_, i64>(8)?.max(0) as u64,
+                },
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Delete the mappings of `domain`, or just the one at `front_uri`. They stop matching at
+    /// once but are kept, API keys included, for [`restore_mapping`](Self::restore_mapping).
+    pub fn delete_mapping(&self, domain: &str, front_uri: Option<&str>) -> Result<usize> {
+        let domain = domain_key(domain);
+        let conn = self.conn.lock();
+        let uri = front_uri.map(|u| u.trim_start_matches('/').trim_end_matches('/'));
+        let affected = conn.execute(
+            "UPDATE mappings SET deleted_at = CURRENT_TIMESTAMP
+             WHERE domain = ?1 AND (?2 IS NULL OR front_uri = ?2) AND deleted_at IS NULL",
+            params![domain, uri],
+        )?;
+        Ok(affected)
+    }
+
+    /// Remove the mappings of `domain` (at `front_uri`) for good, deleted ones included, with
+    /// their API keys.
+    pub fn purge_mapping(&self, domain: &str, front_uri: Option<&str>) -> Result<usize> {
+        let domain = domain_key(domain);
+        let conn = self.conn.lock();
+        let uri = front_uri.map(|u| u.trim_start_matches('/').trim_end_matches('/'));
+        let affected = conn.execute(
+            "DELETE FROM mappings WHERE domain = ?1 AND (?2 IS NULL OR front_uri = ?2)",
+            params![domain, uri],
+        )?;
+        conn.execute("DELETE FROM api_keys WHERE mapping_id NOT IN (SELECT id FROM mappings)", [])?;
+        Ok(affected)
+    }
+
+    /// Bring back the last deleted mapping at each prefix of `domain`, or at `front_uri`.
+    /// Refused for a prefix that a live mapping has taken since.
+    pub fn restore_mapping(&self, domain: &str, front_uri: Option<&str>) -> Result<usize> {
+        let domain = domain_key(domain);
+        let mut conn = self.conn.lock();
+        let uri = front_uri.map(|u| u.trim_start_matches('/').trim_end_matches('/'));
+        let tx = conn.transaction()?;
+        let deleted: Vec<(String, String)> = tx
+            .prepare(
+                "SELECT id, front_uri FROM mappings
+                 WHERE domain = ?1 AND (?2 IS NULL OR front_uri = ?2) AND deleted_at IS NOT NULL
+                 ORDER BY deleted_at DESC, rowid DESC",
+            )?
+            .query_map(params![domain, uri], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut restored: Vec<&str> = Vec::new();
+        for (id, front_uri) in &deleted {
+            if restored.contains(&front_uri.as_str()) {
+                continue;
+            }
+            let taken: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM mappings WHERE domain = ?1 AND front_uri = ?2 AND deleted_at IS NULL",
+                params![domain, front_uri],
+                |r| r.get(0),
+            )?;
+            if taken > 0 {
+                anyhow::bail!("{} (/{}) has a mapping again; delete that one first", domain, front_uri);
+            }
+            tx.execute(
+                "UPDATE mappings SET deleted_at = NULL, updated_at = CURRENT_TIMESTAMP, version = version + 1 WHERE id = ?1",
+                params![id],
+            )?;
+            restored.push(front_uri);
+        }
+        tx.commit()?;
+        Ok(restored.len())
+    }
+
+    /// Deleted mappings, with when they were deleted, most recent first.
+    pub fn list_deleted_mappings(&self, domain: Option<&str>) -> Result<Vec<(Mapping, String)>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, deleted_at FROM mappings WHERE (?1 IS NULL OR domain = ?1) AND deleted_at IS NOT NULL
+             ORDER BY deleted_at DESC, rowid DESC",
+            MAPPING_COLUMNS,
+        ))?;
+        let deleted = stmt
+            .query_map(params![domain.map(domain_key)], |row| {
+                let deleted_at = row.get(row.as_ref().column_count() - 1)?;
+                Ok((row_to_mapping(row)?, deleted_at))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(deleted)
+    }
+
+    pub fn list_mappings(&self, domain: Option<&str>) -> Result<Vec<Mapping>> {
+        let conn = self.conn.lock();
+        let sql = if domain.is_some() {
+            format!("SELECT {} FROM mappings WHERE domain = ?1 AND deleted_at IS NULL ORDER BY domain, front_uri", MAPPING_COLUMNS)
+        } else {
+            format!("SELECT {} FROM mappings WHERE deleted_at IS NULL ORDER BY domain, front_uri", MAPPING_COLUMNS)
+        };
+
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = if let Some(d) = domain {
+            stmt.query(params![domain_key(d)])?
+        } else {
+            stmt.query([])?
+        };
+
+        let mut mappings = Vec::new();
+        while let Some(row) = rows.next()? {
+            mappings.push(row_to_mapping(row)?);
+        }
+        Ok(mappings)
+    }
+
+    pub fn get_mapping_by_id(&self, id: &str) -> Result<Option<Mapping>> {
+        let conn = self.conn.lock();
+        let mapping = conn.query_row(
+            &format!("SELECT {} FROM mappings WHERE id = ?1 AND deleted_at IS NULL", MAPPING_COLUMNS),
+            params![id],
+            row_to_mapping,
+        ).optional()?;
+        Ok(mapping)
+    }
+
+    /// The mapping for exactly this domain and prefix; the oldest if there are several.
+    pub fn find_by_domain_and_uri(&self, domain: &str, front_uri: &str) -> Result<Option<Mapping>> {
+        let domain = domain_key(domain);
+        let conn = self.conn.lock();
+        let front_uri = front_uri.trim_start_matches('/').trim_end_matches('/');
+        let mapping = conn.query_row(
+            &format!(
+                "SELECT {} FROM mappings WHERE domain = ?1 AND front_uri = ?2 AND deleted_at IS NULL ORDER BY created_at, id LIMIT 1",
+                MAPPING_COLUMNS,
+            ),
+            params![domain, front_uri],
+            row_to_mapping,
+        ).optional()?;
+        Ok(mapping)
+    }
+}
+
+impl Clone for DatabaseManager {
+    fn clone(&self) -> Self {
+        let conn = Connection::open(&self.db_path).expect("Failed to open database");
+        conn.execute_batch("PRAGMA journal_mode=WAL;").expect("Failed to set WAL mode");
+        Self {
+            conn: Arc::new(Mutex::new(conn)),
+            db_path: self.db_path.clone(),
+            routes: RwLock::new(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn new_db(dir: &tempfile::TempDir) -> DatabaseManager {
+        DatabaseManager::new(dir.path().join("test.db")).unwrap()
+    }
+
+    fn add(db: &DatabaseManager, domain: &str, front_uri: &str, back_port: u16, back_uri: &str) -> Mapping {
+        db.add_mapping(domain, front_uri, back_port, back_uri, None, None, None, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_create_database() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("test.db");
+        let _db = DatabaseManager::new(&db_path).unwrap();
+        assert!(db_path.exists());
+    }
+
+    #[test]
+    fn test_add_and_find_mapping() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        add(&db, "example.com", "api/v1", 3000, "v1");
+        let m = db.find_mapping("example.com", "/api/v1/users").unwrap().unwrap();
+        assert_eq!(m.domain, "example.com");
+        assert_eq!(m.front_uri, "api/v1");
+        assert_eq!(m.back_port, 3000);
+    }
+
+    #[test]
+    fn test_longest_match_first() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        add(&db, "example.com", "api", 3000, "");
+        add(&db, "example.com", "api/v1", 3001, "v1");
+
+        let m = db.find_mapping("example.com", "/api/v1/users").unwrap().unwrap();
+        assert_eq!(m.back_port, 3001);
+
+        let m = db.find_mapping("example.com", "/api/v2/users").unwrap().unwrap();
+        assert_eq!(m.back_port, 3000);
+    }
+
+    #[test]
+    fn test_scheduled_switch() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        add(&db, "example.com", "", 3000, "");
+        add(&db, "example.com", "", 4000, "");
+        add(&db, "example.com", "beta", 5000, "");
+        let [old, new, beta] = &db.list_mappings(None).unwrap()[..] else { panic!() };
+        let launch = chrono::Utc::now() + chrono::Duration::hours(1);
+        db.set_deactivate_at(&old.id, Some(&launch.to_rfc3339())).unwrap();
+        db.set_activate_at(&new.id, Some(&launch.to_rfc3339())).unwrap();
+        db.set_deactivate_at(&beta.id, Some("2020-01-01T00:00:00Z")).unwrap();
+
+        // Before the launch the old one serves; the retired beta route falls through to it
+        assert_eq!(db.find_mapping("example.com", "/").unwrap().unwrap().back_port, 3000);
+        assert_eq!(db.find_mapping("example.com", "/beta/x").unwrap().unwrap().back_port, 3000);
+
+        let table = db.routes.read();
+        let after = |path| table.as_ref().unwrap().find("example.com", path, launch).map(|m| m.back_port);
+        assert_eq!(after("/"), Some(4000));
+        assert_eq!(after("/beta"), Some(4000));
+    }
+
+    #[test]
+    fn test_route_table_follows_changes_from_any_connection() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        add(&db, "example.com", "", 3000, "");
+        assert_eq!(db.find_mapping("example.com", "/").unwrap().unwrap().back_port, 3000);
+
+        // Same connection
+        add(&db, "example.com", "api", 3001, "");
+        assert_eq!(db.find_mapping("example.com", "/api/x").unwrap().unwrap().back_port, 3001);
+
+        // Another connection (as the mapping CLI would be)
+        let other = db.clone();
+        other.delete_mapping("example.com", Some("api")).unwrap();
+        assert_eq!(db.find_mapping("example.com", "/api/x").unwrap().unwrap().back_port, 3000);
+        other.delete_mapping("example.com", None).unwrap();
+        assert!(db.find_mapping("example.com", "/").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_soft_delete_and_restore() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let root = add(&db, "example.com", "", 3000, "");
+        let api = add(&db, "example.com", "api", 3001, "");
+        assert_eq!(db.delete_mapping("example.com", Some("/api/")).unwrap(), 1);
+        assert_eq!(db.find_mapping("example.com", "/api/x").unwrap().unwrap().id, root.id);
+        assert!(db.get_mapping_by_id(&api.id).unwrap().is_none());
+        assert!(db.find_by_domain_and_uri("example.com", "api").unwrap().is_none());
+        assert_eq!(db.list_mappings(None).unwrap().len(), 1);
+        let [(deleted, at)] = &db.list_deleted_mappings(Some("example.com")).unwrap()[..] else { panic!() };
+        assert_eq!(deleted.id, api.id);
+        assert!(!at.is_empty());
+
+        // Restored as it was, changes included
+        assert_eq!(db.restore_mapping("example.com", Some("api")).unwrap(), 1);
+        db.update_mapping(&api.id, None, None, Some(3002), None).unwrap();
+        db.delete_mapping("example.com", None).unwrap();
+        assert!(!db.domain_exists("example.com").unwrap());
+        assert_eq!(db.restore_mapping("example.com", None).unwrap(), 2);
+        assert_eq!(db.find_mapping("example.com", "/api/x").unwrap().unwrap().back_port, 3002);
+
+        // Not over a mapping added in the meantime
+        db.delete_mapping("example.com", Some("api")).unwrap();
+        add(&db, "example.com", "api", 4000, "");
+        assert!(db.restore_mapping("example.com", Some("api")).is_err());
+        assert_eq!(db.find_mapping("example.com", "/api/x").unwrap().unwrap().back_port, 4000);
+
+        // Purged for good, deleted ones included
+        assert_eq!(db.purge_mapping("example.com", Some("api")).unwrap(), 2);
+        assert!(db.list_deleted_mappings(None).unwrap().is_empty());
+        assert_eq!(db.restore_mapping("example.com", None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let root = add(&db, "example.com", "", 3000, "");
+        add(&db, "old.com", "", 3100, "");
+        let created_at = db.get_mapping_by_id(&root.id).unwrap().unwrap().created_at;
+        let changes: Vec<MappingChange> = serde_json::from_str(
+            r#"[
+                {"op": "add", "mapping": {"domain": "Example.com", "front_uri": "/api/", "back_port": 3001, "rate_limit": "10/1s"}},
+                {"op": "update", "domain": "example.com", "version": 1, "set": {"back_port": 3002, "backend": "app"}},
+                {"op": "delete", "domain": "old.com"}
+            ]"#,
+        ).unwrap();
+        let applied = db.apply_changes(&changes).unwrap().unwrap();
+        assert_eq!(applied.iter().map(|a| (a.op, a.version)).collect::<Vec<_>>(), [("add", Some(1)), ("update", Some(2)), ("delete", None)]);
+        let api = db.find_mapping("example.com", "/api/x").unwrap().unwrap();
+        assert_eq!((api.id.as_str(), api.back_port, api.rate_limit.as_deref()), (applied[0].ids[0].as_str(), 3001, Some("10/1s")));
+        let updated = db.find_mapping("example.com", "/").unwrap().unwrap();
+        assert_eq!((updated.id, updated.back_port, updated.backend.as_deref()), (root.id.clone(), 3002, Some("app")));
+        assert_eq!((updated.created_at, updated.version), (created_at, 2));
+        assert!(!db.domain_exists("old.com").unwrap());
+        assert_eq!(db.restore_mapping("old.com", None).unwrap(), 1);
+
+        // A change that doesn't apply undoes the ones before it
+        let changes: Vec<MappingChange> = serde_json::from_str(
+            r#"[
+                {"op": "delete", "id": "<root>", "purge": true},
+                {"op": "add", "mapping": {"domain": "example.com", "front_uri": "api", "back_port": 4000}}
+            ]"#.replace("<root>", &root.id).as_str(),
+        ).unwrap();
+        let rejected = db.apply_changes(&changes).unwrap().unwrap_err();
+        assert_eq!(rejected.index, 1);
+        assert_eq!(rejected.reason, "example.com (/api) already has a mapping");
+        assert_eq!(db.find_mapping("example.com", "/").unwrap().unwrap().id, root.id);
+
+        for bad in [
+            r#"{"op": "update", "id": "nope", "version": 1, "set": {"back_port": 1}}"#,
+            r#"{"op": "update", "domain": "example.com", "version": 1, "set": {"back_port": 1}}"#,
+            r#"{"op": "update", "domain": "example.com", "version": 2, "set": {"version": 7}}"#,
+            r#"{"op": "update", "domain": "example.com", "version": 2, "set": {"colour": "red"}}"#,
+            r#"{"op": "update", "domain": "example.com", "version": 2, "set": {"back_port": "high"}}"#,
+            r#"{"op": "add", "mapping": {"back_port": 3000}}"#,
+            r#"{"op": "delete", "domain": "nope.com"}"#,
+        ] {
+            let change: MappingChange = serde_json::from_str(bad).unwrap();
+            assert!(db.apply_changes(&[change]).unwrap().is_err(), "{}", bad);
+        }
+        assert!(serde_json::from_str::<MappingChange>(r#"{"op": "delete", "domian": "a.com"}"#).is_err());
+        assert!(serde_json::from_str::<MappingChange>(r#"{"op": "rename"}"#).is_err());
+        assert!(serde_json::from_str::<MappingChange>(r#"{"op": "update", "domain": "a.com", "set": {}}"#).is_err());
+    }
+
+    #[test]
+    fn test_stored_domains_normalized_once() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let upper = add(&db, "example.com", "", 3000, "");
+        add(&db, "example.com", "", 3001, "");
+        let other = add(&db, "other.com", "api", 3002, "");
+        {
+            // As an older version stored them
+            let conn = db.conn.lock();
+            conn.execute("UPDATE mappings SET domain = 'Example.com' WHERE id = ?1", params![upper.id]).unwrap();
+            conn.execute("UPDATE mappings SET domain = 'Other.COM.' WHERE id = ?1", params![other.id]).unwrap();
+            conn.execute("DELETE FROM settings WHERE key = ?1", params![DOMAINS_NORMALIZED]).unwrap();
+        }
+        drop(db);
+
+        // Other.COM. is rewritten, Example.com would collide with example.com and stays
+        let db = new_db(&dir);
+        let get = |db: &DatabaseManager, id: &str| db.get_mapping_by_id(id).unwrap().unwrap();
+        assert_eq!((get(&db, &other.id).domain, get(&db, &other.id).version), ("other.com".to_string(), 2));
+        assert_eq!((get(&db, &upper.id).domain, get(&db, &upper.id).version), ("Example.com".to_string(), 1));
+        assert_eq!(db.get_setting(DOMAINS_NORMALIZED).unwrap(), None);
+
+        // Once the collision is resolved, the next start finishes the migration
+        assert_eq!(db.delete_mapping("example.com", Some("")).unwrap(), 1);
+        drop(db);
+        let db = new_db(&dir);
+        assert_eq!(get(&db, &upper.id).domain, "example.com");
+        assert!(db.get_setting(DOMAINS_NORMALIZED).unwrap().is_some());
+        db.conn.lock().execute("UPDATE mappings SET domain = 'EXAMPLE.com' WHERE id = ?1", params![upper.id]).unwrap();
+        drop(db);
+        assert_eq!(get(&new_db(&dir), &upper.id).domain, "EXAMPLE.com");
+    }
+
+    #[test]
+    fn test_versions() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let m = add(&db, "example.com", "", 3000, "");
+        let version = |db: &DatabaseManager| db.get_mapping_by_id(&m.id).unwrap().unwrap().version;
+        assert_eq!(version(&db), 1);
+        // Every change moves it on
+        db.set_rate_limit(&m.id, Some("10/1s")).unwrap();
+        db.update_mapping(&m.id, None, None, Some(3001), None).unwrap();
+        assert_eq!(version(&db), 3);
+
+        // Two operators who both read version 3: the second is refused. An update setting
+        // several fields moves the version on once
+        let update = |port: u16| MappingChange::Update {
+            id: Some(m.id.clone()),
+            domain: None,
+            front_uri: String::new(),
+            version: 3,
+            set: serde_json::json!({ "back_port": port, "rate_limit": null, "preserve_path": true }).as_object().unwrap().clone(),
+        };
+        assert!(db.apply_changes(&[update(3002)]).unwrap().is_ok());
+        assert!(db.apply_changes(&[update(3003)]).unwrap().is_err());
+        let now = db.get_mapping_by_id(&m.id).unwrap().unwrap();
+        assert_eq!((now.version, now.back_port, now.rate_limit, now.preserve_path), (4, 3002, None, true));
+
+        // Replication keeps versions as they are
+        let mut all = db.list_mappings(None).unwrap();
+        all[0].version = 9;
+        db.replace_mappings(&all).unwrap();
+        assert_eq!(version(&db), 9);
+    }
+
+    #[test]
     fn test_wildcard_domain() {
         let dir = tempdir().unwrap();
         let db = new_db(&dir);
@@ -520,6 +2473,38 @@ mod tests {
         assert_eq!(m.back_port, 5000);
     }
 
+    #[test]
+    fn test_find_mapping_encoded_paths() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        add(&db, "example.com", "", 3000, "");
+        add(&db, "example.com", "api/v1", 3001, "");
+        add(&db, "example.com", "my_app", 3002, "");
+        add(&db, "example.com", "caf%C3%A9", 3003, "");
+
+        // Encoded separators and hex case can't dodge the more specific rule
+        assert_eq!(db.find_mapping("example.com", "/api%2Fv1/users").unwrap().unwrap().back_port, 3001);
+        assert_eq!(db.find_mapping("example.com", "/api%2fv1/users").unwrap().unwrap().back_port, 3001);
+        assert_eq!(db.find_mapping("example.com", "/caf%c3%a9/menu").unwrap().unwrap().back_port, 3003);
+        // `_` and `%` in front_uri are literal, not LIKE wildcards
+        assert_eq!(db.find_mapping("example.com", "/my_app/x").unwrap().unwrap().back_port, 3002);
+        assert_eq!(db.find_mapping("example.com", "/myXapp/x").unwrap().unwrap().back_port, 3000);
+    }
+
+    #[test]
+    fn test_domains_stored_and_matched_normalized() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let m = add(&db, "ExAmPle.COM.", "", 3000, "");
+        assert_eq!(m.domain, "example.com");
+        add(&db, "*.Bücher.example", "", 3001, "");
+
+        assert_eq!(db.find_mapping("EXAMPLE.com.", "/").unwrap().unwrap().back_port, 3000);
+        assert_eq!(db.find_mapping("shop.xn--bcher-kva.example", "/").unwrap().unwrap().back_port, 3001);
+        assert_eq!(db.find_mapping("Shop.BÜCHER.example", "/").unwrap().unwrap().back_port, 3001);
+        assert!(db.find_by_domain_and_uri("Example.com", "").unwrap().is_some());
+    }
+
     #[test]
     fn test_auth_fields_stored() {
         let dir = tempdir().unwrap();
@@ -532,6 +2517,80 @@ mod tests {
         assert_eq!(m.auth_credentials.as_deref(), Some(creds));
     }
 
+    #[test]
+    fn test_api_keys() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let m = add(&db, "api.com", "", 3000, "");
+        let (key, secret) = db.add_api_key(&m.id, "ci", None).unwrap();
+        let (_, old) = db.add_api_key(&m.id, "old", Some("2020-01-01T00:00:00+00:00")).unwrap();
+
+        assert_eq!(db.get_mapping_by_id(&m.id).unwrap().unwrap().auth_type.as_deref(), Some(API_KEY_AUTH));
+        assert_eq!(db.verify_api_key(&m.id, &secret).unwrap().unwrap().id, key.id);
+        assert!(db.verify_api_key(&m.id, &old).unwrap().is_none());
+        assert!(db.verify_api_key("other", &secret).unwrap().is_none());
+        assert_eq!(db.list_api_keys(Some(&m.id)).unwrap().len(), 2);
+
+        // Only the hash is stored
+        let stored: String = db.conn.lock().query_row("SELECT key_hash FROM api_keys WHERE id = ?1", [&key.id], |r| r.get(0)).unwrap();
+        assert_ne!(stored, secret);
+
+        let basic = db.add_mapping("basic.com", "", 3000, "", None, None, None, Some("basic"), Some("[]")).unwrap();
+        assert!(db.add_api_key(&basic.id, "x", None).is_err());
+
+        // Kept while the mapping can be restored
+        db.delete_mapping("api.com", None).unwrap();
+        assert_eq!(db.list_api_keys(None).unwrap().len(), 2);
+        db.purge_mapping("api.com", None).unwrap();
+        assert!(db.list_api_keys(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tenants() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let (tenant, token) = db.add_tenant("acme", "Acme Inc").unwrap();
+        assert!(db.add_tenant("acme", "Again").is_err());
+        assert!(db.add_tenant("no spaces", "x").is_err());
+        assert_eq!(db.verify_tenant_token(&token).unwrap().unwrap().id, tenant.id);
+        assert!(db.verify_tenant_token("rpt_guess").unwrap().is_none());
+
+        let m = add(&db, "shop.acme.com", "", 3000, "");
+        assert!(db.set_owner(&m.id, Some("nobody")).is_err());
+        db.set_owner(&m.id, Some("acme")).unwrap();
+        assert_eq!(db.get_mapping_by_id(&m.id).unwrap().unwrap().owner.as_deref(), Some("acme"));
+
+        let rotated = db.rotate_tenant_token("acme").unwrap().unwrap();
+        assert!(db.verify_tenant_token(&token).unwrap().is_none());
+        assert!(db.verify_tenant_token(&rotated).unwrap().is_some());
+
+        // Not while it still owns mappings
+        assert!(db.delete_tenant("acme").is_err());
+        db.set_owner(&m.id, None).unwrap();
+        assert!(db.delete_tenant("acme").unwrap());
+        assert!(db.list_tenants().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_domain_verification() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        assert!(!db.is_domain_verified("shop.acme.com", "acme").unwrap());
+        assert!(!db.mark_domain_verified("shop.acme.com", "acme").unwrap());
+
+        // The token stays the same until it is proven
+        let claim = db.start_domain_verification("Shop.Acme.com", "acme").unwrap();
+        assert_eq!(claim.domain, "shop.acme.com");
+        assert_eq!(db.start_domain_verification("shop.acme.com", "acme").unwrap().token, claim.token);
+        assert_ne!(db.start_domain_verification("shop.acme.com", "globex").unwrap().token, claim.token);
+
+        assert!(db.mark_domain_verified("shop.acme.com", "acme").unwrap());
+        assert!(db.is_domain_verified("SHOP.acme.com", "acme").unwrap());
+        assert!(!db.is_domain_verified("shop.acme.com", "globex").unwrap());
+        assert_eq!(db.list_domain_verifications(Some("globex")).unwrap().len(), 1);
+        assert_eq!(db.list_domain_verifications(None).unwrap().len(), 2);
+    }
+
     #[test]
     fn test_allowed_ips_stored() {
         let dir = tempdir().unwrap();
@@ -555,4 +2614,90 @@ mod tests {
         let updated = db.get_mapping_by_id(&m.id).unwrap().unwrap();
         assert!(updated.auth_credentials.is_none());
     }
+
+    #[test]
+    fn test_switch_slot() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let m = add(&db, "bg.com", "", 3000, "");
+
+        // No green target yet
+        assert!(db.switch_slot(&m.id, "green", None).is_err());
+
+        db.set_green_target(&m.id, None, 3001).unwrap();
+        db.switch_slot(&m.id, "green", Some(std::time::Duration::from_secs(60))).unwrap();
+
+        let mut live = db.find_mapping("bg.com", "/").unwrap().unwrap();
+        assert!(live.is_green());
+        assert!(live.probation_until.is_some());
+        live.apply_active_slot();
+        assert_eq!(live.back_port, 3001);
+
+        db.switch_slot(&m.id, "blue", None).unwrap();
+        let mut live = db.find_mapping("bg.com", "/").unwrap().unwrap();
+        live.apply_active_slot();
+        assert_eq!(live.back_port, 3000);
+        assert!(live.probation_until.is_none());
+    }
+
+    #[test]
+    fn test_fallback_backend() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let m = add(&db, "failover.com", "api", 3000, "v1");
+        assert!(m.fallback().is_none());
+
+        db.set_fallback_backend(&m.id, Some("standby.internal:8080")).unwrap();
+        let m = db.find_mapping("failover.com", "/api/x").unwrap().unwrap();
+        let standby = m.fallback().unwrap();
+        assert_eq!(standby.backend.as_deref(), Some("http://standby.internal"));
+        assert_eq!(standby.back_port, 8080);
+        assert_eq!((standby.front_uri.as_str(), standby.back_uri.as_str()), ("api", "v1"));
+
+        db.set_fallback_backend(&m.id, Some("http://sorry.internal")).unwrap();
+        assert_eq!(db.get_mapping_by_id(&m.id).unwrap().unwrap().fallback().unwrap().back_port, 80);
+    }
+
+    #[test]
+    fn test_set_log_policy() {
+        let dir = tempdir().unwrap();
+        let db = new_db(&dir);
+        let m = add(&db, "chatty.com", "", 3000, "");
+        assert!(m.log_level.is_none() && m.log_sample.is_none());
+
+        db.set_log_policy(&m.id, Some("debug"), Some("2xx=1%")).unwrap();
+        let m = db.get_mapping_by_id(&m.id).unwrap().unwrap();
+        assert_eq!(m.log_level.as_deref(), Some("debug"));
+        assert_eq!(m.log_sample.as_deref(), Some("2xx=1%"));
+
+        db.set_log_policy(&m.id, None, None).unwrap();
+        assert!(db.get_mapping_by_id(&m.id).unwrap().unwrap().log_level.is_none());
+    }
+
+    #[test]
+    fn test_replace_mappings_keeps_ids_and_settings() {
+        let dir = tempdir().unwrap();
+        let source = new_db(&dir);
+        add(&source, "a.com", "api", 3000, "v1");
+        let id = source.list_mappings(None).unwrap()[0].id.clone();
+        source.set_rate_limit(&id, Some("5/1m")).unwrap();
+        source.set_egress_proxy(&id, Some("direct")).unwrap();
+        source.set_oidc(&id, Some(r#"{"issuer":"https://idp","client_id":"a","client_secret":"s"}"#)).unwrap();
+        source.set_url_signing_secret(&id, Some("${LINK_KEY}")).unwrap();
+        source.set_compress_requests(&id, Some(1024)).unwrap();
+        source.set_pool(&id, Some("max_idle=0")).unwrap();
+        let copy = DatabaseManager::new(dir.path().join("copy.db")).unwrap();
+        add(&copy, "stale.com", "", 4000, "");
+
+        let mappings = source.list_mappings(None).unwrap();
+        copy.replace_mappings(&mappings).unwrap();
+        let copied = copy.list_mappings(None).unwrap();
+        assert_eq!(serde_json::to_string(&copied).unwrap(), serde_json::to_string(&mappings).unwrap());
+        assert!(copy.find_mapping("stale.com", "/").unwrap().is_none());
+
+        assert_eq!(copy.get_setting("k").unwrap(), None);
+        copy.set_setting("k", "1").unwrap();
+        copy.set_setting("k", "2").unwrap();
+        assert_eq!(copy.get_setting("k").unwrap().as_deref(), Some("2"));
+    }
 }
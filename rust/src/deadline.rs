@@ -0,0 +1,137 @@
+//! Per-request deadlines
+//! Reads a client's time budget from `X-Request-Timeout` or gRPC's `grpc-timeout`, clamps it
+//! to the proxy's limit, and rewrites the headers so the backend sees the same budget
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use std::time::Duration;
+
+/// Budget in seconds, fractions allowed (`2`, `0.25`), or with an `ms`/`s` unit (`250ms`)
+pub const REQUEST_TIMEOUT: HeaderName = HeaderName::from_static("x-request-timeout");
+/// gRPC budget: up to 8 digits and a unit (`H`, `M`, `S`, `m`, `u`, `n`), e.g. `500m`
+pub const GRPC_TIMEOUT: HeaderName = HeaderName::from_static("grpc-timeout");
+
+/// Parse an `X-Request-Timeout` value.
+pub fn parse_request_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, millis) = match value.strip_suffix("ms") {
+        Some(n) => (n, true),
+        None => (value.strip_suffix('s').unwrap_or(value), false),
+    };
+    let n: f64 = number.trim().parse().ok()?;
+    if !n.is_finite() || n < 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f64(if millis { n / 1000.0 } else { n }).ok()
+}
+
+/// Parse a `grpc-timeout` value.
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        'H' => Duration::from_secs(n * 3600),
+        'M' => Duration::from_secs(n * 60),
+        'S' => Duration::from_secs(n),
+        'm' => Duration::from_millis(n),
+        'u' => Duration::from_micros(n),
+        'n' => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// `d` as a `grpc-timeout` value, in the finest unit that fits 8 digits.
+pub fn format_grpc_timeout(d: Duration) -> String {
+    const MAX: u128 = 99_999_999;
+    if d.as_micros() <= MAX {
+        format!("{}u", d.as_micros())
+    } else if d.as_millis() <= MAX {
+        format!("{}m", d.as_millis())
+    } else {
+        format!("{}S", d.as_secs().min(MAX as u64))
+    }
+}
+
+/// `d` as an `X-Request-Timeout` value (seconds, millisecond precision).
+pub fn format_request_timeout(d: Duration) -> String {
+    format!("{}.{:03}", d.as_secs(), d.subsec_millis())
+}
+
+/// The tightest budget the request carries, capped at `max`. `None` when it carries no
+/// valid deadline header or `max` is zero (deadlines disabled).
+pub fn budget(headers: &HeaderMap, max: Duration) -> Option<Duration> {
+    if max.is_zero() {
+        return None;
+    }
+    let value = |name: &HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+    let own = value(&REQUEST_TIMEOUT).and_then(parse_request_timeout);
+    let grpc = value(&GRPC_TIMEOUT).and_then(parse_grpc_timeout);
+    let tightest = match (own, grpc) {
+        (Some(a), Some(b)) => a.min(b),
+        (a, b) => a.or(b)?,
+    };
+    Some(tightest.min(max))
+}
+
+/// Rewrite the deadline headers the client sent to the remaining `budget`.
+pub fn forward(headers: &mut HeaderMap, budget: Duration) {
+    if headers.contains_key(REQUEST_TIMEOUT) {
+        if let Ok(v) = HeaderValue::from_str(&format_request_timeout(budget)) {
+            headers.insert(REQUEST_TIMEOUT, v);
+        }
+    }
+    if headers.contains_key(GRPC_TIMEOUT) {
+        if let Ok(v) = HeaderValue::from_str(&format_grpc_timeout(budget)) {
+            headers.insert(GRPC_TIMEOUT, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_timeout() {
+        assert_eq!(parse_request_timeout("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_request_timeout("0.25"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_request_timeout("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_request_timeout("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_request_timeout("-1"), None);
+        assert_eq!(parse_request_timeout("soon"), None);
+        assert_eq!(parse_request_timeout("inf"), None);
+    }
+
+    #[test]
+    fn test_grpc_timeout_round_trip() {
+        assert_eq!(parse_grpc_timeout("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+        assert_eq!(parse_grpc_timeout("5"), None);
+        assert_eq!(parse_grpc_timeout("m"), None);
+        assert_eq!(format_grpc_timeout(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(format_grpc_timeout(Duration::from_secs(3600)), "3600000m");
+        let d = Duration::from_millis(42_123);
+        assert_eq!(parse_grpc_timeout(&format_grpc_timeout(d)), Some(d));
+    }
+
+    #[test]
+    fn test_budget_takes_tightest_and_clamps() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(budget(&headers, Duration::from_secs(60)), None);
+
+        headers.insert(REQUEST_TIMEOUT, HeaderValue::from_static("10"));
+        headers.insert(GRPC_TIMEOUT, HeaderValue::from_static("3S"));
+        assert_eq!(budget(&headers, Duration::from_secs(60)), Some(Duration::from_secs(3)));
+        assert_eq!(budget(&headers, Duration::from_secs(1)), Some(Duration::from_secs(1)));
+        assert_eq!(budget(&headers, Duration::ZERO), None);
+
+        forward(&mut headers, Duration::from_millis(1250));
+        assert_eq!(headers[REQUEST_TIMEOUT], "1.250");
+        assert_eq!(headers[GRPC_TIMEOUT], "1250000u");
+    }
+}
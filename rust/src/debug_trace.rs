@@ -0,0 +1,124 @@
+//! Per-request debug traces
+//! With `DEBUG_SECRET` set, a request carrying `X-RustProxy-Debug: <secret>` is answered
+//! with an `X-RustProxy-Debug` response header saying how it was routed: the mapping it
+//! matched, the path sent upstream, each backend tried with its status and time, and the
+//! total time. "Why did this route there" can then be answered from the client side,
+//! without access to the proxy's logs. The request header is never passed to backends,
+//! and a wrong secret is ignored as if the header were absent
+
+use crate::admin::constant_time_eq;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, StatusCode};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Request header asking for a trace, and response header carrying it
+pub const HEADER: HeaderName = HeaderName::from_static("x-rustproxy-debug");
+
+/// One backend exchange
+#[derive(Debug, Clone)]
+struct Attempt {
+    target: String,
+    /// Response status, `None` when no response came
+    status: Option<StatusCode>,
+    elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Steps {
+    mapping: Option<String>,
+    upstream: Option<String>,
+    attempts: Vec<Attempt>,
+}
+
+/// What happened to one request, filled in as it passes through the proxy. Carried in
+/// the request's extensions, so the forwarding code records attempts where it makes them.
+/// Requests are traced without a header too while `rustproxy tail` follows the traffic.
+#[derive(Debug, Clone, Default)]
+pub struct Trace(Arc<Mutex<Steps>>);
+
+impl Trace {
+    /// Take the debug header off `headers`; a trace if it held `secret`.
+    pub fn requested(headers: &mut HeaderMap, secret: Option<&str>) -> Option<Trace> {
+        let given = headers.remove(HEADER)?;
+        let secret = secret.filter(|s| !s.is_empty())?;
+        constant_time_eq(given.as_bytes(), secret.as_bytes()).then(Trace::default)
+    }
+
+    /// The request matched mapping `id`.
+    pub fn mapping(&self, id: &str) {
+        self.0.lock().mapping = Some(id.to_string());
+    }
+
+    /// The path and query sent to the backend, after rewriting.
+    pub fn upstream(&self, path_and_query: &str) {
+        self.0.lock().upstream = Some(path_and_query.to_string());
+    }
+
+    /// One exchange with `target`: the status it answered, or `None` if it failed.
+    pub fn attempt(&self, target: impl Into<String>, status: Option<StatusCode>, elapsed: Duration) {
+        self.0.lock().attempts.push(Attempt { target: target.into(), status, elapsed });
+    }
+
+    /// The backend of the last exchange.
+    pub fn backend(&self) -> Option<String> {
+        self.0.lock().attempts.last().map(|a| a.target.clone())
+    }
+
+    /// `mapping=ID; upstream=/v1/users; backend=10.0.0.6:3000; attempts=10.0.0.5:3000
+    /// failed 3ms, 10.0.0.6:3000 200 12ms; retries=1; total=17ms`
+    pub fn summary(&self, total: Duration) -> String {
+        let steps = self.0.lock();
+        let mut parts = vec![format!("mapping={}", steps.mapping.as_deref().unwrap_or("none"))];
+        if let Some(upstream) = &steps.upstream {
+            parts.push(format!("upstream={}", upstream));
+        }
+        if let Some(last) = steps.attempts.last() {
+            parts.push(format!("backend={}", last.target));
+            let attempts: Vec<String> = steps.attempts.iter()
+                .map(|a| format!("{} {} {}ms", a.target, a.status.map_or("failed".to_string(), |s| s.as_u16().to_string()), a.elapsed.as_millis()))
+                .collect();
+            parts.push(format!("attempts={}", attempts.join(", ")));
+            parts.push(format!("retries={}", steps.attempts.len() - 1));
+        }
+        parts.push(format!("total={}ms", total.as_millis()));
+        parts.join("; ")
+    }
+
+    /// The summary as a header value; characters a header can't hold become `?`.
+    pub fn header_value(&self, total: Duration) -> HeaderValue {
+        let summary: String = self.summary(total).chars()
+            .map(|c| if c == ' ' || c.is_ascii_graphic() { c } else { '?' })
+            .collect();
+        HeaderValue::from_str(&summary).unwrap_or_else(|_| HeaderValue::from_static("unavailable"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_static("wrong"));
+        assert!(Trace::requested(&mut headers, Some("s3cret")).is_none());
+        assert!(headers.is_empty());
+        headers.insert(HEADER, HeaderValue::from_static("s3cret"));
+        assert!(Trace::requested(&mut headers.clone(), None).is_none());
+        let trace = Trace::requested(&mut headers, Some("s3cret")).unwrap();
+        assert!(headers.is_empty());
+
+        assert_eq!(trace.summary(Duration::from_millis(2)), "mapping=none; total=2ms");
+        trace.mapping("m1");
+        trace.upstream("/v1/users?page=2");
+        trace.clone().attempt("10.0.0.5:3000", None, Duration::from_millis(3));
+        trace.attempt("10.0.0.6:3000", Some(StatusCode::OK), Duration::from_millis(12));
+        assert_eq!(
+            trace.header_value(Duration::from_millis(17)),
+            "mapping=m1; upstream=/v1/users?page=2; backend=10.0.0.6:3000; \
+             attempts=10.0.0.5:3000 failed 3ms, 10.0.0.6:3000 200 12ms; retries=1; total=17ms"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Just enough DER reading to pick fields out of certificates and CSRs
+
+/// A DER element: its tag, all its bytes, its contents, and the bytes after it
+pub(crate) struct Element<'a> {
+    pub tag: u8,
+    pub whole: &'a [u8],
+    pub contents: &'a [u8],
+    pub rest: &'a [u8],
+}
+
+/// The DER element at the start of `input`.
+pub(crate) fn element(input: &[u8]) -> Option<Element<'_>> {
+    let (&tag, after_tag) = input.split_first()?;
+    let (&first, after_len) = after_tag.split_first()?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || after_len.len() < n {
+            return None;
+        }
+        (after_len[..n].iter().fold(0usize, |acc, &b| (acc << 8) | b as usize), 2 + n)
+    };
+    let end = header.checked_add(len).filter(|&end| end <= input.len())?;
+    Some(Element { tag, whole: &input[..end], contents: &input[header..end], rest: &input[end..] })
+}
+
+/// The elements of a SEQUENCE or SET's contents, in order (stops at the first bad one).
+pub(crate) fn elements(mut input: &[u8]) -> impl Iterator<Item = Element<'_>> {
+    std::iter::from_fn(move || {
+        let e = element(input)?;
+        input = e.rest;
+        Some(e)
+    })
+}
+
+/// The fields of a DER certificate's TBSCertificate after the optional version: serial,
+/// signature algorithm, issuer, validity, subject, public key, then the optional ones.
+fn tbs_fields(cert_der: &[u8]) -> Option<Vec<Element<'_>>> {
+    let cert = element(cert_der)?.contents;
+    let mut rest = element(cert)?.contents;
+    if rest.first() == Some(&0xa0) {
+        rest = element(rest)?.rest;
+    }
+    Some(elements(rest).collect())
+}
+
+/// End of a DER certificate's validity, as RFC 3339 (`2026-01-31T23:59:59Z`).
+pub(crate) fn not_after(cert_der: &[u8]) -> Option<String> {
+    let fields = tbs_fields(cert_der)?;
+    let validity = fields.get(3)?;
+    let end = elements(validity.contents).nth(1)?;
+    let time = std::str::from_utf8(end.contents).ok()?.strip_suffix('Z')?;
+    let full = match end.tag {
+        // UTCTime: YYMMDDHHMMSS, years 50–99 are 19xx
+        0x17 if time.len() == 12 => format!("{}{}", if &time[..2] >= "50" { "19" } else { "20" }, time),
+        // GeneralizedTime: YYYYMMDDHHMMSS
+        0x18 if time.len() == 14 => time.to_string(),
+        _ => return None,
+    };
+    if !full.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{}-{}-{}T{}:{}:{}Z", &full[..4], &full[4..6], &full[6..8], &full[8..10], &full[10..12], &full[12..14]))
+}
+
+/// Subject of a DER certificate as an RFC 4514 string, most specific part first
+/// (`CN=alice,O=Example,C=NL`).
+pub(crate) fn subject(cert_der: &[u8]) -> Option<String> {
+    let fields = tbs_fields(cert_der)?;
+    let mut rdns = Vec::new();
+    for rdn in elements(fields.get(4)?.contents) {
+        let mut attributes = Vec::new();
+        for attribute in elements(rdn.contents) {
+            let mut parts = elements(attribute.contents);
+            let (oid, value) = (parts.next()?, parts.next()?);
+            attributes.push(format!("{}={}", attribute_type(oid.contents), attribute_value(&value)));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// DNS names and URIs among a DER certificate's subject alternative names, in order, as
+/// (`DNS` or `URI`, value).
+pub(crate) fn subject_alt_names(cert_der: &[u8]) -> Vec<(&'static str, String)> {
+    let Some(fields) = tbs_fields(cert_der) else { return Vec::new() };
+    // [3] extensions: SEQUENCE of { id, critical (optional), OCTET STRING value }
+    let Some(extensions) = fields.iter().find(|e| e.tag == 0xa3).and_then(|e| element(e.contents)) else {
+        return Vec::new();
+    };
+    let san = elements(extensions.contents).find_map(|ext| {
+        let mut parts = elements(ext.contents);
+        match parts.next() {
+            Some(id) if id.contents == [0x55, 0x1d, 0x11] => parts.find(|p| p.tag == 0x04),
+            _ => None,
+        }
+    });
+    let Some(names) = san.and_then(|value| element(value.contents)) else { return Vec::new() };
+    elements(names.contents)
+        .filter_map(|name| {
+            let kind = match name.tag {
+                0x82 => "DNS",
+                0x86 => "URI",
+                _ => return None,
+            };
+            Some((kind, std::str::from_utf8(name.contents).ok()?.to_string()))
+        })
+        .collect()
+}
+
+/// Short name of a DN attribute type, else its dotted OID.
+fn attribute_type(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x05] => "serialNumber",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        _ => {
+            let mut arcs = Vec::new();
+            let mut arc = 0u64;
+            for &b in oid {
+                arc = (arc << 7) | (b & 0x7f) as u64;
+                if b & 0x80 == 0 {
+                    // The first subidentifier holds the first two arcs
+                    if arcs.is_empty() {
+                        let top = (arc / 40).min(2);
+                        arcs.extend([top, arc - 40 * top]);
+                    } else {
+                        arcs.push(arc);
+                    }
+                    arc = 0;
+                }
+            }
+            return arcs.iter().map(u64::to_string).collect::<Vec<_>>().join(".");
+        }
+    };
+    name.to_string()
+}
+
+/// A DN attribute value: strings escaped per RFC 4514, anything else as `#` and hex.
+fn attribute_value(value: &Element<'_>) -> String {
+    let text = match value.tag {
+        // UTF8String, PrintableString, TeletexString, IA5String
+        0x0c | 0x13 | 0x14 | 0x16 => std::str::from_utf8(value.contents).ok().map(str::to_string),
+        // BMPString
+        0x1e if value.contents.len().is_multiple_of(2) => {
+            let units = value.contents.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+            char::decode_utf16(units).collect::<Result<String, _>>().ok()
+        }
+        _ => None,
+    };
+    let Some(text) = text else {
+        return format!("#{}", value.whole.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    };
+    let last = text.chars().count().saturating_sub(1);
+    let mut out = String::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' if i == 0 => out.push_str("\\#"),
+            ' ' if i == 0 || i == last => out.push_str("\\ "),
+            c if c.is_ascii_control() => out.push_str(&format!("\\{:02x}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,574 @@
+//! Service discovery for mapping backends
+//! Resolves `consul://<service>`, `etcd://<key-prefix>` and `srv://<name>` backends into live
+//! host:port instances
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use dashmap::DashMap;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// How long a Consul blocking query may wait for a change before returning.
+const CONSUL_WAIT: &str = "55s";
+/// How long an etcd watch stream is kept open before it is re-established.
+const ETCD_WATCH_TTL: Duration = Duration::from_secs(300);
+/// Minimum pause between two watch round-trips, so a misbehaving registry can't spin us.
+const WATCH_MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Stop watching a service that no request has resolved for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Floor for SRV record TTLs, so a zero-TTL zone doesn't mean a DNS query per request.
+const SRV_MIN_TTL: Duration = Duration::from_secs(5);
+/// A cached SRV answer no request has used for this many TTLs is dropped.
+const SRV_IDLE_TTLS: u32 = 10;
+
+/// A single resolved backend instance
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    /// SRV priority: lower values are tried first. Always 0 for other sources.
+    pub priority: u16,
+    /// SRV weight: relative share of traffic within a priority group.
+    pub weight: u16,
+}
+
+impl Endpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self { host: host.into(), port, priority: 0, weight: 1 }
+    }
+}
+
+/// Order `targets` for one request: ascending priority, and within each priority group a
+/// weighted random order per RFC 2782. Groups whose weights are all equal are returned in
+/// the given order so callers can round-robin them.
+pub fn weighted_order(targets: &[Endpoint]) -> Vec<Endpoint> {
+    let mut sorted = targets.to_vec();
+    sorted.sort_by_key(|t| t.priority);
+
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(sorted.len());
+    for group in sorted.chunk_by(|a, b| a.priority == b.priority) {
+        if group.iter().all(|t| t.weight == group[0].weight) {
+            ordered.extend_from_slice(group);
+            continue;
+        }
+        let mut remaining = group.to_vec();
+        while !remaining.is_empty() {
+            // Zero-weight records still get a (small) chance, as the RFC suggests
+            let total: u32 = remaining.iter().map(|t| t.weight as u32 + 1).sum();
+            let mut pick = rng.gen_range(0..total);
+            let idx = remaining.iter().position(|t| {
+                let w = t.weight as u32 + 1;
+                if pick < w { true } else { pick -= w; false }
+            }).unwrap_or(0);
+            ordered.push(remaining.remove(idx));
+        }
+    }
+    ordered
+}
+
+/// Where a mapping's backend instances come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoverySource {
+    /// `consul://<service>[?tag=<tag>&dc=<dc>]` — healthy instances of a Consul service
+    Consul { service: String, tag: Option<String>, dc: Option<String> },
+    /// `etcd://<key-prefix>` — every key under the prefix holds one instance address
+    Etcd { prefix: String },
+    /// `srv://_service._proto.name` — DNS SRV records, re-resolved when their TTL expires
+    Srv { name: String },
+}
+
+impl DiscoverySource {
+    /// Parse a mapping `backend` value; returns `None` for ordinary http(s) backends.
+    pub fn parse(backend: &str) -> Option<Self> {
+        if let Some(rest) = backend.strip_prefix("consul://") {
+            let (service, query) = rest.split_once('?').unwrap_or((rest, ""));
+            let service = service.trim_end_matches('/');
+            if service.is_empty() {
+                return None;
+            }
+            let mut tag = None;
+            let mut dc = None;
+            for (k, v) in url::form_urlencoded::parse(query.as_bytes()) {
+                match k.as_ref() {
+                    "tag" => tag = Some(v.into_owned()),
+                    "dc" => dc = Some(v.into_owned()),
+                    _ => {}
+                }
+            }
+            return Some(Self::Consul { service: service.to_string(), tag, dc });
+        }
+        if let Some(rest) = backend.strip_prefix("etcd://") {
+            if rest.is_empty() {
+                return None;
+            }
+            let prefix = if rest.starts_with('/') { rest.to_string() } else { format!("/{}", rest) };
+            return Some(Self::Etcd { prefix });
+        }
+        if let Some(rest) = backend.strip_prefix("srv://") {
+            let name = rest.trim_end_matches('/');
+            if name.is_empty() {
+                return None;
+            }
+            return Some(Self::Srv { name: name.to_string() });
+        }
+        None
+    }
+}
+
+/// Cached instance list for one discovery backend
+struct ServiceEntry {
+    endpoints: Arc<Vec<Endpoint>>,
+    last_used: Instant,
+    /// Identifies the background watch that owns this entry
+    watch_id: u64,
+    /// TTL-based sources (SRV) are refreshed lazily once this passes instead of watched
+    expires_at: Option<Instant>,
+    /// The record TTL of an SRV answer
+    ttl: Duration,
+}
+
+/// Resolves discovery backends and keeps their instance lists fresh with background watches.
+pub struct ServiceDiscovery {
+    consul_addr: String,
+    consul_token: Option<String>,
+    etcd_endpoint: String,
+    client: reqwest::Client,
+    /// backend string → current instances
+    services: DashMap<String, ServiceEntry>,
+    next_watch_id: AtomicU64,
+    /// DNS server for SRV lookups; the system resolver's when `None`
+    srv_nameserver: Option<SocketAddr>,
+    /// Built on first SRV lookup
+    resolver: OnceCell<TokioAsyncResolver>,
+}
+
+impl ServiceDiscovery {
+    pub fn new(
+        consul_addr: Option<String>,
+        consul_token: Option<String>,
+        etcd_endpoint: Option<String>,
+        srv_nameserver: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            consul_addr: consul_addr
+                .unwrap_or_else(|| "http://127.0.0.1:8500".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            consul_token,
+            etcd_endpoint: etcd_endpoint
+                .unwrap_or_else(|| "http://127.0.0.1:2379".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            client: reqwest::Client::new(),
+            services: DashMap::new(),
+            next_watch_id: AtomicU64::new(1),
+            srv_nameserver,
+            resolver: OnceCell::new(),
+        }
+    }
+
+    /// Return the current instances for `backend`.
+    ///
+    /// The first call for a backend queries the registry directly and starts a background
+    /// watch; later calls are served from the watched cache.
+    pub async fn resolve(self: &Arc<Self>, backend: &str, source: &DiscoverySource) -> Result<Arc<Vec<Endpoint>>> {
+        if let DiscoverySource::Srv { name } = source {
+            return self.resolve_srv(backend, name).await;
+        }
+
+        if let Some(mut entry) = self.services.get_mut(backend) {
+            entry.last_used = Instant::now();
+            return Ok(entry.endpoints.clone());
+        }
+
+        let (endpoints, cursor) = self.fetch(source, 0).await?;
+        let endpoints = Arc::new(endpoints);
+        let watch_id = self.next_watch_id.fetch_add(1, Ordering::Relaxed);
+        match self.services.entry(backend.to_string()) {
+            // Another request resolved it concurrently; its watch is already running
+            dashmap::mapref::entry::Entry::Occupied(e) => return Ok(e.get().endpoints.clone()),
+            dashmap::mapref::entry::Entry::Vacant(e) => {
+                e.insert(ServiceEntry { endpoints: endpoints.clone(), last_used: Instant::now(), watch_id, expires_at: None, ttl: Duration::ZERO });
+            }
+        }
+        info!("Discovery: {} resolved to {} instance(s)", backend, endpoints.len());
+        Self::spawn_watch(Arc::clone(self), backend.to_string(), source.clone(), cursor, watch_id);
+        Ok(endpoints)
+    }
+
+    /// Drop cached instances so the next request re-queries the registry (or DNS, for
+    /// `srv://`). Returns how many backends were dropped.
+    pub fn invalidate(&self, backend: Option<&str>) -> usize {
+        match backend {
+            Some(b) => self.services.remove(b).map_or(0, |_| 1),
+            None => {
+                let n = self.services.len();
+                self.services.clear();
+                n
+            }
+        }
+    }
+
+    /// SRV lookups are cached for the record TTL; a failed refresh keeps serving the stale
+    /// answer rather than failing requests. Answers of backends no longer asked for (mapping
+    /// changed or deleted) are dropped at the next lookup.
+    async fn resolve_srv(&self, backend: &str, name: &str) -> Result<Arc<Vec<Endpoint>>> {
+        let now = Instant::now();
+        let stale = match self.services.get_mut(backend) {
+            Some(mut entry) if entry.expires_at.is_some_and(|t| now < t) => {
+                entry.last_used = now;
+                return Ok(entry.endpoints.clone());
+            }
+            Some(entry) => Some(entry.endpoints.clone()),
+            None => None,
+        };
+        self.evict_idle_srv(now);
+
+        let resolver = self.resolver.get_or_try_init(|| match self.srv_nameserver {
+            Some(addr) => {
+                let servers = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                Ok(TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, Vec::new(), servers), ResolverOpts::default()))
+            }
+            None => TokioAsyncResolver::tokio_from_system_conf()
+                .map_err(|e| anyhow!("Failed to load system resolver config: {}", e)),
+        })?;
+
+        let lookup = match resolver.srv_lookup(name).await {
+            Ok(l) => l,
+            Err(e) => {
+                return match stale {
+                    Some(endpoints) => {
+                        warn!("Discovery: SRV lookup for {} failed, serving stale answer: {}", name, e);
+                        Ok(endpoints)
+                    }
+                    None => Err(anyhow!("SRV lookup for {} failed: {}", name, e)),
+                };
+            }
+        };
+
+        let endpoints: Vec<Endpoint> = lookup.iter()
+            // RFC 2782: a target of "." means the service is decidedly not available
+            .filter(|srv| !srv.target().is_root())
+            .map(|srv| Endpoint {
+                host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+                port: srv.port(),
+                priority: srv.priority(),
+                weight: srv.weight(),
+            })
+            .collect();
+        let ttl = lookup.as_lookup().valid_until().saturating_duration_since(Instant::now()).max(SRV_MIN_TTL);
+
+        if stale.as_deref() != Some(&endpoints) {
+            info!("Discovery: {} resolved to {} SRV target(s)", backend, endpoints.len());
+        }
+        let endpoints = Arc::new(endpoints);
+        self.services.insert(backend.to_string(), ServiceEntry {
+            endpoints: endpoints.clone(),
+            last_used: Instant::now(),
+            watch_id: 0,
+            expires_at: Some(Instant::now() + ttl),
+            ttl,
+        });
+        Ok(endpoints)
+    }
+
+    /// Drop the SRV answers no request has used for [`SRV_IDLE_TTLS`] of their TTL.
+    fn evict_idle_srv(&self, now: Instant) {
+        self.services.retain(|backend, entry| {
+            let idle = entry.expires_at.is_some() && now.saturating_duration_since(entry.last_used) > entry.ttl * SRV_IDLE_TTLS;
+            if idle {
+                debug!("Discovery: dropping unused SRV answer for {}", backend);
+            }
+            !idle
+        });
+    }
+
+    fn spawn_watch(this: Arc<Self>, backend: String, source: DiscoverySource, mut cursor: u64, watch_id: u64) {
+        tokio::spawn(async move {
+            loop {
+                let started = Instant::now();
+                match this.services.get(&backend).map(|e| (e.watch_id, e.last_used.elapsed())) {
+                    Some((id, idle)) if id == watch_id && idle < IDLE_TIMEOUT => {}
+                    Some((id, _)) if id == watch_id => {
+                        debug!("Discovery: {} idle, no longer watching", backend);
+                        this.services.remove(&backend);
+                        break;
+                    }
+                    // Invalidated or superseded by a newer watch
+                    _ => break,
+                }
+
+                let changed = match &source {
+                    DiscoverySource::Etcd { .. } => this.etcd_wait_for_change(&source, cursor).await,
+                    _ => Ok(true),
+                };
+
+                let result = match changed {
+                    Ok(true) => this.fetch(&source, cursor).await.map(Some),
+                    Ok(false) => Ok(None),
+                    Err(e) => Err(e),
+                };
+
+                match result {
+                    Ok(Some((endpoints, next))) => {
+                        // Consul resets its index on leader change; start over if it goes backwards
+                        cursor = if next < cursor { 0 } else { next };
+                        if let Some(mut entry) = this.services.get_mut(&backend) {
+                            if entry.watch_id == watch_id && *entry.endpoints != endpoints {
+                                info!("Discovery: {} now has {} instance(s)", backend, endpoints.len());
+                                entry.endpoints = Arc::new(endpoints);
+                            }
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        // Keep serving the last known instances while the registry is unreachable
+                        warn!("Discovery: refreshing {} failed: {}", backend, e);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+
+                let elapsed = started.elapsed();
+                if elapsed < WATCH_MIN_INTERVAL {
+                    tokio::time::sleep(WATCH_MIN_INTERVAL - elapsed).await;
+                }
+            }
+        });
+    }
+
+    /// Query the registry. `cursor` is the Consul index / etcd revision of the previous
+    /// result; for Consul a non-zero cursor turns the request into a blocking query.
+    async fn fetch(&self, source: &DiscoverySource, cursor: u64) -> Result<(Vec<Endpoint>, u64)> {
+        match source {
+            DiscoverySource::Consul { service, tag, dc } => {
+                let mut url = url::Url::parse(&format!("{}/v1/health/service/{}", self.consul_addr, service))
+                    .context("Invalid Consul address")?;
+                {
+                    let mut q = url.query_pairs_mut();
+                    q.append_pair("passing", "true");
+                    if let Some(t) = tag { q.append_pair("tag", t); }
+                    if let Some(d) = dc { q.append_pair("dc", d); }
+                    if cursor > 0 {
+                        q.append_pair("index", &cursor.to_string());
+                        q.append_pair("wait", CONSUL_WAIT);
+                    }
+                }
+                let mut req = self.client.get(url).timeout(Duration::from_secs(70));
+                if let Some(token) = &self.consul_token {
+                    req = req.header("X-Consul-Token", token);
+                }
+                let resp = req.send().await?.error_for_status()?;
+                let index = resp.headers()
+                    .get("x-consul-index")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0);
+                let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await?)?;
+                Ok((parse_consul_health(&body), index))
+            }
+            DiscoverySource::Etcd { prefix } => {
+                let body = serde_json::json!({
+                    "key": general_purpose::STANDARD.encode(prefix),
+                    "range_end": general_purpose::STANDARD.encode(prefix_range_end(prefix.as_bytes())),
+                });
+                let resp = self.client
+                    .post(format!("{}/v3/kv/range", self.etcd_endpoint))
+                    .timeout(Duration::from_secs(10))
+                    .header("Content-Type", "application/json")
+                    .body(body.to_string())
+                    .send().await?
+                    .error_for_status()?;
+                let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await?)?;
+                Ok(parse_etcd_range(&body))
+            }
+            DiscoverySource::Srv { name } => Err(anyhow!("SRV backend {} is not watched", name)),
+        }
+    }
+
+    /// Open an etcd watch on the prefix and wait until it reports an event.
+    /// Returns `Ok(false)` when the watch expired without changes.
+    async fn etcd_wait_for_change(&self, source: &DiscoverySource, revision: u64) -> Result<bool> {
+        let DiscoverySource::Etcd { prefix } = source else {
+            return Ok(true);
+        };
+        let body = serde_json::json!({
+            "create_request": {
+                "key": general_purpose::STANDARD.encode(prefix),
+                "range_end": general_purpose::STANDARD.encode(prefix_range_end(prefix.as_bytes())),
+                "start_revision": revision + 1,
+            }
+        });
+        let mut resp = self.client
+            .post(format!("{}/v3/watch", self.etcd_endpoint))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send().await?
+            .error_for_status()?;
+
+        let deadline = tokio::time::Instant::now() + ETCD_WATCH_TTL;
+        loop {
+            let chunk = match tokio::time::timeout_at(deadline, resp.chunk()).await {
+                Err(_) => return Ok(false),
+                Ok(chunk) => chunk?,
+            };
+            let chunk = chunk.ok_or_else(|| anyhow!("etcd watch stream closed"))?;
+            if String::from_utf8_lossy(&chunk).contains("\"events\"") {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Extract instances from a Consul `/v1/health/service` response.
+/// The service address wins over the node address when both are set.
+fn parse_consul_health(body: &serde_json::Value) -> Vec<Endpoint> {
+    let mut endpoints: Vec<Endpoint> = body.as_array()
+        .map(|entries| entries.iter().filter_map(|entry| {
+            let service = entry.get("Service")?;
+            let port = u16::try_from(service.get("Port")?.as_u64()?).ok()?;
+            let host = service.get("Address").and_then(|a| a.as_str()).filter(|a| !a.is_empty())
+                .or_else(|| entry.get("Node")?.get("Address")?.as_str())?;
+            Some(Endpoint::new(host, port))
+        }).collect())
+        .unwrap_or_default();
+    endpoints.sort_by(|a, b| (&a.host, a.port).cmp(&(&b.host, b.port)));
+    endpoints.dedup();
+    endpoints
+}
+
+/// Extract instances and the store revision from an etcd v3 `/kv/range` response.
+fn parse_etcd_range(body: &serde_json::Value) -> (Vec<Endpoint>, u64) {
+    let revision = body.get("header")
+        .and_then(|h| h.get("revision"))
+        .and_then(|r| r.as_str().map(|s| s.parse().unwrap_or(0)).or_else(|| r.as_u64()))
+        .unwrap_or(0);
+    let mut endpoints: Vec<Endpoint> = body.get("kvs")
+        .and_then(|kvs| kvs.as_array())
+        .map(|kvs| kvs.iter().filter_map(|kv| {
+            let raw = general_purpose::STANDARD.decode(kv.get("value")?.as_str()?).ok()?;
+            parse_etcd_value(&String::from_utf8(raw).ok()?)
+        }).collect())
+        .unwrap_or_default();
+    endpoints.sort_by(|a, b| (&a.host, a.port).cmp(&(&b.host, b.port)));
+    endpoints.dedup();
+    (endpoints, revision)
+}
+
+/// An etcd value is either `host:port`, a URL, or `{"host": ..., "port": ...}`.
+fn parse_etcd_value(value: &str) -> Option<Endpoint> {
+    let value = value.trim();
+    if value.starts_with('{') {
+        let v: serde_json::Value = serde_json::from_str(value).ok()?;
+        let host = v.get("host")?.as_str()?.to_string();
+        let port = u16::try_from(v.get("port")?.as_u64()?).ok()?;
+        return Some(Endpoint::new(host, port));
+    }
+    if value.contains("://") {
+        let url = url::Url::parse(value).ok()?;
+        return Some(Endpoint::new(url.host_str()?, url.port_or_known_default()?));
+    }
+    let (host, port) = value.rsplit_once(':')?;
+    Some(Endpoint::new(host, port.parse().ok()?))
+}
+
+/// etcd prefix queries use `range_end` = prefix with its last byte incremented.
+fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    vec![0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            DiscoverySource::parse("consul://web?tag=v2&dc=eu"),
+            Some(DiscoverySource::Consul { service: "web".into(), tag: Some("v2".into()), dc: Some("eu".into()) })
+        );
+        assert_eq!(
+            DiscoverySource::parse("etcd://services/web"),
+            Some(DiscoverySource::Etcd { prefix: "/services/web".into() })
+        );
+        assert_eq!(
+            DiscoverySource::parse("srv://_http._tcp.web.service.consul"),
+            Some(DiscoverySource::Srv { name: "_http._tcp.web.service.consul".into() })
+        );
+        assert_eq!(DiscoverySource::parse("http://localhost"), None);
+        assert_eq!(DiscoverySource::parse("consul://"), None);
+    }
+
+    #[test]
+    fn test_parse_consul_health() {
+        let body = serde_json::json!([
+            {"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "", "Port": 3000}},
+            {"Node": {"Address": "10.0.0.2"}, "Service": {"Address": "10.1.0.2", "Port": 3001}},
+        ]);
+        assert_eq!(parse_consul_health(&body), vec![
+            Endpoint::new("10.0.0.1", 3000),
+            Endpoint::new("10.1.0.2", 3001),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_etcd_values() {
+        assert_eq!(parse_etcd_value("10.0.0.1:80"), Some(Endpoint::new("10.0.0.1", 80)));
+        assert_eq!(parse_etcd_value("http://web.internal"), Some(Endpoint::new("web.internal", 80)));
+        assert_eq!(parse_etcd_value(r#"{"host":"a","port":9000}"#), Some(Endpoint::new("a", 9000)));
+        assert_eq!(parse_etcd_value("garbage"), None);
+        assert_eq!(prefix_range_end(b"/svc/"), b"/svc0".to_vec());
+    }
+
+    #[test]
+    fn test_weighted_order_respects_priority() {
+        let srv = |host: &str, priority, weight| Endpoint { host: host.into(), port: 80, priority, weight };
+        let targets = vec![srv("backup", 20, 10), srv("a", 10, 60), srv("b", 10, 0)];
+
+        let mut a_first = 0;
+        for _ in 0..200 {
+            let ordered = weighted_order(&targets);
+            assert_eq!(ordered.len(), 3);
+            assert_eq!(ordered[2].host, "backup");
+            if ordered[0].host == "a" { a_first += 1; }
+        }
+        // a has weight 60 vs 0, so it should lead the vast majority of the time
+        assert!(a_first > 150, "a led only {} of 200 orderings", a_first);
+    }
+
+    #[test]
+    fn test_idle_srv_answers_evicted() {
+        let discovery = ServiceDiscovery::new(None, None, None, None);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(1);
+        let entry = |idle: Duration, expires_at: Option<Instant>| ServiceEntry {
+            endpoints: Arc::new(Vec::new()),
+            last_used: now - idle,
+            watch_id: 0,
+            expires_at,
+            ttl,
+        };
+        discovery.services.insert("srv://_a._tcp.old".into(), entry(ttl * 11, Some(now - ttl * 10)));
+        discovery.services.insert("srv://_a._tcp.used".into(), entry(ttl * 2, Some(now - ttl)));
+        // Watched sources stop their own watch when idle
+        discovery.services.insert("consul://web".into(), entry(ttl * 11, None));
+        discovery.evict_idle_srv(now);
+        let mut left: Vec<String> = discovery.services.iter().map(|e| e.key().clone()).collect();
+        left.sort();
+        assert_eq!(left, ["consul://web", "srv://_a._tcp.used"]);
+    }
+}
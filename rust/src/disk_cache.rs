@@ -0,0 +1,480 @@
+//! Disk tier of the response cache
+//! With `RESPONSE_CACHE_DIR`, kept responses are also written to one file each in that
+//! directory, so large static assets survive restarts and need not fit the memory budget.
+//! The index is rebuilt from the files at startup; the least recently used files are
+//! removed past `RESPONSE_CACHE_DISK_MAX_BYTES` or `RESPONSE_CACHE_DISK_MAX_ENTRIES`
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const EXTENSION: &str = "entry";
+
+/// A response as kept on disk
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub mapping_id: String,
+    /// Host, then path and query
+    pub base: String,
+    pub variant: String,
+    pub path: String,
+    /// Request headers the response varies on
+    pub vary: Vec<HeaderName>,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub stored: SystemTime,
+    pub usable_for: Duration,
+}
+
+/// First line of a file; the body follows
+#[derive(Serialize, Deserialize)]
+struct Header {
+    mapping_id: String,
+    base: String,
+    variant: String,
+    path: String,
+    vary: Vec<String>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Seconds since the epoch
+    stored: u64,
+    usable_for: u64,
+}
+
+impl Header {
+    /// `None` when a header value isn't text, which the file format can't hold
+    fn of(record: &Record) -> Option<Self> {
+        let mut headers = Vec::with_capacity(record.headers.len());
+        for (name, value) in &record.headers {
+            headers.push((name.to_string(), value.to_str().ok()?.to_string()));
+        }
+        Some(Self {
+            mapping_id: record.mapping_id.clone(),
+            base: record.base.clone(),
+            variant: record.variant.clone(),
+            path: record.path.clone(),
+            vary: record.vary.iter().map(|n| n.to_string()).collect(),
+            status: record.status.as_u16(),
+            headers,
+            stored: record.stored.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            usable_for: record.usable_for.as_secs(),
+        })
+    }
+
+    fn stored(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.stored)
+    }
+
+    fn expired(&self) -> bool {
+        self.stored().elapsed().unwrap_or_default() > Duration::from_secs(self.usable_for)
+    }
+
+    fn vary(&self) -> Vec<HeaderName> {
+        self.vary.iter().filter_map(|n| HeaderName::from_bytes(n.as_bytes()).ok()).collect()
+    }
+
+    fn into_record(self, body: Bytes) -> Option<Record> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).ok()?, HeaderValue::from_str(value).ok()?);
+        }
+        Some(Record {
+            vary: self.vary(),
+            stored: self.stored(),
+            status: StatusCode::from_u16(self.status).ok()?,
+            usable_for: Duration::from_secs(self.usable_for),
+            headers,
+            body,
+            mapping_id: self.mapping_id,
+            base: self.base,
+            variant: self.variant,
+            path: self.path,
+        })
+    }
+}
+
+/// What the index knows of a file
+struct Indexed {
+    file: PathBuf,
+    mapping_id: String,
+    base: String,
+    variant: String,
+    path: String,
+    status: u16,
+    size: u64,
+    stored: SystemTime,
+    usable_for: Duration,
+    /// Position in the LRU order
+    used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Indexed>,
+    /// Keys least recently used first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: u64,
+    /// Headers the last response for a base key varied on, and how many files it has
+    vary: HashMap<String, (Vec<HeaderName>, usize)>,
+}
+
+impl Index {
+    fn insert(&mut self, key: String, mut entry: Indexed, vary: Vec<HeaderName>) {
+        self.remove(&key);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.lru.insert(self.tick, key.clone());
+        self.bytes += entry.size;
+        let (names, files) = self.vary.entry(entry.base.clone()).or_default();
+        *names = vary;
+        *files += 1;
+        self.entries.insert(key, entry);
+    }
+
+    /// Forget `key`; its file is the caller's to delete.
+    fn remove(&mut self, key: &str) -> Option<Indexed> {
+        let old = self.entries.remove(key)?;
+        self.lru.remove(&old.used);
+        self.bytes -= old.size;
+        if let Some((_, files)) = self.vary.get_mut(&old.base) {
+            *files -= 1;
+            if *files == 0 {
+                self.vary.remove(&old.base);
+            }
+        }
+        Some(old)
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = tick;
+            self.lru.insert(tick, key.to_string());
+        }
+    }
+
+    /// Files to delete to get within the limits, least recently used first.
+    fn evict(&mut self, max_bytes: u64, max_entries: usize) -> Vec<PathBuf> {
+        let mut doomed = Vec::new();
+        while self.bytes > max_bytes || self.entries.len() > max_entries {
+            let Some((_, key)) = self.lru.pop_first() else { break };
+            if let Some(old) = self.remove(&key) {
+                doomed.push(old.file);
+            }
+        }
+        doomed
+    }
+}
+
+/// Outcome of a disk lookup
+pub enum Lookup {
+    Missing,
+    /// Past its window; the file has been removed
+    Expired,
+    Found(Box<Record>),
+}
+
+/// Totals of the disk tier
+#[derive(Debug, Serialize)]
+pub struct DiskStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    pub max_entries: usize,
+}
+
+/// A listed file
+pub struct Listed {
+    pub mapping_id: String,
+    pub base: String,
+    pub variant: String,
+    pub status: u16,
+    pub size: u64,
+    pub age: Duration,
+    pub usable_for: Duration,
+}
+
+/// Response files in one directory, indexed in memory. Cheap to clone; clones share the index.
+#[derive(Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_entries: usize,
+    index: Arc<Mutex<Index>>,
+}
+
+impl DiskCache {
+    /// Open (creating) `dir` and index the responses already in it. Expired or unreadable
+    /// files are removed, as are the least recently written ones past the limits.
+    pub fn open(dir: &Path, max_bytes: u64, max_entries: usize) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let mut found = Vec::new();
+        for item in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+            let path = item?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(EXTENSION) => {}
+                // Left over from a write cut short
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            let meta = fs::metadata(&path)?;
+            match read_header(&path) {
+                Some(header) if !header.expired() => {
+                    found.push((meta.modified().unwrap_or(UNIX_EPOCH), path, meta.len(), header));
+                }
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        found.sort_by_key(|(modified, ..)| *modified);
+
+        let mut index = Index::default();
+        for (_, file, size, header) in found {
+            let key = crate::cache::full_key(&header.base, &header.variant);
+            let vary = header.vary();
+            let stored = header.stored();
+            let entry = Indexed {
+                file,
+                mapping_id: header.mapping_id,
+                base: header.base,
+                variant: header.variant,
+                path: header.path,
+                status: header.status,
+                size,
+                stored,
+                usable_for: Duration::from_secs(header.usable_for),
+                used: 0,
+            };
+            index.insert(key, entry, vary);
+        }
+        for file in index.evict(max_bytes, max_entries) {
+            let _ = fs::remove_file(file);
+        }
+        Ok(Self { dir: dir.to_path_buf(), max_bytes, max_entries, index: Arc::new(Mutex::new(index)) })
+    }
+
+    /// Largest response the tier takes: a sixteenth of its budget.
+    pub fn max_entry_bytes(&self) -> u64 {
+        self.max_bytes / 16
+    }
+
+    /// Headers responses for `base` vary on, when any are on disk.
+    pub fn vary(&self, base: &str) -> Option<Vec<HeaderName>> {
+        self.index.lock().vary.get(base).map(|(names, _)| names.clone())
+    }
+
+    /// Write a response (blocking), replacing an older file for the same key.
+    pub fn write(&self, record: &Record) -> Result<()> {
+        let Some(header) = Header::of(record) else { return Ok(()) };
+        let mut line = serde_json::to_vec(&header)?;
+        line.push(b'\n');
+        let size = (line.len() + record.body.len()) as u64;
+        if size > self.max_entry_bytes() {
+            return Ok(());
+        }
+        let key = crate::cache::full_key(&record.base, &record.variant);
+        let file = self.dir.join(format!("{}.{}", file_name(&key), EXTENSION));
+        // Written aside and renamed so readers never see half a file
+        let tmp = file.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        let mut out = fs::File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+        out.write_all(&line).and_then(|_| out.write_all(&record.body)).and_then(|_| out.sync_data())
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, &file)?;
+
+        let entry = Indexed {
+            file,
+            mapping_id: record.mapping_id.clone(),
+            base: record.base.clone(),
+            variant: record.variant.clone(),
+            path: record.path.clone(),
+            status: record.status.as_u16(),
+            size,
+            stored: record.stored,
+            usable_for: record.usable_for,
+            used: 0,
+        };
+        let doomed = {
+            let mut index = self.index.lock();
+            index.insert(key, entry, record.vary.clone());
+            index.evict(self.max_bytes, self.max_entries)
+        };
+        for file in doomed {
+            let _ = fs::remove_file(file);
+        }
+        Ok(())
+    }
+
+    /// Read the response kept under `key` (blocking).
+    pub fn get(&self, key: &str) -> Lookup {
+        let file = {
+            let mut index = self.index.lock();
+            let Some(entry) = index.entries.get(key) else { return Lookup::Missing };
+            if entry.stored.elapsed().unwrap_or_default() > entry.usable_for {
+                let old = index.remove(key).expect("indexed");
+                drop(index);
+                let _ = fs::remove_file(old.file);
+                return Lookup::Expired;
+            }
+            let file = entry.file.clone();
+            index.touch(key);
+            file
+        };
+        match read_record(&file) {
+            Some(record) => Lookup::Found(Box::new(record)),
+            None => {
+                // Removed or damaged behind our back
+                self.index.lock().remove(key);
+                Lookup::Missing
+            }
+        }
+    }
+
+    /// Remove the files of responses whose path starts with `prefix` (all without one);
+    /// returns their keys.
+    pub fn purge(&self, prefix: Option<&str>) -> Vec<String> {
+        let mut index = self.index.lock();
+        let doomed: Vec<String> = index.entries.iter()
+            .filter(|(_, e)| prefix.is_none_or(|p| e.path.starts_with(p)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in &doomed {
+            if let Some(old) = index.remove(key) {
+                let _ = fs::remove_file(old.file);
+            }
+        }
+        doomed
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        let index = self.index.lock();
+        DiskStats { entries: index.entries.len(), bytes: index.bytes, max_bytes: self.max_bytes, max_entries: self.max_entries }
+    }
+
+    pub fn list(&self) -> Vec<Listed> {
+        let index = self.index.lock();
+        index.entries.values().map(|e| Listed {
+            mapping_id: e.mapping_id.clone(),
+            base: e.base.clone(),
+            variant: e.variant.clone(),
+            status: e.status,
+            size: e.size,
+            age: e.stored.elapsed().unwrap_or_default(),
+            usable_for: e.usable_for,
+        }).collect()
+    }
+}
+
+/// File name of a key: 128 bits of its SHA-256 in hex.
+fn file_name(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    digest.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_header(path: &Path) -> Option<Header> {
+    let mut line = Vec::new();
+    BufReader::new(fs::File::open(path).ok()?).read_until(b'\n', &mut line).ok()?;
+    serde_json::from_slice(&line).ok()
+}
+
+fn read_record(path: &Path) -> Option<Record> {
+    let mut reader = BufReader::new(fs::File::open(path).ok()?);
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line).ok()?;
+    let header: Header = serde_json::from_slice(&line).ok()?;
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body).ok()?;
+    header.into_record(Bytes::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str, variant: &str, body: impl Into<Bytes>) -> Record {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/css"));
+        Record {
+            mapping_id: "m1".to_string(),
+            base: format!("a.test{}", path),
+            variant: variant.to_string(),
+            path: path.to_string(),
+            vary: vec![HeaderName::from_static("accept-encoding")],
+            status: StatusCode::OK,
+            headers,
+            body: body.into(),
+            stored: SystemTime::now(),
+            usable_for: Duration::from_secs(60),
+        }
+    }
+
+    fn found(disk: &DiskCache, key: &str) -> Option<Box<Record>> {
+        match disk.get(key) {
+            Lookup::Found(record) => Some(record),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_write_reopen_and_evict() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = DiskCache::open(dir.path(), 1 << 20, 3).unwrap();
+        disk.write(&record("/a.css", "accept-encoding=gzip", "body { }")).unwrap();
+        disk.write(&record("/b.css", "accept-encoding=", "p { }")).unwrap();
+        let mut expired = record("/old.css", "", "x");
+        expired.stored = SystemTime::now() - Duration::from_secs(120);
+        disk.write(&expired).unwrap();
+        assert_eq!(disk.vary("a.test/a.css").unwrap(), vec![HeaderName::from_static("accept-encoding")]);
+
+        // Survives a restart, minus what expired
+        drop(disk);
+        let disk = DiskCache::open(dir.path(), 1 << 20, 3).unwrap();
+        assert_eq!(disk.stats().entries, 2);
+        let a = found(&disk, "a.test/a.css\naccept-encoding=gzip").unwrap();
+        assert_eq!((a.body, a.path.as_str(), &a.headers["content-type"]), (Bytes::from("body { }"), "/a.css", &HeaderValue::from_static("text/css")));
+        assert!(matches!(disk.get("a.test/old.css"), Lookup::Missing));
+
+        // Over the entry count: the least recently used goes
+        disk.write(&record("/c.css", "", "c")).unwrap();
+        disk.write(&record("/d.css", "", "d")).unwrap();
+        assert!(found(&disk, "a.test/b.css\naccept-encoding=").is_none());
+        assert!(found(&disk, "a.test/a.css\naccept-encoding=gzip").is_some());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        assert_eq!(disk.purge(Some("/a")).len(), 1);
+        assert_eq!(disk.purge(None).len(), 2);
+        assert_eq!((disk.stats().entries, disk.stats().bytes), (0, 0));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = DiskCache::open(dir.path(), 16 * 400, 100).unwrap();
+        disk.write(&record("/big.css", "", "x".repeat(500))).unwrap();
+        assert_eq!(disk.stats().entries, 0, "over a sixteenth of the budget");
+        for i in 0..40 {
+            disk.write(&record(&format!("/{}.css", i), "", "small")).unwrap();
+        }
+        let stats = disk.stats();
+        assert!(stats.bytes <= stats.max_bytes && stats.entries < 40, "{:?}", stats);
+        assert!(found(&disk, "a.test/39.css").is_some());
+        assert!(found(&disk, "a.test/0.css").is_none());
+    }
+}
@@ -0,0 +1,103 @@
+//! Domain control checks
+//! With `REQUIRE_DOMAIN_VERIFICATION`, a mapping owned by a tenant only receives traffic
+//! once the tenant has proven control of its domain, so one tenant of a shared proxy can't
+//! route someone else's hostname to itself. Proof is a token (see
+//! `rustproxy-mapping verify`) published either as a DNS TXT record at
+//! `_rustproxy-challenge.<domain>` — as with ACME dns-01, a wildcard is proven on its base
+//! domain — or served the ACME http-01 way at `/.well-known/acme-challenge/<token>` by the
+//! server the domain points at. This proxy never answers that path from a backend, so the
+//! HTTP check only passes for a domain not (yet) pointed here.
+
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_resolver::TokioAsyncResolver;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Label the TXT record is published under
+pub const TXT_LABEL: &str = "_rustproxy-challenge";
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How control is proven
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Dns,
+    Http,
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "dns" => Ok(Self::Dns),
+            "http" => Ok(Self::Http),
+            other => Err(format!("unknown verification method '{}' (dns, http)", other)),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dns => "dns",
+            Self::Http => "http",
+        })
+    }
+}
+
+/// Name of the TXT record proving control of `domain`
+pub fn txt_name(domain: &str) -> String {
+    format!("{}.{}", TXT_LABEL, domain.trim_start_matches("*."))
+}
+
+/// Where the HTTP check fetches the token; `None` for wildcards, which have no single host.
+pub fn http_url(domain: &str, token: &str) -> Option<String> {
+    (!domain.starts_with("*.")).then(|| format!("http://{}/.well-known/acme-challenge/{}", domain, token))
+}
+
+/// Check that `token` is published for `domain` the `method` way.
+pub async fn check(domain: &str, token: &str, method: Method) -> Result<()> {
+    match method {
+        Method::Dns => {
+            let name = txt_name(domain);
+            let resolver = TokioAsyncResolver::tokio_from_system_conf()
+                .map_err(|e| anyhow!("Failed to load system resolver config: {}", e))?;
+            let lookup = resolver.txt_lookup(name.as_str()).await.map_err(|e| anyhow!("TXT lookup for {} failed: {}", name, e))?;
+            let published = lookup.iter()
+                .map(|txt| txt.iter().map(|part| String::from_utf8_lossy(part)).collect::<String>())
+                .any(|value| value.trim() == token);
+            if !published {
+                bail!("no TXT record {} with the token", name);
+            }
+        }
+        Method::Http => {
+            let url = http_url(domain, token).ok_or_else(|| anyhow!("a wildcard domain can only be verified by DNS"))?;
+            let http = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+            let resp = http.get(&url).send().await.with_context(|| format!("fetching {}", url))?;
+            if !resp.status().is_success() {
+                bail!("{} answered {}", url, resp.status());
+            }
+            if resp.text().await?.trim() != token {
+                bail!("{} did not return the token", url);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_where_tokens_go() {
+        assert_eq!(txt_name("shop.acme.com"), "_rustproxy-challenge.shop.acme.com");
+        assert_eq!(txt_name("*.acme.com"), "_rustproxy-challenge.acme.com");
+        assert_eq!(http_url("shop.acme.com", "t0k").as_deref(), Some("http://shop.acme.com/.well-known/acme-challenge/t0k"));
+        assert_eq!(http_url("*.acme.com", "t0k"), None);
+        assert_eq!("DNS".parse::<Method>(), Ok(Method::Dns));
+        assert!("email".parse::<Method>().is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Connection draining
+//! Tracks requests and WebSocket sessions per route so that, when a mapping is deleted or
+//! re-pointed, they may finish against the old target for a grace period before being cut
+
+use crate::database::{DatabaseManager, Mapping};
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::info;
+
+/// The route a session was admitted on: mapping ID plus the target it resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub mapping_id: String,
+    fingerprint: String,
+}
+
+impl Route {
+    /// Route of a mapping as stored (active slot applied, before per-request templates
+    /// or experiment variants).
+    pub fn of(mapping: &Mapping) -> Self {
+        Self {
+            mapping_id: mapping.id.clone(),
+            fingerprint: Self::fingerprint(mapping),
+        }
+    }
+
+    fn fingerprint(m: &Mapping) -> String {
+        format!("{:?}|{}|{:?}|{}", m.backend, m.back_port, m.back_ports, m.back_uri)
+    }
+}
+
+struct Session {
+    route: Route,
+    /// Set once the route went away; the session is cut at `since + drain_timeout`
+    draining_since: Option<Instant>,
+    cancel: watch::Sender<bool>,
+}
+
+/// Live sessions, keyed by a per-process counter
+pub struct DrainTracker {
+    sessions: DashMap<u64, Session>,
+    next_id: AtomicU64,
+    drain_timeout: Duration,
+}
+
+/// Keeps a session registered; dropping it ends the session.
+pub struct SessionGuard {
+    id: u64,
+    tracker: Arc<DrainTracker>,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl SessionGuard {
+    /// Resolves once the session has to stop (its drain period ran out).
+    pub async fn cancelled(&mut self) {
+        let _ = self.cancelled.wait_for(|c| *c).await;
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.tracker.sessions.remove(&self.id);
+    }
+}
+
+impl DrainTracker {
+    pub fn new(drain_timeout: Duration) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            next_id: AtomicU64::new(0),
+            drain_timeout,
+        }
+    }
+
+    /// Register a session on `route`.
+    pub fn register(self: &Arc<Self>, route: Route) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (cancel, cancelled) = watch::channel(false);
+        self.sessions.insert(id, Session { route, draining_since: None, cancel });
+        SessionGuard { id, tracker: self.clone(), cancelled }
+    }
+
+    /// Number of live sessions and how many of them are draining.
+    pub fn counts(&self) -> (usize, usize) {
+        let draining = self.sessions.iter().filter(|s| s.draining_since.is_some()).count();
+        (self.sessions.len(), draining)
+    }
+
+    /// Compare live sessions against the current mappings: start draining sessions whose
+    /// route is gone or changed, and cut those whose drain period is over.
+    pub fn sweep(&self, db: &DatabaseManager) {
+        if self.sessions.is_empty() {
+            return;
+        }
+        let mut ids: Vec<String> = self.sessions.iter().map(|s| s.route.mapping_id.clone()).collect();
+        ids.sort();
+        ids.dedup();
+
+        let mut current = std::collections::HashMap::new();
+        for id in ids {
+            // A lookup error is not a removal; try again next sweep
+            let Ok(found) = db.get_mapping_by_id(&id) else { continue };
+            let route = found.map(|mut m| {
+                m.apply_active_slot();
+                Route::of(&m)
+            });
+            current.insert(id, route);
+        }
+        self.apply(&current, Instant::now());
+    }
+
+    fn apply(&self, current: &std::collections::HashMap<String, Option<Route>>, now: Instant) {
+        for mut s in self.sessions.iter_mut() {
+            let Some(live) = current.get(&s.route.mapping_id) else { continue };
+            let stale = live.as_ref() != Some(&s.route);
+            match s.draining_since {
+                None if stale => {
+                    info!("Draining session on mapping {} (route removed or changed)", s.route.mapping_id);
+                    s.draining_since = Some(now);
+                }
+                // The route came back (e.g. a switch was undone)
+                Some(_) if !stale => s.draining_since = None,
+                Some(since) if now.duration_since(since) >= self.drain_timeout => {
+                    let _ = s.cancel.send(true);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn mapping(port: u16) -> Mapping {
+        Mapping { id: "m1".to_string(), back_port: port, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_changed_route_drains_then_cancels() {
+        let tracker = Arc::new(DrainTracker::new(Duration::from_secs(10)));
+        let mut guard = tracker.register(Route::of(&mapping(3000)));
+        let t0 = Instant::now();
+
+        // Unchanged: nothing happens
+        tracker.apply(&HashMap::from([("m1".to_string(), Some(Route::of(&mapping(3000))))]), t0);
+        assert_eq!(tracker.counts(), (1, 0));
+
+        // Re-pointed: draining, but not cut yet
+        let changed = HashMap::from([("m1".to_string(), Some(Route::of(&mapping(3001))))]);
+        tracker.apply(&changed, t0);
+        assert_eq!(tracker.counts(), (1, 1));
+        tracker.apply(&changed, t0 + Duration::from_secs(5));
+        assert!(!*guard.cancelled.borrow());
+
+        // Drain period over: cut
+        tracker.apply(&changed, t0 + Duration::from_secs(10));
+        tokio::time::timeout(Duration::from_secs(1), guard.cancelled()).await.unwrap();
+
+        drop(guard);
+        assert_eq!(tracker.counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_deleted_route_drains_and_restored_route_recovers() {
+        let tracker = Arc::new(DrainTracker::new(Duration::from_secs(10)));
+        let _guard = tracker.register(Route::of(&mapping(3000)));
+        let now = Instant::now();
+
+        tracker.apply(&HashMap::from([("m1".to_string(), None)]), now);
+        assert_eq!(tracker.counts(), (1, 1));
+        tracker.apply(&HashMap::from([("m1".to_string(), Some(Route::of(&mapping(3000))))]), now);
+        assert_eq!(tracker.counts(), (1, 0));
+    }
+}
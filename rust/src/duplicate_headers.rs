@@ -0,0 +1,140 @@
+//! Duplicate critical headers
+//! Two parsers that disagree on which `Host`, `Content-Length` or `Transfer-Encoding` of
+//! a request counts are how requests get smuggled past or routed around the proxy in
+//! front of them. Rather than going with whichever copy hyper kept, requests repeating
+//! these headers are settled here before routing: copies that agree are collapsed into
+//! one, copies that conflict are refused with `400`. With `DUPLICATE_HEADERS=strict` any
+//! repetition is refused, as is a `Host` header naming another host than an absolute-form
+//! request target
+
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use hyper::Uri;
+use std::str::FromStr;
+
+/// How repeated critical headers are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateHeaders {
+    /// Identical copies become one; conflicting ones are refused
+    #[default]
+    Collapse,
+    /// Any repetition or ambiguity is refused
+    Strict,
+}
+
+impl FromStr for DuplicateHeaders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "collapse" => Ok(Self::Collapse),
+            "strict" => Ok(Self::Strict),
+            other => Err(format!("unknown duplicate header handling '{}' (collapse, strict)", other)),
+        }
+    }
+}
+
+/// Settle repeated `Host`, `Content-Length` and `Transfer-Encoding` headers in place, or
+/// say why the request must be refused.
+pub fn settle(headers: &mut HeaderMap, uri: &Uri, policy: DuplicateHeaders) -> Result<(), &'static str> {
+    let strict = policy == DuplicateHeaders::Strict;
+
+    let hosts: Vec<&HeaderValue> = headers.get_all(HOST).iter().collect();
+    if hosts.len() > 1 {
+        if strict {
+            return Err("multiple Host headers");
+        }
+        if hosts.iter().any(|h| !h.as_bytes().eq_ignore_ascii_case(hosts[0].as_bytes())) {
+            return Err("conflicting Host headers");
+        }
+        let host = hosts[0].clone();
+        headers.insert(HOST, host);
+    }
+    if let (true, Some(authority), Some(host)) = (strict, uri.authority(), headers.get(HOST)) {
+        if !host.as_bytes().eq_ignore_ascii_case(authority.as_str().as_bytes()) {
+            return Err("Host header differs from the request target");
+        }
+    }
+
+    let fields = headers.get_all(CONTENT_LENGTH).iter().count();
+    let lengths: Vec<&str> = match headers.get_all(CONTENT_LENGTH).iter().map(|v| v.to_str()).collect::<Result<Vec<_>, _>>() {
+        Ok(values) => values.into_iter().flat_map(|v| v.split(',')).map(str::trim).collect(),
+        Err(_) => return Err("invalid Content-Length"),
+    };
+    if lengths.iter().any(|l| l.is_empty() || !l.bytes().all(|b| b.is_ascii_digit())) {
+        return Err("invalid Content-Length");
+    }
+    if lengths.iter().any(|l| l.trim_start_matches('0') != lengths[0].trim_start_matches('0')) {
+        return Err("conflicting Content-Length headers");
+    }
+    if lengths.len() > 1 || fields > 1 {
+        if strict {
+            return Err("multiple Content-Length headers");
+        }
+        let length = HeaderValue::from_str(lengths[0]).map_err(|_| "invalid Content-Length")?;
+        headers.insert(CONTENT_LENGTH, length);
+    }
+
+    let fields = headers.get_all(TRANSFER_ENCODING).iter().count();
+    if fields > 0 {
+        let codings: Vec<String> = match headers.get_all(TRANSFER_ENCODING).iter().map(|v| v.to_str()).collect::<Result<Vec<_>, _>>() {
+            Ok(values) => values.into_iter().flat_map(|v| v.split(',')).map(|c| c.trim().to_ascii_lowercase()).collect(),
+            Err(_) => return Err("invalid Transfer-Encoding"),
+        };
+        // A request body is chunked exactly once, last (RFC 9112 §6.1)
+        let chunked = codings.iter().filter(|c| *c == "chunked").count();
+        if chunked != 1 || codings.last().map(String::as_str) != Some("chunked") || codings.iter().any(String::is_empty) {
+            return Err("conflicting Transfer-Encoding");
+        }
+        if fields > 1 {
+            if strict {
+                return Err("multiple Transfer-Encoding headers");
+            }
+            let joined = HeaderValue::from_str(&codings.join(", ")).map_err(|_| "invalid Transfer-Encoding")?;
+            headers.insert(TRANSFER_ENCODING, joined);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_settle() {
+        let origin: Uri = "/a".parse().unwrap();
+        let check = |pairs: &[(&str, &str)], uri: &Uri, policy| {
+            let mut h = headers(pairs);
+            settle(&mut h, uri, policy).map(|()| h)
+        };
+        use DuplicateHeaders::{Collapse, Strict};
+
+        let h = check(&[("host", "a.test"), ("host", "A.test"), ("content-length", "5, 5")], &origin, Collapse).unwrap();
+        assert_eq!((h.get_all(HOST).iter().count(), &h[CONTENT_LENGTH]), (1, &HeaderValue::from_static("5")));
+        let h = check(&[("transfer-encoding", "gzip"), ("transfer-encoding", "chunked")], &origin, Collapse).unwrap();
+        assert_eq!(h[TRANSFER_ENCODING], "gzip, chunked");
+
+        assert_eq!(check(&[("host", "a.test"), ("host", "b.test")], &origin, Collapse).unwrap_err(), "conflicting Host headers");
+        assert_eq!(check(&[("content-length", "5"), ("content-length", "6")], &origin, Collapse).unwrap_err(), "conflicting Content-Length headers");
+        assert_eq!(check(&[("content-length", "+5")], &origin, Collapse).unwrap_err(), "invalid Content-Length");
+        for te in ["chunked, chunked", "chunked, gzip", "identity"] {
+            assert_eq!(check(&[("transfer-encoding", te)], &origin, Collapse).unwrap_err(), "conflicting Transfer-Encoding");
+        }
+
+        assert!(check(&[("host", "a.test"), ("host", "a.test")], &origin, Strict).is_err());
+        assert!(check(&[("content-length", "5, 5")], &origin, Strict).is_err());
+        let absolute: Uri = "http://a.test/a".parse().unwrap();
+        assert!(check(&[("host", "a.test")], &absolute, Strict).is_ok());
+        assert_eq!(check(&[("host", "b.test")], &absolute, Strict).unwrap_err(), "Host header differs from the request target");
+        assert!(check(&[("host", "b.test")], &absolute, Collapse).is_ok());
+        assert_eq!("STRICT".parse::<DuplicateHeaders>(), Ok(Strict));
+    }
+}
@@ -0,0 +1,248 @@
+//! Early Hints
+//! Slow backends keep browsers waiting for the HTML before they can fetch its CSS and
+//! scripts. A mapping's `early_hints` lists `Link` values (`</app.css>; rel=preload;
+//! as=style, </app.js>; rel=preload; as=script`) that page loads get at once in a `103
+//! Early Hints` response (RFC 8297), before the backend is even asked, and again on the
+//! final response. With `forward` in the list, 103s the backend sends itself are relayed
+//! to the client too. Hints go to HTTP/1.1 clients only, and not to requests with
+//! `Expect`, whose `100 Continue` they could cross
+
+use hyper::header::{HeaderValue, ACCEPT, EXPECT, LINK};
+use hyper::{HeaderMap, Method, Request, StatusCode, Version};
+use parking_lot::Mutex;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+/// A mapping's hints
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EarlyHints {
+    /// `Link` values sent for page loads
+    pub links: Vec<String>,
+    /// Relay the backend's own 103s
+    pub forward: bool,
+}
+
+/// `s` split at commas outside `<...>` and quoted strings, as `Link` lists are.
+fn split_list(s: &str) -> Vec<&str> {
+    let (mut items, mut start, mut in_uri, mut in_quotes) = (Vec::new(), 0, false, false);
+    for (i, c) in s.char_indices() {
+        match c {
+            '<' if !in_quotes => in_uri = true,
+            '>' if !in_quotes => in_uri = false,
+            '"' if !in_uri => in_quotes = !in_quotes,
+            ',' if !in_uri && !in_quotes => {
+                items.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(s[start..].trim());
+    items.into_iter().filter(|item| !item.is_empty()).collect()
+}
+
+impl FromStr for EarlyHints {
+    type Err = String;
+
+    /// `Link` values like `</app.css>; rel=preload; as=style`, and/or `forward`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hints = Self::default();
+        for item in split_list(s) {
+            if item.eq_ignore_ascii_case("forward") {
+                hints.forward = true;
+            } else if item.starts_with('<') && item.contains('>') && HeaderValue::from_str(item).is_ok() {
+                hints.links.push(item.to_string());
+            } else {
+                return Err(format!("expected a Link value like '</app.css>; rel=preload; as=style' or 'forward', got '{}'", item));
+            }
+        }
+        if hints.links.is_empty() && !hints.forward {
+            return Err("early hints need Link values and/or 'forward'".to_string());
+        }
+        Ok(hints)
+    }
+}
+
+impl fmt::Display for EarlyHints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let forward = self.forward.then_some("forward");
+        let items: Vec<&str> = forward.into_iter().chain(self.links.iter().map(String::as_str)).collect();
+        f.write_str(&items.join(", "))
+    }
+}
+
+/// Whether a request is a page load, which the mapping's own hints are for.
+pub fn is_navigation(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::GET
+        && headers.get_all(ACCEPT).iter().any(|v| v.to_str().is_ok_and(|a| a.contains("text/html")))
+}
+
+/// A `103 Early Hints` response with `links`.
+pub fn response(links: &[HeaderValue]) -> Vec<u8> {
+    let mut out = b"HTTP/1.1 103 Early Hints\r\n".to_vec();
+    for link in links {
+        out.extend_from_slice(b"link: ");
+        out.extend_from_slice(link.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// A client connection that 103 responses can be written to while hyper waits for the
+/// response of its current request. hyper writes nothing then: HTTP/1 reads the next
+/// request only once the previous response is out.
+pub struct HintsIo<S> {
+    inner: Arc<Mutex<S>>,
+}
+
+/// Where the 103s for a request's client go (a request extension)
+#[derive(Clone)]
+pub struct Writer(Arc<Mutex<dyn AsyncWrite + Send + Unpin>>);
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static> HintsIo<S> {
+    pub fn new(stream: S) -> (Self, Writer) {
+        let inner = Arc::new(Mutex::new(stream));
+        (Self { inner: inner.clone() }, Writer(inner))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HintsIo<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner.lock()).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HintsIo<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner.lock()).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.lock().is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner.lock()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner.lock()).poll_shutdown(cx)
+    }
+}
+
+impl Writer {
+    /// Write a 103 with `links`.
+    pub async fn send(&self, links: &[HeaderValue]) -> io::Result<()> {
+        let bytes = response(links);
+        let mut written = 0;
+        while written < bytes.len() {
+            let n = std::future::poll_fn(|cx| Pin::new(&mut *self.0.lock()).poll_write(cx, &bytes[written..])).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            written += n;
+        }
+        std::future::poll_fn(|cx| Pin::new(&mut *self.0.lock()).poll_flush(cx)).await
+    }
+}
+
+/// `Link` values of the backend's 103s, from its connection to the request (an extension
+/// of the request to the backend)
+#[derive(Clone)]
+pub struct Relay(mpsc::UnboundedSender<Vec<HeaderValue>>);
+
+impl Relay {
+    /// Have the 103s of the backend's answer to `req` passed on.
+    pub fn listen<B>(&self, req: &mut Request<B>) {
+        let tx = self.0.clone();
+        hyper::ext::on_informational(req, move |res| {
+            if res.status() == StatusCode::EARLY_HINTS {
+                let _ = tx.send(res.headers().get_all(LINK).iter().cloned().collect());
+            }
+        });
+    }
+}
+
+/// The hints of one request
+pub struct Session {
+    writer: Writer,
+    links: Vec<HeaderValue>,
+    relayed: Option<mpsc::UnboundedReceiver<Vec<HeaderValue>>>,
+}
+
+impl Session {
+    /// Hints to give while `req` is forwarded, if its client takes them. `links` are sent
+    /// first; with `hints.forward`, the backend's 103s follow.
+    pub fn start<B>(hints: &EarlyHints, links: Vec<HeaderValue>, req: &mut Request<B>) -> Option<Self> {
+        let writer = req.extensions().get::<Writer>()?.clone();
+        if req.version() != Version::HTTP_11 || req.headers().contains_key(EXPECT) || (links.is_empty() && !hints.forward) {
+            return None;
+        }
+        let relayed = hints.forward.then(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            req.extensions_mut().insert(Relay(tx));
+            rx
+        });
+        Some(Self { writer, links, relayed })
+    }
+
+    /// Run `forward`, sending the hints meanwhile. A client that can't be written to
+    /// doesn't get further hints; the response is still waited for.
+    pub async fn run<F: Future>(self, forward: F) -> F::Output {
+        let Session { writer, mut links, relayed } = self;
+        let mut writable = links.is_empty() || writer.send(&links).await.is_ok();
+        let Some(mut relayed) = relayed else {
+            return forward.await;
+        };
+        tokio::pin!(forward);
+        loop {
+            tokio::select! {
+                out = &mut forward => return out,
+                Some(backend) = relayed.recv() => {
+                    let new: Vec<HeaderValue> = backend.into_iter().filter(|l| !links.contains(l)).collect();
+                    if writable && !new.is_empty() {
+                        writable = writer.send(&new).await.is_ok();
+                        links.extend(new);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_early_hints() {
+        let hints: EarlyHints = "</app.css>; rel=preload; as=style, </a,b.js>; rel=preload; as=script, forward".parse().unwrap();
+        assert_eq!(hints.links, ["</app.css>; rel=preload; as=style", "</a,b.js>; rel=preload; as=script"]);
+        assert!(hints.forward);
+        assert_eq!(hints.to_string(), "forward, </app.css>; rel=preload; as=style, </a,b.js>; rel=preload; as=script");
+        assert_eq!(hints.to_string().parse::<EarlyHints>().unwrap(), hints);
+        assert_eq!("forward".parse::<EarlyHints>().unwrap(), EarlyHints { links: vec![], forward: true });
+        assert!("app.css".parse::<EarlyHints>().is_err());
+        assert!("".parse::<EarlyHints>().is_err());
+
+        let links = [HeaderValue::from_static("</app.css>; rel=preload; as=style")];
+        assert_eq!(response(&links), b"HTTP/1.1 103 Early Hints\r\nlink: </app.css>; rel=preload; as=style\r\n\r\n");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/html,*/*;q=0.8"));
+        assert!(is_navigation(&Method::GET, &headers) && !is_navigation(&Method::POST, &headers));
+        assert!(!is_navigation(&Method::GET, &HeaderMap::new()));
+    }
+}
@@ -0,0 +1,139 @@
+//! Echo server
+//! `rustproxy echo --port 9000` answers every request with what it received — method, path,
+//! query, HTTP version, headers in the order they came and body — as JSON. Pointing a mapping
+//! at it shows what the proxy makes of a request (rewritten paths, `X-Forwarded-*`, signature
+//! and identity headers) without writing a backend for the purpose. Bodies that aren't UTF-8
+//! come back base64-encoded; bodies over 1 MiB are answered 413
+
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Largest body echoed
+pub const MAX_BODY: usize = 1 << 20;
+
+/// A request as received
+#[derive(Debug, Clone, Serialize)]
+pub struct Echo {
+    /// `--name` of the echo server, to tell several apart behind one mapping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub remote: SocketAddr,
+    pub method: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    pub version: String,
+    /// `name: value`, repeated headers included
+    pub headers: Vec<String>,
+    pub body_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+impl Echo {
+    pub fn new(parts: &hyper::http::request::Parts, body: &[u8], remote: SocketAddr, name: Option<&str>) -> Self {
+        let text = std::str::from_utf8(body).ok();
+        Self {
+            name: name.map(String::from),
+            remote,
+            method: parts.method.to_string(),
+            path: parts.uri.path().to_string(),
+            query: parts.uri.query().map(String::from),
+            version: format!("{:?}", parts.version),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(k, v)| format!("{}: {}", k, String::from_utf8_lossy(v.as_bytes())))
+                .collect(),
+            body_bytes: body.len(),
+            body: text.filter(|t| !t.is_empty()).map(String::from),
+            body_base64: match text {
+                Some(_) => None,
+                None => Some(general_purpose::STANDARD.encode(body)),
+            },
+        }
+    }
+}
+
+async fn handle(req: Request<Incoming>, remote: SocketAddr, name: Option<Arc<str>>) -> Response<Full<Bytes>> {
+    let (parts, body) = req.into_parts();
+    let body = match Limited::new(body, MAX_BODY).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Full::new(Bytes::from(format!("echo: body over {} bytes\n", MAX_BODY))))
+                .unwrap()
+        }
+    };
+    let echo = Echo::new(&parts, &body, remote, name.as_deref());
+    let mut json = serde_json::to_vec_pretty(&echo).unwrap_or_default();
+    json.push(b'\n');
+    Response::builder()
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(Full::new(Bytes::from(json)))
+        .unwrap()
+}
+
+/// Answer connections on `listener` with echoes, until the task is dropped.
+pub async fn serve(listener: TcpListener, name: Option<String>) {
+    let name: Option<Arc<str>> = name.map(Arc::from);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Echo accept() failed: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let name = name.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let name = name.clone();
+                async move { Ok::<_, Infallible>(handle(req, remote, name).await) }
+            });
+            let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_echo() {
+        let req = Request::post("/api/v1/items?debug=1")
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("accept", "text/html")
+            .header("accept", "application/json")
+            .body(())
+            .unwrap();
+        let (parts, _) = req.into_parts();
+        let remote: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let echo = serde_json::to_value(Echo::new(&parts, b"{\"a\":1}", remote, Some("blue"))).unwrap();
+        assert_eq!(echo["name"], "blue");
+        assert_eq!((echo["method"].as_str(), echo["path"].as_str(), echo["query"].as_str()), (Some("POST"), Some("/api/v1/items"), Some("debug=1")));
+        assert_eq!(echo["headers"], json!(["x-forwarded-for: 203.0.113.7", "accept: text/html", "accept: application/json"]));
+        assert_eq!((echo["body"].as_str(), echo["body_bytes"].as_u64()), (Some("{\"a\":1}"), Some(7)));
+
+        let binary = serde_json::to_value(Echo::new(&parts, &[0xff, 0x00], remote, None)).unwrap();
+        assert_eq!((binary.get("name"), binary.get("body"), binary["body_base64"].as_str()), (None, None, Some("/wA=")));
+    }
+}
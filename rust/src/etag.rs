@@ -0,0 +1,120 @@
+//! Generated ETags
+//! With `GENERATE_ETAGS`, successful GET responses that carry no validator (`ETag` or
+//! `Last-Modified`) get a weak ETag hashed from their body, and requests whose
+//! `If-None-Match` lists it are answered `304 Not Modified` by the proxy — conditional
+//! requests for legacy backends that never learnt them
+
+use crate::body_rewrite::read_bounded;
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Response, StatusCode};
+
+/// Bodies bigger than this are passed on without an ETag
+pub const MAX_BYTES: usize = 8 * 1024 * 1024;
+
+/// Headers a 304 repeats from the response it stands for (RFC 9110 §15.4.5)
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 5] =
+    [header::CACHE_CONTROL, header::CONTENT_LOCATION, header::DATE, header::EXPIRES, header::VARY];
+
+/// Whether a response should get a generated ETag.
+pub fn wanted(status: StatusCode, headers: &HeaderMap) -> bool {
+    let no_store = headers.get_all(header::CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|d| d.trim().eq_ignore_ascii_case("no-store")));
+    status == StatusCode::OK
+        && !headers.contains_key(header::ETAG)
+        && !headers.contains_key(header::LAST_MODIFIED)
+        && !no_store
+}
+
+/// Weak ETag for `body`: 128 bits of its SHA-256.
+pub fn compute(body: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, body);
+    let tag = base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, &digest.as_ref()[..16]);
+    format!("W/\"{}\"", tag)
+}
+
+/// Whether an `If-None-Match` value lists `etag`, compared weakly (`W/` ignored).
+pub fn matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|t| opaque(t) == opaque(etag))
+}
+
+/// Add a generated ETag to `resp` when it has no validator, and turn it into a 304 when
+/// the request's `If-None-Match` lists it.
+pub async fn apply(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    if_none_match: Option<&str>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if !wanted(resp.status(), resp.headers()) {
+        return resp;
+    }
+    let declared = resp.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|n| n > MAX_BYTES) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let body = match read_bounded(body, MAX_BYTES, declared.unwrap_or(0)).await {
+        Ok(body) => body,
+        Err(body) => return Response::from_parts(parts, body),
+    };
+    let etag = compute(&body);
+    let value = HeaderValue::from_str(&etag).expect("base64 is a valid header value");
+
+    if if_none_match.is_some_and(|inm| matches(inm, &etag)) {
+        let mut not_modified = Response::builder().status(StatusCode::NOT_MODIFIED).header(header::ETAG, value);
+        for name in &NOT_MODIFIED_HEADERS {
+            for v in parts.headers.get_all(name) {
+                not_modified = not_modified.header(name, v);
+            }
+        }
+        return not_modified.body(Empty::new().map_err(|never| match never {}).boxed()).expect("valid 304");
+    }
+    parts.headers.insert(header::ETAG, value);
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Response::from_parts(parts, Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)], body: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut builder = Response::builder();
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+        builder.body(Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_generate_and_match() {
+        let resp = apply(response(&[("cache-control", "max-age=60")], "static file"), None).await;
+        let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\"") && etag.len() == 22 + 4, "{}", etag);
+        assert_eq!(etag, compute(b"static file"));
+        assert_ne!(etag, compute(b"static file 2"));
+
+        let resp = apply(response(&[("cache-control", "max-age=60")], "static file"), Some(&format!("\"x\", {}", etag))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "max-age=60");
+        assert_eq!(resp.headers()[header::ETAG], etag.as_str());
+        assert!(resp.into_body().collect().await.unwrap().to_bytes().is_empty());
+
+        // Strong form in If-None-Match still matches; other tags don't
+        assert!(matches(etag.trim_start_matches("W/"), &etag));
+        assert!(!matches("W/\"other\"", &etag));
+        assert!(matches("*", &etag));
+
+        // Validators of the backend, or no-store: untouched
+        let resp = apply(response(&[("etag", "\"v1\"")], "x"), Some("\"v1\"")).await;
+        assert_eq!((resp.status(), &resp.headers()[header::ETAG]), (StatusCode::OK, &HeaderValue::from_static("\"v1\"")));
+        assert!(!wanted(StatusCode::OK, response(&[("last-modified", "Mon, 01 Jan 2024 00:00:00 GMT")], "").headers()));
+        assert!(!wanted(StatusCode::OK, response(&[("cache-control", "private, no-store")], "").headers()));
+        assert!(!wanted(StatusCode::NOT_FOUND, &HeaderMap::new()));
+    }
+}
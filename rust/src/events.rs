@@ -0,0 +1,316 @@
+//! Event log
+//! Significant proxy events — routing, backend health, certificates, rate limits,
+//! anomalies, admin actions — kept in a bounded in-memory ring, streamed to admin subscribers (SSE) and
+//! handed to the webhooks. Finished requests are streamed too, to subscribers that ask for
+//! them (`rustproxy tail`), but never logged or sent to webhooks
+
+use crate::database::Mapping;
+use crate::tls::Change;
+use crate::webhook::Webhooks;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Repeating events (rate limits, failing certificates) are recorded once per subject per window
+const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+/// How often the mapping table is compared for added and removed mappings
+pub const MAPPING_POLL: Duration = Duration::from_secs(2);
+/// Live events a slow stream subscriber may fall behind by before it misses some
+const STREAM_BUFFER: usize = 256;
+/// Requests a slow stream subscriber may fall behind by before it misses some
+const TRAFFIC_BUFFER: usize = 1024;
+
+/// Something that happened; serialized as `{"event": "<name>", ...fields}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    MappingAdded { mapping_id: String, domain: String, front_uri: String },
+    MappingRemoved { mapping_id: String, domain: String, front_uri: String },
+    /// `target` is `host:port`
+    BackendDown { mapping_id: String, target: String },
+    BackendUp { mapping_id: String, target: String },
+    /// `target` is the HA key `mapping_id:host:port`
+    OutlierEjected { target: String, reason: String, cooldown_secs: u64 },
+    OutlierReturned { target: String },
+    /// A certificate appeared in the certificate directory
+    CertIssued { domain: String },
+    /// A loaded certificate's files were replaced
+    CertRenewed { domain: String },
+    /// A certificate could not be loaded, or the renewal command failed (no domain)
+    CertFailed { domain: Option<String>, error: String },
+    RateLimited { domain: String, client: String },
+    /// A client was banned automatically (see [`crate::bans`]); `until` is RFC 3339
+    ClientBanned { network: String, source: String, reason: Option<String>, until: Option<String> },
+    /// A mapping's error rate or latency departed from its baseline (see [`crate::anomaly`]);
+    /// `metric` is `error_rate` or `latency`
+    AnomalyDetected { mapping_id: String, domain: String, metric: String, summary: String },
+    AnomalyResolved { mapping_id: String, domain: String, metric: String, summary: String },
+    /// A state-changing admin API call
+    AdminAction { operation: String },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::MappingAdded { .. } => "mapping_added",
+            Event::MappingRemoved { .. } => "mapping_removed",
+            Event::BackendDown { .. } => "backend_down",
+            Event::BackendUp { .. } => "backend_up",
+            Event::OutlierEjected { .. } => "outlier_ejected",
+            Event::OutlierReturned { .. } => "outlier_returned",
+            Event::CertIssued { .. } => "cert_issued",
+            Event::CertRenewed { .. } => "cert_renewed",
+            Event::CertFailed { .. } => "cert_failed",
+            Event::RateLimited { .. } => "rate_limited",
+            Event::ClientBanned { .. } => "client_banned",
+            Event::AnomalyDetected { .. } => "anomaly_detected",
+            Event::AnomalyResolved { .. } => "anomaly_resolved",
+            Event::AdminAction { .. } => "admin_action",
+        }
+    }
+
+    /// Key under which repeats are suppressed, for events that recur while a condition lasts.
+    fn repeat_key(&self) -> Option<String> {
+        match self {
+            Event::CertFailed { domain, .. } => Some(format!("cert_failed:{}", domain.as_deref().unwrap_or(""))),
+            Event::RateLimited { domain, client } => Some(format!("rate_limited:{}:{}", domain, client)),
+            _ => None,
+        }
+    }
+}
+
+impl From<Change> for Event {
+    fn from(change: Change) -> Self {
+        match change {
+            Change::Loaded(domain) => Event::CertIssued { domain },
+            Change::Reloaded(domain) => Event::CertRenewed { domain },
+            Change::Failed(domain, error) => Event::CertFailed { domain: Some(domain), error },
+        }
+    }
+}
+
+/// Events for mappings in `after` but not `before` and the other way round.
+pub fn mapping_events(before: &[Mapping], after: &[Mapping]) -> Vec<Event> {
+    let added = after.iter().filter(|m| !before.iter().any(|b| b.id == m.id)).map(|m| Event::MappingAdded {
+        mapping_id: m.id.clone(),
+        domain: m.domain.clone(),
+        front_uri: m.front_uri.clone(),
+    });
+    let removed = before.iter().filter(|m| !after.iter().any(|a| a.id == m.id)).map(|m| Event::MappingRemoved {
+        mapping_id: m.id.clone(),
+        domain: m.domain.clone(),
+        front_uri: m.front_uri.clone(),
+    });
+    added.chain(removed).collect()
+}
+
+/// An event as logged and sent: `{"id": n, "time": "<RFC 3339>", "event": "<name>", ...}`
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    /// Increasing per process; SSE clients resume with `Last-Event-ID`
+    pub id: u64,
+    pub time: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// A finished request, as streamed to subscribers of the traffic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    /// RFC 3339
+    pub time: String,
+    /// Host without port, `-` when the request had none
+    pub domain: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    /// `host:port` (or FastCGI socket) of the backend that answered, last of those tried
+    pub backend: Option<String>,
+    pub mapping_id: Option<String>,
+    pub client: String,
+}
+
+struct Inner {
+    ring: Mutex<VecDeque<Arc<Record>>>,
+    capacity: usize,
+    next_id: AtomicU64,
+    live: broadcast::Sender<Arc<Record>>,
+    traffic: broadcast::Sender<Arc<Request>>,
+    recent: DashMap<String, Instant>,
+    webhooks: Webhooks,
+}
+
+/// Event hub handle shared by everything that reports events
+#[derive(Clone)]
+pub struct Events {
+    inner: Arc<Inner>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new(0, Webhooks::default())
+    }
+}
+
+impl Events {
+    /// Keep the last `capacity` events (0: none) and pass every event to `webhooks`.
+    pub fn new(capacity: usize, webhooks: Webhooks) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                ring: Mutex::new(VecDeque::with_capacity(capacity.min(4096))),
+                capacity,
+                next_id: AtomicU64::new(1),
+                live: broadcast::channel(STREAM_BUFFER).0,
+                traffic: broadcast::channel(TRAFFIC_BUFFER).0,
+                recent: DashMap::new(),
+                webhooks,
+            }),
+        }
+    }
+
+    /// Whether anything consumes events (a log, stream subscribers or webhooks).
+    pub fn enabled(&self) -> bool {
+        self.inner.capacity > 0 || self.inner.webhooks.enabled() || self.inner.live.receiver_count() > 0
+    }
+
+    pub fn emit(&self, event: Event) {
+        let inner = &self.inner;
+        if !self.enabled() {
+            return;
+        }
+        if let Some(key) = event.repeat_key() {
+            let now = Instant::now();
+            if inner.recent.get(&key).is_some_and(|at| now.duration_since(*at) < REPEAT_WINDOW) {
+                return;
+            }
+            if inner.recent.len() > 10_000 {
+                inner.recent.retain(|_, at| now.duration_since(*at) < REPEAT_WINDOW);
+            }
+            inner.recent.insert(key, now);
+        }
+        let record = Arc::new(Record {
+            id: inner.next_id.fetch_add(1, Ordering::Relaxed),
+            time: chrono::Utc::now().to_rfc3339(),
+            event,
+        });
+        if inner.capacity > 0 {
+            let mut ring = inner.ring.lock();
+            if ring.len() == inner.capacity {
+                ring.pop_front();
+            }
+            ring.push_back(record.clone());
+        }
+        inner.webhooks.send(&record);
+        let _ = inner.live.send(record);
+    }
+
+    /// Logged events with an ID above `after`, oldest first, at most the last `limit`.
+    pub fn since(&self, after: Option<u64>, limit: usize) -> Vec<Arc<Record>> {
+        let ring = self.inner.ring.lock();
+        let newer: Vec<_> = ring.iter().filter(|r| after.is_none_or(|a| r.id > a)).cloned().collect();
+        newer[newer.len().saturating_sub(limit)..].to_vec()
+    }
+
+    /// Events emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Record>> {
+        self.inner.live.subscribe()
+    }
+
+    /// Whether anyone is following the traffic; requests are only described for them.
+    pub fn watching_traffic(&self) -> bool {
+        self.inner.traffic.receiver_count() > 0
+    }
+
+    /// Pass a finished request to the subscribers following the traffic.
+    pub fn request(&self, request: Request) {
+        let _ = self.inner.traffic.send(Arc::new(request));
+    }
+
+    /// Requests finished from now on.
+    pub fn subscribe_traffic(&self) -> broadcast::Receiver<Arc<Request>> {
+        self.inner.traffic.subscribe()
+    }
+}
+
+/// One Server-Sent Events message.
+pub fn sse_message(record: &Record) -> String {
+    let data = serde_json::to_string(record).unwrap_or_default();
+    format!("id: {}\nevent: {}\ndata: {}\n\n", record.id, record.event.name(), data)
+}
+
+/// A finished request as a Server-Sent Events message. It has no ID: requests aren't
+/// logged, so a reconnecting client can't be sent the ones it missed.
+pub fn sse_request(request: &Request) -> String {
+    format!("event: request\ndata: {}\n\n", serde_json::to_string(request).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_and_repeats() {
+        let events = Events::new(3, Webhooks::default());
+        for i in 0..5 {
+            events.emit(Event::OutlierReturned { target: format!("t{}", i) });
+        }
+        let all = events.since(None, 100);
+        assert_eq!(all.iter().map(|r| r.id).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(events.since(Some(4), 100).len(), 1);
+        assert_eq!(events.since(None, 1)[0].id, 5);
+
+        let limited = || Event::RateLimited { domain: "a.com".into(), client: "1.2.3.4".into() };
+        events.emit(limited());
+        events.emit(limited());
+        events.emit(Event::RateLimited { domain: "a.com".into(), client: "5.6.7.8".into() });
+        assert_eq!(events.since(Some(5), 100).len(), 2);
+
+        let json = serde_json::to_value(&*events.since(None, 1)[0]).unwrap();
+        assert_eq!(json["event"], "rate_limited");
+        assert_eq!(json["client"], "5.6.7.8");
+        assert_eq!(json["id"], 7);
+        assert!(sse_message(&events.since(None, 1)[0]).starts_with("id: 7\nevent: rate_limited\ndata: {"));
+    }
+
+    #[test]
+    fn test_traffic() {
+        let events = Events::default();
+        assert!(!events.watching_traffic());
+        let mut rx = events.subscribe_traffic();
+        assert!(events.watching_traffic() && !events.enabled());
+        let request = Request {
+            time: "2024-05-01T10:00:00+00:00".into(),
+            domain: "a.com".into(),
+            method: "GET".into(),
+            path: "/x".into(),
+            status: 502,
+            duration_ms: 12,
+            backend: Some("10.0.0.5:3000".into()),
+            mapping_id: Some("m1".into()),
+            client: "1.2.3.4".into(),
+        };
+        events.request(request.clone());
+        assert_eq!(*rx.try_recv().unwrap(), request);
+        let message = sse_request(&request);
+        assert!(message.starts_with("event: request\ndata: {") && !message.contains("id: "));
+        let data = message.lines().nth(1).unwrap().strip_prefix("data: ").unwrap();
+        assert_eq!(serde_json::from_str::<Request>(data).unwrap(), request);
+    }
+
+    #[test]
+    fn test_mapping_events() {
+        let mapping = |id: &str| Mapping { id: id.into(), domain: format!("{}.com", id), ..Default::default() };
+        let events = mapping_events(&[mapping("a"), mapping("b")], &[mapping("b"), mapping("c")]);
+        assert_eq!(events, vec![
+            Event::MappingAdded { mapping_id: "c".into(), domain: "c.com".into(), front_uri: String::new() },
+            Event::MappingRemoved { mapping_id: "a".into(), domain: "a.com".into(), front_uri: String::new() },
+        ]);
+        assert_eq!(Event::from(Change::Reloaded("a.com".into())), Event::CertRenewed { domain: "a.com".into() });
+    }
+}
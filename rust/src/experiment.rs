@@ -0,0 +1,157 @@
+//! A/B experiments per mapping
+//! Splits traffic between backend variants by a stable hash of a cookie or header, so each
+//! user keeps seeing the same variant
+
+use crate::database::Mapping;
+use anyhow::{bail, Result};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+/// Header carrying `<experiment>=<variant>` to the backend and back to the client
+pub const HEADER: &str = "x-experiment";
+
+/// Experiment definition, stored as JSON in the mapping's `experiment` column.
+///
+/// ```json
+/// {"name": "checkout", "key": "cookie:uid",
+///  "variants": [{"name": "control", "weight": 90},
+///               {"name": "v2", "port": 3001, "weight": 10}]}
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Experiment {
+    pub name: String,
+    /// What identifies a user: `cookie:<name>` or `header:<name>`.
+    /// Requests without it are bucketed by client IP.
+    pub key: String,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    /// Backend server URL; defaults to the mapping's backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// Backend port; defaults to the mapping's back_port / back_ports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Experiment {
+    /// Parse and validate an experiment definition.
+    pub fn parse(json: &str) -> Result<Self> {
+        let exp: Experiment = serde_json::from_str(json)?;
+        if exp.name.is_empty() {
+            bail!("experiment needs a name");
+        }
+        if !(exp.key.starts_with("cookie:") || exp.key.starts_with("header:")) || exp.key.ends_with(':') {
+            bail!("experiment key must be cookie:<name> or header:<name>");
+        }
+        if exp.variants.is_empty() || exp.variants.iter().all(|v| v.weight == 0) {
+            bail!("experiment needs at least one variant with a non-zero weight");
+        }
+        Ok(exp)
+    }
+
+    /// Pick the variant for a request. The same key value always yields the same variant
+    /// (across restarts and nodes) as long as the variant list is unchanged.
+    pub fn assign(&self, headers: &HeaderMap, client_ip: &str) -> &Variant {
+        let subject = self.subject(headers).unwrap_or_else(|| client_ip.to_string());
+        let total: u32 = self.variants.iter().map(|v| v.weight).sum();
+        let mut bucket = (fnv1a(format!("{}:{}", self.name, subject).as_bytes()) % total as u64) as u32;
+        for v in &self.variants {
+            if bucket < v.weight {
+                return v;
+            }
+            bucket -= v.weight;
+        }
+        &self.variants[0]
+    }
+
+    fn subject(&self, headers: &HeaderMap) -> Option<String> {
+        if let Some(name) = self.key.strip_prefix("cookie:") {
+            return headers.get_all(hyper::header::COOKIE).iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+                .filter(|v| !v.is_empty());
+        }
+        let name = self.key.strip_prefix("header:")?;
+        headers.get(name)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+    }
+}
+
+/// Point `mapping` at the variant's target.
+pub fn apply_variant(mapping: &mut Mapping, variant: &Variant) {
+    if let Some(backend) = &variant.backend {
+        mapping.backend = Some(backend.clone());
+    }
+    if let Some(port) = variant.port {
+        mapping.back_port = port;
+        mapping.back_ports = None;
+    }
+}
+
+/// 64-bit FNV-1a — stable across Rust versions, unlike `DefaultHasher`.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment() -> Experiment {
+        Experiment::parse(r#"{"name":"checkout","key":"cookie:uid","variants":[
+            {"name":"control","weight":50},{"name":"v2","port":3001,"weight":50}]}"#).unwrap()
+    }
+
+    fn cookie(v: &str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(hyper::header::COOKIE, v.parse().unwrap());
+        h
+    }
+
+    #[test]
+    fn test_assignment_is_sticky() {
+        let exp = experiment();
+        for uid in ["alice", "bob", "carol", "dave"] {
+            let first = exp.assign(&cookie(&format!("theme=dark; uid={}", uid)), "1.1.1.1").name.clone();
+            for ip in ["2.2.2.2", "3.3.3.3"] {
+                assert_eq!(exp.assign(&cookie(&format!("uid={}", uid)), ip).name, first);
+            }
+        }
+    }
+
+    #[test]
+    fn test_assignment_splits_traffic() {
+        let exp = experiment();
+        let v2 = (0..1000)
+            .filter(|i| exp.assign(&cookie(&format!("uid=user{}", i)), "").name == "v2")
+            .count();
+        assert!((400..600).contains(&v2), "v2 got {} of 1000", v2);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_definitions() {
+        assert!(Experiment::parse(r#"{"name":"x","key":"uid","variants":[{"name":"a"}]}"#).is_err());
+        assert!(Experiment::parse(r#"{"name":"x","key":"header:X-User","variants":[]}"#).is_err());
+        assert!(Experiment::parse(r#"{"name":"x","key":"header:X-User","variants":[{"name":"a"}]}"#).is_ok());
+    }
+}
@@ -0,0 +1,347 @@
+//! FastCGI backends
+//! Requests to `fcgi://` backends (PHP-FPM and the like) sent as FastCGI responder requests
+//! — CGI/1.1 parameters, the body as `STDIN` — with the `STDOUT` stream parsed as a CGI
+//! response and passed on as it arrives. Existing non-script files under the mapping's
+//! document root are served directly, as a front web server would, from a pre-compressed
+//! `.br` or `.gz` copy next to them where the client accepts that coding.
+
+use crate::content_coding::{self, Coding};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE, LOCATION};
+use hyper::{HeaderMap, Response, StatusCode};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// One request per connection, so a fixed id will do
+const REQUEST_ID: u16 = 1;
+/// Largest record content
+const MAX_RECORD: usize = 65535;
+/// Largest CGI response head accepted
+const MAX_HEAD: usize = 64 * 1024;
+/// Script run for paths that don't name one (front controller)
+pub const INDEX: &str = "index.php";
+
+/// Headers clients must not be able to turn into CGI variables (`HTTP_PROXY`: httpoxy)
+const SKIPPED_HEADERS: [&str; 5] = ["proxy", "connection", "keep-alive", "transfer-encoding", "upgrade"];
+
+/// The script a request path runs, split as CGI expects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    /// `SCRIPT_NAME`: the URL path of the script
+    pub name: String,
+    /// `SCRIPT_FILENAME`: the script under the document root
+    pub filename: String,
+    /// `PATH_INFO`: what follows the script in the path
+    pub path_info: String,
+}
+
+impl Script {
+    /// `/blog/post.php/extra` runs `/blog/post.php` with `PATH_INFO=/extra`; paths without
+    /// a `.php` segment run `/index.php` with the whole path as `PATH_INFO`.
+    pub fn resolve(document_root: &str, path: &str) -> Self {
+        let root = document_root.trim_end_matches('/');
+        let split = path.match_indices(".php")
+            .map(|(i, _)| i + 4)
+            .find(|&end| end == path.len() || path.as_bytes()[end] == b'/');
+        let (name, path_info) = match split {
+            Some(end) => (path[..end].to_string(), path[end..].to_string()),
+            None => (format!("/{}", INDEX), path.to_string()),
+        };
+        Self { filename: format!("{}{}", root, name), name, path_info }
+    }
+}
+
+/// Pre-compressed copies looked for next to static files, in order of preference
+const SIDECARS: [(Coding, &str); 2] = [(Coding::Brotli, "br"), (Coding::Gzip, "gz")];
+
+/// A static file as served
+#[derive(Debug)]
+pub struct StaticFile {
+    pub body: Bytes,
+    pub media_type: &'static str,
+    /// Coding of `body`, when it is a pre-compressed copy
+    pub coding: Option<Coding>,
+    /// Pre-compressed copies exist, so the response depends on `Accept-Encoding`
+    pub varies: bool,
+}
+
+/// A file under `document_root` a request path names directly, unless it's a script.
+/// Dot segments never get here (paths are normalized before routing), but are refused.
+/// A `.br` or `.gz` copy of the file is served instead where `accept_encoding` allows it,
+/// unless it is older than the file (left over from an earlier build).
+pub async fn static_file(document_root: &str, path: &str, accept_encoding: Option<&str>) -> Option<StaticFile> {
+    if path.ends_with(".php") || path.ends_with('/') {
+        return None;
+    }
+    let relative = Path::new(path.trim_start_matches('/'));
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let file: PathBuf = Path::new(document_root).join(relative);
+    let meta = tokio::fs::metadata(&file).await.ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    let mut varies = false;
+    for (coding, extension) in SIDECARS {
+        let mut sidecar = file.clone().into_os_string();
+        sidecar.push(".");
+        sidecar.push(extension);
+        let Ok(sidecar_meta) = tokio::fs::metadata(&sidecar).await else { continue };
+        let current = match (sidecar_meta.modified(), meta.modified()) {
+            (Ok(compressed), Ok(original)) => compressed >= original,
+            _ => true,
+        };
+        if !sidecar_meta.is_file() || !current {
+            continue;
+        }
+        varies = true;
+        if accept_encoding.is_some_and(|a| content_coding::accepts(a, coding)) {
+            if let Ok(body) = tokio::fs::read(&sidecar).await {
+                return Some(StaticFile { body: Bytes::from(body), media_type: media_type(path), coding: Some(coding), varies });
+            }
+        }
+    }
+    let body = tokio::fs::read(&file).await.ok()?;
+    Some(StaticFile { body: Bytes::from(body), media_type: media_type(path), coding: None, varies })
+}
+
+fn media_type(path: &str) -> &'static str {
+    let ext = path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Request headers as `HTTP_*` variables, plus `CONTENT_TYPE`/`CONTENT_LENGTH`.
+pub fn header_params(headers: &HeaderMap, params: &mut Vec<(String, String)>) {
+    for (name, value) in headers {
+        let Ok(value) = value.to_str() else { continue };
+        match name.as_str() {
+            "content-type" => params.push(("CONTENT_TYPE".to_string(), value.to_string())),
+            "content-length" => {}
+            n if SKIPPED_HEADERS.contains(&n) => {}
+            n => {
+                let var = format!("HTTP_{}", n.to_ascii_uppercase().replace('-', "_"));
+                // Repeated headers are joined, as a CGI server would
+                match params.iter_mut().find(|(k, _)| *k == var) {
+                    Some((_, existing)) => {
+                        existing.push_str(if n == "cookie" { "; " } else { ", " });
+                        existing.push_str(value);
+                    }
+                    None => params.push((var, value.to_string())),
+                }
+            }
+        }
+    }
+}
+
+fn record(kind: u8, content: &[u8], out: &mut Vec<u8>) {
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[VERSION, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.extend_from_slice(&[0; 7][..padding]);
+}
+
+fn push_length(len: usize, out: &mut Vec<u8>) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// The request as records: begin, params, stdin, each stream closed by an empty record.
+pub fn encode_request(params: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(256 + body.len());
+    let mut begin = RESPONDER.to_be_bytes().to_vec();
+    begin.extend_from_slice(&[0; 6]);
+    record(BEGIN_REQUEST, &begin, &mut out);
+
+    let mut pairs = Vec::new();
+    for (name, value) in params {
+        push_length(name.len(), &mut pairs);
+        push_length(value.len(), &mut pairs);
+        pairs.extend_from_slice(name.as_bytes());
+        pairs.extend_from_slice(value.as_bytes());
+    }
+    for chunk in pairs.chunks(MAX_RECORD) {
+        record(PARAMS, chunk, &mut out);
+    }
+    record(PARAMS, &[], &mut out);
+
+    for chunk in body.chunks(MAX_RECORD) {
+        record(STDIN, chunk, &mut out);
+    }
+    record(STDIN, &[], &mut out);
+    out
+}
+
+/// Next `STDOUT` content; `None` once the request ended. `STDERR` goes to the log.
+async fn next_stdout<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Vec<u8>>> {
+    loop {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await.context("FastCGI backend closed the connection")?;
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0u8; len + header[6] as usize];
+        stream.read_exact(&mut content).await?;
+        content.truncate(len);
+        match header[1] {
+            STDOUT if !content.is_empty() => return Ok(Some(content)),
+            STDOUT => {}
+            STDERR if !content.is_empty() => warn!("FastCGI: {}", String::from_utf8_lossy(&content).trim_end()),
+            END_REQUEST => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Split a CGI response head into status and headers (`Status:` header, else 302 with a
+/// `Location`, else 200).
+fn parse_head(head: &str) -> Result<(StatusCode, HeaderMap)> {
+    let mut status = None;
+    let mut headers = HeaderMap::new();
+    for line in head.lines().filter(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(|| anyhow!("bad CGI header line '{}'", line))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            let code = value.split_whitespace().next().unwrap_or("");
+            status = Some(StatusCode::from_bytes(code.as_bytes()).with_context(|| format!("bad CGI status '{}'", value))?);
+            continue;
+        }
+        headers.append(HeaderName::from_bytes(name.trim().as_bytes())?, HeaderValue::from_str(value)?);
+    }
+    let status = status.unwrap_or(if headers.contains_key(LOCATION) { StatusCode::FOUND } else { StatusCode::OK });
+    if !headers.contains_key(CONTENT_TYPE) && status != StatusCode::FOUND {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    }
+    Ok((status, headers))
+}
+
+/// Send a request over `stream` and answer with the script's response, its body streamed
+/// as the backend writes it. `guard` is held until the response is complete.
+pub async fn request<S, G>(mut stream: S, params: &[(String, String)], body: &[u8], guard: G) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    G: Send + 'static,
+{
+    stream.write_all(&encode_request(params, body)).await.context("sending FastCGI request")?;
+    stream.flush().await?;
+
+    let mut head = Vec::new();
+    let (head_end, separator) = loop {
+        let Some(chunk) = next_stdout(&mut stream).await? else {
+            bail!("FastCGI response ended before its headers");
+        };
+        head.extend_from_slice(&chunk);
+        let crlf = head.windows(4).position(|w| w == b"\r\n\r\n").map(|i| (i, 4));
+        let lf = head.windows(2).position(|w| w == b"\n\n").map(|i| (i, 2));
+        if let Some(found) = [crlf, lf].into_iter().flatten().min() {
+            break found;
+        }
+        if head.len() > MAX_HEAD {
+            bail!("FastCGI response head too large");
+        }
+    };
+    let (status, headers) = parse_head(&String::from_utf8_lossy(&head[..head_end]))?;
+    let rest = Bytes::copy_from_slice(&head[head_end + separator..]);
+
+    let (tx, rx) = mpsc::channel::<Bytes>(16);
+    tokio::spawn(async move {
+        let _guard = guard;
+        if !rest.is_empty() && tx.send(rest).await.is_err() {
+            return;
+        }
+        loop {
+            match next_stdout(&mut stream).await {
+                Ok(Some(chunk)) => {
+                    if tx.send(Bytes::from(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("FastCGI response cut short: {:#}", e);
+                    return;
+                }
+            }
+        }
+    });
+    let frames = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (Ok::<_, hyper::Error>(Frame::data(chunk)), rx))
+    });
+
+    let mut response = Response::new(BodyExt::boxed(StreamBody::new(frames)));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_script() {
+        let s = Script::resolve("/var/www/app/", "/blog/post.php/2024/hello");
+        assert_eq!(s.name, "/blog/post.php");
+        assert_eq!(s.filename, "/var/www/app/blog/post.php");
+        assert_eq!(s.path_info, "/2024/hello");
+
+        let s = Script::resolve("/var/www/app", "/users/7");
+        assert_eq!(s.filename, "/var/www/app/index.php");
+        assert_eq!(s.path_info, "/users/7");
+        assert_eq!(Script::resolve("/srv", "/x.phpfoo").name, "/index.php");
+    }
+
+    #[test]
+    fn test_cgi_head() {
+        let (status, headers) = parse_head("Status: 404 Not Found\r\nContent-Type: text/plain\r\nX-A: 1").unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers["content-type"], "text/plain");
+        let (status, _) = parse_head("Location: /login").unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+        assert!(parse_head("garbage").is_err());
+
+        let mut params = Vec::new();
+        let mut h = HeaderMap::new();
+        h.insert("proxy", "http://evil".parse().unwrap());
+        h.insert("x-token", "abc".parse().unwrap());
+        h.insert("content-type", "application/json".parse().unwrap());
+        header_params(&h, &mut params);
+        assert!(params.contains(&("HTTP_X_TOKEN".to_string(), "abc".to_string())));
+        assert!(params.contains(&("CONTENT_TYPE".to_string(), "application/json".to_string())));
+        assert!(!params.iter().any(|(k, _)| k == "HTTP_PROXY"));
+    }
+}
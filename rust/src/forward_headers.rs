@@ -0,0 +1,114 @@
+//! Request header allow-lists
+//! By default every client header but the framing ones goes to the backend. A mapping
+//! with `forward_headers` passes on only the headers it names (`accept`, `x-app-*`) plus
+//! those the request can't do without: content type and encoding, WebSocket handshake
+//! headers, the loop detection and experiment headers the proxy sets itself. Cookies,
+//! `Authorization` and whatever internal headers clients or upstream proxies add stay
+//! behind unless listed — for backends run by someone else. The proxy's own
+//! `X-Forwarded-*` headers are added after the list is applied
+
+use crate::hops;
+use hyper::header::{self, HeaderName};
+use hyper::HeaderMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Headers kept whatever the list says
+const ALWAYS: [HeaderName; 9] = [
+    header::HOST,
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::TRANSFER_ENCODING,
+    header::CONNECTION,
+    header::UPGRADE,
+    header::VIA,
+    hops::HOP_HEADER,
+];
+
+/// Prefixes kept whatever the list says
+const ALWAYS_PREFIXES: [&str; 2] = ["sec-websocket-", crate::experiment::HEADER];
+
+/// Request headers a mapping forwards
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardHeaders {
+    names: Vec<HeaderName>,
+    /// Lowercase prefixes from `name-*` entries
+    prefixes: Vec<String>,
+}
+
+impl FromStr for ForwardHeaders {
+    type Err = String;
+
+    /// `accept,accept-language,x-app-*`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut list = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let item = item.to_ascii_lowercase();
+            match item.strip_suffix('*') {
+                Some(prefix) if !prefix.is_empty() && HeaderName::from_bytes(prefix.as_bytes()).is_ok() => {
+                    list.prefixes.push(prefix.to_string());
+                }
+                Some(_) => return Err(format!("invalid header prefix '{}'", item)),
+                None => list.names.push(HeaderName::from_bytes(item.as_bytes()).map_err(|_| format!("invalid header name '{}'", item))?),
+            }
+        }
+        if list == Self::default() {
+            return Err("list at least one header to forward".to_string());
+        }
+        Ok(list)
+    }
+}
+
+impl fmt::Display for ForwardHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = self.names.iter().map(|n| n.to_string())
+            .chain(self.prefixes.iter().map(|p| format!("{}*", p)))
+            .collect();
+        f.write_str(&items.join(","))
+    }
+}
+
+impl ForwardHeaders {
+    /// Whether `name` may go to the backend.
+    pub fn allows(&self, name: &HeaderName) -> bool {
+        let prefixed = |p: &str| name.as_str().starts_with(p);
+        ALWAYS.contains(name) || self.names.contains(name)
+            || ALWAYS_PREFIXES.iter().any(|p| prefixed(p)) || self.prefixes.iter().any(|p| prefixed(p))
+    }
+
+    /// Drop every header from `headers` that isn't allowed.
+    pub fn retain(&self, headers: &mut HeaderMap) {
+        let dropped: Vec<HeaderName> = headers.keys().filter(|n| !self.allows(n)).cloned().collect();
+        for name in dropped {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_headers() {
+        let list: ForwardHeaders = "Accept, x-app-*".parse().unwrap();
+        assert_eq!(list.to_string(), "accept,x-app-*");
+        assert!("".parse::<ForwardHeaders>().is_err());
+        assert!("*".parse::<ForwardHeaders>().is_err());
+        assert!("bad header".parse::<ForwardHeaders>().is_err());
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("host", "api.test"), ("accept", "*/*"), ("x-app-tenant", "7"), ("cookie", "sid=1"),
+            ("authorization", "Bearer t"), ("x-internal-user", "alice"), ("content-type", "text/plain"),
+            ("sec-websocket-key", "k"), ("x-experiment", "checkout=v2"),
+        ] {
+            headers.insert(HeaderName::from_static(name), value.parse().unwrap());
+        }
+        list.retain(&mut headers);
+        let mut kept: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+        kept.sort();
+        assert_eq!(kept, ["accept", "content-type", "host", "sec-websocket-key", "x-app-tenant", "x-experiment"]);
+    }
+}
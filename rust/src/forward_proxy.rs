@@ -0,0 +1,182 @@
+//! Forward proxy
+//! The daemon can double as the egress proxy of internal clients: with
+//! `FORWARD_PROXY_LISTEN` set, a listener of its own takes `CONNECT host:port` requests and
+//! tunnels them to the destination (through `EGRESS_PROXY` where one is set). It is off by
+//! default and guarded by an ACL: only clients in `FORWARD_PROXY_CLIENTS` (loopback unless
+//! configured) may open tunnels, and only to destinations `FORWARD_PROXY_DESTINATIONS`
+//! allows (`*:443` unless configured). Requests other than `CONNECT` get `405`; mappings
+//! are never consulted on this listener
+
+use crate::bans::Network;
+use hyper::Uri;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Clients that may open tunnels, unless configured
+pub const DEFAULT_CLIENTS: &str = "127.0.0.1,::1";
+
+/// Destinations tunnels may go to, unless configured
+pub const DEFAULT_DESTINATIONS: &str = "*:443";
+
+/// Hosts a destination entry covers
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Hosts {
+    Any,
+    /// `*.example.com`: names under the domain, not the domain itself
+    Under(String),
+    Name(String),
+    Addresses(Network),
+}
+
+/// A destination tunnels may go to: `api.example.com:443`, `*.example.com:443`,
+/// `10.0.0.0/8:5432`, `[2001:db8::/32]:443`, `*:443` or `example.com:*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    hosts: Hosts,
+    /// `None`: any port
+    port: Option<u16>,
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (host, port) = s.rsplit_once(':')
+            .ok_or_else(|| format!("expected host:port like '*.example.com:443', got '{}'", s))?;
+        let port = match port {
+            "*" => None,
+            p => Some(p.parse::<u16>().ok().filter(|p| *p > 0).ok_or_else(|| format!("invalid port '{}' in '{}'", p, s))?),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let hosts = match host.as_str() {
+            "" => return Err(format!("missing host in '{}'", s)),
+            "*" => Hosts::Any,
+            h if h.starts_with("*.") => Hosts::Under(h[1..].to_string()),
+            h if h.contains('*') => return Err(format!("'*' only stands for a whole host or leading labels, in '{}'", s)),
+            h => match h.parse::<Network>() {
+                Ok(network) => Hosts::Addresses(network),
+                Err(_) if h.contains('/') => return Err(format!("invalid CIDR in '{}'", s)),
+                Err(_) => Hosts::Name(h.trim_end_matches('.').to_string()),
+            },
+        };
+        Ok(Self { hosts, port })
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hosts {
+            Hosts::Any => f.write_str("*")?,
+            Hosts::Under(suffix) => write!(f, "*{}", suffix)?,
+            Hosts::Name(name) => f.write_str(name)?,
+            Hosts::Addresses(network) if network.to_string().contains(':') => write!(f, "[{}]", network)?,
+            Hosts::Addresses(network) => write!(f, "{}", network)?,
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => f.write_str(":*"),
+        }
+    }
+}
+
+impl Destination {
+    /// Whether a tunnel to `host` (lowercase, without brackets) and `port` is allowed.
+    /// Addresses only match address entries, names only name entries.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+        match (&self.hosts, host.parse::<IpAddr>()) {
+            (Hosts::Any, _) => true,
+            (Hosts::Addresses(network), Ok(ip)) => network.contains(ip),
+            (Hosts::Under(suffix), Err(_)) => host.ends_with(suffix.as_str()),
+            (Hosts::Name(name), Err(_)) => host == name,
+            _ => false,
+        }
+    }
+}
+
+/// Who may open tunnels, and to where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub clients: Vec<Network>,
+    pub destinations: Vec<Destination>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            clients: DEFAULT_CLIENTS.split(',').map(|s| s.parse().unwrap()).collect(),
+            destinations: DEFAULT_DESTINATIONS.split(',').map(|s| s.parse().unwrap()).collect(),
+        }
+    }
+}
+
+impl Acl {
+    /// Whether the client at `ip` may use the forward proxy.
+    pub fn allows_client(&self, ip: IpAddr) -> bool {
+        self.clients.iter().any(|network| network.contains(ip))
+    }
+
+    /// Whether tunnels to `host` and `port` are allowed.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.destinations.iter().any(|d| d.allows(host, port))
+    }
+}
+
+/// Host (lowercase, without IPv6 brackets or a trailing dot) and port a `CONNECT` request's
+/// authority-form target names. The port is required.
+pub fn target(uri: &Uri) -> Option<(String, u16)> {
+    if uri.scheme().is_some() || uri.path_and_query().is_some_and(|p| !p.as_str().is_empty() && p.as_str() != "/") {
+        return None;
+    }
+    let authority = uri.authority()?;
+    let port = authority.port_u16().filter(|p| *p > 0)?;
+    let host = authority.host().trim_start_matches('[').trim_end_matches(']').trim_end_matches('.');
+    (!host.is_empty()).then(|| (host.to_ascii_lowercase(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destinations() {
+        let acl = Acl {
+            clients: vec!["10.0.0.0/8".parse().unwrap()],
+            destinations: ["*.example.com:443", "api.partner.com:*", "192.168.1.0/24:5432", "[2001:db8::/32]:443"]
+                .iter().map(|s| s.parse().unwrap()).collect(),
+        };
+        assert!(acl.allows("www.example.com", 443) && !acl.allows("example.com", 443));
+        assert!(!acl.allows("www.example.com", 80));
+        assert!(acl.allows("api.partner.com", 8443) && !acl.allows("partner.com", 443));
+        assert!(acl.allows("192.168.1.20", 5432) && !acl.allows("192.168.2.20", 5432));
+        assert!(acl.allows("2001:db8::1", 443));
+        assert!(acl.allows_client("10.1.2.3".parse().unwrap()) && !acl.allows_client("127.0.0.1".parse().unwrap()));
+        let shown: Vec<String> = acl.destinations.iter().map(ToString::to_string).collect();
+        assert_eq!(shown, ["*.example.com:443", "api.partner.com:*", "192.168.1.0/24:5432", "[2001:db8::/32]:443"]);
+
+        let any: Destination = "*:443".parse().unwrap();
+        assert!(any.allows("10.0.0.1", 443) && any.allows("internal", 443) && !any.allows("internal", 22));
+        assert!("example.com".parse::<Destination>().is_err());
+        assert!("ex*ample.com:443".parse::<Destination>().is_err());
+        assert!("10.0.0.0/33:443".parse::<Destination>().is_err());
+        assert!("example.com:0".parse::<Destination>().is_err());
+
+        let acl = Acl::default();
+        assert!(acl.allows_client("::1".parse().unwrap()) && acl.allows_client("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!acl.allows_client("192.168.1.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_target() {
+        let uri: Uri = "Example.COM:443".parse().unwrap();
+        assert_eq!(target(&uri), Some(("example.com".to_string(), 443)));
+        let uri: Uri = "[2001:db8::1]:8443".parse().unwrap();
+        assert_eq!(target(&uri), Some(("2001:db8::1".to_string(), 8443)));
+        assert_eq!(target(&"example.com".parse().unwrap()), None);
+        assert_eq!(target(&"http://example.com:80/path".parse().unwrap()), None);
+    }
+}
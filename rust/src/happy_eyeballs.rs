@@ -0,0 +1,156 @@
+//! Happy Eyeballs (RFC 8305)
+//! A backend name with both AAAA and A records is connected to over both families: the
+//! addresses are interleaved, preferred family first, and each attempt gets a head start
+//! of `BACKEND_CONNECT_ATTEMPT_DELAY` before the next one starts alongside it. The first
+//! connection made wins, so a broken IPv6 path costs a quarter second instead of a
+//! connect timeout.
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Which address family backend connections try first, or only
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    /// IPv6 first, IPv4 alongside (RFC 8305's default)
+    #[default]
+    Ipv6,
+    Ipv4,
+    Ipv6Only,
+    Ipv4Only,
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ipv6" => Ok(Self::Ipv6),
+            "ipv4" => Ok(Self::Ipv4),
+            "ipv6-only" => Ok(Self::Ipv6Only),
+            "ipv4-only" => Ok(Self::Ipv4Only),
+            other => Err(format!("invalid IP preference '{}' (ipv6, ipv4, ipv6-only or ipv4-only)", other)),
+        }
+    }
+}
+
+impl fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ipv6 => "ipv6",
+            Self::Ipv4 => "ipv4",
+            Self::Ipv6Only => "ipv6-only",
+            Self::Ipv4Only => "ipv4-only",
+        })
+    }
+}
+
+/// Addresses in the order they are tried: alternating families, the preferred one first
+/// (RFC 8305 §4), each family keeping the resolver's order.
+pub fn order(addrs: impl IntoIterator<Item = SocketAddr>, preference: IpPreference) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let (first, second) = match preference {
+        IpPreference::Ipv6 => (v6, v4),
+        IpPreference::Ipv4 => (v4, v6),
+        IpPreference::Ipv6Only => (v6, Vec::new()),
+        IpPreference::Ipv4Only => (v4, Vec::new()),
+    };
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race `attempt` over `addrs` in order: the next address starts when the previous attempt
+/// fails or `delay` passes without an answer, and the first success is returned (the
+/// attempts still pending are dropped).
+pub async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, delay: Duration, attempt: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut addrs = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.push(attempt(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no usable address")));
+        }
+        // Whichever comes first: an attempt finishing, or the next one's turn
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(connected) => return Ok(connected),
+                Err(e) => last_err = Some(e),
+            },
+            _ = tokio::time::sleep(delay), if !addrs.as_slice().is_empty() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let resolved = addrs(&["10.0.0.1:80", "10.0.0.2:80", "[2001:db8::1]:80", "10.0.0.3:80", "[2001:db8::2]:80"]);
+        assert_eq!(order(resolved.clone(), IpPreference::Ipv6),
+            addrs(&["[2001:db8::1]:80", "10.0.0.1:80", "[2001:db8::2]:80", "10.0.0.2:80", "10.0.0.3:80"]));
+        assert_eq!(order(resolved.clone(), IpPreference::Ipv4),
+            addrs(&["10.0.0.1:80", "[2001:db8::1]:80", "10.0.0.2:80", "[2001:db8::2]:80", "10.0.0.3:80"]));
+        assert_eq!(order(resolved.clone(), IpPreference::Ipv4Only), addrs(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]));
+        assert!(order(addrs(&["10.0.0.1:80"]), IpPreference::Ipv6Only).is_empty());
+        assert_eq!("IPv4-only".parse::<IpPreference>().unwrap().to_string(), "ipv4-only");
+        assert!("dual".parse::<IpPreference>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let list = addrs(&["[2001:db8::1]:80", "10.0.0.1:80", "10.0.0.2:80"]);
+
+        // The first address hangs: the second starts after the delay and wins
+        let started = Instant::now();
+        let won = race(list.clone(), Duration::from_millis(50), |addr| async move {
+            if addr.is_ipv6() {
+                std::future::pending::<()>().await;
+            }
+            Ok(addr)
+        }).await.unwrap();
+        assert_eq!(won, list[1]);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // Failures move on at once, without waiting out the delay
+        let (started, last) = (Instant::now(), list[2]);
+        let won = race(list.clone(), Duration::from_secs(10), |addr| async move {
+            match addr == last {
+                true => Ok(addr),
+                false => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")),
+            }
+        }).await.unwrap();
+        assert_eq!(won, list[2]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // All fail: the last error
+        let err = race(list.clone(), Duration::from_millis(10), |_| async {
+            Err::<(), _>(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+        }).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(race(Vec::new(), Duration::ZERO, |_| async { Ok(()) }).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
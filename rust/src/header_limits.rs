@@ -0,0 +1,103 @@
+//! Per-mapping header limits
+//! `MAX_HEADERS` and `MAX_HEADER_BYTES` bound every request on the listener; a mapping's
+//! `header_limits` can be tighter for the routes that need it, e.g. a public API that has
+//! no business receiving 60 headers or a 30K cookie. Requests over a limit get `431` once
+//! their mapping is known, before authentication, body checks or any backend work. Limits
+//! above the listener's have no effect: those requests never get this far
+
+use crate::usage::parse_bytes;
+use hyper::HeaderMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Header limits of one mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderLimits {
+    /// Most headers in a request
+    pub count: Option<usize>,
+    /// Largest request head (request line plus headers) in bytes
+    pub bytes: Option<usize>,
+    /// Largest single header (name plus value) in bytes
+    pub field: Option<usize>,
+}
+
+impl FromStr for HeaderLimits {
+    type Err = String;
+
+    /// `count=30,bytes=8K,field=2K`; any may be left out.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", item))?;
+            let value = value.trim();
+            let size = || parse_bytes(value).and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| format!("'{}' needs a size (e.g. 8K), got '{}'", name.trim(), value));
+            match name.trim() {
+                "count" => limits.count = Some(value.parse().map_err(|_| format!("'count' needs a number, got '{}'", value))?),
+                "bytes" => limits.bytes = Some(size()?),
+                "field" => limits.field = Some(size()?),
+                other => return Err(format!("unknown header limit '{}' (count, bytes, field)", other)),
+            }
+        }
+        if limits == Self::default() {
+            return Err("header limits need count=N, bytes=SIZE and/or field=SIZE".to_string());
+        }
+        Ok(limits)
+    }
+}
+
+impl fmt::Display for HeaderLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = [("count", self.count), ("bytes", self.bytes), ("field", self.field)]
+            .into_iter()
+            .filter_map(|(name, n)| Some(format!("{}={}", name, n?)))
+            .collect();
+        f.write_str(&items.join(","))
+    }
+}
+
+impl HeaderLimits {
+    /// The first limit a request with these headers and a `line_len` byte request line
+    /// breaks, if any, as named in the settings.
+    pub fn exceeded_by(&self, headers: &HeaderMap, line_len: usize) -> Option<&'static str> {
+        if self.count.is_some_and(|max| headers.len() > max) {
+            return Some("count");
+        }
+        // name + ": " + value + CRLF per header, as the listener counts them
+        let sizes = || headers.iter().map(|(k, v)| k.as_str().len() + v.len() + 4);
+        if self.field.is_some_and(|max| sizes().any(|n| n - 4 > max)) {
+            return Some("field");
+        }
+        if self.bytes.is_some_and(|max| line_len + sizes().sum::<usize>() > max) {
+            return Some("bytes");
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_limits() {
+        let limits: HeaderLimits = " count=3, bytes=1K ,field=100".parse().unwrap();
+        assert_eq!(limits.to_string(), "count=3,bytes=1024,field=100");
+        assert_eq!(limits.to_string().parse::<HeaderLimits>().unwrap(), limits);
+        assert!("".parse::<HeaderLimits>().is_err());
+        assert!("count=many".parse::<HeaderLimits>().is_err());
+        assert!("cookies=1".parse::<HeaderLimits>().is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "api.test".parse().unwrap());
+        headers.insert("accept", "*/*".parse().unwrap());
+        assert_eq!(limits.exceeded_by(&headers, 20), None);
+        headers.insert("cookie", "c".repeat(100).parse().unwrap());
+        assert_eq!(limits.exceeded_by(&headers, 20), Some("field"));
+        headers.insert("cookie", "c".repeat(94).parse().unwrap());
+        assert_eq!(limits.exceeded_by(&headers, 20), None);
+        assert_eq!(limits.exceeded_by(&headers, 1000), Some("bytes"));
+        headers.insert("x-extra", "1".parse().unwrap());
+        assert_eq!(limits.exceeded_by(&headers, 20), Some("count"));
+    }
+}
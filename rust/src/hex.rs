@@ -0,0 +1,6 @@
+//! Lowercase hex for hashes, signatures and random tokens
+
+/// `bytes` as lowercase hex, two digits each.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,132 @@
+//! Deploy holds
+//! While a backend restarts for a deploy, its requests would fail or be rejected. A held
+//! mapping (`rustproxy-mapping hold app.example.com --for 60s`) queues them instead: each
+//! waits until the hold is released or runs out, then goes to the backend as usual. The
+//! hold is stored as `until=<RFC 3339>,max_queue=<n>`; once `max_queue` requests wait,
+//! further ones get a 503 with `Retry-After` for the rest of the hold. Client deadlines
+//! and route draining keep applying to queued requests
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Queued requests per held mapping, unless the hold says otherwise
+pub const DEFAULT_MAX_QUEUE: usize = 1000;
+
+/// How often a queued request checks whether the hold was released
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A mapping's hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hold {
+    /// When queued requests are let through at the latest
+    pub until: DateTime<Utc>,
+    /// Requests that may wait at once
+    pub max_queue: usize,
+}
+
+impl FromStr for Hold {
+    type Err = String;
+
+    /// `until=2026-10-16T12:00:00Z` and optionally `max_queue=500`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut until = None;
+        let mut max_queue = DEFAULT_MAX_QUEUE;
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", item))?;
+            let value = value.trim();
+            match name.trim() {
+                "until" => {
+                    let t = DateTime::parse_from_rfc3339(value).map_err(|_| format!("'until' needs an RFC 3339 time, got '{}'", value))?;
+                    until = Some(t.with_timezone(&Utc));
+                }
+                "max_queue" => {
+                    max_queue = value.parse().ok().filter(|n| *n > 0)
+                        .ok_or_else(|| format!("'max_queue' needs a positive number, got '{}'", value))?;
+                }
+                other => return Err(format!("unknown hold setting '{}' (until, max_queue)", other)),
+            }
+        }
+        Ok(Self { until: until.ok_or("hold needs until=<RFC 3339 time>")?, max_queue })
+    }
+}
+
+impl fmt::Display for Hold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "until={},max_queue={}", self.until.to_rfc3339(), self.max_queue)
+    }
+}
+
+impl Hold {
+    /// Time left of the hold at `now`; zero once it has run out.
+    pub fn remaining(&self, now: DateTime<Utc>) -> Duration {
+        (self.until - now).to_std().unwrap_or(Duration::ZERO)
+    }
+
+    /// Whether requests are held at `now`.
+    pub fn active(&self, now: DateTime<Utc>) -> bool {
+        !self.remaining(now).is_zero()
+    }
+}
+
+/// Requests currently queued per mapping ID
+#[derive(Default)]
+pub struct Queues(DashMap<String, Arc<AtomicUsize>>);
+
+/// A place in a mapping's queue, given back on drop
+pub struct Queued(Arc<AtomicUsize>);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Queues {
+    /// Queue a request for `mapping_id`, unless `max_queue` are waiting already.
+    pub fn enter(&self, mapping_id: &str, max_queue: usize) -> Option<Queued> {
+        let count = self.0.entry(mapping_id.to_string()).or_default().clone();
+        count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max_queue).then_some(n + 1)).ok()?;
+        Some(Queued(count))
+    }
+
+    /// Requests waiting for `mapping_id`.
+    pub fn len(&self, mapping_id: &str) -> usize {
+        self.0.get(mapping_id).map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_hold() {
+        let hold: Hold = "until=2026-10-16T12:00:00Z, max_queue=2".parse().unwrap();
+        assert_eq!(hold.to_string(), "until=2026-10-16T12:00:00+00:00,max_queue=2");
+        assert_eq!(hold.to_string().parse::<Hold>().unwrap(), hold);
+        assert_eq!("until=2026-10-16T14:00:00+02:00".parse::<Hold>().unwrap().max_queue, DEFAULT_MAX_QUEUE);
+        assert!("max_queue=5".parse::<Hold>().is_err());
+        assert!("until=tomorrow".parse::<Hold>().is_err());
+        assert!("until=2026-10-16T12:00:00Z,max_queue=0".parse::<Hold>().is_err());
+        assert!("until=2026-10-16T12:00:00Z,retry=5".parse::<Hold>().is_err());
+
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 11, 59, 30).unwrap();
+        assert_eq!(hold.remaining(now), Duration::from_secs(30));
+        assert!(hold.active(now) && !hold.active(hold.until));
+
+        let queues = Queues::default();
+        let first = queues.enter("m1", 2).unwrap();
+        let _second = queues.enter("m1", 2).unwrap();
+        assert!(queues.enter("m1", 2).is_none());
+        assert_eq!(queues.len("m1"), 2);
+        drop(first);
+        assert!(queues.enter("m1", 2).is_some());
+        assert_eq!(queues.len("m2"), 0);
+    }
+}
@@ -0,0 +1,62 @@
+//! Proxy loop detection
+//! Every forwarded request carries `X-RustProxy-Hop: <n>` and a `Via: 1.1 rustproxy` entry.
+//! A mapping whose backend leads back to a proxy (itself, or a peer pointing back at it)
+//! sees the count grow on each pass, and once it reaches `MAX_PROXY_HOPS` the request is
+//! answered with 508 Loop Detected instead of going round until sockets run out. `Via`
+//! covers backends that pass our own header on but drop unknown ones.
+
+use hyper::header::{HeaderName, HeaderValue, VIA};
+use hyper::HeaderMap;
+
+/// Proxies of this kind the request has already passed
+pub const HOP_HEADER: HeaderName = HeaderName::from_static("x-rustproxy-hop");
+
+/// How this proxy names itself in `Via`
+pub const VIA_PSEUDONYM: &str = "rustproxy";
+
+/// Passes so far: the hop header or the `Via` entries naming us, whichever counts more.
+pub fn count(headers: &HeaderMap) -> u32 {
+    let hop = headers.get(&HOP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+    let via = headers.get_all(VIA).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter(|entry| entry.split_whitespace().nth(1).is_some_and(|by| by.eq_ignore_ascii_case(VIA_PSEUDONYM)))
+        .count() as u32;
+    hop.max(via)
+}
+
+/// Record one more pass on the headers of a request about to be forwarded; `received`
+/// are the headers it arrived with.
+pub fn stamp(received: &HeaderMap, forwarded: &mut HeaderMap) {
+    forwarded.insert(HOP_HEADER, HeaderValue::from(count(received).saturating_add(1)));
+    forwarded.append(VIA, HeaderValue::from_static("1.1 rustproxy"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_and_stamp() {
+        let mut received = HeaderMap::new();
+        assert_eq!(count(&received), 0);
+
+        received.append(VIA, HeaderValue::from_static("1.0 fred, 1.1 rustproxy"));
+        received.append(VIA, HeaderValue::from_static("1.1 RustProxy (edge)"));
+        assert_eq!(count(&received), 2);
+        received.insert(HOP_HEADER, HeaderValue::from_static("5"));
+        assert_eq!(count(&received), 5);
+        received.insert(HOP_HEADER, HeaderValue::from_static("junk"));
+        assert_eq!(count(&received), 2);
+
+        // Copied along with the other client headers, then stamped
+        let mut forwarded = received.clone();
+        stamp(&received, &mut forwarded);
+        assert_eq!(forwarded[&HOP_HEADER], "3");
+        assert_eq!(forwarded.get_all(VIA).iter().count(), 3);
+        assert_eq!(count(&forwarded), 3);
+    }
+}
@@ -0,0 +1,173 @@
+//! Host name normalization
+//! Parses request authorities (Host header or absolute-form target) and brings them and
+//! stored mapping domains into one canonical form (lowercase ASCII, IDNA/punycode, no
+//! trailing dot) so they compare equal
+
+use std::borrow::Cow;
+use std::net::Ipv6Addr;
+
+/// Host and optional port of a request authority
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authority<'a> {
+    /// Host as written, IPv6 literals keep their brackets (`[::1]`)
+    pub host: &'a str,
+    pub port: Option<u16>,
+}
+
+impl Authority<'_> {
+    /// The port, falling back to the scheme default.
+    pub fn port_or_default(&self, https: bool) -> u16 {
+        self.port.unwrap_or(if https { 443 } else { 80 })
+    }
+}
+
+/// Parse `host`, `host:port`, `[v6]` or `[v6]:port`.
+///
+/// Userinfo (`user@host`), unbracketed IPv6, bad IPv6 literals and non-numeric or
+/// out-of-range ports are rejected. An empty port (`host:`) counts as no port.
+pub fn parse_authority(s: &str) -> Option<Authority<'_>> {
+    let s = s.trim();
+    if s.is_empty() || s.contains(['@', '/', ' ']) {
+        return None;
+    }
+    let (host, port) = if s.starts_with('[') {
+        let end = s.find(']')?;
+        s[1..end].parse::<Ipv6Addr>().ok()?;
+        let rest = &s[end + 1..];
+        let port = match rest {
+            "" => None,
+            _ => Some(rest.strip_prefix(':')?),
+        };
+        (&s[..=end], port)
+    } else {
+        match s.split_once(':') {
+            Some((_, port)) if port.contains(':') => return None,
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        None | Some("") => None,
+        Some(p) if p.bytes().all(|b| b.is_ascii_digit()) => Some(p.parse::<u16>().ok()?),
+        Some(_) => return None,
+    };
+    Some(Authority { host, port })
+}
+
+/// Authority a request is addressed to: the absolute-form target wins over the Host
+/// header (RFC 9112 §3.2.2).
+pub fn request_authority<T>(req: &hyper::Request<T>) -> Option<&str> {
+    authority_of(req.uri(), req.headers().get(hyper::header::HOST))
+}
+
+/// [`request_authority`] from a request target and its Host header.
+pub fn authority_of<'a>(uri: &'a hyper::Uri, host: Option<&'a hyper::header::HeaderValue>) -> Option<&'a str> {
+    if let Some(authority) = uri.authority() {
+        return Some(authority.as_str());
+    }
+    host.and_then(|h| h.to_str().ok())
+}
+
+/// Canonical form of a host name, or `None` if it is empty or not a valid IDN.
+///
+/// `ExAmPle.com.` → `example.com`, `Bücher.example` → `xn--bcher-kva.example`.
+/// Bracketed IPv6 literals are brought into their compressed form (`[0::1]` → `[::1]`).
+/// Hosts already in canonical form are borrowed, not copied.
+pub fn normalize_host(host: &str) -> Option<Cow<'_, str>> {
+    let host = host.trim();
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return v6.parse::<Ipv6Addr>().ok().map(|ip| Cow::Owned(format!("[{}]", ip)));
+    }
+    if host.is_ascii() {
+        if host.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Some(Cow::Owned(host.to_ascii_lowercase()));
+        }
+        return Some(Cow::Borrowed(host));
+    }
+    idna::domain_to_ascii(host).ok().filter(|h| !h.is_empty()).map(Cow::Owned)
+}
+
+/// Canonical form of a mapping domain, which may also be a `*.parent` wildcard or the
+/// `*` catch-all.
+pub fn normalize_domain(domain: &str) -> Option<Cow<'_, str>> {
+    let domain = domain.trim();
+    if domain == "*" {
+        return Some(Cow::Borrowed(domain));
+    }
+    match domain.strip_prefix("*.") {
+        Some(parent) => normalize_host(parent).map(|p| Cow::Owned(format!("*.{}", p))),
+        None => normalize_host(domain),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("ExAmPle.com.").as_deref(), Some("example.com"));
+        assert!(matches!(normalize_host("example.com."), Some(Cow::Borrowed("example.com"))));
+        assert_eq!(normalize_host("Bücher.Example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(normalize_host("xn--bcher-kva.example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(normalize_host("[::1]").as_deref(), Some("[::1]"));
+        assert_eq!(normalize_host("[0:0::1]").as_deref(), Some("[::1]"));
+        assert_eq!(normalize_host("[zz]"), None);
+        assert_eq!(normalize_host("."), None);
+        assert_eq!(normalize_host(""), None);
+    }
+
+    #[test]
+    fn test_normalize_domain_patterns() {
+        assert_eq!(normalize_domain("*").as_deref(), Some("*"));
+        assert_eq!(normalize_domain("*.Bücher.example.").as_deref(), Some("*.xn--bcher-kva.example"));
+        assert_eq!(normalize_domain("API.Example.com").as_deref(), Some("api.example.com"));
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        let a = |s| parse_authority(s).map(|a| (a.host, a.port));
+        assert_eq!(a("example.com"), Some(("example.com", None)));
+        assert_eq!(a("example.com:8080"), Some(("example.com", Some(8080))));
+        assert_eq!(a("example.com:"), Some(("example.com", None)));
+        assert_eq!(a("[::1]:8080"), Some(("[::1]", Some(8080))));
+        assert_eq!(a("[2001:db8::1]"), Some(("[2001:db8::1]", None)));
+        assert_eq!(a("127.0.0.1:3000"), Some(("127.0.0.1", Some(3000))));
+
+        assert_eq!(a("::1"), None);
+        assert_eq!(a("[::1"), None);
+        assert_eq!(a("[not-ip]:80"), None);
+        assert_eq!(a("[::1]x"), None);
+        assert_eq!(a("example.com:99999"), None);
+        assert_eq!(a("example.com:80a"), None);
+        assert_eq!(a("user@example.com"), None);
+        assert_eq!(a(":80"), None);
+    }
+
+    #[test]
+    fn test_port_or_default() {
+        assert_eq!(parse_authority("a.com").unwrap().port_or_default(true), 443);
+        assert_eq!(parse_authority("a.com").unwrap().port_or_default(false), 80);
+        assert_eq!(parse_authority("a.com:8443").unwrap().port_or_default(true), 8443);
+    }
+
+    #[test]
+    fn test_request_authority_prefers_absolute_form() {
+        let req = hyper::Request::builder()
+            .uri("http://target.example:81/path")
+            .header("host", "other.example")
+            .body(())
+            .unwrap();
+        assert_eq!(request_authority(&req), Some("target.example:81"));
+
+        let req = hyper::Request::builder().uri("/path").header("host", "[::1]:80").body(()).unwrap();
+        assert_eq!(request_authority(&req), Some("[::1]:80"));
+    }
+}
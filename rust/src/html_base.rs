@@ -0,0 +1,175 @@
+//! Base path for sub-path mounted apps
+//! An app written for `/` but mounted under a mapping's front_uri links to `/static/...`,
+//! which falls outside its mapping; this keeps its HTML links and redirects under the prefix
+
+use crate::body_rewrite::{self, is_text_content_type};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::Response;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// How HTML of a mapping with a front_uri is adjusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BasePathMode {
+    /// Inject `<base href="/prefix/">` into `<head>`: for apps using relative links
+    Base,
+    /// Prefix root-relative `href`/`src`/`action`/`formaction`/`poster` attributes and
+    /// `Location` headers: for apps linking from `/`
+    Links,
+}
+
+impl FromStr for BasePathMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "base" => Ok(Self::Base),
+            "links" => Ok(Self::Links),
+            other => Err(format!("unknown HTML base mode '{}' (base, links)", other)),
+        }
+    }
+}
+
+/// Attribute up to the start of its (optionally quoted) URL value
+static LINK_ATTR: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\s(?:href|src|action|formaction|poster)\s*=\s*["']?"#).unwrap()
+});
+
+/// UTF-8 `text/html` or `application/xhtml+xml`.
+pub fn is_html(content_type: &str) -> bool {
+    let media = content_type.split(';').next().unwrap_or("").trim();
+    (media.eq_ignore_ascii_case("text/html") || media.eq_ignore_ascii_case("application/xhtml+xml"))
+        && is_text_content_type(content_type)
+}
+
+/// Whether `url` already points under `prefix` (`/app`, `/app/x`, `/app?q`, ...).
+fn is_under(url: &str, prefix: &str) -> bool {
+    url.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#', '"', '\'', ' ', '>']))
+}
+
+/// End of the first `<name ...>` tag (ASCII case-insensitive), so `<head` skips `<header>`.
+fn tag_end(html: &str, name: &str) -> Option<usize> {
+    let bytes = html.as_bytes();
+    let mut from = 0;
+    while let Some(i) = html[from..].find('<') {
+        let start = from + i + 1;
+        let after = start + name.len();
+        from = start;
+        if bytes.get(start..after).is_some_and(|n| n.eq_ignore_ascii_case(name.as_bytes()))
+            && matches!(bytes.get(after), Some(b'>' | b'/' | b' ' | b'\t' | b'\r' | b'\n'))
+        {
+            return html[after..].find('>').map(|j| after + j + 1);
+        }
+    }
+    None
+}
+
+/// Insert `<base href="{prefix}/">` at the top of `<head>` (or after `<html>`), unless the
+/// document has a `<base>` already or is a fragment without either tag.
+pub fn inject_base<'a>(html: &'a str, prefix: &str) -> Cow<'a, str> {
+    if tag_end(html, "base").is_some() {
+        return Cow::Borrowed(html);
+    }
+    let Some(at) = tag_end(html, "head").or_else(|| tag_end(html, "html")) else {
+        return Cow::Borrowed(html);
+    };
+    let mut out = String::with_capacity(html.len() + prefix.len() + 16);
+    out.push_str(&html[..at]);
+    out.push_str("<base href=\"");
+    out.push_str(prefix);
+    out.push_str("/\">");
+    out.push_str(&html[at..]);
+    Cow::Owned(out)
+}
+
+/// Prefix root-relative link attributes (`/x`, not `//host/x`) with `prefix`.
+pub fn prefix_root_links<'a>(html: &'a str, prefix: &str) -> Cow<'a, str> {
+    let mut out = String::new();
+    let mut copied = 0;
+    for m in LINK_ATTR.find_iter(html) {
+        let url = &html[m.end()..];
+        if url.starts_with('/') && !url.starts_with("//") && !is_under(url, prefix) {
+            out.push_str(&html[copied..m.end()]);
+            out.push_str(prefix);
+            copied = m.end();
+        }
+    }
+    if copied == 0 {
+        return Cow::Borrowed(html);
+    }
+    out.push_str(&html[copied..]);
+    Cow::Owned(out)
+}
+
+/// `Location` moved under `prefix` when it is root-relative and not there already.
+pub fn prefix_location(location: &str, prefix: &str) -> Option<String> {
+    (location.starts_with('/') && !location.starts_with("//") && !is_under(location, prefix))
+        .then(|| format!("{}{}", prefix, location))
+}
+
+/// Adjust a response from a mapping mounted at `prefix` (e.g. `/app`). `edit_body` is
+/// false for HEAD requests, whose headers describe a body that isn't there.
+pub async fn apply(
+    mut resp: Response<BoxBody<Bytes, hyper::Error>>,
+    mode: BasePathMode,
+    prefix: &str,
+    edit_body: bool,
+    max_bytes: usize,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if mode == BasePathMode::Links {
+        let moved = resp.headers().get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|l| prefix_location(l, prefix))
+            .and_then(|l| HeaderValue::from_str(&l).ok());
+        if let Some(location) = moved {
+            resp.headers_mut().insert(LOCATION, location);
+        }
+    }
+    if !edit_body {
+        return resp;
+    }
+    body_rewrite::edit_text_body(resp, max_bytes, is_html, |html| match mode {
+        BasePathMode::Base => inject_base(html, prefix),
+        BasePathMode::Links => prefix_root_links(html, prefix),
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_base() {
+        assert_eq!(
+            inject_base("<!doctype html><HTML><Head lang=en><title>x</title></head>", "/app"),
+            r#"<!doctype html><HTML><Head lang=en><base href="/app/"><title>x</title></head>"#,
+        );
+        assert_eq!(inject_base("<html><header>", "/app"), r#"<html><base href="/app/"><header>"#);
+        assert!(matches!(inject_base(r#"<head><base href="/"></head>"#, "/app"), Cow::Borrowed(_)));
+        assert!(matches!(inject_base("<p>fragment</p>", "/app"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_prefix_root_links() {
+        let html = r#"<a href="/login">in</a> <img SRC='/logo.png'> <a href="//cdn.example.com/x">
+            <a href="/app/already"><a href="https://other/"><a href=/bare><form action="/post"><a href="rel">"#;
+        assert_eq!(prefix_root_links(html, "/app"), r#"<a href="/app/login">in</a> <img SRC='/app/logo.png'> <a href="//cdn.example.com/x">
+            <a href="/app/already"><a href="https://other/"><a href=/app/bare><form action="/app/post"><a href="rel">"#);
+        // "/application" is not under "/app"
+        assert_eq!(prefix_root_links(r#"<a href="/application">"#, "/app"), r#"<a href="/app/application">"#);
+        assert!(matches!(prefix_root_links("<p>none</p>", "/app"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_prefix_location() {
+        assert_eq!(prefix_location("/login?next=/", "/app").as_deref(), Some("/app/login?next=/"));
+        assert_eq!(prefix_location("/app/login", "/app"), None);
+        assert_eq!(prefix_location("https://example.com/login", "/app"), None);
+        assert_eq!("Links".parse::<BasePathMode>(), Ok(BasePathMode::Links));
+    }
+}
@@ -0,0 +1,361 @@
+//! Image resizing
+//! Mappings with an `image_filter` act as a small image CDN: a `GET` for a PNG, JPEG or
+//! WebP with `w=`, `h=`, `format=` (`png`, `jpeg`, `webp`) or `q=` (JPEG quality) in its
+//! query gets the backend's image scaled to fit within `w`×`h` (never enlarged) and
+//! re-encoded. The parameters are taken off before the request goes to the backend. Results
+//! are kept in memory by source image and parameters, so each size is only computed once.
+//! WebP output is lossless, GIFs and other types pass through unchanged. The mapping's
+//! setting caps output dimensions and the size of images worth decoding
+
+use crate::body_rewrite;
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::header::{HeaderValue, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, TRANSFER_ENCODING};
+use hyper::{Response, StatusCode};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use parking_lot::Mutex;
+use ring::digest;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+use tracing::debug;
+
+/// Query parameters the filter takes
+pub const PARAMS: [&str; 4] = ["w", "h", "format", "q"];
+
+/// Memory kept for resized images, across mappings
+pub const CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Limits of a mapping's image filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageFilter {
+    /// Widest output, whatever `w=` asks for
+    pub max_width: u32,
+    /// Highest output, whatever `h=` asks for
+    pub max_height: u32,
+    /// JPEG quality without `q=`
+    pub quality: u8,
+    /// Larger source images are passed through unchanged
+    pub max_bytes: usize,
+}
+
+impl Default for ImageFilter {
+    fn default() -> Self {
+        Self { max_width: 2048, max_height: 2048, quality: 80, max_bytes: 10 * 1024 * 1024 }
+    }
+}
+
+fn number<T: FromStr + PartialOrd + Default>(name: &str, value: &str) -> Result<T, String> {
+    value.trim().parse::<T>().ok().filter(|n| *n > T::default())
+        .ok_or_else(|| format!("'{}' needs a positive number, got '{}'", name, value.trim()))
+}
+
+fn quality(name: &str, value: &str) -> Result<u8, String> {
+    number::<u8>(name, value).ok().filter(|q| *q <= 100)
+        .ok_or_else(|| format!("'{}' needs a quality from 1 to 100, got '{}'", name, value.trim()))
+}
+
+impl FromStr for ImageFilter {
+    type Err = String;
+
+    /// `on` for the defaults, or any of `max_width=2048,max_height=2048,quality=80,max_bytes=10485760`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::default();
+        let items: Vec<&str> = s.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        if items.is_empty() {
+            return Err("image filter needs 'on' or settings like max_width=2048,quality=80".to_string());
+        }
+        for item in items {
+            if item.eq_ignore_ascii_case("on") {
+                continue;
+            }
+            let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", item))?;
+            match name.trim() {
+                "max_width" => filter.max_width = number(name.trim(), value)?,
+                "max_height" => filter.max_height = number(name.trim(), value)?,
+                "quality" => filter.quality = quality(name.trim(), value)?,
+                "max_bytes" => filter.max_bytes = number(name.trim(), value)?,
+                other => return Err(format!("unknown image filter setting '{}' (max_width, max_height, quality, max_bytes)", other)),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for ImageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "max_width={},max_height={},quality={},max_bytes={}", self.max_width, self.max_height, self.quality, self.max_bytes)
+    }
+}
+
+/// Image types the filter reads and writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl Format {
+    /// The format of a `Content-Type`; `None` for types left alone.
+    pub fn of_content_type(content_type: &str) -> Option<Self> {
+        match content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase().as_str() {
+            "image/png" => Some(Format::Png),
+            "image/jpeg" | "image/jpg" => Some(Format::Jpeg),
+            "image/webp" => Some(Format::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Jpeg => "image/jpeg",
+            Format::Webp => "image/webp",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            Format::Png => ImageFormat::Png,
+            Format::Jpeg => ImageFormat::Jpeg,
+            Format::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// What a request asks for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Output format; the source's by default
+    pub format: Option<Format>,
+    pub quality: Option<u8>,
+}
+
+impl Transform {
+    /// The transform in a query string: `None` without any of [`PARAMS`], an error for
+    /// invalid values.
+    pub fn from_query(query: Option<&str>) -> Result<Option<Self>, String> {
+        let mut transform = Self::default();
+        let mut any = false;
+        for pair in query.unwrap_or("").split('&') {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if !PARAMS.contains(&name) {
+                continue;
+            }
+            any = true;
+            match name {
+                "w" => transform.width = Some(number(name, value)?),
+                "h" => transform.height = Some(number(name, value)?),
+                "q" => transform.quality = Some(quality(name, value)?),
+                _ => {
+                    transform.format = Some(match value.to_ascii_lowercase().as_str() {
+                        "png" => Format::Png,
+                        "jpeg" | "jpg" => Format::Jpeg,
+                        "webp" => Format::Webp,
+                        other => return Err(format!("unsupported image format '{}' (png, jpeg, webp)", other)),
+                    })
+                }
+            }
+        }
+        Ok(any.then_some(transform))
+    }
+
+    /// A query without the filter's parameters, for the backend.
+    pub fn strip_query(query: &str) -> Option<String> {
+        let rest: Vec<&str> = query.split('&')
+            .filter(|pair| !pair.is_empty() && !PARAMS.contains(&pair.split_once('=').map_or(*pair, |(n, _)| n)))
+            .collect();
+        (!rest.is_empty()).then(|| rest.join("&"))
+    }
+
+    /// Scale and re-encode `source` under `filter`'s limits.
+    pub fn apply(&self, source: &[u8], from: Format, filter: &ImageFilter) -> Result<(Vec<u8>, Format), image::ImageError> {
+        let mut reader = ImageReader::with_format(Cursor::new(source), from.image_format());
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(16384);
+        limits.max_image_height = Some(16384);
+        reader.limits(limits);
+        let mut img = reader.decode()?;
+
+        let width = self.width.unwrap_or(u32::MAX).min(filter.max_width).min(img.width());
+        let height = self.height.unwrap_or(u32::MAX).min(filter.max_height).min(img.height());
+        if (width, height) != (img.width(), img.height()) {
+            img = img.resize(width, height, FilterType::Lanczos3);
+        }
+
+        let format = self.format.unwrap_or(from);
+        let mut out = Vec::new();
+        match format {
+            Format::Jpeg => {
+                let quality = self.quality.unwrap_or(filter.quality);
+                DynamicImage::ImageRgb8(img.to_rgb8()).write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?
+            }
+            Format::Png => img.write_with_encoder(PngEncoder::new_with_quality(&mut out, CompressionType::Best, PngFilter::Adaptive))?,
+            Format::Webp => {
+                let img = match img.color().has_alpha() {
+                    true => DynamicImage::ImageRgba8(img.to_rgba8()),
+                    false => DynamicImage::ImageRgb8(img.to_rgb8()),
+                };
+                img.write_with_encoder(WebPEncoder::new_lossless(&mut out))?
+            }
+        }
+        Ok((out, format))
+    }
+
+    /// Cache key of this transform of `source` under `filter`.
+    pub fn key(&self, source: &[u8], filter: &ImageFilter) -> String {
+        let hash = digest::digest(&digest::SHA256, source);
+        let hex: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{} {:?} {}", hex, self, filter)
+    }
+}
+
+/// Resize a backend's `200` image response for `transform`, from `cache` where done
+/// before. Other responses, bigger images and images that fail to decode pass through.
+pub async fn apply(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    transform: Transform,
+    filter: ImageFilter,
+    cache: &Cache,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let from = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).and_then(Format::of_content_type);
+    let identity = resp.headers().get(CONTENT_ENCODING).is_none_or(|v| v.as_bytes().eq_ignore_ascii_case(b"identity"));
+    let declared = resp.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+    let (Some(from), true, StatusCode::OK) = (from, identity, resp.status()) else {
+        return resp;
+    };
+    if declared.is_some_and(|n| n > filter.max_bytes) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let source = match body_rewrite::read_bounded(body, filter.max_bytes, declared.unwrap_or(0)).await {
+        Ok(buf) => buf,
+        Err(body) => return Response::from_parts(parts, body),
+    };
+
+    let key = transform.key(&source, &filter);
+    let (image, format) = match cache.get(&key) {
+        Some(hit) => hit,
+        None => {
+            let source = Bytes::from(source);
+            let input = source.clone();
+            match tokio::task::spawn_blocking(move || transform.apply(&input, from, &filter)).await {
+                Ok(Ok((image, format))) => {
+                    let image = Bytes::from(image);
+                    cache.put(key, image.clone(), format);
+                    (image, format)
+                }
+                Ok(Err(e)) => {
+                    debug!("Passing image through unchanged: {}", e);
+                    (source, from)
+                }
+                Err(_) => (source, from),
+            }
+        }
+    };
+    // The validators and digests of the source don't describe the new image
+    for name in [ETAG, TRANSFER_ENCODING, ACCEPT_RANGES] {
+        parts.headers.remove(name);
+    }
+    parts.headers.remove("content-md5");
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(image.len()));
+    Response::from_parts(parts, Full::new(image).map_err(|never| match never {}).boxed())
+}
+
+#[derive(Default)]
+struct Entries {
+    images: HashMap<String, (Bytes, Format)>,
+    order: VecDeque<String>,
+    bytes: usize,
+}
+
+/// Resized images, the oldest dropped past [`CACHE_MAX_BYTES`]
+#[derive(Default)]
+pub struct Cache(Mutex<Entries>);
+
+impl Cache {
+    pub fn get(&self, key: &str) -> Option<(Bytes, Format)> {
+        self.0.lock().images.get(key).cloned()
+    }
+
+    pub fn put(&self, key: String, image: Bytes, format: Format) {
+        if image.len() > CACHE_MAX_BYTES / 16 {
+            return;
+        }
+        let mut entries = self.0.lock();
+        entries.bytes += image.len();
+        if let Some((old, _)) = entries.images.insert(key.clone(), (image, format)) {
+            entries.bytes -= old.len();
+        } else {
+            entries.order.push_back(key);
+        }
+        while entries.bytes > CACHE_MAX_BYTES {
+            let Some(oldest) = entries.order.pop_front() else { break };
+            if let Some((dropped, _)) = entries.images.remove(&oldest) {
+                entries.bytes -= dropped.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage};
+
+    #[test]
+    fn test_settings_and_query() {
+        let filter: ImageFilter = "max_width=800, quality=70".parse().unwrap();
+        assert_eq!(filter.to_string(), "max_width=800,max_height=2048,quality=70,max_bytes=10485760");
+        assert_eq!(filter.to_string().parse::<ImageFilter>().unwrap(), filter);
+        assert_eq!("on".parse::<ImageFilter>().unwrap(), ImageFilter::default());
+        assert!("quality=101".parse::<ImageFilter>().is_err());
+        assert!("max_width=0".parse::<ImageFilter>().is_err());
+        assert!("fit=cover".parse::<ImageFilter>().is_err());
+
+        let t = Transform::from_query(Some("v=3&w=300&format=WEBP")).unwrap().unwrap();
+        assert_eq!(t, Transform { width: Some(300), height: None, format: Some(Format::Webp), quality: None });
+        assert_eq!(Transform::from_query(Some("v=3")).unwrap(), None);
+        assert_eq!(Transform::from_query(None).unwrap(), None);
+        assert!(Transform::from_query(Some("w=wide")).is_err());
+        assert!(Transform::from_query(Some("format=avif")).is_err());
+        assert_eq!(Transform::strip_query("v=3&w=300&h=200").as_deref(), Some("v=3"));
+        assert_eq!(Transform::strip_query("w=300&q=50"), None);
+        assert_eq!(Format::of_content_type("image/jpeg; charset=binary"), Some(Format::Jpeg));
+        assert_eq!(Format::of_content_type("image/gif"), None);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut source = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(400, 200)).write_to(&mut Cursor::new(&mut source), ImageFormat::Png).unwrap();
+        let filter = ImageFilter { max_height: 50, ..ImageFilter::default() };
+
+        let t = Transform { width: Some(100), format: Some(Format::Webp), ..Transform::default() };
+        let (webp, format) = t.apply(&source, Format::Png, &filter).unwrap();
+        assert_eq!(format, Format::Webp);
+        assert_eq!(image::load_from_memory_with_format(&webp, ImageFormat::WebP).unwrap().dimensions(), (100, 50));
+
+        // Capped by the mapping, never enlarged
+        let t = Transform { width: Some(4000), format: Some(Format::Jpeg), ..Transform::default() };
+        let (jpeg, _) = t.apply(&source, Format::Png, &filter).unwrap();
+        assert_eq!(image::load_from_memory(&jpeg).unwrap().dimensions(), (100, 50));
+        assert!(t.apply(b"not a png", Format::Png, &filter).is_err());
+
+        let cache = Cache::default();
+        let key = t.key(&source, &filter);
+        assert_ne!(key, Transform::default().key(&source, &filter));
+        assert_ne!(key, t.key(&source, &ImageFilter::default()));
+        cache.put(key.clone(), Bytes::from(jpeg.clone()), Format::Jpeg);
+        assert_eq!(cache.get(&key), Some((Bytes::from(jpeg), Format::Jpeg)));
+    }
+}
@@ -0,0 +1,90 @@
+//! Leader leases
+//! A named lease held by one process at a time until it stops renewing it: a row in the
+//! SQLite database for processes sharing it, or a Redis key for nodes that don't
+
+use crate::database::DatabaseManager;
+use crate::redis::{RedisClient, Value};
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Redis keys: `{KEY_PREFIX}{name}`
+const KEY_PREFIX: &str = "rustproxy:lease:";
+
+/// Take the lease when it is free or already ours, and (re)start its expiry
+const ACQUIRE_SCRIPT: &str = "local v = redis.call('GET', KEYS[1]) \
+    if v == false or v == ARGV[1] then redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2]) return 1 end \
+    return 0";
+
+/// Where leases are kept
+#[derive(Clone)]
+pub enum LeaseStore {
+    Database(Arc<DatabaseManager>),
+    Redis(Arc<RedisClient>),
+}
+
+/// One named lease, held on behalf of this process
+pub struct Lease {
+    store: LeaseStore,
+    name: String,
+    holder: String,
+    ttl: Duration,
+}
+
+impl Lease {
+    /// A lease that lapses `ttl` after its holder last renewed it.
+    pub fn new(store: LeaseStore, name: &str, ttl: Duration) -> Self {
+        Self {
+            store,
+            name: name.to_string(),
+            holder: uuid::Uuid::new_v4().to_string(),
+            ttl,
+        }
+    }
+
+    /// Take the lease if it is free, or renew it if held already. Returns whether this
+    /// process holds it; call again well within `ttl` to keep it.
+    pub async fn acquire(&self) -> Result<bool> {
+        match &self.store {
+            LeaseStore::Database(db) => {
+                let (db, name, holder, ttl) = (db.clone(), self.name.clone(), self.holder.clone(), self.ttl);
+                tokio::task::spawn_blocking(move || db.try_lease(&name, &holder, ttl)).await?
+            }
+            LeaseStore::Redis(redis) => {
+                let key = format!("{}{}", KEY_PREFIX, self.name);
+                let ttl = self.ttl.as_millis().max(1).to_string();
+                let reply = redis.command(&[
+                    b"EVAL", ACQUIRE_SCRIPT.as_bytes(), b"1", key.as_bytes(), self.holder.as_bytes(), ttl.as_bytes(),
+                ]).await?;
+                match reply {
+                    Value::Int(n) => Ok(n == 1),
+                    other => Err(anyhow!("unexpected lease reply {:?}", other)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_database_lease_is_exclusive_until_it_lapses() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+        let store = LeaseStore::Database(db);
+        let first = Lease::new(store.clone(), "certificates", Duration::from_millis(200));
+        let second = Lease::new(store.clone(), "certificates", Duration::from_millis(200));
+        let other = Lease::new(store, "other", Duration::from_millis(200));
+
+        assert!(first.acquire().await.unwrap());
+        assert!(!second.acquire().await.unwrap());
+        assert!(first.acquire().await.unwrap(), "renewal");
+        assert!(other.acquire().await.unwrap(), "leases are independent");
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(second.acquire().await.unwrap());
+        assert!(!first.acquire().await.unwrap());
+    }
+}
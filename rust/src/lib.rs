@@ -3,14 +3,113 @@
 //! This is a Rust port of jsproxy, providing:
 //! - Domain-based routing with SQLite mappings
 //! - Path rewriting (front_uri -> back_uri)
-//! - HTTPS with automatic certificate management
+//! - Per-mapping literal/regex substitutions in text response bodies
+//! - HTML base path injection for apps mounted under a front_uri
+//! - HTTPS with automatic certificate management and hot reload of renewed certificates
+//! - Certificate renewal run by one lease-holding node, its certificates copied to the others
+//! - Internal ACME server issuing certificates from its own CA to internal services
+//! - `https://` backends verified by public roots, a private CA or key pins
+//! - Backend connections through a SOCKS5 or HTTP CONNECT egress proxy
+//! - FastCGI (`fcgi://`) backends such as PHP-FPM, without a web server in between
 //! - WebSocket proxy support
+//! - Connection draining when routes are removed or re-pointed
+//! - Client deadlines (`X-Request-Timeout`, `grpc-timeout`) enforced and passed upstream
+//! - Connection gauges and a global connection cap kept below the FD limit
 //! - Health check endpoint
+//! - Stale-if-error: last good responses served while a backend fails
+//! - Startup self-check of ports, database, certificates and backends
+//! - Per-client rate limits, optionally shared across instances through Redis
+//! - Experimental cluster mode replicating mappings and backend health between nodes
+//! - Admin API to flush caches and reset HA circuit breakers, with an event log and stream
+//! - Outlier detection ejecting HA targets with unusual error rates or latency
+//! - Consul/etcd service discovery for backends
+//! - `${ENV_VAR}` / request-variable templates in backend and rewrite targets
+//! - Sticky per-route A/B experiments
+//! - Path normalization (slashes, dot segments, percent-encoding) before matching
+//! - Case-insensitive, IDNA-aware host matching
 
+pub mod access_hours;
+pub mod access_log;
+pub mod acme_server;
+pub mod admin;
+pub mod analytics;
+pub mod anomaly;
+pub mod app_templates;
+pub mod bans;
+pub mod bench;
+pub mod body_rewrite;
+pub mod cache;
+pub mod cert_leader;
+pub mod cert_preflight;
 pub mod certificate;
+pub mod cli_output;
+pub mod cluster;
+pub mod config_export;
+pub mod connections;
+pub mod content_coding;
+pub mod contract;
 pub mod database;
+pub mod deadline;
+pub mod debug_trace;
+mod der;
+pub mod discovery;
+pub mod domain_verify;
+pub mod disk_cache;
+pub mod drain;
+pub mod duplicate_headers;
+pub mod early_hints;
+pub mod echo;
+pub mod egress;
+pub mod etag;
+pub mod events;
+pub mod experiment;
+pub mod fastcgi;
+pub mod forward_headers;
+pub mod forward_proxy;
+pub mod happy_eyeballs;
+pub mod header_limits;
+mod hex;
+pub mod hold;
+pub mod hops;
+pub mod host;
+pub mod html_base;
+pub mod image_filter;
+pub mod lease;
+pub mod lint;
+pub mod mapping_csv;
+pub mod minify;
+pub mod normalize;
+pub mod oidc;
+pub mod outlier;
+pub mod pool;
+pub mod probes;
 pub mod proxy;
+pub mod ratelimit;
+pub mod redis;
+pub mod replay;
+pub mod scrub;
+pub mod request_compression;
+pub mod route_url;
+pub mod s3_site;
+pub mod selfcheck;
+pub mod session;
+pub mod signed_url;
+pub mod snapshot;
+pub mod startup_page;
+pub mod tail;
+pub mod tcp_options;
+pub mod template;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod tls;
+pub mod tls_info;
+pub mod upstream_protocol;
+pub mod upstream_signing;
+pub mod upstream_tls;
+pub mod usage;
+pub mod webhook;
+pub mod xfcc;
 
 pub use certificate::CertificateManager;
 pub use database::{DatabaseManager, Mapping};
-pub use proxy::{FallbackHandler, NotFoundFallback, ProxyBuilder, ProxyConfig, ProxyServer};
+pub use proxy::{FallbackHandler, NotFoundFallback, ProxyBuilder, ProxyConfig, ProxyServer, ServerHandle};
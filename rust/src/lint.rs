@@ -0,0 +1,203 @@
+//! Route conflict detection
+//! Mappings that can never receive traffic, or that send it back to the proxy itself.
+//! Mappings are matched longest `front_uri` first, so a longer prefix never hides a shorter
+//! one outright; what does hide a mapping is another with the same prefix for the same
+//! domain (`api` and `%61pi` alike — the one added first wins) while both are scheduled
+//! (`activate_at`/`deactivate_at` windows that overlap), or a prefix normalized request
+//! paths can never start with (`api//v1` while slashes are merged, `api/../v1`).
+//! Reported by `rustproxy-mapping lint` and logged as warnings at startup
+
+use crate::database::Mapping;
+use crate::discovery::DiscoverySource;
+use crate::normalize::{self, PathNormalization};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Problem {
+    /// Same domain and prefix as an older mapping scheduled at the same time, which gets
+    /// all the traffic
+    Duplicate,
+    /// No normalized request path matches the prefix
+    Unreachable,
+    /// The backend is the proxy's own listener
+    Loop,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Duplicate => "duplicate",
+            Self::Unreachable => "unreachable",
+            Self::Loop => "loop",
+        })
+    }
+}
+
+/// One problem with one mapping
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub problem: Problem,
+    pub mapping: String,
+    pub domain: String,
+    pub front_uri: String,
+    pub detail: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}/{} ({}): {}", self.problem, self.domain, self.front_uri, self.mapping, self.detail)
+    }
+}
+
+/// Where the proxy itself listens
+pub struct Listeners<'a> {
+    /// Bind address, e.g. `0.0.0.0`
+    pub host: &'a str,
+    pub ports: &'a [u16],
+}
+
+impl Listeners<'_> {
+    /// Whether `host:port` reaches one of the listeners: a loopback or unspecified address,
+    /// the bind address, or the mapping's own domain (which resolves to the proxy).
+    fn reached_by(&self, host: &str, port: u16, domain: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let local = match host.parse::<IpAddr>() {
+            Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+            Err(_) => host.eq_ignore_ascii_case("localhost"),
+        };
+        self.ports.contains(&port) && (local || host.eq_ignore_ascii_case(self.host) || host.eq_ignore_ascii_case(domain))
+    }
+}
+
+/// Check every mapping; findings come in the order of `mappings`.
+pub fn check(mappings: &[Mapping], listeners: &Listeners, normalization: &PathNormalization) -> Vec<Finding> {
+    let finding = |m: &Mapping, problem, detail: String| Finding {
+        problem,
+        mapping: m.id.clone(),
+        domain: m.domain.clone(),
+        front_uri: m.front_uri.clone(),
+        detail,
+    };
+
+    // The oldest scheduled mapping of each route is the one matched
+    let mut routes: HashMap<(String, Vec<u8>), Vec<&Mapping>> = HashMap::new();
+    for m in mappings {
+        let key = (m.domain.to_ascii_lowercase(), normalize::decoded_octets(&m.front_uri));
+        routes.entry(key).or_default().push(m);
+    }
+    for same in routes.values_mut() {
+        same.sort_by(|a, b| (&a.created_at, &a.id).cmp(&(&b.created_at, &b.id)));
+    }
+
+    let mut findings = Vec::new();
+    for m in mappings {
+        let key = (m.domain.to_ascii_lowercase(), normalize::decoded_octets(&m.front_uri));
+        let mut older = routes[&key].iter().take_while(|o| o.id != m.id);
+        if let Some(oldest) = older.find(|o| overlaps(o, m)) {
+            findings.push(finding(m, Problem::Duplicate, format!("same route as {}, which receives its traffic", oldest.id)));
+        }
+
+        let pattern = String::from_utf8_lossy(&key.1).into_owned();
+        let pattern = format!("/{}", pattern);
+        if normalization.merge_slashes && pattern.contains("//") {
+            findings.push(finding(m, Problem::Unreachable, "request paths have repeated slashes merged".to_string()));
+        } else if pattern.contains("/./") || pattern.contains("/../") {
+            findings.push(finding(m, Problem::Unreachable, "request paths have . and .. segments resolved".to_string()));
+        }
+
+        for (host, port) in targets(m) {
+            if listeners.reached_by(&host, port, &m.domain) {
+                findings.push(finding(m, Problem::Loop, format!("backend {}:{} is this proxy", host, port)));
+            }
+        }
+    }
+    findings
+}
+
+/// Whether two mappings' `activate_at`/`deactivate_at` windows share a moment; unset or
+/// unparseable bounds are open, as when routing.
+fn overlaps(a: &Mapping, b: &Mapping) -> bool {
+    let at = |t: &Option<String>| t.as_deref().and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+    let before = |start: &Option<String>, end: &Option<String>| match (at(start), at(end)) {
+        (Some(start), Some(end)) => start < end,
+        _ => true,
+    };
+    before(&a.activate_at, &b.deactivate_at) && before(&b.activate_at, &a.deactivate_at)
+}
+
+/// Backend `host:port`s of both slots, as the proxy would connect to them; discovery and
+/// FastCGI socket backends are left out.
+fn targets(m: &Mapping) -> Vec<(String, u16)> {
+    let host = |backend: Option<&str>| -> Option<String> {
+        let backend = backend.unwrap_or("http://localhost");
+        if DiscoverySource::parse(backend).is_some() {
+            return None;
+        }
+        url::Url::parse(backend).ok()?.host_str().map(str::to_string)
+    };
+    let mut targets = Vec::new();
+    if let Some(blue) = host(m.backend.as_deref()) {
+        match m.back_ports.as_deref() {
+            Some(ports) => targets.extend(ports.split(',').filter_map(|p| p.trim().parse().ok()).map(|p| (blue.clone(), p))),
+            None => targets.push((blue, m.back_port)),
+        }
+    }
+    if let (Some(green), Some(port)) = (host(m.green_backend.as_deref().or(m.backend.as_deref())), m.green_port) {
+        targets.push((green, port));
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(id: &str, domain: &str, front_uri: &str, backend: Option<&str>, port: u16) -> Mapping {
+        Mapping {
+            id: id.to_string(),
+            domain: domain.to_string(),
+            front_uri: front_uri.to_string(),
+            backend: backend.map(str::to_string),
+            back_port: port,
+            created_at: format!("2024-01-01T00:00:0{}Z", id.len()),
+            ..Mapping::default()
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let mappings = vec![
+            mapping("a", "api.test", "v1", None, 3000),
+            mapping("bb", "api.test", "%761", None, 3001),
+            mapping("c", "api.test", "v2", None, 3002),
+            mapping("d", "api.test", "docs//old", None, 3003),
+            mapping("e", "api.test", "x/../y", None, 3004),
+            mapping("f", "self.test", "", None, 8080),
+            mapping("g", "loop.test", "", Some("https://loop.test"), 8443),
+            mapping("h", "ok.test", "", Some("http://10.0.0.5"), 8080),
+            Mapping { back_ports: Some("3000, 8443".to_string()), ..mapping("i", "ha.test", "", Some("http://[::1]"), 0) },
+            Mapping { green_port: Some(8080), ..mapping("j", "bg.test", "", None, 3000) },
+            mapping("k", "sd.test", "", Some("consul://web"), 8080),
+            // Takes over from l at launch, so never routed alongside it
+            Mapping { deactivate_at: Some("2030-01-01T00:00:00Z".to_string()), ..mapping("l", "launch.test", "", None, 3000) },
+            Mapping { activate_at: Some("2030-01-01T00:00:00Z".to_string()), ..mapping("mm", "launch.test", "", None, 3001) },
+        ];
+        let listeners = Listeners { host: "0.0.0.0", ports: &[8080, 8443] };
+        let found: Vec<_> = check(&mappings, &listeners, &PathNormalization::default())
+            .iter().map(|f| format!("{} {}", f.problem, f.mapping)).collect();
+        assert_eq!(found, [
+            "duplicate bb", "unreachable d", "unreachable e", "loop f", "loop g", "loop i", "loop j",
+        ]);
+
+        // Without slash merging, `docs//old` can be requested
+        let keep = PathNormalization { merge_slashes: false, ..PathNormalization::default() };
+        assert!(check(&mappings[3..4], &listeners, &keep).is_empty());
+
+        let finding = &check(&mappings[..2], &listeners, &keep)[0];
+        assert_eq!(finding.to_string(), "duplicate api.test/%761 (bb): same route as a, which receives its traffic");
+    }
+}
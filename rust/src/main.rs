@@ -4,16 +4,35 @@
 //! via WORKERS env var), each binding the same port with SO_REUSEPORT.  The kernel
 //! distributes incoming connections across all workers.
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use rustproxy::access_log::{AccessLevel, LogPolicy, Sampling};
+use rustproxy::analytics::{self, Sink};
+use rustproxy::bans::{BanPolicy, Network};
+use rustproxy::bench::LoadConfig;
+use rustproxy::connections::LimitAction;
+use rustproxy::duplicate_headers::DuplicateHeaders;
+use rustproxy::egress::EgressProxy;
+use rustproxy::forward_proxy::{self, Acl, Destination};
+use rustproxy::happy_eyeballs::IpPreference;
+use rustproxy::normalize::{PathNormalization, PercentDecoding, TrailingSlash};
+use rustproxy::pool::PoolSettings;
+use rustproxy::ratelimit::RateLimit;
+use rustproxy::replay::ReplayConfig;
+use rustproxy::scrub::ScrubProfile;
+use rustproxy::session;
+use rustproxy::tail::{self, StatusFilter};
+use rustproxy::tcp_options::TcpOptions;
+use rustproxy::xfcc::ClientCertDetails;
 use rustproxy::{CertificateManager, DatabaseManager, ProxyConfig, ProxyServer};
 use socket2::{Domain, Socket, Type};
 use std::net::SocketAddr;
 use std::net::TcpListener as StdListener;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tracing::{info, Level};
+use tracing::{info, warn, Level};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::FmtSubscriber;
 
 /// RustProxy — A resilient HTTP/HTTPS reverse proxy server
@@ -47,6 +66,325 @@ struct Args {
     #[arg(long, env = "ACME_DIRECTORY_URL")]
     acme_directory_url: Option<String>,
 
+    /// Consul HTTP API address used by `consul://` backends
+    #[arg(long, env = "CONSUL_HTTP_ADDR")]
+    consul_addr: Option<String>,
+
+    #[arg(long, env = "CONSUL_HTTP_TOKEN", hide_env_values = true)]
+    consul_token: Option<String>,
+
+    /// etcd v3 endpoint used by `etcd://` backends
+    #[arg(long, env = "ETCD_ENDPOINT")]
+    etcd_endpoint: Option<String>,
+
+    /// DNS server (ip:port) for `srv://` backends instead of the system resolver's
+    #[arg(long, env = "SRV_NAMESERVER")]
+    srv_nameserver: Option<std::net::SocketAddr>,
+
+    /// Blue/green: 5xx share during probation that rolls a switch back
+    #[arg(long, env = "ROLLBACK_ERROR_RATE", default_value = "0.5")]
+    rollback_error_rate: f64,
+
+    /// Blue/green: responses needed during probation before rolling back
+    #[arg(long, env = "ROLLBACK_MIN_REQUESTS", default_value = "20")]
+    rollback_min_requests: u32,
+
+    /// Collapse repeated slashes in request paths before matching
+    #[arg(long, env = "MERGE_SLASHES", default_value = "true", action = clap::ArgAction::Set)]
+    merge_slashes: bool,
+
+    /// Percent-encoding policy for paths: keep, unreserved, reject-encoded-slash
+    #[arg(long, env = "PERCENT_DECODING", default_value = "unreserved")]
+    percent_decoding: PercentDecoding,
+
+    /// Trailing-slash redirects: ignore, add, remove
+    #[arg(long, env = "TRAILING_SLASH", default_value = "ignore")]
+    trailing_slash: TrailingSlash,
+
+    /// Most headers accepted per request
+    #[arg(long, env = "MAX_HEADERS", default_value = "100")]
+    max_headers: usize,
+
+    /// Largest accepted request head in bytes
+    #[arg(long, env = "MAX_HEADER_BYTES", default_value = "65536")]
+    max_header_bytes: usize,
+
+    /// Longest accepted request line in bytes
+    #[arg(long, env = "MAX_REQUEST_LINE", default_value = "8192")]
+    max_request_line: usize,
+
+    /// Seconds a client gets to send the full request head
+    #[arg(long, env = "HEADER_READ_TIMEOUT", default_value = "30")]
+    header_read_timeout: u64,
+
+    /// Reject requests carrying both Content-Length and Transfer-Encoding
+    #[arg(long, env = "REJECT_AMBIGUOUS_FRAMING", default_value = "true", action = clap::ArgAction::Set)]
+    reject_ambiguous_framing: bool,
+
+    /// Repeated Host, Content-Length or Transfer-Encoding: collapse identical copies, or refuse any (strict)
+    #[arg(long, env = "DUPLICATE_HEADERS", default_value = "collapse")]
+    duplicate_headers: DuplicateHeaders,
+
+    /// Seconds in-flight requests and WebSocket sessions may keep using a removed or changed route
+    #[arg(long, env = "DRAIN_TIMEOUT", default_value = "30")]
+    drain_timeout: u64,
+
+    /// Seconds between rescans of the certs directory for new or renewed certificates (0 = off)
+    #[arg(long, env = "CERT_RELOAD_INTERVAL", default_value = "5")]
+    cert_reload_interval: u64,
+
+    /// Bearer token enabling the admin API under /_proxy/admin/
+    #[arg(long, env = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// Level access lines are written at: off, error, warn, info, debug, trace
+    #[arg(long, env = "ACCESS_LOG_LEVEL", default_value = "info")]
+    access_log_level: AccessLevel,
+
+    /// Share of requests logged per status class, e.g. "2xx=1%,3xx=10%" (default: all)
+    #[arg(long, env = "ACCESS_LOG_SAMPLE", default_value = "")]
+    access_log_sample: Sampling,
+
+    /// Most open client connections (0 = no cap)
+    #[arg(long, env = "MAX_CONNECTIONS", default_value = "0")]
+    max_connections: usize,
+
+    /// File descriptors kept free below the process limit
+    #[arg(long, env = "FD_RESERVE", default_value = "64")]
+    fd_reserve: usize,
+
+    /// At the connection cap: reject (close, 503 on HTTP) or pause accepting
+    #[arg(long, env = "CONNECTION_LIMIT_ACTION", default_value = "reject")]
+    connection_limit_action: LimitAction,
+
+    /// Most seconds a client deadline (X-Request-Timeout, grpc-timeout) may ask for (0 = ignore them)
+    #[arg(long, env = "MAX_REQUEST_TIMEOUT", default_value = "300")]
+    max_request_timeout: u64,
+
+    /// Largest response body (bytes) mapping body rewrites and HTML base paths are applied to
+    #[arg(long, env = "BODY_REWRITE_MAX_BYTES", default_value = "1048576")]
+    body_rewrite_max_bytes: usize,
+
+    /// Default requests per client IP and domain, e.g. 100/1m (unset = unlimited)
+    #[arg(long, env = "RATE_LIMIT")]
+    rate_limit: Option<RateLimit>,
+
+    /// Ban clients that keep hitting their rate limit, e.g. strikes=20,within=10m,duration=1h
+    #[arg(long, env = "RATE_LIMIT_BAN")]
+    rate_limit_ban: Option<BanPolicy>,
+
+    /// Ban clients that keep getting 401/403 from a mapping, e.g. strikes=10,within=5m,duration=1h
+    #[arg(long, env = "AUTH_FAILURE_BAN")]
+    auth_failure_ban: Option<BanPolicy>,
+
+    /// Comma-separated IPs/CIDRs of load balancers whose X-Forwarded-For is believed (unset: nobody's)
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Vec<Network>,
+
+    /// redis:// URL where all instances count rate-limited requests together
+    #[arg(long, env = "REDIS_URL")]
+    redis_url: Option<String>,
+
+    /// Experimental cluster mode: comma-separated host:port of the other nodes' HTTP listeners
+    #[arg(long, env = "CLUSTER_PEERS", value_delimiter = ',')]
+    cluster_peers: Vec<String>,
+
+    /// Shared secret cluster nodes authenticate each other with
+    #[arg(long, env = "CLUSTER_SECRET", hide_env_values = true)]
+    cluster_secret: Option<String>,
+
+    /// Seconds between pulls of newer state from each peer
+    #[arg(long, env = "CLUSTER_INTERVAL", default_value = "2")]
+    cluster_interval: u64,
+
+    /// Shell command renewing certificates into CERTS_DIR (e.g. certbot renew), run by one node at a time
+    #[arg(long, env = "CERT_RENEW_COMMAND")]
+    cert_renew_command: Option<String>,
+
+    /// Seconds between runs of CERT_RENEW_COMMAND
+    #[arg(long, env = "CERT_RENEW_INTERVAL", default_value = "43200")]
+    cert_renew_interval: u64,
+
+    /// Bytes of memory for responses kept for mappings with stale-if-error
+    #[arg(long, env = "RESPONSE_CACHE_MAX_BYTES", default_value = "67108864")]
+    response_cache_max_bytes: usize,
+
+    /// Directory responses kept for stale-if-error are also written to, surviving restarts
+    #[arg(long, env = "RESPONSE_CACHE_DIR")]
+    response_cache_dir: Option<PathBuf>,
+
+    /// Bytes of disk for RESPONSE_CACHE_DIR; least recently used responses are removed past it
+    #[arg(long, env = "RESPONSE_CACHE_DISK_MAX_BYTES", default_value = "1073741824")]
+    response_cache_disk_max_bytes: u64,
+
+    /// Responses kept in RESPONSE_CACHE_DIR at most
+    #[arg(long, env = "RESPONSE_CACHE_DISK_MAX_ENTRIES", default_value = "100000")]
+    response_cache_disk_max_entries: usize,
+
+    /// Seconds between outlier checks of HA targets (0 disables outlier ejection)
+    #[arg(long, env = "OUTLIER_INTERVAL", default_value = "10")]
+    outlier_interval: u64,
+
+    /// Base seconds an outlier stays ejected (grows with repeated ejections)
+    #[arg(long, env = "OUTLIER_COOLDOWN", default_value = "30")]
+    outlier_cooldown: u64,
+
+    /// Alert (log and event) when a mapping's error rate or latency departs from its baseline
+    #[arg(long, env = "ANOMALY_ALERTS", default_value = "true", action = clap::ArgAction::Set)]
+    anomaly_alerts: bool,
+
+    /// Proxy for backend connections: socks5://, socks5h:// or http:// (CONNECT), with optional user:password@
+    #[arg(long, env = "EGRESS_PROXY", hide_env_values = true)]
+    egress_proxy: Option<EgressProxy>,
+
+    /// Connections open at once to one backend target of a mapping (0: unlimited)
+    #[arg(long, env = "POOL_MAX_CONNECTIONS", default_value = "0")]
+    pool_max_connections: usize,
+
+    /// Idle backend connections kept per mapping target for reuse (0: no keep-alive)
+    #[arg(long, env = "POOL_MAX_IDLE", default_value = "32")]
+    pool_max_idle: usize,
+
+    /// Seconds an idle backend connection is kept
+    #[arg(long, env = "POOL_IDLE_TIMEOUT", default_value = "90")]
+    pool_idle_timeout: u64,
+
+    /// Seconds after which a backend connection is not reused (0: no limit)
+    #[arg(long, env = "POOL_MAX_LIFETIME", default_value = "0")]
+    pool_max_lifetime: u64,
+
+    /// Socket options of listeners and client connections, e.g. nodelay,keepalive=60,recv_buffer=256K
+    #[arg(long, env = "CLIENT_TCP_OPTIONS", default_value = "")]
+    client_tcp_options: TcpOptions,
+
+    /// Socket options of backend connections, e.g. nodelay,keepalive=30,keepalive_interval=10,keepalive_retries=3
+    #[arg(long, env = "BACKEND_TCP_OPTIONS", default_value = "")]
+    backend_tcp_options: TcpOptions,
+
+    /// Address family tried first for backends with both: ipv6, ipv4, ipv6-only or ipv4-only
+    #[arg(long, env = "BACKEND_IP_PREFERENCE", default_value = "ipv6")]
+    backend_ip_preference: IpPreference,
+
+    /// Milliseconds a backend connection attempt runs before the next address is tried alongside
+    #[arg(long, env = "BACKEND_CONNECT_ATTEMPT_DELAY", default_value = "250")]
+    backend_connect_attempt_delay: u64,
+
+    /// Key OIDC session cookies are encrypted with (same on every instance; unset: random per process)
+    #[arg(long, env = "SESSION_SECRET", hide_env_values = true)]
+    session_secret: Option<String>,
+
+    /// Seconds an OIDC login lasts
+    #[arg(long, env = "OIDC_SESSION_TTL", default_value = "28800")]
+    oidc_session_ttl: u64,
+
+    /// Where login sessions are kept: cookie, memory, sqlite or redis (REDIS_URL)
+    #[arg(long, env = "SESSION_STORE", default_value = "cookie")]
+    session_store: session::Backend,
+
+    /// URLs (comma-separated) routing, health, certificate and rate-limit events are POSTed to
+    #[arg(long, env = "WEBHOOK_URLS", value_delimiter = ',')]
+    webhook_urls: Vec<String>,
+
+    /// Key webhook bodies are HMAC-SHA256 signed with (X-Webhook-Signature)
+    #[arg(long, env = "WEBHOOK_SECRET", hide_env_values = true)]
+    webhook_secret: Option<String>,
+
+    /// Recent events kept for GET /_proxy/admin/events (0: none)
+    #[arg(long, env = "EVENT_LOG_SIZE", default_value = "1000")]
+    event_log_size: usize,
+
+    /// Weak ETags (and 304s) for successful GET responses the backend sent without validators
+    #[arg(long, env = "GENERATE_ETAGS", default_value = "false")]
+    generate_etags: bool,
+
+    /// IPs/CIDRs (comma-separated) /health answers; others are routed like any path
+    #[arg(long, env = "HEALTH_ALLOWED_IPS")]
+    health_allowed_ips: Option<String>,
+
+    /// Internal address (e.g. 127.0.0.1:8081) serving /health and the admin API instead of the public ports
+    #[arg(long, env = "INTERNAL_LISTEN")]
+    internal_listen: Option<SocketAddr>,
+
+    /// Answer ACME http-01 challenges only for hosts with a mapping
+    #[arg(long, env = "ACME_CHALLENGES_MAPPED_ONLY", default_value = "false")]
+    acme_challenges_mapped_only: bool,
+
+    /// Proxies of ours a request may already have passed before it gets 508 Loop Detected (0: no limit)
+    #[arg(long, env = "MAX_PROXY_HOPS", default_value = "10")]
+    max_proxy_hops: u32,
+
+    /// Seconds between writes of per-mapping request and byte counts to the usage table
+    #[arg(long, env = "USAGE_FLUSH_INTERVAL", default_value = "10")]
+    usage_flush_interval: u64,
+
+    /// Route tenants' mappings only once the tenant has proven control of the domain (rustproxy-mapping verify)
+    #[arg(long, env = "REQUIRE_DOMAIN_VERIFICATION", default_value = "false")]
+    require_domain_verification: bool,
+
+    /// Seconds between synthetic probes of every backend, kept as uptime history (0 = off)
+    #[arg(long, env = "PROBE_INTERVAL", default_value = "0")]
+    probe_interval: u64,
+
+    /// Seconds probe results are kept
+    #[arg(long, env = "PROBE_RETENTION", default_value = "604800")]
+    probe_retention: u64,
+
+    /// Fingerprinting headers removed from backend responses: off, basic or strict (mappings may override)
+    #[arg(long, env = "SCRUB_RESPONSE_HEADERS", default_value = "off")]
+    scrub_response_headers: ScrubProfile,
+
+    /// Secret that, sent in an X-RustProxy-Debug request header, gets a routing trace back
+    #[arg(long, env = "DEBUG_SECRET")]
+    debug_secret: Option<String>,
+
+    /// PEM bundle of CAs whose client certificates the HTTPS listener asks for and verifies (optional for clients)
+    #[arg(long, env = "TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Pass TLS version, cipher, SNI and client certificate subject to backends in X-TLS-* / X-Client-Cert-DN
+    #[arg(long, env = "FORWARD_TLS_HEADERS", default_value = "false")]
+    forward_tls_headers: bool,
+
+    /// Pass verified client certificates to backends in X-Forwarded-Client-Cert: hash plus any of subject,uri,dns,cert,chain
+    #[arg(long, env = "FORWARD_CLIENT_CERT")]
+    forward_client_cert: Option<ClientCertDetails>,
+
+    /// Address (e.g. 10.0.0.5:3128) of a forward-proxy listener taking CONNECT tunnels from internal clients (unset: off)
+    #[arg(long, env = "FORWARD_PROXY_LISTEN")]
+    forward_proxy_listen: Option<SocketAddr>,
+
+    /// Comma-separated IPs/CIDRs that may open tunnels on FORWARD_PROXY_LISTEN
+    #[arg(long, env = "FORWARD_PROXY_CLIENTS", value_delimiter = ',', default_value = forward_proxy::DEFAULT_CLIENTS)]
+    forward_proxy_clients: Vec<Network>,
+
+    /// Comma-separated host:port destinations tunnels may go to (*.example.com:443, 10.0.0.0/8:5432, *:*)
+    #[arg(long, env = "FORWARD_PROXY_DESTINATIONS", value_delimiter = ',', default_value = forward_proxy::DEFAULT_DESTINATIONS)]
+    forward_proxy_destinations: Vec<Destination>,
+
+    /// Where every request is also written for `rustproxy stats`: sqlite:///path.db or clickhouse://[user:password@]host:8123/db
+    #[arg(long, env = "ANALYTICS_SINK", hide_env_values = true)]
+    analytics_sink: Option<Sink>,
+
+    /// Seconds analytics rows are kept
+    #[arg(long, env = "ANALYTICS_RETENTION", default_value = "2592000")]
+    analytics_retention: u64,
+
+    /// Seconds between batched writes to the analytics sink
+    #[arg(long, env = "ANALYTICS_FLUSH_INTERVAL", default_value = "5")]
+    analytics_flush_interval: u64,
+
+    /// Serve ACME under /_proxy/acme/ from an internal CA (certs_dir/internal-ca)
+    #[arg(long, env = "ACME_SERVER", default_value = "false")]
+    acme_server: bool,
+
+    /// Comma-separated name suffixes the internal ACME server issues for (empty: any)
+    #[arg(long, env = "ACME_SERVER_DOMAINS", value_delimiter = ',')]
+    acme_server_domains: Vec<String>,
+
+    /// Port the internal ACME server fetches http-01 challenges on
+    #[arg(long, env = "ACME_SERVER_CHALLENGE_PORT", default_value = "80")]
+    acme_server_challenge_port: u16,
+
     #[arg(long, env = "LOG_LEVEL", default_value = "info")]
     log_level: String,
 
@@ -57,15 +395,285 @@ struct Args {
 
     #[arg(long)]
     production: bool,
+
+    /// Run the startup self-check, print its JSON report and exit (1 if any check failed)
+    #[arg(long)]
+    check_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Drive synthetic load through a running instance and report RPS and latency
+    Bench {
+        /// Proxy URL to load, e.g. http://127.0.0.1:8080/api/test
+        #[arg(long)]
+        target: hyper::Uri,
+
+        /// Host header to send (selects the mapping)
+        #[arg(long)]
+        host: Option<String>,
+
+        /// HTTP method
+        #[arg(long, default_value = "GET")]
+        method: hyper::Method,
+
+        /// Request body
+        #[arg(long)]
+        body: Option<String>,
+
+        /// Concurrent keep-alive connections
+        #[arg(short, long, default_value = "50")]
+        connections: usize,
+
+        /// Seconds to run
+        #[arg(short, long, default_value = "10")]
+        duration: u64,
+    },
+    /// Certificate tools
+    Cert {
+        #[command(subcommand)]
+        action: CertAction,
+    },
+    /// Answer every request with its method, path, headers and body as JSON, to check what a mapping sends
+    Echo {
+        /// Port to listen on
+        #[arg(long)]
+        port: u16,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: std::net::IpAddr,
+
+        /// Name included in every echo, to tell instances behind one mapping apart
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Re-issue the requests in an access log against another instance or a staging backend
+    Replay {
+        /// Log file with `access method=...` lines; `-` reads stdin
+        log: PathBuf,
+
+        /// URL the logged paths are appended to, e.g. http://staging:8080
+        #[arg(long)]
+        target: hyper::Uri,
+
+        /// Host header to send instead of the logged host
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Pace relative to the log: 2 is twice as fast, 0 sends as fast as possible
+        #[arg(long, default_value = "1")]
+        speed: f64,
+
+        /// Requests in flight at most
+        #[arg(short, long, default_value = "50")]
+        concurrency: usize,
+
+        /// Also replay POST, PUT, DELETE... (with empty bodies; the log has none)
+        #[arg(long)]
+        all_methods: bool,
+
+        /// Exit with an error when any status differs from the logged one
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Save the state snapshot (config, mappings, certificates, backend health) of a running instance
+    Snapshot {
+        /// Admin base URL, e.g. http://127.0.0.1:8081 (the internal listener)
+        #[arg(long)]
+        url: hyper::Uri,
+
+        /// Admin API token
+        #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+        token: String,
+
+        /// File to write instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Summarize recent traffic from the analytics sink: volume, statuses, latency, top paths
+    Stats {
+        /// Analytics sink the proxy writes to
+        #[arg(long, env = "ANALYTICS_SINK", hide_env_values = true)]
+        sink: Sink,
+
+        /// Only this host (default: all)
+        #[arg(long)]
+        domain: Option<String>,
+
+        /// Span to look back over, e.g. 30m, 24h, 7d
+        #[arg(long, default_value = "24h", value_parser = analytics::parse_since)]
+        since: std::time::Duration,
+
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Follow a running instance's traffic live: requests with status, latency and backend
+    Tail {
+        /// Admin base URL, e.g. http://127.0.0.1:8081 (the internal listener)
+        #[arg(long)]
+        url: hyper::Uri,
+
+        /// Admin API token
+        #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+        token: String,
+
+        /// Only this host (default: all)
+        #[arg(long)]
+        domain: Option<String>,
+
+        /// Only these statuses, e.g. 5xx or 404,429
+        #[arg(long)]
+        status: Option<StatusFilter>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CertAction {
+    /// Check DNS, port 80/443 reachability and CAA for a domain before ordering a certificate
+    Preflight {
+        /// Domain the certificate is for
+        domain: String,
+
+        /// CAA identifier of the CA that will issue
+        #[arg(long, default_value = "letsencrypt.org")]
+        ca: String,
+
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn run_command(command: Command, db_path: &Path) -> Result<()> {
+    match command {
+        Command::Bench { target, host, method, body, connections, duration } => run_bench(LoadConfig {
+            target,
+            host,
+            method,
+            body: body.map(bytes::Bytes::from).unwrap_or_default(),
+            connections,
+            duration: std::time::Duration::from_secs(duration),
+        }),
+        Command::Cert { action: CertAction::Preflight { domain, ca, json } } => run_cert_preflight(db_path, &domain, &ca, json),
+        Command::Echo { port, host, name } => run_echo(SocketAddr::new(host, port), name),
+        Command::Replay { log, target, host, speed, concurrency, all_methods, strict } => {
+            run_replay(&log, ReplayConfig { target, host, speed, concurrency, all_methods }, strict)
+        }
+        Command::Snapshot { url, token, output } => run_snapshot(&url, &token, output.as_deref()),
+        Command::Stats { sink, domain, since, json } => run_stats(&sink, domain.as_deref(), since, json),
+        Command::Tail { url, token, domain, status } => run_tail(&url, &token, tail::Filter { domain, status }),
+    }
+}
+
+fn run_bench(config: LoadConfig) -> Result<()> {
+    println!(
+        "Running {}s test @ {} with {} connection(s)",
+        config.duration.as_secs(),
+        config.target,
+        config.connections,
+    );
+    let report = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(rustproxy::bench::run(config))?;
+    println!("{}", report);
+    Ok(())
+}
+
+fn run_echo(addr: SocketAddr, name: Option<String>) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let listener = TcpListener::bind(addr).await.with_context(|| format!("binding {}", addr))?;
+            println!("Echoing requests on http://{}", listener.local_addr()?);
+            rustproxy::echo::serve(listener, name).await;
+            Ok(())
+        })
+}
+
+fn run_replay(log: &Path, config: ReplayConfig, strict: bool) -> Result<()> {
+    let text = if log.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(log).with_context(|| format!("reading {}", log.display()))?
+    };
+    let entries = rustproxy::replay::parse(&text);
+    if entries.is_empty() {
+        bail!("no access log lines in {}", log.display());
+    }
+    println!("Replaying {} request(s) @ {} at {}x speed", entries.len(), config.target, config.speed);
+    let report = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(rustproxy::replay::run(config, entries))?;
+    println!("{}", report);
+    if strict && report.mismatches() > 0 {
+        bail!("{} response(s) differed from the log", report.mismatches());
+    }
+    Ok(())
+}
+
+fn run_snapshot(url: &hyper::Uri, token: &str, output: Option<&Path>) -> Result<()> {
+    let snapshot = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(rustproxy::snapshot::fetch(url, token))?;
+    match output {
+        Some(path) => std::fs::write(path, snapshot).with_context(|| format!("writing {}", path.display()))?,
+        None => println!("{}", snapshot),
+    }
+    Ok(())
+}
+
+fn run_cert_preflight(db_path: &Path, domain: &str, ca: &str, json: bool) -> Result<()> {
+    let db = DatabaseManager::new(db_path)?;
+    let report = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(rustproxy::cert_preflight::run(&db, domain, ca))?;
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => print!("{}", report),
+    }
+    if !report.passed() {
+        bail!("preflight failed for {}", report.domain);
+    }
+    Ok(())
+}
+
+fn run_stats(sink: &Sink, domain: Option<&str>, since: std::time::Duration, json: bool) -> Result<()> {
+    let stats = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(analytics::stats(sink, domain, since))?;
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&stats)?),
+        false => print!("{}", stats),
+    }
+    Ok(())
+}
+
+fn run_tail(url: &hyper::Uri, token: &str, filter: tail::Filter) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(tail::follow(url, token, &filter, |line| println!("{}", line)))
 }
 
 /// Bind a TCP socket with SO_REUSEPORT so multiple threads can listen on the same address.
-fn bind_reuseport(addr: SocketAddr) -> std::io::Result<TcpListener> {
+fn bind_reuseport(addr: SocketAddr, options: &TcpOptions) -> std::io::Result<TcpListener> {
     let domain = if addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
     let socket = Socket::new(domain, Type::STREAM, None)?;
     socket.set_reuse_address(true)?;
     socket.set_reuse_port(true)?;
     socket.set_nonblocking(true)?;
+    options.apply_buffers(&socket)?;
     socket.bind(&addr.into())?;
     socket.listen(1024)?;
     let std_listener: StdListener = socket.into();
@@ -81,6 +689,10 @@ fn default_workers() -> usize {
 fn main() -> Result<()> {
     let mut args = Args::parse();
 
+    if let Some(command) = args.command.take() {
+        return run_command(command, &args.db_path);
+    }
+
     if args.production {
         args.http_port = 80;
         args.https_port = 443;
@@ -95,7 +707,14 @@ fn main() -> Result<()> {
         _       => Level::INFO,
     };
 
-    let _subscriber = FmtSubscriber::builder()
+    // --check-config keeps stdout for the report
+    let writer = if args.check_config {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    FmtSubscriber::builder()
+        .with_writer(writer)
         .with_max_level(log_level)
         .with_target(false)
         .with_thread_ids(true)   // show thread id so workers are distinguishable
@@ -120,18 +739,121 @@ fn main() -> Result<()> {
         enable_https: args.enable_https,
         force_https:  args.force_https,
         http_host:    args.http_host.clone(),
+        consul_addr:   args.consul_addr.clone(),
+        consul_token:  args.consul_token.clone(),
+        etcd_endpoint: args.etcd_endpoint.clone(),
+        srv_nameserver: args.srv_nameserver,
+        rollback_error_rate:   args.rollback_error_rate,
+        rollback_min_requests: args.rollback_min_requests,
+        path_normalization: PathNormalization {
+            merge_slashes:    args.merge_slashes,
+            percent_decoding: args.percent_decoding,
+            trailing_slash:   args.trailing_slash,
+        },
+        max_headers:              args.max_headers,
+        max_header_bytes:         args.max_header_bytes,
+        max_request_line:         args.max_request_line,
+        header_read_timeout:      std::time::Duration::from_secs(args.header_read_timeout),
+        reject_ambiguous_framing: args.reject_ambiguous_framing,
+        duplicate_headers:        args.duplicate_headers,
+        drain_timeout:            std::time::Duration::from_secs(args.drain_timeout),
+        cert_reload_interval:     std::time::Duration::from_secs(args.cert_reload_interval),
+        admin_token:              args.admin_token,
+        access_log: LogPolicy {
+            level:    args.access_log_level,
+            sampling: args.access_log_sample,
+        },
+        max_connections:          args.max_connections,
+        fd_reserve:               args.fd_reserve,
+        connection_limit_action:  args.connection_limit_action,
+        max_request_timeout:      std::time::Duration::from_secs(args.max_request_timeout),
+        body_rewrite_max_bytes:   args.body_rewrite_max_bytes,
+        rate_limit:               args.rate_limit,
+        rate_limit_ban:           args.rate_limit_ban,
+        auth_failure_ban:         args.auth_failure_ban,
+        trusted_proxies:          args.trusted_proxies,
+        redis_url:                args.redis_url,
+        cluster_peers:            args.cluster_peers,
+        cluster_secret:           args.cluster_secret,
+        cluster_interval:         std::time::Duration::from_secs(args.cluster_interval.max(1)),
+        cert_renew_command:       args.cert_renew_command,
+        cert_renew_interval:      std::time::Duration::from_secs(args.cert_renew_interval),
+        response_cache_max_bytes: args.response_cache_max_bytes,
+        response_cache_dir:       args.response_cache_dir,
+        response_cache_disk_max_bytes: args.response_cache_disk_max_bytes,
+        response_cache_disk_max_entries: args.response_cache_disk_max_entries,
+        outlier_interval:         std::time::Duration::from_secs(args.outlier_interval),
+        outlier_cooldown:         std::time::Duration::from_secs(args.outlier_cooldown.max(1)),
+        anomaly_alerts:           args.anomaly_alerts,
+        egress_proxy:             args.egress_proxy,
+        upstream_pool:            PoolSettings {
+            max_connections: args.pool_max_connections,
+            max_idle:        args.pool_max_idle,
+            idle_timeout:    std::time::Duration::from_secs(args.pool_idle_timeout),
+            max_lifetime:    std::time::Duration::from_secs(args.pool_max_lifetime),
+        },
+        client_tcp:               args.client_tcp_options,
+        backend_tcp:              args.backend_tcp_options,
+        backend_ip_preference:    args.backend_ip_preference,
+        backend_connect_attempt_delay: std::time::Duration::from_millis(args.backend_connect_attempt_delay.max(10)),
+        session_secret:           args.session_secret,
+        oidc_session_ttl:         std::time::Duration::from_secs(args.oidc_session_ttl.max(60)),
+        session_store:            args.session_store,
+        acme_server:              args.acme_server,
+        acme_server_domains:      args.acme_server_domains,
+        acme_server_challenge_port: args.acme_server_challenge_port,
+        webhook_urls:             args.webhook_urls,
+        webhook_secret:           args.webhook_secret,
+        event_log_size:           args.event_log_size,
+        generate_etags:           args.generate_etags,
+        health_allowed_ips:       args.health_allowed_ips,
+        internal_listen:          args.internal_listen,
+        acme_challenges_mapped_only: args.acme_challenges_mapped_only,
+        max_proxy_hops:           args.max_proxy_hops,
+        usage_flush_interval:     std::time::Duration::from_secs(args.usage_flush_interval.max(1)),
+        require_domain_verification: args.require_domain_verification,
+        probe_interval:           std::time::Duration::from_secs(args.probe_interval),
+        probe_retention:          std::time::Duration::from_secs(args.probe_retention),
+        scrub_response_headers:   args.scrub_response_headers,
+        debug_secret:             args.debug_secret,
+        tls_client_ca:            args.tls_client_ca,
+        forward_tls_headers:      args.forward_tls_headers,
+        forward_client_cert:      args.forward_client_cert,
+        forward_proxy_listen:     args.forward_proxy_listen,
+        forward_proxy_acl:        Acl { clients: args.forward_proxy_clients, destinations: args.forward_proxy_destinations },
+        analytics_sink:           args.analytics_sink,
+        analytics_retention:      std::time::Duration::from_secs(args.analytics_retention.max(60)),
+        analytics_flush_interval: std::time::Duration::from_secs(args.analytics_flush_interval.max(1)),
     };
 
     let server = Arc::new(ProxyServer::new(config, db_manager, cert_manager));
 
+    let report = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(server.self_check(true));
+    if args.check_config {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        std::process::exit(if report.ok { 0 } else { 1 });
+    }
+    info!("Self-check: {}", serde_json::to_string(&report)?);
+    for failed in report.failures() {
+        warn!("Self-check failed: {} {}: {}", failed.check, failed.subject, failed.detail.as_deref().unwrap_or("-"));
+    }
+    match server.lint_routes() {
+        Ok(findings) => findings.iter().for_each(|f| warn!("Route conflict: {}", f)),
+        Err(e) => warn!("Route conflict check failed: {:#}", e),
+    }
+
     let http_addr: SocketAddr = format!("{}:{}", args.http_host, args.http_port).parse()?;
+    let https_addr: SocketAddr = format!("{}:{}", args.http_host, args.https_port).parse()?;
 
     if n_workers == 1 {
         // Single-worker path: plain bind (no SO_REUSEPORT needed)
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()?
-            .block_on(server.run())?;
+            .block_on(async { server.run().await?.wait().await })?;
     } else {
         // Multi-worker: each OS thread gets its own SO_REUSEPORT listener and Tokio runtime,
         // mirroring Node.js cluster where each worker has its own event loop.
@@ -140,6 +862,8 @@ fn main() -> Result<()> {
         for worker_id in 0..n_workers {
             let s = server.clone();
             let addr = http_addr;
+            let client_tcp = args.client_tcp_options;
+            let https_addr = args.enable_https.then_some(https_addr);
 
             handles.push(std::thread::Builder::new()
                 .name(format!("worker-{}", worker_id))
@@ -149,9 +873,17 @@ fn main() -> Result<()> {
                         .build()?;
 
                     rt.block_on(async move {
-                        let listener = bind_reuseport(addr)
+                        let listener = bind_reuseport(addr, &client_tcp)
                             .map_err(|e| anyhow::anyhow!("SO_REUSEPORT bind failed: {}", e))?;
-                        s.run_with_listener(listener).await
+                        match https_addr {
+                            Some(https_addr) => {
+                                let tls_listener = bind_reuseport(https_addr, &client_tcp)
+                                    .map_err(|e| anyhow::anyhow!("SO_REUSEPORT bind failed: {}", e))?;
+                                tokio::try_join!(s.clone().run_with_listener(listener), s.run_tls_with_listener(tls_listener))?;
+                                Ok(())
+                            }
+                            None => s.run_with_listener(listener).await,
+                        }
                     })
                 })?);
         }
@@ -0,0 +1,405 @@
+//! Mappings as CSV
+//! `rustproxy-mapping export` writes the routing-relevant columns of each mapping as one
+//! spreadsheet row; `import` reads such a file back. Every cell is checked as strictly as
+//! the CLI checks the same setting, the whole file is reported at once, and the result is a
+//! [`Plan`]: the adds, updates (only the cells that differ, against the exported `version`)
+//! and — when asked — deletes, with a preview of each, applied in one transaction by
+//! [`DatabaseManager::apply_changes`](crate::database::DatabaseManager::apply_changes).
+//! Secrets, JSON-valued settings and columns not listed here are never exported and are
+//! left as they are on import
+
+use crate::access_hours::AccessHours;
+use crate::database::{Mapping, MappingChange};
+use crate::ratelimit::RateLimit;
+use crate::usage::Quota;
+use anyhow::Result;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+
+/// The columns, in file order; `domain` is the only one a file must have
+pub const COLUMNS: [&str; 18] = [
+    "id",
+    "version",
+    "domain",
+    "front_uri",
+    "back_port",
+    "back_uri",
+    "backend",
+    "back_ports",
+    "allowed_ips",
+    "rate_limit",
+    "fallback_backend",
+    "max_body_bytes",
+    "preserve_path",
+    "quota",
+    "owner",
+    "activate_at",
+    "deactivate_at",
+    "access_hours",
+];
+
+/// The cell of `column` for `m`; unset settings are empty.
+fn cell(m: &Mapping, column: &str) -> String {
+    let text = |v: &Option<String>| v.clone().unwrap_or_default();
+    match column {
+        "id" => m.id.clone(),
+        "version" => m.version.to_string(),
+        "domain" => m.domain.clone(),
+        "front_uri" => m.front_uri.clone(),
+        "back_port" => m.back_port.to_string(),
+        "back_uri" => m.back_uri.clone(),
+        "backend" => text(&m.backend),
+        "back_ports" => text(&m.back_ports),
+        "allowed_ips" => text(&m.allowed_ips),
+        "rate_limit" => text(&m.rate_limit),
+        "fallback_backend" => text(&m.fallback_backend),
+        "max_body_bytes" => m.max_body_bytes.map(|n| n.to_string()).unwrap_or_default(),
+        "preserve_path" => m.preserve_path.to_string(),
+        "quota" => text(&m.quota),
+        "owner" => text(&m.owner),
+        "activate_at" => text(&m.activate_at),
+        "deactivate_at" => text(&m.deactivate_at),
+        "access_hours" => text(&m.access_hours),
+        _ => String::new(),
+    }
+}
+
+/// Check the cell of a settable `column`: its canonical text and the value stored.
+fn parse_cell(column: &str, raw: &str) -> Result<(String, Value), String> {
+    let s = raw.trim();
+    let canonical = match column {
+        "domain" => crate::host::normalize_domain(s).ok_or_else(|| format!("invalid domain '{}'", s))?.into_owned(),
+        "front_uri" | "back_uri" => s.trim_start_matches('/').trim_end_matches('/').to_string(),
+        "back_port" => {
+            let port: u16 = s.parse().map_err(|_| format!("invalid port '{}'", s))?;
+            return Ok((port.to_string(), Value::from(port)));
+        }
+        "max_body_bytes" if !s.is_empty() => {
+            let n: u64 = s.parse().map_err(|_| format!("'{}' is not a number of bytes", s))?;
+            return Ok((n.to_string(), Value::from(n)));
+        }
+        "preserve_path" => {
+            let on = match s.to_ascii_lowercase().as_str() {
+                "" | "false" | "no" | "0" => false,
+                "true" | "yes" | "1" => true,
+                _ => return Err(format!("expected true or false, got '{}'", s)),
+            };
+            return Ok((on.to_string(), Value::Bool(on)));
+        }
+        "back_ports" => {
+            let ports: Vec<u16> = s
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| p.parse().map_err(|_| format!("invalid port '{}'", p)))
+                .collect::<Result<_, _>>()?;
+            ports.iter().map(u16::to_string).collect::<Vec<_>>().join(",")
+        }
+        "allowed_ips" => {
+            let entries: Vec<&str> = s.split(',').map(str::trim).filter(|e| !e.is_empty()).collect();
+            for entry in &entries {
+                let (ip, bits) = entry.split_once('/').map_or((*entry, None), |(ip, bits)| (ip, Some(bits)));
+                let ip: IpAddr = ip.parse().map_err(|_| format!("'{}' is not an IP address or CIDR range", entry))?;
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+                if bits.is_some_and(|b| b.parse::<u8>().map_or(true, |b| b > max)) {
+                    return Err(format!("'{}' is not an IP address or CIDR range", entry));
+                }
+            }
+            entries.join(",")
+        }
+        "rate_limit" if !s.is_empty() && !s.eq_ignore_ascii_case("off") => {
+            s.parse::<RateLimit>()?;
+            s.to_string()
+        }
+        "rate_limit" => s.to_ascii_lowercase(),
+        "fallback_backend" if !s.is_empty() => {
+            let probe = Mapping { fallback_backend: Some(s.to_string()), ..Default::default() };
+            if probe.fallback().is_none() {
+                return Err(format!("'{}' is not a host:port or http(s) URL", s));
+            }
+            s.to_string()
+        }
+        "quota" if !s.is_empty() => s.parse::<Quota>()?.to_string(),
+        "access_hours" if !s.is_empty() => s.parse::<AccessHours>()?.to_string(),
+        "activate_at" | "deactivate_at" if !s.is_empty() => {
+            chrono::DateTime::parse_from_rfc3339(s).map_err(|_| format!("'{}' is not an RFC 3339 time", s))?;
+            s.to_string()
+        }
+        _ => s.to_string(),
+    };
+    // Empty cells unset optional settings
+    let value = match column {
+        "domain" | "front_uri" | "back_uri" => Value::String(canonical.clone()),
+        _ if canonical.is_empty() => Value::Null,
+        _ => Value::String(canonical.clone()),
+    };
+    Ok((canonical, value))
+}
+
+/// Write `mappings` as CSV with a header row.
+pub fn write(mappings: &[Mapping], out: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(COLUMNS)?;
+    for m in mappings {
+        writer.write_record(COLUMNS.iter().map(|c| cell(m, c)))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// One row of an imported file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    /// Line in the file, for messages
+    pub line: u64,
+    pub id: Option<String>,
+    pub version: Option<u64>,
+    /// Checked cells by column, in canonical form
+    pub cells: Vec<(&'static str, String, Value)>,
+}
+
+impl Row {
+    fn get(&self, column: &str) -> Option<&str> {
+        self.cells.iter().find(|(c, _, _)| *c == column).map(|(_, text, _)| text.as_str())
+    }
+}
+
+/// Read a CSV file with a header row of [`COLUMNS`] (in any order, any subset with
+/// `domain`). Every problem in the file is returned, by line.
+pub fn read(input: impl Read) -> Result<Vec<Row>, Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input);
+    let headers = reader.headers().map_err(|e| vec![e.to_string()])?.clone();
+    let mut problems = Vec::new();
+    let mut columns = Vec::new();
+    for name in headers.iter() {
+        match COLUMNS.iter().find(|c| c.eq_ignore_ascii_case(name)) {
+            Some(c) if columns.contains(c) => problems.push(format!("line 1: column '{}' appears twice", c)),
+            Some(c) => columns.push(*c),
+            None => problems.push(format!("line 1: unknown column '{}' (known: {})", name, COLUMNS.join(", "))),
+        }
+    }
+    if !columns.contains(&"domain") {
+        problems.push("line 1: the 'domain' column is required".to_string());
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                problems.push(e.to_string());
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        if record.iter().all(str::is_empty) {
+            continue;
+        }
+        let mut row = Row { line, id: None, version: None, cells: Vec::new() };
+        for (column, raw) in columns.iter().zip(record.iter()) {
+            match *column {
+                "id" => row.id = Some(raw.to_string()).filter(|id| !id.is_empty()),
+                "version" if raw.is_empty() => {}
+                "version" => match raw.parse() {
+                    Ok(v) => row.version = Some(v),
+                    Err(_) => problems.push(format!("line {}: version: '{}' is not a number", line, raw)),
+                },
+                _ => match parse_cell(column, raw) {
+                    Ok((text, value)) => row.cells.push((column, text, value)),
+                    Err(e) => problems.push(format!("line {}: {}: {}", line, column, e)),
+                },
+            }
+        }
+        rows.push(row);
+    }
+    if problems.is_empty() {
+        Ok(rows)
+    } else {
+        Err(problems)
+    }
+}
+
+/// What an import would do
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// For [`DatabaseManager::apply_changes`](crate::database::DatabaseManager::apply_changes)
+    pub changes: Vec<MappingChange>,
+    /// Where each change comes from (`line 4`, or the mapping a prune deletes)
+    pub sources: Vec<String>,
+    /// One line per change: `+ added`, `~ updated (cell: old -> new)`, `- deleted`
+    pub preview: Vec<String>,
+}
+
+fn route(domain: &str, front_uri: &str) -> String {
+    format!("{}/{}", domain, front_uri)
+}
+
+/// Compare `rows` with the `current` mappings. Rows name a mapping by `id`, else by
+/// `domain` and `front_uri`; the others are added. With `prune`, mappings no row names are
+/// deleted. Rows that clash or name unknown IDs are returned as problems.
+pub fn plan(rows: &[Row], current: &[Mapping], prune: bool) -> Result<Plan, Vec<String>> {
+    let by_id: HashMap<&str, &Mapping> = current.iter().map(|m| (m.id.as_str(), m)).collect();
+    let mut by_route: HashMap<(&str, &str), &Mapping> = HashMap::new();
+    for m in current {
+        by_route.entry((m.domain.as_str(), m.front_uri.as_str())).or_insert(m);
+    }
+
+    let mut plan = Plan::default();
+    let mut problems = Vec::new();
+    let mut named: HashSet<&str> = HashSet::new();
+    let mut routes: HashMap<String, u64> = HashMap::new();
+    for row in rows {
+        let domain = row.get("domain").unwrap_or_default();
+        let existing = match &row.id {
+            Some(id) => match by_id.get(id.as_str()) {
+                Some(m) => Some(*m),
+                None => {
+                    problems.push(format!("line {}: no mapping with id {}", row.line, id));
+                    continue;
+                }
+            },
+            None => by_route.get(&(domain, row.get("front_uri").unwrap_or_default())).copied(),
+        };
+        let front_uri = row.get("front_uri").or(existing.map(|m| m.front_uri.as_str())).unwrap_or_default();
+        if let Some(first) = routes.insert(route(domain, front_uri), row.line) {
+            problems.push(format!("line {}: {} is also on line {}", row.line, route(domain, front_uri), first));
+            continue;
+        }
+
+        match existing {
+            Some(m) => {
+                if !named.insert(m.id.as_str()) {
+                    problems.push(format!("line {}: mapping {} is named twice", row.line, m.id));
+                    continue;
+                }
+                let mut set = Map::new();
+                let mut diffs = Vec::new();
+                for (column, text, value) in &row.cells {
+                    let old = cell(m, column);
+                    if *text != old {
+                        diffs.push(format!("{}: {} -> {}", column, shown(&old), shown(text)));
+                        set.insert(column.to_string(), value.clone());
+                    }
+                }
+                if set.is_empty() {
+                    continue;
+                }
+                plan.preview.push(format!("~ {} ({})", route(&m.domain, &m.front_uri), diffs.join("; ")));
+                plan.sources.push(format!("line {}", row.line));
+                plan.changes.push(MappingChange::Update {
+                    id: Some(m.id.clone()),
+                    domain: None,
+                    front_uri: String::new(),
+                    version: row.version.unwrap_or(m.version),
+                    set,
+                });
+            }
+            None => {
+                if !row.cells.iter().any(|(c, _, _)| *c == "back_port") && !row.cells.iter().any(|(c, t, _)| *c == "back_ports" && !t.is_empty()) {
+                    problems.push(format!("line {}: a new mapping needs back_port or back_ports", row.line));
+                    continue;
+                }
+                let mapping: Map<String, Value> = row.cells.iter().map(|(c, _, v)| (c.to_string(), v.clone())).collect();
+                plan.preview.push(format!("+ {}", route(domain, front_uri)));
+                plan.sources.push(format!("line {}", row.line));
+                plan.changes.push(MappingChange::Add { mapping });
+            }
+        }
+    }
+    if prune {
+        for m in current.iter().filter(|m| !named.contains(m.id.as_str())) {
+            plan.preview.push(format!("- {}", route(&m.domain, &m.front_uri)));
+            plan.sources.push(format!("{} (not in the file)", route(&m.domain, &m.front_uri)));
+            plan.changes.push(MappingChange::Delete { id: Some(m.id.clone()), domain: None, front_uri: None, purge: false });
+        }
+    }
+    if problems.is_empty() {
+        Ok(plan)
+    } else {
+        Err(problems)
+    }
+}
+
+fn shown(cell: &str) -> &str {
+    if cell.is_empty() { "(empty)" } else { cell }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(id: &str, domain: &str, front_uri: &str, port: u16) -> Mapping {
+        Mapping { id: id.into(), domain: domain.into(), front_uri: front_uri.into(), back_port: port, version: 3, ..Default::default() }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut api = mapping("m1", "example.com", "api", 3000);
+        api.rate_limit = Some("10/1s".into());
+        api.allowed_ips = Some("10.0.0.0/8,192.168.1.5".into());
+        let mut out = Vec::new();
+        write(&[api.clone(), mapping("m2", "shop.example.com", "", 4000)], &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("id,version,domain,front_uri,back_port,"), "{}", text);
+        assert!(text.contains(",\"10.0.0.0/8,192.168.1.5\",10/1s,"), "{}", text);
+
+        // Unchanged: nothing to do
+        let rows = read(text.as_bytes()).unwrap();
+        assert_eq!(rows.len(), 2);
+        let plan = plan(&rows, &[api, mapping("m2", "shop.example.com", "", 4000)], false).unwrap();
+        assert!(plan.changes.is_empty() && plan.preview.is_empty());
+    }
+
+    #[test]
+    fn test_plan() {
+        let current = [mapping("m1", "example.com", "api", 3000), mapping("m2", "old.example.com", "", 3100)];
+        let csv = "domain,front_uri,back_port,rate_limit,id,version\n\
+                   Example.com,/api/,3001,100/1m,m1,3\n\
+                   new.example.com,,5000,,,\n";
+        let rows = read(csv.as_bytes()).unwrap();
+        let plan = plan(&rows, &current, true).unwrap();
+        assert_eq!(plan.preview, [
+            "~ example.com/api (back_port: 3000 -> 3001; rate_limit: (empty) -> 100/1m)",
+            "+ new.example.com/",
+            "- old.example.com/",
+        ]);
+        assert_eq!(plan.sources, ["line 2", "line 3", "old.example.com/ (not in the file)"]);
+        let MappingChange::Update { id, version, set, .. } = &plan.changes[0] else { panic!("{:?}", plan.changes) };
+        assert_eq!((id.as_deref(), *version), (Some("m1"), 3));
+        assert_eq!(set.keys().collect::<Vec<_>>(), ["back_port", "rate_limit"]);
+        assert_eq!(set["back_port"], 3001);
+        let MappingChange::Add { mapping } = &plan.changes[1] else { panic!("{:?}", plan.changes) };
+        assert_eq!((mapping["domain"].as_str(), mapping["rate_limit"].is_null()), (Some("new.example.com"), true));
+        assert!(matches!(&plan.changes[2], MappingChange::Delete { id: Some(id), purge: false, .. } if id == "m2"));
+    }
+
+    #[test]
+    fn test_strict_validation() {
+        let problems = read("domain,colour\na.com,red\n".as_bytes()).unwrap_err();
+        assert!(problems[0].starts_with("line 1: unknown column 'colour'"), "{:?}", problems);
+        assert_eq!(read("back_port\n3000\n".as_bytes()).unwrap_err(), ["line 1: the 'domain' column is required"]);
+
+        // Every bad cell, by line
+        let csv = "domain,back_port,rate_limit,allowed_ips,preserve_path,activate_at\n\
+                   a.com,3000,lots,10.0.0.1,true,\n\
+                   .,99999,,10.0.0.0/40,maybe,tomorrow\n";
+        let problems = read(csv.as_bytes()).unwrap_err();
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems[0].starts_with("line 2: rate_limit:"), "{:?}", problems);
+        assert!(problems[1..].iter().all(|p| p.starts_with("line 3: ")), "{:?}", problems);
+
+        // Rows that clash with each other or the table
+        let current = [mapping("m1", "example.com", "", 3000)];
+        let csv = "id,domain,back_port,back_ports\nnope,a.com,1,\n,b.com,1,\n,b.com,2,\n,c.com,0,\n,example.com,3000,\n";
+        let problems = plan(&read(csv.as_bytes()).unwrap(), &current, false).unwrap_err();
+        assert_eq!(problems, ["line 2: no mapping with id nope", "line 4: b.com/ is also on line 3"]);
+        let csv = "domain,back_ports\nc.com,\n";
+        let problems = plan(&read(csv.as_bytes()).unwrap(), &current, false).unwrap_err();
+        assert_eq!(problems, ["line 2: a new mapping needs back_port or back_ports"]);
+    }
+}
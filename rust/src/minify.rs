@@ -0,0 +1,263 @@
+//! Minification
+//! Backends that serve their HTML, CSS and JavaScript as written can have it minified on
+//! the way out. A mapping's `minify` (`on`, or e.g. `html,css,max_bytes=262144,max_ms=50`)
+//! names the types to minify and guards the work: bigger bodies and minifications that
+//! take longer are passed through unchanged. Results are kept by a hash of the original,
+//! so a page is only minified once per version; compressed bodies are decoded first and
+//! compressed again afterwards (see `content_coding`). Input that doesn't parse, or doesn't
+//! get smaller, is sent as it came
+
+use crate::body_rewrite;
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, TRANSFER_ENCODING};
+use hyper::{Response, StatusCode};
+use lightningcss::stylesheet::{MinifyOptions, ParserOptions, PrinterOptions, StyleSheet};
+use ring::digest;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::debug;
+
+/// Memory kept for minified bodies, across mappings
+pub const CACHE_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+/// What a mapping minifies, and within which limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Minify {
+    pub html: bool,
+    pub css: bool,
+    pub js: bool,
+    /// Larger bodies are passed through unchanged
+    pub max_bytes: usize,
+    /// Minification time after which the original is sent
+    pub max_time: Duration,
+}
+
+impl Default for Minify {
+    fn default() -> Self {
+        Self { html: true, css: true, js: true, max_bytes: 512 * 1024, max_time: Duration::from_millis(100) }
+    }
+}
+
+impl FromStr for Minify {
+    type Err = String;
+
+    /// `on` for all types, or any of `html,css,js` and `max_bytes=524288,max_ms=100`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut minify = Self::default();
+        let items: Vec<&str> = s.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        if items.is_empty() {
+            return Err("minify needs 'on' or types like html,css,js".to_string());
+        }
+        let mut types = Vec::new();
+        for item in items {
+            let number = |name: &str, value: &str| value.trim().parse::<usize>().ok().filter(|n| *n > 0)
+                .ok_or_else(|| format!("'{}' needs a positive number, got '{}'", name, value.trim()));
+            match item.split_once('=').map(|(n, v)| (n.trim(), v)) {
+                Some(("max_bytes", value)) => minify.max_bytes = number("max_bytes", value)?,
+                Some(("max_ms", value)) => minify.max_time = Duration::from_millis(number("max_ms", value)? as u64),
+                Some((other, _)) => return Err(format!("unknown minify setting '{}' (max_bytes, max_ms)", other)),
+                None => match item.to_ascii_lowercase().as_str() {
+                    "on" => {}
+                    t @ ("html" | "css" | "js") => types.push(t.to_string()),
+                    other => return Err(format!("unknown type to minify '{}' (html, css, js)", other)),
+                },
+            }
+        }
+        if !types.is_empty() {
+            minify.html = types.iter().any(|t| t == "html");
+            minify.css = types.iter().any(|t| t == "css");
+            minify.js = types.iter().any(|t| t == "js");
+        }
+        Ok(minify)
+    }
+}
+
+impl fmt::Display for Minify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, on) in [("html", self.html), ("css", self.css), ("js", self.js)] {
+            if on {
+                write!(f, "{},", name)?;
+            }
+        }
+        write!(f, "max_bytes={},max_ms={}", self.max_bytes, self.max_time.as_millis())
+    }
+}
+
+/// Kinds of text the filter minifies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Html,
+    Css,
+    Js,
+}
+
+impl Minify {
+    /// What a `Content-Type` holds, if this mapping minifies it. XHTML is left alone, as
+    /// HTML minification doesn't keep it well-formed XML.
+    pub fn kind_of(&self, content_type: &str) -> Option<Kind> {
+        let media = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        match media.as_str() {
+            "text/html" if self.html => Some(Kind::Html),
+            "text/css" if self.css => Some(Kind::Css),
+            "text/javascript" | "application/javascript" | "application/x-javascript" if self.js => Some(Kind::Js),
+            _ => None,
+        }
+    }
+
+    /// `source` minified; `None` where it fails to parse or wouldn't get smaller.
+    pub fn minify(&self, kind: Kind, source: &[u8]) -> Option<Vec<u8>> {
+        let out = match kind {
+            Kind::Html => {
+                let cfg = minify_html::Cfg {
+                    minify_css: self.css,
+                    minify_js: self.js,
+                    keep_closing_tags: true,
+                    keep_html_and_head_opening_tags: true,
+                    ..minify_html::Cfg::spec_compliant()
+                };
+                minify_html::minify(source, &cfg)
+            }
+            Kind::Css => {
+                let code = std::str::from_utf8(source).ok()?;
+                let mut sheet = StyleSheet::parse(code, ParserOptions::default()).ok()?;
+                sheet.minify(MinifyOptions::default()).ok()?;
+                sheet.to_css(PrinterOptions { minify: true, ..PrinterOptions::default() }).ok()?.code.into_bytes()
+            }
+            Kind::Js => {
+                let session = minify_js::Session::new();
+                let mut out = Vec::new();
+                minify_js::minify(&session, minify_js::TopLevelMode::Global, source, &mut out).ok()?;
+                out
+            }
+        };
+        (out.len() < source.len()).then_some(out)
+    }
+}
+
+/// Minified bodies by hash of the original; `None` for originals not worth minifying
+#[derive(Default)]
+pub struct Cache {
+    entries: DashMap<String, Option<Bytes>>,
+    bytes: AtomicUsize,
+}
+
+impl Cache {
+    fn key(kind: Kind, minify: &Minify, source: &[u8]) -> String {
+        let hash = digest::digest(&digest::SHA256, source);
+        let hex: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{} {:?} {}", hex, kind, minify)
+    }
+
+    fn get(&self, key: &str) -> Option<Option<Bytes>> {
+        self.entries.get(key).map(|e| e.clone())
+    }
+
+    fn put(&self, key: String, minified: Option<Bytes>) {
+        let size = key.len() + minified.as_ref().map_or(0, Bytes::len);
+        // Old versions of pages are left behind; start over rather than grow unbounded
+        if self.bytes.fetch_add(size, Ordering::Relaxed) + size > CACHE_MAX_BYTES {
+            self.entries.clear();
+            self.bytes.store(size, Ordering::Relaxed);
+        }
+        self.entries.insert(key, minified);
+    }
+}
+
+/// Minify a `200` response this mapping minifies the type of, from `cache` where done
+/// before. Anything else passes through unchanged.
+pub async fn apply(
+    resp: Response<BoxBody<Bytes, hyper::Error>>,
+    minify: Minify,
+    cache: &Cache,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let kind = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).and_then(|t| minify.kind_of(t));
+    let identity = resp.headers().get(CONTENT_ENCODING).is_none_or(|v| v.as_bytes().eq_ignore_ascii_case(b"identity"));
+    let declared = resp.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+    let (Some(kind), true, StatusCode::OK) = (kind, identity, resp.status()) else {
+        return resp;
+    };
+    if declared.is_some_and(|n| n > minify.max_bytes) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let source = match body_rewrite::read_bounded(body, minify.max_bytes, declared.unwrap_or(0)).await {
+        Ok(buf) => Bytes::from(buf),
+        Err(body) => return Response::from_parts(parts, body),
+    };
+
+    let key = Cache::key(kind, &minify, &source);
+    let minified = match cache.get(&key) {
+        Some(known) => known,
+        None => {
+            let input = source.clone();
+            let work = tokio::task::spawn_blocking(move || minify.minify(kind, &input));
+            let minified = match tokio::time::timeout(minify.max_time, work).await {
+                Ok(Ok(out)) => out.map(Bytes::from),
+                Ok(Err(_)) => None,
+                Err(_) => {
+                    debug!("Minifying a {} byte {:?} body took over {:?}, sending it as is", source.len(), kind, minify.max_time);
+                    None
+                }
+            };
+            cache.put(key, minified.clone());
+            minified
+        }
+    };
+    let Some(body) = minified else {
+        return Response::from_parts(parts, Full::new(source).map_err(|never| match never {}).boxed());
+    };
+    // Same content, other bytes: a strong validator becomes a weak one
+    if let Some(tag) = parts.headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|t| !t.starts_with("W/")) {
+        if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", tag)) {
+            parts.headers.insert(ETAG, weak);
+        }
+    }
+    parts.headers.remove(TRANSFER_ENCODING);
+    parts.headers.remove("content-md5");
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Response::from_parts(parts, Full::new(body).map_err(|never| match never {}).boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let minify: Minify = "css, js, max_ms=50".parse().unwrap();
+        assert_eq!(minify.to_string(), "css,js,max_bytes=524288,max_ms=50");
+        assert_eq!(minify.to_string().parse::<Minify>().unwrap(), minify);
+        assert_eq!("on".parse::<Minify>().unwrap(), Minify::default());
+        assert!("xml".parse::<Minify>().is_err());
+        assert!("on,max_bytes=0".parse::<Minify>().is_err());
+        assert!("".parse::<Minify>().is_err());
+
+        assert_eq!(minify.kind_of("text/css; charset=utf-8"), Some(Kind::Css));
+        assert_eq!(minify.kind_of("application/javascript"), Some(Kind::Js));
+        assert_eq!(minify.kind_of("text/html"), None);
+        assert_eq!(Minify::default().kind_of("application/xhtml+xml"), None);
+    }
+
+    #[test]
+    fn test_minify() {
+        let minify = Minify::default();
+        let html = b"<!DOCTYPE html>\n<html>\n  <head>\n    <title> Hello </title>\n  </head>\n  <body>\n    <!-- note -->\n    <p>Hi   there</p>\n    <pre>  kept  </pre>\n  </body>\n</html>\n";
+        let out = String::from_utf8(minify.minify(Kind::Html, html).unwrap()).unwrap();
+        assert!(out.len() < html.len() && !out.contains("note"), "{}", out);
+        assert!(out.contains("<pre>  kept  </pre>"), "{}", out);
+
+        let css = b"body {\n  color: #ff0000;\n  margin: 0px 0px 0px 0px;\n}\n/* unused */\n";
+        assert_eq!(minify.minify(Kind::Css, css).unwrap(), b"body{color:red;margin:0}");
+
+        let js = b"function add(first, second) {\n  // sum\n  return first + second;\n}\n";
+        let out = minify.minify(Kind::Js, js).unwrap();
+        assert!(out.len() < js.len() && !out.contains(&b'\n'), "{}", String::from_utf8_lossy(&out));
+
+        assert_eq!(minify.minify(Kind::Js, b"function ("), None);
+        assert_eq!(minify.minify(Kind::Css, b"a{color:red}"), None);
+    }
+}
@@ -0,0 +1,323 @@
+//! Request path normalization
+//! Canonicalizes the request path before mapping lookup so that `front_uri` rules see the
+//! same path the backend will, and `//`, `.`/`..` or percent-encoded tricks can't slip past them
+
+use std::borrow::Cow;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// How percent-encoded octets in the path are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PercentDecoding {
+    /// Leave escapes as sent (dot segments are still recognised in encoded form)
+    Keep,
+    /// Decode escaped unreserved characters (`%41` → `A`, `%2E` → `.`) and upper-case the
+    /// hex digits of everything else, so equivalent spellings compare equal
+    #[default]
+    Unreserved,
+    /// As `Unreserved`, but refuse `%2F` and `%5C` so an encoded separator can never hide
+    /// a path boundary from `front_uri` matching
+    RejectEncodedSlash,
+}
+
+impl FromStr for PercentDecoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "unreserved" => Ok(Self::Unreserved),
+            "reject-encoded-slash" => Ok(Self::RejectEncodedSlash),
+            _ => Err(format!("unknown percent decoding '{}' (keep, unreserved, reject-encoded-slash)", s)),
+        }
+    }
+}
+
+/// Trailing-slash policy, applied as a 308 redirect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrailingSlash {
+    /// `/docs` and `/docs/` are served as sent
+    #[default]
+    Ignore,
+    /// Redirect `/docs` to `/docs/` (paths whose last segment has a `.` are left alone)
+    Add,
+    /// Redirect `/docs/` to `/docs`
+    Remove,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ignore" => Ok(Self::Ignore),
+            "add" => Ok(Self::Add),
+            "remove" => Ok(Self::Remove),
+            _ => Err(format!("unknown trailing slash policy '{}' (ignore, add, remove)", s)),
+        }
+    }
+}
+
+/// Path normalization settings
+#[derive(Debug, Clone)]
+pub struct PathNormalization {
+    /// Collapse runs of `/` into one
+    pub merge_slashes: bool,
+    pub percent_decoding: PercentDecoding,
+    pub trailing_slash: TrailingSlash,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        Self {
+            merge_slashes: true,
+            percent_decoding: PercentDecoding::default(),
+            trailing_slash: TrailingSlash::default(),
+        }
+    }
+}
+
+/// Why a path was refused (answered with 400)
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PathError {
+    #[error("malformed percent-encoding")]
+    InvalidEscape,
+    #[error("encoded path separator")]
+    EncodedSlash,
+    #[error("path escapes the root")]
+    Traversal,
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn hex_val(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Apply the percent-decoding policy to a whole path.
+fn decode(path: &str, policy: PercentDecoding) -> Result<Cow<'_, str>, PathError> {
+    if !path.contains('%') {
+        return Ok(Cow::Borrowed(path));
+    }
+    let bytes = path.as_bytes();
+    let mut out = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            let start = i;
+            while i < bytes.len() && bytes[i] != b'%' {
+                i += 1;
+            }
+            out.push_str(&path[start..i]);
+            continue;
+        }
+        let (hi, lo) = match (bytes.get(i + 1).copied().and_then(hex_val), bytes.get(i + 2).copied().and_then(hex_val)) {
+            (Some(hi), Some(lo)) => (hi, lo),
+            _ => return Err(PathError::InvalidEscape),
+        };
+        let octet = hi << 4 | lo;
+        match policy {
+            PercentDecoding::Keep => out.push_str(&path[i..i + 3]),
+            _ if is_unreserved(octet) => out.push(octet as char),
+            PercentDecoding::RejectEncodedSlash if octet == b'/' || octet == b'\\' => {
+                return Err(PathError::EncodedSlash)
+            }
+            _ => out.push_str(&format!("%{:02X}", octet)),
+        }
+        i += 3;
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// A segment that means "this directory", also in encoded form (`%2e`).
+fn dot_segment(seg: &str) -> Option<usize> {
+    let decoded = seg.replace("%2e", ".").replace("%2E", ".");
+    match decoded.as_str() {
+        "." => Some(1),
+        ".." => Some(2),
+        _ => None,
+    }
+}
+
+/// Normalize a request path: decode per policy, merge slashes, resolve `.` and `..`.
+///
+/// Paths not starting with `/` (e.g. `*` for `OPTIONS`) are returned unchanged.
+pub fn normalize_path<'a>(path: &'a str, opts: &PathNormalization) -> Result<Cow<'a, str>, PathError> {
+    if !path.starts_with('/') {
+        return Ok(Cow::Borrowed(path));
+    }
+    let decoded = decode(path, opts.percent_decoding)?;
+
+    let needs_work = (opts.merge_slashes && decoded.contains("//"))
+        || decoded.split('/').any(|s| dot_segment(s).is_some());
+    if !needs_work {
+        return Ok(decoded);
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing = false;
+    for seg in decoded[1..].split('/') {
+        trailing = false;
+        match dot_segment(seg) {
+            Some(1) => trailing = true,
+            Some(_) => {
+                if segments.pop().is_none() {
+                    return Err(PathError::Traversal);
+                }
+                trailing = true;
+            }
+            None if seg.is_empty() && opts.merge_slashes => trailing = true,
+            None => segments.push(seg),
+        }
+    }
+
+    let mut out = String::with_capacity(decoded.len());
+    for seg in &segments {
+        out.push('/');
+        out.push_str(seg);
+    }
+    if trailing || out.is_empty() {
+        out.push('/');
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// The octet at `bytes[i]`, decoding a valid `%XX` escape, and how many bytes it spans.
+fn octet_at(bytes: &[u8], i: usize) -> Option<(u8, usize)> {
+    let b = *bytes.get(i)?;
+    if b == b'%' {
+        if let (Some(hi), Some(lo)) = (bytes.get(i + 1).copied().and_then(hex_val), bytes.get(i + 2).copied().and_then(hex_val)) {
+            return Some((hi << 4 | lo, 3));
+        }
+    }
+    Some((b, 1))
+}
+
+/// The decoded octets of `s`, without collecting them.
+fn octets(s: &str) -> impl Iterator<Item = u8> + '_ {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        let (b, len) = octet_at(bytes, i)?;
+        i += len;
+        Some(b)
+    })
+}
+
+/// Fully percent-decode `s` (including `%2F`) into raw octets.
+pub fn decoded_octets(s: &str) -> Vec<u8> {
+    octets(s).collect()
+}
+
+/// Match `prefix` against the start of `path` on their decoded octets, so `%2F`/`/`,
+/// `%7e`/`%7E`/`~` etc. compare equal. Returns how many bytes of the raw `path` the
+/// prefix covers, which always ends on a character boundary.
+pub fn match_prefix(path: &str, prefix: &str) -> Option<usize> {
+    match_octets(path, octets(prefix))
+}
+
+/// [`match_prefix`] of `/{front_uri}`, without building the pattern.
+pub fn match_front_uri(path: &str, front_uri: &str) -> Option<usize> {
+    match_octets(path, std::iter::once(b'/').chain(octets(front_uri)))
+}
+
+fn match_octets(path: &str, prefix: impl Iterator<Item = u8>) -> Option<usize> {
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    for want in prefix {
+        let (got, len) = octet_at(bytes, i)?;
+        if got != want {
+            return None;
+        }
+        i += len;
+    }
+    path.is_char_boundary(i).then_some(i)
+}
+
+/// Where to redirect `path` under `policy`, if anywhere.
+pub fn trailing_slash_redirect(path: &str, policy: TrailingSlash) -> Option<String> {
+    match policy {
+        TrailingSlash::Ignore => None,
+        TrailingSlash::Add => {
+            let last = path.rsplit('/').next().unwrap_or("");
+            (!path.ends_with('/') && !last.contains('.')).then(|| format!("{}/", path))
+        }
+        TrailingSlash::Remove => {
+            let trimmed = path.trim_end_matches('/');
+            (trimmed.len() < path.len() && !trimmed.is_empty()).then(|| trimmed.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn norm(path: &str) -> Result<String, PathError> {
+        normalize_path(path, &PathNormalization::default()).map(|p| p.into_owned())
+    }
+
+    #[test]
+    fn test_merge_and_dot_segments() {
+        assert_eq!(norm("/api//v1///users").unwrap(), "/api/v1/users");
+        assert_eq!(norm("/api/./v1/../v2/").unwrap(), "/api/v2/");
+        assert_eq!(norm("/a/b/..").unwrap(), "/a/");
+        assert_eq!(norm("/").unwrap(), "/");
+        assert_eq!(norm("*").unwrap(), "*");
+    }
+
+    #[test]
+    fn test_traversal_above_root_rejected() {
+        assert_eq!(norm("/.."), Err(PathError::Traversal));
+        assert_eq!(norm("/api/../../etc/passwd"), Err(PathError::Traversal));
+        assert_eq!(norm("/api/%2e%2e/%2E%2E/secret"), Err(PathError::Traversal));
+    }
+
+    #[test]
+    fn test_percent_decoding_policies() {
+        assert_eq!(norm("/%61pi/%7euser/a%2fb").unwrap(), "/api/~user/a%2Fb");
+        assert_eq!(norm("/bad%zz"), Err(PathError::InvalidEscape));
+
+        let keep = PathNormalization { percent_decoding: PercentDecoding::Keep, ..Default::default() };
+        assert_eq!(normalize_path("/%61pi/%2e%2e/x", &keep).unwrap(), "/x");
+        assert_eq!(normalize_path("/%61pi", &keep).unwrap(), "/%61pi");
+
+        let strict = PathNormalization { percent_decoding: PercentDecoding::RejectEncodedSlash, ..Default::default() };
+        assert_eq!(normalize_path("/api%2Fadmin", &strict), Err(PathError::EncodedSlash));
+        assert_eq!(normalize_path("/api%5cadmin", &strict), Err(PathError::EncodedSlash));
+    }
+
+    #[test]
+    fn test_merge_slashes_off() {
+        let opts = PathNormalization { merge_slashes: false, ..Default::default() };
+        assert_eq!(normalize_path("/a//b/./c", &opts).unwrap(), "/a//b/c");
+    }
+
+    #[test]
+    fn test_trailing_slash_redirect() {
+        assert_eq!(trailing_slash_redirect("/docs", TrailingSlash::Add).as_deref(), Some("/docs/"));
+        assert_eq!(trailing_slash_redirect("/app.js", TrailingSlash::Add), None);
+        assert_eq!(trailing_slash_redirect("/docs/", TrailingSlash::Remove).as_deref(), Some("/docs"));
+        assert_eq!(trailing_slash_redirect("/", TrailingSlash::Remove), None);
+        assert_eq!(trailing_slash_redirect("/docs", TrailingSlash::Ignore), None);
+    }
+
+    #[test]
+    fn test_match_prefix_is_encoding_safe() {
+        assert_eq!(match_prefix("/api/v1/users", "/api/v1"), Some(7));
+        assert_eq!(match_prefix("/api%2Fv1/users", "/api/v1"), Some(9));
+        assert_eq!(match_prefix("/api%2fv1/users", "/api%2Fv1"), Some(9));
+        assert_eq!(match_prefix("/caf%C3%A9/menu", "/caf%c3%a9"), Some(10));
+        assert_eq!(match_prefix("/%7Euser", "/~user"), Some(8));
+        // No LIKE-style wildcards
+        assert_eq!(match_prefix("/myXapp", "/my_app"), None);
+        assert_eq!(match_prefix("/anything", "/%"), None);
+        assert_eq!(match_prefix("/ap", "/api"), None);
+        // An escape matching half of a raw multi-byte character is no match
+        assert_eq!(match_prefix("/é", "/%C3"), None);
+        assert_eq!(match_front_uri("/api%2Fv1/users", "api/v1"), Some(9));
+        assert_eq!(match_front_uri("api/v1", "api/v1"), None);
+    }
+}
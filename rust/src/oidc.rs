@@ -0,0 +1,520 @@
+//! OpenID Connect login
+//! Mappings with `oidc` settings only let signed-in users through: browsers without a
+//! session are sent to the identity provider (authorization code flow with PKCE), the
+//! callback under `/_proxy/oidc/` starts a session (kept in the [`SessionStore`]), and the
+//! user's claims are forwarded to the backend as `X-Forwarded-User`/`-Email`/`-Preferred-Username`/`-Groups`
+
+use crate::session::SessionStore;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine as _};
+use dashmap::DashMap;
+use hyper::header::{HeaderMap, HeaderValue, COOKIE};
+use rand::RngCore;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+pub const PREFIX: &str = "/_proxy/oidc/";
+/// Where the identity provider sends users back to; register `<scheme>://<host>` + this
+pub const CALLBACK_PATH: &str = "/_proxy/oidc/callback";
+
+const SESSION_COOKIE: &str = "_rp_session";
+const LOGIN_COOKIE: &str = "_rp_oidc_login";
+/// Time a user has to finish signing in at the provider
+const LOGIN_TTL: u64 = 600;
+/// How long fetched provider metadata is reused
+const DISCOVERY_TTL: Duration = Duration::from_secs(3600);
+
+/// Identity headers set for the backend; clients' own copies are removed
+pub const USER_HEADER: &str = "x-forwarded-user";
+pub const EMAIL_HEADER: &str = "x-forwarded-email";
+pub const USERNAME_HEADER: &str = "x-forwarded-preferred-username";
+pub const GROUPS_HEADER: &str = "x-forwarded-groups";
+
+/// Login settings of a mapping (JSON in its `oidc` column)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OidcSettings {
+    /// Issuer URL; `<issuer>/.well-known/openid-configuration` must exist
+    pub issuer: String,
+    pub client_id: String,
+    /// May be a `${ENV_VAR}` reference, to keep the secret out of the database
+    pub client_secret: String,
+    /// Space-separated; `openid email profile` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<String>,
+    /// Only users with a verified email in one of these domains get in (empty: anyone)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_domains: Vec<String>,
+}
+
+impl OidcSettings {
+    pub fn parse(json: &str) -> Result<Self> {
+        let settings: Self = serde_json::from_str(json).context("invalid OIDC settings")?;
+        let issuer = url::Url::parse(&settings.issuer).with_context(|| format!("invalid OIDC issuer '{}'", settings.issuer))?;
+        if !matches!(issuer.scheme(), "https" | "http") {
+            bail!("OIDC issuer must be an http(s) URL");
+        }
+        if settings.client_id.is_empty() {
+            bail!("OIDC settings need a client_id");
+        }
+        Ok(settings)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    fn client_secret(&self) -> String {
+        let vars = crate::template::RequestVars { host: "", path: "", remote_addr: "" };
+        crate::template::expand(&self.client_secret, &vars).into_owned()
+    }
+
+    fn allows(&self, claims: &Claims) -> bool {
+        if self.allowed_domains.is_empty() {
+            return true;
+        }
+        let Some(email) = claims.email.as_deref().filter(|_| claims.email_verified != Some(false)) else {
+            return false;
+        };
+        let domain = email.rsplit_once('@').map(|(_, d)| d.to_ascii_lowercase()).unwrap_or_default();
+        self.allowed_domains.iter().any(|d| d.eq_ignore_ascii_case(&domain))
+    }
+}
+
+/// The signed-in user, kept encrypted in the session cookie or in the session store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub sub: String,
+    pub email: Option<String>,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Issuer and client it was issued for, so it only opens mappings using the same login
+    iss: String,
+    aud: String,
+    /// Unix time
+    exp: u64,
+}
+
+impl Session {
+    /// Replace client-sent identity headers with this user's.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        strip_identity(headers);
+        let mut set = |name: &'static str, value: &str| {
+            if let Ok(v) = HeaderValue::from_str(value) {
+                headers.insert(name, v);
+            }
+        };
+        set(USER_HEADER, &self.sub);
+        if let Some(email) = &self.email {
+            set(EMAIL_HEADER, email);
+        }
+        if let Some(username) = &self.username {
+            set(USERNAME_HEADER, username);
+        }
+        if !self.groups.is_empty() {
+            set(GROUPS_HEADER, &self.groups.join(","));
+        }
+    }
+}
+
+/// Identity headers removed from requests to OIDC mappings, whoever sends them.
+pub fn strip_identity(headers: &mut HeaderMap) {
+    for name in [USER_HEADER, EMAIL_HEADER, USERNAME_HEADER, GROUPS_HEADER] {
+        headers.remove(name);
+    }
+}
+
+/// A login in progress, in a short-lived cookie until the provider redirects back
+#[derive(Serialize, Deserialize)]
+struct Login {
+    state: String,
+    nonce: String,
+    verifier: String,
+    mapping_id: String,
+    return_to: String,
+    exp: u64,
+}
+
+/// ID token claims used
+#[derive(Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(a) => a == client_id,
+            Audience::Many(list) => list.iter().any(|a| a == client_id),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// Where to send the browser, and the cookies to set on the way
+pub struct Redirect {
+    pub location: String,
+    pub cookies: Vec<String>,
+}
+
+fn now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    B64.encode(bytes)
+}
+
+/// Value of cookie `name` in the request's `Cookie` headers.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(COOKIE).iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Drop the proxy's own cookies from what the backend sees.
+pub fn strip_cookies(headers: &mut HeaderMap) {
+    let kept: Vec<String> = headers.get_all(COOKIE).iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .map(str::trim)
+        .filter(|c| !c.is_empty() && !c.starts_with(&format!("{}=", SESSION_COOKIE)) && !c.starts_with(&format!("{}=", LOGIN_COOKIE)))
+        .map(str::to_string)
+        .collect();
+    headers.remove(COOKIE);
+    if let Ok(v) = HeaderValue::from_str(&kept.join("; ")) {
+        if !kept.is_empty() {
+            headers.insert(COOKIE, v);
+        }
+    }
+}
+
+fn set_cookie(name: &str, value: &str, max_age: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        name, value, max_age, if secure { "; Secure" } else { "" },
+    )
+}
+
+/// Encrypts cookie contents (AES-256-GCM) with a key derived from the session secret
+struct Sealer {
+    key: LessSafeKey,
+}
+
+impl Sealer {
+    fn new(secret: &[u8]) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, secret);
+        let key = UnboundKey::new(&aead::AES_256_GCM, digest.as_ref()).expect("32-byte key");
+        Self { key: LessSafeKey::new(key) }
+    }
+
+    fn seal<T: Serialize>(&self, value: &T) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut data = serde_json::to_vec(value).unwrap_or_default();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .expect("sealing into a Vec");
+        let mut out = nonce.to_vec();
+        out.extend(data);
+        B64.encode(out)
+    }
+
+    fn open<T: serde::de::DeserializeOwned>(&self, sealed: &str) -> Option<T> {
+        let mut data = B64.decode(sealed).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let mut body = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).ok()?;
+        let plain = self.key.open_in_place(nonce, Aad::empty(), &mut body).ok()?;
+        serde_json::from_slice(plain).ok()
+    }
+}
+
+/// Logins for all OIDC mappings: session cookies, provider metadata, code exchange
+pub struct Oidc {
+    sealer: Sealer,
+    store: SessionStore,
+    /// No session secret configured: warn on first use
+    ephemeral: std::sync::atomic::AtomicBool,
+    session_ttl: Duration,
+    http: reqwest::Client,
+    metadata: DashMap<String, (Metadata, Instant)>,
+}
+
+impl Oidc {
+    /// Cookies are encrypted with `secret`; without one a random key is used, and cookie
+    /// sessions end with the process and aren't valid on other instances.
+    pub fn new(secret: Option<&str>, session_ttl: Duration, store: SessionStore) -> Self {
+        let secret = secret.filter(|s| !s.is_empty());
+        Self {
+            sealer: Sealer::new(secret.map_or_else(random_token, str::to_string).as_bytes()),
+            ephemeral: (secret.is_none() && store.in_cookie()).into(),
+            store,
+            session_ttl,
+            http: reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap_or_default(),
+            metadata: DashMap::new(),
+        }
+    }
+
+    /// The user signed in to the login `settings` describe, if any.
+    pub async fn session(&self, headers: &HeaderMap, settings: &OidcSettings) -> Option<Session> {
+        let value = cookie(headers, SESSION_COOKIE)?;
+        let session: Session = if self.store.in_cookie() {
+            self.sealer.open(value)?
+        } else {
+            match self.store.get(value).await {
+                Ok(data) => serde_json::from_str(&data?).ok()?,
+                Err(e) => {
+                    error!("Session store lookup failed: {:#}", e);
+                    return None;
+                }
+            }
+        };
+        (session.exp > now() && session.iss == settings.issuer && session.aud == settings.client_id).then_some(session)
+    }
+
+    /// Start a login: the provider's authorization URL and the cookie remembering it.
+    /// `redirect_uri` is the absolute callback URL, `return_to` the path to come back to.
+    pub async fn login(
+        &self,
+        settings: &OidcSettings,
+        mapping_id: &str,
+        redirect_uri: &str,
+        return_to: &str,
+        secure: bool,
+    ) -> Result<Redirect> {
+        if self.ephemeral.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            warn!("SESSION_SECRET not set: OIDC sessions won't survive a restart or work across instances");
+        }
+        let metadata = self.metadata(&settings.issuer).await?;
+        let login = Login {
+            state: random_token(),
+            nonce: random_token(),
+            verifier: random_token(),
+            mapping_id: mapping_id.to_string(),
+            return_to: safe_return(return_to).to_string(),
+            exp: now() + LOGIN_TTL,
+        };
+        let challenge = B64.encode(ring::digest::digest(&ring::digest::SHA256, login.verifier.as_bytes()));
+        let mut location = url::Url::parse(&metadata.authorization_endpoint).context("bad authorization_endpoint")?;
+        location.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &settings.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", settings.scopes.as_deref().unwrap_or("openid email profile"))
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(Redirect {
+            location: location.into(),
+            cookies: vec![set_cookie(LOGIN_COOKIE, &self.sealer.seal(&login), LOGIN_TTL, secure)],
+        })
+    }
+
+    /// Mapping a callback belongs to, from its login cookie.
+    pub fn callback_mapping(&self, headers: &HeaderMap) -> Option<String> {
+        let login: Login = self.sealer.open(cookie(headers, LOGIN_COOKIE)?)?;
+        Some(login.mapping_id)
+    }
+
+    /// Finish a login: check the state, redeem the code, check the ID token, set the session.
+    ///
+    /// The ID token comes straight from the token endpoint over a connection this proxy
+    /// opened, so (as OIDC Core §3.1.3.7 allows) its issuer, audience, expiry and nonce are
+    /// checked but not its signature.
+    pub async fn callback(
+        &self,
+        headers: &HeaderMap,
+        query: &str,
+        settings: &OidcSettings,
+        redirect_uri: &str,
+        secure: bool,
+    ) -> Result<Redirect> {
+        let login: Login = cookie(headers, LOGIN_COOKIE)
+            .and_then(|c| self.sealer.open(c))
+            .filter(|l: &Login| l.exp > now())
+            .ok_or_else(|| anyhow!("no login in progress (expired or cookies blocked)"))?;
+        let params: std::collections::HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        if let Some(error) = params.get("error") {
+            bail!("provider refused the login: {} {}", error, params.get("error_description").map_or("", |s| s.as_str()));
+        }
+        if params.get("state") != Some(&login.state) {
+            bail!("state mismatch");
+        }
+        let code = params.get("code").ok_or_else(|| anyhow!("callback without code"))?;
+
+        let metadata = self.metadata(&settings.issuer).await?;
+        let secret = settings.client_secret();
+        let resp = self.http.post(&metadata.token_endpoint)
+            .basic_auth(&settings.client_id, Some(&secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", &settings.client_id),
+                ("code_verifier", &login.verifier),
+            ])
+            .send().await
+            .with_context(|| format!("token request to {}", metadata.token_endpoint))?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        if !status.is_success() {
+            bail!("token endpoint answered {}: {}", status, body.chars().take(200).collect::<String>());
+        }
+        let token: serde_json::Value = serde_json::from_str(&body).context("token response is not JSON")?;
+        let id_token = token["id_token"].as_str().ok_or_else(|| anyhow!("token response without id_token"))?;
+        let claims = id_token_claims(id_token)?;
+        if claims.iss != metadata.issuer || !claims.aud.contains(&settings.client_id) {
+            bail!("ID token issued by {} for another client", claims.iss);
+        }
+        if claims.exp <= now() {
+            bail!("ID token expired");
+        }
+        if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+            bail!("ID token nonce mismatch");
+        }
+        if !settings.allows(&claims) {
+            bail!("{} is not in an allowed domain", claims.email.as_deref().unwrap_or(&claims.sub));
+        }
+
+        let ttl = self.session_ttl.as_secs();
+        let session = Session {
+            sub: claims.sub,
+            email: claims.email,
+            username: claims.preferred_username,
+            groups: claims.groups,
+            iss: settings.issuer.clone(),
+            aud: settings.client_id.clone(),
+            exp: now() + ttl,
+        };
+        let value = if self.store.in_cookie() {
+            self.sealer.seal(&session)
+        } else {
+            let id = random_token();
+            self.store.put(&id, &serde_json::to_string(&session)?, self.session_ttl).await
+                .context("storing the session")?;
+            id
+        };
+        Ok(Redirect {
+            location: login.return_to,
+            cookies: vec![
+                set_cookie(SESSION_COOKIE, &value, ttl, secure),
+                set_cookie(LOGIN_COOKIE, "", 0, secure),
+            ],
+        })
+    }
+
+    /// End the session: drop it from the store (so copies of the cookie stop working too)
+    /// and return the cookie clearing it.
+    pub async fn logout(&self, headers: &HeaderMap, secure: bool) -> String {
+        if let Some(id) = cookie(headers, SESSION_COOKIE).filter(|_| !self.store.in_cookie()) {
+            if let Err(e) = self.store.delete(id).await {
+                error!("Session store delete failed: {:#}", e);
+            }
+        }
+        set_cookie(SESSION_COOKIE, "", 0, secure)
+    }
+
+    async fn metadata(&self, issuer: &str) -> Result<Metadata> {
+        if let Some(entry) = self.metadata.get(issuer).filter(|e| e.1.elapsed() < DISCOVERY_TTL) {
+            return Ok(entry.0.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        let body = self.http.get(&url).send().await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("fetching {}", url))?
+            .text().await?;
+        let metadata: Metadata = serde_json::from_str(&body).with_context(|| format!("bad provider metadata at {}", url))?;
+        self.metadata.insert(issuer.to_string(), (metadata.clone(), Instant::now()));
+        Ok(metadata)
+    }
+}
+
+/// Only local paths are returned to, so the callback can't redirect elsewhere.
+pub fn safe_return(path: &str) -> &str {
+    if path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\") {
+        path
+    } else {
+        "/"
+    }
+}
+
+fn id_token_claims(token: &str) -> Result<Claims> {
+    let payload = token.split('.').nth(1).ok_or_else(|| anyhow!("malformed ID token"))?;
+    let json = B64.decode(payload.trim_end_matches('=')).context("malformed ID token")?;
+    serde_json::from_slice(&json).context("malformed ID token claims")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_cookies() {
+        let sealer = Sealer::new(b"secret");
+        let session = Session {
+            sub: "u1".into(),
+            email: Some("a@example.com".into()),
+            username: None,
+            groups: vec!["admins".into()],
+            iss: "https://idp".into(),
+            aud: "app".into(),
+            exp: now() + 60,
+        };
+        let sealed = sealer.seal(&session);
+        assert_eq!(sealer.open::<Session>(&sealed), Some(session));
+        assert!(Sealer::new(b"other").open::<Session>(&sealed).is_none());
+
+        let mut tampered = B64.decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(sealer.open::<Session>(&B64.encode(tampered)).is_none());
+    }
+
+    #[test]
+    fn test_cookies_and_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("a=1; _rp_session=xyz; b=2"));
+        headers.insert(USER_HEADER, HeaderValue::from_static("spoofed"));
+        assert_eq!(cookie(&headers, SESSION_COOKIE), Some("xyz"));
+        strip_cookies(&mut headers);
+        assert_eq!(headers[COOKIE], "a=1; b=2");
+        strip_identity(&mut headers);
+        assert!(headers.get(USER_HEADER).is_none());
+
+        assert_eq!(safe_return("/app?x=1"), "/app?x=1");
+        assert_eq!(safe_return("//evil.example"), "/");
+        assert_eq!(safe_return("https://evil.example"), "/");
+    }
+}
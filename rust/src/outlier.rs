@@ -0,0 +1,244 @@
+//! Outlier detection
+//! Targets of a multi-backend mapping whose error rate or latency stands out from their
+//! peers' over an interval are ejected from rotation for a cooldown that grows each time
+//! the same target is ejected again
+
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Requests a target must have seen in an interval to be judged
+const MIN_REQUESTS: u32 = 10;
+/// Error rate above the median of the mapping's targets that makes an outlier
+const ERROR_MARGIN: f64 = 0.3;
+/// Mean latency, as a multiple of the median, that makes an outlier…
+const LATENCY_FACTOR: f64 = 3.0;
+/// …if it is also at least this much slower
+const LATENCY_MARGIN: Duration = Duration::from_millis(50);
+/// Cooldowns grow with repeated ejections up to this many times the base
+const MAX_COOLDOWN_FACTOR: u32 = 10;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Window {
+    requests: u32,
+    errors: u32,
+    latency: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct Ejection {
+    until: Instant,
+    /// Ejections in a row, without an interval judged healthy in between
+    times: u32,
+    reason: String,
+}
+
+/// A target currently out of rotation, as shown by the status endpoint
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EjectionInfo {
+    /// `{mapping_id}:{host}:{port}`
+    pub target: String,
+    pub reason: String,
+    pub remaining_secs: u64,
+    pub times: u32,
+}
+
+/// What an evaluation changed, for logging
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Ejected { target: String, reason: String, cooldown: Duration },
+    Returned { target: String },
+}
+
+/// Per-target outcome counters and current ejections, keyed like the HA scores.
+pub struct OutlierDetector {
+    cooldown: Duration,
+    windows: DashMap<String, Window>,
+    ejections: DashMap<String, Ejection>,
+}
+
+impl OutlierDetector {
+    /// Ejections last `cooldown` times the number of ejections in a row.
+    pub fn new(cooldown: Duration) -> Self {
+        Self { cooldown, windows: DashMap::new(), ejections: DashMap::new() }
+    }
+
+    /// Count one attempt against a target.
+    pub fn record(&self, key: &str, failed: bool, latency: Duration) {
+        let mut w = self.windows.entry(key.to_string()).or_default();
+        w.requests += 1;
+        w.errors += u32::from(failed);
+        w.latency += latency;
+    }
+
+    pub fn is_ejected(&self, key: &str) -> bool {
+        self.ejections.get(key).is_some_and(|e| e.until > Instant::now())
+    }
+
+    /// Judge the interval since the last call and start a new one.
+    pub fn evaluate(&self) -> Vec<Event> {
+        let now = Instant::now();
+        let mut events = Vec::new();
+
+        // Cooldowns that ran out
+        for mut e in self.ejections.iter_mut() {
+            if e.until <= now && !e.reason.is_empty() {
+                e.reason.clear();
+                events.push(Event::Returned { target: e.key().clone() });
+            }
+        }
+
+        let windows: Vec<(String, Window)> = self.windows.iter().map(|e| (e.key().clone(), *e.value())).collect();
+        self.windows.clear();
+        let mut by_mapping: HashMap<&str, Vec<(&str, Window)>> = HashMap::new();
+        for (key, w) in &windows {
+            if w.requests >= MIN_REQUESTS {
+                let mapping_id = key.split(':').next().unwrap_or("");
+                by_mapping.entry(mapping_id).or_default().push((key, *w));
+            }
+        }
+
+        for (mapping_id, targets) in by_mapping {
+            if targets.len() < 2 {
+                continue;
+            }
+            let error_rates: Vec<f64> = targets.iter().map(|(_, w)| w.errors as f64 / w.requests as f64).collect();
+            let latencies: Vec<Duration> = targets.iter().map(|(_, w)| w.latency / w.requests).collect();
+            let median_errors = median(error_rates.clone());
+            let median_latency = median(latencies.clone());
+
+            // At least half the mapping's targets (seen this interval or ejected) stay in rotation
+            let of_mapping = |k: &str| k.split(':').next() == Some(mapping_id);
+            let ejected: Vec<String> = self.ejections.iter()
+                .filter(|e| e.until > now && of_mapping(e.key()))
+                .map(|e| e.key().clone())
+                .collect();
+            let total = windows.iter().filter(|(k, _)| of_mapping(k) && !ejected.contains(k)).count() + ejected.len();
+            let mut budget = (total / 2).saturating_sub(ejected.len());
+
+            for (i, (key, _)) in targets.iter().enumerate() {
+                let reason = if error_rates[i] >= median_errors + ERROR_MARGIN {
+                    Some(format!("error rate {:.0}% vs median {:.0}%", error_rates[i] * 100.0, median_errors * 100.0))
+                } else if latencies[i].as_secs_f64() >= median_latency.as_secs_f64() * LATENCY_FACTOR
+                    && latencies[i] >= median_latency + LATENCY_MARGIN
+                {
+                    Some(format!("latency {}ms vs median {}ms", latencies[i].as_millis(), median_latency.as_millis()))
+                } else {
+                    None
+                };
+                match reason {
+                    Some(reason) if budget > 0 && !self.is_ejected(key) => {
+                        budget -= 1;
+                        let mut e = self.ejections.entry(key.to_string()).or_insert(Ejection {
+                            until: now,
+                            times: 0,
+                            reason: String::new(),
+                        });
+                        e.times = (e.times + 1).min(MAX_COOLDOWN_FACTOR);
+                        let cooldown = self.cooldown * e.times;
+                        e.until = now + cooldown;
+                        e.reason = reason.clone();
+                        events.push(Event::Ejected { target: key.to_string(), reason, cooldown });
+                    }
+                    Some(_) => {}
+                    // Judged healthy: the next ejection starts from the base cooldown again
+                    None => {
+                        self.ejections.remove_if(*key, |_, e| e.until <= now);
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// Targets currently ejected, soonest back first.
+    pub fn ejections(&self) -> Vec<EjectionInfo> {
+        let now = Instant::now();
+        let mut list: Vec<EjectionInfo> = self.ejections.iter()
+            .filter(|e| e.until > now)
+            .map(|e| EjectionInfo {
+                target: e.key().clone(),
+                reason: e.reason.clone(),
+                remaining_secs: (e.until - now).as_secs_f64().ceil() as u64,
+                times: e.times,
+            })
+            .collect();
+        list.sort_by_key(|e| e.remaining_secs);
+        list
+    }
+
+    /// Put ejected targets back at once; `matches` selects them by key. Returns how many.
+    pub fn reset(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let now = Instant::now();
+        let keys: Vec<String> = self.ejections.iter()
+            .filter(|e| e.until > now && matches(e.key()))
+            .map(|e| e.key().clone())
+            .collect();
+        for key in &keys {
+            self.ejections.remove(key);
+        }
+        keys.len()
+    }
+}
+
+fn median<T: PartialOrd + Copy>(mut values: Vec<T>) -> T {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values[(values.len() - 1) / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(d: &OutlierDetector, key: &str, requests: u32, errors: u32, latency_ms: u64) {
+        for i in 0..requests {
+            d.record(key, i < errors, Duration::from_millis(latency_ms));
+        }
+    }
+
+    #[test]
+    fn test_error_outlier_ejected_and_returns() {
+        let d = OutlierDetector::new(Duration::from_millis(30));
+        feed(&d, "m:h:1", 20, 0, 5);
+        feed(&d, "m:h:2", 20, 1, 5);
+        feed(&d, "m:h:3", 20, 15, 5);
+        feed(&d, "other:h:1", 20, 20, 5);
+        let events = d.evaluate();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(matches!(&events[0], Event::Ejected { target, .. } if target == "m:h:3"));
+        assert!(d.is_ejected("m:h:3"));
+        assert_eq!(d.ejections()[0].times, 1);
+
+        // Ejected again before an interval judged it healthy: longer cooldown
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(d.evaluate(), vec![Event::Returned { target: "m:h:3".to_string() }]);
+        feed(&d, "m:h:1", 20, 0, 5);
+        feed(&d, "m:h:3", 20, 20, 5);
+        feed(&d, "m:h:2", 20, 0, 5);
+        d.evaluate();
+        assert_eq!(d.ejections()[0].times, 2);
+        assert_eq!(d.reset(|k| k.starts_with("m:")), 1);
+        assert!(!d.is_ejected("m:h:3"));
+    }
+
+    #[test]
+    fn test_latency_outlier_and_half_stay_in_rotation() {
+        let d = OutlierDetector::new(Duration::from_secs(30));
+        feed(&d, "m:h:1", 20, 0, 10);
+        feed(&d, "m:h:2", 20, 0, 400);
+        let events = d.evaluate();
+        assert!(matches!(&events[..], [Event::Ejected { target, .. }] if target == "m:h:2"));
+
+        // Two of two failing: no one is ejected — there'd be nothing left
+        let d = OutlierDetector::new(Duration::from_secs(30));
+        feed(&d, "m:h:1", 20, 20, 10);
+        feed(&d, "m:h:2", 20, 20, 10);
+        assert!(d.evaluate().is_empty());
+
+        // Too few requests to judge
+        feed(&d, "m:h:1", 5, 0, 10);
+        feed(&d, "m:h:2", 5, 5, 10);
+        assert!(d.evaluate().is_empty());
+    }
+}
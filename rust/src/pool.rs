@@ -0,0 +1,362 @@
+//! Upstream connection pool
+//! Backend HTTP/1.1 connections are kept open after a response and reused by later
+//! requests to the same target of the same mapping. How many, and for how long, comes from
+//! the `POOL_*` settings, which a mapping's `pool` overrides setting by setting
+//! (e.g. `max_connections=200,max_idle=64` for a busy API, `max_idle=0` for a rarely used
+//! admin route). HTTP/2 (h2c) connections take concurrent requests, so they go back to the
+//! pool as soon as a request is started on them. Request bodies go out either buffered,
+//! which lets a request be sent again, or streamed from the client as they arrive
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::client::conn::{http1, http2, TrySendError};
+use hyper::{Request, Response};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// Limits of the connections to one backend target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSettings {
+    /// Connections open at once, in use or idle (0: unlimited); requests beyond wait
+    pub max_connections: usize,
+    /// Idle connections kept for reuse (0: close after each response)
+    pub max_idle: usize,
+    /// How long an idle connection is kept
+    pub idle_timeout: Duration,
+    /// Age after which a connection is not reused (zero: no limit)
+    pub max_lifetime: Duration,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: 0,
+            max_idle: 32,
+            idle_timeout: Duration::from_secs(90),
+            max_lifetime: Duration::ZERO,
+        }
+    }
+}
+
+impl PoolSettings {
+    /// `self` with the settings a mapping's `pool` spec names replaced, e.g.
+    /// `max_connections=100,max_idle=8,idle_timeout=30,max_lifetime=600` (seconds).
+    pub fn with_overrides(mut self, spec: &str) -> Result<Self> {
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = item.split_once('=').ok_or_else(|| anyhow!("expected name=value, got '{}'", item))?;
+            let value: u64 = value.trim().parse().map_err(|_| anyhow!("'{}' needs a whole number", name.trim()))?;
+            match name.trim() {
+                "max_connections" => self.max_connections = value as usize,
+                "max_idle" => self.max_idle = value as usize,
+                "idle_timeout" => self.idle_timeout = Duration::from_secs(value),
+                "max_lifetime" => self.max_lifetime = Duration::from_secs(value),
+                other => bail!("unknown pool setting '{}'", other),
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for PoolSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "max_connections={},max_idle={},idle_timeout={},max_lifetime={}",
+            self.max_connections, self.max_idle, self.idle_timeout.as_secs(), self.max_lifetime.as_secs())
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The body of a request to a backend
+pub enum RequestBody {
+    /// Read whole; can be cloned to send the request again
+    Buffered(Full<Bytes>),
+    /// Forwarded frame by frame as the client sends it; goes out once
+    Streaming(UnsyncBoxBody<Bytes, BoxError>),
+}
+
+impl RequestBody {
+    pub fn streaming<B>(body: B) -> Self
+    where
+        B: Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        Self::Streaming(body.map_err(Into::into).boxed_unsync())
+    }
+
+    /// A copy to send again, if the body is buffered.
+    pub fn try_clone(&self) -> Option<Self> {
+        match self {
+            Self::Buffered(full) => Some(Self::Buffered(full.clone())),
+            Self::Streaming(_) => None,
+        }
+    }
+}
+
+impl From<Full<Bytes>> for RequestBody {
+    fn from(full: Full<Bytes>) -> Self {
+        Self::Buffered(full)
+    }
+}
+
+impl Body for RequestBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        match self.get_mut() {
+            Self::Buffered(full) => Pin::new(full).poll_frame(cx).map_err(|never| match never {}),
+            Self::Streaming(body) => Pin::new(body).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Self::Buffered(full) => full.is_end_stream(),
+            Self::Streaming(body) => body.is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Self::Buffered(full) => full.size_hint(),
+            Self::Streaming(body) => body.size_hint(),
+        }
+    }
+}
+
+/// Request side of an HTTP/1.1 or HTTP/2 backend connection
+pub enum Sender {
+    Http1(http1::SendRequest<RequestBody>),
+    Http2(http2::SendRequest<RequestBody>),
+}
+
+impl Sender {
+    pub fn is_closed(&self) -> bool {
+        match self {
+            Sender::Http1(s) => s.is_closed(),
+            Sender::Http2(s) => s.is_closed(),
+        }
+    }
+
+    pub async fn ready(&mut self) -> hyper::Result<()> {
+        match self {
+            Sender::Http1(s) => s.ready().await,
+            Sender::Http2(s) => s.ready().await,
+        }
+    }
+
+    /// Send `req`; if it never went out, the error hands it back.
+    pub async fn try_send_request(&mut self, req: Request<RequestBody>) -> Result<Response<Incoming>, TrySendError<Request<RequestBody>>> {
+        match self {
+            Sender::Http1(s) => s.try_send_request(req).await,
+            Sender::Http2(s) => s.try_send_request(req).await,
+        }
+    }
+}
+
+/// A backend connection checked out of the pool
+pub struct Connection {
+    pub sender: Sender,
+    created: Instant,
+    /// Whether an earlier request used it; the backend may have closed it meanwhile
+    pub reused: bool,
+    permit: Option<OwnedSemaphorePermit>,
+    /// A handle on an HTTP/2 connection that stays in the pool (see [`Pool::share`])
+    shared: bool,
+}
+
+impl Connection {
+    fn expired(&self, settings: &PoolSettings) -> bool {
+        !settings.max_lifetime.is_zero() && self.created.elapsed() >= settings.max_lifetime
+    }
+}
+
+/// Outcome of [`Pool::checkout`]
+pub enum Checkout {
+    Idle(Connection),
+    /// Nothing idle: the caller opens a connection and hands it to [`Pool::connected`]
+    New(Option<OwnedSemaphorePermit>),
+}
+
+struct Idle {
+    conn: Connection,
+    since: Instant,
+}
+
+struct Slot {
+    idle: Vec<Idle>,
+    settings: PoolSettings,
+    /// Open connections when `max_connections` is set
+    limit: Option<Arc<Semaphore>>,
+    /// Signalled when a connection is returned or closed, for requests waiting on the limit
+    returned: Arc<Notify>,
+}
+
+impl Slot {
+    fn new(settings: PoolSettings) -> Self {
+        Self {
+            idle: Vec::new(),
+            settings,
+            limit: (settings.max_connections > 0).then(|| Arc::new(Semaphore::new(settings.max_connections))),
+            returned: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Close idle connections past their idle timeout or lifetime, or closed by the backend.
+    fn prune(&mut self) {
+        let settings = self.settings;
+        let before = self.idle.len();
+        self.idle.retain(|i| i.since.elapsed() < settings.idle_timeout && !i.conn.expired(&settings) && !i.conn.sender.is_closed());
+        if self.idle.len() < before {
+            self.returned.notify_one();
+        }
+    }
+}
+
+/// Connections of one key, for the admin API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PoolStats {
+    pub idle: usize,
+    /// Connections open, when limited
+    pub open: Option<usize>,
+    pub max_connections: usize,
+}
+
+/// Idle backend connections by key (mapping and target). Clones share the pool.
+#[derive(Clone, Default)]
+pub struct Pool {
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+impl Pool {
+    /// A reusable idle connection for `key`, or the go-ahead to open one once the limit allows.
+    pub async fn checkout(&self, key: &str, settings: PoolSettings) -> Checkout {
+        loop {
+            let idle = {
+                let mut slots = self.slots.lock();
+                let slot = slots.entry(key.to_string()).or_insert_with(|| Slot::new(settings));
+                if slot.settings != settings {
+                    // Changed settings take effect for new connections; the old limit
+                    // lapses as its connections close
+                    let idle = std::mem::take(&mut slot.idle);
+                    *slot = Slot::new(settings);
+                    slot.idle = idle;
+                }
+                slot.prune();
+                slot.idle.pop().ok_or_else(|| (slot.limit.clone(), slot.returned.clone()))
+            };
+            let (limit, returned) = match idle {
+                // The backend may have closed it since it was returned
+                Ok(mut idle) => match idle.conn.sender.ready().await {
+                    Ok(()) => {
+                        idle.conn.reused = true;
+                        return Checkout::Idle(idle.conn);
+                    }
+                    Err(_) => continue,
+                },
+                Err(waiting) => waiting,
+            };
+            let Some(limit) = limit else { return Checkout::New(None) };
+            if let Ok(permit) = limit.clone().try_acquire_owned() {
+                return Checkout::New(Some(permit));
+            }
+            tokio::select! {
+                permit = limit.acquire_owned() => match permit {
+                    Ok(permit) => return Checkout::New(Some(permit)),
+                    Err(_) => continue,
+                },
+                _ = returned.notified() => continue,
+            }
+        }
+    }
+
+    /// Wrap a newly opened connection.
+    pub fn connected(&self, sender: Sender, permit: Option<OwnedSemaphorePermit>) -> Connection {
+        Connection { sender, created: Instant::now(), reused: false, permit, shared: false }
+    }
+
+    /// An HTTP/2 connection goes straight back to the pool, for other requests to use at
+    /// the same time; the caller gets a handle on it. HTTP/1.1 connections are returned as is.
+    pub fn share(&self, key: &str, settings: PoolSettings, conn: Connection) -> Connection {
+        let Sender::Http2(sender) = &conn.sender else { return conn };
+        let handle = Connection {
+            sender: Sender::Http2(sender.clone()),
+            created: conn.created,
+            reused: conn.reused,
+            permit: None,
+            shared: true,
+        };
+        self.put(key, settings, conn);
+        handle
+    }
+
+    /// Return a connection whose response was read in full; it is kept for reuse unless
+    /// the settings say otherwise.
+    pub fn put(&self, key: &str, settings: PoolSettings, conn: Connection) {
+        if conn.shared {
+            return;
+        }
+        let mut slots = self.slots.lock();
+        let slot = slots.entry(key.to_string()).or_insert_with(|| Slot::new(settings));
+        let current = slot.limit.as_ref().map(Arc::as_ptr);
+        let same_limit = conn.permit.as_ref().map(|p| Arc::as_ptr(p.semaphore())) == current;
+        if settings.max_idle > 0 && !settings.idle_timeout.is_zero() && !conn.expired(&settings)
+            && !conn.sender.is_closed() && same_limit {
+            slot.idle.push(Idle { conn, since: Instant::now() });
+            if slot.idle.len() > settings.max_idle {
+                slot.idle.remove(0);
+            }
+        }
+        slot.returned.notify_one();
+    }
+
+    /// Close idle connections past their idle timeout or lifetime, and forget unused keys.
+    pub fn sweep(&self) {
+        let mut slots = self.slots.lock();
+        slots.retain(|_, slot| {
+            slot.prune();
+            let open = slot.limit.as_ref().map_or(0, |l| slot.settings.max_connections - l.available_permits());
+            !slot.idle.is_empty() || open > 0
+        });
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, PoolStats> {
+        let slots = self.slots.lock();
+        slots.iter().map(|(key, slot)| {
+            let stats = PoolStats {
+                idle: slot.idle.len(),
+                open: slot.limit.as_ref().map(|l| slot.settings.max_connections - l.available_permits()),
+                max_connections: slot.settings.max_connections,
+            };
+            (key.clone(), stats)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let global = PoolSettings::default();
+        let api = global.with_overrides("max_connections=200, max_idle=64").unwrap();
+        assert_eq!((api.max_connections, api.max_idle, api.idle_timeout), (200, 64, Duration::from_secs(90)));
+        let admin = global.with_overrides("max_idle=0,idle_timeout=5,max_lifetime=60").unwrap();
+        assert_eq!(admin.to_string(), "max_connections=0,max_idle=0,idle_timeout=5,max_lifetime=60");
+        assert_eq!(global.with_overrides("").unwrap(), global);
+        assert!(global.with_overrides("max_idle=-1").is_err());
+        assert!(global.with_overrides("keepalive=1").is_err());
+        assert!(global.with_overrides("max_idle").is_err());
+    }
+}
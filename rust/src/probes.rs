@@ -0,0 +1,125 @@
+//! Synthetic probes
+//! With `PROBE_INTERVAL`, every backend target of every mapping is probed on a schedule —
+//! the TCP connect the HA health checker uses, through the mapping's egress proxy —
+//! whether traffic flows or not. Each result (up or down, connect latency) is kept in the
+//! `probes` table for `PROBE_RETENTION`, and `GET /_proxy/admin/status` reports uptime and
+//! latency per target over the last hour, day and week plus an hourly trend of the last
+//! day, so the proxy doubles as a basic uptime monitor for its own backends. Probes only
+//! record: routing still follows real traffic and the health checker. Instances sharing a
+//! database pool their probes
+
+use crate::database::{DatabaseManager, Probe, ProbeStats};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Spans uptime and latency are reported over
+pub const WINDOWS: [(&str, i64); 3] = [("1h", 3600), ("24h", 86_400), ("7d", 604_800)];
+
+/// Hours covered by the hourly trend
+const TREND_HOURS: i64 = 24;
+
+/// One hour of a target's trend
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Hour {
+    /// RFC 3339 start of the hour
+    pub start: String,
+    pub probes: u64,
+    pub uptime: f64,
+    pub latency_ms: Option<f64>,
+}
+
+/// Uptime and latency of one target
+#[derive(Debug, Serialize)]
+pub struct TargetReport {
+    pub mapping_id: String,
+    pub target: String,
+    pub last: Probe,
+    /// Percent of probes that connected, per window
+    pub uptime: BTreeMap<&'static str, f64>,
+    /// Average connect time of the probes that did, per window
+    pub latency_ms: BTreeMap<&'static str, Option<f64>>,
+    pub hourly: Vec<Hour>,
+}
+
+fn round2(x: f64) -> f64 {
+    (x * 100.0).round() / 100.0
+}
+
+fn percent(up: u64, probes: u64) -> f64 {
+    round2(up as f64 * 100.0 / probes.max(1) as f64)
+}
+
+/// Reports of every target probed within the retention, as of `now` (Unix seconds).
+pub fn report(db: &DatabaseManager, now: i64) -> Result<Vec<TargetReport>> {
+    let mut reports: Vec<TargetReport> = db.last_probes()?.into_iter().map(|last| TargetReport {
+        mapping_id: last.mapping_id.clone(),
+        target: last.target.clone(),
+        last,
+        uptime: BTreeMap::new(),
+        latency_ms: BTreeMap::new(),
+        hourly: Vec::new(),
+    }).collect();
+    let index: HashMap<(String, String), usize> = reports.iter().enumerate()
+        .map(|(i, r)| ((r.mapping_id.clone(), r.target.clone()), i))
+        .collect();
+    let stats = |since: i64, bucket: i64| -> Result<Vec<(usize, ProbeStats)>> {
+        Ok(db.probe_stats(since, bucket)?.into_iter()
+            .filter_map(|s| Some((*index.get(&(s.mapping_id.clone(), s.target.clone()))?, s)))
+            .collect())
+    };
+    for (name, span) in WINDOWS {
+        for (i, s) in stats(now - span, i64::MAX)? {
+            reports[i].uptime.insert(name, percent(s.up, s.probes));
+            reports[i].latency_ms.insert(name, s.latency_ms.map(round2));
+        }
+    }
+    let trend_start = (now / 3600 - TREND_HOURS + 1) * 3600;
+    for (i, s) in stats(trend_start, 3600)? {
+        reports[i].hourly.push(Hour {
+            start: chrono::DateTime::from_timestamp(s.bucket, 0).unwrap_or_default().to_rfc3339(),
+            probes: s.probes,
+            uptime: percent(s.up, s.probes),
+            latency_ms: s.latency_ms.map(round2),
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_report() {
+        let dir = tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+        let now = 1_700_000_000 / 3600 * 3600 + 1800;
+        let probe = |at: i64, ok: bool, latency_ms: f64| Probe {
+            mapping_id: "m1".to_string(),
+            target: "10.0.0.1:80".to_string(),
+            at,
+            ok,
+            latency_ms,
+            error: (!ok).then(|| "connection refused".to_string()),
+        };
+        let old = [probe(now - 30 * 86_400, true, 1.0)];
+        let week = [probe(now - 2 * 86_400, false, 3000.0), probe(now - 2 * 3600, true, 4.0)];
+        let hour = [probe(now - 60, true, 2.0), probe(now, true, 3.0)];
+        db.record_probes(&old, 0).unwrap();
+        db.record_probes(&week, 0).unwrap();
+        // Trimmed to the last week
+        db.record_probes(&hour, now - 7 * 86_400).unwrap();
+
+        let reports = report(&db, now).unwrap();
+        assert_eq!(reports.len(), 1);
+        let r = &reports[0];
+        assert_eq!(r.last, hour[1]);
+        assert_eq!((r.uptime["1h"], r.uptime["24h"], r.uptime["7d"]), (100.0, 100.0, 75.0));
+        assert_eq!((r.latency_ms["1h"], r.latency_ms["7d"]), (Some(2.5), Some(3.0)));
+        assert_eq!(r.hourly.len(), 2);
+        assert_eq!((r.hourly[0].probes, r.hourly[0].latency_ms), (1, Some(4.0)));
+        assert_eq!((r.hourly[1].probes, r.hourly[1].uptime), (2, 100.0));
+    }
+}
@@ -1,24 +1,84 @@
 //! Proxy server implementation
 //! Handles HTTP/HTTPS reverse proxying with path rewriting, auth, IP allowlisting, and HA
 
+use crate::access_hours::AccessHours;
+use crate::access_log::{AccessLog, LogPolicy};
+use crate::analytics::{self, Analytics};
+use crate::anomaly::{self, AnomalyDetector};
+use crate::acme_server::{self, AcmeServer};
+use crate::admin::{self, Action, Caller};
+use crate::bans::{self, BanPolicy, Bans, Network};
+use crate::body_rewrite::{self, RewriteCache};
+use crate::cache::{self, CacheKey, ResponseCache, Stale};
+use crate::cert_leader::{self, CertLeader};
 use crate::certificate::CertificateManager;
-use crate::database::{DatabaseManager, Mapping};
+use crate::cluster::{self, Cluster, HealthReport};
+use crate::connections::{ConnectionGuard, ConnectionStats, ConnectionTracker, LimitAction};
+use crate::content_coding;
+use crate::contract::{self, Contract};
+use crate::database::{self, DatabaseManager, Mapping};
+use crate::deadline;
+use crate::debug_trace::{self, Trace};
+use crate::discovery::{weighted_order, DiscoverySource, Endpoint, ServiceDiscovery};
+use crate::disk_cache::DiskCache;
+use crate::drain::{DrainTracker, Route};
+use crate::early_hints::{self, EarlyHints};
+use crate::duplicate_headers::{self, DuplicateHeaders};
+use crate::egress::{self, EgressProxy};
+use crate::etag;
+use crate::events::{self, Event, Events};
+use crate::experiment::{self, Experiment};
+use crate::fastcgi;
+use crate::forward_headers::ForwardHeaders;
+use crate::forward_proxy;
+use crate::happy_eyeballs::IpPreference;
+use crate::header_limits::HeaderLimits;
+use crate::hold::{self, Hold};
+use crate::html_base::{self, BasePathMode};
+use crate::image_filter::{self, ImageFilter, Transform};
+use crate::lease::{Lease, LeaseStore};
+use crate::minify::{self, Minify};
+use crate::normalize::{self, PathNormalization};
+use crate::oidc::{self, Oidc, OidcSettings};
+use crate::outlier::{self, OutlierDetector};
+use crate::pool::{Checkout, Pool, PoolSettings, RequestBody, Sender};
+use crate::probes;
+use crate::ratelimit::{self, Decision, RateLimit, RateLimiter};
+use crate::redis::RedisClient;
+use crate::request_compression;
+use crate::s3_site;
+use crate::scrub::ScrubProfile;
+use crate::session::{self, SessionStore};
+use crate::signed_url;
+use crate::snapshot;
+use crate::startup_page::{self, StartupPage, Starting};
+use crate::tcp_options::TcpOptions;
+use crate::template::{self, RequestVars};
+use crate::tls::CertStore;
+use crate::tls_info::TlsInfo;
+use crate::upstream_protocol::UpstreamProtocol;
+use crate::upstream_signing::UpstreamSigning;
+use crate::upstream_tls::{self, Connector, ConnectorCache};
+use crate::usage::{self, Meter, Quota};
+use crate::webhook::Webhooks;
+use crate::xfcc::ClientCertDetails;
 use anyhow::{Context, Result, anyhow};
 use bytes::Bytes;
 use dashmap::DashMap;
-use http_body_util::{BodyExt, Empty, Full, combinators::BoxBody};
-use hyper::body::Incoming;
-use hyper::header::{HOST, UPGRADE, CONNECTION};
+use http_body_util::{BodyExt, Empty, Full, Limited, StreamBody, combinators::BoxBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{HeaderValue, HOST, UPGRADE};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response, StatusCode, Uri, Version};
-use hyper_util::rt::TokioIo;
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use socket2::SockRef;
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{debug, error, info, warn};
 use base64::{Engine as _, engine::general_purpose};
 use url::Url;
@@ -54,6 +114,41 @@ impl FallbackHandler for NotFoundFallback {
     }
 }
 
+/// A started server: the addresses its listeners are bound to, and their accept loops
+///
+/// Dropping the handle leaves the server running; [`ServerHandle::shutdown`] stops it.
+#[derive(Debug)]
+pub struct ServerHandle {
+    http_addr: SocketAddr,
+    https_addr: Option<SocketAddr>,
+    tasks: Vec<tokio::task::JoinHandle<Result<()>>>,
+}
+
+impl ServerHandle {
+    /// Bound HTTP address (the OS-assigned port when `http_port` was 0).
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    /// Bound HTTPS address, when HTTPS is enabled.
+    pub fn https_addr(&self) -> Option<SocketAddr> {
+        self.https_addr
+    }
+
+    /// Resolve when an accept loop stops (they only do on error or shutdown).
+    pub async fn wait(self) -> Result<()> {
+        let (result, _, _) = futures_util::future::select_all(self.tasks).await;
+        result.map_err(|e| anyhow!("accept loop ended: {}", e))?
+    }
+
+    /// Stop accepting connections; those already open finish on their own.
+    pub fn shutdown(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
 /// Proxy server configuration
 #[derive(Clone)]
 pub struct ProxyConfig {
@@ -62,6 +157,172 @@ pub struct ProxyConfig {
     pub enable_https: bool,
     pub force_https: bool,
     pub http_host: String,
+    /// Consul HTTP API address for `consul://` backends (default http://127.0.0.1:8500)
+    pub consul_addr: Option<String>,
+    /// ACL token sent as `X-Consul-Token`
+    pub consul_token: Option<String>,
+    /// etcd v3 gRPC-gateway address for `etcd://` backends (default http://127.0.0.1:2379)
+    pub etcd_endpoint: Option<String>,
+    /// DNS server for `srv://` backends instead of the system resolver's (e.g. Consul DNS)
+    pub srv_nameserver: Option<SocketAddr>,
+    /// Blue/green: 5xx share (0.0–1.0) during probation that triggers an automatic rollback
+    pub rollback_error_rate: f64,
+    /// Blue/green: responses observed during probation before the error rate is trusted
+    pub rollback_min_requests: u32,
+    /// Path canonicalization applied before mapping lookup
+    pub path_normalization: PathNormalization,
+    /// Most headers accepted in a request (431 beyond that)
+    pub max_headers: usize,
+    /// Largest accepted request head (request line plus headers) in bytes; 431 beyond that
+    pub max_header_bytes: usize,
+    /// Longest accepted request line (method, target, version) in bytes; 414 beyond that
+    pub max_request_line: usize,
+    /// Time a client gets to send the complete request head before the connection is closed
+    pub header_read_timeout: Duration,
+    /// Refuse requests carrying both Content-Length and Transfer-Encoding (400)
+    pub reject_ambiguous_framing: bool,
+    /// What happens to requests repeating Host, Content-Length or Transfer-Encoding
+    pub duplicate_headers: DuplicateHeaders,
+    /// How long requests and WebSocket sessions may keep using a removed or re-pointed route
+    pub drain_timeout: Duration,
+    /// How often `certs_dir` is rescanned for new or renewed certificates (zero disables)
+    pub cert_reload_interval: Duration,
+    /// Bearer token for the admin API under `/_proxy/admin/` (disabled when unset)
+    pub admin_token: Option<String>,
+    /// Access-log level and sampling, unless a mapping overrides them
+    pub access_log: LogPolicy,
+    /// Most open client connections across all listeners (0 = no cap)
+    pub max_connections: usize,
+    /// File descriptors kept free below the process limit; client plus backend
+    /// connections are capped there
+    pub fd_reserve: usize,
+    /// Reject or pause accepting while no connection slot is free
+    pub connection_limit_action: LimitAction,
+    /// Cap on client deadlines from `X-Request-Timeout` / `grpc-timeout` (zero ignores them)
+    pub max_request_timeout: Duration,
+    /// Largest response body a mapping's body rewrites and HTML base path are applied to;
+    /// bigger ones pass as is
+    pub body_rewrite_max_bytes: usize,
+    /// Requests per client IP and domain, unless a mapping sets its own (`None`: unlimited)
+    pub rate_limit: Option<RateLimit>,
+    /// Ban clients that keep hitting their rate limit (see [`crate::bans`]; `None`: never)
+    pub rate_limit_ban: Option<BanPolicy>,
+    /// Ban clients that keep getting 401 or 403 from a mapping (`None`: never)
+    pub auth_failure_ban: Option<BanPolicy>,
+    /// Load balancers and proxies whose `X-Forwarded-For` names the client for bans, rate
+    /// limits, IP allowlists and experiments; from anyone else it is ignored
+    pub trusted_proxies: Vec<Network>,
+    /// `redis://` URL of the store shared by all instances for rate-limit counters
+    pub redis_url: Option<String>,
+    /// Cluster mode: HTTP addresses (`host:port`) of the other nodes to share mappings
+    /// and backend health with (empty: standalone)
+    pub cluster_peers: Vec<String>,
+    /// Bearer token all cluster nodes share; cluster mode stays off without it
+    pub cluster_secret: Option<String>,
+    /// How often each peer is asked for newer state
+    pub cluster_interval: Duration,
+    /// Shell command renewing certificates into `certs_dir`, run only by the node holding
+    /// the certificate lease (in Redis when `redis_url` is set, else in the database)
+    pub cert_renew_command: Option<String>,
+    /// How often the lease holder runs `cert_renew_command`
+    pub cert_renew_interval: Duration,
+    /// Memory for responses kept for mappings with `stale_if_error`
+    pub response_cache_max_bytes: usize,
+    /// Directory kept responses are also written to, surviving restarts (unset: memory only)
+    pub response_cache_dir: Option<std::path::PathBuf>,
+    /// Disk for kept responses; the least recently used go first
+    pub response_cache_disk_max_bytes: u64,
+    /// Files kept in `response_cache_dir` at most
+    pub response_cache_disk_max_entries: usize,
+    /// HA: how often targets' error rates and latency are compared (zero: no outlier detection)
+    pub outlier_interval: Duration,
+    /// HA: how long an outlier is ejected, multiplied by its ejections in a row
+    pub outlier_cooldown: Duration,
+    /// Alert when a mapping's error rate or latency departs from its own baseline
+    /// (see [`crate::anomaly`])
+    pub anomaly_alerts: bool,
+    /// SOCKS5 or HTTP CONNECT proxy backend connections go through, unless a mapping
+    /// sets its own or `direct`
+    pub egress_proxy: Option<EgressProxy>,
+    /// Backend connection pool limits, per mapping and target; a mapping's `pool` overrides them
+    pub upstream_pool: PoolSettings,
+    /// Socket options of the listeners and accepted client connections
+    pub client_tcp: TcpOptions,
+    /// Socket options of backend connections
+    pub backend_tcp: TcpOptions,
+    /// Address family backend connections try first (or only) when a name has both
+    pub backend_ip_preference: IpPreference,
+    /// Head start of each backend connection attempt before the next address is tried alongside
+    pub backend_connect_attempt_delay: Duration,
+    /// Key OIDC session cookies are encrypted with; shared by all instances behind one
+    /// name. Unset: a random key, so sessions end on restart
+    pub session_secret: Option<String>,
+    /// How long an OIDC login lasts before the user is sent to the provider again
+    pub oidc_session_ttl: Duration,
+    /// Where login sessions are kept: in the cookie, or server-side (memory, SQLite, Redis
+    /// at `redis_url`) with only an ID in the cookie
+    pub session_store: session::Backend,
+    /// Serve ACME under `/_proxy/acme/`, issuing from the internal CA in `certs_dir/internal-ca`
+    pub acme_server: bool,
+    /// Name suffixes the internal ACME server issues for (empty: any)
+    pub acme_server_domains: Vec<String>,
+    /// Port http-01 challenges are fetched on by the internal ACME server
+    pub acme_server_challenge_port: u16,
+    /// URLs routing, health, certificate and rate-limit events are POSTed to
+    pub webhook_urls: Vec<String>,
+    /// Key webhook bodies are HMAC-signed with (`X-Webhook-Signature`)
+    pub webhook_secret: Option<String>,
+    /// Recent events kept for the admin API (0: none)
+    pub event_log_size: usize,
+    /// Weak ETags for successful GET responses without validators, and 304s for them
+    pub generate_etags: bool,
+    /// IPs and CIDRs (comma-separated) `/health` is answered for, by connection address;
+    /// others are routed like any other path. Unset: everyone
+    pub health_allowed_ips: Option<String>,
+    /// Internal address `/health` and the admin API are served on instead of the public
+    /// listeners, keeping them off the data plane
+    pub internal_listen: Option<SocketAddr>,
+    /// Answer `/.well-known/acme-challenge/` only for hosts that have a mapping
+    pub acme_challenges_mapped_only: bool,
+    /// Requests that already passed this many proxies of ours get 508 Loop Detected
+    /// (see [`crate::hops`]; 0: never)
+    pub max_proxy_hops: u32,
+    /// How often per-mapping usage counts are added to the database (see [`crate::usage`])
+    pub usage_flush_interval: Duration,
+    /// Mappings owned by a tenant get no traffic until the tenant has proven control of
+    /// the domain (see [`crate::domain_verify`])
+    pub require_domain_verification: bool,
+    /// How often every backend target is probed for the uptime history (see
+    /// [`crate::probes`]; zero: never)
+    pub probe_interval: Duration,
+    /// How long probe results are kept
+    pub probe_retention: Duration,
+    /// Fingerprinting headers removed from responses of mappings without their own
+    /// profile (see [`crate::scrub`])
+    pub scrub_response_headers: ScrubProfile,
+    /// Secret of the `X-RustProxy-Debug` request header that gets a routing trace back
+    /// (see [`crate::debug_trace`])
+    pub debug_secret: Option<String>,
+    /// CA bundle the HTTPS listener verifies client certificates against; clients are
+    /// asked for one but may go without (unset: not asked)
+    pub tls_client_ca: Option<std::path::PathBuf>,
+    /// Pass the connection's TLS details to backends (see [`crate::tls_info`])
+    pub forward_tls_headers: bool,
+    /// Pass verified client certificates to backends in `X-Forwarded-Client-Cert` with these
+    /// details (see [`crate::xfcc`]; `None`: not passed)
+    pub forward_client_cert: Option<ClientCertDetails>,
+    /// Address of the forward-proxy listener taking `CONNECT` tunnels from internal clients
+    /// (see [`crate::forward_proxy`]; unset: off)
+    pub forward_proxy_listen: Option<SocketAddr>,
+    /// Clients that may open tunnels on `forward_proxy_listen`, and where to
+    pub forward_proxy_acl: forward_proxy::Acl,
+    /// Where every finished request is also written as a row (see [`crate::analytics`];
+    /// `None`: nowhere)
+    pub analytics_sink: Option<analytics::Sink>,
+    /// How long analytics rows are kept
+    pub analytics_retention: Duration,
+    /// How often buffered analytics rows are written
+    pub analytics_flush_interval: Duration,
 }
 
 impl Default for ProxyConfig {
@@ -72,10 +333,108 @@ impl Default for ProxyConfig {
             enable_https: false,
             force_https: false,
             http_host: "0.0.0.0".to_string(),
+            consul_addr: None,
+            consul_token: None,
+            etcd_endpoint: None,
+            srv_nameserver: None,
+            rollback_error_rate: 0.5,
+            rollback_min_requests: 20,
+            path_normalization: PathNormalization::default(),
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_request_line: 8 * 1024,
+            header_read_timeout: Duration::from_secs(30),
+            reject_ambiguous_framing: true,
+            duplicate_headers: DuplicateHeaders::Collapse,
+            drain_timeout: Duration::from_secs(30),
+            cert_reload_interval: Duration::from_secs(5),
+            admin_token: None,
+            access_log: LogPolicy::default(),
+            max_connections: 0,
+            fd_reserve: 64,
+            connection_limit_action: LimitAction::Reject,
+            max_request_timeout: Duration::from_secs(300),
+            body_rewrite_max_bytes: 1024 * 1024,
+            rate_limit: None,
+            rate_limit_ban: None,
+            auth_failure_ban: None,
+            trusted_proxies: Vec::new(),
+            redis_url: None,
+            cluster_peers: Vec::new(),
+            cluster_secret: None,
+            cluster_interval: Duration::from_secs(2),
+            cert_renew_command: None,
+            cert_renew_interval: Duration::from_secs(12 * 3600),
+            response_cache_max_bytes: 64 * 1024 * 1024,
+            response_cache_dir: None,
+            response_cache_disk_max_bytes: 1024 * 1024 * 1024,
+            response_cache_disk_max_entries: 100_000,
+            outlier_interval: Duration::from_secs(10),
+            outlier_cooldown: Duration::from_secs(30),
+            anomaly_alerts: true,
+            egress_proxy: None,
+            upstream_pool: PoolSettings::default(),
+            client_tcp: TcpOptions::default(),
+            backend_tcp: TcpOptions::default(),
+            backend_ip_preference: IpPreference::default(),
+            backend_connect_attempt_delay: Duration::from_millis(250),
+            session_secret: None,
+            oidc_session_ttl: Duration::from_secs(8 * 3600),
+            session_store: session::Backend::Cookie,
+            acme_server: false,
+            acme_server_domains: Vec::new(),
+            acme_server_challenge_port: 80,
+            webhook_urls: Vec::new(),
+            webhook_secret: None,
+            event_log_size: 1000,
+            generate_etags: false,
+            health_allowed_ips: None,
+            internal_listen: None,
+            acme_challenges_mapped_only: false,
+            max_proxy_hops: 10,
+            usage_flush_interval: Duration::from_secs(10),
+            require_domain_verification: false,
+            probe_interval: Duration::ZERO,
+            probe_retention: Duration::from_secs(7 * 86_400),
+            scrub_response_headers: ScrubProfile::Off,
+            debug_secret: None,
+            tls_client_ca: None,
+            forward_tls_headers: false,
+            forward_client_cert: None,
+            forward_proxy_listen: None,
+            forward_proxy_acl: forward_proxy::Acl::default(),
+            analytics_sink: None,
+            analytics_retention: Duration::from_secs(30 * 86_400),
+            analytics_flush_interval: Duration::from_secs(5),
         }
     }
 }
 
+/// Request extension: the path as sent by the client, before normalization
+#[derive(Clone)]
+struct OriginalPath(String);
+
+/// Blue/green: responses seen since the last switch of a mapping still in probation
+#[derive(Default)]
+struct ProbationStats {
+    switched_at: String,
+    total: u32,
+    errors: u32,
+}
+
+/// Response extension marking a request refused for its mapping's quota, which is not counted
+#[derive(Clone, Copy)]
+struct QuotaExceeded;
+
+/// Request extension marking requests that arrived on the HTTPS listener, with what
+/// their connection negotiated
+#[derive(Clone)]
+struct TlsConnection(Arc<TlsInfo>);
+
+/// Where `auth_type = api_key` mappings take the key from
+const API_KEY_HEADER: &str = "x-api-key";
+const API_KEY_PARAM: &str = "api_key";
+
 struct AuthResult {
     allowed: bool,
     credential_index: Option<usize>,
@@ -83,17 +442,200 @@ struct AuthResult {
     scheme: &'static str,
 }
 
+/// How a mapping's backend is reached: directly or through an egress proxy, over TLS for
+/// `https://` backends, reusing pooled connections
+#[derive(Clone, Default)]
+struct Dialer {
+    tls: Option<Arc<Connector>>,
+    egress: Option<Arc<EgressProxy>>,
+    pool: Pool,
+    pool_settings: PoolSettings,
+    mapping_id: String,
+    tcp: TcpOptions,
+    ip_preference: IpPreference,
+    attempt_delay: Duration,
+    protocol: UpstreamProtocol,
+    signing: Option<Arc<UpstreamSigning>>,
+}
+
+impl Dialer {
+    /// Send `req` to `host:port` on a pooled connection and read the response; the body
+    /// may still fail. A request that couldn't go out on a reused connection (closed by the
+    /// backend meanwhile) is sent again on a new one, as are idempotent requests whose
+    /// response never came; a streamed body only while none of it went out. The request is
+    /// signed and the mapping's upstream protocol settings are applied first.
+    async fn exchange(
+        &self,
+        host: &str,
+        port: u16,
+        req: Request<impl Into<RequestBody>>,
+        conns: &Arc<ConnectionTracker>,
+    ) -> Result<(hyper::http::response::Parts, Result<Bytes, hyper::Error>)> {
+        let mut req = req.map(Into::into);
+        let scheme = match (self.tls.is_some(), self.protocol.h2c) {
+            (true, _) => "https://",
+            (false, true) => "h2c://",
+            (false, false) => "",
+        };
+        let key = format!("{}|{}{}:{}", self.mapping_id, scheme, host, port);
+        if let Some(signing) = &self.signing {
+            let RequestBody::Buffered(body) = req.body() else {
+                return Err(anyhow!("signed requests need a buffered body"));
+            };
+            let body = body.clone().collect().await.map(|b| b.to_bytes()).unwrap_or_default();
+            let default_port = if self.tls.is_some() { 443 } else { 80 };
+            let authority = match port == default_port {
+                true => host.to_string(),
+                false => format!("{}:{}", host, port),
+            };
+            signing.sign(&mut req, &body, &authority, chrono::Utc::now());
+        }
+        self.protocol.apply(&mut req);
+        loop {
+            let conn = match self.pool.checkout(&key, self.pool_settings).await {
+                Checkout::Idle(conn) => conn,
+                Checkout::New(permit) => {
+                    let stream = TokioIo::new(self.connect(host, port).await?);
+                    let guard = conns.backend();
+                    let sender = match self.protocol.h2c {
+                        true => {
+                            let (sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), stream).await
+                                .context("Failed to establish HTTP/2 connection to backend")?;
+                            tokio::spawn(async move {
+                                let _guard = guard;
+                                let _ = conn.await;
+                            });
+                            Sender::Http2(sender)
+                        }
+                        false => {
+                            let (sender, conn) = hyper::client::conn::http1::handshake(stream).await
+                                .context("Failed to establish connection to backend")?;
+                            tokio::spawn(async move {
+                                let _guard = guard;
+                                let _ = conn.await;
+                            });
+                            Sender::Http1(sender)
+                        }
+                    };
+                    self.pool.connected(sender, permit)
+                }
+            };
+            let mut conn = match self.protocol.keep_alive() {
+                true => self.pool.share(&key, self.pool_settings, conn),
+                false => conn,
+            };
+            let copy = req.body().try_clone().filter(|_| conn.reused && req.method().is_idempotent()).map(|body| {
+                let mut copy = Request::new(body);
+                *copy.method_mut() = req.method().clone();
+                *copy.uri_mut() = req.uri().clone();
+                *copy.version_mut() = req.version();
+                *copy.headers_mut() = req.headers().clone();
+                copy
+            });
+            match conn.sender.try_send_request(req).await {
+                Ok(resp) => {
+                    let (parts, body) = resp.into_parts();
+                    let body = body.collect().await.map(|b| b.to_bytes());
+                    if body.is_ok() && self.protocol.keep_alive() {
+                        self.pool.put(&key, self.pool_settings, conn);
+                    }
+                    return Ok((parts, body));
+                }
+                Err(mut e) => match e.take_message().or(copy) {
+                    Some(again) if conn.reused => {
+                        debug!("Pooled connection to {}:{} was closed, reconnecting", host, port);
+                        req = again;
+                    }
+                    _ => return Err(anyhow!("send_request failed: {}", e.into_error())),
+                },
+            }
+        }
+    }
+
+    async fn connect(&self, host: &str, port: u16) -> Result<Box<dyn upstream_tls::Io>> {
+        let stream = match &self.egress {
+            Some(egress) => {
+                let stream = egress.connect(host, port).await?;
+                self.tcp.apply(&stream).context("Failed to set backend socket options")?;
+                stream
+            }
+            None => self.tcp.connect(&format!("{}:{}", host, port), self.ip_preference, self.attempt_delay).await
+                .map_err(|e| anyhow!("connect {}:{}: {}", host, port, e))?,
+        };
+        Ok(match &self.tls {
+            Some(tls) => Box::new(tls.connect(stream, host).await?),
+            None => Box::new(stream),
+        })
+    }
+}
+
 /// Proxy server
 pub struct ProxyServer {
     config: ProxyConfig,
     db_manager: Arc<DatabaseManager>,
     cert_manager: Arc<CertificateManager>,
-    /// HA: score per port key "{mapping_id}:{port}", range 0–100 (100 = healthy).
+    /// HA: score per target key "{mapping_id}:{host}:{port}", range 0–100 (100 = healthy).
     port_scores: DashMap<String, u8>,
     /// HA: round-robin tie-break counters per mapping ID.
     rr_counters: DashMap<String, usize>,
-    /// HA: set of port keys currently being background-probed.
+    /// HA: set of target keys currently being background-probed.
     bg_checks: DashMap<String, ()>,
+    /// HA: targets ejected for standing out from their peers.
+    outliers: OutlierDetector,
+    /// Per-mapping traffic compared with its baseline, for anomaly alerts.
+    anomalies: AnomalyDetector,
+    /// Instance lists for `consul://` / `etcd://` backends.
+    discovery: Arc<ServiceDiscovery>,
+    /// Blue/green: probation stats per mapping ID.
+    probation: DashMap<String, ProbationStats>,
+    /// In-flight requests and WebSocket sessions per route, for draining.
+    drain: Arc<DrainTracker>,
+    /// Certificates served on the HTTPS listener, kept in sync with `certs_dir`.
+    tls: Arc<CertStore>,
+    /// Open client and backend connections, and the caps on them.
+    conns: Arc<ConnectionTracker>,
+    /// Last good responses of mappings with `stale_if_error`.
+    response_cache: ResponseCache,
+    /// Compiled response body substitutions, by rule JSON.
+    body_rewrites: RewriteCache,
+    /// TLS clients for `https://` backends, by verification settings.
+    upstream_tls: ConnectorCache,
+    /// Server-wide egress proxy for backend connections
+    egress_proxy: Option<Arc<EgressProxy>>,
+    /// Mappings whose backend refused gzip request bodies.
+    compression_refusals: request_compression::Refusals,
+    /// Mappings whose backend is showing its startup page.
+    starting: Starting,
+    /// Requests waiting on held mappings.
+    held: hold::Queues,
+    /// Images resized by mappings' image filters.
+    images: image_filter::Cache,
+    /// Minified HTML, CSS and JavaScript bodies.
+    minified: minify::Cache,
+    /// Idle backend connections, by mapping and target.
+    pool: Pool,
+    /// Requests and bytes per mapping, for reports and quotas.
+    usage: Meter,
+    /// Response contract results per mapping.
+    contracts: contract::Monitor,
+    /// Logins of mappings with OIDC settings.
+    oidc: Oidc,
+    /// Internal CA speaking ACME, when enabled.
+    acme_server: Option<Arc<AcmeServer>>,
+    /// Request counters for rate limits, in memory or in Redis.
+    rate_limiter: RateLimiter,
+    /// Banned networks and clients on their way to a ban.
+    bans: Bans,
+    /// Cluster mode: state shared with peer nodes.
+    cluster: Option<Arc<Cluster>>,
+    /// Which node renews certificates, when several may.
+    cert_leader: Option<Arc<CertLeader>>,
+    /// Recent events for the admin API, passed on to `webhook_urls`.
+    events: Events,
+    /// Rows of finished requests on their way to the analytics sink.
+    analytics: Option<Arc<Analytics>>,
+    /// Background tasks are started once, however many workers share this server.
+    background_started: std::sync::atomic::AtomicBool,
     /// Called when no DB mapping matches the request.
     fallback: Arc<dyn FallbackHandler>,
 }
@@ -104,6 +646,97 @@ impl ProxyServer {
         db_manager: Arc<DatabaseManager>,
         cert_manager: Arc<CertificateManager>,
     ) -> Self {
+        let discovery = Arc::new(ServiceDiscovery::new(
+            config.consul_addr.clone(),
+            config.consul_token.clone(),
+            config.etcd_endpoint.clone(),
+            config.srv_nameserver,
+        ));
+        let drain = Arc::new(DrainTracker::new(config.drain_timeout));
+        let tls = Arc::new(CertStore::new(cert_manager.certs_dir()));
+        let events = Events::new(
+            config.event_log_size,
+            Webhooks::new(config.webhook_urls.clone(), config.webhook_secret.clone()),
+        );
+        cert_manager.use_database(db_manager.clone());
+        let conns = Arc::new(ConnectionTracker::new(config.max_connections, config.fd_reserve));
+        let redis = config.redis_url.as_deref().and_then(|url| {
+            RedisClient::new(url, Duration::from_millis(250))
+                .map_err(|e| error!("Rate limits are counted per instance: {}", e))
+                .ok()
+        });
+        let rate_limiter = RateLimiter::new(redis);
+        let bans = Bans::new(config.rate_limit_ban, config.auth_failure_ban);
+        if let Err(e) = bans.load_offenders(&db_manager).and_then(|_| bans.sync(&db_manager)) {
+            error!("Could not load bans: {:#}", e);
+        }
+        let cert_leader = (!config.cluster_peers.is_empty() || config.cert_renew_command.is_some()).then(|| {
+            let redis = config.redis_url.as_deref().and_then(|url| RedisClient::new(url, Duration::from_millis(250)).ok());
+            let store = match redis {
+                Some(redis) => LeaseStore::Redis(Arc::new(redis)),
+                None => {
+                    if !config.cluster_peers.is_empty() {
+                        warn!("Without REDIS_URL the certificate lease only covers processes sharing this database");
+                    }
+                    LeaseStore::Database(db_manager.clone())
+                }
+            };
+            let lease = Lease::new(store, cert_leader::LEASE_NAME, cert_leader::LEASE_TTL);
+            Arc::new(CertLeader::new(
+                lease,
+                cert_manager.certs_dir(),
+                config.cert_renew_command.clone(),
+                config.cert_renew_interval,
+            ).with_events(events.clone()))
+        });
+        let cluster = match (&config.cluster_peers[..], &config.cluster_secret) {
+            ([], _) => None,
+            (_, None) => {
+                error!("Cluster mode needs a cluster secret; running standalone");
+                None
+            }
+            (peers, Some(secret)) => Cluster::new(peers, secret, db_manager.clone(), cert_leader.clone())
+                .map_err(|e| error!("Cluster mode disabled: {:#}", e))
+                .ok()
+                .map(Arc::new),
+        };
+        if let Err(e) = tls.reload() {
+            warn!("Could not load certificates: {:#}", e);
+        }
+        let mut response_cache = ResponseCache::new(config.response_cache_max_bytes);
+        if let Some(dir) = &config.response_cache_dir {
+            match DiskCache::open(dir, config.response_cache_disk_max_bytes, config.response_cache_disk_max_entries) {
+                Ok(disk) => response_cache = response_cache.with_disk(disk),
+                Err(e) => error!("Response cache is kept in memory only: {:#}", e),
+            }
+        }
+        let outliers = OutlierDetector::new(config.outlier_cooldown);
+        let analytics = config.analytics_sink.clone().map(|sink| Arc::new(Analytics::new(sink, config.analytics_retention)));
+        let egress_proxy = config.egress_proxy.clone().map(Arc::new);
+        let sessions = match config.session_store {
+            session::Backend::Cookie => SessionStore::Cookie,
+            session::Backend::Memory => SessionStore::Memory(Arc::default()),
+            session::Backend::Sqlite => SessionStore::Database(db_manager.clone()),
+            session::Backend::Redis => match config.redis_url.as_deref().map(|url| RedisClient::new(url, Duration::from_millis(250))) {
+                Some(Ok(redis)) => SessionStore::Redis(Arc::new(redis)),
+                Some(Err(e)) => {
+                    error!("Sessions are kept in cookies: {}", e);
+                    SessionStore::Cookie
+                }
+                None => {
+                    error!("SESSION_STORE=redis needs REDIS_URL; sessions are kept in cookies");
+                    SessionStore::Cookie
+                }
+            },
+        };
+        let oidc = Oidc::new(config.session_secret.as_deref(), config.oidc_session_ttl, sessions);
+        let acme_server = config.acme_server.then(|| {
+            let dir = cert_manager.certs_dir().join("internal-ca");
+            AcmeServer::new(&dir, config.acme_server_domains.clone(), config.acme_server_challenge_port)
+                .map_err(|e| error!("Internal ACME server disabled: {:#}", e))
+                .ok()
+                .map(Arc::new)
+        }).flatten();
         Self {
             config,
             db_manager,
@@ -111,226 +744,2107 @@ impl ProxyServer {
             port_scores: DashMap::new(),
             rr_counters: DashMap::new(),
             bg_checks: DashMap::new(),
+            outliers,
+            anomalies: AnomalyDetector::default(),
+            discovery,
+            probation: DashMap::new(),
+            drain,
+            tls,
+            conns,
+            response_cache,
+            body_rewrites: RewriteCache::default(),
+            upstream_tls: ConnectorCache::default(),
+            egress_proxy,
+            compression_refusals: request_compression::Refusals::default(),
+            starting: Starting::default(),
+            held: hold::Queues::default(),
+            images: image_filter::Cache::default(),
+            minified: minify::Cache::default(),
+            pool: Pool::default(),
+            usage: Meter::default(),
+            contracts: contract::Monitor::default(),
+            oidc,
+            acme_server,
+            rate_limiter,
+            bans,
+            cluster,
+            cert_leader,
+            events,
+            analytics,
+            background_started: std::sync::atomic::AtomicBool::new(false),
             fallback: Arc::new(NotFoundFallback),
         }
     }
 
+    /// Open client and backend connection gauges.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.conns.stats()
+    }
+
+    /// Validate ports, database, certificates and mapping backends (see [`crate::selfcheck`]).
+    /// Pass `bind_ports: false` once the listeners are up.
+    pub async fn self_check(&self, bind_ports: bool) -> crate::selfcheck::Report {
+        crate::selfcheck::run(&self.config, &self.db_manager, &self.tls, bind_ports).await
+    }
+
+    /// Duplicate, unreachable and looping mappings (see [`crate::lint`]).
+    pub fn lint_routes(&self) -> Result<Vec<crate::lint::Finding>> {
+        let mut ports = vec![self.config.http_port];
+        if self.config.enable_https {
+            ports.push(self.config.https_port);
+        }
+        let listeners = crate::lint::Listeners { host: &self.config.http_host, ports: &ports };
+        Ok(crate::lint::check(&self.db_manager.list_mappings(None)?, &listeners, &self.config.path_normalization))
+    }
+
+    /// Spawn the server-wide periodic tasks on the current runtime (first caller only).
+    fn start_background_tasks(self: &Arc<Self>) {
+        if self.background_started.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
+        // Draining: compare live sessions against the DB once a second
+        let drain = self.drain.clone();
+        let db = self.db_manager.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tick.tick().await;
+                let (drain, db) = (drain.clone(), db.clone());
+                let _ = tokio::task::spawn_blocking(move || drain.sweep(&db)).await;
+            }
+        });
+
+        // Health and admin endpoints on their own internal listener
+        if let Some(addr) = self.config.internal_listen {
+            tokio::spawn(self.clone().run_internal_listener(addr));
+        }
+
+        // Analytics: write the buffered rows in batches
+        if let Some(analytics) = self.analytics.clone() {
+            let every = self.config.analytics_flush_interval.max(Duration::from_secs(1));
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(every);
+                loop {
+                    tick.tick().await;
+                    if let Err(e) = analytics.flush().await {
+                        warn!("Analytics flush failed: {:#}", e);
+                    }
+                }
+            });
+        }
+
+        // CONNECT tunnels for internal clients on their own listener
+        if let Some(addr) = self.config.forward_proxy_listen {
+            tokio::spawn(self.clone().run_forward_proxy(addr));
+        }
+
+        // Usage: add this instance's counts to the database and pick up everyone's totals
+        let (usage, db) = (self.usage.clone(), self.db_manager.clone());
+        let every = self.config.usage_flush_interval.max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(every);
+            loop {
+                tick.tick().await;
+                let (usage, db) = (usage.clone(), db.clone());
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || usage.flush(&db)).await {
+                    warn!("Usage flush failed: {:#}", e);
+                }
+            }
+        });
+
+        // Bans: store strike counts, pick up bans given elsewhere, drop expired ones
+        let (bans, db) = (self.bans.clone(), self.db_manager.clone());
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(bans::SYNC_INTERVAL);
+            loop {
+                tick.tick().await;
+                let (bans, db) = (bans.clone(), db.clone());
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || bans.sync(&db)).await {
+                    warn!("Ban sync failed: {:#}", e);
+                }
+            }
+        });
+
+        // Synthetic probes: every backend target, for the uptime history
+        if !self.config.probe_interval.is_zero() {
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(proxy.config.probe_interval);
+                loop {
+                    tick.tick().await;
+                    let results = proxy.probe_all().await;
+                    let (db, keep_since) = (proxy.db_manager.clone(), chrono::Utc::now().timestamp() - proxy.config.probe_retention.as_secs() as i64);
+                    if let Ok(Err(e)) = tokio::task::spawn_blocking(move || db.record_probes(&results, keep_since)).await {
+                        warn!("Storing probe results failed: {:#}", e);
+                    }
+                }
+            });
+        }
+
+        // Upstream pool: close connections idle past their timeout
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tick.tick().await;
+                pool.sweep();
+            }
+        });
+
+        // Certificates: pick up files renewed or added by external tooling (certbot)
+        if !self.config.cert_reload_interval.is_zero() {
+            let (tls, events) = (self.tls.clone(), self.events.clone());
+            let every = self.config.cert_reload_interval;
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(every);
+                tick.tick().await;
+                loop {
+                    tick.tick().await;
+                    let tls = tls.clone();
+                    match tokio::task::spawn_blocking(move || tls.reload_changes()).await {
+                        Ok(Ok(changes)) => changes.into_iter().for_each(|c| events.emit(c.into())),
+                        Ok(Err(e)) => warn!("Certificate reload failed: {:#}", e),
+                        Err(_) => {}
+                    }
+                }
+            });
+        }
+
+        // HA: eject targets whose error rate or latency stands out from their peers'
+        if !self.config.outlier_interval.is_zero() {
+            let server = self.clone();
+            let every = self.config.outlier_interval;
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(every);
+                tick.tick().await;
+                loop {
+                    tick.tick().await;
+                    for event in server.outliers.evaluate() {
+                        match event {
+                            outlier::Event::Ejected { target, reason, cooldown } => {
+                                warn!("Outlier: ejecting {} for {}s ({})", target, cooldown.as_secs(), reason);
+                                server.events.emit(Event::OutlierEjected { target, reason, cooldown_secs: cooldown.as_secs() });
+                            }
+                            outlier::Event::Returned { target } => {
+                                info!("Outlier: {} back in rotation", target);
+                                server.events.emit(Event::OutlierReturned { target });
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        // Anomalies: mappings doing markedly worse than their own last hour
+        if self.config.anomaly_alerts {
+            let server = self.clone();
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(Duration::from_secs(60));
+                loop {
+                    tick.tick().await;
+                    let changes = server.anomalies.evaluate();
+                    if changes.is_empty() {
+                        continue;
+                    }
+                    let db = server.db_manager.clone();
+                    let mappings = tokio::task::spawn_blocking(move || db.list_mappings(None)).await.ok().and_then(Result::ok).unwrap_or_default();
+                    let domain = |id: &str| mappings.iter().find(|m| m.id == id).map_or_else(|| "-".to_string(), |m| m.domain.clone());
+                    for change in changes {
+                        match change {
+                            anomaly::Event::Detected { mapping_id, metric, summary } => {
+                                let domain = domain(&mapping_id);
+                                warn!("Anomaly: {} ({}): {}", domain, mapping_id, summary);
+                                server.events.emit(Event::AnomalyDetected { mapping_id, domain, metric: metric.to_string(), summary });
+                            }
+                            anomaly::Event::Resolved { mapping_id, metric, summary } => {
+                                let domain = domain(&mapping_id);
+                                info!("Anomaly over: {} ({}): {}", domain, mapping_id, summary);
+                                server.events.emit(Event::AnomalyResolved { mapping_id, domain, metric: metric.to_string(), summary });
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        // Events: mappings added or removed (by the CLI, admin API or a peer)
+        if self.events.enabled() {
+            let (events, db) = (self.events.clone(), self.db_manager.clone());
+            tokio::spawn(async move {
+                let mut known: Option<Vec<Mapping>> = None;
+                let mut tick = tokio::time::interval(events::MAPPING_POLL);
+                loop {
+                    tick.tick().await;
+                    let db = db.clone();
+                    let Ok(Ok(mappings)) = tokio::task::spawn_blocking(move || db.list_mappings(None)).await else { continue };
+                    if let Some(known) = &known {
+                        events::mapping_events(known, &mappings).into_iter().for_each(|e| events.emit(e));
+                    }
+                    known = Some(mappings);
+                }
+            });
+        }
+
+        // Certificates: take or keep the renewal lease
+        if let Some(leader) = self.cert_leader.clone() {
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(cert_leader::LEASE_TTL / 3);
+                loop {
+                    tick.tick().await;
+                    leader.tick().await;
+                }
+            });
+        }
+
+        // Cluster: take newer mappings, backend health and certificates from peers
+        if let Some(cluster) = self.cluster.clone() {
+            let server = self.clone();
+            let every = self.config.cluster_interval;
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(every);
+                loop {
+                    tick.tick().await;
+                    for report in cluster.sync_all().await {
+                        server.apply_peer_health(&report);
+                    }
+                }
+            });
+        }
+    }
+
     // ── HA helpers ──────────────────────────────────────────────────────────
 
-    fn port_key(mapping_id: &str, port: u16) -> String {
-        format!("{}:{}", mapping_id, port)
+    fn port_key(mapping_id: &str, target: &Endpoint) -> String {
+        format!("{}:{}:{}", mapping_id, target.host, target.port)
+    }
+
+    fn get_port_score(&self, mapping_id: &str, target: &Endpoint) -> u8 {
+        self.port_scores.get(&Self::port_key(mapping_id, target)).map(|v| *v).unwrap_or(100)
+    }
+
+    fn boost_port(&self, mapping_id: &str, target: &Endpoint) {
+        let key = Self::port_key(mapping_id, target);
+        let previous = self.port_scores.insert(key.clone(), 100);
+        if previous.is_some_and(|s| s < 100) {
+            self.report_health(&key, true);
+        }
+        // Up from a probe already announced the recovery (score 50)
+        if previous == Some(0) {
+            self.events.emit(Event::BackendUp { mapping_id: mapping_id.to_string(), target: format!("{}:{}", target.host, target.port) });
+        }
     }
 
-    fn get_port_score(&self, mapping_id: &str, port: u16) -> u8 {
-        self.port_scores.get(&Self::port_key(mapping_id, port)).map(|v| *v).unwrap_or(100)
+    fn penalize_port(&self, mapping_id: &str, target: &Endpoint) {
+        let key = Self::port_key(mapping_id, target);
+        if self.port_scores.insert(key.clone(), 0) != Some(0) {
+            self.report_health(&key, false);
+            self.events.emit(Event::BackendDown { mapping_id: mapping_id.to_string(), target: format!("{}:{}", target.host, target.port) });
+        }
     }
 
-    fn boost_port(&self, mapping_id: &str, port: u16) {
-        self.port_scores.insert(Self::port_key(mapping_id, port), 100);
+    /// Cluster mode: pass a change in a target's health on to the peers.
+    fn report_health(&self, key: &str, healthy: bool) {
+        if let Some(cluster) = &self.cluster {
+            cluster.report_health(key, healthy);
+        }
     }
 
-    fn penalize_port(&self, mapping_id: &str, port: u16) {
-        self.port_scores.insert(Self::port_key(mapping_id, port), 0);
+    /// Cluster mode: a peer saw a target go down (probe it here too) or come back.
+    fn apply_peer_health(self: &Arc<Self>, report: &HealthReport) {
+        // Keys are "{mapping_id}:{host}:{port}"
+        let parsed = report.target.split_once(':').and_then(|(mapping_id, addr)| {
+            let (host, port) = addr.rsplit_once(':')?;
+            Some((mapping_id, Endpoint::new(host, port.parse().ok()?)))
+        });
+        let Some((mapping_id, target)) = parsed else {
+            return;
+        };
+        if report.healthy {
+            self.bg_checks.remove(&report.target);
+            self.port_scores.insert(report.target.clone(), 50);
+        } else {
+            self.port_scores.insert(report.target.clone(), 0);
+            self.clone().start_background_check(mapping_id.to_string(), target, self.egress_proxy.clone());
+        }
+        info!("Cluster: {} reported {} by a peer", report.target, if report.healthy { "up" } else { "down" });
     }
 
-    /// Return ports sorted best-score-first; round-robin as tie-break.
-    fn ranked_ports(&self, mapping_id: &str, ports: &[u16]) -> Vec<u16> {
+    /// Return targets sorted best-score-first; SRV priority/weight, then round-robin, as tie-break.
+    fn ranked_ports(&self, mapping_id: &str, targets: &[Endpoint]) -> Vec<Endpoint> {
         let mut counter = self.rr_counters.entry(mapping_id.to_string()).or_insert(0);
         let i = *counter;
         *counter = i.wrapping_add(1);
         drop(counter);
 
-        let n = ports.len();
-        let mut rotated: Vec<u16> = ports[i % n..].iter().chain(ports[..i % n].iter()).copied().collect();
-        rotated.sort_by_key(|&p| std::cmp::Reverse(self.get_port_score(mapping_id, p)));
-        rotated
-    }
+        let n = targets.len();
+        let rotated: Vec<Endpoint> = targets[i % n..].iter().chain(targets[..i % n].iter()).cloned().collect();
+        let mut ranked = weighted_order(&rotated);
+        ranked.sort_by_key(|t| std::cmp::Reverse(self.get_port_score(mapping_id, t)));
+        // Ejected outliers only get traffic when nothing else is left
+        if ranked.iter().any(|t| !self.outliers.is_ejected(&Self::port_key(mapping_id, t))) {
+            ranked.retain(|t| !self.outliers.is_ejected(&Self::port_key(mapping_id, t)));
+        }
+        ranked
+    }
+
+    /// TCP-probe a target in the background (through `egress` when set) until it responds;
+    /// then set score to 50 so the next real request gives it a try.
+    fn start_background_check(self: Arc<Self>, mapping_id: String, target: Endpoint, egress: Option<Arc<EgressProxy>>) {
+        let key = Self::port_key(&mapping_id, &target);
+        if self.bg_checks.contains_key(&key) {
+            return;
+        }
+        self.bg_checks.insert(key.clone(), ());
+        warn!("HA: {}:{} scored 0, starting background probe for mapping {}", target.host, target.port, mapping_id);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            loop {
+                if !self.bg_checks.contains_key(&key) {
+                    break;
+                }
+                let addr = format!("{}:{}", target.host, target.port);
+                match self.probe(&target, egress.as_deref()).await {
+                    Ok(()) => {
+                        self.bg_checks.remove(&key);
+                        self.port_scores.insert(key.clone(), 50);
+                        self.report_health(&key, true);
+                        self.events.emit(Event::BackendUp { mapping_id: mapping_id.clone(), target: addr.clone() });
+                        info!("HA: {} back up (score→50) for mapping {}", addr, mapping_id);
+                        break;
+                    }
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Whether a TCP connection to `target` (through `egress` when set) opens within 3s.
+    async fn probe(&self, target: &Endpoint, egress: Option<&EgressProxy>) -> Result<()> {
+        let connect = async {
+            match egress {
+                Some(egress) => egress.connect(&target.host, target.port).await.map(drop),
+                None => self.config.backend_tcp
+                    .connect(&format!("{}:{}", target.host, target.port), self.config.backend_ip_preference, self.config.backend_connect_attempt_delay)
+                    .await.map(drop).map_err(Into::into),
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(3), connect).await.map_err(|_| anyhow!("timed out"))?
+    }
+
+    /// Probe every target of every mapping at once (see [`probes`]).
+    async fn probe_all(&self) -> Vec<database::Probe> {
+        let mappings = match self.db_manager.list_mappings(None) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Probes: listing mappings failed: {:#}", e);
+                return Vec::new();
+            }
+        };
+        let mut checks = Vec::new();
+        for mapping in &mappings {
+            let targets = match Self::single_target(mapping) {
+                Some(t) => vec![t],
+                None => self.backend_targets(mapping).await.unwrap_or_default(),
+            };
+            let egress = self.egress(mapping).ok().flatten();
+            for target in targets {
+                let egress = egress.clone();
+                checks.push(async move {
+                    let started = std::time::Instant::now();
+                    let result = self.probe(&target, egress.as_deref()).await;
+                    database::Probe {
+                        mapping_id: mapping.id.clone(),
+                        target: format!("{}:{}", target.host, target.port),
+                        at: chrono::Utc::now().timestamp(),
+                        ok: result.is_ok(),
+                        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                        error: result.err().map(|e| format!("{:#}", e)),
+                    }
+                });
+            }
+        }
+        futures_util::future::join_all(checks).await
+    }
+
+    // ── Admin API ─────────────────────────────────────────────────────────────
+
+    async fn handle_admin(&self, req: Request<Incoming>, op: &str, token: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let caller = if admin::authorized(req.headers(), token) {
+            Caller::Operator
+        } else {
+            match admin::bearer(req.headers()).map(|t| self.db_manager.verify_tenant_token(t)) {
+                Some(Ok(Some(tenant))) => Caller::Tenant(tenant.id),
+                Some(Err(e)) => {
+                    error!("Admin: tenant token lookup failed: {:#}", e);
+                    return Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error");
+                }
+                _ => return Self::unauthorized_response("bearer"),
+            }
+        };
+        let action = match admin::parse(req.method(), op, req.uri().query()) {
+            Ok(a) => a,
+            Err((status, message)) => return Self::error_response(status, message),
+        };
+        if let Caller::Tenant(tenant) = &caller {
+            let permitted = match &action {
+                Action::ResetBreakers { mapping: Some(id), .. } => {
+                    matches!(self.db_manager.get_mapping_by_id(id), Ok(Some(m)) if caller.owns(m.owner.as_deref()))
+                }
+                other => other.tenant_allowed(),
+            };
+            if !permitted {
+                warn!("Admin: tenant {} refused {:?}", tenant, action);
+                return Self::error_response(StatusCode::FORBIDDEN, "Forbidden");
+            }
+        }
+        if !matches!(
+            action,
+            Action::Connections
+                | Action::Status
+                | Action::CacheEntries { .. }
+                | Action::Events { .. }
+                | Action::EventStream { .. }
+                | Action::Usage { .. }
+                | Action::Snapshot
+                | Action::Match
+                | Action::Mappings
+        ) {
+            match &caller {
+                Caller::Operator => warn!("Admin: {:?}", action),
+                Caller::Tenant(tenant) => warn!("Admin (tenant {}): {:?}", tenant, action),
+            }
+            self.events.emit(Event::AdminAction { operation: op.trim_end_matches('/').to_string() });
+        }
+        let cleared = match &action {
+            Action::Connections => return Self::json_response(StatusCode::OK, &serde_json::json!(self.conns.stats())),
+            Action::Status => {
+                let status = serde_json::json!({
+                    "connections": self.conns.stats(),
+                    "ejections": self.outliers.ejections(),
+                    "cache": self.response_cache.stats(),
+                    "pools": self.pool.stats(),
+                    "contracts": self.contracts.stats(),
+                    "probes": probes::report(&self.db_manager, chrono::Utc::now().timestamp()).unwrap_or_else(|e| {
+                        error!("Admin: probe report failed: {:#}", e);
+                        Vec::new()
+                    }),
+                });
+                return Self::json_response(StatusCode::OK, &status);
+            }
+            Action::Events { since, limit } => {
+                let records = self.events.since(*since, *limit);
+                let records: Vec<&events::Record> = records.iter().map(|r| &**r).collect();
+                return Self::json_response(StatusCode::OK, &serde_json::json!({ "events": records }));
+            }
+            Action::CacheEntries { sort, limit } => {
+                let entries = self.response_cache.entries(*sort, *limit);
+                return Self::json_response(StatusCode::OK, &serde_json::json!({ "entries": entries }));
+            }
+            Action::EventStream { requests } => return self.event_stream_response(&req, *requests),
+            Action::Usage { period } => {
+                // This instance's latest counts included
+                let period = period.clone().unwrap_or_else(|| usage::period(chrono::Utc::now()));
+                let report = self.usage.flush(&self.db_manager).and_then(|_| self.db_manager.usage_report(Some(&period)));
+                return match report {
+                    Ok(mut rows) => {
+                        rows.retain(|r| caller.owns(r.owner.as_deref()));
+                        Self::json_response(StatusCode::OK, &serde_json::json!({ "period": period, "mappings": rows }))
+                    }
+                    Err(e) => {
+                        error!("Admin: usage report failed: {:#}", e);
+                        Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                    }
+                };
+            }
+            Action::Mappings => return match self.db_manager.list_mappings(None) {
+                Ok(mut mappings) => {
+                    mappings.retain(|m| caller.owns(m.owner.as_deref()));
+                    Self::json_response(StatusCode::OK, &serde_json::json!({ "mappings": snapshot::mappings(mappings) }))
+                }
+                Err(e) => {
+                    error!("Admin: listing mappings failed: {:#}", e);
+                    Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                }
+            },
+            Action::Snapshot => return match self.snapshot() {
+                Ok(snapshot) => Self::json_response(StatusCode::OK, &snapshot),
+                Err(e) => {
+                    error!("Admin: snapshot failed: {:#}", e);
+                    Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                }
+            },
+            Action::Match => {
+                let body = match Limited::new(req.into_body(), admin::MAX_MATCH_BODY).collect().await {
+                    Ok(b) => b.to_bytes(),
+                    Err(_) => return Self::error_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large"),
+                };
+                let query: admin::MatchQuery = match serde_json::from_slice(&body) {
+                    Ok(q) => q,
+                    Err(_) => return Self::error_response(StatusCode::BAD_REQUEST, "Expected {host, path, method, headers}"),
+                };
+                return match self.dry_run(&query) {
+                    Ok(result) => Self::json_response(StatusCode::OK, &result),
+                    Err((status, message)) => Self::error_response(status, message),
+                };
+            }
+            Action::ApplyMappings => {
+                let body = match Limited::new(req.into_body(), admin::MAX_BATCH_BODY).collect().await {
+                    Ok(b) => b.to_bytes(),
+                    Err(_) => return Self::error_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large"),
+                };
+                let batch: admin::Batch = match serde_json::from_slice(&body) {
+                    Ok(b) => b,
+                    Err(e) => return Self::error_response(StatusCode::BAD_REQUEST, &format!("Expected {{\"changes\": [...]}}: {}", e)),
+                };
+                return match self.db_manager.apply_changes(&batch.changes) {
+                    Ok(Ok(applied)) => {
+                        info!("Admin: applied {} mapping changes", applied.len());
+                        Self::json_response(StatusCode::OK, &serde_json::json!({ "operation": "mappings:batch", "applied": applied }))
+                    }
+                    Ok(Err(rejected)) => Self::json_response(StatusCode::CONFLICT, &serde_json::json!({ "error": rejected })),
+                    Err(e) => {
+                        error!("Admin: mapping batch failed: {:#}", e);
+                        Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                    }
+                };
+            }
+            Action::PurgeCache { prefix } => self.response_cache.purge(prefix.as_deref()),
+            Action::ResetRateLimits { key } => match self.rate_limiter.reset(key.as_deref()).await {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Admin: rate-limit reset failed: {}", e);
+                    return Self::error_response(StatusCode::BAD_GATEWAY, "Rate-limit store unavailable");
+                }
+            },
+            Action::FlushDns { backend } => self.discovery.invalidate(backend.as_deref()),
+            Action::ResetBreakers { backend, mapping } => self.reset_breakers(backend.as_deref(), mapping.as_deref()),
+        };
+        let body = serde_json::json!({ "operation": op.trim_end_matches('/'), "cleared": cleared });
+        Self::json_response(StatusCode::OK, &body)
+    }
+
+    /// Configuration, mappings, certificates and backend health (see [`snapshot`]).
+    fn snapshot(&self) -> Result<serde_json::Value> {
+        let scores: std::collections::BTreeMap<String, u8> =
+            self.port_scores.iter().map(|e| (e.key().clone(), *e.value())).collect();
+        let mut probing: Vec<String> = self.bg_checks.iter().map(|e| e.key().clone()).collect();
+        probing.sort();
+        Ok(serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "generated_at": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "config": snapshot::config(&self.config),
+            "mappings": snapshot::mappings(self.db_manager.list_mappings(None)?),
+            "certificates": self.tls.inventory(),
+            "backends": {
+                "scores": scores,
+                "probing": probing,
+                "ejections": self.outliers.ejections(),
+                "pools": self.pool.stats(),
+            },
+        }))
+    }
+
+    /// Route `query` the way [`Self::process_request`] would, without sending it: the
+    /// mapping found (active slot, templates and experiment applied), the path the backend
+    /// would get, and its URL.
+    fn dry_run(&self, query: &admin::MatchQuery) -> Result<serde_json::Value, (StatusCode, &'static str)> {
+        let bad_request = |message| (StatusCode::BAD_REQUEST, message);
+        let method: Method = query.method.parse().map_err(|_| bad_request("Invalid method"))?;
+        let mut req = Request::builder().method(method).uri(query.path.as_str());
+        for (name, value) in &query.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let mut req = req.body(()).map_err(|_| bad_request("Invalid path or headers"))?;
+        let uri = req.uri().clone();
+        let path = normalize::normalize_path(uri.path(), &self.config.path_normalization)
+            .map_err(|_| bad_request("Path rejected by normalization"))?;
+        if path != uri.path() {
+            Self::set_request_path(&mut req, &path).map_err(|_| bad_request("Invalid path"))?;
+            req.extensions_mut().insert(OriginalPath(uri.path().to_string()));
+        }
+        let host = crate::host::parse_authority(&query.host)
+            .and_then(|a| crate::host::normalize_host(a.host))
+            .ok_or(bad_request("Invalid host"))?;
+
+        let database_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "Database error");
+        let Some(mut mapping) = self.db_manager.find_mapping(&host, &path).map_err(database_error)? else {
+            return Ok(serde_json::json!({ "matched": false, "host": host, "path": path }));
+        };
+        if self.awaiting_verification(&mapping).map_err(database_error)? {
+            return Ok(serde_json::json!({ "matched": false, "host": host, "path": path, "unverified": mapping.id }));
+        }
+        mapping.apply_active_slot();
+        let peer = match &query.client_ip {
+            Some(ip) => ip.parse::<IpAddr>().map_err(|_| bad_request("Invalid client_ip"))?,
+            None => IpAddr::from([127, 0, 0, 1]),
+        };
+        let peer = SocketAddr::new(peer, 0);
+        let client_ip = self.client_ip(&req, peer);
+        template::expand_mapping(&mut mapping, &RequestVars { host: &host, path: &path, remote_addr: &Self::peer_ip(peer) });
+        let mut variant = None;
+        if let Some(exp) = mapping.experiment.take().and_then(|json| Experiment::parse(&json).ok()) {
+            let assigned = exp.assign(req.headers(), &client_ip);
+            experiment::apply_variant(&mut mapping, assigned);
+            variant = Some(format!("{}={}", exp.name, assigned.name));
+        }
+        Ok(serde_json::json!({
+            "matched": true,
+            "host": host,
+            "path": path,
+            "method": req.method().as_str(),
+            "mapping": {
+                "id": mapping.id,
+                "domain": mapping.domain,
+                "front_uri": mapping.front_uri,
+                "slot": if mapping.is_green() { "green" } else { "blue" },
+                "back_ports": mapping.back_ports,
+            },
+            "experiment": variant,
+            "upstream_path": Self::upstream_path_and_query(&req, &mapping),
+            "backend_url": Self::build_backend_url(&mapping, req.uri().path(), req.uri().query()),
+        }))
+    }
+
+    /// Admin event stream: events logged after `Last-Event-ID` (when sent), then live ones
+    /// and, with `requests`, finished requests, with a comment every 15s so idle connections
+    /// aren't cut by proxies in between.
+    fn event_stream_response<T>(&self, req: &Request<T>, requests: bool) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let live = self.events.subscribe();
+        let traffic = requests.then(|| self.events.subscribe_traffic());
+        let last = req.headers().get("last-event-id").and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<u64>().ok());
+        let backlog = last.map(|id| self.events.since(Some(id), usize::MAX)).unwrap_or_default();
+        let seen = backlog.last().map(|r| r.id).or(last).unwrap_or(0);
+        let head = format!(": connected\n\n{}", backlog.iter().map(|r| events::sse_message(r)).collect::<String>());
+
+        let state = (live, traffic, seen, Some(head));
+        let frames = futures_util::stream::unfold(state, |(mut live, mut traffic, mut seen, head)| async move {
+            let chunk = match head {
+                Some(head) => head,
+                None => loop {
+                    let next_request = async {
+                        match traffic.as_mut() {
+                            Some(traffic) => traffic.recv().await,
+                            None => std::future::pending().await,
+                        }
+                    };
+                    let keepalive = tokio::time::sleep(Duration::from_secs(15));
+                    tokio::select! {
+                        event = live.recv() => match event {
+                            // Already sent from the backlog
+                            Ok(record) if record.id <= seen => continue,
+                            Ok(record) => {
+                                seen = record.id;
+                                break events::sse_message(&record);
+                            }
+                            Err(RecvError::Lagged(n)) => break format!(": missed {} events\n\n", n),
+                            Err(RecvError::Closed) => return None,
+                        },
+                        request = next_request => match request {
+                            Ok(request) => break events::sse_request(&request),
+                            Err(RecvError::Lagged(n)) => break format!(": missed {} requests\n\n", n),
+                            Err(RecvError::Closed) => return None,
+                        },
+                        _ = keepalive => break ": keepalive\n\n".to_string(),
+                    }
+                },
+            };
+            Some((Ok::<_, hyper::Error>(Frame::data(Bytes::from(chunk))), (live, traffic, seen, None)))
+        });
+        Response::builder()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .body(BodyExt::boxed(StreamBody::new(frames)))
+            .unwrap()
+    }
+
+    async fn handle_acme_server(req: Request<Incoming>, what: &str, acme: &Arc<AcmeServer>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let Some(authority) = crate::host::request_authority(&req) else {
+            return Self::error_response(StatusCode::BAD_REQUEST, "Bad Request");
+        };
+        let scheme = if Self::is_https_request(&req) { "https" } else { "http" };
+        let base = format!("{}://{}{}", scheme, authority, acme_server::PREFIX);
+        let method = req.method().clone();
+        let body = match Limited::new(req.into_body(), acme_server::MAX_REQUEST).collect().await {
+            Ok(b) => b.to_bytes(),
+            Err(_) => return Self::error_response(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large"),
+        };
+        acme.handle(&method, what, &base, body).await.map(|b| b.map_err(|never| match never {}).boxed())
+    }
+
+    async fn handle_oidc<T>(&self, req: &Request<T>, what: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let secure = Self::is_https_request(req);
+        match what {
+            "callback" => {
+                let settings = self.oidc.callback_mapping(req.headers())
+                    .and_then(|id| self.db_manager.get_mapping_by_id(&id).ok().flatten())
+                    .and_then(|m| m.oidc)
+                    .and_then(|json| OidcSettings::parse(&json).ok());
+                let (Some(settings), Some(redirect_uri)) = (settings, Self::oidc_redirect_uri(req)) else {
+                    return Self::error_response(StatusCode::BAD_REQUEST, "No login in progress");
+                };
+                let query = req.uri().query().unwrap_or("");
+                match self.oidc.callback(req.headers(), query, &settings, &redirect_uri, secure).await {
+                    Ok(redirect) => Self::found_response(&redirect.location, &redirect.cookies),
+                    Err(e) => {
+                        warn!("OIDC login via {} failed: {:#}", settings.issuer, e);
+                        Self::error_response(StatusCode::FORBIDDEN, "Login failed")
+                    }
+                }
+            }
+            "logout" => {
+                let target = req.uri().query()
+                    .and_then(|q| url::form_urlencoded::parse(q.as_bytes()).find(|(k, _)| k == "rd"))
+                    .map(|(_, v)| oidc::safe_return(&v).to_string())
+                    .unwrap_or_else(|| "/".to_string());
+                Self::found_response(&target, &[self.oidc.logout(req.headers(), secure).await])
+            }
+            _ => Self::error_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    /// Absolute URL of the OIDC callback on the host the request came to.
+    fn oidc_redirect_uri<T>(req: &Request<T>) -> Option<String> {
+        let authority = crate::host::request_authority(req)?;
+        let scheme = if Self::is_https_request(req) { "https" } else { "http" };
+        Some(format!("{}://{}{}", scheme, authority, oidc::CALLBACK_PATH))
+    }
+
+    /// Let signed-in users through with their identity headers set; otherwise send browsers
+    /// to the provider and answer others 401.
+    async fn check_oidc(&self, req: &mut Request<Incoming>, mapping: &Mapping, json: &str) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let settings = match OidcSettings::parse(json) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Mapping {} has invalid OIDC settings: {:#}", mapping.domain, e);
+                return Some(Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"));
+            }
+        };
+        if let Some(session) = self.oidc.session(req.headers(), &settings).await {
+            session.apply(req.headers_mut());
+            oidc::strip_cookies(req.headers_mut());
+            return None;
+        }
+        let browser = matches!(*req.method(), Method::GET | Method::HEAD)
+            && req.headers().get(hyper::header::ACCEPT).and_then(|a| a.to_str().ok()).is_some_and(|a| a.contains("text/html"));
+        if !browser {
+            return Some(Self::error_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+        }
+        let redirect_uri = Self::oidc_redirect_uri(req)?;
+        let return_to = req.extensions().get::<OriginalPath>().map(|p| p.0.as_str()).unwrap_or(req.uri().path());
+        let return_to = match req.uri().query() {
+            Some(q) => format!("{}?{}", return_to, q),
+            None => return_to.to_string(),
+        };
+        let secure = Self::is_https_request(req);
+        Some(match self.oidc.login(&settings, &mapping.id, &redirect_uri, &return_to, secure).await {
+            Ok(redirect) => Self::found_response(&redirect.location, &redirect.cookies),
+            Err(e) => {
+                error!("OIDC login via {} unavailable: {:#}", settings.issuer, e);
+                Self::error_response(StatusCode::BAD_GATEWAY, "Login unavailable")
+            }
+        })
+    }
+
+    fn handle_cluster<T>(&self, req: &Request<T>, what: &str, cluster: &Cluster) -> Response<BoxBody<Bytes, hyper::Error>> {
+        if !cluster.authorized(req.headers()) {
+            return Self::unauthorized_response("bearer");
+        }
+        if req.method() != Method::GET {
+            return Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Use GET");
+        }
+        match what {
+            "state" => Self::json_response(StatusCode::OK, &serde_json::json!(cluster.state())),
+            "snapshot" => match cluster.snapshot() {
+                Ok(snapshot) => Self::json_response(StatusCode::OK, &serde_json::json!(snapshot)),
+                Err(e) => {
+                    error!("Cluster: snapshot failed: {:#}", e);
+                    Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                }
+            },
+            "certs" => match cluster.certs() {
+                Some(Ok(files)) => Self::json_response(StatusCode::OK, &serde_json::json!(files)),
+                Some(Err(e)) => {
+                    error!("Cluster: reading certificates failed: {:#}", e);
+                    Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                }
+                None => Self::error_response(StatusCode::NOT_FOUND, "Not the certificate leader"),
+            },
+            // Only what this node knows: peers ask on a miss, they don't relay
+            _ if what.starts_with("challenge/") => match self.cert_manager.get_acme_challenge(&what["challenge/".len()..]) {
+                Some(k) => Self::text_response(StatusCode::OK, &k),
+                None => Self::error_response(StatusCode::NOT_FOUND, "Challenge not found"),
+            },
+            _ => Self::error_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    /// Forget HA scores (and stop background probes) so matching targets are tried again
+    /// at full score. Returns how many scored targets were reset.
+    /// Whether `mapping` belongs to a tenant that has yet to prove control of its domain.
+    fn awaiting_verification(&self, mapping: &Mapping) -> Result<bool> {
+        match (&mapping.owner, self.config.require_domain_verification) {
+            (Some(owner), true) => Ok(!self.db_manager.is_domain_verified(&mapping.domain, owner)?),
+            _ => Ok(false),
+        }
+    }
+
+    fn reset_breakers(&self, backend: Option<&str>, mapping_id: Option<&str>) -> usize {
+        // Keys are "{mapping_id}:{host}:{port}"
+        let matches = |key: &str| {
+            backend.is_none_or(|b| key.ends_with(&format!(":{}", b)))
+                && mapping_id.is_none_or(|m| key.starts_with(&format!("{}:", m)))
+        };
+        let keys: Vec<String> = self.port_scores.iter()
+            .filter(|e| matches(e.key()))
+            .map(|e| e.key().clone())
+            .collect();
+        for key in &keys {
+            self.port_scores.remove(key);
+            self.bg_checks.remove(key);
+        }
+        keys.len() + self.outliers.reset(|key| !keys.iter().any(|k| k == key) && matches(key))
+    }
+
+    // ── Blue/green helpers ────────────────────────────────────────────────────
+
+    /// Count a response against a mapping that was recently switched, and roll the switch
+    /// back if the new slot's error rate crosses the configured threshold.
+    fn observe_probation(&self, mapping: &Mapping, failed: bool) {
+        let (Some(until), Some(switched_at)) = (&mapping.probation_until, &mapping.switched_at) else {
+            return;
+        };
+        match chrono::DateTime::parse_from_rfc3339(until) {
+            Ok(until) if chrono::Utc::now() < until => {}
+            _ => return,
+        }
+
+        let mut stats = self.probation.entry(mapping.id.clone()).or_default();
+        if stats.switched_at != *switched_at {
+            *stats = ProbationStats { switched_at: switched_at.clone(), ..Default::default() };
+        }
+        stats.total += 1;
+        if failed {
+            stats.errors += 1;
+        }
+        let (total, errors) = (stats.total, stats.errors);
+        drop(stats);
+
+        if total < self.config.rollback_min_requests
+            || (errors as f64) < self.config.rollback_error_rate * total as f64
+        {
+            return;
+        }
+
+        self.probation.remove(&mapping.id);
+        let from = if mapping.is_green() { "green" } else { "blue" };
+        let to = if mapping.is_green() { "blue" } else { "green" };
+        warn!(
+            "Blue/green: {}/{} errored on {}/{} requests after switch to {}, rolling back to {}",
+            mapping.domain, mapping.front_uri, errors, total, from, to
+        );
+        let db = self.db_manager.clone();
+        let id = mapping.id.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = db.switch_slot(&id, to, None) {
+                error!("Blue/green: rollback of mapping {} failed: {}", id, e);
+            }
+        });
+    }
+
+    /// All candidate targets for a multi-backend mapping: the `back_ports` list on the
+    /// backend host, or the instances currently registered for a discovery backend.
+    async fn backend_targets(&self, mapping: &Mapping) -> Result<Vec<Endpoint>> {
+        let backend = mapping.backend.as_deref().unwrap_or("http://localhost");
+        if let Some(source) = DiscoverySource::parse(backend) {
+            return Ok(self.discovery.resolve(backend, &source).await?.to_vec());
+        }
+
+        let backend_url: Url = backend.parse().unwrap_or_else(|_| "http://localhost".parse().unwrap());
+        let backend_host = backend_url.host_str().unwrap_or("localhost").to_string();
+        Ok(mapping.back_ports.as_deref().unwrap_or("")
+            .split(',')
+            .filter_map(|s| s.trim().parse().ok())
+            .map(|port| Endpoint::new(backend_host.clone(), port))
+            .collect())
+    }
+
+    fn uses_discovery(mapping: &Mapping) -> bool {
+        mapping.backend.as_deref().and_then(DiscoverySource::parse).is_some()
+    }
+
+    /// Start the proxy server (binds its own listeners — used in single-worker mode).
+    ///
+    /// Resolves once the listeners are bound; port 0 takes an OS-assigned port, see
+    /// [`ServerHandle::http_addr`]. Await [`ServerHandle::wait`] to serve until stopped.
+    pub async fn run(self: Arc<Self>) -> Result<ServerHandle> {
+        let http_addr: SocketAddr = format!("{}:{}", self.config.http_host, self.config.http_port).parse()?;
+        let listener = TcpListener::bind(http_addr).await?;
+        self.config.client_tcp.apply_buffers(&SockRef::from(&listener))?;
+        let http_addr = listener.local_addr()?;
+        info!("Proxy server starting on HTTP:{}", http_addr.port());
+
+        let mut tasks = Vec::new();
+        let mut https_addr = None;
+        if self.config.enable_https {
+            let addr: SocketAddr = format!("{}:{}", self.config.http_host, self.config.https_port).parse()?;
+            let tls_listener = TcpListener::bind(addr).await?;
+            self.config.client_tcp.apply_buffers(&SockRef::from(&tls_listener))?;
+            let addr = tls_listener.local_addr()?;
+            info!("Proxy server starting on HTTPS:{}", addr.port());
+            https_addr = Some(addr);
+            tasks.push(tokio::spawn(self.clone().run_tls_with_listener(tls_listener)));
+        }
+        tasks.push(tokio::spawn(self.run_with_listener(listener)));
+        Ok(ServerHandle { http_addr, https_addr, tasks })
+    }
+
+    /// Accept loop on a pre-bound listener.
+    /// Called by each worker thread when running in multi-worker mode.
+    pub async fn run_with_listener(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        info!("HTTP worker listening on {}", listener.local_addr()?);
+        self.start_background_tasks();
+
+        loop {
+            let (stream, remote_addr, guard) = self.accept(&listener, false).await;
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                let _guard = guard;
+                if let Err(e) = Self::handle_connection(stream, remote_addr, proxy, None).await {
+                    debug!("HTTP connection error from {}: {}", remote_addr, e);
+                }
+            });
+        }
+    }
+
+    /// HTTPS accept loop on a pre-bound listener. Certificates are chosen by SNI from
+    /// `certs_dir` and reloaded when their files change.
+    pub async fn run_tls_with_listener(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        info!("HTTPS worker listening on {}", listener.local_addr()?);
+        self.start_background_tasks();
+        let acceptor = tokio_rustls::TlsAcceptor::from(self.tls.server_config(self.config.tls_client_ca.as_deref())?);
+
+        loop {
+            let (stream, remote_addr, guard) = self.accept(&listener, true).await;
+            let proxy = self.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let _guard = guard;
+                let handshake = tokio::time::timeout(proxy.config.header_read_timeout, acceptor.accept(stream));
+                let stream = match handshake.await {
+                    Ok(Ok(s)) => s,
+                    Ok(Err(e)) => return debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Err(_) => return debug!("TLS handshake with {} timed out", remote_addr),
+                };
+                let tls = TlsConnection(Arc::new(TlsInfo::of(stream.get_ref().1)));
+                if let Err(e) = Self::handle_connection(stream, remote_addr, proxy, Some(tls)).await {
+                    debug!("HTTPS connection error from {}: {}", remote_addr, e);
+                }
+            });
+        }
+    }
+
+    /// Next admitted client connection. Over the connection cap it is turned away (or
+    /// accepting pauses, per `connection_limit_action`); accept errors such as EMFILE back
+    /// off briefly instead of ending the loop.
+    async fn accept(&self, listener: &TcpListener, tls: bool) -> (TcpStream, SocketAddr, ConnectionGuard) {
+        loop {
+            if self.config.connection_limit_action == LimitAction::Pause {
+                self.conns.wait_for_room().await;
+            }
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("accept() failed: {} (open connections: {:?})", e, self.conns.stats());
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            match self.conns.try_admit() {
+                Some(guard) => {
+                    if let Err(e) = self.config.client_tcp.apply(&stream) {
+                        debug!("Socket options of {} not set: {}", remote_addr, e);
+                    }
+                    return (stream, remote_addr, guard);
+                }
+                None => {
+                    debug!("Connection limit reached, turning away {}", remote_addr);
+                    // Best effort: a fresh socket's send buffer takes this without blocking
+                    if let (false, Ok(mut stream)) = (tls, stream.into_std()) {
+                        let _ = std::io::Write::write(
+                            &mut stream,
+                            b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_connection<S>(
+        stream: S,
+        remote_addr: SocketAddr,
+        proxy: Arc<Self>,
+        tls: Option<TlsConnection>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        // 103 Early Hints are written next to hyper, while it waits for a response
+        let (io, hints) = early_hints::HintsIo::new(stream);
+        let io = TokioIo::new(io);
+        let config = &proxy.config;
+        http1::Builder::new()
+            .preserve_header_case(true)
+            .title_case_headers(false)
+            .max_headers(config.max_headers)
+            // hyper's read buffer bounds the request head; it can't go below 8 KiB
+            .max_buf_size(config.max_header_bytes.max(8192))
+            .timer(hyper_util::rt::TokioTimer::new())
+            .header_read_timeout(config.header_read_timeout)
+            .serve_connection(
+                io,
+                service_fn(move |mut req: Request<Incoming>| {
+                    let p = proxy.clone();
+                    if let Some(tls) = &tls {
+                        req.extensions_mut().insert(tls.clone());
+                    }
+                    if req.version() == Version::HTTP_11 {
+                        req.extensions_mut().insert(hints.clone());
+                    }
+                    async move { Self::handle_request(req, remote_addr, p).await }
+                }),
+            )
+            .with_upgrades()
+            .await
+            .map_err(|e| anyhow!("HTTP service error: {}", e))
+    }
+
+    async fn handle_request(
+        mut req: Request<Incoming>,
+        remote_addr: SocketAddr,
+        proxy: Arc<Self>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
+        let started = std::time::Instant::now();
+        let trace = Trace::requested(req.headers_mut(), proxy.config.debug_secret.as_deref());
+        // Someone follows the traffic: note the backend that answers
+        let followed = trace.clone().or_else(|| proxy.events.watching_traffic().then(Trace::default));
+        if let Some(trace) = &followed {
+            req.extensions_mut().insert(trace.clone());
+        }
+        let mut log = AccessLog::new(&req, remote_addr);
+        log.policy = proxy.config.access_log.clone();
+        log.tls = req.extensions().get::<TlsConnection>().map(|t| t.0.clone());
+        let client_ip = proxy.client_ip(&req, remote_addr);
+        let bytes_in = req.headers().get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let mut response = match proxy.process_request(req, remote_addr, &mut log).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Request error: {}", e);
+                Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+            }
+        };
+        if let Some(trace) = trace {
+            response.headers_mut().insert(debug_trace::HEADER, trace.header_value(started.elapsed()));
+        }
+        log.finish(response.status().as_u16());
+        if let Some(analytics) = &proxy.analytics {
+            let bytes_out = hyper::body::Body::size_hint(response.body()).exact().unwrap_or(0);
+            analytics.record(log.analytics_row(response.status().as_u16(), bytes_in, bytes_out));
+        }
+        if let (true, Some(id)) = (proxy.config.anomaly_alerts, log.mapping_id.as_deref()) {
+            proxy.anomalies.record(id, response.status().as_u16(), started.elapsed());
+        }
+        if let Some(followed) = followed.filter(|_| proxy.events.watching_traffic()) {
+            proxy.events.request(log.traffic(response.status().as_u16(), followed.backend()));
+        }
+        if let (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, Some(id)) = (response.status(), log.mapping_id.as_deref()) {
+            proxy.note_ban(&client_ip, proxy.bans.auth_failure(&client_ip, id, &proxy.db_manager));
+        }
+        Ok(match log.mapping_id.as_deref() {
+            Some(id) if response.extensions().get::<QuotaExceeded>().is_none() => proxy.usage.record(id, bytes_in, response),
+            _ => response,
+        })
+    }
+
+    /// Log and announce an automatic ban of `client_ip`, if one was given.
+    fn note_ban(&self, client_ip: &str, ban: Result<Option<database::Ban>>) {
+        match ban {
+            Ok(Some(ban)) => {
+                warn!("Banned {} until {}: {}", ban.network, ban.expires_at.as_deref().unwrap_or("lifted"), ban.reason.as_deref().unwrap_or_default());
+                self.events.emit(Event::ClientBanned { network: ban.network, source: ban.source, reason: ban.reason, until: ban.expires_at });
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Could not store ban of {}: {:#}", client_ip, e),
+        }
+    }
+
+    async fn process_request(
+        self: &Arc<Self>,
+        mut req: Request<Incoming>,
+        remote_addr: SocketAddr,
+        log: &mut AccessLog,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        // Cheap handles (shared buffers) on the target and Host header, so the path and
+        // host below can borrow from them while `req` is modified and moved
+        let uri = req.uri().clone();
+        let host_header = req.headers().get(HOST).cloned();
+
+        // Normalize before anything looks at the path, so matching and forwarding agree
+        let path = match normalize::normalize_path(uri.path(), &self.config.path_normalization) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Rejecting path {:?} from {}: {}", uri.path(), remote_addr, e);
+                return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Bad Request"));
+            }
+        };
+        if path != uri.path() {
+            Self::set_request_path(&mut req, &path)?;
+            req.extensions_mut().insert(OriginalPath(uri.path().to_string()));
+        }
+        let method = req.method().clone();
+
+        debug!("{} {} from {}", method, path, remote_addr);
+
+        if let Some((status, message)) = self.check_request_limits(&req) {
+            return Ok(Self::error_response(status, message));
+        }
+        if let Err(problem) = duplicate_headers::settle(req.headers_mut(), &uri, self.config.duplicate_headers) {
+            debug!("Refusing request from {}: {}", remote_addr, problem);
+            return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Bad Request: {}", problem)));
+        }
+
+        // Health check and admin API, unless they live on the internal listener
+        let public_builtins = self.config.internal_listen.is_none();
+        if public_builtins && path == "/health" && self.health_allowed(remote_addr) {
+            return Ok(Self::text_response(StatusCode::OK, "OK"));
+        }
+
+        // Cluster peers pulling state
+        if let (Some(what), Some(cluster)) = (path.strip_prefix(cluster::PREFIX), &self.cluster) {
+            return Ok(self.handle_cluster(&req, what, cluster));
+        }
+
+        if let (true, Some(op), Some(token)) = (public_builtins, path.strip_prefix(admin::PREFIX), self.config.admin_token.as_deref()) {
+            return Ok(self.handle_admin(req, op, token).await);
+        }
+
+        // OIDC login callback and logout
+        if let Some(what) = path.strip_prefix(oidc::PREFIX) {
+            return Ok(self.handle_oidc(&req, what).await);
+        }
+
+        // Internal ACME server
+        if let (Some(what), Some(acme)) = (path.strip_prefix(acme_server::PREFIX), &self.acme_server) {
+            return Ok(Self::handle_acme_server(req, what, acme).await);
+        }
+
+        // ACME test challenge
+        if path.starts_with("/.well-known/test-challenge/") {
+            let token = path.strip_prefix("/.well-known/test-challenge/").unwrap_or("");
+            return match self.cert_manager.get_test_challenge(token) {
+                Some(v) => Ok(Self::text_response(StatusCode::OK, &v)),
+                None => Ok(Self::error_response(StatusCode::NOT_FOUND, "Not found")),
+            };
+        }
+
+        // ACME challenge
+        if path.starts_with("/.well-known/acme-challenge/") {
+            if self.config.acme_challenges_mapped_only && !self.is_mapped_host(&uri, host_header.as_ref()) {
+                return Ok(Self::error_response(StatusCode::NOT_FOUND, "Challenge not found"));
+            }
+            let token = path.strip_prefix("/.well-known/acme-challenge/").unwrap_or("");
+            let mut found = self.cert_manager.get_acme_challenge(token);
+            // Cluster mode: the order may have been placed by a node with another database
+            if let (None, Some(cluster)) = (&found, &self.cluster) {
+                found = cluster.acme_challenge(token).await;
+            }
+            return match found {
+                Some(k) => Ok(Self::text_response(StatusCode::OK, &k)),
+                None => Ok(Self::error_response(StatusCode::NOT_FOUND, "Challenge not found")),
+            };
+        }
+
+        // Force HTTPS redirect (an unparsable Host falls through to the 400 below)
+        if self.config.force_https && !Self::is_https_request(&req) {
+            if let Some(authority) = crate::host::request_authority(&req).and_then(crate::host::parse_authority) {
+                let path_and_query = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+                let location = Self::https_location(authority.host, self.config.https_port, path_and_query);
+                // 301 lets clients turn a POST into a GET; 308 keeps method and body
+                return Ok(match *req.method() {
+                    Method::GET | Method::HEAD => Self::redirect_response(&location),
+                    _ => Self::permanent_redirect_response(&location),
+                });
+            }
+        }
+
+        // Trailing-slash policy
+        if let Some(target) = normalize::trailing_slash_redirect(&path, self.config.path_normalization.trailing_slash) {
+            let location = match req.uri().query() {
+                Some(q) => format!("{}?{}", target, q),
+                None => target,
+            };
+            return Ok(Self::permanent_redirect_response(&location));
+        }
+
+        // Resolve host (lowercase, IDNA, no trailing dot — the form domains are stored in)
+        let host = crate::host::authority_of(&uri, host_header.as_ref())
+            .map(|a| crate::host::parse_authority(a).and_then(|a| crate::host::normalize_host(a.host)));
+
+        let host = match host {
+            Some(Some(h)) => h,
+            Some(None) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Invalid Host header")),
+            None => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Missing Host header")),
+        };
+
+        // Banned clients get nothing, whichever address they are banned by
+        let client_ip = self.client_ip(&req, remote_addr);
+        if self.bans.banned(&client_ip) || self.bans.banned(&Self::peer_ip(remote_addr)) {
+            debug!("Refusing banned client {} ({})", client_ip, remote_addr);
+            return Ok(Self::error_response(StatusCode::FORBIDDEN, "Forbidden"));
+        }
+
+        // Find mapping (blue/green: resolved to the live slot); one awaiting proof of domain
+        // control is treated as absent
+        let mut found = self.db_manager.find_mapping(&host, &path)?;
+        if let Some(m) = &found {
+            if self.awaiting_verification(m)? {
+                debug!("{} not routed: tenant {} has not verified the domain", m.id, m.owner.as_deref().unwrap_or_default());
+                found = None;
+            }
+        }
+        let mut mapping = match found {
+            Some(mut m) => {
+                m.apply_active_slot();
+                m
+            }
+            None => {
+                let fb = self.fallback.handle(req, remote_addr).await?;
+                return Ok(fb.map(|b| b.map_err(|never| match never {}).boxed()));
+            }
+        };
+
+        log.mapping_id = Some(mapping.id.clone());
+        if let Some(trace) = req.extensions().get::<Trace>() {
+            trace.mapping(&mapping.id);
+        }
+        log.policy = log.policy.with_overrides(mapping.log_level.as_deref(), mapping.log_sample.as_deref());
+        let route = Route::of(&mapping);
+
+        // A backend that leads back here: stop after a few rounds instead of recursing
+        let hops = crate::hops::count(req.headers());
+        if self.config.max_proxy_hops > 0 && hops >= self.config.max_proxy_hops {
+            warn!("Loop detected for {}{}: request already passed {} proxies", mapping.domain, path, hops);
+            return Ok(Self::error_response(StatusCode::LOOP_DETECTED, "Loop Detected"));
+        }
+
+        // Header limits tighter than the listener's (see `header_limits`)
+        if let Some(limits) = mapping.header_limits.as_deref().and_then(|l| l.parse::<HeaderLimits>().ok()) {
+            if let Some(limit) = limits.exceeded_by(req.headers(), Self::request_line_len(&req)) {
+                debug!("Request for {}{} over the mapping's header {} limit", mapping.domain, path, limit);
+                return Ok(Self::error_response(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large"));
+            }
+        }
+
+        // IP allowlist check
+        if !Self::is_ip_allowed(&client_ip, mapping.allowed_ips.as_deref()) {
+            return Ok(Self::error_response(StatusCode::FORBIDDEN, "Forbidden"));
+        }
+
+        // Access hours (see `access_hours`)
+        if let Some(hours) = mapping.access_hours.as_deref().and_then(|h| h.parse::<AccessHours>().ok()) {
+            let now = chrono::Utc::now();
+            if !hours.is_open(now) {
+                debug!("{}{} requested outside its access hours", mapping.domain, path);
+                return Ok(Self::closed_response(&hours, now).await);
+            }
+        }
+
+        // Rate limit per client and domain (across instances when counted in Redis)
+        if let Some(limit) = ratelimit::effective(mapping.rate_limit.as_deref(), self.config.rate_limit) {
+            let key = ratelimit::client_key(&mapping.domain, &client_ip);
+            if let Decision::Deny { retry_after } = self.rate_limiter.check(&key, &limit).await {
+                self.events.emit(Event::RateLimited { domain: mapping.domain.clone(), client: client_ip.clone() });
+                self.note_ban(&client_ip, self.bans.strike(&client_ip, &self.db_manager));
+                let mut resp = Self::error_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                resp.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+                return Ok(resp);
+            }
+        }
+
+        // Monthly quota (see `usage`)
+        if let Some(quota) = mapping.quota.as_deref().and_then(|q| q.parse::<Quota>().ok()) {
+            if quota.exceeded_by(&self.usage.used(&mapping.id)) {
+                debug!("Quota of {}/{} reached", mapping.domain, mapping.front_uri);
+                let mut resp = Self::error_response(StatusCode::TOO_MANY_REQUESTS, "Quota Exceeded");
+                let secs = usage::until_next_period(chrono::Utc::now()).as_secs();
+                resp.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+                resp.extensions_mut().insert(QuotaExceeded);
+                return Ok(resp);
+            }
+        }
+
+        // Auth check
+        if mapping.auth_type.as_deref() == Some(database::API_KEY_AUTH) {
+            match self.check_api_key(&req, &mapping) {
+                Some(key_id) => log.api_key = Some(key_id),
+                None => return Ok(Self::unauthorized_response("api_key")),
+            }
+        } else {
+            let auth = Self::check_auth(&req, &mapping);
+            if !auth.allowed {
+                return Ok(Self::unauthorized_response(auth.scheme));
+            }
+            if let Some(idx) = auth.credential_index {
+                let db = self.db_manager.clone();
+                let mid = mapping.id.clone();
+                tokio::task::spawn_blocking(move || db.record_auth_use(&mid, idx));
+            }
+        }
+
+        // Signed URLs: valid signature and expiry required, then stripped from the query
+        if let Some(secret) = mapping.url_signing_secret.as_deref() {
+            let now = chrono::Utc::now().timestamp().max(0) as u64;
+            match signed_url::verify(&signed_url::key(secret), &path, req.uri().query(), now) {
+                Ok(query) => Self::set_request_query(&mut req, query.as_deref())?,
+                Err(rejection) => {
+                    debug!("Signed URL check for {}{} failed: {:?}", host, path, rejection);
+                    return Ok(Self::error_response(StatusCode::FORBIDDEN, rejection.message()));
+                }
+            }
+        }
+
+        // OIDC login: signed-in users only, their identity passed on in headers
+        if let Some(json) = mapping.oidc.as_deref() {
+            if let Some(resp) = self.check_oidc(&mut req, &mapping, json).await {
+                return Ok(resp);
+            }
+        }
+
+        // Body rules: declared size and media type
+        if let Some((status, message)) = Self::check_body_rules(&req, &mapping) {
+            return Ok(Self::error_response(status, message));
+        }
+
+        // ${ENV} / $host / $path / $remote_addr in backend and back_uri; the address is the
+        // connecting one, as X-Forwarded-For is whatever the client says
+        template::expand_mapping(&mut mapping, &RequestVars {
+            host: &host,
+            path: &path,
+            remote_addr: &Self::peer_ip(remote_addr),
+        });
+
+        // A/B experiment: sticky variant per user, announced to backend and client
+        let mut assignment = None;
+        if let Some(json) = mapping.experiment.take() {
+            match Experiment::parse(&json) {
+                Ok(exp) => {
+                    let variant = exp.assign(req.headers(), &client_ip);
+                    experiment::apply_variant(&mut mapping, variant);
+                    let tag = format!("{}={}", exp.name, variant.name);
+                    if let Ok(v) = tag.parse::<hyper::header::HeaderValue>() {
+                        req.headers_mut().insert(experiment::HEADER, v.clone());
+                        assignment = Some(v);
+                    }
+                    log.experiment = Some(tag);
+                }
+                Err(e) => warn!("Ignoring invalid experiment on {}: {}", mapping.domain, e),
+            }
+        }
+
+        // Body rewrites, base path and minification: only let the backend pick codings that
+        // can be decoded for editing. HEAD responses have no body, so only their headers are
+        // touched.
+        let has_body = *req.method() != Method::HEAD;
+        let rewrites = match mapping.body_rewrites.as_deref() {
+            Some(json) if has_body => self.body_rewrites.get(json),
+            _ => None,
+        };
+        let base_path = match mapping.html_base.as_deref().map(str::parse::<BasePathMode>) {
+            Some(Ok(mode)) if !mapping.front_uri.is_empty() => Some((mode, format!("/{}", mapping.front_uri))),
+            Some(Err(e)) => {
+                warn!("Ignoring HTML base mode of {}: {}", mapping.domain, e);
+                None
+            }
+            _ => None,
+        };
+        let minify = match mapping.minify.as_deref().map(str::parse::<Minify>) {
+            Some(Ok(settings)) if has_body => Some(settings),
+            Some(Err(e)) => {
+                warn!("Ignoring minify settings of {}: {}", mapping.domain, e);
+                None
+            }
+            _ => None,
+        };
+        let edits_body = rewrites.is_some() || (base_path.is_some() && has_body) || minify.is_some();
+        let mut accept_encoding = None;
+        if edits_body {
+            accept_encoding = req.headers_mut().remove(hyper::header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok().map(str::to_string));
+            if let Some(decodable) = accept_encoding.as_deref().and_then(content_coding::decodable) {
+                req.headers_mut().insert(hyper::header::ACCEPT_ENCODING, decodable);
+            }
+        }
+
+        // Image filter: w=, h=, format= and q= ask for a resized image; the backend gets the
+        // request without them, and all of the image
+        let images = match mapping.image_filter.as_deref().map(str::parse::<ImageFilter>) {
+            Some(Ok(filter)) if method == Method::GET => match Transform::from_query(req.uri().query()) {
+                Ok(transform) => transform.map(|t| (t, filter)),
+                Err(e) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, &format!("Bad Request: {}", e))),
+            },
+            Some(Err(e)) => {
+                warn!("Ignoring image filter of {}: {}", mapping.domain, e);
+                None
+            }
+            _ => None,
+        };
+        if images.is_some() {
+            let query = req.uri().query().and_then(Transform::strip_query);
+            Self::set_request_query(&mut req, query.as_deref())?;
+            req.headers_mut().remove(hyper::header::RANGE);
+        }
+
+        // Client deadline: capped, passed on to the backend, and enforced until the
+        // response head arrives
+        let budget = deadline::budget(req.headers(), self.config.max_request_timeout);
+        if let Some(b) = budget {
+            deadline::forward(req.headers_mut(), b);
+        }
+        let expired = async move {
+            match budget {
+                Some(b) => tokio::time::sleep(b).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        // Startup page: page loads the backend is slow to answer get an auto-refreshing page
+        let startup = mapping.startup_page.as_deref()
+            .and_then(|s| s.parse::<StartupPage>().ok())
+            .filter(|_| startup_page::applies(&method, req.headers()));
+        let startup_after = startup.as_ref().map(|s| s.after);
+        let slow = async move {
+            match startup_after {
+                Some(after) => tokio::time::sleep(after).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        // Early Hints: preload links for page loads go out before the backend answers
+        let early = mapping.early_hints.as_deref().and_then(|h| h.parse::<EarlyHints>().ok());
+        let preload: Vec<HeaderValue> = match &early {
+            Some(e) if early_hints::is_navigation(&method, req.headers()) => {
+                e.links.iter().filter_map(|l| HeaderValue::from_str(l).ok()).collect()
+            }
+            _ => Vec::new(),
+        };
+        let hints = early.as_ref().and_then(|e| early_hints::Session::start(e, preload.clone(), &mut req));
+
+        // Generated ETags: the client's validator is checked against the final response
+        let if_none_match = match self.config.generate_etags && method == Method::GET {
+            true => Some(req.headers().get(hyper::header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()).map(str::to_string)),
+            false => None,
+        };
+
+        // Stale-if-error: good GET responses are kept so a failing backend can be answered
+        // with the last one
+        let stale_key = match mapping.stale_if_error {
+            Some(secs) if secs > 0 && method == Method::GET && !req.headers().contains_key(hyper::header::AUTHORIZATION)
+                && !Self::is_websocket_upgrade(&req) =>
+            {
+                let path_and_query = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+                Some((CacheKey::new(&mapping.id, &host, path_and_query, &path, req.headers()), Duration::from_secs(secs)))
+            }
+            _ => None,
+        };
 
-    /// TCP-probe a port in the background until it responds; then set score to 50
-    /// so the next real request gives it a try.
-    fn start_background_check(self: Arc<Self>, mapping_id: String, port: u16, host: String) {
-        let key = Self::port_key(&mapping_id, port);
-        if self.bg_checks.contains_key(&key) {
-            return;
+        // Header allow-list: everything the proxy itself needed from the request is read
+        if let Some(list) = mapping.forward_headers.as_deref().and_then(|l| l.parse::<ForwardHeaders>().ok()) {
+            list.retain(req.headers_mut());
+        }
+        let tls = req.extensions().get::<TlsConnection>().map(|t| t.0.clone());
+        if self.config.forward_tls_headers {
+            TlsInfo::forward(tls.as_deref(), req.headers_mut());
+        }
+        if let Some(details) = self.config.forward_client_cert {
+            details.apply(tls.as_deref(), req.headers_mut());
         }
-        self.bg_checks.insert(key.clone(), ());
-        warn!("HA: port {} scored 0, starting background probe for mapping {}", port, mapping_id);
 
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            loop {
-                if !self.bg_checks.contains_key(&key) {
-                    break;
+        if let Some(trace) = req.extensions().get::<Trace>() {
+            trace.upstream(&Self::upstream_path_and_query(&req, &mapping));
+        }
+
+        // Registered for draining: if the route is removed or re-pointed meanwhile, the
+        // request may finish against the old target until the drain timeout cuts it
+        req.extensions_mut().insert(route.clone());
+        let mut session = self.drain.register(route);
+        let started = std::time::Instant::now();
+        let mut result = tokio::select! {
+            r = async {
+                match (self.wait_while_held(&mapping, &host, &path).await, hints) {
+                    (Some(full), _) => Ok(full),
+                    (None, Some(hints)) => hints.run(self.forward(req, &mapping, remote_addr)).await,
+                    (None, None) => self.forward(req, &mapping, remote_addr).await,
                 }
-                let addr = format!("{}:{}", host, port);
-                match tokio::time::timeout(Duration::from_secs(3), TcpStream::connect(&addr)).await {
-                    Ok(Ok(_)) => {
-                        self.bg_checks.remove(&key);
-                        self.port_scores.insert(key.clone(), 50);
-                        info!("HA: port {} back up (score→50) for mapping {}", port, mapping_id);
-                        break;
-                    }
-                    _ => {
-                        tokio::time::sleep(Duration::from_secs(5)).await;
+            } => {
+                if startup.is_some() {
+                    self.starting.answered(&mapping.id);
+                }
+                r
+            }
+            _ = session.cancelled() => {
+                Ok(Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable: route removed"))
+            }
+            _ = expired => {
+                Ok(Self::error_response(StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout: request deadline exceeded"))
+            }
+            _ = slow => match startup.as_ref().filter(|s| self.starting.show_page(&mapping.id, s.max_wait)) {
+                Some(startup) => Ok(Self::startup_response(startup).await),
+                None => {
+                    warn!("Backend of {} still not answering after its startup page's max wait", mapping.domain);
+                    Ok(Self::error_response(StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout: backend did not start"))
+                }
+            },
+        };
+        drop(session);
+        // Response contract: broken ones are counted and logged, and with `enforce` replaced
+        if let (Ok(resp), Some(contract)) = (&result, mapping.contract.as_deref().and_then(|c| c.parse::<Contract>().ok())) {
+            let violations = contract.check(resp.status(), resp.headers(), started.elapsed());
+            self.contracts.record(&mapping.id, &violations);
+            if !violations.is_empty() {
+                let broken: Vec<String> = violations.iter().map(ToString::to_string).collect();
+                warn!("Response for {}{} broke its contract: {}", mapping.domain, path, broken.join(", "));
+                if contract.enforce {
+                    result = Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway: response contract violated"));
+                }
+            }
+        }
+        if let Some((key, window)) = stale_key {
+            result = self.stale_if_error(result, key, window).await;
+        }
+        if let Some((transform, filter)) = images {
+            result = match result {
+                Ok(resp) => Ok(image_filter::apply(resp, transform, filter, &self.images).await),
+                Err(e) => Err(e),
+            };
+        }
+        let mut coding = None;
+        if edits_body {
+            result = match result {
+                Ok(resp) => {
+                    let (resp, decoded) = content_coding::decode_response(resp, self.config.body_rewrite_max_bytes).await;
+                    coding = decoded;
+                    Ok(resp)
+                }
+                Err(e) => Err(e),
+            };
+        }
+        if let Some(rules) = rewrites {
+            result = match result {
+                Ok(resp) => Ok(body_rewrite::rewrite_response(resp, &rules, self.config.body_rewrite_max_bytes).await),
+                Err(e) => Err(e),
+            };
+        }
+        if let Some((mode, prefix)) = base_path {
+            let max = self.config.body_rewrite_max_bytes;
+            result = match result {
+                Ok(resp) => Ok(html_base::apply(resp, mode, &prefix, has_body, max).await),
+                Err(e) => Err(e),
+            };
+        }
+        if let Some(settings) = minify {
+            result = match result {
+                Ok(resp) => Ok(minify::apply(resp, settings, &self.minified).await),
+                Err(e) => Err(e),
+            };
+        }
+        if let Some(coding) = coding {
+            result = match result {
+                Ok(resp) => Ok(content_coding::encode_response(resp, coding, accept_encoding.as_deref()).await),
+                Err(e) => Err(e),
+            };
+        }
+        if let Some(if_none_match) = if_none_match {
+            result = match result {
+                Ok(resp) => Ok(etag::apply(resp, if_none_match.as_deref()).await),
+                Err(e) => Err(e),
+            };
+        }
+        if let Ok(resp) = result.as_mut() {
+            ScrubProfile::effective(mapping.scrub_headers.as_deref(), self.config.scrub_response_headers).apply(resp.headers_mut());
+        }
+        if let (Ok(resp), Some(v)) = (result.as_mut(), assignment) {
+            resp.headers_mut().insert(experiment::HEADER, v);
+        }
+        // The hinted links again, for clients that got no 103
+        if let Ok(resp) = result.as_mut() {
+            if resp.status().is_success() {
+                for link in preload {
+                    if !resp.headers().get_all(hyper::header::LINK).iter().any(|v| *v == link) {
+                        resp.headers_mut().append(hyper::header::LINK, link);
                     }
                 }
             }
-        });
-    }
-
-    /// Start the proxy server (binds its own listener — used in single-worker mode).
-    pub async fn run(self: Arc<Self>) -> Result<()> {
-        let http_addr: SocketAddr = format!("{}:{}", self.config.http_host, self.config.http_port).parse()?;
-        info!("Proxy server starting on HTTP:{}", self.config.http_port);
-        if self.config.enable_https {
-            info!("HTTPS will be enabled on port {}", self.config.https_port);
         }
-        let listener = TcpListener::bind(http_addr).await?;
-        self.run_with_listener(listener).await
+        let failed = result.as_ref().map(|r| r.status().is_server_error()).unwrap_or(true);
+        self.observe_probation(&mapping, failed);
+        result
     }
 
-    /// Accept loop on a pre-bound listener.
-    /// Called by each worker thread when running in multi-worker mode.
-    pub async fn run_with_listener(self: Arc<Self>, listener: TcpListener) -> Result<()> {
-        info!("HTTP worker listening on {}", listener.local_addr()?);
-
-        loop {
-            let (stream, remote_addr) = listener.accept().await?;
-            let proxy = self.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(stream, remote_addr, proxy).await {
-                    debug!("HTTP connection error from {}: {}", remote_addr, e);
+    /// Stale-if-error: keep a good response, or answer a failed one with the kept copy.
+    async fn stale_if_error(
+        &self,
+        result: Result<Response<BoxBody<Bytes, hyper::Error>>>,
+        key: CacheKey,
+        window: Duration,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        match result {
+            Ok(resp) if resp.status() == StatusCode::OK => {
+                let Some(usable_for) = cache::stale_window(resp.headers(), window) else {
+                    return Ok(resp);
+                };
+                let (parts, body) = resp.into_parts();
+                let body = body.collect().await?.to_bytes();
+                self.response_cache.store(&key, parts.status, &parts.headers, body.clone(), usable_for);
+                Ok(Response::from_parts(parts, Self::full_body(body)))
+            }
+            Ok(resp) if !cache::is_error_status(resp.status()) => Ok(resp),
+            failed => match self.response_cache.stale(&key).await {
+                Some(stale) => {
+                    match &failed {
+                        Ok(resp) => warn!("Backend answered {} for {}, serving stale copy", resp.status(), key.base),
+                        Err(e) => warn!("Backend failed for {} ({:#}), serving stale copy", key.base, e),
+                    }
+                    Ok(Self::stale_response(stale))
                 }
-            });
+                None => failed,
+            },
         }
     }
 
-    async fn handle_connection(
-        stream: TcpStream,
-        remote_addr: SocketAddr,
-        proxy: Arc<Self>,
-    ) -> Result<()> {
-        let io = TokioIo::new(stream);
-        http1::Builder::new()
-            .preserve_header_case(true)
-            .title_case_headers(false)
-            .serve_connection(
-                io,
-                service_fn(move |req| {
-                    let p = proxy.clone();
-                    async move { Self::handle_request(req, remote_addr, p).await }
-                }),
-            )
-            .await
-            .map_err(|e| anyhow!("HTTP service error: {}", e))
-    }
-
-    async fn handle_request(
+    /// Send a matched request to the mapping's backend(s).
+    async fn forward(
+        self: &Arc<Self>,
         req: Request<Incoming>,
+        mapping: &Mapping,
         remote_addr: SocketAddr,
-        proxy: Arc<Self>,
-    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
-        match proxy.process_request(req, remote_addr).await {
-            Ok(response) => Ok(response),
-            Err(e) => {
-                error!("Request error: {}", e);
-                Ok(Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"))
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        // WebSocket upgrade (discovery backends are pinned to their best-ranked instance)
+        let is_https = req.extensions().get::<TlsConnection>().is_some();
+        if Self::is_websocket_upgrade(&req) {
+            if Self::uses_discovery(mapping) {
+                let targets = match self.backend_targets(mapping).await {
+                    Ok(t) if !t.is_empty() => t,
+                    Ok(_) => return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway: no healthy instances")),
+                    Err(e) => {
+                        error!("Discovery failed for {}: {}", mapping.domain, e);
+                        return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
+                    }
+                };
+                let target = self.ranked_ports(&mapping.id, &targets).remove(0);
+                let mut pinned = mapping.clone();
+                pinned.backend = Some(format!("http://{}", target.host));
+                pinned.back_port = target.port;
+                let dial = match self.dialer(&pinned) {
+                    Ok(dial) => dial,
+                    Err(resp) => return Ok(resp),
+                };
+                return Self::handle_websocket_proxy(req, &pinned, remote_addr, is_https, &dial, &self.drain, &self.conns).await;
+            }
+            let dial = match self.dialer(mapping) {
+                Ok(dial) => dial,
+                Err(resp) => return Ok(resp),
+            };
+            return Self::handle_websocket_proxy(req, mapping, remote_addr, is_https, &dial, &self.drain, &self.conns).await;
+        }
+
+        // Failover: the body is buffered so the request can be sent again to the fallback
+        // backend when the primary is down or answers 5xx
+        let Some(standby) = mapping.fallback() else {
+            return self.forward_primary(req, mapping, remote_addr, is_https).await;
+        };
+        let (parts, body) = req.into_parts();
+        let body = match body.collect().await {
+            Ok(b) => b.to_bytes(),
+            Err(_) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Bad Request")),
+        };
+        if self.primary_down(mapping).await {
+            debug!("Primary of {} is down, using fallback {}:{}", mapping.domain, standby.backend.as_deref().unwrap_or(""), standby.back_port);
+        } else {
+            let primary = Request::from_parts(parts.clone(), Full::new(body.clone()));
+            let failure = match self.forward_primary(primary, mapping, remote_addr, is_https).await {
+                Ok(resp) if !resp.status().is_server_error() => return Ok(resp),
+                Ok(resp) => resp.status().to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            warn!("Primary of {} failed ({}), retrying against fallback {}:{}",
+                mapping.domain, failure, standby.backend.as_deref().unwrap_or(""), standby.back_port);
+            if let Some(target) = Self::single_target(mapping) {
+                self.penalize_port(&mapping.id, &target);
+                self.clone().start_background_check(mapping.id.clone(), target, self.egress(mapping).ok().flatten());
             }
         }
+        let dial = match self.dialer(&standby) {
+            Ok(dial) => dial,
+            Err(resp) => return Ok(resp),
+        };
+        let req = Request::from_parts(parts, Full::new(body));
+        Self::proxy_request(req, &standby, remote_addr, is_https, &dial, &self.conns).await
     }
 
-    async fn process_request(
+    /// Send a request to the mapping's own backend(s).
+    async fn forward_primary<B>(
         self: &Arc<Self>,
-        req: Request<Incoming>,
+        req: Request<B>,
+        mapping: &Mapping,
         remote_addr: SocketAddr,
-    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
-        let path = req.uri().path().to_string();
-        let method = req.method().clone();
-
-        debug!("{} {} from {}", method, path, remote_addr);
+        is_https: bool,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+    where
+        B: hyper::body::Body<Data = Bytes> + Send + 'static,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let dial = match self.dialer(mapping) {
+            Ok(dial) => dial,
+            Err(resp) => return Ok(resp),
+        };
 
-        // Health check
-        if path == "/health" {
-            return Ok(Self::text_response(StatusCode::OK, "OK"));
+        if Self::uses_fastcgi(mapping) {
+            return self.fastcgi_request(req, mapping, remote_addr, is_https, &dial).await;
         }
-
-        // ACME test challenge
-        if path.starts_with("/.well-known/test-challenge/") {
-            let token = path.strip_prefix("/.well-known/test-challenge/").unwrap_or("");
-            return match self.cert_manager.get_test_challenge(token) {
-                Some(v) => Ok(Self::text_response(StatusCode::OK, &v)),
-                None => Ok(Self::error_response(StatusCode::NOT_FOUND, "Not found")),
-            };
+        if Self::uses_s3(mapping) {
+            return self.s3_request(req, mapping, &dial).await;
         }
 
-        // ACME challenge
-        if path.starts_with("/.well-known/acme-challenge/") {
-            let token = path.strip_prefix("/.well-known/acme-challenge/").unwrap_or("");
-            return match self.cert_manager.get_acme_challenge(token) {
-                Some(k) => Ok(Self::text_response(StatusCode::OK, &k)),
-                None => Ok(Self::error_response(StatusCode::NOT_FOUND, "Challenge not found")),
-            };
+        // Compressed request bodies; sent again as received if the backend can't decode them
+        let Some(min_bytes) = mapping.compress_requests.filter(|_| !self.compression_refusals.active(&mapping.id)) else {
+            return self.send_to_backend(req, mapping, remote_addr, is_https, &dial).await;
+        };
+        let (parts, body) = req.into_parts();
+        let body = match body.collect().await {
+            Ok(b) => b.to_bytes(),
+            Err(_) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Bad Request")),
+        };
+        if let Some(compressed) = request_compression::compress(&parts.headers, &body, min_bytes) {
+            let mut gzipped = Request::from_parts(parts.clone(), Full::new(compressed));
+            gzipped.headers_mut().insert(hyper::header::CONTENT_ENCODING, HeaderValue::from_static(request_compression::ENCODING));
+            let resp = self.send_to_backend(gzipped, mapping, remote_addr, is_https, &dial).await?;
+            if !request_compression::refused(resp.status(), resp.headers()) {
+                return Ok(resp);
+            }
+            warn!("Backend of {} refused a gzip request body; sending bodies uncompressed for now", mapping.domain);
+            self.compression_refusals.record(&mapping.id);
         }
+        self.send_to_backend(Request::from_parts(parts, Full::new(body)), mapping, remote_addr, is_https, &dial).await
+    }
 
-        // Force HTTPS redirect
-        if self.config.force_https && !Self::is_https_request(&req) {
-            let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
-            let location = format!("https://{}{}", host, req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/"));
-            return Ok(Self::redirect_response(&location));
+    /// Proxy to the mapping's HTTP backend(s).
+    async fn send_to_backend<B>(
+        self: &Arc<Self>,
+        req: Request<B>,
+        mapping: &Mapping,
+        remote_addr: SocketAddr,
+        is_https: bool,
+        dial: &Dialer,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+    where
+        B: hyper::body::Body<Data = Bytes> + Send + 'static,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        // HA round-robin across multiple ports or discovered instances
+        if mapping.back_ports.is_some() || Self::uses_discovery(mapping) {
+            return self.ha_proxy_request(req, mapping, remote_addr, is_https, dial).await;
         }
 
-        // Resolve host
-        let host = req.headers()
-            .get(HOST)
-            .and_then(|h| h.to_str().ok())
-            .map(|h| h.split(':').next().unwrap_or(h).to_string());
+        Self::proxy_request(req, mapping, remote_addr, is_https, dial, &self.conns).await
+    }
 
-        let host = match host {
-            Some(h) => h,
-            None => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Missing Host header")),
+    /// How to reach a mapping's backend: its egress proxy, and a TLS client for `https://`
+    /// backends. Unusable settings answer 502.
+    #[allow(clippy::result_large_err)]
+    fn dialer(&self, mapping: &Mapping) -> Result<Dialer, Response<BoxBody<Bytes, hyper::Error>>> {
+        let bad_gateway = |what: &str, e: anyhow::Error| {
+            error!("{} of {} unusable: {:#}", what, mapping.domain, e);
+            Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway")
+        };
+        let egress = self.egress(mapping).map_err(|e| bad_gateway("Egress proxy", e))?;
+        let tls = match mapping.backend.as_deref().is_some_and(|b| b.starts_with("https://") || b.starts_with(s3_site::SCHEME)) {
+            true => Some(self.upstream_tls.get(mapping.upstream_tls.as_deref(), &mapping.domain)
+                .map_err(|e| bad_gateway("Upstream TLS", e))?),
+            false => None,
+        };
+        let pool_settings = match mapping.pool.as_deref() {
+            Some(spec) => self.config.upstream_pool.with_overrides(spec).map_err(|e| bad_gateway("Pool settings", e))?,
+            None => self.config.upstream_pool,
+        };
+        let signing = match mapping.upstream_signing.as_deref() {
+            Some(spec) => Some(Arc::new(spec.parse::<UpstreamSigning>().map_err(|e| bad_gateway("Upstream signing", anyhow!(e)))?)),
+            None => None,
         };
+        Ok(Dialer {
+            tls,
+            egress,
+            pool: self.pool.clone(),
+            pool_settings,
+            mapping_id: mapping.id.clone(),
+            tcp: self.config.backend_tcp,
+            ip_preference: self.config.backend_ip_preference,
+            attempt_delay: self.config.backend_connect_attempt_delay,
+            protocol: mapping.upstream_protocol.as_deref().and_then(|p| p.parse().ok()).unwrap_or_default(),
+            signing,
+        })
+    }
 
-        // Find mapping
-        let mapping = match self.db_manager.find_mapping(&host, &path)? {
-            Some(m) => m,
-            None => {
-                let fb = self.fallback.handle(req, remote_addr).await?;
-                return Ok(fb.map(|b| b.map_err(|never| match never {}).boxed()));
-            }
+    fn uses_fastcgi(mapping: &Mapping) -> bool {
+        mapping.backend.as_deref().is_some_and(|b| b.starts_with("fcgi://"))
+    }
+
+    /// FastCGI backend: static files from the document root, everything else run as a
+    /// script (`fcgi://host` on back_port, or `fcgi:///path/to.sock`).
+    async fn fastcgi_request<B>(
+        &self,
+        req: Request<B>,
+        mapping: &Mapping,
+        remote_addr: SocketAddr,
+        is_https: bool,
+        dial: &Dialer,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+    where
+        B: hyper::body::Body<Data = Bytes>,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let Some(root) = mapping.document_root.as_deref() else {
+            error!("FastCGI backend of {} has no document root", mapping.domain);
+            return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
         };
+        let upstream = Self::upstream_path_and_query(&req, mapping);
+        let (path, query) = upstream.split_once('?').unwrap_or((&upstream, ""));
+
+        if matches!(*req.method(), hyper::Method::GET | hyper::Method::HEAD) {
+            let accept_encoding = req.headers().get(hyper::header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok());
+            if let Some(file) = fastcgi::static_file(root, path, accept_encoding).await {
+                let mut resp = Response::builder().header(hyper::header::CONTENT_TYPE, file.media_type);
+                if let Some(coding) = file.coding {
+                    resp = resp.header(hyper::header::CONTENT_ENCODING, coding.name());
+                }
+                if file.varies {
+                    resp = resp.header(hyper::header::VARY, "Accept-Encoding");
+                }
+                return Ok(resp.body(Self::full_body(file.body)).unwrap());
+            }
+        }
 
-        // IP allowlist check
-        let client_ip = Self::get_client_ip(&req, remote_addr);
-        if !Self::is_ip_allowed(&client_ip, mapping.allowed_ips.as_deref()) {
-            return Ok(Self::error_response(StatusCode::FORBIDDEN, "Forbidden"));
+        let script = fastcgi::Script::resolve(root, path);
+        let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+        let (server_name, server_port) = match host.rsplit_once(':').filter(|(_, p)| p.parse::<u16>().is_ok()) {
+            Some((name, port)) => (name.to_string(), port.to_string()),
+            None => (host.to_string(), if is_https { "443" } else { "80" }.to_string()),
+        };
+        let mut params: Vec<(String, String)> = [
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_SOFTWARE", "rustproxy".to_string()),
+            ("SERVER_PROTOCOL", "HTTP/1.1".to_string()),
+            ("SERVER_NAME", server_name),
+            ("SERVER_PORT", server_port),
+            ("REQUEST_METHOD", req.method().to_string()),
+            ("REQUEST_URI", upstream.clone()),
+            ("QUERY_STRING", query.to_string()),
+            ("DOCUMENT_ROOT", root.trim_end_matches('/').to_string()),
+            ("DOCUMENT_URI", path.to_string()),
+            ("SCRIPT_NAME", script.name),
+            ("SCRIPT_FILENAME", script.filename),
+            ("PATH_INFO", script.path_info),
+            ("REMOTE_ADDR", remote_addr.ip().to_string()),
+            ("REMOTE_PORT", remote_addr.port().to_string()),
+            ("REDIRECT_STATUS", "200".to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        if is_https {
+            params.push(("HTTPS".to_string(), "on".to_string()));
         }
+        fastcgi::header_params(req.headers(), &mut params);
+        let trace = req.extensions().get::<Trace>().cloned();
 
-        // Auth check
-        let auth = Self::check_auth(&req, &mapping);
-        if !auth.allowed {
-            return Ok(Self::unauthorized_response(auth.scheme));
+        let body = match req.into_body().collect().await {
+            Ok(b) => b.to_bytes(),
+            Err(_) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Bad Request")),
+        };
+        params.push(("CONTENT_LENGTH".to_string(), body.len().to_string()));
+
+        let backend = mapping.backend.as_deref().unwrap_or_default();
+        let started = std::time::Instant::now();
+        let stream: Result<Box<dyn upstream_tls::Io>> = match backend.strip_prefix("fcgi://") {
+            Some(socket) if socket.starts_with('/') => tokio::net::UnixStream::connect(socket).await
+                .map(|s| Box::new(s) as Box<dyn upstream_tls::Io>)
+                .map_err(|e| anyhow!("connect {}: {}", socket, e)),
+            Some(host) => dial.connect(host.trim_end_matches('/'), mapping.back_port).await,
+            None => Err(anyhow!("not a FastCGI backend")),
+        };
+        let result = match stream {
+            Ok(stream) => fastcgi::request(stream, &params, &body, self.conns.backend()).await,
+            Err(e) => Err(e),
+        };
+        if let Some(trace) = trace {
+            let target = match backend.starts_with("fcgi:///") {
+                true => backend.to_string(),
+                false => format!("{}:{}", backend.trim_end_matches('/'), mapping.back_port),
+            };
+            trace.attempt(target, result.as_ref().ok().map(|resp| resp.status()), started.elapsed());
         }
-        if let Some(idx) = auth.credential_index {
-            let db = self.db_manager.clone();
-            let mid = mapping.id.clone();
-            tokio::task::spawn_blocking(move || db.record_auth_use(&mid, idx));
+        result.or_else(|e| {
+            error!("FastCGI request to {} failed: {:#}", backend, e);
+            Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"))
+        })
+    }
+
+    fn uses_s3(mapping: &Mapping) -> bool {
+        mapping.backend.as_deref().and_then(s3_site::endpoint).is_some()
+    }
+
+    /// Static site from an S3-compatible bucket: `GET`/`HEAD` of the object the path names,
+    /// with index documents, and the directory redirect S3 website hosting does.
+    async fn s3_request<B>(
+        &self,
+        req: Request<B>,
+        mapping: &Mapping,
+        dial: &Dialer,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        if !matches!(*req.method(), hyper::Method::GET | hyper::Method::HEAD) {
+            let mut resp = Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+            resp.headers_mut().insert(hyper::header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Ok(resp);
         }
+        let Some((host, tls)) = mapping.backend.as_deref().and_then(s3_site::endpoint) else {
+            return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
+        };
+        let port = mapping.back_port;
+        let upstream = Self::upstream_path_and_query(&req, mapping);
+        let path = upstream.split_once('?').map_or(upstream.as_str(), |(p, _)| p);
+        let trace = req.extensions().get::<Trace>().cloned();
+        let get = |object: &str| {
+            let mut builder = Request::builder().method(req.method().clone()).uri(object).version(Version::HTTP_11);
+            if let Some(headers) = builder.headers_mut() {
+                *headers = s3_site::request_headers(req.headers());
+                if let Some(value) = s3_site::host_header(host, port, tls) {
+                    headers.insert(HOST, value);
+                }
+            }
+            builder.body(Full::new(Bytes::new())).context("Failed to build bucket request")
+        };
+        let fetch = |object: String| {
+            let trace = trace.clone();
+            let request = get(&object);
+            async move {
+                let started = std::time::Instant::now();
+                let result = dial.exchange(host, port, request?, &self.conns).await;
+                if let Some(trace) = trace {
+                    trace.attempt(format!("{}:{}", host, port), result.as_ref().ok().map(|(parts, _)| parts.status), started.elapsed());
+                }
+                result
+            }
+        };
+        let not_found = |status: StatusCode| matches!(status, StatusCode::NOT_FOUND | StatusCode::FORBIDDEN);
 
-        // WebSocket upgrade
-        if Self::is_websocket_upgrade(&req) {
-            return Self::handle_websocket_proxy(req, &mapping, remote_addr, false).await;
+        let (parts, body) = match fetch(s3_site::object_path(path).into_owned()).await {
+            Ok(exchanged) => exchanged,
+            Err(e) => {
+                error!("Bucket {} unreachable: {:#}", host, e);
+                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
+            }
+        };
+        if not_found(parts.status) {
+            // `/docs` with a `docs/index.html`: redirect to the directory, as S3 websites do
+            if s3_site::may_be_directory(path) {
+                let index = format!("{}/{}", path, s3_site::INDEX_DOCUMENT);
+                if fetch(index).await.is_ok_and(|(parts, _)| parts.status.is_success()) {
+                    let location = match req.uri().query() {
+                        Some(q) => format!("{}/?{}", req.uri().path(), q),
+                        None => format!("{}/", req.uri().path()),
+                    };
+                    return Ok(Response::builder()
+                        .status(StatusCode::MOVED_PERMANENTLY)
+                        .header(hyper::header::LOCATION, location)
+                        .body(Self::empty_body())
+                        .unwrap());
+                }
+            }
+            return Ok(Self::error_response(StatusCode::NOT_FOUND, "Not Found"));
+        }
+        if parts.status.is_client_error() && !matches!(parts.status, StatusCode::PRECONDITION_FAILED | StatusCode::RANGE_NOT_SATISFIABLE)
+            || parts.status.is_server_error()
+        {
+            warn!("Bucket {} answered {} for {}", host, parts.status, path);
+            return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
         }
+        let body = match body {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Reading object {} from {} failed: {}", path, host, e);
+                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
+            }
+        };
+        let mut resp = Response::new(Self::full_body(body));
+        *resp.status_mut() = parts.status;
+        *resp.headers_mut() = s3_site::response_headers(&parts.headers);
+        Ok(resp)
+    }
 
-        // HA round-robin across multiple ports
-        if mapping.back_ports.is_some() {
-            return self.ha_proxy_request(req, &mapping, remote_addr, false).await;
+    /// The egress proxy of a mapping: its own, none for `direct`, else the server's.
+    fn egress(&self, mapping: &Mapping) -> Result<Option<Arc<EgressProxy>>> {
+        match mapping.egress_proxy.as_deref().map(str::trim) {
+            Some(egress::DIRECT) => Ok(None),
+            Some(url) => Ok(Some(Arc::new(EgressProxy::parse(url)?))),
+            None => Ok(self.egress_proxy.clone()),
         }
+    }
 
-        Self::proxy_request(req, &mapping, remote_addr, false).await
+    /// Failover: whether every primary target is scored down (failed and not yet seen
+    /// back by its probe), so requests go straight to the fallback backend.
+    async fn primary_down(&self, mapping: &Mapping) -> bool {
+        let targets = match Self::single_target(mapping) {
+            Some(target) => vec![target],
+            None => self.backend_targets(mapping).await.unwrap_or_default(),
+        };
+        !targets.is_empty() && targets.iter().all(|t| self.get_port_score(&mapping.id, t) == 0)
+    }
+
+    /// The one target of a mapping without `back_ports` or discovery.
+    fn single_target(mapping: &Mapping) -> Option<Endpoint> {
+        if mapping.back_ports.is_some() || Self::uses_discovery(mapping) {
+            return None;
+        }
+        let url: Url = mapping.backend.as_deref().unwrap_or("http://localhost").parse().ok()?;
+        Some(Endpoint::new(url.host_str()?, mapping.back_port))
     }
 
     // ── Auth helpers ──────────────────────────────────────────────────────────
 
+    /// ID of the valid key sent in `X-Api-Key` or the `api_key` query parameter.
+    fn check_api_key(&self, req: &Request<Incoming>, mapping: &Mapping) -> Option<String> {
+        let secret = match req.headers().get(API_KEY_HEADER).and_then(|h| h.to_str().ok()) {
+            Some(h) => h.trim().to_string(),
+            None => url::form_urlencoded::parse(req.uri().query()?.as_bytes())
+                .find(|(k, _)| k == API_KEY_PARAM)?
+                .1
+                .into_owned(),
+        };
+        match self.db_manager.verify_api_key(&mapping.id, &secret) {
+            Ok(key) => key.map(|k| k.id),
+            Err(e) => {
+                error!("API key lookup for {} failed: {}", mapping.domain, e);
+                None
+            }
+        }
+    }
+
     fn check_auth(req: &Request<Incoming>, mapping: &Mapping) -> AuthResult {
         let auth_type = match mapping.auth_type.as_deref() {
             Some(t) => t,
@@ -400,74 +2914,197 @@ impl ProxyServer {
             }
 
             "password" => {
-                let pass: Option<&str> = if auth_header.starts_with("Bearer ") {
-                    Some(auth_header[7..].trim())
-                } else if auth_header.starts_with("Basic ") {
-                    // Extract password from Base64 "user:pass" (take everything after last ':')
-                    general_purpose::STANDARD.decode(auth_header[6..].trim())
+                // Bearer token is the password itself; Basic takes everything after the last ':'
+                let pass_owned: Option<String> = if let Some(token) = auth_header.strip_prefix("Bearer ") {
+                    Some(token.trim().to_string())
+                } else if let Some(encoded) = auth_header.strip_prefix("Basic ") {
+                    general_purpose::STANDARD.decode(encoded.trim())
                         .ok()
                         .and_then(|b| String::from_utf8(b).ok())
                         .map(|s| {
                             let idx = s.rfind(':').map(|i| i + 1).unwrap_or(0);
                             s[idx..].to_string()
                         })
-                        .as_deref()
-                        .map(|_| "") // placeholder — see below
                 } else {
                     None
                 };
 
-                // Re-decode cleanly to avoid lifetime issues
-                let pass_owned: Option<String> = if auth_header.starts_with("Bearer ") {
-                    Some(auth_header[7..].trim().to_string())
-                } else if auth_header.starts_with("Basic ") {
-                    general_purpose::STANDARD.decode(auth_header[6..].trim())
-                        .ok()
-                        .and_then(|b| String::from_utf8(b).ok())
-                        .map(|s| {
-                            let idx = s.rfind(':').map(|i| i + 1).unwrap_or(0);
-                            s[idx..].to_string()
-                        })
-                } else {
-                    None
+                let pass_str = match &pass_owned {
+                    Some(p) => p.as_str(),
+                    None => return AuthResult { allowed: false, credential_index: None, scheme: "basic" },
                 };
-                let _ = pass; // unused, using pass_owned
 
-                let pass_str = match &pass_owned {
-                    Some(p) => p.as_str(),
-                    None => return AuthResult { allowed: false, credential_index: None, scheme: "basic" },
-                };
+                for (i, c) in creds.iter().enumerate() {
+                    let cp = c.get("pass").and_then(|v| v.as_str()).unwrap_or("");
+                    if cp == pass_str && !expired(c) {
+                        return AuthResult { allowed: true, credential_index: Some(i), scheme: "basic" };
+                    }
+                }
+                AuthResult { allowed: false, credential_index: None, scheme: "basic" }
+            }
+
+            _ => AuthResult { allowed: false, credential_index: None, scheme: "basic" },
+        }
+    }
+
+    // ── Built-in endpoint restrictions ────────────────────────────────────────
+
+    /// Whether `/health` is answered for this connection; the socket address is used,
+    /// since X-Forwarded-For is whatever the client says.
+    fn health_allowed(&self, remote_addr: SocketAddr) -> bool {
+        Self::is_ip_allowed(&remote_addr.ip().to_canonical().to_string(), self.config.health_allowed_ips.as_deref())
+    }
+
+    /// Whether the request's host has a mapping of its own.
+    fn is_mapped_host(&self, uri: &Uri, host_header: Option<&HeaderValue>) -> bool {
+        crate::host::authority_of(uri, host_header)
+            .and_then(crate::host::parse_authority)
+            .and_then(|a| crate::host::normalize_host(a.host))
+            .is_some_and(|host| self.db_manager.domain_exists(&host).unwrap_or(false))
+    }
+
+    /// Serve `/health` and the admin API alone on `addr`, e.g. on an internal network for
+    /// load balancers and operators. Everything else is 404.
+    async fn run_internal_listener(self: Arc<Self>, addr: SocketAddr) {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => return error!("Internal listener on {} failed: {}", addr, e),
+        };
+        info!("Health and admin endpoints listening on {}", addr);
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Internal listener accept() failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let proxy = proxy.clone();
+                    async move { Ok::<_, Infallible>(proxy.handle_internal(req, remote_addr).await) }
+                });
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+            });
+        }
+    }
+
+    async fn handle_internal(&self, req: Request<Incoming>, remote_addr: SocketAddr) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let path = req.uri().path().to_string();
+        if path == "/health" && self.health_allowed(remote_addr) {
+            return Self::text_response(StatusCode::OK, "OK");
+        }
+        if let (Some(op), Some(token)) = (path.strip_prefix(admin::PREFIX), self.config.admin_token.as_deref()) {
+            return self.handle_admin(req, op, token).await;
+        }
+        Self::error_response(StatusCode::NOT_FOUND, "Not found")
+    }
 
-                for (i, c) in creds.iter().enumerate() {
-                    let cp = c.get("pass").and_then(|v| v.as_str()).unwrap_or("");
-                    if cp == pass_str && !expired(c) {
-                        return AuthResult { allowed: true, credential_index: Some(i), scheme: "basic" };
-                    }
+    /// Take `CONNECT` requests on `addr` and tunnel them where the forward-proxy ACL allows.
+    async fn run_forward_proxy(self: Arc<Self>, addr: SocketAddr) {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => return error!("Forward proxy listener on {} failed: {}", addr, e),
+        };
+        info!("Forward proxy listening on {}", addr);
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Forward proxy accept() failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
                 }
-                AuthResult { allowed: false, credential_index: None, scheme: "basic" }
-            }
+            };
+            let proxy = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let proxy = proxy.clone();
+                    async move { Ok::<_, Infallible>(proxy.handle_forward_proxy(req, remote_addr).await) }
+                });
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades().await;
+            });
+        }
+    }
 
-            _ => AuthResult { allowed: false, credential_index: None, scheme: "basic" },
+    async fn handle_forward_proxy(&self, req: Request<Incoming>, remote_addr: SocketAddr) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let acl = &self.config.forward_proxy_acl;
+        if !acl.allows_client(remote_addr.ip()) {
+            warn!("Forward proxy: client {} is not allowed", remote_addr.ip());
+            return Self::error_response(StatusCode::FORBIDDEN, "Forbidden");
+        }
+        if req.method() != Method::CONNECT {
+            let mut resp = Self::error_response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed: only CONNECT is supported");
+            resp.headers_mut().insert(hyper::header::ALLOW, HeaderValue::from_static("CONNECT"));
+            return resp;
         }
+        let Some((host, port)) = forward_proxy::target(req.uri()) else {
+            return Self::error_response(StatusCode::BAD_REQUEST, "Bad Request: CONNECT needs host:port");
+        };
+        if !acl.allows(&host, port) {
+            warn!("Forward proxy: {} may not connect to {}:{}", remote_addr.ip(), host, port);
+            return Self::error_response(StatusCode::FORBIDDEN, "Forbidden: destination not allowed");
+        }
+
+        let connected = match &self.egress_proxy {
+            Some(egress) => egress.connect(&host, port).await,
+            None => self.config.backend_tcp
+                .connect(&format!("{}:{}", host, port), self.config.backend_ip_preference, self.config.backend_connect_attempt_delay).await
+                .map_err(|e| anyhow!("connect {}:{}: {}", host, port, e)),
+        };
+        let mut upstream = match connected {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Forward proxy: tunnel from {} to {}:{} failed: {:#}", remote_addr.ip(), host, port, e);
+                return Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway");
+            }
+        };
+        debug!("Forward proxy: tunnel from {} to {}:{}", remote_addr.ip(), host, port);
+
+        // Counted until the tunnel closes
+        let guard = self.conns.backend();
+        let client_upgrade = hyper::upgrade::on(req);
+        tokio::spawn(async move {
+            let _guard = guard;
+            match client_upgrade.await {
+                Ok(upgraded) => { let _ = tokio::io::copy_bidirectional(&mut TokioIo::new(upgraded), &mut upstream).await; }
+                Err(e) => debug!("Forward proxy: CONNECT upgrade failed: {}", e),
+            }
+        });
+        Response::new(Self::empty_body())
     }
 
     // ── IP allowlist helpers ──────────────────────────────────────────────────
 
-    fn get_client_ip(req: &Request<Incoming>, remote_addr: SocketAddr) -> String {
-        if let Some(xff) = req.headers().get("x-forwarded-for") {
-            if let Ok(v) = xff.to_str() {
-                let ip = v.split(',').next().unwrap_or("").trim().to_string();
-                if !ip.is_empty() {
-                    return ip;
+    /// The client's address: the connecting one, unless that is one of `trusted_proxies`;
+    /// then the nearest `X-Forwarded-For` hop that isn't (the ones before it are whatever
+    /// the client sent).
+    fn client_ip<T>(&self, req: &Request<T>, remote_addr: SocketAddr) -> String {
+        let trusted = |ip: IpAddr| self.config.trusted_proxies.iter().any(|n| n.contains(ip));
+        let mut ip = remote_addr.ip().to_canonical();
+        if trusted(ip) {
+            let hops: Vec<&str> = req.headers().get_all("x-forwarded-for").iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .collect();
+            for hop in hops.into_iter().rev() {
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(hop) => ip = hop.to_canonical(),
+                    Err(_) => break,
+                }
+                if !trusted(ip) {
+                    break;
                 }
             }
         }
-        let ip = remote_addr.ip().to_string();
-        // Strip IPv6-mapped IPv4 prefix
-        if let Some(stripped) = ip.strip_prefix("::ffff:") {
-            return stripped.to_string();
-        }
-        ip
+        ip.to_string()
+    }
+
+    /// The connecting address, IPv6-mapped IPv4 ones as plain IPv4.
+    fn peer_ip(remote_addr: SocketAddr) -> String {
+        remote_addr.ip().to_canonical().to_string()
     }
 
     fn is_ip_allowed(client_ip: &str, allowed_ips: Option<&str>) -> bool {
@@ -507,9 +3144,119 @@ impl ProxyServer {
         Some((a << 24) | (b << 16) | (c << 8) | d)
     }
 
+    // ── Listener hardening ────────────────────────────────────────────────────
+
+    /// Limits hyper can't enforce itself: request line length, head size when it fits in
+    /// hyper's minimum buffer, and ambiguous message framing.
+    fn check_request_limits<T>(&self, req: &Request<T>) -> Option<(StatusCode, &'static str)> {
+        let config = &self.config;
+        let line_len = Self::request_line_len(req);
+        if line_len > config.max_request_line {
+            return Some((StatusCode::URI_TOO_LONG, "URI Too Long"));
+        }
+
+        // name + ": " + value + CRLF per header
+        let head_len: usize = line_len + req.headers().iter().map(|(k, v)| k.as_str().len() + v.len() + 4).sum::<usize>();
+        if head_len > config.max_header_bytes {
+            return Some((StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large"));
+        }
+
+        if config.reject_ambiguous_framing
+            && req.headers().contains_key(hyper::header::TRANSFER_ENCODING)
+            && req.headers().contains_key(hyper::header::CONTENT_LENGTH)
+        {
+            return Some((StatusCode::BAD_REQUEST, "Bad Request: conflicting Content-Length and Transfer-Encoding"));
+        }
+        None
+    }
+
+    /// Length of "METHOD SP target SP HTTP/1.1" CRLF as the client sent it.
+    fn request_line_len<T>(req: &Request<T>) -> usize {
+        let target_len = req.uri().path_and_query().map(|pq| pq.as_str().len()).unwrap_or(1)
+            + req.uri().authority().map(|a| a.as_str().len() + 7).unwrap_or(0);
+        req.method().as_str().len() + target_len + 10
+    }
+
+    /// Request headers copied to the backend. Host is set separately, and the body is sent
+    /// re-framed by hyper, so the client's own framing headers must not leak through.
+    fn is_forwarded_request_header(name: &hyper::header::HeaderName) -> bool {
+        name != HOST && name != hyper::header::TRANSFER_ENCODING && name != hyper::header::CONTENT_LENGTH
+    }
+
+    /// Append the client headers that are forwarded to the backend to `to`, with this
+    /// hop recorded (see [`crate::hops`]).
+    pub fn copy_forwarded_headers(from: &hyper::HeaderMap, to: &mut hyper::HeaderMap) {
+        for (key, value) in from.iter() {
+            if Self::is_forwarded_request_header(key) {
+                to.append(key, value.clone());
+            }
+        }
+        crate::hops::stamp(from, to);
+    }
+
+    // ── Body rule helpers ─────────────────────────────────────────────────────
+
+    /// Enforce `max_body_bytes` and `allowed_content_types` against the request headers.
+    /// Returns the rejection, if any. Only the declared length is checked, so a body
+    /// without Content-Length (chunked) is refused with 411 while a size limit is set.
+    fn check_body_rules<T>(req: &Request<T>, mapping: &Mapping) -> Option<(StatusCode, &'static str)> {
+        let headers = req.headers();
+        let length = match headers.get(hyper::header::CONTENT_LENGTH) {
+            Some(v) => match v.to_str().ok().and_then(|s| s.trim().parse::<u64>().ok()) {
+                Some(n) => Some(n),
+                None => return Some((StatusCode::BAD_REQUEST, "Invalid Content-Length")),
+            },
+            None => None,
+        };
+        let chunked = headers.contains_key(hyper::header::TRANSFER_ENCODING);
+        let has_body = chunked || length.unwrap_or(0) > 0;
+
+        if let Some(max) = mapping.max_body_bytes {
+            match length {
+                Some(n) if n > max => return Some((StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large")),
+                None if chunked => return Some((StatusCode::LENGTH_REQUIRED, "Length Required")),
+                _ => {}
+            }
+        }
+
+        let allowed = match mapping.allowed_content_types.as_deref() {
+            Some(s) if !s.trim().is_empty() => s,
+            _ => return None,
+        };
+        let content_type = headers.get(hyper::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        if content_type.is_none() && !has_body {
+            return None;
+        }
+        let media_type = content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if Self::is_content_type_allowed(&media_type, allowed) {
+            None
+        } else {
+            Some((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported Media Type"))
+        }
+    }
+
+    fn is_content_type_allowed(media_type: &str, allowed: &str) -> bool {
+        if media_type.is_empty() {
+            return false;
+        }
+        allowed.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .any(|entry| match entry.strip_suffix("/*") {
+                Some(top) => media_type.split('/').next().is_some_and(|t| t.eq_ignore_ascii_case(top)),
+                None => entry == "*/*" || media_type.eq_ignore_ascii_case(entry),
+            })
+    }
+
     // ── Request helpers ───────────────────────────────────────────────────────
 
     fn is_https_request<T>(req: &Request<T>) -> bool {
+        if req.extensions().get::<TlsConnection>().is_some() {
+            return true;
+        }
         if let Some(proto) = req.headers().get("x-forwarded-proto") {
             if proto.to_str().ok() == Some("https") { return true; }
         }
@@ -522,6 +3269,30 @@ impl ProxyServer {
         false
     }
 
+    /// Replace the path of `req`, keeping its query.
+    fn set_request_path<T>(req: &mut Request<T>, path: &str) -> Result<()> {
+        let path_and_query = match req.uri().query() {
+            Some(q) => format!("{}?{}", path, q),
+            None => path.to_string(),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse()?);
+        *req.uri_mut() = Uri::from_parts(parts)?;
+        Ok(())
+    }
+
+    /// Replace the query of `req`, keeping its path.
+    fn set_request_query<T>(req: &mut Request<T>, query: Option<&str>) -> Result<()> {
+        let path_and_query = match query {
+            Some(q) => format!("{}?{}", req.uri().path(), q),
+            None => req.uri().path().to_string(),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse()?);
+        *req.uri_mut() = Uri::from_parts(parts)?;
+        Ok(())
+    }
+
     fn is_websocket_upgrade<T>(req: &Request<T>) -> bool {
         req.headers().get(UPGRADE)
             .and_then(|v| v.to_str().ok())
@@ -531,58 +3302,128 @@ impl ProxyServer {
 
     // ── Path rewriting ────────────────────────────────────────────────────────
 
-    fn rewrite_path(path: &str, mapping: &Mapping) -> String {
-        let mut result = path.to_string();
-
-        if !mapping.front_uri.is_empty() {
-            let front_pattern = format!("/{}", mapping.front_uri);
-            if result.starts_with(&front_pattern) {
-                result = result[front_pattern.len()..].to_string();
-                if result.is_empty() { result = "/".to_string(); }
+    /// Path and query to send upstream: the normalized path, or with `preserve_path` the
+    /// client's original encoding — unless that still contains dot segments, which are
+    /// never passed through.
+    fn upstream_path_and_query<T>(req: &Request<T>, mapping: &Mapping) -> String {
+        let raw = req.extensions().get::<OriginalPath>().map(|o| o.0.as_str());
+        let rewritten = match raw {
+            Some(raw) if mapping.preserve_path && Self::is_free_of_dot_segments(raw) => {
+                Self::rewrite_path_raw(raw, mapping).unwrap_or_else(|| Self::rewrite_path(req.uri().path(), mapping))
             }
+            _ if mapping.preserve_path => Self::rewrite_path_raw(req.uri().path(), mapping)
+                .unwrap_or_else(|| Self::rewrite_path(req.uri().path(), mapping)),
+            _ => Self::rewrite_path(req.uri().path(), mapping),
+        };
+        match req.uri().query() {
+            Some(q) => format!("{}?{}", rewritten, q),
+            None => rewritten,
         }
+    }
+
+    fn is_free_of_dot_segments(path: &str) -> bool {
+        let opts = PathNormalization {
+            merge_slashes: false,
+            percent_decoding: normalize::PercentDecoding::Keep,
+            ..Default::default()
+        };
+        matches!(normalize::normalize_path(path, &opts), Ok(p) if p == path)
+    }
 
+    /// Like [`Self::rewrite_path`], but byte-for-byte: no slash merging, escapes untouched.
+    /// `None` when the front_uri prefix isn't literally present.
+    fn rewrite_path_raw(path: &str, mapping: &Mapping) -> Option<String> {
+        let mut rest = path;
+        if !mapping.front_uri.is_empty() {
+            let end = normalize::match_prefix(path, &format!("/{}", mapping.front_uri))?;
+            rest = &path[end..];
+        }
+        if rest.is_empty() {
+            rest = "/";
+        }
+        let mut result = String::with_capacity(path.len() + mapping.back_uri.len() + 2);
         if !mapping.back_uri.is_empty() {
-            result = format!("/{}{}", mapping.back_uri, result);
+            result.push('/');
+            result.push_str(&mapping.back_uri);
+        }
+        if result.is_empty() && !rest.starts_with('/') {
+            result.push('/');
         }
+        result.push_str(rest);
+        Some(result)
+    }
+
+    /// Map a (normalized) request path onto the backend: strip `front_uri`, prepend
+    /// `back_uri`, merge slashes.
+    pub fn rewrite_path(path: &str, mapping: &Mapping) -> String {
+        let mut result = String::with_capacity(path.len() + mapping.back_uri.len() + 2);
+        Self::push_rewritten_path(&mut result, path, mapping);
+        result
+    }
 
-        while result.contains("//") {
-            result = result.replace("//", "/");
+    /// [`Self::rewrite_path`] appended to `out`, in one pass and without temporaries.
+    fn push_rewritten_path(out: &mut String, path: &str, mapping: &Mapping) {
+        let mut rest = path;
+        if !mapping.front_uri.is_empty() {
+            if let Some(end) = normalize::match_front_uri(path, &mapping.front_uri) {
+                rest = if end == path.len() { "/" } else { &path[end..] };
+            }
         }
 
-        if !result.starts_with('/') {
-            result = format!("/{}", result);
+        let start = out.len();
+        let mut after_slash = false;
+        let mut push = |s: &str| {
+            for c in s.chars() {
+                if !(c == '/' && after_slash) {
+                    out.push(c);
+                }
+                after_slash = c == '/';
+            }
+        };
+        if !mapping.back_uri.is_empty() {
+            push("/");
+            push(&mapping.back_uri);
         }
-        if result.is_empty() {
-            result = "/".to_string();
+        push(rest);
+
+        if !out[start..].starts_with('/') {
+            out.insert(start, '/');
         }
-        result
     }
 
-    fn build_backend_url(mapping: &Mapping, path: &str, query: Option<&str>) -> String {
+    /// Full backend URL for a request path and query under `mapping`.
+    pub fn build_backend_url(mapping: &Mapping, path: &str, query: Option<&str>) -> String {
+        use std::fmt::Write;
         let backend = mapping.backend.as_deref().unwrap_or("http://localhost");
-        let rewritten_path = Self::rewrite_path(path, mapping);
-        let mut url = format!("{}:{}{}", backend, mapping.back_port, rewritten_path);
+        let query_len = query.map_or(0, |q| q.len() + 1);
+        let mut url = String::with_capacity(backend.len() + 6 + path.len() + mapping.back_uri.len() + 2 + query_len);
+        let _ = write!(url, "{}:{}", backend, mapping.back_port);
+        Self::push_rewritten_path(&mut url, path, mapping);
         if let Some(q) = query {
-            url = format!("{}?{}", url, q);
+            url.push('?');
+            url.push_str(q);
         }
         url
     }
 
     // ── Core proxy ────────────────────────────────────────────────────────────
 
-    async fn proxy_request(
-        req: Request<Incoming>,
+    async fn proxy_request<B>(
+        req: Request<B>,
         mapping: &Mapping,
         remote_addr: SocketAddr,
         is_https: bool,
-    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        dial: &Dialer,
+        conns: &Arc<ConnectionTracker>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+    where
+        B: hyper::body::Body<Data = Bytes> + Send + 'static,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
         let is_get = req.method() == hyper::Method::GET;
-        let original_host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("").to_string();
+        let original_host = req.headers().get(HOST).cloned().unwrap_or_else(|| HeaderValue::from_static(""));
 
-        let path = req.uri().path().to_string();
-        let query = req.uri().query().map(|q| q.to_string());
-        let backend_url = Self::build_backend_url(mapping, &path, query.as_deref());
+        let backend_url = Self::build_backend_url(mapping, req.uri().path(), req.uri().query());
 
         debug!("Proxying to: {}", backend_url);
 
@@ -590,56 +3431,46 @@ impl ProxyServer {
         let host = url.host_str().unwrap_or("localhost");
         let port = url.port().unwrap_or(if url.scheme() == "https" { 443 } else { 80 });
 
-        let stream = match TcpStream::connect(format!("{}:{}", host, port)).await {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Failed to connect to backend: {}", e);
-                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
-            }
-        };
+        let uri: Uri = Self::upstream_path_and_query(&req, mapping).parse().context("Invalid URI")?;
 
+        // The body streams through as the client sends it, unless it must be signed
         let (parts, body) = req.into_parts();
-        let body_bytes = match body.collect().await {
-            Ok(b) => b.to_bytes(),
-            Err(_) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Bad Request")),
-        };
-
-        let rewritten_path = Self::rewrite_path(parts.uri.path(), mapping);
-        let uri_str = match query.as_deref() {
-            Some(q) => format!("{}?{}", rewritten_path, q),
-            None => rewritten_path,
+        let body = match dial.signing {
+            Some(_) => match body.collect().await {
+                Ok(b) => RequestBody::from(Full::new(b.to_bytes())),
+                Err(_) => return Ok(Self::error_response(StatusCode::BAD_REQUEST, "Bad Request")),
+            },
+            None => RequestBody::streaming(body),
         };
-        let uri: Uri = uri_str.parse().context("Invalid URI")?;
+        let trace = parts.extensions.get::<Trace>().cloned();
 
         let mut builder = Request::builder().method(parts.method).uri(uri).version(Version::HTTP_11);
-        for (key, value) in parts.headers.iter() {
-            if key != HOST { builder = builder.header(key, value); }
+        if let Some(headers) = builder.headers_mut() {
+            Self::copy_forwarded_headers(&parts.headers, headers);
         }
         builder = builder.header(HOST, &original_host);
         builder = builder.header("X-Forwarded-For", remote_addr.ip().to_string());
         builder = builder.header("X-Forwarded-Host", &original_host);
         builder = builder.header("X-Forwarded-Proto", if is_https { "https" } else { "http" });
 
-        let proxy_req = builder.body(Full::new(body_bytes)).context("Failed to build proxy request")?;
-
-        let io = TokioIo::new(stream);
-        let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await
-            .context("Failed to establish connection to backend")?;
-        tokio::spawn(async move { let _ = conn.await; });
+        let mut proxy_req = builder.body(body).context("Failed to build proxy request")?;
+        if let Some(relay) = parts.extensions.get::<early_hints::Relay>() {
+            relay.listen(&mut proxy_req);
+        }
 
-        let response = match sender.send_request(proxy_req).await {
-            Ok(r) => r,
+        let started = std::time::Instant::now();
+        let exchanged = dial.exchange(host, port, proxy_req, conns).await;
+        if let Some(trace) = trace {
+            trace.attempt(format!("{}:{}", host, port), exchanged.as_ref().ok().map(|(parts, _)| parts.status), started.elapsed());
+        }
+        let (parts, body_bytes) = match exchanged {
+            Ok((parts, body)) => (parts, body.unwrap_or_default()),
             Err(e) => {
-                error!("Failed to send request to backend: {}", e);
+                error!("Failed to proxy to backend: {:#}", e);
                 return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
             }
         };
 
-        let (parts, body) = response.into_parts();
-        let body_bytes = body.collect().await
-            .map(|b| b.to_bytes())
-            .unwrap_or_else(|_| Bytes::new());
-
         let mut builder = Response::builder().status(parts.status);
         for (key, value) in parts.headers.iter() {
             builder = builder.header(key, value);
@@ -657,10 +3488,11 @@ impl ProxyServer {
             }
         }
 
-        Ok(builder.body(Self::full_body(body_bytes)).context("Failed to build response")?)
+        builder.body(Self::full_body(body_bytes)).context("Failed to build response")
     }
 
     /// Try a single backend port; returns (status, headers, body) or an error.
+    #[allow(clippy::too_many_arguments)]
     async fn try_port(
         method: hyper::Method,
         uri: Uri,
@@ -670,90 +3502,88 @@ impl ProxyServer {
         port: u16,
         remote_addr: SocketAddr,
         is_https: bool,
+        dial: &Dialer,
+        conns: &Arc<ConnectionTracker>,
     ) -> Result<(StatusCode, hyper::HeaderMap, Bytes)> {
-        let addr = format!("{}:{}", host, port);
-        let stream = TcpStream::connect(&addr).await
-            .map_err(|e| anyhow!("connect {}: {}", addr, e))?;
-
         let mut builder = Request::builder().method(method).uri(uri).version(Version::HTTP_11);
-        for (key, value) in headers.iter() {
-            if key != HOST { builder = builder.header(key, value); }
+        if let Some(to) = builder.headers_mut() {
+            Self::copy_forwarded_headers(&headers, to);
         }
         builder = builder.header(HOST, format!("{}:{}", host, port));
         builder = builder.header("X-Forwarded-For", remote_addr.ip().to_string());
         builder = builder.header("X-Forwarded-Proto", if is_https { "https" } else { "http" });
 
         let proxy_req = builder.body(Full::new(body_bytes)).context("Failed to build proxy request")?;
-        let io = TokioIo::new(stream);
-        let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await
-            .context("Handshake failed")?;
-        tokio::spawn(async move { let _ = conn.await; });
-
-        let response = sender.send_request(proxy_req).await.context("send_request failed")?;
-        let (parts, body) = response.into_parts();
-        let body_bytes = body.collect().await.context("Failed to read response body")?.to_bytes();
+        let (parts, body) = dial.exchange(host, port, proxy_req, conns).await?;
+        let body_bytes = body.context("Failed to read response body")?;
 
         Ok((parts.status, parts.headers, body_bytes))
     }
 
-    /// HA score-based proxy: tries ports best-score-first, first port that responds wins.
-    /// Connection failures penalize the port and start a background probe.
-    async fn ha_proxy_request(
+    /// HA score-based proxy: tries targets best-score-first, first target that responds wins.
+    /// Connection failures penalize the target and start a background probe.
+    async fn ha_proxy_request<B>(
         self: &Arc<Self>,
-        req: Request<Incoming>,
+        req: Request<B>,
         mapping: &Mapping,
         remote_addr: SocketAddr,
         is_https: bool,
-    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
-        let back_ports_str = mapping.back_ports.as_deref().unwrap_or("");
-        let all_ports: Vec<u16> = back_ports_str
-            .split(',')
-            .filter_map(|s| s.trim().parse().ok())
-            .collect();
+        dial: &Dialer,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>>
+    where
+        B: hyper::body::Body<Data = Bytes>,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let all_targets = match self.backend_targets(mapping).await {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Discovery failed for {}: {}", mapping.domain, e);
+                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
+            }
+        };
 
-        if all_ports.is_empty() {
+        if all_targets.is_empty() {
+            if Self::uses_discovery(mapping) {
+                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway: no healthy instances"));
+            }
             return Ok(Self::error_response(StatusCode::INTERNAL_SERVER_ERROR, "HA: no ports configured"));
         }
 
-        let path = req.uri().path().to_string();
-        let query = req.uri().query().map(|q| q.to_string());
-        let rewritten_path = Self::rewrite_path(&path, mapping);
-        let uri_str = match query.as_deref() {
-            Some(q) => format!("{}?{}", rewritten_path, q),
-            None => rewritten_path,
-        };
-        let uri: Uri = uri_str.parse().context("Invalid URI")?;
+        let uri: Uri = Self::upstream_path_and_query(&req, mapping).parse().context("Invalid URI")?;
 
         let (parts, body) = req.into_parts();
         let body_bytes = body.collect().await.context("Failed to read request body")?.to_bytes();
 
-        let backend = mapping.backend.as_deref().unwrap_or("http://localhost");
-        let backend_url: Url = backend.parse().unwrap_or_else(|_| "http://localhost".parse().unwrap());
-        let backend_host = backend_url.host_str().unwrap_or("localhost").to_string();
-
-        let ordered = self.ranked_ports(&mapping.id, &all_ports);
+        let ordered = self.ranked_ports(&mapping.id, &all_targets);
 
-        for &port in &ordered {
-            match Self::try_port(
+        for target in ordered {
+            let started = std::time::Instant::now();
+            let attempt = Self::try_port(
                 parts.method.clone(),
                 uri.clone(),
                 parts.headers.clone(),
                 body_bytes.clone(),
-                &backend_host,
-                port,
+                &target.host,
+                target.port,
                 remote_addr,
                 is_https,
-            ).await {
+                dial,
+                &self.conns,
+            ).await;
+            if let Some(trace) = parts.extensions.get::<Trace>() {
+                trace.attempt(format!("{}:{}", target.host, target.port), attempt.as_ref().ok().map(|(status, _, _)| *status), started.elapsed());
+            }
+            let failed = attempt.as_ref().map_or(true, |(status, _, _)| status.is_server_error());
+            self.outliers.record(&Self::port_key(&mapping.id, &target), failed, started.elapsed());
+            match attempt {
                 Ok((status, headers, body)) => {
-                    self.boost_port(&mapping.id, port);
+                    self.boost_port(&mapping.id, &target);
                     return Ok(Self::build_ha_response(status, headers, body));
                 }
                 Err(e) => {
-                    warn!("HA: port {} failed: {}", port, e);
-                    self.penalize_port(&mapping.id, port);
-                    self.clone().start_background_check(
-                        mapping.id.clone(), port, backend_host.clone()
-                    );
+                    warn!("HA: {}:{} failed: {}", target.host, target.port, e);
+                    self.penalize_port(&mapping.id, &target);
+                    self.clone().start_background_check(mapping.id.clone(), target, dial.egress.clone());
                 }
             }
         }
@@ -776,74 +3606,107 @@ impl ProxyServer {
         builder.body(Self::full_body(body)).unwrap()
     }
 
-    /// Handle WebSocket proxy
+    /// Handle WebSocket proxy: forward the upgrade, then bridge both upgraded connections.
+    /// The bridge is registered for draining under the request's route.
     async fn handle_websocket_proxy(
-        req: Request<Incoming>,
+        mut req: Request<Incoming>,
         mapping: &Mapping,
         remote_addr: SocketAddr,
         is_https: bool,
+        dial: &Dialer,
+        drain: &Arc<DrainTracker>,
+        conns: &Arc<ConnectionTracker>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
         let original_host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("").to_string();
-        let path = req.uri().path();
-        let query = req.uri().query();
-        let backend_url = Self::build_backend_url(mapping, path, query);
+        let backend_url = Self::build_backend_url(mapping, req.uri().path(), req.uri().query());
 
         debug!("WebSocket proxying to: {}", backend_url);
 
         let url: Url = backend_url.parse().context("Invalid backend URL")?;
         let host = url.host_str().unwrap_or("localhost");
-        let port = url.port().unwrap_or(80);
+        let port = url.port().unwrap_or(if dial.tls.is_some() { 443 } else { 80 });
 
-        let backend_stream = match TcpStream::connect(format!("{}:{}", host, port)).await {
+        let backend_stream = match dial.connect(host, port).await {
             Ok(s) => s,
             Err(e) => {
-                error!("Failed to connect to backend {}: {}", format!("{}:{}", host, port), e);
+                error!("Failed to connect to backend {}:{}: {:#}", host, port, e);
                 return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
             }
         };
 
-        let rewritten_path = Self::rewrite_path(path, mapping);
-        let uri_str = if let Some(q) = query {
-            format!("{}?{}", rewritten_path, q)
-        } else {
-            rewritten_path
-        };
+        let uri: Uri = Self::upstream_path_and_query(&req, mapping).parse().context("Invalid URI")?;
+        let route = req.extensions().get::<Route>().cloned();
+        let client_upgrade = hyper::upgrade::on(&mut req);
 
-        let mut upgrade_req = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", uri_str, original_host);
-        for (key, value) in req.headers().iter() {
-            if key != HOST {
-                if let Ok(v) = value.to_str() {
-                    upgrade_req.push_str(&format!("{}: {}\r\n", key.as_str(), v));
-                }
-            }
+        let mut builder = Request::builder().method(req.method().clone()).uri(uri).version(Version::HTTP_11);
+        if let Some(headers) = builder.headers_mut() {
+            Self::copy_forwarded_headers(req.headers(), headers);
         }
-        upgrade_req.push_str(&format!("X-Forwarded-For: {}\r\n", remote_addr.ip()));
-        upgrade_req.push_str(&format!("X-Forwarded-Host: {}\r\n", original_host));
-        upgrade_req.push_str(&format!("X-Forwarded-Proto: {}\r\n", if is_https { "https" } else { "http" }));
-        upgrade_req.push_str("\r\n");
-
-        let mut backend_stream = backend_stream;
-        backend_stream.write_all(upgrade_req.as_bytes()).await?;
+        builder = builder.header(HOST, &original_host);
+        builder = builder.header("X-Forwarded-For", remote_addr.ip().to_string());
+        builder = builder.header("X-Forwarded-Host", &original_host);
+        builder = builder.header("X-Forwarded-Proto", if is_https { "https" } else { "http" });
+        let upgrade_req = builder.body(Empty::<Bytes>::new()).context("Failed to build upgrade request")?;
 
-        let mut response_buf = vec![0u8; 4096];
-        let n = backend_stream.read(&mut response_buf).await?;
-        let response_str = String::from_utf8_lossy(&response_buf[..n]);
+        // Counted until the tunnel closes (or the upgrade fails)
+        let guard = conns.backend();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(backend_stream)).await
+            .context("Failed to establish connection to backend")?;
+        tokio::spawn(async move { let _ = conn.with_upgrades().await; });
 
-        if !response_str.contains("101") {
-            warn!("WebSocket upgrade rejected by backend");
+        let mut backend_resp = match sender.send_request(upgrade_req).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to send WebSocket upgrade to backend: {}", e);
+                return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "Bad Gateway"));
+            }
+        };
+        if backend_resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+            warn!("WebSocket upgrade rejected by backend ({})", backend_resp.status());
             return Ok(Self::error_response(StatusCode::BAD_GATEWAY, "WebSocket upgrade failed"));
         }
+        let backend_upgrade = hyper::upgrade::on(&mut backend_resp);
 
-        Ok(Response::builder()
-            .status(StatusCode::SWITCHING_PROTOCOLS)
-            .header(UPGRADE, "websocket")
-            .header(CONNECTION, "Upgrade")
-            .body(Self::empty_body())
-            .context("Failed to build WebSocket response")?)
+        let mut session = route.map(|r| drain.register(r));
+        tokio::spawn(async move {
+            let _guard = guard;
+            let (client, backend) = match tokio::try_join!(client_upgrade, backend_upgrade) {
+                Ok(pair) => pair,
+                Err(e) => {
+                    debug!("WebSocket upgrade failed: {}", e);
+                    return;
+                }
+            };
+            let mut client = TokioIo::new(client);
+            let mut backend = TokioIo::new(backend);
+            let bridge = tokio::io::copy_bidirectional(&mut client, &mut backend);
+            match session.as_mut() {
+                Some(s) => tokio::select! {
+                    _ = bridge => {}
+                    _ = s.cancelled() => debug!("WebSocket session closed: drain timeout after route change"),
+                },
+                None => { let _ = bridge.await; }
+            }
+        });
+
+        let mut response = Response::builder().status(StatusCode::SWITCHING_PROTOCOLS);
+        for (key, value) in backend_resp.headers().iter() {
+            response = response.header(key, value);
+        }
+        response.body(Self::empty_body()).context("Failed to build WebSocket response")
     }
 
     // ── Response builders ─────────────────────────────────────────────────────
 
+    fn stale_response(stale: Stale) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut resp = Response::new(Self::full_body(stale.body));
+        *resp.status_mut() = stale.status;
+        *resp.headers_mut() = stale.headers;
+        resp.headers_mut().insert(hyper::header::AGE, HeaderValue::from(stale.age.as_secs()));
+        resp.headers_mut().append(hyper::header::WARNING, HeaderValue::from_static(cache::STALE_WARNING));
+        resp
+    }
+
     fn text_response(status: StatusCode, body: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
         Response::builder()
             .status(status)
@@ -852,6 +3715,14 @@ impl ProxyServer {
             .unwrap()
     }
 
+    fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Self::full_body(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+
     fn error_response(status: StatusCode, message: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
         Response::builder()
             .status(status)
@@ -860,11 +3731,84 @@ impl ProxyServer {
             .unwrap()
     }
 
+    /// Answer for a mapping outside its access hours: the configured status and page, and
+    /// when it opens again for a 503.
+    /// Deploy hold: while the mapping is held, wait for its release (re-read every
+    /// [`hold::POLL_INTERVAL`]) or the end of the hold. Returns the 503 for a full queue.
+    async fn wait_while_held(&self, mapping: &Mapping, host: &str, path: &str) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let active = |m: &Mapping| m.hold.as_deref().and_then(|h| h.parse::<Hold>().ok()).filter(|h| h.active(chrono::Utc::now()));
+        let mut held = active(mapping)?;
+        let Some(_queued) = self.held.enter(&mapping.id, held.max_queue) else {
+            warn!("Hold queue of {} full ({} requests)", mapping.domain, held.max_queue);
+            let mut resp = Self::error_response(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable: deploy in progress");
+            let secs = held.remaining(chrono::Utc::now()).as_secs() + 1;
+            resp.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs));
+            return Some(resp);
+        };
+        debug!("Holding request for {}{} ({} waiting)", mapping.domain, path, self.held.len(&mapping.id));
+        loop {
+            tokio::time::sleep(held.remaining(chrono::Utc::now()).min(hold::POLL_INTERVAL)).await;
+            let current = self.db_manager.find_mapping(host, path).ok().flatten().filter(|m| m.id == mapping.id);
+            match current.as_ref().and_then(active) {
+                Some(h) => held = h,
+                None => return None,
+            }
+        }
+    }
+
+    /// The startup page of a mapping whose backend is slow to answer: a 503 that reloads
+    /// itself after the refresh interval.
+    async fn startup_response(startup: &StartupPage) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let refresh = startup.refresh.as_secs().to_string();
+        let body = match &startup.page {
+            Some(page) => tokio::fs::read(page).await.unwrap_or_else(|e| {
+                warn!("Cannot read startup page {}: {}", page.display(), e);
+                startup_page::DEFAULT_PAGE.replace("{refresh}", &refresh).into_bytes()
+            }),
+            None => startup_page::DEFAULT_PAGE.replace("{refresh}", &refresh).into_bytes(),
+        };
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .header(hyper::header::RETRY_AFTER, &refresh)
+            .header("Refresh", &refresh)
+            .body(Self::full_body(Bytes::from(body)))
+            .unwrap()
+    }
+
+    async fn closed_response(hours: &AccessHours, now: chrono::DateTime<chrono::Utc>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut resp = match &hours.page {
+            Some(page) => match tokio::fs::read(page).await {
+                Ok(body) => {
+                    let html = page.extension().is_some_and(|e| e == "html" || e == "htm");
+                    Response::builder()
+                        .status(hours.status)
+                        .header("Content-Type", if html { "text/html; charset=utf-8" } else { "text/plain" })
+                        .body(Self::full_body(Bytes::from(body)))
+                        .unwrap()
+                }
+                Err(e) => {
+                    warn!("Cannot read access hours page {}: {}", page.display(), e);
+                    Self::error_response(hours.status, "Closed")
+                }
+            },
+            None => Self::error_response(hours.status, "Closed: outside access hours"),
+        };
+        if hours.status == StatusCode::SERVICE_UNAVAILABLE {
+            if let Some(open) = hours.next_open(now) {
+                let secs = (open - now).num_seconds().max(1) as u64;
+                resp.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs));
+            }
+        }
+        resp
+    }
+
     fn unauthorized_response(scheme: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
-        let www_auth = if scheme == "bearer" {
-            "Bearer realm=\"Proxy\""
-        } else {
-            "Basic realm=\"Proxy\""
+        let www_auth = match scheme {
+            "bearer" => "Bearer realm=\"Proxy\"",
+            "api_key" => "ApiKey realm=\"Proxy\"",
+            _ => "Basic realm=\"Proxy\"",
         };
         Response::builder()
             .status(StatusCode::UNAUTHORIZED)
@@ -874,6 +3818,14 @@ impl ProxyServer {
             .unwrap()
     }
 
+    /// `https://host[:port]/path?query`, leaving out the port when it is the default 443.
+    fn https_location(host: &str, https_port: u16, path_and_query: &str) -> String {
+        match https_port {
+            443 => format!("https://{}{}", host, path_and_query),
+            port => format!("https://{}:{}{}", host, port, path_and_query),
+        }
+    }
+
     fn redirect_response(location: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
         Response::builder()
             .status(StatusCode::MOVED_PERMANENTLY)
@@ -882,6 +3834,24 @@ impl ProxyServer {
             .unwrap()
     }
 
+    /// 302 with cookies to set
+    fn found_response(location: &str, cookies: &[String]) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut builder = Response::builder().status(StatusCode::FOUND).header("Location", location);
+        for cookie in cookies {
+            builder = builder.header(hyper::header::SET_COOKIE, cookie);
+        }
+        builder.body(Self::empty_body()).unwrap()
+    }
+
+    /// 308: like 301, but the client must repeat the method and body
+    fn permanent_redirect_response(location: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header("Location", location)
+            .body(Self::empty_body())
+            .unwrap()
+    }
+
     fn full_body(bytes: Bytes) -> BoxBody<Bytes, hyper::Error> {
         Full::new(bytes).map_err(|never| match never {}).boxed()
     }
@@ -926,6 +3896,77 @@ impl ProxyBuilder {
     pub fn force_https(mut self, v: bool) -> Self { self.config.force_https = v; self }
     pub fn http_host(mut self, h: impl Into<String>) -> Self { self.config.http_host = h.into(); self }
     pub fn acme_directory_url(mut self, url: impl Into<String>) -> Self { self.acme_directory_url = Some(url.into()); self }
+    pub fn consul_addr(mut self, addr: impl Into<String>) -> Self { self.config.consul_addr = Some(addr.into()); self }
+    pub fn etcd_endpoint(mut self, addr: impl Into<String>) -> Self { self.config.etcd_endpoint = Some(addr.into()); self }
+    pub fn srv_nameserver(mut self, addr: SocketAddr) -> Self { self.config.srv_nameserver = Some(addr); self }
+    pub fn path_normalization(mut self, n: PathNormalization) -> Self { self.config.path_normalization = n; self }
+    pub fn max_headers(mut self, n: usize) -> Self { self.config.max_headers = n; self }
+    pub fn max_header_bytes(mut self, n: usize) -> Self { self.config.max_header_bytes = n; self }
+    pub fn max_request_line(mut self, n: usize) -> Self { self.config.max_request_line = n; self }
+    pub fn header_read_timeout(mut self, d: Duration) -> Self { self.config.header_read_timeout = d; self }
+    pub fn duplicate_headers(mut self, d: DuplicateHeaders) -> Self { self.config.duplicate_headers = d; self }
+    pub fn cert_reload_interval(mut self, d: Duration) -> Self { self.config.cert_reload_interval = d; self }
+    pub fn admin_token(mut self, t: impl Into<String>) -> Self { self.config.admin_token = Some(t.into()); self }
+    pub fn access_log(mut self, p: LogPolicy) -> Self { self.config.access_log = p; self }
+    pub fn max_connections(mut self, n: usize) -> Self { self.config.max_connections = n; self }
+    pub fn fd_reserve(mut self, n: usize) -> Self { self.config.fd_reserve = n; self }
+    pub fn connection_limit_action(mut self, a: LimitAction) -> Self { self.config.connection_limit_action = a; self }
+    pub fn max_request_timeout(mut self, d: Duration) -> Self { self.config.max_request_timeout = d; self }
+    pub fn body_rewrite_max_bytes(mut self, n: usize) -> Self { self.config.body_rewrite_max_bytes = n; self }
+    pub fn rate_limit(mut self, l: RateLimit) -> Self { self.config.rate_limit = Some(l); self }
+    pub fn rate_limit_ban(mut self, p: BanPolicy) -> Self { self.config.rate_limit_ban = Some(p); self }
+    pub fn auth_failure_ban(mut self, p: BanPolicy) -> Self { self.config.auth_failure_ban = Some(p); self }
+    pub fn trusted_proxies(mut self, n: Vec<Network>) -> Self { self.config.trusted_proxies = n; self }
+    pub fn redis_url(mut self, url: impl Into<String>) -> Self { self.config.redis_url = Some(url.into()); self }
+    pub fn cluster_peers(mut self, peers: Vec<String>) -> Self { self.config.cluster_peers = peers; self }
+    pub fn cluster_secret(mut self, s: impl Into<String>) -> Self { self.config.cluster_secret = Some(s.into()); self }
+    pub fn cluster_interval(mut self, d: Duration) -> Self { self.config.cluster_interval = d; self }
+    pub fn cert_renew_command(mut self, c: impl Into<String>) -> Self { self.config.cert_renew_command = Some(c.into()); self }
+    pub fn cert_renew_interval(mut self, d: Duration) -> Self { self.config.cert_renew_interval = d; self }
+    pub fn upstream_pool(mut self, settings: PoolSettings) -> Self { self.config.upstream_pool = settings; self }
+    pub fn client_tcp(mut self, options: TcpOptions) -> Self { self.config.client_tcp = options; self }
+    pub fn backend_tcp(mut self, options: TcpOptions) -> Self { self.config.backend_tcp = options; self }
+    pub fn backend_ip_preference(mut self, p: IpPreference) -> Self { self.config.backend_ip_preference = p; self }
+    pub fn backend_connect_attempt_delay(mut self, d: Duration) -> Self { self.config.backend_connect_attempt_delay = d; self }
+    pub fn response_cache_max_bytes(mut self, n: usize) -> Self { self.config.response_cache_max_bytes = n; self }
+    pub fn response_cache_dir(mut self, dir: impl Into<std::path::PathBuf>) -> Self { self.config.response_cache_dir = Some(dir.into()); self }
+    pub fn response_cache_disk_max_bytes(mut self, n: u64) -> Self { self.config.response_cache_disk_max_bytes = n; self }
+    pub fn response_cache_disk_max_entries(mut self, n: usize) -> Self { self.config.response_cache_disk_max_entries = n; self }
+    pub fn outlier_interval(mut self, d: Duration) -> Self { self.config.outlier_interval = d; self }
+    pub fn outlier_cooldown(mut self, d: Duration) -> Self { self.config.outlier_cooldown = d; self }
+    pub fn anomaly_alerts(mut self, on: bool) -> Self { self.config.anomaly_alerts = on; self }
+    pub fn egress_proxy(mut self, p: EgressProxy) -> Self { self.config.egress_proxy = Some(p); self }
+    pub fn session_secret(mut self, s: impl Into<String>) -> Self { self.config.session_secret = Some(s.into()); self }
+    pub fn oidc_session_ttl(mut self, d: Duration) -> Self { self.config.oidc_session_ttl = d; self }
+    pub fn session_store(mut self, store: session::Backend) -> Self { self.config.session_store = store; self }
+    pub fn acme_server(mut self, on: bool) -> Self { self.config.acme_server = on; self }
+    pub fn acme_server_domains(mut self, d: Vec<String>) -> Self { self.config.acme_server_domains = d; self }
+    pub fn acme_server_challenge_port(mut self, p: u16) -> Self { self.config.acme_server_challenge_port = p; self }
+    pub fn webhook_urls(mut self, urls: Vec<String>) -> Self { self.config.webhook_urls = urls; self }
+    pub fn webhook_secret(mut self, secret: impl Into<String>) -> Self { self.config.webhook_secret = Some(secret.into()); self }
+    pub fn event_log_size(mut self, n: usize) -> Self { self.config.event_log_size = n; self }
+    pub fn generate_etags(mut self, on: bool) -> Self { self.config.generate_etags = on; self }
+    pub fn health_allowed_ips(mut self, ips: impl Into<String>) -> Self { self.config.health_allowed_ips = Some(ips.into()); self }
+    pub fn internal_listen(mut self, addr: SocketAddr) -> Self { self.config.internal_listen = Some(addr); self }
+    pub fn acme_challenges_mapped_only(mut self, on: bool) -> Self { self.config.acme_challenges_mapped_only = on; self }
+    pub fn max_proxy_hops(mut self, n: u32) -> Self { self.config.max_proxy_hops = n; self }
+    pub fn usage_flush_interval(mut self, d: Duration) -> Self { self.config.usage_flush_interval = d; self }
+    pub fn require_domain_verification(mut self, on: bool) -> Self { self.config.require_domain_verification = on; self }
+    pub fn probe_interval(mut self, d: Duration) -> Self { self.config.probe_interval = d; self }
+    pub fn probe_retention(mut self, d: Duration) -> Self { self.config.probe_retention = d; self }
+    pub fn scrub_response_headers(mut self, p: ScrubProfile) -> Self { self.config.scrub_response_headers = p; self }
+    pub fn debug_secret(mut self, s: impl Into<String>) -> Self { self.config.debug_secret = Some(s.into()); self }
+    pub fn tls_client_ca(mut self, path: impl Into<std::path::PathBuf>) -> Self { self.config.tls_client_ca = Some(path.into()); self }
+    pub fn forward_tls_headers(mut self, on: bool) -> Self { self.config.forward_tls_headers = on; self }
+    pub fn forward_client_cert(mut self, d: ClientCertDetails) -> Self { self.config.forward_client_cert = Some(d); self }
+    pub fn analytics_sink(mut self, sink: analytics::Sink) -> Self { self.config.analytics_sink = Some(sink); self }
+    pub fn analytics_retention(mut self, d: Duration) -> Self { self.config.analytics_retention = d; self }
+    pub fn analytics_flush_interval(mut self, d: Duration) -> Self { self.config.analytics_flush_interval = d; self }
+    pub fn forward_proxy(mut self, addr: SocketAddr, acl: forward_proxy::Acl) -> Self {
+        self.config.forward_proxy_listen = Some(addr);
+        self.config.forward_proxy_acl = acl;
+        self
+    }
 
     /// Set a custom fallback handler for requests with no proxy mapping.
     pub fn fallback(mut self, h: impl FallbackHandler) -> Self {
@@ -938,15 +3979,11 @@ impl ProxyBuilder {
         let cert_manager = Arc::new(crate::certificate::CertificateManager::new(
             &self.certs_dir, self.acme_directory_url,
         )?);
-        Ok(ProxyServer {
-            config: self.config,
-            db_manager,
-            cert_manager,
-            port_scores: DashMap::new(),
-            rr_counters: DashMap::new(),
-            bg_checks: DashMap::new(),
-            fallback: self.fallback.unwrap_or_else(|| Arc::new(NotFoundFallback)),
-        })
+        let mut server = ProxyServer::new(self.config, db_manager, cert_manager);
+        if let Some(fallback) = self.fallback {
+            server.fallback = fallback;
+        }
+        Ok(server)
     }
 }
 
@@ -961,13 +3998,7 @@ mod tests {
             front_uri: front_uri.to_string(),
             back_port: 3000,
             back_uri: back_uri.to_string(),
-            backend: None,
-            back_ports: None,
-            allowed_ips: None,
-            auth_type: None,
-            auth_credentials: None,
-            created_at: String::new(),
-            updated_at: String::new(),
+            ..Default::default()
         }
     }
 
@@ -1023,4 +4054,105 @@ mod tests {
         assert!(ProxyServer::is_ip_allowed("192.168.0.100", Some("10.0.0.1,192.168.0.0/24")));
         assert!(!ProxyServer::is_ip_allowed("8.8.8.8", Some("10.0.0.1,192.168.0.0/24")));
     }
+
+    fn body_request(content_type: Option<&str>, length: Option<&str>) -> Request<()> {
+        let mut b = Request::builder().method("POST").uri("/upload");
+        if let Some(ct) = content_type {
+            b = b.header("content-type", ct);
+        }
+        if let Some(len) = length {
+            b = b.header("content-length", len);
+        }
+        b.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_body_rules_max_length() {
+        let mut m = mapping("", "");
+        m.max_body_bytes = Some(1000);
+        assert!(ProxyServer::check_body_rules(&body_request(None, Some("1000")), &m).is_none());
+        assert_eq!(ProxyServer::check_body_rules(&body_request(None, Some("1001")), &m).unwrap().0,
+                   StatusCode::PAYLOAD_TOO_LARGE);
+        let chunked = Request::builder().header("transfer-encoding", "chunked").body(()).unwrap();
+        assert_eq!(ProxyServer::check_body_rules(&chunked, &m).unwrap().0, StatusCode::LENGTH_REQUIRED);
+    }
+
+    #[test]
+    fn test_body_rules_content_type() {
+        let mut m = mapping("", "");
+        m.allowed_content_types = Some("application/json, image/*".to_string());
+        let check = |ct, len| ProxyServer::check_body_rules(&body_request(ct, len), &m).map(|r| r.0);
+        assert_eq!(check(Some("application/json; charset=utf-8"), Some("2")), None);
+        assert_eq!(check(Some("IMAGE/PNG"), Some("10")), None);
+        assert_eq!(check(Some("text/html"), Some("10")), Some(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        assert_eq!(check(None, Some("10")), Some(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        // Bodyless requests are not affected
+        assert_eq!(check(None, None), None);
+    }
+
+    #[test]
+    fn test_rewrite_path_encoded_prefix() {
+        assert_eq!(ProxyServer::rewrite_path("/api%2Fv1/users", &mapping("api/v1", "v1")), "/v1/users");
+    }
+
+    #[test]
+    fn test_rewrite_path_raw_keeps_encoding() {
+        let mut m = mapping("api", "v1");
+        assert_eq!(ProxyServer::rewrite_path_raw("/api/a%2Fb//c", &m).as_deref(), Some("/v1/a%2Fb//c"));
+        assert_eq!(ProxyServer::rewrite_path_raw("/api", &m).as_deref(), Some("/v1/"));
+        assert_eq!(ProxyServer::rewrite_path_raw("/other", &m), None);
+        m.back_uri.clear();
+        assert_eq!(ProxyServer::rewrite_path_raw("/api", &m).as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn test_upstream_path_preserved_only_when_enabled() {
+        let mut m = mapping("", "");
+        let mut req = Request::builder().uri("/files/a%2fb?x=1").body(()).unwrap();
+        req.extensions_mut().insert(OriginalPath("/files//a%2fb".to_string()));
+        *req.uri_mut() = "/files/a%2Fb?x=1".parse().unwrap();
+
+        assert_eq!(ProxyServer::upstream_path_and_query(&req, &m), "/files/a%2Fb?x=1");
+        m.preserve_path = true;
+        assert_eq!(ProxyServer::upstream_path_and_query(&req, &m), "/files//a%2fb?x=1");
+
+        // Dot segments are never forwarded raw
+        req.extensions_mut().insert(OriginalPath("/x/../files/a%2fb".to_string()));
+        assert_eq!(ProxyServer::upstream_path_and_query(&req, &m), "/files/a%2Fb?x=1");
+    }
+
+    /// A server on a database in a temporary directory, which lives as long as the directory.
+    fn server(config: ProxyConfig) -> (tempfile::TempDir, ProxyServer) {
+        let dir = tempfile::tempdir().unwrap();
+        let server = ProxyServer::new(
+            config,
+            Arc::new(DatabaseManager::new(dir.path().join("t.db")).unwrap()),
+            Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+        );
+        (dir, server)
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let (_dir, proxy) = server(ProxyConfig { max_request_line: 64, max_header_bytes: 200, ..Default::default() });
+        let ok = Request::builder().uri("/short").header("x-a", "1").body(()).unwrap();
+        assert!(proxy.check_request_limits(&ok).is_none());
+
+        let long = Request::builder().uri(format!("/{}", "a".repeat(64))).body(()).unwrap();
+        assert_eq!(proxy.check_request_limits(&long).unwrap().0, StatusCode::URI_TOO_LONG);
+
+        let big = Request::builder().uri("/").header("x-big", "b".repeat(200)).body(()).unwrap();
+        assert_eq!(proxy.check_request_limits(&big).unwrap().0, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+        let smuggle = Request::builder().uri("/")
+            .header("content-length", "5").header("transfer-encoding", "chunked").body(()).unwrap();
+        assert_eq!(proxy.check_request_limits(&smuggle).unwrap().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_https_location_uses_configured_port() {
+        assert_eq!(ProxyServer::https_location("example.com", 443, "/a?b=1"), "https://example.com/a?b=1");
+        assert_eq!(ProxyServer::https_location("example.com", 8443, "/a?b=1"), "https://example.com:8443/a?b=1");
+        assert_eq!(ProxyServer::https_location("[::1]", 8443, "/"), "https://[::1]:8443/");
+    }
 }
@@ -0,0 +1,282 @@
+//! Request rate limiting
+//! Sliding-window limits per client IP and domain, counted in memory or — with a Redis URL
+//! configured — in Redis, so a limit holds across all proxy instances instead of
+//! multiplying by the node count
+
+use crate::redis::{RedisClient, Value};
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Redis keys: `{KEY_PREFIX}{domain}/{ip}:{window number}`
+const KEY_PREFIX: &str = "rustproxy:rl:";
+
+/// Count a request in the current window and read the previous one, atomically
+const COUNT_SCRIPT: &str = "local c = redis.call('INCR', KEYS[1]) \
+    if c == 1 then redis.call('PEXPIRE', KEYS[1], ARGV[1]) end \
+    return {c, tonumber(redis.call('GET', KEYS[2]) or '0')}";
+
+/// Requests allowed per window, written `100/1m` (`s`, `m`, `h`; `10/s` means per second)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u64,
+    pub window: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit '{}' (e.g. 100/1m)", s);
+        let (requests, window) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let requests: u64 = requests.trim().parse().map_err(|_| invalid())?;
+        let window = window.trim();
+        let unit = match window.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3600,
+            _ => return Err(invalid()),
+        };
+        let count = match &window[..window.len() - 1] {
+            "" => 1,
+            n => n.parse::<u64>().map_err(|_| invalid())?,
+        };
+        if requests == 0 || count == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { requests, window: Duration::from_secs(count * unit) })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}s", self.requests, self.window.as_secs())
+    }
+}
+
+/// The limit for a mapping: its own `rate_limit` (`off` disables), else the server default.
+pub fn effective(mapping_value: Option<&str>, default: Option<RateLimit>) -> Option<RateLimit> {
+    match mapping_value.map(str::trim) {
+        None | Some("") => default,
+        Some(v) if v.eq_ignore_ascii_case("off") => None,
+        Some(v) => v.parse().map_err(|e| warn!("Using the default rate limit: {}", e)).ok().or(default),
+    }
+}
+
+/// Bucket of one client on one domain
+pub fn client_key(domain: &str, client_ip: &str) -> String {
+    format!("{}/{}", domain, client_ip)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny { retry_after: Duration },
+}
+
+struct Counter {
+    window: u64,
+    current: u64,
+    previous: u64,
+    /// Unix ms after which the counter can't affect a decision any more
+    expires: u64,
+}
+
+pub struct RateLimiter {
+    local: DashMap<String, Counter>,
+    redis: Option<RedisClient>,
+    checks: AtomicU64,
+    /// Redis failures are logged at most once a minute
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// Counting in memory, or in Redis when a client is given. Should Redis fail, requests
+    /// are counted in memory (per instance) until it answers again.
+    pub fn new(redis: Option<RedisClient>) -> Self {
+        Self {
+            local: DashMap::new(),
+            redis,
+            checks: AtomicU64::new(0),
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    /// Count a request against `key` and decide whether it is within `limit`.
+    pub async fn check(&self, key: &str, limit: &RateLimit) -> Decision {
+        let window_ms = (limit.window.as_millis() as u64).max(1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let window = now / window_ms;
+        let elapsed = now % window_ms;
+
+        let counted = match &self.redis {
+            Some(redis) => Self::count_in_redis(redis, key, window, window_ms).await
+                .map_err(|e| self.warn_redis(e))
+                .ok(),
+            None => None,
+        };
+        let (current, previous) = counted.unwrap_or_else(|| self.count_locally(key, window, now, window_ms));
+
+        // Sliding window: the previous window counts for the share that still overlaps
+        let overlap = (window_ms - elapsed) as f64 / window_ms as f64;
+        if previous as f64 * overlap + current as f64 <= limit.requests as f64 {
+            Decision::Allow
+        } else {
+            Decision::Deny { retry_after: Duration::from_millis(window_ms - elapsed) }
+        }
+    }
+
+    fn count_locally(&self, key: &str, window: u64, now: u64, window_ms: u64) -> (u64, u64) {
+        if self.checks.fetch_add(1, Ordering::Relaxed) % 4096 == 4095 {
+            self.local.retain(|_, c| c.expires > now);
+        }
+        let mut counter = self.local.entry(key.to_string()).or_insert(Counter {
+            window,
+            current: 0,
+            previous: 0,
+            expires: 0,
+        });
+        if counter.window != window {
+            counter.previous = if counter.window + 1 == window { counter.current } else { 0 };
+            counter.current = 0;
+            counter.window = window;
+        }
+        counter.current += 1;
+        counter.expires = (window + 2) * window_ms;
+        (counter.current, counter.previous)
+    }
+
+    async fn count_in_redis(redis: &RedisClient, key: &str, window: u64, window_ms: u64) -> Result<(u64, u64)> {
+        let current = format!("{}{}:{}", KEY_PREFIX, key, window);
+        let previous = format!("{}{}:{}", KEY_PREFIX, key, window.saturating_sub(1));
+        let ttl = (window_ms * 2).to_string();
+        let reply = redis.command(&[
+            b"EVAL", COUNT_SCRIPT.as_bytes(), b"2", current.as_bytes(), previous.as_bytes(), ttl.as_bytes(),
+        ]).await?;
+        match reply {
+            Value::Array(v) if v.len() == 2 => match (v[0].as_int(), v[1].as_int()) {
+                (Some(c), Some(p)) => Ok((c.max(0) as u64, p.max(0) as u64)),
+                _ => Err(anyhow!("unexpected rate-limit reply {:?}", v)),
+            },
+            other => Err(anyhow!("unexpected rate-limit reply {:?}", other)),
+        }
+    }
+
+    fn warn_redis(&self, e: anyhow::Error) {
+        let mut last = self.last_warning.lock();
+        if last.is_none_or(|t| t.elapsed() >= Duration::from_secs(60)) {
+            warn!("Rate limiting falls back to per-instance counters: {}", e);
+            *last = Some(Instant::now());
+        }
+    }
+
+    /// Empty the buckets of a domain (`example.com`), one client on it
+    /// (`example.com/1.2.3.4`), or all (`None`). Returns how many counters were dropped.
+    pub async fn reset(&self, key: Option<&str>) -> Result<usize> {
+        let matches = |k: &str| match key {
+            None => true,
+            Some(key) if key.contains('/') => k == key,
+            Some(domain) => k.strip_prefix(domain).is_some_and(|rest| rest.starts_with('/')),
+        };
+        let before = self.local.len();
+        self.local.retain(|k, _| !matches(k));
+        let mut cleared = before.saturating_sub(self.local.len());
+
+        if let Some(redis) = &self.redis {
+            let pattern = match key {
+                None => format!("{}*", KEY_PREFIX),
+                Some(key) if key.contains('/') => format!("{}{}:*", KEY_PREFIX, glob_escape(key)),
+                Some(domain) => format!("{}{}/*", KEY_PREFIX, glob_escape(domain)),
+            };
+            cleared += Self::delete_matching(redis, &pattern).await?;
+        }
+        Ok(cleared)
+    }
+
+    async fn delete_matching(redis: &RedisClient, pattern: &str) -> Result<usize> {
+        let mut cursor = b"0".to_vec();
+        let mut deleted = 0;
+        loop {
+            let reply = redis.command(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", b"500"]).await?;
+            let Value::Array(mut parts) = reply else {
+                return Err(anyhow!("unexpected SCAN reply {:?}", reply));
+            };
+            let (Some(Value::Array(keys)), Some(Value::Bulk(next))) = (parts.pop(), parts.pop()) else {
+                return Err(anyhow!("unexpected SCAN reply"));
+            };
+            let keys: Vec<Vec<u8>> = keys.into_iter()
+                .filter_map(|k| match k { Value::Bulk(k) => Some(k), _ => None })
+                .collect();
+            if !keys.is_empty() {
+                let mut args: Vec<&[u8]> = vec![b"DEL"];
+                args.extend(keys.iter().map(Vec::as_slice));
+                deleted += redis.command(&args).await?.as_int().unwrap_or(0).max(0) as usize;
+            }
+            if next == b"0" {
+                return Ok(deleted);
+            }
+            cursor = next;
+        }
+    }
+}
+
+/// `s` with Redis glob metacharacters escaped.
+fn glob_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!("100/1m".parse(), Ok(RateLimit { requests: 100, window: Duration::from_secs(60) }));
+        assert_eq!("10/s".parse(), Ok(RateLimit { requests: 10, window: Duration::from_secs(1) }));
+        assert_eq!(" 5000 / 2h ".parse(), Ok(RateLimit { requests: 5000, window: Duration::from_secs(7200) }));
+        assert!("100".parse::<RateLimit>().is_err());
+        assert!("0/1m".parse::<RateLimit>().is_err());
+        assert!("10/1d".parse::<RateLimit>().is_err());
+
+        let default = "10/s".parse().ok();
+        assert_eq!(effective(Some("off"), default), None);
+        assert_eq!(effective(Some("3/1m"), default), "3/1m".parse().ok());
+        assert_eq!(effective(Some("nonsense"), default), default);
+        assert_eq!(effective(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_limit_and_reset() {
+        let limiter = RateLimiter::new(None);
+        let limit = RateLimit { requests: 2, window: Duration::from_secs(3600) };
+        let key = client_key("a.com", "10.0.0.1");
+        assert_eq!(limiter.check(&key, &limit).await, Decision::Allow);
+        assert_eq!(limiter.check(&key, &limit).await, Decision::Allow);
+        assert!(matches!(limiter.check(&key, &limit).await, Decision::Deny { .. }));
+        // Other clients and domains have their own buckets
+        assert_eq!(limiter.check(&client_key("a.com", "10.0.0.2"), &limit).await, Decision::Allow);
+        assert_eq!(limiter.check(&client_key("b.com", "10.0.0.1"), &limit).await, Decision::Allow);
+
+        assert_eq!(limiter.reset(Some("a.co")).await.unwrap(), 0);
+        assert_eq!(limiter.reset(Some("a.com")).await.unwrap(), 2);
+        assert_eq!(limiter.check(&key, &limit).await, Decision::Allow);
+        assert_eq!(limiter.reset(None).await.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_glob_escape() {
+        assert_eq!(glob_escape("*.example.com/[::1]"), "\\*.example.com/\\[::1\\]");
+    }
+}
@@ -0,0 +1,248 @@
+//! Minimal Redis client
+//! Just enough RESP2 for shared counters: a handful of pooled connections, commands as
+//! byte-string arrays, replies decoded into [`Value`]
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// Connections kept open; commands on one connection are serialized
+const POOL_SIZE: usize = 8;
+
+/// A decoded reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Nil,
+    Int(i64),
+    Status(String),
+    Bulk(Vec<u8>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            Value::Bulk(b) => std::str::from_utf8(b).ok()?.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Where and how to connect, from `redis://[[user]:password@]host[:port][/db]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    addr: String,
+    user: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Self> {
+        let url = url::Url::parse(url).with_context(|| format!("invalid Redis URL '{}'", url))?;
+        if url.scheme() != "redis" {
+            bail!("unsupported Redis URL scheme '{}' (redis://)", url.scheme());
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("Redis URL needs a host"))?;
+        let db = match url.path().trim_start_matches('/') {
+            "" => 0,
+            db => db.parse().with_context(|| format!("invalid Redis database '{}'", db))?,
+        };
+        Ok(Self {
+            addr: format!("{}:{}", host, url.port().unwrap_or(6379)),
+            user: Some(url.username()).filter(|u| !u.is_empty()).map(percent_decode),
+            password: url.password().map(percent_decode),
+            db,
+        })
+    }
+}
+
+/// `%XX` escapes of a URL userinfo part decoded.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+type Connection = BufStream<TcpStream>;
+
+pub struct RedisClient {
+    target: Target,
+    /// Per command, connecting included
+    timeout: Duration,
+    pool: Vec<Mutex<Option<Connection>>>,
+    next: AtomicUsize,
+}
+
+impl RedisClient {
+    /// Client for `url`; connections are opened on first use.
+    pub fn new(url: &str, timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            target: Target::parse(url)?,
+            timeout,
+            pool: (0..POOL_SIZE).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Run one command. A failed connection is dropped and reopened by a later call.
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Value> {
+        let slot = &self.pool[self.next.fetch_add(1, Ordering::Relaxed) % self.pool.len()];
+        let mut conn = slot.lock().await;
+        let result = tokio::time::timeout(self.timeout, async {
+            if conn.is_none() {
+                *conn = Some(self.connect().await?);
+            }
+            let stream = conn.as_mut().expect("connected above");
+            request(stream, args).await
+        }).await;
+        match result {
+            Ok(Ok(Value::Status(s))) if s.starts_with('-') => Err(anyhow!("Redis: {}", &s[1..])),
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => {
+                *conn = None;
+                Err(e)
+            }
+            Err(_) => {
+                *conn = None;
+                Err(anyhow!("Redis {} timed out", self.target.addr))
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let stream = TcpStream::connect(&self.target.addr).await
+            .with_context(|| format!("connecting to Redis at {}", self.target.addr))?;
+        stream.set_nodelay(true)?;
+        let mut conn = BufStream::new(stream);
+        if let Some(password) = &self.target.password {
+            let reply = match &self.target.user {
+                Some(user) => request(&mut conn, &[b"AUTH", user.as_bytes(), password.as_bytes()]).await?,
+                None => request(&mut conn, &[b"AUTH", password.as_bytes()]).await?,
+            };
+            expect_ok(reply, "AUTH")?;
+        }
+        if self.target.db != 0 {
+            let reply = request(&mut conn, &[b"SELECT", self.target.db.to_string().as_bytes()]).await?;
+            expect_ok(reply, "SELECT")?;
+        }
+        Ok(conn)
+    }
+}
+
+fn expect_ok(reply: Value, command: &str) -> Result<()> {
+    match reply {
+        Value::Status(s) if s == "OK" => Ok(()),
+        Value::Status(s) => Err(anyhow!("Redis {} failed: {}", command, s.trim_start_matches('-'))),
+        other => Err(anyhow!("unexpected Redis {} reply {:?}", command, other)),
+    }
+}
+
+async fn request(conn: &mut Connection, args: &[&[u8]]) -> Result<Value> {
+    conn.write_all(&encode(args)).await?;
+    conn.flush().await?;
+    read_value(conn).await
+}
+
+/// A command as a RESP array of bulk strings.
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + args.iter().map(|a| a.len() + 16).sum::<usize>());
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Read one reply. Error replies come back as a `Status` starting with `-`.
+pub async fn read_value<R: AsyncBufReadExt + Unpin + Send>(r: &mut R) -> Result<Value> {
+    let mut line = String::new();
+    if r.read_line(&mut line).await? == 0 {
+        bail!("Redis closed the connection");
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let Some(kind) = line.bytes().next().filter(u8::is_ascii) else {
+        bail!("bad RESP reply '{}'", line);
+    };
+    let rest = &line[1..];
+    let len = || rest.parse::<i64>().with_context(|| format!("bad RESP length '{}'", rest));
+    Ok(match kind {
+        b'+' => Value::Status(rest.to_string()),
+        b'-' => Value::Status(format!("-{}", rest)),
+        b':' => Value::Int(len()?),
+        b'$' => match len()? {
+            n if n < 0 => Value::Nil,
+            n => {
+                let mut buf = vec![0; n as usize + 2];
+                r.read_exact(&mut buf).await?;
+                buf.truncate(n as usize);
+                Value::Bulk(buf)
+            }
+        },
+        b'*' => match len()? {
+            n if n < 0 => Value::Nil,
+            n => {
+                let mut items = Vec::with_capacity(n.min(1024) as usize);
+                for _ in 0..n {
+                    items.push(Box::pin(read_value(r)).await?);
+                }
+                Value::Array(items)
+            }
+        },
+        _ => bail!("bad RESP reply '{}'", line),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        let t = Target::parse("redis://:p%40ss@cache.internal:6380/2").unwrap();
+        assert_eq!(t, Target {
+            addr: "cache.internal:6380".to_string(),
+            user: None,
+            password: Some("p@ss".to_string()),
+            db: 2,
+        });
+        assert_eq!(Target::parse("redis://localhost").unwrap().addr, "localhost:6379");
+        assert!(Target::parse("rediss://localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encode_and_read() {
+        assert_eq!(encode(&[b"GET", b"k"]), b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+
+        let mut reply: &[u8] = b"*3\r\n:7\r\n$-1\r\n$5\r\nhe\r\no\r\n-ERR nope\r\n";
+        assert_eq!(read_value(&mut reply).await.unwrap(), Value::Array(vec![
+            Value::Int(7),
+            Value::Nil,
+            Value::Bulk(b"he\r\no".to_vec()),
+        ]));
+        assert_eq!(read_value(&mut reply).await.unwrap(), Value::Status("-ERR nope".to_string()));
+        assert!(read_value(&mut reply).await.is_err());
+    }
+}
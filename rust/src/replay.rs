@@ -0,0 +1,258 @@
+//! Traffic replay
+//! Re-issues the requests in an access log (`rustproxy replay <file> --target ...`) against
+//! another instance or a staging backend, keeping the logged pacing (optionally sped up or
+//! slowed down), and reports which responses differ from the logged status
+
+use crate::bench::LoadReport;
+use anyhow::{anyhow, Context, Result};
+use hyper::{Method, Uri};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// One request read from an access log line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Timestamp at the start of the line, when the log format has one
+    pub time: Option<chrono::DateTime<chrono::FixedOffset>>,
+    pub method: String,
+    pub host: String,
+    /// Path only; the access log doesn't record query strings
+    pub path: String,
+    pub status: u16,
+}
+
+/// Parse an access log line as written by [`AccessLog`](crate::access_log::AccessLog), with
+/// or without the timestamp and level the log formatter puts in front. Other lines: `None`.
+pub fn parse_line(line: &str) -> Option<Entry> {
+    let line = strip_ansi(line);
+    let start = line.find(" access method=").map(|i| i + 1).or_else(|| line.starts_with("access method=").then_some(0))?;
+    let time = line[..start].split_whitespace().next().and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+
+    let fields = fields(&line[start + "access ".len()..]);
+    let field = |name: &str| fields.iter().find(|(k, _)| *k == name).map(|(_, v)| v.clone());
+    Some(Entry {
+        time,
+        method: field("method")?,
+        host: field("host")?,
+        path: field("path")?,
+        status: field("status")?.parse().ok()?,
+    })
+}
+
+/// Every access line in `text`, in order.
+pub fn parse(text: &str) -> Vec<Entry> {
+    text.lines().filter_map(parse_line).collect()
+}
+
+/// `key=value` pairs; values with spaces, quotes or `=` are Rust-debug quoted.
+fn fields(s: &str) -> Vec<(&str, String)> {
+    let mut out = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => match unquote(quoted) {
+                Some(parsed) => parsed,
+                None => break,
+            },
+            None => {
+                let end = after.find(' ').unwrap_or(after.len());
+                (after[..end].to_string(), &after[end..])
+            }
+        };
+        out.push((key, value));
+        rest = next.trim_start();
+    }
+    out
+}
+
+/// Read a debug-quoted string up to its closing quote; the value and what follows it.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                '0' => value.push('\0'),
+                'u' => {
+                    let (open, _) = chars.next()?;
+                    let close = s[open..].find('}')? + open;
+                    value.push(char::from_u32(u32::from_str_radix(&s[open + 1..close], 16).ok()?)?);
+                    while chars.next().is_some_and(|(j, _)| j < close) {}
+                }
+                other => value.push(other),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Log files written to a terminal carry colour codes around the level
+fn strip_ansi(line: &str) -> std::borrow::Cow<'_, str> {
+    if !line.contains('\x1b') {
+        return line.into();
+    }
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out.into()
+}
+
+/// Where and how fast to replay
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// `http://host:port` requests are sent to; logged paths are appended
+    pub target: Uri,
+    /// Host header for every request instead of the logged one
+    pub host: Option<String>,
+    /// Pace multiplier: 2.0 replays twice as fast as logged, 0 sends without waiting
+    pub speed: f64,
+    /// Requests in flight at most
+    pub concurrency: usize,
+    /// Also replay methods other than GET, HEAD and OPTIONS (sent with an empty body)
+    pub all_methods: bool,
+}
+
+/// Outcome of a replay
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub load: LoadReport,
+    /// Entries not sent because of their method
+    pub skipped: u64,
+    /// Responses with the logged status
+    pub matched: u64,
+    /// Responses that differed, by `(logged, received)` status
+    pub mismatched: BTreeMap<(u16, u16), u64>,
+}
+
+impl ReplayReport {
+    pub fn mismatches(&self) -> u64 {
+        self.mismatched.values().sum()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.load)?;
+        writeln!(f, "Skipped:      {}", self.skipped)?;
+        writeln!(f, "Matched:      {}", self.matched)?;
+        let mismatched: Vec<String> = self.mismatched.iter().map(|((l, r), n)| format!("{}->{}={}", l, r, n)).collect();
+        write!(f, "Mismatched:   {} {}", self.mismatches(), mismatched.join(" "))
+    }
+}
+
+/// Send `entries` to the target in log order, spaced as they were logged.
+pub async fn run(config: ReplayConfig, entries: Vec<Entry>) -> Result<ReplayReport> {
+    if config.target.scheme_str() != Some("http") && config.target.scheme_str() != Some("https") {
+        return Err(anyhow!("target must be an http:// or https:// URL"));
+    }
+    let authority = config.target.authority().context("target has no host")?;
+    let base = format!("{}://{}{}", config.target.scheme_str().unwrap_or("http"), authority, config.target.path().trim_end_matches('/'));
+    // Keep redirects as they are so their status can be compared
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(30))
+        .build()?;
+
+    let mut report = ReplayReport::default();
+    let first = entries.iter().find_map(|e| e.time);
+    let limit = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let Ok(method) = entry.method.parse::<Method>() else {
+            report.skipped += 1;
+            continue;
+        };
+        if !config.all_methods && !matches!(method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            report.skipped += 1;
+            continue;
+        }
+        if let (Some(first), Some(at)) = (first, entry.time) {
+            if config.speed > 0.0 {
+                let offset = (at - first).to_std().unwrap_or_default().div_f64(config.speed);
+                tokio::time::sleep_until((started + offset).into()).await;
+            }
+        }
+        let permit = limit.clone().acquire_owned().await?;
+        let host = config.host.clone().unwrap_or(entry.host);
+        let req = client
+            .request(reqwest::Method::from_bytes(method.as_str().as_bytes())?, format!("{}{}", base, entry.path))
+            .header(reqwest::header::HOST, host);
+        tasks.push(tokio::spawn(async move {
+            let sent = Instant::now();
+            let outcome = match req.send().await {
+                Ok(resp) => {
+                    let status = resp.status().as_u16();
+                    resp.bytes().await.map(|_| status).ok()
+                }
+                Err(_) => None,
+            };
+            drop(permit);
+            (entry.status, outcome, sent.elapsed())
+        }));
+    }
+
+    for task in tasks {
+        let (logged, outcome, latency) = task.await?;
+        let Some(status) = outcome else {
+            report.load.errors += 1;
+            continue;
+        };
+        report.load.latencies_us.push(latency.as_micros() as u64);
+        *report.load.statuses.entry(status).or_default() += 1;
+        if status == logged {
+            report.matched += 1;
+        } else {
+            *report.mismatched.entry((logged, status)).or_default() += 1;
+        }
+    }
+    report.load.elapsed = started.elapsed();
+    report.load.latencies_us.sort_unstable();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let entry = parse_line(
+            "2026-10-16T12:00:01.250000Z  INFO ThreadId(02) access method=GET host=a.com path=\"/my file \\\"x\\\"\" status=404 duration_ms=3 client=1.2.3.4 mapping=m1 experiment=- api_key=-",
+        ).unwrap();
+        assert_eq!(entry.method, "GET");
+        assert_eq!(entry.host, "a.com");
+        assert_eq!(entry.path, "/my file \"x\"");
+        assert_eq!(entry.status, 404);
+        assert_eq!(entry.time.unwrap().timestamp_millis() % 1000, 250);
+
+        let bare = parse_line("access method=POST host=b.com path=/api status=201 duration_ms=1 sample_rate=0.1").unwrap();
+        assert_eq!((bare.time, bare.path.as_str(), bare.status), (None, "/api", 201));
+
+        let coloured = parse_line("\x1b[2m2026-10-16T12:00:00Z\x1b[0m \x1b[32m INFO\x1b[0m access method=GET host=c.com path=/\\u{e9} status=200");
+        assert_eq!(coloured.unwrap().host, "c.com");
+        assert_eq!(parse_line("access method=GET host=a.com path=\"/caf\\u{e9}\" status=200").unwrap().path, "/café");
+
+        assert!(parse_line("2026-10-16T12:00:00Z  INFO Starting RustProxy").is_none());
+        assert!(parse_line("access method=GET host=a.com status=200").is_none());
+        assert_eq!(parse("access method=GET host=a path=/ status=200\nnoise\n").len(), 1);
+    }
+}
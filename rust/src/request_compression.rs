@@ -0,0 +1,108 @@
+//! Request body compression
+//! Mappings with `compress_requests` send request bodies of at least that many bytes to the
+//! backend gzip-compressed, saving bandwidth to remote backends. A backend that answers
+//! 415 without gzip in its `Accept-Encoding` (RFC 7694) gets the body again as received,
+//! and plain bodies for a while after
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use flate2::write::GzEncoder;
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{HeaderMap, StatusCode};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+pub const ENCODING: &str = "gzip";
+
+/// How long a backend that refused a compressed body is sent plain ones
+const REFUSAL_TTL: Duration = Duration::from_secs(3600);
+
+/// Media types that are compressed already and don't shrink further
+fn precompressed(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    essence.starts_with("image/") && essence != "image/svg+xml"
+        || essence.starts_with("video/")
+        || essence.starts_with("audio/")
+        || matches!(
+            essence.as_str(),
+            "application/gzip" | "application/zip" | "application/zstd" | "application/x-bzip2" | "application/x-xz"
+                | "application/x-7z-compressed" | "application/pdf"
+        )
+}
+
+/// `body` gzip-compressed when it is at least `min_bytes`, not encoded or compressed
+/// already, and gets smaller.
+pub fn compress(headers: &HeaderMap, body: &[u8], min_bytes: u64) -> Option<Bytes> {
+    if (body.len() as u64) < min_bytes.max(1) || headers.contains_key(CONTENT_ENCODING) {
+        return None;
+    }
+    if headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(precompressed) {
+        return None;
+    }
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), flate2::Compression::fast());
+    encoder.write_all(body).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < body.len()).then(|| Bytes::from(compressed))
+}
+
+/// Whether a response to a compressed request says the backend can't decode it.
+pub fn refused(status: StatusCode, headers: &HeaderMap) -> bool {
+    status == StatusCode::UNSUPPORTED_MEDIA_TYPE
+        && !headers.get_all(ACCEPT_ENCODING).iter().filter_map(|v| v.to_str().ok()).any(|v| {
+            v.split(',').any(|coding| coding.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case(ENCODING))
+        })
+}
+
+/// Mappings whose backend refused compressed bodies, and when
+#[derive(Default)]
+pub struct Refusals(DashMap<String, Instant>);
+
+impl Refusals {
+    pub fn active(&self, mapping_id: &str) -> bool {
+        self.0.get(mapping_id).is_some_and(|at| at.elapsed() < REFUSAL_TTL)
+    }
+
+    pub fn record(&self, mapping_id: &str) {
+        self.0.insert(mapping_id.to_string(), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use hyper::header::HeaderValue;
+    use std::io::Read;
+
+    #[test]
+    fn test_compress() {
+        let body = "{\"items\": [1, 2, 3]}".repeat(100);
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let gz = compress(&headers, body.as_bytes(), 1024).unwrap();
+        let mut plain = String::new();
+        GzDecoder::new(&gz[..]).read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, body);
+
+        assert!(compress(&headers, body.as_bytes(), 1 << 20).is_none());
+        assert!(compress(&headers, b"x", 0).is_none(), "grows");
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert!(compress(&headers, body.as_bytes(), 1).is_none());
+        headers.remove(CONTENT_TYPE);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(compress(&headers, body.as_bytes(), 1).is_none());
+    }
+
+    #[test]
+    fn test_refused() {
+        let mut headers = HeaderMap::new();
+        assert!(refused(StatusCode::UNSUPPORTED_MEDIA_TYPE, &headers));
+        assert!(!refused(StatusCode::BAD_REQUEST, &headers));
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity, GZIP;q=0.5"));
+        assert!(!refused(StatusCode::UNSUPPORTED_MEDIA_TYPE, &headers), "the media type was the problem");
+
+        let refusals = Refusals::default();
+        refusals.record("m1");
+        assert!(refusals.active("m1") && !refusals.active("m2"));
+    }
+}
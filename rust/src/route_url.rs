@@ -0,0 +1,114 @@
+//! Mappings from URLs
+//! `rustproxy-mapping add --from-url https://app.example.com/api=http://10.0.0.5:3000/v1`
+//! names a route the way people think of it — this public URL goes to that backend URL —
+//! instead of as a domain, port and `--frontend`/`--backend`/`--server` flags. The public
+//! side gives the domain (a `*.` wildcard included) and `front_uri`; the backend side the
+//! server, port (the scheme's default if left out) and `back_uri`. The public scheme doesn't
+//! matter, as the proxy serves both
+
+use std::str::FromStr;
+
+/// A route spelled `<public URL>=<backend URL>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteUrl {
+    pub domain: String,
+    pub front_uri: String,
+    /// `https://10.0.0.5`; unset for `http://localhost`, the default
+    pub backend: Option<String>,
+    pub back_port: u16,
+    pub back_uri: String,
+}
+
+impl FromStr for RouteUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (public, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <public URL>=<backend URL>, e.g. https://app.example.com/api=http://10.0.0.5:3000/v1, got '{}'", s))?;
+        let (domain, front_uri) = parse_public(public.trim())?;
+        let (backend, back_port, back_uri) = parse_backend(target.trim())?;
+        Ok(Self { domain, front_uri, backend, back_port, back_uri })
+    }
+}
+
+/// Domain and `front_uri` of `https://app.example.com/api`. Taken apart by hand rather than
+/// as a URL, which has no room for a `*` host.
+fn parse_public(url: &str) -> Result<(String, String), String> {
+    let rest = match url.split_once("://") {
+        Some(("http" | "https", rest)) => rest,
+        Some((scheme, _)) => return Err(format!("public URL '{}': the proxy serves http and https, not {}", url, scheme)),
+        None => url,
+    };
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if host.contains(['@', ':', '?', '#']) {
+        return Err(format!("public URL '{}': the proxy routes by domain and path only (no user, port, query or fragment)", url));
+    }
+    if path.contains(['?', '#']) {
+        return Err(format!("public URL '{}': a route is a path prefix (no query or fragment)", url));
+    }
+    let domain = crate::host::normalize_domain(host).ok_or_else(|| format!("public URL '{}': invalid domain '{}'", url, host))?;
+    Ok((domain.into_owned(), path.trim_matches('/').to_string()))
+}
+
+/// Server, port and `back_uri` of `http://10.0.0.5:3000/v1`.
+fn parse_backend(url: &str) -> Result<(Option<String>, u16, String), String> {
+    let parsed: url::Url = match url.contains("://") {
+        true => url.parse(),
+        false => format!("http://{}", url).parse(),
+    }
+    .map_err(|e| format!("backend URL '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("backend URL '{}': use --server for {} backends", url, parsed.scheme()));
+    }
+    if !parsed.username().is_empty() || parsed.password().is_some() || parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(format!("backend URL '{}': only a server, port and path (no user, query or fragment)", url));
+    }
+    let host = parsed.host_str().filter(|h| !h.is_empty()).ok_or_else(|| format!("backend URL '{}' has no host", url))?;
+    let port = parsed.port_or_known_default().ok_or_else(|| format!("backend URL '{}' has no port", url))?;
+    let backend = match (parsed.scheme(), host) {
+        ("http", "localhost") => None,
+        (scheme, host) => Some(format!("{}://{}", scheme, host)),
+    };
+    Ok((backend, port, parsed.path().trim_matches('/').to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_urls() {
+        let route: RouteUrl = "https://App.Example.com/api=http://10.0.0.5:3000/v1".parse().unwrap();
+        assert_eq!(route, RouteUrl {
+            domain: "app.example.com".into(),
+            front_uri: "api".into(),
+            backend: Some("http://10.0.0.5".into()),
+            back_port: 3000,
+            back_uri: "v1".into(),
+        });
+
+        // Whole domain to a local port; defaults filled in
+        let route: RouteUrl = "example.com=localhost:8080".parse().unwrap();
+        assert_eq!((route.front_uri.as_str(), route.backend, route.back_port, route.back_uri.as_str()), ("", None, 8080, ""));
+        let route: RouteUrl = "http://*.example.com/docs/=https://docs.internal".parse().unwrap();
+        assert_eq!((route.domain.as_str(), route.front_uri.as_str()), ("*.example.com", "docs"));
+        assert_eq!((route.backend.as_deref(), route.back_port), (Some("https://docs.internal"), 443));
+    }
+
+    #[test]
+    fn test_invalid_route_urls() {
+        for (spec, problem) in [
+            ("https://app.example.com/api", "expected <public URL>=<backend URL>"),
+            ("https://app.example.com:8443/=http://localhost:3000", "no user, port"),
+            ("https://app.example.com/a?x=1=http://localhost:3000", "no query"),
+            ("ftp://app.example.com=http://localhost:3000", "not ftp"),
+            ("https://./=http://localhost:3000", "invalid domain"),
+            ("app.example.com=fcgi://127.0.0.1:9000", "use --server for fcgi"),
+            ("app.example.com=http://localhost:3000/?debug=1", "no user, query"),
+        ] {
+            let err = spec.parse::<RouteUrl>().unwrap_err();
+            assert!(err.contains(problem), "{}: {}", spec, err);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Static sites from S3-compatible buckets
+//! An `s3://<endpoint>` backend (`s3+http://` for a plain-HTTP MinIO and the like) serves a
+//! static site straight from object storage, under the mapping's own domain and
+//! certificate. Paths map to object keys the usual way — `back_uri` is the bucket (for
+//! path-style endpoints) and key prefix — and end in `index.html` where they end in `/`;
+//! `/docs` answers a redirect to `/docs/` when only `docs/index.html` exists. Only `GET` and
+//! `HEAD` are served, query strings and client credentials never reach the bucket, and
+//! missing objects are a plain 404 rather than S3's XML. Private buckets are read with the
+//! mapping's `aws4` upstream signing (see [`crate::upstream_signing`])
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use std::borrow::Cow;
+
+pub const SCHEME: &str = "s3://";
+/// Without TLS, for local object stores
+pub const PLAIN_SCHEME: &str = "s3+http://";
+/// Object served for paths ending in `/`
+pub const INDEX_DOCUMENT: &str = "index.html";
+
+/// Client headers passed to the bucket: conditional and range requests
+const REQUEST_HEADERS: [&str; 6] = ["range", "if-match", "if-none-match", "if-modified-since", "if-unmodified-since", "accept-encoding"];
+
+/// Response headers kept from the bucket
+const RESPONSE_HEADERS: [&str; 10] = [
+    "content-type", "content-length", "content-range", "content-encoding", "content-language",
+    "content-disposition", "cache-control", "etag", "last-modified", "accept-ranges",
+];
+
+/// Whether `backend` is a bucket, and whether it is reached over TLS.
+pub fn endpoint(backend: &str) -> Option<(&str, bool)> {
+    if let Some(host) = backend.strip_prefix(SCHEME) {
+        return Some((host.trim_end_matches('/'), true));
+    }
+    backend.strip_prefix(PLAIN_SCHEME).map(|host| (host.trim_end_matches('/'), false))
+}
+
+/// The object a request path names.
+pub fn object_path(path: &str) -> Cow<'_, str> {
+    match path.ends_with('/') {
+        true => Cow::Owned(format!("{}{}", path, INDEX_DOCUMENT)),
+        false => Cow::Borrowed(path),
+    }
+}
+
+/// Paths that may name a "directory" whose index to redirect to: no trailing slash and
+/// no extension in the last segment.
+pub fn may_be_directory(path: &str) -> bool {
+    let last = path.rsplit('/').next().unwrap_or("");
+    !last.is_empty() && !last.contains('.')
+}
+
+fn copy(from: &HeaderMap, names: &[&str]) -> HeaderMap {
+    let mut to = HeaderMap::new();
+    for name in names {
+        for value in from.get_all(*name) {
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                to.append(name, value.clone());
+            }
+        }
+    }
+    to
+}
+
+/// The client headers the bucket gets.
+pub fn request_headers(client: &HeaderMap) -> HeaderMap {
+    copy(client, &REQUEST_HEADERS)
+}
+
+/// The bucket's response headers the client gets (no `x-amz-*` request IDs and such).
+pub fn response_headers(bucket: &HeaderMap) -> HeaderMap {
+    copy(bucket, &RESPONSE_HEADERS)
+}
+
+/// `Host` for the bucket endpoint: the port only where it isn't the scheme's default.
+pub fn host_header(host: &str, port: u16, tls: bool) -> Option<HeaderValue> {
+    let authority = match (tls, port) {
+        (true, 443) | (false, 80) => host.to_string(),
+        _ => format!("{}:{}", host, port),
+    };
+    HeaderValue::from_str(&authority).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_and_headers() {
+        assert_eq!(endpoint("s3://site.s3.eu-west-1.amazonaws.com/"), Some(("site.s3.eu-west-1.amazonaws.com", true)));
+        assert_eq!(endpoint("s3+http://minio.internal"), Some(("minio.internal", false)));
+        assert_eq!(endpoint("https://minio.internal"), None);
+
+        assert_eq!(object_path("/site/docs/"), "/site/docs/index.html");
+        assert_eq!(object_path("/site/app.js"), "/site/app.js");
+        assert!(may_be_directory("/site/docs") && !may_be_directory("/site/app.js") && !may_be_directory("/site/"));
+
+        let mut client = HeaderMap::new();
+        client.insert("range", HeaderValue::from_static("bytes=0-9"));
+        client.insert("authorization", HeaderValue::from_static("Bearer abc"));
+        client.insert("cookie", HeaderValue::from_static("session=1"));
+        let sent = request_headers(&client);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent["range"], "bytes=0-9");
+
+        let mut bucket = HeaderMap::new();
+        bucket.insert("etag", HeaderValue::from_static("\"abc\""));
+        bucket.insert("x-amz-request-id", HeaderValue::from_static("4442587FB7D0A2F9"));
+        bucket.insert("server", HeaderValue::from_static("AmazonS3"));
+        assert_eq!(response_headers(&bucket).keys().map(|k| k.as_str()).collect::<Vec<_>>(), ["etag"]);
+
+        assert_eq!(host_header("b.s3.amazonaws.com", 443, true).unwrap(), "b.s3.amazonaws.com");
+        assert_eq!(host_header("minio", 9000, false).unwrap(), "minio:9000");
+    }
+}
@@ -0,0 +1,131 @@
+//! Response header scrubbing
+//! Backends announce themselves in response headers — `Server: Apache/2.4.41 (Ubuntu)`,
+//! `X-Powered-By: PHP/7.4.3`, `X-AspNet-Version` — which tells an attacker which
+//! vulnerabilities to try. A scrubbing profile removes such headers before responses
+//! reach the client: `basic` the usual version banners, `strict` also framework, cache
+//! and debug headers naming internals. Set for all mappings with
+//! `SCRUB_RESPONSE_HEADERS`, and per mapping (`off` exempts one)
+
+use hyper::HeaderMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Headers `basic` removes
+const BASIC: &[&str] = &["server", "x-powered-by", "x-aspnet-version", "x-aspnetmvc-version"];
+
+/// Headers `strict` removes on top of `basic`
+const STRICT: &[&str] = &[
+    "x-generator",
+    "x-runtime",
+    "x-version",
+    "x-backend-server",
+    "x-served-by",
+    "x-upstream",
+    "x-turbo-charged-by",
+    "x-mod-pagespeed",
+    "x-page-speed",
+    "x-varnish",
+    "x-litespeed-cache",
+    "x-envoy-upstream-service-time",
+    "liferay-portal",
+];
+
+/// Header prefixes `strict` removes
+const STRICT_PREFIXES: &[&str] = &["x-drupal-", "x-debug-", "x-aspnet-", "x-sourcefiles"];
+
+/// Which fingerprinting headers are removed from responses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScrubProfile {
+    /// Responses pass as the backend sent them
+    #[default]
+    Off,
+    /// Version banners: `Server`, `X-Powered-By`, `X-AspNet-Version`, `X-AspNetMvc-Version`
+    Basic,
+    /// `basic` plus framework, CDN/cache and debug headers
+    Strict,
+}
+
+impl FromStr for ScrubProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(Self::Off),
+            "basic" => Ok(Self::Basic),
+            "strict" => Ok(Self::Strict),
+            other => Err(format!("unknown scrubbing profile '{}' (off, basic, strict)", other)),
+        }
+    }
+}
+
+impl fmt::Display for ScrubProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Basic => "basic",
+            Self::Strict => "strict",
+        })
+    }
+}
+
+impl ScrubProfile {
+    /// The mapping's own profile if it has a valid one, else the global one.
+    pub fn effective(mapping: Option<&str>, global: ScrubProfile) -> ScrubProfile {
+        mapping.and_then(|p| p.parse().ok()).unwrap_or(global)
+    }
+
+    /// Remove the profile's headers from `headers`.
+    pub fn apply(self, headers: &mut HeaderMap) {
+        if self == Self::Off {
+            return;
+        }
+        for name in BASIC {
+            headers.remove(*name);
+        }
+        if self == Self::Strict {
+            for name in STRICT {
+                headers.remove(*name);
+            }
+            let prefixed: Vec<_> = headers.keys()
+                .filter(|n| STRICT_PREFIXES.iter().any(|p| n.as_str().starts_with(p)))
+                .cloned()
+                .collect();
+            for name in prefixed {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub_profiles() {
+        let response = || {
+            let mut headers = HeaderMap::new();
+            for (name, value) in [
+                ("server", "Apache/2.4.41"), ("x-powered-by", "PHP/7.4"), ("x-generator", "Drupal 9"),
+                ("x-drupal-cache", "HIT"), ("content-type", "text/html"), ("x-request-id", "1"),
+            ] {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+        let kept = |profile: ScrubProfile| {
+            let mut headers = response();
+            profile.apply(&mut headers);
+            let mut names: Vec<String> = headers.keys().map(|n| n.to_string()).collect();
+            names.sort();
+            names.join(",")
+        };
+        assert_eq!(kept(ScrubProfile::Off), "content-type,server,x-drupal-cache,x-generator,x-powered-by,x-request-id");
+        assert_eq!(kept(ScrubProfile::Basic), "content-type,x-drupal-cache,x-generator,x-request-id");
+        assert_eq!(kept(ScrubProfile::Strict), "content-type,x-request-id");
+
+        assert_eq!(ScrubProfile::effective(Some("off"), ScrubProfile::Strict), ScrubProfile::Off);
+        assert_eq!(ScrubProfile::effective(None, ScrubProfile::Basic), ScrubProfile::Basic);
+        assert!("paranoid".parse::<ScrubProfile>().is_err());
+    }
+}
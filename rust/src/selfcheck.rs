@@ -0,0 +1,210 @@
+//! Startup self-check
+//! Validates the configuration before traffic is served — ports bindable, database schema
+//! readable, certificate files parse, mapping backends resolvable — and reports the result
+//! as JSON (`--check-config` prints it and exits)
+
+use crate::database::DatabaseManager;
+use crate::discovery::DiscoverySource;
+use crate::egress::{self, EgressProxy, Kind};
+use crate::proxy::ProxyConfig;
+use crate::tls::CertStore;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// How long one backend name may take to resolve
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Not checked here (e.g. a backend resolved at request time)
+    Skip,
+    Fail,
+}
+
+/// One check: what was looked at and how it went
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub check: &'static str,
+    pub subject: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks.iter().filter(|c| c.status == Status::Fail)
+    }
+}
+
+fn check(check: &'static str, subject: impl Into<String>, result: Result<Option<String>, String>) -> Check {
+    let (status, detail) = match result {
+        Ok(detail) => (Status::Ok, detail),
+        Err(e) => (Status::Fail, Some(e)),
+    };
+    Check { check, subject: subject.into(), status, detail }
+}
+
+/// Run every check. `bind_ports` is off when the listeners are already bound (embedding).
+pub async fn run(config: &ProxyConfig, db: &DatabaseManager, tls: &CertStore, bind_ports: bool) -> Report {
+    let mut checks = Vec::new();
+
+    if bind_ports {
+        let mut ports = vec![("http", config.http_port)];
+        if config.enable_https {
+            ports.push(("https", config.https_port));
+        }
+        for (name, port) in ports {
+            let addr = format!("{}:{}", config.http_host, port);
+            let result = tokio::net::TcpListener::bind(&addr).await.map(|_| None).map_err(|e| e.to_string());
+            checks.push(check("port", format!("{} {}", name, addr), result));
+        }
+    }
+
+    let mappings = db.list_mappings(None);
+    checks.push(check(
+        "database",
+        "mappings",
+        mappings.as_ref().map(|m| Some(format!("{} mapping(s)", m.len()))).map_err(|e| format!("{:#}", e)),
+    ));
+
+    if config.enable_https {
+        match tls.validate() {
+            Ok(certs) if certs.is_empty() => checks.push(check("certificate", "certs_dir", Err("no certificates found".to_string()))),
+            Ok(certs) => checks.extend(certs.into_iter().map(|(name, result)| check("certificate", name, result.map(|_| None)))),
+            Err(e) => checks.push(check("certificate", "certs_dir", Err(format!("{:#}", e)))),
+        }
+        if let Some(path) = &config.tls_client_ca {
+            let roots = crate::tls::client_roots(path).map(|r| Some(format!("{} CA certificate(s)", r.len())));
+            checks.push(check("certificate", "tls_client_ca", roots.map_err(|e| format!("{:#}", e))));
+        }
+    }
+
+    let mut hosts = BTreeSet::new();
+    for m in mappings.iter().flatten() {
+        // HTTP CONNECT and socks5h proxies resolve backend names themselves
+        let egress = match m.egress_proxy.as_deref() {
+            Some(egress::DIRECT) => None,
+            Some(url) => EgressProxy::parse(url).ok(),
+            None => config.egress_proxy.clone(),
+        };
+        let remote_dns = egress.is_some_and(|e| e.kind != Kind::Socks5 { remote_dns: false });
+        let targets = [
+            (m.backend.as_deref(), Some(m.back_port)),
+            (m.green_backend.as_deref().or(m.backend.as_deref()), m.green_port),
+        ];
+        for (backend, port) in targets {
+            let Some(port) = port else { continue };
+            match backend {
+                Some(b) if b.contains('$') || DiscoverySource::parse(b).is_some() => {
+                    checks.push(Check {
+                        check: "backend",
+                        subject: b.to_string(),
+                        status: Status::Skip,
+                        detail: Some("resolved per request".to_string()),
+                    });
+                }
+                Some(b) if b.starts_with("fcgi:///") => {
+                    let socket = &b["fcgi://".len()..];
+                    let result = match std::path::Path::new(socket).exists() {
+                        true => Ok(None),
+                        false => Err("socket not found".to_string()),
+                    };
+                    checks.push(check("backend", b, result));
+                }
+                _ if remote_dns => {
+                    checks.push(Check {
+                        check: "backend",
+                        subject: format!("{}:{}", backend.unwrap_or("localhost"), port),
+                        status: Status::Skip,
+                        detail: Some("resolved by the egress proxy".to_string()),
+                    });
+                }
+                Some(b) => match url::Url::parse(b).ok().and_then(|u| u.host_str().map(str::to_string)) {
+                    Some(host) => { hosts.insert((host, port)); }
+                    None => checks.push(check("backend", b, Err("not a valid URL".to_string()))),
+                },
+                None => { hosts.insert(("localhost".to_string(), port)); }
+            }
+        }
+    }
+    for (host, port) in hosts {
+        let subject = format!("{}:{}", host, port);
+        let lookup = tokio::time::timeout(RESOLVE_TIMEOUT, tokio::net::lookup_host(subject.clone())).await;
+        let result = match lookup {
+            Ok(Ok(mut addrs)) => match addrs.next() {
+                Some(addr) => Ok(Some(addr.ip().to_string())),
+                None => Err("no addresses".to_string()),
+            },
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("lookup timed out".to_string()),
+        };
+        checks.push(check("backend", subject, result));
+    }
+
+    let ok = checks.iter().all(|c| c.status != Status::Fail);
+    Report { ok, checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate::CertificateManager;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_report_flags_bad_backend_and_certificate() {
+        let dir = tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+        db.add_mapping("ok.com", "", 3000, "", None, None, None, None, None).unwrap();
+        db.add_mapping("typo.com", "", 80, "", Some("http://no-such-host.invalid"), None, None, None, None).unwrap();
+        db.add_mapping("sd.com", "", 0, "", Some("consul://web"), None, None, None, None).unwrap();
+
+        let certs = dir.path().join("certs");
+        CertificateManager::new(&certs, None).unwrap();
+        std::fs::write(certs.join("broken.crt"), "junk").unwrap();
+        std::fs::write(certs.join("broken.key"), "junk").unwrap();
+
+        let config = ProxyConfig { enable_https: true, ..ProxyConfig::default() };
+        let report = run(&config, &db, &CertStore::new(&certs), false).await;
+        assert!(!report.ok);
+
+        let status = |check: &str, subject: &str| {
+            report.checks.iter().find(|c| c.check == check && c.subject == subject).map(|c| c.status)
+        };
+        assert_eq!(status("database", "mappings"), Some(Status::Ok));
+        assert_eq!(status("certificate", "localhost"), Some(Status::Ok));
+        assert_eq!(status("certificate", "broken"), Some(Status::Fail));
+        assert_eq!(status("backend", "localhost:3000"), Some(Status::Ok));
+        assert_eq!(status("backend", "no-such-host.invalid:80"), Some(Status::Fail));
+        assert_eq!(status("backend", "consul://web"), Some(Status::Skip));
+
+        let failed: Vec<_> = report.failures().map(|c| c.subject.as_str()).collect();
+        assert_eq!(failed, ["broken", "no-such-host.invalid:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_port_in_use_fails() {
+        let dir = tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            http_host: "127.0.0.1".to_string(),
+            http_port: taken.local_addr().unwrap().port(),
+            ..ProxyConfig::default()
+        };
+        let report = run(&config, &db, &CertStore::new(dir.path()), true).await;
+        assert!(!report.ok);
+        assert_eq!(report.checks[0].check, "port");
+        assert_eq!(report.checks[0].status, Status::Fail);
+    }
+}
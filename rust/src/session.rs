@@ -0,0 +1,155 @@
+//! Login sessions
+//! Where signed-in users' sessions live: in the (encrypted) cookie itself, or server-side
+//! in memory, the SQLite database or Redis with only a random ID in the cookie. Server-side
+//! sessions can be revoked at logout; the database and Redis keep them across restarts,
+//! Redis also across nodes that don't share a database
+
+use crate::database::DatabaseManager;
+use crate::redis::{RedisClient, Value};
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Redis keys: `{KEY_PREFIX}{hashed id}`
+const KEY_PREFIX: &str = "rustproxy:session:";
+
+/// In-memory sessions are swept for expired ones every this many writes
+const SWEEP_EVERY: usize = 1024;
+
+/// Configured kind of store (`SESSION_STORE`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Everything in the encrypted cookie; nothing kept on the server
+    #[default]
+    Cookie,
+    Memory,
+    Sqlite,
+    Redis,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "cookie" => Ok(Self::Cookie),
+            "memory" => Ok(Self::Memory),
+            "sqlite" | "database" => Ok(Self::Sqlite),
+            "redis" => Ok(Self::Redis),
+            other => Err(format!("unknown session store '{}' (cookie, memory, sqlite, redis)", other)),
+        }
+    }
+}
+
+/// Where session data is kept
+#[derive(Clone)]
+pub enum SessionStore {
+    Cookie,
+    Memory(Arc<DashMap<String, (String, Instant)>>),
+    Database(Arc<DatabaseManager>),
+    Redis(Arc<RedisClient>),
+}
+
+impl SessionStore {
+    /// True when the session data travels in the cookie rather than an ID.
+    pub fn in_cookie(&self) -> bool {
+        matches!(self, SessionStore::Cookie)
+    }
+
+    /// Keep `data` under `id` for `ttl`.
+    pub async fn put(&self, id: &str, data: &str, ttl: Duration) -> Result<()> {
+        let key = hashed(id);
+        match self {
+            SessionStore::Cookie => Err(anyhow!("cookie sessions are not stored")),
+            SessionStore::Memory(map) => {
+                if map.len() % SWEEP_EVERY == SWEEP_EVERY - 1 {
+                    map.retain(|_, (_, expires)| *expires > Instant::now());
+                }
+                map.insert(key, (data.to_string(), Instant::now() + ttl));
+                Ok(())
+            }
+            SessionStore::Database(db) => {
+                let (db, data) = (db.clone(), data.to_string());
+                tokio::task::spawn_blocking(move || db.put_session(&key, &data, ttl)).await?
+            }
+            SessionStore::Redis(redis) => {
+                let key = format!("{}{}", KEY_PREFIX, key);
+                let ttl = ttl.as_millis().max(1).to_string();
+                redis.command(&[b"SET", key.as_bytes(), data.as_bytes(), b"PX", ttl.as_bytes()]).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// The data kept under `id`, unless it expired or was removed.
+    pub async fn get(&self, id: &str) -> Result<Option<String>> {
+        let key = hashed(id);
+        match self {
+            SessionStore::Cookie => Ok(None),
+            SessionStore::Memory(map) => Ok(map.get(&key)
+                .filter(|entry| entry.1 > Instant::now())
+                .map(|entry| entry.0.clone())),
+            SessionStore::Database(db) => {
+                let db = db.clone();
+                tokio::task::spawn_blocking(move || db.get_session(&key)).await?
+            }
+            SessionStore::Redis(redis) => {
+                match redis.command(&[b"GET", format!("{}{}", KEY_PREFIX, key).as_bytes()]).await? {
+                    Value::Bulk(data) => Ok(Some(String::from_utf8_lossy(&data).into_owned())),
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let key = hashed(id);
+        match self {
+            SessionStore::Cookie => {}
+            SessionStore::Memory(map) => {
+                map.remove(&key);
+            }
+            SessionStore::Database(db) => {
+                let db = db.clone();
+                tokio::task::spawn_blocking(move || db.delete_session(&key)).await??;
+            }
+            SessionStore::Redis(redis) => {
+                redis.command(&[b"DEL", format!("{}{}", KEY_PREFIX, key).as_bytes()]).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stores only see hashes of the IDs in cookies, so a leaked table holds no usable IDs.
+fn hashed(id: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, id.as_bytes());
+    base64::Engine::encode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_server_side_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+        for store in [SessionStore::Memory(Arc::default()), SessionStore::Database(db)] {
+            store.put("sid-1", r#"{"sub":"a"}"#, Duration::from_secs(60)).await.unwrap();
+            store.put("sid-2", "short", Duration::from_millis(1)).await.unwrap();
+            assert_eq!(store.get("sid-1").await.unwrap().as_deref(), Some(r#"{"sub":"a"}"#));
+            assert_eq!(store.get("unknown").await.unwrap(), None);
+
+            tokio::time::sleep(Duration::from_millis(1100)).await;
+            assert_eq!(store.get("sid-2").await.unwrap(), None);
+
+            store.delete("sid-1").await.unwrap();
+            assert_eq!(store.get("sid-1").await.unwrap(), None);
+        }
+        assert_eq!("SQLite".parse::<Backend>(), Ok(Backend::Sqlite));
+        assert!("files".parse::<Backend>().is_err());
+    }
+}
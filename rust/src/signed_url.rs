@@ -0,0 +1,123 @@
+//! Signed URLs
+//! Mappings with a `url_signing_secret` only serve links carrying an `expires` Unix time
+//! and a `signature`: the hex HMAC-SHA256 of `<path>?<query up to and including expires>`.
+//! Both parameters are removed before the request is passed on, so backends serve
+//! time-limited download links without knowing about them
+
+use crate::hex::hex;
+use ring::hmac;
+
+pub const EXPIRES_PARAM: &str = "expires";
+pub const SIGNATURE_PARAM: &str = "signature";
+
+/// Why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// No (or a repeated) `expires`/`signature` parameter
+    Missing,
+    Expired,
+    Invalid,
+}
+
+impl Rejection {
+    pub fn message(self) -> &'static str {
+        match self {
+            Rejection::Missing => "Signed URL required",
+            Rejection::Expired => "Link expired",
+            Rejection::Invalid => "Invalid signature",
+        }
+    }
+}
+
+/// The mapping's secret with `${ENV_VAR}` references resolved.
+pub fn key(configured: &str) -> String {
+    let vars = crate::template::RequestVars { host: "", path: "", remote_addr: "" };
+    crate::template::expand(configured, &vars).into_owned()
+}
+
+/// `path` and `query` with `expires` and a signature for them appended.
+pub fn sign(secret: &str, path: &str, query: Option<&str>, expires: u64) -> String {
+    let signed = match query.filter(|q| !q.is_empty()) {
+        Some(q) => format!("{}?{}&{}={}", path, q, EXPIRES_PARAM, expires),
+        None => format!("{}?{}={}", path, EXPIRES_PARAM, expires),
+    };
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), signed.as_bytes());
+    format!("{}&{}={}", signed, SIGNATURE_PARAM, hex(tag.as_ref()))
+}
+
+/// Check the signature and expiry of a request at Unix time `now`; on success, the query
+/// to forward (the signing parameters removed).
+pub fn verify(secret: &str, path: &str, query: Option<&str>, now: u64) -> Result<Option<String>, Rejection> {
+    let pairs: Vec<&str> = query.unwrap_or("").split('&').filter(|p| !p.is_empty()).collect();
+    let value = |name: &str| {
+        let mut found = pairs.iter().filter_map(|p| p.strip_prefix(name)?.strip_prefix('='));
+        match (found.next(), found.next()) {
+            (Some(v), None) => Some(v),
+            _ => None,
+        }
+    };
+    let (Some(expires), Some(signature)) = (value(EXPIRES_PARAM), value(SIGNATURE_PARAM)) else {
+        return Err(Rejection::Missing);
+    };
+    let signature = unhex(signature).ok_or(Rejection::Invalid)?;
+
+    // Everything the signer saw: the parameters before `signature`
+    let signature_at = pairs.iter().position(|p| p.starts_with(&format!("{}=", SIGNATURE_PARAM))).unwrap_or(pairs.len());
+    let signed = format!("{}?{}", path, pairs[..signature_at].join("&"));
+    hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), signed.as_bytes(), &signature)
+        .map_err(|_| Rejection::Invalid)?;
+    // Checked after the signature, so an unsigned `expires` can't be probed
+    if expires.parse::<u64>().map_err(|_| Rejection::Invalid)? <= now {
+        return Err(Rejection::Expired);
+    }
+
+    let rest: Vec<&str> = pairs.iter()
+        .take(signature_at)
+        .filter(|p| !p.starts_with(&format!("{}=", EXPIRES_PARAM)))
+        .copied()
+        .collect();
+    Ok((!rest.is_empty()).then(|| rest.join("&")))
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(url: &str) -> (&str, Option<&str>) {
+        match url.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (url, None),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let url = sign("k1", "/dl/report.pdf", Some("v=2"), 1_000);
+        assert!(url.starts_with("/dl/report.pdf?v=2&expires=1000&signature="));
+        let (path, query) = split(&url);
+        assert_eq!(verify("k1", path, query, 999), Ok(Some("v=2".to_string())));
+        assert_eq!(verify("k1", path, query, 1_000), Err(Rejection::Expired));
+        assert_eq!(verify("k2", path, query, 999), Err(Rejection::Invalid));
+        assert_eq!(verify("k1", "/dl/other.pdf", query, 999), Err(Rejection::Invalid));
+
+        // Extending the expiry or adding parameters breaks the signature
+        let longer = url.replace("expires=1000", "expires=9000");
+        assert_eq!(verify("k1", path, split(&longer).1, 999), Err(Rejection::Invalid));
+        let (_, q) = split(&url);
+        let extra = format!("x=1&{}", q.unwrap());
+        assert_eq!(verify("k1", path, Some(&extra), 999), Err(Rejection::Invalid));
+
+        let bare = sign("k1", "/a", None, 50);
+        let (path, query) = split(&bare);
+        assert_eq!(verify("k1", path, query, 1), Ok(None));
+        assert_eq!(verify("k1", "/a", Some("expires=50"), 1), Err(Rejection::Missing));
+        assert_eq!(verify("k1", "/a", None, 1), Err(Rejection::Missing));
+    }
+}
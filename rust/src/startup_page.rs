@@ -0,0 +1,146 @@
+//! Startup pages
+//! Backends that are slow to answer while they start — serverless cold starts, apps
+//! warming caches — can have browsers shown a "starting up" page instead of waiting into a
+//! 504. With a mapping's `startup_page` (e.g. `after=5,refresh=3,max_wait=120`), a page
+//! navigation (`GET` accepting `text/html`) not answered within `after` seconds gets a 503
+//! page that reloads itself every `refresh` seconds. Once the mapping has been starting
+//! for `max_wait` seconds without answering, the proxy gives up and answers 504 again.
+//! `page=` names an HTML file to serve instead of the built-in page
+
+use dashmap::DashMap;
+use hyper::header::ACCEPT;
+use hyper::{HeaderMap, Method};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The built-in page; `{refresh}` is replaced by the refresh interval
+pub const DEFAULT_PAGE: &str = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+<meta http-equiv=\"refresh\" content=\"{refresh}\"><title>Starting up</title></head>\
+<body style=\"font-family:sans-serif;text-align:center;margin-top:15%\"><h1>Starting up&hellip;</h1>\
+<p>This service is waking up. The page reloads in {refresh} seconds.</p></body></html>\n";
+
+/// When and how a mapping shows its startup page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartupPage {
+    /// Wait for the backend before showing the page
+    pub after: Duration,
+    /// Reload interval of the page
+    pub refresh: Duration,
+    /// How long a backend may keep starting before requests get a 504 again
+    pub max_wait: Duration,
+    /// HTML file served instead of [`DEFAULT_PAGE`]
+    pub page: Option<PathBuf>,
+}
+
+impl Default for StartupPage {
+    fn default() -> Self {
+        Self {
+            after: Duration::from_secs(5),
+            refresh: Duration::from_secs(5),
+            max_wait: Duration::from_secs(120),
+            page: None,
+        }
+    }
+}
+
+impl FromStr for StartupPage {
+    type Err = String;
+
+    /// `on` for the defaults, or any of `after=5,refresh=5,max_wait=120,page=/path/starting.html`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut startup = Self::default();
+        let items: Vec<&str> = s.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        if items.is_empty() {
+            return Err("startup page needs 'on' or settings like after=5,refresh=5,max_wait=120".to_string());
+        }
+        for item in items {
+            if item.eq_ignore_ascii_case("on") {
+                continue;
+            }
+            let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", item))?;
+            let secs = || value.trim().parse::<u64>().ok().filter(|s| *s > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("'{}' needs a number of seconds, got '{}'", name.trim(), value));
+            match name.trim() {
+                "after" => startup.after = secs()?,
+                "refresh" => startup.refresh = secs()?,
+                "max_wait" => startup.max_wait = secs()?,
+                "page" if !value.trim().is_empty() => startup.page = Some(PathBuf::from(value.trim())),
+                other => return Err(format!("unknown startup page setting '{}' (after, refresh, max_wait, page)", other)),
+            }
+        }
+        Ok(startup)
+    }
+}
+
+impl fmt::Display for StartupPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "after={},refresh={},max_wait={}", self.after.as_secs(), self.refresh.as_secs(), self.max_wait.as_secs())?;
+        if let Some(page) = &self.page {
+            write!(f, ",page={}", page.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a request is a page navigation, which a startup page makes sense for.
+pub fn applies(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::GET
+        && headers.get_all(ACCEPT).iter().any(|v| v.to_str().is_ok_and(|a| a.contains("text/html")))
+}
+
+/// Since when each mapping's backend has been starting (its first startup page)
+#[derive(Default)]
+pub struct Starting(DashMap<String, Instant>);
+
+impl Starting {
+    /// The backend of `mapping_id` did not answer in time: whether to show the page
+    /// (`true`) or give up, when it has been starting for `max_wait`. Giving up starts
+    /// the wait over for later requests.
+    pub fn show_page(&self, mapping_id: &str, max_wait: Duration) -> bool {
+        let since = *self.0.entry(mapping_id.to_string()).or_insert_with(Instant::now);
+        if since.elapsed() >= max_wait {
+            self.0.remove(mapping_id);
+            return false;
+        }
+        true
+    }
+
+    /// The backend of `mapping_id` answered: it has started.
+    pub fn answered(&self, mapping_id: &str) {
+        self.0.remove(mapping_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_startup_page() {
+        let startup: StartupPage = "after=2, max_wait=60".parse().unwrap();
+        assert_eq!(startup.to_string(), "after=2,refresh=5,max_wait=60");
+        assert_eq!("on".parse::<StartupPage>().unwrap(), StartupPage::default());
+        let custom: StartupPage = "refresh=3,page=/etc/rustproxy/starting.html".parse().unwrap();
+        assert_eq!(custom.to_string().parse::<StartupPage>().unwrap(), custom);
+        assert!("after=0".parse::<StartupPage>().is_err());
+        assert!("after=soon".parse::<StartupPage>().is_err());
+        assert!("timeout=5".parse::<StartupPage>().is_err());
+        assert!("".parse::<StartupPage>().is_err());
+
+        let mut headers = HeaderMap::new();
+        assert!(!applies(&Method::GET, &headers));
+        headers.insert(ACCEPT, HeaderValue::from_static("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(applies(&Method::GET, &headers) && !applies(&Method::POST, &headers));
+
+        let starting = Starting::default();
+        assert!(starting.show_page("m1", Duration::from_secs(60)));
+        assert!(starting.show_page("m1", Duration::from_secs(60)));
+        assert!(!starting.show_page("m1", Duration::ZERO));
+        starting.answered("m1");
+        assert!(starting.show_page("m1", Duration::from_secs(60)));
+    }
+}
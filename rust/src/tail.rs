@@ -0,0 +1,216 @@
+//! Traffic tail
+//! `rustproxy tail` follows a running instance's traffic from the admin event stream
+//! (`events/stream?requests=1`) and prints each finished request matching its filters as
+//! it happens: time, method, host and path, status, latency, and the backend that
+//! answered. Requests are only described while someone follows them, and one that falls
+//! too far behind is told how many it missed
+
+use crate::events::Request;
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use std::fmt;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+
+/// A status to show: one code (`404`) or a class (`5xx`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Code(u16),
+    Class(u16),
+}
+
+/// Statuses to show, e.g. `5xx` or `4xx,503`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusFilter(Vec<Status>);
+
+impl FromStr for StatusFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let statuses = s.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|item| {
+            let lower = item.to_ascii_lowercase();
+            let status = match lower.strip_suffix("xx") {
+                Some(class) => class.parse().ok().filter(|c| (1..=5).contains(c)).map(Status::Class),
+                None => lower.parse().ok().filter(|c| (100..=599).contains(c)).map(Status::Code),
+            };
+            status.ok_or_else(|| format!("expected a status like 404 or a class like 5xx, got '{}'", item))
+        });
+        let statuses = statuses.collect::<Result<Vec<_>, _>>()?;
+        if statuses.is_empty() {
+            return Err("no statuses given".to_string());
+        }
+        Ok(Self(statuses))
+    }
+}
+
+impl fmt::Display for StatusFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = self.0.iter().map(|s| match s {
+            Status::Code(code) => code.to_string(),
+            Status::Class(class) => format!("{}xx", class),
+        }).collect();
+        f.write_str(&items.join(","))
+    }
+}
+
+impl StatusFilter {
+    pub fn matches(&self, status: u16) -> bool {
+        self.0.iter().any(|s| match s {
+            Status::Code(code) => *code == status,
+            Status::Class(class) => status / 100 == *class,
+        })
+    }
+}
+
+/// Which requests to show; `None` shows all
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Host, without port
+    pub domain: Option<String>,
+    pub status: Option<StatusFilter>,
+}
+
+impl Filter {
+    pub fn matches(&self, request: &Request) -> bool {
+        self.domain.as_deref().is_none_or(|d| d.trim_end_matches('.').eq_ignore_ascii_case(&request.domain))
+            && self.status.as_ref().is_none_or(|s| s.matches(request.status))
+    }
+}
+
+/// `12:04:31.207  GET     shop.example.com/cart  502  1532ms  -> 10.0.0.5:3000  [mapping]`,
+/// in local time
+pub fn line(request: &Request) -> String {
+    let time = chrono::DateTime::parse_from_rfc3339(&request.time)
+        .map(|t| t.with_timezone(&chrono::Local).format("%H:%M:%S%.3f").to_string())
+        .unwrap_or_else(|_| request.time.clone());
+    let mut line = format!(
+        "{}  {:<7} {}{}  {}  {}ms  -> {}",
+        time,
+        request.method,
+        request.domain,
+        request.path,
+        request.status,
+        request.duration_ms,
+        request.backend.as_deref().unwrap_or("-"),
+    );
+    if let Some(id) = &request.mapping_id {
+        line.push_str(&format!("  [{}]", id));
+    }
+    line
+}
+
+/// What a message of the event stream says, as far as the tail cares
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    Request(Box<Request>),
+    /// `: missed 12 requests`
+    Missed(u64),
+    Other,
+}
+
+fn parse_message(message: &str) -> Message {
+    let (mut event, mut data) = ("message", String::new());
+    for line in message.lines() {
+        if let Some(n) = line.strip_prefix(": missed ").and_then(|l| l.strip_suffix(" requests")) {
+            return n.parse().map_or(Message::Other, Message::Missed);
+        } else if let Some(name) = line.strip_prefix("event:") {
+            event = name.trim();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    match event {
+        "request" => serde_json::from_str(&data).map_or(Message::Other, |r| Message::Request(Box::new(r))),
+        _ => Message::Other,
+    }
+}
+
+/// Follow the traffic of the instance whose admin API is at `base`, handing a line for
+/// each request `filter` lets through to `print`, until the stream ends.
+pub async fn follow(base: &Uri, token: &str, filter: &Filter, mut print: impl FnMut(String)) -> Result<()> {
+    if base.scheme_str().is_some_and(|s| s != "http") {
+        bail!("only http:// admin URLs are supported");
+    }
+    let host = base.host().ok_or_else(|| anyhow!("{} has no host", base))?;
+    let addr = format!("{}:{}", host, base.port_u16().unwrap_or(80));
+    let stream = TcpStream::connect(&addr).await.with_context(|| format!("connect {}", addr))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+    let path = format!("{}{}events/stream?requests=1", base.path().trim_end_matches('/'), crate::admin::PREFIX);
+    let req = hyper::Request::get(path)
+        .header(hyper::header::HOST, base.authority().map(|a| a.as_str()).unwrap_or(host))
+        .header(hyper::header::AUTHORIZATION, format!("Bearer {}", token))
+        .header(hyper::header::ACCEPT, "text/event-stream")
+        .body(Empty::<Bytes>::new())?;
+    let resp = sender.send_request(req).await?;
+    let status = resp.status();
+    let mut body = resp.into_body();
+    if !status.is_success() {
+        let text = body.collect().await?.to_bytes();
+        bail!("{} answered {}: {}", base, status, String::from_utf8_lossy(&text).trim());
+    }
+
+    let mut pending = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let Ok(data) = frame?.into_data() else { continue };
+        pending.extend_from_slice(&data);
+        while let Some(end) = pending.windows(2).position(|w| w == b"\n\n") {
+            let message: Vec<u8> = pending.drain(..end + 2).collect();
+            match parse_message(&String::from_utf8_lossy(&message)) {
+                Message::Request(request) if filter.matches(&request) => print(line(&request)),
+                Message::Missed(n) => print(format!("... {} requests missed (not keeping up)", n)),
+                _ => {}
+            }
+        }
+    }
+    bail!("{} closed the event stream", base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(domain: &str, status: u16) -> Request {
+        Request {
+            time: "2024-05-01T10:00:00.250+00:00".into(),
+            domain: domain.into(),
+            method: "GET".into(),
+            path: "/cart".into(),
+            status,
+            duration_ms: 1532,
+            backend: Some("10.0.0.5:3000".into()),
+            mapping_id: Some("m1".into()),
+            client: "1.2.3.4".into(),
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let statuses: StatusFilter = "5XX, 404".parse().unwrap();
+        assert_eq!(statuses.to_string(), "5xx,404");
+        assert!(statuses.matches(502) && statuses.matches(404) && !statuses.matches(403));
+        assert!("6xx".parse::<StatusFilter>().is_err() && "abc".parse::<StatusFilter>().is_err() && "".parse::<StatusFilter>().is_err());
+
+        let filter = Filter { domain: Some("Shop.example.com".into()), status: Some(statuses) };
+        assert!(filter.matches(&request("shop.example.com", 503)));
+        assert!(!filter.matches(&request("shop.example.com", 200)));
+        assert!(!filter.matches(&request("www.example.com", 503)));
+        assert!(Filter::default().matches(&request("www.example.com", 200)));
+    }
+
+    #[test]
+    fn test_messages() {
+        let shown = line(&request("shop.example.com", 502));
+        assert!(shown.ends_with("GET     shop.example.com/cart  502  1532ms  -> 10.0.0.5:3000  [m1]"), "{}", shown);
+
+        let data = serde_json::to_string(&request("a.com", 200)).unwrap();
+        let message = format!("event: request\ndata: {}\n\n", data);
+        assert_eq!(parse_message(&message), Message::Request(Box::new(request("a.com", 200))));
+        assert_eq!(parse_message(": missed 12 requests\n\n"), Message::Missed(12));
+        assert_eq!(parse_message("id: 3\nevent: backend_down\ndata: {}\n\n"), Message::Other);
+        assert_eq!(parse_message(": keepalive\n\n"), Message::Other);
+    }
+}
@@ -0,0 +1,203 @@
+//! TCP socket options
+//! `CLIENT_TCP_OPTIONS` tunes the listeners and the client connections they accept,
+//! `BACKEND_TCP_OPTIONS` the connections to backends (and to the egress proxy). Both are
+//! specs like `nodelay,keepalive=60,keepalive_interval=10,keepalive_retries=5,recv_buffer=4M`;
+//! anything not named keeps the operating system's default
+
+use crate::happy_eyeballs::{self, IpPreference};
+use socket2::{SockRef, Socket, TcpKeepalive};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// TCP_NODELAY: small writes go out at once instead of waiting for Nagle's algorithm
+    pub nodelay: bool,
+    /// SO_KEEPALIVE, probing after this long idle
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes
+    pub keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes before the connection is dropped
+    pub keepalive_retries: Option<u32>,
+    /// SO_RCVBUF in bytes
+    pub recv_buffer: Option<usize>,
+    /// SO_SNDBUF in bytes
+    pub send_buffer: Option<usize>,
+}
+
+impl TcpOptions {
+    /// Set the buffer sizes of a socket. On a listener, accepted connections inherit them,
+    /// and the receive window is scaled to them.
+    pub fn apply_buffers(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(n) = self.recv_buffer {
+            socket.set_recv_buffer_size(n)?;
+        }
+        if let Some(n) = self.send_buffer {
+            socket.set_send_buffer_size(n)?;
+        }
+        Ok(())
+    }
+
+    /// Set the options on an established connection.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        self.apply_buffers(&socket)?;
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            #[cfg(not(windows))]
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Connect to `host:port`, racing the addresses it resolves to (see [`happy_eyeballs`]).
+    /// Buffer sizes are set before the handshake so the window scale matches them.
+    pub async fn connect(&self, addr: &str, preference: IpPreference, attempt_delay: Duration) -> io::Result<TcpStream> {
+        let addrs = happy_eyeballs::order(lookup_host(addr).await?, preference);
+        happy_eyeballs::race(addrs, attempt_delay, |addr| self.connect_addr(addr)).await
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+        self.apply_buffers(&SockRef::from(&socket))?;
+        let stream = socket.connect(addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+}
+
+/// Whole number with an optional K, M or G (binary) suffix
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1 << 10),
+        (i, 'm' | 'M') => (&s[..i], 1 << 20),
+        (i, 'g' | 'G') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit).filter(|n| *n > 0)
+}
+
+impl FromStr for TcpOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = match item.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (item, None),
+            };
+            let needs = |what: &str| value.ok_or_else(|| format!("'{}' needs {}", name, what));
+            let secs = |v: &str| v.parse::<u64>().ok().filter(|n| *n > 0).map(Duration::from_secs)
+                .ok_or_else(|| format!("'{}' needs a number of seconds, got '{}'", name, v));
+            let size = |v: &str| parse_size(v).ok_or_else(|| format!("'{}' needs a size in bytes (e.g. 256K), got '{}'", name, v));
+            match name {
+                "nodelay" => options.nodelay = match value {
+                    None | Some("1" | "on" | "true") => true,
+                    Some("0" | "off" | "false") => false,
+                    Some(other) => return Err(format!("'nodelay' is on or off, got '{}'", other)),
+                },
+                "keepalive" => options.keepalive = Some(secs(needs("seconds")?)?),
+                "keepalive_interval" => options.keepalive_interval = Some(secs(needs("seconds")?)?),
+                "keepalive_retries" => {
+                    let v = needs("a count")?;
+                    options.keepalive_retries = Some(v.parse().ok().filter(|n| *n > 0)
+                        .ok_or_else(|| format!("'keepalive_retries' needs a count, got '{}'", v))?);
+                }
+                "recv_buffer" => options.recv_buffer = Some(size(needs("a size")?)?),
+                "send_buffer" => options.send_buffer = Some(size(needs("a size")?)?),
+                other => return Err(format!(
+                    "unknown TCP option '{}' (nodelay, keepalive, keepalive_interval, keepalive_retries, recv_buffer, send_buffer)", other)),
+            }
+        }
+        if options.keepalive.is_none() && (options.keepalive_interval.is_some() || options.keepalive_retries.is_some()) {
+            return Err("keepalive_interval and keepalive_retries need keepalive".to_string());
+        }
+        Ok(options)
+    }
+}
+
+impl fmt::Display for TcpOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items = Vec::new();
+        if self.nodelay {
+            items.push("nodelay".to_string());
+        }
+        if let Some(d) = self.keepalive {
+            items.push(format!("keepalive={}", d.as_secs()));
+        }
+        if let Some(d) = self.keepalive_interval {
+            items.push(format!("keepalive_interval={}", d.as_secs()));
+        }
+        if let Some(n) = self.keepalive_retries {
+            items.push(format!("keepalive_retries={}", n));
+        }
+        if let Some(n) = self.recv_buffer {
+            items.push(format!("recv_buffer={}", n));
+        }
+        if let Some(n) = self.send_buffer {
+            items.push(format!("send_buffer={}", n));
+        }
+        f.write_str(&items.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let options: TcpOptions = "nodelay, keepalive=60,keepalive_interval=10,keepalive_retries=5,recv_buffer=4M,send_buffer=262144"
+            .parse().unwrap();
+        assert!(options.nodelay);
+        assert_eq!(options.keepalive, Some(Duration::from_secs(60)));
+        assert_eq!(options.recv_buffer, Some(4 << 20));
+        assert_eq!(options.to_string(),
+            "nodelay,keepalive=60,keepalive_interval=10,keepalive_retries=5,recv_buffer=4194304,send_buffer=262144");
+        assert_eq!(options.to_string().parse::<TcpOptions>().unwrap(), options);
+        assert_eq!("".parse::<TcpOptions>().unwrap(), TcpOptions::default());
+        assert!(!"nodelay=off".parse::<TcpOptions>().unwrap().nodelay);
+
+        assert!("keepalive_interval=10".parse::<TcpOptions>().is_err());
+        assert!("keepalive=0".parse::<TcpOptions>().is_err());
+        assert!("recv_buffer=big".parse::<TcpOptions>().is_err());
+        assert!("keepalive".parse::<TcpOptions>().is_err());
+        assert!("cork".parse::<TcpOptions>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_on_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let options: TcpOptions = "nodelay,keepalive=30,keepalive_interval=5,keepalive_retries=3,send_buffer=64K".parse().unwrap();
+        let stream = options.connect(&addr, IpPreference::Ipv6, Duration::from_millis(250)).await.unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+            assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        }
+        // The kernel may round the size up (Linux doubles it)
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert!(!SockRef::from(&accepted).nodelay().unwrap());
+    }
+}
@@ -0,0 +1,143 @@
+//! Template expansion for mapping fields
+//! Expands `${ENV_VAR}` and the request variables `$host`, `$path`, `$remote_addr` in
+//! `backend` and `back_uri` at request time
+
+use crate::database::Mapping;
+use std::borrow::Cow;
+
+/// Per-request values available to mapping templates
+pub struct RequestVars<'a> {
+    pub host: &'a str,
+    pub path: &'a str,
+    pub remote_addr: &'a str,
+}
+
+impl RequestVars<'_> {
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        match name {
+            // Host comes from the client; keep it to hostname characters so it can't smuggle
+            // a different authority, path or userinfo into the backend URL.
+            "host" => Some(Cow::Owned(
+                self.host.chars().filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')).collect(),
+            )),
+            "path" => Some(Cow::Borrowed(self.path.trim_start_matches('/'))),
+            // The connecting address, or the one given to a routing dry run: only an IP
+            // address goes in, anything else expands to nothing
+            "remote_addr" => Some(match self.remote_addr.parse::<std::net::IpAddr>() {
+                Ok(_) => Cow::Borrowed(self.remote_addr),
+                Err(_) => Cow::Borrowed(""),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Expand templates in `input`.
+///
+/// - `$host`, `$path`, `$remote_addr` (or `${host}` etc.) — request variables; `$path` has
+///   its leading slash removed so it composes with `back_uri`
+/// - `${NAME}` — environment variable `NAME`, empty when unset
+/// - `$$` — a literal `$`
+///
+/// Any other `$` sequence is left untouched.
+pub fn expand<'a>(input: &'a str, vars: &RequestVars) -> Cow<'a, str> {
+    if !input.contains('$') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        if let Some(tail) = after.strip_prefix('$') {
+            out.push('$');
+            rest = tail;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => {
+                    let name = &braced[..end];
+                    match vars.get(name) {
+                        Some(v) => out.push_str(&v),
+                        None => out.push_str(&std::env::var(name).unwrap_or_default()),
+                    }
+                    rest = &braced[end + 1..];
+                }
+                None => {
+                    out.push('$');
+                    rest = after;
+                }
+            }
+        } else {
+            let len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+            match vars.get(&after[..len]) {
+                Some(v) => out.push_str(&v),
+                None => out.push_str(&rest[pos..pos + 1 + len]),
+            }
+            rest = &after[len..];
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// Expand templates in the routing fields of `mapping` in place.
+pub fn expand_mapping(mapping: &mut Mapping, vars: &RequestVars) {
+    if let Some(backend) = &mapping.backend {
+        if let Cow::Owned(v) = expand(backend, vars) {
+            mapping.backend = Some(v);
+        }
+    }
+    if let Cow::Owned(v) = expand(&mapping.back_uri, vars) {
+        mapping.back_uri = v.trim_start_matches('/').trim_end_matches('/').to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: RequestVars<'static> = RequestVars {
+        host: "shop.example.com",
+        path: "/api/items",
+        remote_addr: "10.0.0.9",
+    };
+
+    #[test]
+    fn test_expand_request_vars() {
+        assert_eq!(expand("http://$host.internal", &VARS), "http://shop.example.com.internal");
+        assert_eq!(expand("tenants/${host}/$path", &VARS), "tenants/shop.example.com/api/items");
+        assert_eq!(expand("$remote_addr", &VARS), "10.0.0.9");
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("RUSTPROXY_TEMPLATE_TEST_HOST", "staging.internal");
+        assert_eq!(expand("http://${RUSTPROXY_TEMPLATE_TEST_HOST}", &VARS), "http://staging.internal");
+        assert_eq!(expand("http://${RUSTPROXY_TEMPLATE_UNSET}x", &VARS), "http://x");
+    }
+
+    #[test]
+    fn test_expand_leaves_unknown_and_escapes() {
+        assert_eq!(expand("price$$5", &VARS), "price$5");
+        assert_eq!(expand("$unknown/${open", &VARS), "$unknown/${open");
+        assert!(matches!(expand("plain", &VARS), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_host_is_sanitized() {
+        let vars = RequestVars { host: "evil.com@127.0.0.1/x", path: "/", remote_addr: "" };
+        assert_eq!(expand("http://$host", &vars), "http://evil.com127.0.0.1x");
+    }
+
+    #[test]
+    fn test_remote_addr_must_be_an_ip() {
+        for forged in ["evil.com/x@", "10.0.0.1@evil.com", "10.0.0.1/admin", "[::1]"] {
+            let vars = RequestVars { host: "", path: "/", remote_addr: forged };
+            assert_eq!(expand("http://$remote_addr:8080", &vars), "http://:8080", "{}", forged);
+        }
+        let vars = RequestVars { host: "", path: "/", remote_addr: "2001:db8::1" };
+        assert_eq!(expand("$remote_addr", &vars), "2001:db8::1");
+    }
+}
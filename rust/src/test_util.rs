@@ -0,0 +1,302 @@
+//! Test support
+//! With the `test-util` feature: a proxy with its database and certificates in a temporary
+//! directory, and stub backends, all on OS-assigned ports of 127.0.0.1. Each is listening
+//! when the function starting it returns — the listener is bound first — so a test never
+//! sleeps waiting for a server. HTTPS uses self-signed certificates that the clients made
+//! here trust, for TLS and WebSocket tests end to end. Failures panic, as in a test
+
+use crate::proxy::{ProxyConfig, ProxyServer, ServerHandle};
+use crate::{CertificateManager, DatabaseManager};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::client::TlsStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::WebSocketStream;
+
+/// How long [`listening`] waits
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait until something accepts connections on `addr`: for the listeners the proxy opens
+/// in the background once running (`internal_listen`, `forward_proxy_listen`).
+pub async fn listening(addr: SocketAddr) {
+    let ready = tokio::time::timeout(READY_TIMEOUT, async {
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    ready.await.unwrap_or_else(|_| panic!("nothing listening on {} after {:?}", addr, READY_TIMEOUT));
+}
+
+/// A stub backend; stopped when dropped
+#[derive(Debug)]
+pub struct Backend {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Backend {
+    /// Answer every request with `handler`.
+    pub async fn start<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Response<Full<Bytes>>> + Send + 'static,
+    {
+        let listener = Self::bind().await;
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let service = service_fn(move |req| {
+                    let response = handler(req);
+                    async move { Ok::<_, Infallible>(response.await) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades());
+            }
+        });
+        Self { addr, task }
+    }
+
+    /// [`crate::echo`]: the request as JSON.
+    pub async fn echo() -> Self {
+        Self::serve_echo(None).await
+    }
+
+    /// [`Backend::echo`] answering with `name` as well, to tell several apart.
+    pub async fn echo_named(name: &str) -> Self {
+        Self::serve_echo(Some(name.to_string())).await
+    }
+
+    async fn serve_echo(name: Option<String>) -> Self {
+        let listener = Self::bind().await;
+        let addr = listener.local_addr().unwrap();
+        Self { addr, task: tokio::spawn(crate::echo::serve(listener, name)) }
+    }
+
+    /// `<tag>|path=<path>|host=<Host>|xff=<X-Forwarded-For>`, to tell backends apart in one line.
+    pub async fn tagged(tag: &'static str) -> Self {
+        Self::start(move |req: Request<Incoming>| async move {
+            let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(String::from);
+            let body = format!(
+                "{}|path={}|host={}|xff={}",
+                tag,
+                req.uri().path(),
+                header("host").unwrap_or_else(|| "unknown".into()),
+                header("x-forwarded-for").unwrap_or_else(|| "none".into()),
+            );
+            Response::new(Full::new(Bytes::from(body)))
+        })
+        .await
+    }
+
+    /// `status` with `body` for every request.
+    pub async fn status(status: u16, body: &'static str) -> Self {
+        let status = StatusCode::from_u16(status).expect("valid status");
+        Self::start(move |_req| async move { Response::builder().status(status).body(Full::new(Bytes::from(body))).unwrap() }).await
+    }
+
+    /// A WebSocket server sending text messages back.
+    pub async fn websocket_echo() -> Self {
+        use futures_util::{SinkExt, StreamExt};
+        let listener = Self::bind().await;
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else { return };
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if msg.is_text() && ws.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Self { addr, task }
+    }
+
+    async fn bind() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0").await.expect("binding a backend port")
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Stop accepting and answering, as a backend going down.
+    pub fn abort(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Sets up a [`TestProxy`]
+pub struct TestProxyBuilder {
+    dir: TempDir,
+    config: ProxyConfig,
+    tls_names: Vec<String>,
+}
+
+impl TestProxyBuilder {
+    /// Start from `config` instead of the defaults; its addresses and ports are replaced.
+    pub fn config(mut self, config: ProxyConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Serve HTTPS too, with a self-signed certificate for `domain`.
+    pub fn https(mut self, domain: &str) -> Self {
+        self.tls_names.push(domain.to_string());
+        self
+    }
+
+    /// The temporary directory the database and certificates go in, for files a test
+    /// configures the proxy with (CA bundles, cache directories).
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Start the proxy; it accepts connections when this returns.
+    pub async fn start(mut self) -> TestProxy {
+        let db = Arc::new(DatabaseManager::new(self.dir.path().join("test.db")).expect("opening the test database"));
+        let certs = Arc::new(CertificateManager::new(self.dir.path().join("certs"), None).expect("creating the certificates directory"));
+        let mut roots = Vec::new();
+        for name in &self.tls_names {
+            certs.generate_self_signed(name, &[name.as_str()]).expect("generating a self-signed certificate");
+            roots.push(std::fs::read(self.dir.path().join("certs").join(format!("{}.crt", name))).unwrap());
+        }
+        self.config.enable_https |= !self.tls_names.is_empty();
+        self.config.http_host = "127.0.0.1".into();
+        self.config.http_port = 0;
+        self.config.https_port = 0;
+        let server = Arc::new(ProxyServer::new(self.config, db.clone(), certs.clone()));
+        let handle = server.clone().run().await.expect("starting the proxy");
+        TestProxy { dir: self.dir, db, certs, server, handle, roots }
+    }
+}
+
+/// A running proxy with a database and certificates of its own; stopped when dropped
+pub struct TestProxy {
+    dir: TempDir,
+    pub db: Arc<DatabaseManager>,
+    pub certs: Arc<CertificateManager>,
+    pub server: Arc<ProxyServer>,
+    handle: ServerHandle,
+    /// PEM of the self-signed certificates, trusted by the clients made here
+    roots: Vec<Vec<u8>>,
+}
+
+impl TestProxy {
+    pub fn builder() -> TestProxyBuilder {
+        TestProxyBuilder {
+            dir: tempfile::tempdir().expect("creating a temporary directory"),
+            config: ProxyConfig::default(),
+            tls_names: Vec::new(),
+        }
+    }
+
+    /// A plain HTTP proxy with the default configuration.
+    pub async fn start() -> Self {
+        Self::builder().start().await
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn http_addr(&self) -> SocketAddr {
+        self.handle.http_addr()
+    }
+
+    pub fn https_addr(&self) -> SocketAddr {
+        self.handle.https_addr().expect("HTTPS not enabled; see TestProxyBuilder::https")
+    }
+
+    /// Route `domain` + `front_uri` to a backend on `port` of localhost.
+    pub fn map(&self, domain: &str, front_uri: &str, port: u16, back_uri: &str) {
+        self.db.add_mapping(domain, front_uri, port, back_uri, None, None, None, None, None).expect("adding a mapping");
+    }
+
+    /// `http://<host>:<port><path>`, for a [`TestProxy::client`] of `host`.
+    pub fn http_url(&self, host: &str, path: &str) -> String {
+        format!("http://{}:{}{}", host, self.http_addr().port(), path)
+    }
+
+    /// `https://<host>:<port><path>`, for a [`TestProxy::client`] of `host`.
+    pub fn https_url(&self, host: &str, path: &str) -> String {
+        format!("https://{}:{}{}", host, self.https_addr().port(), path)
+    }
+
+    /// A client sending requests for `host` to this proxy and trusting its certificates.
+    pub fn client(&self, host: &str) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder().resolve(host, self.http_addr());
+        for pem in &self.roots {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem).expect("certificate PEM"));
+        }
+        builder.build().expect("building the client")
+    }
+
+    /// TLS settings trusting this proxy's certificates, for raw TLS clients.
+    pub fn tls_config(&self) -> rustls::ClientConfig {
+        let mut roots = rustls::RootCertStore::empty();
+        for pem in &self.roots {
+            for cert in rustls_pemfile::certs(&mut &pem[..]) {
+                roots.add(cert.expect("certificate PEM")).expect("trusting the certificate");
+            }
+        }
+        rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()
+    }
+
+    /// A TLS connection to the HTTPS listener, with `host` as SNI.
+    pub async fn connect_tls(&self, host: &str) -> TlsStream<TcpStream> {
+        let tcp = TcpStream::connect(self.https_addr()).await.expect("connecting to the HTTPS listener");
+        let name = rustls::pki_types::ServerName::try_from(host.to_string()).expect("valid server name");
+        tokio_rustls::TlsConnector::from(Arc::new(self.tls_config())).connect(name, tcp).await.expect("TLS handshake")
+    }
+
+    /// A WebSocket session to `path` of `host` over HTTP.
+    pub async fn websocket(&self, host: &str, path: &str) -> WebSocketStream<TcpStream> {
+        let stream = TcpStream::connect(self.http_addr()).await.expect("connecting to the HTTP listener");
+        let req = websocket_request(&format!("ws://{}{}", host, path));
+        tokio_tungstenite::client_async(req, stream).await.expect("WebSocket handshake").0
+    }
+
+    /// A WebSocket session to `path` of `host` over HTTPS.
+    pub async fn websocket_tls(&self, host: &str, path: &str) -> WebSocketStream<TlsStream<TcpStream>> {
+        let stream = self.connect_tls(host).await;
+        let req = websocket_request(&format!("wss://{}{}", host, path));
+        tokio_tungstenite::client_async(req, stream).await.expect("WebSocket handshake").0
+    }
+}
+
+fn websocket_request(url: &str) -> tokio_tungstenite::tungstenite::handshake::client::Request {
+    url.into_client_request().expect("valid WebSocket URL")
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+    }
+}
@@ -0,0 +1,338 @@
+//! TLS certificate store
+//! Serves the certificates in `certs_dir` by SNI and picks up new or renewed ones (e.g.
+//! written by certbot) without a restart
+
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use serde::Serialize;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// Certificate served when no name matches the SNI (or the client sent none)
+const DEFAULT_NAME: &str = "localhost";
+
+/// Cert and key files of one name, in either supported layout:
+/// `<name>.crt` + `<name>.key`, or certbot's `<name>/fullchain.pem` + `<name>/privkey.pem`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CertFiles {
+    name: String,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    /// Modification times of (cert, key) when loaded
+    modified: (SystemTime, SystemTime),
+}
+
+/// What a rescan did to one certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Loaded(String),
+    Reloaded(String),
+    /// Name and error; the previous certificate (if any) stays in use
+    Failed(String, String),
+}
+
+/// A loaded certificate, for the admin snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct CertInfo {
+    pub name: String,
+    /// RFC 3339 end of the leaf certificate's validity
+    pub not_after: Option<String>,
+    /// Certificates in the served chain, leaf included
+    pub chain: usize,
+}
+
+/// Certificates by host name (`*.example.com` for wildcards), loaded from `certs_dir`
+pub struct CertStore {
+    certs_dir: PathBuf,
+    certs: DashMap<String, Loaded>,
+}
+
+impl std::fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertStore")
+            .field("certs_dir", &self.certs_dir)
+            .field("names", &self.names())
+            .finish()
+    }
+}
+
+impl CertStore {
+    pub fn new<P: AsRef<Path>>(certs_dir: P) -> Self {
+        Self {
+            certs_dir: certs_dir.as_ref().to_path_buf(),
+            certs: DashMap::new(),
+        }
+    }
+
+    /// Loaded host names, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.certs.iter().map(|e| e.key().clone()).collect();
+        names.sort();
+        names
+    }
+
+    /// Loaded certificates, sorted by name.
+    pub fn inventory(&self) -> Vec<CertInfo> {
+        let mut list: Vec<CertInfo> = self.certs.iter().map(|e| CertInfo {
+            name: e.key().clone(),
+            not_after: e.key.cert.first().and_then(|c| crate::der::not_after(c)),
+            chain: e.key.cert.len(),
+        }).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Rescan `certs_dir`: load new certificates, reload those whose cert or key file
+    /// changed and drop those whose files are gone. Returns how many were (re)loaded.
+    ///
+    /// A pair that fails to parse (e.g. caught halfway through a renewal) keeps serving
+    /// the previously loaded certificate and is retried on the next scan.
+    pub fn reload(&self) -> Result<usize> {
+        let changes = self.reload_changes()?;
+        Ok(changes.iter().filter(|c| !matches!(c, Change::Failed(..))).count())
+    }
+
+    /// [`reload`](Self::reload), reporting what happened to each certificate it touched.
+    pub fn reload_changes(&self) -> Result<Vec<Change>> {
+        let files = self.scan()?;
+        let mut changes = Vec::new();
+        for f in &files {
+            let modified = match (mtime(&f.cert), mtime(&f.key)) {
+                (Some(c), Some(k)) => (c, k),
+                _ => continue,
+            };
+            if self.certs.get(&f.name).is_some_and(|l| l.modified == modified) {
+                continue;
+            }
+            match load_pair(&f.cert, &f.key) {
+                Ok(key) => {
+                    let renewed = self.certs.insert(f.name.clone(), Loaded { key, modified }).is_some();
+                    info!("{} certificate for {}", if renewed { "Reloaded" } else { "Loaded" }, f.name);
+                    changes.push(if renewed { Change::Reloaded(f.name.clone()) } else { Change::Loaded(f.name.clone()) });
+                }
+                Err(e) => {
+                    warn!("Could not load certificate for {}: {:#}", f.name, e);
+                    changes.push(Change::Failed(f.name.clone(), format!("{:#}", e)));
+                }
+            }
+        }
+        self.certs.retain(|name, _| {
+            let keep = files.iter().any(|f| &f.name == name);
+            if !keep {
+                info!("Certificate for {} removed", name);
+            }
+            keep
+        });
+        Ok(changes)
+    }
+
+    /// Try to parse every cert/key pair in `certs_dir` without loading it.
+    pub fn validate(&self) -> Result<Vec<(String, Result<(), String>)>> {
+        let mut files = self.scan()?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files
+            .into_iter()
+            .map(|f| {
+                let result = load_pair(&f.cert, &f.key).map(|_| ()).map_err(|e| format!("{:#}", e));
+                (f.name, result)
+            })
+            .collect())
+    }
+
+    /// The certificate for `server_name`: exact match, then a one-label wildcard, then
+    /// the default certificate.
+    pub fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = server_name.map(|n| n.trim_end_matches('.').to_ascii_lowercase()) {
+            if let Some(l) = self.certs.get(&name) {
+                return Some(l.key.clone());
+            }
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(l) = self.certs.get(&format!("*.{}", parent)) {
+                    return Some(l.key.clone());
+                }
+            }
+        }
+        self.certs.get(DEFAULT_NAME).map(|l| l.key.clone())
+    }
+
+    /// A rustls server config resolving certificates from this store. With `client_ca`,
+    /// clients are asked for a certificate, and one they present must be issued by a CA in
+    /// that PEM bundle; clients without one are still served.
+    pub fn server_config(self: &Arc<Self>, client_ca: Option<&Path>) -> Result<Arc<ServerConfig>> {
+        let builder = match client_ca {
+            Some(path) => {
+                let verifier = WebPkiClientVerifier::builder(Arc::new(client_roots(path)?))
+                    .allow_unauthenticated()
+                    .build()
+                    .with_context(|| format!("client CA bundle {}", path.display()))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    fn scan(&self) -> Result<Vec<CertFiles>> {
+        let mut files = Vec::new();
+        let entries = fs::read_dir(&self.certs_dir)
+            .with_context(|| format!("reading {}", self.certs_dir.display()))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            if path.is_dir() {
+                let (cert, key) = (path.join("fullchain.pem"), path.join("privkey.pem"));
+                if cert.exists() && key.exists() {
+                    files.push(CertFiles { name: host_name(file_name), cert, key });
+                }
+            } else if let Some(stem) = file_name.strip_suffix(".crt") {
+                let key = self.certs_dir.join(format!("{}.key", stem));
+                if key.exists() {
+                    files.push(CertFiles { name: host_name(stem), cert: path.clone(), key });
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+/// The CA certificates in a PEM bundle, for verifying client certificates.
+pub fn client_roots(path: &Path) -> Result<RootCertStore> {
+    let file = fs::File::open(path).with_context(|| format!("cannot open client CA bundle {}", path.display()))?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+        roots.add(cert?).with_context(|| format!("bad certificate in {}", path.display()))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("no certificates in client CA bundle {}", path.display()));
+    }
+    Ok(roots)
+}
+
+/// Host name a cert file stem stands for (`wildcard.example.com` → `*.example.com`,
+/// see `CertificateManager::sanitize_domain`).
+fn host_name(stem: &str) -> String {
+    let stem = stem.to_ascii_lowercase();
+    match stem.strip_prefix("wildcard.") {
+        Some(parent) => format!("*.{}", parent),
+        None => stem,
+    }
+}
+
+/// Modification time, following symlinks (certbot's `live/` files are links into `archive/`).
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_pair(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>> {
+    let mut reader = BufReader::new(fs::File::open(cert_path)?);
+    let chain = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(anyhow!("no certificate in {}", cert_path.display()));
+    }
+    let mut reader = BufReader::new(fs::File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("no private key in {}", key_path.display()))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("unsupported private key in {}: {}", key_path.display(), e))?;
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate::CertificateManager;
+    use tempfile::tempdir;
+
+    fn served_der(store: &CertStore, name: &str) -> Vec<u8> {
+        store.lookup(Some(name)).unwrap().end_entity_cert().unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_lookup_exact_wildcard_and_default() {
+        let dir = tempdir().unwrap();
+        let manager = CertificateManager::new(dir.path(), None).unwrap();
+        manager.generate_self_signed("*.example.com", &["*.example.com"]).unwrap();
+        manager.generate_self_signed("api.example.com", &["api.example.com"]).unwrap();
+
+        let store = CertStore::new(dir.path());
+        assert_eq!(store.reload().unwrap(), 3);
+        assert_eq!(store.names(), ["*.example.com", "api.example.com", "localhost"]);
+        let inventory = store.inventory();
+        assert_eq!(inventory[1].name, "api.example.com");
+        assert_eq!(inventory[1].chain, 1);
+        let not_after = inventory[1].not_after.as_deref().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(not_after).is_ok(), "{}", not_after);
+
+        let wildcard = served_der(&store, "*.example.com");
+        assert_eq!(served_der(&store, "www.example.com"), wildcard);
+        assert_ne!(served_der(&store, "API.example.com."), wildcard);
+        assert_eq!(served_der(&store, "other.test"), served_der(&store, "localhost"));
+        assert!(store.lookup(None).is_some());
+    }
+
+    #[test]
+    fn test_reload_picks_up_changed_added_and_removed_files() {
+        let dir = tempdir().unwrap();
+        let manager = CertificateManager::new(dir.path(), None).unwrap();
+        manager.generate_self_signed("site.test", &["site.test"]).unwrap();
+
+        let store = CertStore::new(dir.path());
+        store.reload().unwrap();
+        let before = served_der(&store, "site.test");
+        // Nothing changed: nothing reloaded
+        assert_eq!(store.reload().unwrap(), 0);
+
+        // Renewed in place (bump the mtime in case the clock is coarse)
+        manager.generate_self_signed("site.test", &["site.test"]).unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options().write(true).open(dir.path().join("site.test.crt")).unwrap().set_modified(later).unwrap();
+        assert_eq!(store.reload().unwrap(), 1);
+        assert_ne!(served_der(&store, "site.test"), before);
+
+        // certbot layout
+        let live = dir.path().join("new.test");
+        fs::create_dir(&live).unwrap();
+        fs::copy(dir.path().join("site.test.crt"), live.join("fullchain.pem")).unwrap();
+        fs::copy(dir.path().join("site.test.key"), live.join("privkey.pem")).unwrap();
+        assert_eq!(store.reload().unwrap(), 1);
+        assert!(store.names().contains(&"new.test".to_string()));
+
+        fs::remove_file(dir.path().join("site.test.crt")).unwrap();
+        store.reload().unwrap();
+        assert!(!store.names().contains(&"site.test".to_string()));
+    }
+
+    #[test]
+    fn test_broken_renewal_keeps_serving_old_certificate() {
+        let dir = tempdir().unwrap();
+        CertificateManager::new(dir.path(), None).unwrap();
+        let store = CertStore::new(dir.path());
+        store.reload().unwrap();
+        let before = served_der(&store, "localhost");
+
+        fs::write(dir.path().join("localhost.crt"), "not a certificate").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options().write(true).open(dir.path().join("localhost.crt")).unwrap().set_modified(later).unwrap();
+        assert_eq!(store.reload().unwrap(), 0);
+        assert_eq!(served_der(&store, "localhost"), before);
+    }
+}
@@ -0,0 +1,122 @@
+//! TLS details of client connections
+//! Requests on the HTTPS listener carry what their connection negotiated: protocol
+//! version, cipher suite, SNI name and — when `TLS_CLIENT_CA` has the listener ask for
+//! client certificates — the subject of the verified certificate the client presented.
+//! They are written to the access log, and with `FORWARD_TLS_HEADERS` passed to backends
+//! in `X-TLS-Version`, `X-TLS-Cipher`, `X-TLS-SNI` and `X-Client-Cert-DN`. Copies of these
+//! headers sent by clients are always dropped then, so backends can rely on them for audit
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use rustls::{ProtocolVersion, ServerConnection};
+
+pub const VERSION_HEADER: HeaderName = HeaderName::from_static("x-tls-version");
+pub const CIPHER_HEADER: HeaderName = HeaderName::from_static("x-tls-cipher");
+pub const SNI_HEADER: HeaderName = HeaderName::from_static("x-tls-sni");
+pub const CLIENT_DN_HEADER: HeaderName = HeaderName::from_static("x-client-cert-dn");
+
+/// What one client connection negotiated
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// `TLSv1.2`, `TLSv1.3`
+    pub version: String,
+    /// IANA name of the cipher suite, e.g. `TLS13_AES_128_GCM_SHA256`
+    pub cipher: String,
+    /// Server name the client asked for
+    pub sni: Option<String>,
+    /// RFC 4514 subject of the client certificate, if one was presented and verified
+    pub client_dn: Option<String>,
+    /// DER certificates the client presented, leaf first (see [`crate::xfcc`])
+    pub client_chain: Vec<Vec<u8>>,
+}
+
+impl TlsInfo {
+    /// Details of a connection whose handshake is done.
+    pub fn of(conn: &ServerConnection) -> Self {
+        let version = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(other) => format!("{:?}", other),
+            None => "-".to_string(),
+        };
+        let cipher = conn.negotiated_cipher_suite().map(|s| {
+            let suite = s.suite();
+            suite.as_str().map_or_else(|| format!("{:?}", suite), str::to_string)
+        });
+        Self {
+            version,
+            cipher: cipher.unwrap_or_else(|| "-".to_string()),
+            sni: conn.server_name().map(str::to_string),
+            client_dn: conn.peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|cert| crate::der::subject(cert)),
+            client_chain: conn.peer_certificates()
+                .map(|chain| chain.iter().map(|c| c.to_vec()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Replace the TLS headers in `headers` with those of `info`; for requests not
+    /// made over TLS (`None`) they are only removed.
+    pub fn forward(info: Option<&TlsInfo>, headers: &mut HeaderMap) {
+        for name in [VERSION_HEADER, CIPHER_HEADER, SNI_HEADER, CLIENT_DN_HEADER] {
+            headers.remove(name);
+        }
+        let Some(info) = info else { return };
+        let values = [
+            (VERSION_HEADER, Some(&info.version)),
+            (CIPHER_HEADER, Some(&info.cipher)),
+            (SNI_HEADER, info.sni.as_ref()),
+            (CLIENT_DN_HEADER, info.client_dn.as_ref()),
+        ];
+        for (name, value) in values {
+            if let Some(v) = value.and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok()) {
+                headers.insert(name, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+
+    #[test]
+    fn test_client_cert_subject() {
+        let mut params = CertificateParams::new(vec!["client.test".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CountryName, "NL");
+        params.distinguished_name.push(DnType::OrganizationName, "Example, Inc.");
+        params.distinguished_name.push(DnType::CommonName, " alice ");
+        params.distinguished_name.push(DnType::CustomDnType(vec![2, 5, 4, 97]), "VATNL-123");
+        let der = Certificate::from_params(params).unwrap().serialize_der().unwrap();
+        assert_eq!(
+            crate::der::subject(&der).unwrap(),
+            "2.5.4.97=VATNL-123,CN=\\ alice\\ ,O=Example\\, Inc.,C=NL"
+        );
+    }
+
+    #[test]
+    fn test_forward_replaces_client_copies() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_DN_HEADER, HeaderValue::from_static("CN=admin"));
+        headers.insert(VERSION_HEADER, HeaderValue::from_static("TLSv1.3"));
+        TlsInfo::forward(None, &mut headers);
+        assert!(headers.is_empty());
+
+        headers.insert(CLIENT_DN_HEADER, HeaderValue::from_static("CN=admin"));
+        let info = TlsInfo {
+            version: "TLSv1.3".to_string(),
+            cipher: "TLS13_AES_128_GCM_SHA256".to_string(),
+            sni: Some("api.test".to_string()),
+            client_dn: None,
+            client_chain: Vec::new(),
+        };
+        TlsInfo::forward(Some(&info), &mut headers);
+        assert_eq!(headers[VERSION_HEADER], "TLSv1.3");
+        assert_eq!(headers[CIPHER_HEADER], "TLS13_AES_128_GCM_SHA256");
+        assert_eq!(headers[SNI_HEADER], "api.test");
+        assert!(!headers.contains_key(CLIENT_DN_HEADER));
+    }
+}
@@ -0,0 +1,148 @@
+//! Upstream protocol compatibility
+//! Backends are spoken to in HTTP/1.1 over pooled keep-alive connections, which some old
+//! services (embedded devices, legacy application servers) handle badly. A mapping's
+//! `upstream_protocol` works around them: `close` uses a new connection per request and
+//! says so in `Connection: close`, `no-expect` drops `Expect: 100-continue`, and
+//! `http/1.0` sends requests as HTTP/1.0, which implies both. Backends that only speak
+//! cleartext HTTP/2 (gRPC services without TLS, typically) get `h2c`: HTTP/2 with prior
+//! knowledge, no HTTP/1.1 handshake or upgrade first. WebSocket upgrades need HTTP/1.1 and
+//! are left alone
+
+use hyper::header::{HeaderValue, CONNECTION, EXPECT, HOST};
+use hyper::{Request, Uri, Version};
+use std::fmt;
+use std::str::FromStr;
+
+/// How requests of a mapping are sent to its backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpstreamProtocol {
+    /// Requests go out as HTTP/1.0
+    pub http10: bool,
+    /// No keep-alive: one connection per request
+    pub close: bool,
+    /// `Expect` headers are removed
+    pub no_expect: bool,
+    /// Cleartext HTTP/2 with prior knowledge
+    pub h2c: bool,
+}
+
+impl FromStr for UpstreamProtocol {
+    type Err = String;
+
+    /// `http/1.0`, or any of `h2c,close,no-expect` (`http/1.1` is the default)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut protocol = Self::default();
+        let mut any = false;
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            any = true;
+            match item.to_ascii_lowercase().as_str() {
+                "http/1.0" | "http1.0" | "1.0" => {
+                    protocol = Self { http10: true, close: true, no_expect: true, h2c: false };
+                }
+                "http/1.1" | "http1.1" | "1.1" => {}
+                "h2c" | "http/2" | "http2" => protocol.h2c = true,
+                "close" | "no-keepalive" => protocol.close = true,
+                "no-expect" => protocol.no_expect = true,
+                other => return Err(format!("unknown upstream protocol setting '{}' (http/1.0, http/1.1, h2c, close, no-expect)", other)),
+            }
+        }
+        if !any {
+            return Err("upstream protocol needs http/1.0, http/1.1, h2c, close and/or no-expect".to_string());
+        }
+        if protocol.http10 && protocol.h2c {
+            return Err("http/1.0 and h2c exclude each other".to_string());
+        }
+        Ok(protocol)
+    }
+}
+
+impl fmt::Display for UpstreamProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.http10 {
+            return f.write_str("http/1.0");
+        }
+        let items: Vec<&str> = [("h2c", self.h2c), ("close", self.close), ("no-expect", self.no_expect)]
+            .into_iter()
+            .filter_map(|(name, on)| on.then_some(name))
+            .collect();
+        match items.is_empty() {
+            true => f.write_str("http/1.1"),
+            false => f.write_str(&items.join(",")),
+        }
+    }
+}
+
+impl UpstreamProtocol {
+    /// Whether backend connections may be kept and reused.
+    pub fn keep_alive(&self) -> bool {
+        !self.close
+    }
+
+    /// Adjust a request about to be sent to the backend. For h2c its `Host` becomes the
+    /// `:authority` of an absolute URI, as HTTP/2 has no `Host` header.
+    pub fn apply<B>(&self, req: &mut Request<B>) {
+        if self.http10 {
+            *req.version_mut() = Version::HTTP_10;
+        }
+        if self.h2c {
+            let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or_default();
+            let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            if let Ok(uri) = Uri::builder().scheme("http").authority(host).path_and_query(path).build() {
+                *req.uri_mut() = uri;
+                req.headers_mut().remove(HOST);
+                *req.version_mut() = Version::HTTP_2;
+            }
+        }
+        if self.no_expect {
+            req.headers_mut().remove(EXPECT);
+        }
+        if self.close {
+            req.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_protocol() {
+        let old: UpstreamProtocol = "HTTP/1.0".parse().unwrap();
+        assert_eq!(old, UpstreamProtocol { http10: true, close: true, no_expect: true, h2c: false });
+        assert_eq!(old.to_string(), "http/1.0");
+        let quirks: UpstreamProtocol = "no-expect, close".parse().unwrap();
+        assert_eq!(quirks.to_string(), "close,no-expect");
+        assert_eq!(quirks.to_string().parse::<UpstreamProtocol>().unwrap(), quirks);
+        assert_eq!("http/1.1".parse::<UpstreamProtocol>().unwrap().to_string(), "http/1.1");
+        assert!("".parse::<UpstreamProtocol>().is_err());
+        assert!("http/3".parse::<UpstreamProtocol>().is_err());
+        assert!("http/1.0,h2c".parse::<UpstreamProtocol>().is_err());
+
+        let mut req = Request::builder()
+            .header(EXPECT, "100-continue")
+            .header(CONNECTION, "keep-alive")
+            .body(())
+            .unwrap();
+        old.apply(&mut req);
+        assert_eq!(req.version(), Version::HTTP_10);
+        assert!(!req.headers().contains_key(EXPECT));
+        assert_eq!(req.headers()[CONNECTION], "close");
+        assert!(!old.keep_alive() && UpstreamProtocol::default().keep_alive());
+    }
+
+    #[test]
+    fn test_h2c_request() {
+        let h2c: UpstreamProtocol = "h2c, no-expect".parse().unwrap();
+        assert_eq!(h2c.to_string(), "h2c,no-expect");
+        let mut req = Request::builder()
+            .uri("/helloworld.Greeter/SayHello?x=1")
+            .header(HOST, "grpc.internal:50051")
+            .body(())
+            .unwrap();
+        h2c.apply(&mut req);
+        assert_eq!(req.version(), Version::HTTP_2);
+        assert_eq!(req.uri(), "http://grpc.internal:50051/helloworld.Greeter/SayHello?x=1");
+        assert!(!req.headers().contains_key(HOST));
+    }
+}
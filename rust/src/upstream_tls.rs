@@ -0,0 +1,252 @@
+//! Upstream TLS
+//! Connections to `https://` backends, verified against the public roots or a mapping's
+//! own CA bundle, optionally pinned to a public key, checked under another host name, or
+//! — loudly — not verified at all
+
+use crate::der::element;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use dashmap::DashMap;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::warn;
+
+/// Prefix of SPKI pins (as in curl's `--pinnedpubkey`)
+const PIN_PREFIX: &str = "sha256//";
+
+/// A backend connection, plain or TLS
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Verification settings of a mapping (JSON in its `upstream_tls` column)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamTls {
+    /// PEM bundle of the CAs to trust instead of the public roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<String>,
+    /// `sha256//<base64>` hashes of acceptable server public keys (SPKI); one must match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<String>,
+    /// Name sent as SNI and checked against the certificate instead of the backend host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Skip chain and name verification (pins are still checked)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+}
+
+impl UpstreamTls {
+    pub fn parse(json: &str) -> Result<Self> {
+        let settings: Self = serde_json::from_str(json).context("invalid upstream TLS settings")?;
+        for pin in &settings.pins {
+            parse_pin(pin)?;
+        }
+        if let Some(name) = &settings.server_name {
+            ServerName::try_from(name.as_str()).map_err(|_| anyhow!("invalid TLS server name '{}'", name))?;
+        }
+        Ok(settings)
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Decode a `sha256//<base64>` pin.
+pub fn parse_pin(pin: &str) -> Result<Vec<u8>> {
+    let hash = pin.trim().strip_prefix(PIN_PREFIX)
+        .and_then(|b64| general_purpose::STANDARD.decode(b64).ok())
+        .filter(|h| h.len() == 32);
+    hash.ok_or_else(|| anyhow!("invalid pin '{}' (expected sha256//<base64 of 32 bytes>)", pin))
+}
+
+/// The `sha256//<base64>` pin of a DER certificate's public key.
+pub fn spki_pin(cert_der: &[u8]) -> Option<String> {
+    let spki = subject_public_key_info(cert_der)?;
+    let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+    Some(format!("{}{}", PIN_PREFIX, general_purpose::STANDARD.encode(hash)))
+}
+
+/// The SubjectPublicKeyInfo of a DER certificate, header included.
+fn subject_public_key_info(cert_der: &[u8]) -> Option<&[u8]> {
+    let cert = element(cert_der)?.contents;
+    let mut rest = element(cert)?.contents;
+    // [0] version (optional), serial, signature algorithm, issuer, validity, subject
+    if rest.first() == Some(&0xa0) {
+        rest = element(rest)?.rest;
+    }
+    for _ in 0..5 {
+        rest = element(rest)?.rest;
+    }
+    let spki = element(rest)?;
+    (spki.tag == 0x30).then_some(spki.whole)
+}
+
+/// Chain and name verification (unless insecure), then the pins
+#[derive(Debug)]
+struct Verifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            let spki = subject_public_key_info(end_entity)
+                .ok_or_else(|| rustls::Error::General("unparsable server certificate".into()))?;
+            let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+            if !self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref()) {
+                return Err(rustls::Error::General("server key matches no pin".into()));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// TLS client for the backends of one verification setting
+pub struct Connector {
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+impl Connector {
+    pub fn new(settings: &UpstreamTls) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match &settings.ca {
+            Some(path) => {
+                let file = File::open(path).with_context(|| format!("cannot open CA bundle {}", path))?;
+                for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                    roots.add(cert?).with_context(|| format!("bad certificate in {}", path))?;
+                }
+                if roots.is_empty() {
+                    bail!("no certificates in CA bundle {}", path);
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let webpki = match settings.insecure {
+            true => None,
+            false => Some(WebPkiServerVerifier::builder(Arc::new(roots)).build()?),
+        };
+        let verifier = Verifier {
+            webpki,
+            pins: settings.pins.iter().map(|p| parse_pin(p)).collect::<Result<_>>()?,
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        };
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: settings.server_name.clone(),
+        })
+    }
+
+    /// Run the TLS handshake on a connection to `host` (or the configured server name).
+    pub async fn connect(&self, stream: TcpStream, host: &str) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let name = self.server_name.as_deref().unwrap_or(host.trim_start_matches('[').trim_end_matches(']'));
+        let name = ServerName::try_from(name.to_string()).map_err(|_| anyhow!("invalid TLS server name '{}'", name))?;
+        self.connector.connect(name, stream).await.context("TLS handshake with backend failed")
+    }
+}
+
+/// Connectors by settings JSON, built on first use
+#[derive(Default)]
+pub struct ConnectorCache {
+    connectors: DashMap<String, Arc<Connector>>,
+}
+
+impl ConnectorCache {
+    /// The connector for a mapping's settings (`None`: public roots, no pins).
+    pub fn get(&self, json: Option<&str>, domain: &str) -> Result<Arc<Connector>> {
+        let key = json.unwrap_or("");
+        if let Some(c) = self.connectors.get(key) {
+            return Ok(c.clone());
+        }
+        let settings = match json {
+            Some(j) => UpstreamTls::parse(j)?,
+            None => UpstreamTls::default(),
+        };
+        if settings.insecure {
+            warn!("!!! TLS verification of the backend of {} is DISABLED (upstream_tls insecure): \
+                   its certificate chain and name are not checked !!!", domain);
+        }
+        let connector = Arc::new(Connector::new(&settings)?);
+        self.connectors.insert(key.to_string(), connector.clone());
+        Ok(connector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spki_pin_matches_key() {
+        let cert = rcgen::generate_simple_self_signed(vec!["backend.test".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let expected = ring::digest::digest(&ring::digest::SHA256, &cert.get_key_pair().public_key_der());
+        let pin = spki_pin(&der).unwrap();
+        assert_eq!(parse_pin(&pin).unwrap(), expected.as_ref());
+        assert!(spki_pin(&der[..der.len() / 2]).is_none());
+    }
+
+    #[test]
+    fn test_parse_settings() {
+        let s = UpstreamTls::parse(r#"{"ca":"/etc/ca.pem","server_name":"api.internal"}"#).unwrap();
+        assert_eq!(s.ca.as_deref(), Some("/etc/ca.pem"));
+        assert!(!s.insecure);
+        assert_eq!(UpstreamTls::parse(&s.to_json()).unwrap(), s);
+        assert!(UpstreamTls::parse("{}").unwrap().is_default());
+        assert!(UpstreamTls::parse(r#"{"pins":["sha256//short"]}"#).is_err());
+        assert!(UpstreamTls::parse(r#"{"server_name":"bad name"}"#).is_err());
+    }
+}
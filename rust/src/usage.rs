@@ -0,0 +1,234 @@
+//! Usage accounting and monthly quotas
+//! Requests and body bytes per mapping are counted in memory and added to the `usage`
+//! table every `USAGE_FLUSH_INTERVAL`, by calendar month (UTC). A mapping's `quota` caps a
+//! month's requests and/or bytes (in plus out); once reached, its requests get 429 until
+//! the month ends. Instances sharing a database each add their own counts, and check
+//! quotas against the stored totals as of their last flush plus what they counted since
+
+use crate::database::{DatabaseManager, Usage};
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+use http_body_util::combinators::BoxBody;
+use hyper::Response;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Monthly limits of one mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub requests: Option<u64>,
+    /// Request plus response body bytes
+    pub bytes: Option<u64>,
+}
+
+impl Quota {
+    pub fn exceeded_by(&self, used: &Usage) -> bool {
+        self.requests.is_some_and(|max| used.requests >= max)
+            || self.bytes.is_some_and(|max| used.bytes_in.saturating_add(used.bytes_out) >= max)
+    }
+}
+
+/// Whole number with an optional K, M, G or T (binary) suffix
+pub(crate) fn parse_bytes(s: &str) -> Option<u64> {
+    let (digits, unit) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1u64 << 10),
+        (i, 'm' | 'M') => (&s[..i], 1 << 20),
+        (i, 'g' | 'G') => (&s[..i], 1 << 30),
+        (i, 't' | 'T') => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    /// `requests=1000000,bytes=50G`; either may be left out.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quota = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got '{}'", item))?;
+            let value = value.trim();
+            match name.trim() {
+                "requests" => quota.requests = Some(value.parse().map_err(|_| format!("'requests' needs a count, got '{}'", value))?),
+                "bytes" => quota.bytes = Some(parse_bytes(value).ok_or_else(|| format!("'bytes' needs a size (e.g. 50G), got '{}'", value))?),
+                other => return Err(format!("unknown quota '{}' (requests, bytes)", other)),
+            }
+        }
+        if quota == Self::default() {
+            return Err("a quota needs requests=N and/or bytes=SIZE".to_string());
+        }
+        Ok(quota)
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Vec<String> = [("requests", self.requests), ("bytes", self.bytes)]
+            .into_iter()
+            .filter_map(|(name, n)| Some(format!("{}={}", name, n?)))
+            .collect();
+        f.write_str(&items.join(","))
+    }
+}
+
+/// The accounting period `now` falls in (`YYYY-MM`)
+pub fn period(now: chrono::DateTime<chrono::Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+/// Time left until the period after `now` starts (for `Retry-After`)
+pub fn until_next_period(now: chrono::DateTime<chrono::Utc>) -> std::time::Duration {
+    use chrono::Datelike;
+    let (year, month) = if now.month() == 12 { (now.year() + 1, 1) } else { (now.year(), now.month() + 1) };
+    chrono::NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc() - now)
+        .and_then(|left| left.to_std().ok())
+        .unwrap_or_default()
+}
+
+/// Counts of one mapping since the last flush
+#[derive(Debug, Default)]
+pub struct Counters {
+    requests: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Counters {
+    fn peek(&self) -> Usage {
+        Usage {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self) -> Usage {
+        Usage {
+            requests: self.requests.swap(0, Ordering::Relaxed),
+            bytes_in: self.bytes_in.swap(0, Ordering::Relaxed),
+            bytes_out: self.bytes_out.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Per-mapping counts of this instance. Clones share them.
+#[derive(Clone, Default)]
+pub struct Meter {
+    pending: Arc<DashMap<String, Arc<Counters>>>,
+    /// The period and its stored totals, as of the last flush
+    stored: Arc<RwLock<(String, HashMap<String, Usage>)>>,
+}
+
+impl Meter {
+    /// Count a request and its response body, which is counted as it is sent.
+    pub fn record(&self, mapping_id: &str, bytes_in: u64, response: Response<BoxBody<Bytes, hyper::Error>>)
+        -> Response<BoxBody<Bytes, hyper::Error>>
+    {
+        let counters = self.pending.entry(mapping_id.to_string()).or_default().clone();
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        response.map(|body| body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                counters.bytes_out.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            frame
+        }).boxed())
+    }
+
+    /// This month's usage of a mapping: stored totals plus what was counted since.
+    pub fn used(&self, mapping_id: &str) -> Usage {
+        let mut used = {
+            let stored = self.stored.read();
+            match stored.0 == period(chrono::Utc::now()) {
+                true => stored.1.get(mapping_id).copied().unwrap_or_default(),
+                false => Usage::default(),
+            }
+        };
+        if let Some(counters) = self.pending.get(mapping_id) {
+            used.add(counters.peek());
+        }
+        used
+    }
+
+    /// Add the counts since the last flush to the database and reload this month's totals.
+    pub fn flush(&self, db: &DatabaseManager) -> anyhow::Result<()> {
+        let now = period(chrono::Utc::now());
+        let counts: Vec<(String, Usage)> = self.pending.iter()
+            .map(|e| (e.key().clone(), e.value().take()))
+            .filter(|(_, u)| *u != Usage::default())
+            .collect();
+        // Mappings idle for a whole flush are dropped; a request in between gets new counters
+        self.pending.retain(|_, c| Arc::strong_count(c) > 1 || c.peek() != Usage::default());
+        if let Err(e) = db.add_usage(&now, &counts) {
+            // Counted again next time rather than lost
+            for (id, u) in counts {
+                let counters = self.pending.entry(id).or_default().clone();
+                counters.requests.fetch_add(u.requests, Ordering::Relaxed);
+                counters.bytes_in.fetch_add(u.bytes_in, Ordering::Relaxed);
+                counters.bytes_out.fetch_add(u.bytes_out, Ordering::Relaxed);
+            }
+            return Err(e);
+        }
+        let totals = db.usage(&now)?;
+        *self.stored.write() = (now, totals);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_quota_parse() {
+        let quota: Quota = "requests=1000, bytes=2K".parse().unwrap();
+        assert_eq!(quota, Quota { requests: Some(1000), bytes: Some(2048) });
+        assert_eq!(quota.to_string(), "requests=1000,bytes=2048");
+        assert_eq!("bytes=50G".parse::<Quota>().unwrap().to_string(), "bytes=53687091200");
+        assert!(quota.exceeded_by(&Usage { requests: 1000, ..Usage::default() }));
+        assert!(quota.exceeded_by(&Usage { bytes_in: 1024, bytes_out: 1024, ..Usage::default() }));
+        assert!(!quota.exceeded_by(&Usage { requests: 999, bytes_in: 1024, bytes_out: 1023 }));
+        assert!("".parse::<Quota>().is_err());
+        assert!("requests=lots".parse::<Quota>().is_err());
+        assert!("minutes=5".parse::<Quota>().is_err());
+
+        let now = chrono::DateTime::parse_from_rfc3339("2024-12-31T23:59:00Z").unwrap().to_utc();
+        assert_eq!(period(now), "2024-12");
+        assert_eq!(until_next_period(now).as_secs(), 60);
+    }
+
+    #[tokio::test]
+    async fn test_record_and_flush() {
+        let dir = tempdir().unwrap();
+        let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+        let (a, b) = (Meter::default(), Meter::default());
+
+        let body = |s: &'static str| Full::new(Bytes::from(s)).map_err(|never| match never {}).boxed();
+        let resp = a.record("m1", 10, Response::new(body("hello")));
+        resp.into_body().collect().await.unwrap();
+        let _ = b.record("m1", 0, Response::new(body("")));
+        assert_eq!(a.used("m1"), Usage { requests: 1, bytes_in: 10, bytes_out: 5 });
+
+        // Each instance adds its own counts; both see the sum after flushing
+        a.flush(&db).unwrap();
+        b.flush(&db).unwrap();
+        a.flush(&db).unwrap();
+        assert_eq!(a.used("m1"), Usage { requests: 2, bytes_in: 10, bytes_out: 5 });
+        assert_eq!(b.used("m1"), a.used("m1"));
+        assert_eq!(a.used("m2"), Usage::default());
+
+        let report = db.usage_report(None).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].period.as_str(), report[0].domain.as_deref()), (period(chrono::Utc::now()).as_str(), None));
+    }
+}
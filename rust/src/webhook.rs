@@ -0,0 +1,133 @@
+//! Webhook notifications
+//! Every [`Event`](crate::events::Event) POSTed as JSON to the URLs in `WEBHOOK_URLS`,
+//! signed with `WEBHOOK_SECRET` and retried with backoff, so chat and paging bridges can
+//! react without scraping logs
+
+use crate::events::Record;
+use ring::hmac;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// `sha256=<hex HMAC-SHA256 of the body>`, present when a secret is configured
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const EVENT_HEADER: &str = "x-webhook-event";
+
+/// Tries per delivery; waits 1s, 2s, 4s… in between
+const ATTEMPTS: u32 = 4;
+/// Deliveries in flight (including retries) beyond which new events are dropped
+const MAX_IN_FLIGHT: usize = 256;
+
+struct Inner {
+    urls: Vec<String>,
+    secret: Option<String>,
+    http: reqwest::Client,
+    in_flight: AtomicUsize,
+}
+
+/// Dispatcher handle; the default one (no URLs) drops every event
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    inner: Option<Arc<Inner>>,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>, secret: Option<String>) -> Self {
+        let urls: Vec<String> = urls.into_iter().map(|u| u.trim().to_string()).filter(|u| !u.is_empty()).collect();
+        if urls.is_empty() {
+            return Self::default();
+        }
+        Self {
+            inner: Some(Arc::new(Inner {
+                urls,
+                secret: secret.filter(|s| !s.is_empty()),
+                http: reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap_or_default(),
+                in_flight: AtomicUsize::new(0),
+            })),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Queue `record` for every URL. Never blocks; outside a Tokio runtime, or with too many
+    /// deliveries pending, it is dropped.
+    pub fn send(&self, record: &Record) {
+        let Some(inner) = &self.inner else { return };
+        let name = record.event.name();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            debug!("Webhook {} dropped: no runtime", name);
+            return;
+        };
+        let body = serde_json::to_string(record).unwrap_or_default();
+        let signature = inner.secret.as_deref().map(|s| signature(s, body.as_bytes()));
+        for url in &inner.urls {
+            if inner.in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+                inner.in_flight.fetch_sub(1, Ordering::Relaxed);
+                warn!("Webhook {} to {} dropped: too many deliveries pending", name, url);
+                continue;
+            }
+            let (inner, url, body, signature) = (inner.clone(), url.clone(), body.clone(), signature.clone());
+            runtime.spawn(async move {
+                inner.deliver(&url, name, body, signature.as_deref()).await;
+                inner.in_flight.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+impl Inner {
+    async fn deliver(&self, url: &str, name: &str, body: String, signature: Option<&str>) {
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 1..=ATTEMPTS {
+            let mut req = self.http.post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, name)
+                .body(body.clone());
+            if let Some(sig) = signature {
+                req = req.header(SIGNATURE_HEADER, sig);
+            }
+            let outcome = match req.send().await {
+                Ok(resp) if resp.status().is_success() => return,
+                // Only server errors and throttling are worth another try
+                Ok(resp) if !resp.status().is_server_error() && resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    warn!("Webhook {} to {} refused: {}", name, url, resp.status());
+                    return;
+                }
+                Ok(resp) => resp.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            if attempt == ATTEMPTS {
+                warn!("Webhook {} to {} failed after {} attempts: {}", name, url, ATTEMPTS, outcome);
+                return;
+            }
+            debug!("Webhook {} to {} failed ({}), retrying in {:?}", name, url, outcome, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+/// Value of the signature header for `body`; receivers recompute it with the shared secret.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), body);
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+        assert!(!Webhooks::new(vec![" ".into()], None).enabled());
+    }
+}
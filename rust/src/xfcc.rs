@@ -0,0 +1,150 @@
+//! Client certificate passthrough
+//! With `FORWARD_CLIENT_CERT` set, requests whose client presented a verified certificate
+//! (see `TLS_CLIENT_CA`) reach the backend with an `X-Forwarded-Client-Cert` header in
+//! Envoy's format: `Hash=<sha256 of the DER>;Subject="CN=alice,O=Example";URI=spiffe://...`.
+//! The setting lists what goes in besides the hash: `subject`, `uri` and `dns` (subject
+//! alternative names), `cert` and `chain` (URL-encoded PEM). An `X-Forwarded-Client-Cert`
+//! sent by the client is always dropped, so backends only ever see the proxy's
+
+use crate::tls_info::TlsInfo;
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use std::fmt;
+use std::str::FromStr;
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-forwarded-client-cert");
+
+/// Elements of the header besides `Hash`, which is always there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientCertDetails {
+    pub subject: bool,
+    pub uri: bool,
+    pub dns: bool,
+    pub cert: bool,
+    pub chain: bool,
+}
+
+impl FromStr for ClientCertDetails {
+    type Err = String;
+
+    /// `hash`, or `hash` plus any of `subject,uri,dns,cert,chain`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut details = Self::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match item.to_ascii_lowercase().as_str() {
+                "hash" => {}
+                "subject" => details.subject = true,
+                "uri" => details.uri = true,
+                "dns" => details.dns = true,
+                "cert" => details.cert = true,
+                "chain" => details.chain = true,
+                other => return Err(format!("unknown client certificate detail '{}' (hash, subject, uri, dns, cert, chain)", other)),
+            }
+        }
+        Ok(details)
+    }
+}
+
+impl fmt::Display for ClientCertDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("hash")?;
+        for (name, on) in [("subject", self.subject), ("uri", self.uri), ("dns", self.dns), ("cert", self.cert), ("chain", self.chain)] {
+            if on {
+                write!(f, ",{}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A DER certificate as PEM.
+fn pem(der: &[u8]) -> String {
+    let b64 = B64.encode(der);
+    let lines: Vec<&str> = b64.as_bytes().chunks(64).map(|c| std::str::from_utf8(c).unwrap_or_default()).collect();
+    format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", lines.join("\n"))
+}
+
+/// Percent-encode everything but unreserved characters.
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Quoted header element; the value's own quotes and backslashes are escaped.
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl ClientCertDetails {
+    /// The header value for a client's certificate chain (leaf first), if it has one.
+    pub fn value(&self, chain: &[Vec<u8>]) -> Option<String> {
+        let leaf = chain.first()?;
+        let digest = ring::digest::digest(&ring::digest::SHA256, leaf);
+        let mut parts = vec![format!("Hash={}", digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>())];
+        if self.cert {
+            parts.push(format!("Cert={}", quoted(&url_encode(&pem(leaf)))));
+        }
+        if self.chain {
+            let all: String = chain.iter().map(|c| pem(c)).collect();
+            parts.push(format!("Chain={}", quoted(&url_encode(&all))));
+        }
+        if self.subject {
+            parts.push(format!("Subject={}", quoted(&crate::der::subject(leaf).unwrap_or_default())));
+        }
+        for (kind, name) in crate::der::subject_alt_names(leaf) {
+            if (kind == "URI" && self.uri) || (kind == "DNS" && self.dns) {
+                parts.push(format!("{}={}", kind, name));
+            }
+        }
+        Some(parts.join(";"))
+    }
+
+    /// Replace any `X-Forwarded-Client-Cert` in `headers` with the one for the request's
+    /// connection, if its client presented a certificate.
+    pub fn apply(&self, tls: Option<&TlsInfo>, headers: &mut HeaderMap) {
+        headers.remove(HEADER);
+        let value = tls.and_then(|t| self.value(&t.client_chain));
+        if let Some(v) = value.and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok()) {
+            headers.insert(HEADER, v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+
+    #[test]
+    fn test_xfcc_value() {
+        let details: ClientCertDetails = "Hash, subject,uri,dns".parse().unwrap();
+        assert_eq!(details.to_string(), "hash,subject,uri,dns");
+        assert_eq!("".parse::<ClientCertDetails>().unwrap().to_string(), "hash");
+        assert!("issuer".parse::<ClientCertDetails>().is_err());
+
+        let mut params = CertificateParams::new(vec!["client.test".to_string()]);
+        params.subject_alt_names.push(SanType::URI("spiffe://example.org/ns/web".to_string()));
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let der = Certificate::from_params(params).unwrap().serialize_der().unwrap();
+        let hash: String = ring::digest::digest(&ring::digest::SHA256, &der).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+
+        assert_eq!(details.value(&[]), None);
+        assert_eq!(
+            details.value(std::slice::from_ref(&der)).unwrap(),
+            format!("Hash={};Subject=\"CN=alice\";DNS=client.test;URI=spiffe://example.org/ns/web", hash)
+        );
+        let full = ClientCertDetails { cert: true, ..Default::default() }.value(&[der]).unwrap();
+        assert!(full.starts_with(&format!("Hash={};Cert=\"-----BEGIN%20CERTIFICATE-----%0A", hash)), "{}", full);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_static("Hash=forged"));
+        details.apply(Some(&TlsInfo::default()), &mut headers);
+        assert!(headers.is_empty());
+    }
+}
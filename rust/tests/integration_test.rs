@@ -8,7 +8,10 @@
 //! - IP allowlisting
 //! - Auth (basic, bearer, password)
 //! - Wildcard and catch-all domain routing
-//! - WebSocket proxying (basic)
+//! - WebSocket proxying, over HTTP and HTTPS
+//!
+//! Proxies and backends come from `rustproxy::test_util` (feature `test-util`) or are
+//! started the same way: bound to an OS-assigned port before the test sends anything
 
 use bytes::Bytes;
 use http_body_util::Full;
@@ -17,71 +20,46 @@ use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
+use rustproxy::test_util::{self, Backend, TestProxy};
 use rustproxy::{CertificateManager, DatabaseManager, FallbackHandler, ProxyBuilder, ProxyConfig, ProxyServer};
 use anyhow::Result;
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tempfile::tempdir;
 use tokio::net::TcpListener;
 use tokio::time::sleep;
 
-static PORT_COUNTER: AtomicU16 = AtomicU16::new(19000);
-
-fn get_unique_port() -> u16 {
-    PORT_COUNTER.fetch_add(1, Ordering::SeqCst)
-}
-
-/// Simple backend server for testing — echoes path, host, and X-Forwarded-For
-async fn run_backend_server(port: u16, tag: &'static str) -> tokio::task::JoinHandle<()> {
-    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let listener = TcpListener::bind(addr).await.unwrap();
-
-    tokio::spawn(async move {
-        loop {
-            let Ok((stream, _)) = listener.accept().await else { continue };
-            let io = TokioIo::new(stream);
-            tokio::spawn(async move {
-                let _ = http1::Builder::new()
-                    .serve_connection(io, service_fn(move |req: Request<Incoming>| async move {
-                        let path = req.uri().path().to_string();
-                        let host = req.headers().get("host")
-                            .and_then(|h| h.to_str().ok()).unwrap_or("unknown");
-                        let xff = req.headers().get("x-forwarded-for")
-                            .and_then(|h| h.to_str().ok()).unwrap_or("none");
-                        Ok::<_, Infallible>(Response::builder().status(200)
-                            .body(Full::new(Bytes::from(format!("{}|path={}|host={}|xff={}", tag, path, host, xff))))
-                            .unwrap())
-                    }))
-                    .await;
-            });
-        }
-    })
+/// A port nothing listens on (for unreachable backends): OS-assigned, then released.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
 }
 
-async fn setup_proxy(http_port: u16, db_path: &std::path::Path, certs_dir: &std::path::Path) -> Arc<ProxyServer> {
+async fn setup_proxy(db_path: &std::path::Path, certs_dir: &std::path::Path) -> Arc<ProxyServer> {
     let db_manager = Arc::new(DatabaseManager::new(db_path).unwrap());
     let cert_manager = Arc::new(CertificateManager::new(certs_dir, None).unwrap());
     let config = ProxyConfig {
-        http_port,
-        https_port: http_port + 1,
+        http_port: 0,
         enable_https: false,
         force_https: false,
-        http_host: "0.0.0.0".to_string(),
+        http_host: "127.0.0.1".to_string(),
+        ..ProxyConfig::default()
     };
     Arc::new(ProxyServer::new(config, db_manager, cert_manager))
 }
 
+/// Start `proxy` on its configured ports (0 = OS-assigned) and return the HTTP port.
+async fn serve(proxy: Arc<ProxyServer>) -> u16 {
+    proxy.run().await.unwrap().http_addr().port()
+}
+
 fn add(db: &DatabaseManager, domain: &str, front: &str, port: u16, back: &str) {
     db.add_mapping(domain, front, port, back, None, None, None, None, None).unwrap();
 }
 
-async fn start_proxy(proxy_port: u16, db_path: &std::path::Path, certs_dir: &std::path::Path) {
-    let proxy = setup_proxy(proxy_port, db_path, certs_dir).await;
-    tokio::spawn(async move { let _ = proxy.run().await; });
-    sleep(Duration::from_millis(150)).await;
+/// Start a default proxy; returns its HTTP port once it is listening.
+async fn start_proxy(db_path: &std::path::Path, certs_dir: &std::path::Path) -> u16 {
+    serve(setup_proxy(db_path, certs_dir).await).await
 }
 
 // ── Core proxy tests ──────────────────────────────────────────────────────────
@@ -89,8 +67,7 @@ async fn start_proxy(proxy_port: u16, db_path: &std::path::Path, certs_dir: &std
 #[tokio::test]
 async fn test_health_endpoint() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/health", proxy_port))
@@ -100,18 +77,69 @@ async fn test_health_endpoint() {
     assert_eq!(resp.text().await.unwrap(), "OK");
 }
 
+#[tokio::test]
+async fn test_builtin_endpoints_restricted() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("MAPPED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    add(&db, "mapped.test", "", backend_port, "");
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    certs.store_acme_challenge("tok_1", "tok_1.thumbprint");
+    let internal_addr: std::net::SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let config = ProxyConfig {
+        http_port: 0,
+        health_allowed_ips: Some("10.0.0.0/8, 127.0.0.1".to_string()),
+        internal_listen: Some(internal_addr),
+        admin_token: Some("s3cret".to_string()),
+        acme_challenges_mapped_only: true,
+        ..ProxyConfig::default()
+    };
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs.clone()))).await;
+
+    // /health and the admin API only on the internal listener; on the public port they
+    // reach the backend
+    let resp = reqwest::get(format!("http://localhost:{}/health", proxy_port)).await.unwrap();
+    assert!(resp.text().await.unwrap().starts_with("MAPPED|path=/health"));
+    test_util::listening(internal_addr).await;
+    let internal = reqwest::get(format!("http://{}/health", internal_addr)).await.unwrap();
+    assert_eq!(internal.text().await.unwrap(), "OK");
+    let resp = reqwest::get(format!("http://{}/other", internal_addr)).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+    let client = reqwest::Client::new();
+    let status = |base: String| client.get(format!("{}/_proxy/admin/status", base)).bearer_auth("s3cret").send();
+    let resp = status(format!("http://localhost:{}", proxy_port)).await.unwrap();
+    assert!(resp.text().await.unwrap().starts_with("MAPPED|path=/_proxy/admin/status"));
+    let resp = status(format!("http://{}", internal_addr)).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert!(body["connections"].is_object(), "{}", body);
+
+    // ACME challenges for mapped hosts only
+    let challenge = |host: &'static str| client
+        .get(format!("http://127.0.0.1:{}/.well-known/acme-challenge/tok_1", proxy_port))
+        .header("Host", host)
+        .send();
+    assert_eq!(challenge("mapped.test").await.unwrap().text().await.unwrap(), "tok_1.thumbprint");
+    assert_eq!(challenge("unmapped.test").await.unwrap().status().as_u16(), 404);
+
+    // Source restriction without an internal listener
+    let config = ProxyConfig { http_port: 0, health_allowed_ips: Some("10.0.0.0/8".to_string()), ..ProxyConfig::default() };
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+    let resp = reqwest::get(format!("http://localhost:{}/health", proxy_port)).await.unwrap();
+    assert!(resp.text().await.unwrap().starts_with("MAPPED|path=/health"));
+}
+
 #[tokio::test]
 async fn test_proxy_simple_request() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("BACKEND_RESPONSE").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "localhost", "", backend_port, "");
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "BACKEND_RESPONSE").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let body = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -125,15 +153,13 @@ async fn test_proxy_simple_request() {
 #[tokio::test]
 async fn test_proxy_path_rewriting() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("REWRITTEN").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "localhost", "api/v1", backend_port, "v1");
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "REWRITTEN").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let body = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/api/v1/users", proxy_port))
@@ -146,18 +172,16 @@ async fn test_proxy_path_rewriting() {
 #[tokio::test]
 async fn test_proxy_longest_match() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let port_short = get_unique_port();
-    let port_long = get_unique_port();
+    let b1 = Backend::tagged("SHORT_MATCH").await;
+    let port_short = b1.port();
+    let b2 = Backend::tagged("LONG_MATCH").await;
+    let port_long = b2.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "localhost", "api", port_short, "");
     add(&db, "localhost", "api/v1", port_long, "v1");
     drop(db);
-
-    let _b1 = run_backend_server(port_short, "SHORT_MATCH").await;
-    let _b2 = run_backend_server(port_long, "LONG_MATCH").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let client = reqwest::Client::new();
 
@@ -173,8 +197,7 @@ async fn test_proxy_longest_match() {
 #[tokio::test]
 async fn test_proxy_no_mapping_404() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -187,8 +210,7 @@ async fn test_proxy_no_mapping_404() {
 #[tokio::test]
 async fn test_proxy_missing_host_400() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpStream;
@@ -201,18 +223,40 @@ async fn test_proxy_missing_host_400() {
     assert!(String::from_utf8_lossy(&buf[..n]).contains("400"));
 }
 
+#[tokio::test]
+async fn test_duplicate_host_headers() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("DUPLICATES").await;
+    let backend_port = backend.port();
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let status = |hosts: &'static str| async move {
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", proxy_port)).await.unwrap();
+        stream.write_all(format!("GET / HTTP/1.1\r\n{}Connection: close\r\n\r\n", hosts).as_bytes()).await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or("").to_string()
+    };
+    assert!(status("Host: localhost\r\nHost: LOCALHOST\r\n").await.contains("200"));
+    assert!(status("Host: localhost\r\nHost: evil.test\r\n").await.contains("400"));
+    backend.abort();
+}
+
 #[tokio::test]
 async fn test_proxy_x_forwarded_headers() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("HEADERS_TEST").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "example.com", "", backend_port, "");
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "HEADERS_TEST").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let body = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -223,18 +267,51 @@ async fn test_proxy_x_forwarded_headers() {
     assert!(body.contains("xff=127.0.0.1"));
 }
 
+#[tokio::test]
+async fn test_remote_addr_template_ignores_forged_forwarded_for() {
+    let backend = Backend::tagged("TEMPLATE").await;
+    let proxy = TestProxy::start().await;
+    proxy.map("example.com", "", backend.port(), "client-$remote_addr");
+    let client = proxy.client("example.com");
+    let get = |xff: &'static str| client.get(proxy.http_url("example.com", "/x")).header("X-Forwarded-For", xff).send();
+
+    for xff in ["169.254.169.254", "evil.com/x@"] {
+        let body = get(xff).await.unwrap().text().await.unwrap();
+        assert!(body.starts_with("TEMPLATE|path=/client-127.0.0.1/x|"), "{}", body);
+    }
+}
+
+#[tokio::test]
+async fn test_echo_backend_shows_upstream_request() {
+    let echo = Backend::echo_named("echo").await;
+    let proxy = TestProxy::start().await;
+    proxy.map("example.com", "api", echo.port(), "v2");
+
+    let body = proxy.client("example.com")
+        .post(proxy.http_url("example.com", "/api/items?page=2"))
+        .body("payload")
+        .send().await.unwrap().text().await.unwrap();
+    let seen: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+    assert_eq!(seen["name"], "echo");
+    assert_eq!((seen["method"].as_str(), seen["path"].as_str(), seen["query"].as_str()), (Some("POST"), Some("/v2/items"), Some("page=2")));
+    assert_eq!(seen["body"], "payload");
+    let headers: Vec<&str> = seen["headers"].as_array().unwrap().iter().filter_map(|h| h.as_str()).collect();
+    assert!(headers.contains(&"x-forwarded-for: 127.0.0.1"), "{:?}", headers);
+    let forwarded_host = format!("x-forwarded-host: example.com:{}", proxy.http_addr().port());
+    assert!(headers.contains(&forwarded_host.as_str()), "{:?}", headers);
+}
+
 #[tokio::test]
 async fn test_proxy_post_request() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("POST_TEST").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "localhost", "", backend_port, "");
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "POST_TEST").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .post(format!("http://127.0.0.1:{}/api/data", proxy_port))
@@ -248,15 +325,13 @@ async fn test_proxy_post_request() {
 #[tokio::test]
 async fn test_proxy_query_string_preserved() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "localhost", "", backend_port, "");
     drop(db);
 
-    let addr: SocketAddr = format!("127.0.0.1:{}", backend_port).parse().unwrap();
-    let listener = TcpListener::bind(addr).await.unwrap();
     tokio::spawn(async move {
         loop {
             let Ok((stream, _)) = listener.accept().await else { continue };
@@ -273,7 +348,7 @@ async fn test_proxy_query_string_preserved() {
         }
     });
 
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let body = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/api?foo=bar&baz=qux", proxy_port))
@@ -287,14 +362,13 @@ async fn test_proxy_query_string_preserved() {
 #[tokio::test]
 async fn test_backend_unreachable_502() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend_port = free_port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "localhost", "", backend_port, "");
     drop(db);
 
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -304,6 +378,52 @@ async fn test_backend_unreachable_502() {
     assert_eq!(resp.status().as_u16(), 502);
 }
 
+#[tokio::test]
+async fn test_request_deadline_enforced_and_forwarded() {
+    let dir = tempdir().unwrap();
+    // Echoes the deadline it was given; /slow answers after a second
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                        if req.uri().path() == "/slow" {
+                            sleep(Duration::from_secs(1)).await;
+                        }
+                        let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("none").to_string();
+                        let body = format!("{}|{}", header("x-request-timeout"), header("grpc-timeout"));
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body))))
+                    }))
+                    .await;
+            });
+        }
+    });
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path)).header("Host", "localhost");
+
+    // Tightest budget wins and is passed on in the client's own header formats
+    let body = get("/").header("X-Request-Timeout", "30").header("grpc-timeout", "2S")
+        .send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "2.000|2000000u");
+
+    // Over the cap (MAX_REQUEST_TIMEOUT default 300s): clamped
+    let body = get("/").header("X-Request-Timeout", "3600").send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "300.000|none");
+
+    let resp = get("/slow").header("X-Request-Timeout", "200ms").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 504);
+    let resp = get("/slow").send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "none|none");
+}
+
 // ── Database tests ────────────────────────────────────────────────────────────
 
 #[tokio::test]
@@ -329,15 +449,13 @@ async fn test_database_operations() {
 #[tokio::test]
 async fn test_wildcard_domain_routing() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("WILDCARD").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "*.example.com", "", backend_port, "");
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "WILDCARD").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     // sub.example.com should match *.example.com
     let body = reqwest::Client::new()
@@ -357,15 +475,13 @@ async fn test_wildcard_domain_routing() {
 #[tokio::test]
 async fn test_catchall_domain_routing() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("CATCHALL").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "*", "", backend_port, "");
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "CATCHALL").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     for host in &["random.example.com", "anything.net", "foo.bar.baz"] {
         let body = reqwest::Client::new()
@@ -379,18 +495,16 @@ async fn test_catchall_domain_routing() {
 #[tokio::test]
 async fn test_exact_domain_beats_catchall() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let exact_port = get_unique_port();
-    let catchall_port = get_unique_port();
+    let b1 = Backend::tagged("EXACT").await;
+    let exact_port = b1.port();
+    let b2 = Backend::tagged("CATCHALL").await;
+    let catchall_port = b2.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     add(&db, "specific.com", "", exact_port, "");
     add(&db, "*", "", catchall_port, "");
     drop(db);
-
-    let _b1 = run_backend_server(exact_port, "EXACT").await;
-    let _b2 = run_backend_server(catchall_port, "CATCHALL").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let client = reqwest::Client::new();
 
@@ -408,17 +522,15 @@ async fn test_exact_domain_beats_catchall() {
 #[tokio::test]
 async fn test_ip_allowlist_blocks_unlisted() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("SHOULD_NOT_SEE").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     // Allow only 10.0.0.1 — our test client is 127.0.0.1, so it should be blocked
     db.add_mapping("localhost", "", backend_port, "", None, None,
                    Some("10.0.0.1"), None, None).unwrap();
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "SHOULD_NOT_SEE").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -431,17 +543,15 @@ async fn test_ip_allowlist_blocks_unlisted() {
 #[tokio::test]
 async fn test_ip_allowlist_allows_listed() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("ALLOWED").await;
+    let backend_port = backend.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     // Allow loopback range
     db.add_mapping("localhost", "", backend_port, "", None, None,
                    Some("127.0.0.0/8"), None, None).unwrap();
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "ALLOWED").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -457,17 +567,15 @@ async fn test_ip_allowlist_allows_listed() {
 #[tokio::test]
 async fn test_bearer_auth_allowed() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("BEARER_OK").await;
+    let backend_port = backend.port();
 
     let creds = r#"[{"token":"secret-token"}]"#;
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     db.add_mapping("localhost", "", backend_port, "", None, None,
                    None, Some("bearer"), Some(creds)).unwrap();
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "BEARER_OK").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let client = reqwest::Client::new();
 
@@ -495,17 +603,15 @@ async fn test_bearer_auth_allowed() {
 #[tokio::test]
 async fn test_basic_auth() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("BASIC_OK").await;
+    let backend_port = backend.port();
 
     let creds = r#"[{"user":"admin","pass":"password123"}]"#;
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     db.add_mapping("localhost", "", backend_port, "", None, None,
                    None, Some("basic"), Some(creds)).unwrap();
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "BASIC_OK").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let client = reqwest::Client::new();
 
@@ -523,20 +629,224 @@ async fn test_basic_auth() {
     assert!(resp.text().await.unwrap().contains("BASIC_OK"));
 }
 
+#[tokio::test]
+async fn test_api_key_auth() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("KEY_OK").await;
+    let backend_port = backend.port();
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    let (_, secret) = db.add_api_key(&m.id, "ci", None).unwrap();
+    let (revoked, revoked_secret) = db.add_api_key(&m.id, "old", None).unwrap();
+    db.delete_api_key(&revoked.id).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path)).header("Host", "localhost");
+
+    let resp = get("/test").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "ApiKey realm=\"Proxy\"");
+    assert_eq!(get("/test").header("X-Api-Key", &revoked_secret).send().await.unwrap().status().as_u16(), 401);
+
+    let resp = get("/test").header("X-Api-Key", &secret).send().await.unwrap();
+    assert!(resp.text().await.unwrap().contains("KEY_OK"));
+    let resp = get(&format!("/test?page=2&api_key={}", secret)).send().await.unwrap();
+    assert!(resp.text().await.unwrap().contains("KEY_OK"));
+}
+
+#[tokio::test]
+async fn test_signed_urls() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!("URI={}", req.uri())))))
+                    }))
+                    .await;
+            });
+        }
+    });
+
+    std::env::set_var("TEST_LINK_KEY", "link-secret");
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "dl", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_url_signing_secret(&m.id, Some("${TEST_LINK_KEY}")).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let get = |path_and_query: String| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path_and_query))
+        .header("Host", "localhost").send();
+    let now = chrono::Utc::now().timestamp() as u64;
+
+    let link = rustproxy::signed_url::sign("link-secret", "/dl/report.pdf", Some("v=2"), now + 60);
+    let resp = get(link.clone()).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.text().await.unwrap(), "URI=/report.pdf?v=2");
+
+    let resp = get("/dl/report.pdf".to_string()).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 403);
+    assert_eq!(get(link.replace("v=2", "v=3")).await.unwrap().status().as_u16(), 403);
+    let expired = rustproxy::signed_url::sign("link-secret", "/dl/report.pdf", None, now - 1);
+    let resp = get(expired).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 403);
+    assert!(resp.text().await.unwrap().contains("expired"));
+}
+
+/// A minimal OpenID provider: discovery and a token endpoint issuing (unsigned) ID tokens
+/// for `email`, with the nonce the test hands it
+async fn run_oidc_provider(nonce: Arc<parking_lot::Mutex<String>>) -> String {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine as _};
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let base = issuer.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (nonce, issuer) = (nonce.clone(), base.clone());
+            tokio::spawn(async move {
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                    let (nonce, issuer) = (nonce.clone(), issuer.clone());
+                    async move {
+                        let body = match req.uri().path() {
+                            "/.well-known/openid-configuration" => serde_json::json!({
+                                "issuer": issuer,
+                                "authorization_endpoint": format!("{}/authorize", issuer),
+                                "token_endpoint": format!("{}/token", issuer),
+                            }),
+                            _ => {
+                                let form = http_body_util::BodyExt::collect(req.into_body()).await.unwrap().to_bytes();
+                                let form = String::from_utf8_lossy(&form).into_owned();
+                                assert!(form.contains("code=good-code") && form.contains("code_verifier="), "{}", form);
+                                let claims = serde_json::json!({
+                                    "iss": issuer, "sub": "user-1", "aud": "proxy-app",
+                                    "exp": chrono::Utc::now().timestamp() + 300, "nonce": *nonce.lock(),
+                                    "email": "ada@example.com", "email_verified": true,
+                                });
+                                let token = format!("{}.{}.sig", B64.encode(r#"{"alg":"RS256"}"#), B64.encode(claims.to_string()));
+                                serde_json::json!({ "access_token": "at", "token_type": "Bearer", "id_token": token })
+                            }
+                        };
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body.to_string()))))
+                    }
+                })).await;
+            });
+        }
+    });
+    issuer
+}
+
+#[tokio::test]
+async fn test_oidc_login_flow() {
+    let dir = tempdir().unwrap();
+    let nonce = Arc::new(parking_lot::Mutex::new(String::new()));
+    let issuer = run_oidc_provider(nonce.clone()).await;
+
+    // Backend showing the identity headers and cookies it gets
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                let h = |n: &str| req.headers().get(n).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!(
+                    "user={}|email={}|cookie={}", h("x-forwarded-user"), h("x-forwarded-email"), h("cookie"),
+                )))))
+            })));
+        }
+    });
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    let settings = format!(r#"{{"issuer":"{}","client_id":"proxy-app","client_secret":"s3cret","allowed_domains":["example.com"]}}"#, issuer);
+    db.set_oidc(&m.id, Some(&settings)).unwrap();
+    drop(db);
+    let build = || ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .session_secret("test-secret")
+        .session_store(rustproxy::session::Backend::Sqlite)
+        .build()
+        .unwrap();
+    let proxy_port = serve(Arc::new(build())).await;
+    let url = |path: &str| format!("http://127.0.0.1:{}{}", proxy_port, path);
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    let cookie_of = |resp: &reqwest::Response, name: &str| resp.headers().get_all("set-cookie").iter()
+        .filter_map(|c| c.to_str().ok())
+        .find(|c| c.starts_with(&format!("{}=", name)))
+        .map(|c| c.split(';').next().unwrap().to_string())
+        .unwrap();
+
+    // API clients get a 401, browsers go to the provider
+    let resp = client.get(url("/dash?tab=2")).header("Host", "localhost").header("X-Forwarded-User", "admin").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+    let resp = client.get(url("/dash?tab=2")).header("Host", "localhost").header("Accept", "text/html").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 302);
+    let location = url::Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+    assert!(location.as_str().starts_with(&format!("{}/authorize?", issuer)));
+    let params: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+    assert_eq!(params["redirect_uri"], "http://localhost/_proxy/oidc/callback");
+    assert_eq!(params["code_challenge_method"], "S256");
+    let login_cookie = cookie_of(&resp, "_rp_oidc_login");
+    *nonce.lock() = params["nonce"].clone();
+
+    // The provider sends the browser back; a forged state is refused
+    let callback = |state: &str| client.get(url(&format!("/_proxy/oidc/callback?code=good-code&state={}", state)))
+        .header("Host", "localhost").header("Cookie", login_cookie.clone()).send();
+    assert_eq!(callback("forged").await.unwrap().status().as_u16(), 403);
+    let resp = callback(&params["state"]).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 302);
+    assert_eq!(resp.headers()["location"], "/dash?tab=2");
+    let session = cookie_of(&resp, "_rp_session");
+
+    let body = client.get(url("/dash")).header("Host", "localhost")
+        .header("X-Forwarded-User", "admin")
+        .header("Cookie", format!("theme=dark; {}", session))
+        .send().await.unwrap().text().await.unwrap();
+    assert_eq!(body, "user=user-1|email=ada@example.com|cookie=theme=dark");
+
+    // A tampered session is no session
+    let mut forged = session.clone();
+    forged.pop();
+    let resp = client.get(url("/dash")).header("Host", "localhost").header("Cookie", forged).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+
+    // Sessions live in the database, so another instance (or a restarted one) knows them
+    let other_port = serve(Arc::new(build())).await;
+    let resp = client.get(format!("http://127.0.0.1:{}/dash", other_port)).header("Host", "localhost")
+        .header("Cookie", session.clone()).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+
+    // Logging out revokes the session everywhere, not just in this browser
+    let resp = client.get(url("/_proxy/oidc/logout?rd=//evil.example")).header("Host", "localhost")
+        .header("Cookie", session.clone()).send().await.unwrap();
+    assert_eq!(resp.headers()["location"], "/");
+    assert!(cookie_of(&resp, "_rp_session").ends_with('='));
+    let resp = client.get(format!("http://127.0.0.1:{}/dash", other_port)).header("Host", "localhost")
+        .header("Cookie", session).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
 #[tokio::test]
 async fn test_password_auth_via_bearer() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("PASS_OK").await;
+    let backend_port = backend.port();
 
     let creds = r#"[{"pass":"mypassword"}]"#;
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     db.add_mapping("localhost", "", backend_port, "", None, None,
                    None, Some("password"), Some(creds)).unwrap();
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "PASS_OK").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let client = reqwest::Client::new();
 
@@ -559,8 +869,8 @@ async fn test_password_auth_via_bearer() {
 #[tokio::test]
 async fn test_auth_expiry() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let backend = Backend::tagged("SHOULD_NOT_REACH").await;
+    let backend_port = backend.port();
 
     // Token expired in the past
     let creds = r#"[{"token":"expired","expires_at":"2020-01-01T00:00:00Z"}]"#;
@@ -568,9 +878,7 @@ async fn test_auth_expiry() {
     db.add_mapping("localhost", "", backend_port, "", None, None,
                    None, Some("bearer"), Some(creds)).unwrap();
     drop(db);
-
-    let _backend = run_backend_server(backend_port, "SHOULD_NOT_REACH").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/test", proxy_port))
@@ -586,18 +894,16 @@ async fn test_auth_expiry() {
 #[tokio::test]
 async fn test_ha_round_robin_both_up() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let port1 = get_unique_port();
-    let port2 = get_unique_port();
+    let b1 = Backend::tagged("BACKEND1").await;
+    let port1 = b1.port();
+    let b2 = Backend::tagged("BACKEND2").await;
+    let port2 = b2.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     db.add_mapping("localhost", "", 0, "", None, Some(&format!("{},{}", port1, port2)),
                    None, None, None).unwrap();
     drop(db);
-
-    let _b1 = run_backend_server(port1, "BACKEND1").await;
-    let _b2 = run_backend_server(port2, "BACKEND2").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     let client = reqwest::Client::new();
     let mut saw_b1 = false;
@@ -618,17 +924,15 @@ async fn test_ha_round_robin_both_up() {
 #[tokio::test]
 async fn test_ha_failover_dead_port() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let port_dead = get_unique_port(); // nothing running on this port
-    let port_alive = get_unique_port();
+    let port_dead = free_port(); // nothing running on this port
+    let b2 = Backend::tagged("ALIVE").await;
+    let port_alive = b2.port();
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
     db.add_mapping("localhost", "", 0, "", None, Some(&format!("{},{}", port_dead, port_alive)),
                    None, None, None).unwrap();
     drop(db);
-
-    let _b2 = run_backend_server(port_alive, "ALIVE").await;
-    start_proxy(proxy_port, &dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
     // Should always get a response from the alive backend
     let client = reqwest::Client::new();
@@ -640,113 +944,2430 @@ async fn test_ha_failover_dead_port() {
     }
 }
 
-// ── Fallback / embedded-library tests ────────────────────────────────────────
+#[tokio::test]
+async fn test_webhooks_on_backend_and_mapping_events() {
+    let dir = tempdir().unwrap();
+    let received = Arc::new(parking_lot::Mutex::new(Vec::<(String, String)>::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+    let sink = received.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sink = sink.clone();
+            tokio::spawn(async move {
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                    let sink = sink.clone();
+                    async move {
+                        let signature = req.headers()["x-webhook-signature"].to_str().unwrap().to_string();
+                        let body = http_body_util::BodyExt::collect(req.into_body()).await.unwrap().to_bytes();
+                        sink.lock().push((signature, String::from_utf8_lossy(&body).into_owned()));
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::new())))
+                    }
+                })).await;
+            });
+        }
+    });
 
-/// A custom fallback that always returns 200 with a known body.
-struct HelloFallback;
+    let port_dead = free_port();
+    let b = Backend::tagged("ALIVE").await;
+    let port_alive = b.port();
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", 0, "", None, Some(&format!("{},{}", port_dead, port_alive)), None, None, None).unwrap();
+    let proxy = ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .webhook_urls(vec![hook_url])
+        .webhook_secret("hook-secret")
+        .build()
+        .unwrap();
+    let proxy_port = serve(Arc::new(proxy)).await;
 
-#[async_trait::async_trait]
-impl FallbackHandler for HelloFallback {
-    async fn handle(
-        &self,
-        _req: hyper::Request<hyper::body::Incoming>,
-        _remote_addr: std::net::SocketAddr,
-    ) -> Result<hyper::Response<http_body_util::Full<bytes::Bytes>>> {
-        Ok(hyper::Response::builder()
-            .status(200)
-            .body(http_body_util::Full::new(bytes::Bytes::from("hello from fallback")))
-            .unwrap())
+    // Round-robin reaches the dead port within two requests
+    for _ in 0..2 {
+        reqwest::Client::new().get(format!("http://127.0.0.1:{}/", proxy_port)).header("Host", "localhost").send().await.unwrap();
+    }
+    sleep(Duration::from_millis(500)).await;
+    let added = db.add_mapping("new.example.com", "", port_alive, "", None, None, None, None, None).unwrap();
+
+    let events = |name: &str| -> Vec<serde_json::Value> {
+        received.lock().iter()
+            .map(|(_, body)| serde_json::from_str::<serde_json::Value>(body).unwrap())
+            .filter(|e| e["event"] == name)
+            .collect()
+    };
+    for _ in 0..50 {
+        if !events("mapping_added").is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let down = events("backend_down");
+    assert_eq!(down.len(), 1, "{:?}", received.lock());
+    assert_eq!(down[0]["mapping_id"], m.id);
+    assert_eq!(down[0]["target"], format!("localhost:{}", port_dead));
+    assert_eq!(events("mapping_added")[0]["mapping_id"], added.id);
+    assert_eq!(events("mapping_added")[0]["domain"], "new.example.com");
+
+    for (signature, body) in received.lock().iter() {
+        assert_eq!(signature, &rustproxy::webhook::signature("hook-secret", body.as_bytes()));
     }
 }
 
-/// A fallback that always returns 500 — used to verify /health bypasses it.
-struct PanicFallback;
+#[tokio::test]
+async fn test_fallback_backend_on_dead_or_failing_primary() {
+    let dir = tempdir().unwrap();
+    let s = Backend::tagged("STANDBY").await;
+    let standby = s.port();
+    let failing = Backend::status(503, "DOWN").await;
+    let failing_port = failing.port();
 
-#[async_trait::async_trait]
-impl FallbackHandler for PanicFallback {
-    async fn handle(
-        &self,
-        _req: hyper::Request<hyper::body::Incoming>,
-        _remote_addr: std::net::SocketAddr,
-    ) -> Result<hyper::Response<http_body_util::Full<bytes::Bytes>>> {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(http_body_util::Full::new(bytes::Bytes::from("fallback error")))
-            .unwrap())
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let dead = db.add_mapping("dead.test", "api", free_port(), "v1", None, None, None, None, None).unwrap();
+    let erroring = db.add_mapping("failing.test", "", failing_port, "", None, None, None, None, None).unwrap();
+    for m in [&dead, &erroring] {
+        db.set_fallback_backend(&m.id, Some(&format!("127.0.0.1:{}", standby))).unwrap();
     }
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        let resp = client.post(format!("http://127.0.0.1:{}/api/items", proxy_port))
+            .header("Host", "dead.test").body("payload").send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        let body = resp.text().await.unwrap();
+        assert!(body.starts_with("STANDBY|path=/v1/items"), "{}", body);
+    }
+
+    let resp = client.get(format!("http://127.0.0.1:{}/", proxy_port))
+        .header("Host", "failing.test").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert!(resp.text().await.unwrap().starts_with("STANDBY"));
+}
+
+/// An HTTPS backend with a self-signed certificate for `backend.test`; returns its port and
+/// the certificate PEM.
+async fn run_tls_backend() -> (u16, String) {
+    let cert = rcgen::generate_simple_self_signed(vec!["backend.test".to_string()]).unwrap();
+    let pem = cert.serialize_pem().unwrap();
+    let chain = vec![rustls::pki_types::CertificateDer::from(cert.serialize_der().unwrap())];
+    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into());
+    let config = rustls::ServerConfig::builder().with_no_client_auth().with_single_cert(chain, key).unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(tls) = acceptor.accept(stream).await else { return };
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(tls), service_fn(|req: Request<Incoming>| async move {
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!("TLS|path={}", req.uri().path())))))
+                })).await;
+            });
+        }
+    });
+    (port, pem)
 }
 
 #[tokio::test]
-async fn test_fallback_called_when_no_mapping() {
+async fn test_https_backend_verification_settings() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-
-    let server = Arc::new(ProxyBuilder::new()
-        .db_path(dir.path().join("test.db"))
-        .certs_dir(dir.path().join("certs"))
-        .http_port(proxy_port)
-        .fallback(HelloFallback)
-        .build()
-        .unwrap());
+    let (port, pem) = run_tls_backend().await;
+    let ca = dir.path().join("ca.pem");
+    std::fs::write(&ca, &pem).unwrap();
+    let der = rustls_pemfile::certs(&mut pem.as_bytes()).next().unwrap().unwrap();
+    let pin = rustproxy::upstream_tls::spki_pin(&der).unwrap();
 
-    tokio::spawn(async move { let _ = server.run().await; });
-    sleep(Duration::from_millis(150)).await;
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let settings = [
+        ("public.test", None),
+        ("ca.test", Some(format!(r#"{{"ca":"{}","server_name":"backend.test"}}"#, ca.display()))),
+        ("wrongname.test", Some(format!(r#"{{"ca":"{}"}}"#, ca.display()))),
+        ("pinned.test", Some(format!(r#"{{"insecure":true,"pins":["{}"]}}"#, pin))),
+        ("badpin.test", Some(format!(r#"{{"insecure":true,"pins":["sha256//{}"]}}"#, "A".repeat(43) + "="))),
+        ("insecure.test", Some(r#"{"insecure":true}"#.to_string())),
+    ];
+    for (domain, tls) in &settings {
+        let m = db.add_mapping(domain, "", port, "", Some("https://127.0.0.1"), None, None, None, None).unwrap();
+        db.set_upstream_tls(&m.id, tls.as_deref()).unwrap();
+    }
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
 
-    let resp = reqwest::Client::new()
-        .get(format!("http://127.0.0.1:{}/anything", proxy_port))
-        .header("Host", "myapp.example.com")
-        .send().await.unwrap();
+    let client = reqwest::Client::new();
+    for (domain, expected) in [
+        ("public.test", 502),
+        ("ca.test", 200),
+        ("wrongname.test", 502),
+        ("pinned.test", 200),
+        ("badpin.test", 502),
+        ("insecure.test", 200),
+    ] {
+        let resp = client.get(format!("http://127.0.0.1:{}/hello", proxy_port))
+            .header("Host", domain).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), expected, "{}", domain);
+        if expected == 200 {
+            assert_eq!(resp.text().await.unwrap(), "TLS|path=/hello");
+        }
+    }
+}
 
-    assert_eq!(resp.status().as_u16(), 200);
-    assert_eq!(resp.text().await.unwrap(), "hello from fallback");
+/// An HTTP CONNECT proxy; returns its port and the number of tunnels it opened.
+async fn run_connect_proxy() -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let tunnels = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = tunnels.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut client = BufReader::new(stream);
+                let mut request_line = String::new();
+                client.read_line(&mut request_line).await.unwrap();
+                let mut line = String::new();
+                while client.read_line(&mut line).await.unwrap() > 2 {
+                    line.clear();
+                }
+                let target = request_line.split_whitespace().nth(1).unwrap().to_string();
+                let mut upstream = tokio::net::TcpStream::connect(&target).await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                client.get_mut().write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+                let _ = tokio::io::copy_bidirectional(client.get_mut(), &mut upstream).await;
+            });
+        }
+    });
+    (port, tunnels)
 }
 
 #[tokio::test]
-async fn test_proxy_wins_over_fallback() {
+async fn test_backend_reached_through_egress_proxy() {
     let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
-    let backend_port = get_unique_port();
+    let b = Backend::tagged("EGRESS").await;
+    let backend = b.port();
+    let (egress, tunnels) = run_connect_proxy().await;
 
     let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
-    add(&db, "localhost", "", backend_port, "");
+    let own = db.add_mapping("own.test", "", backend, "", None, None, None, None, None).unwrap();
+    db.set_egress_proxy(&own.id, Some(&format!("http://127.0.0.1:{}", egress))).unwrap();
+    let direct = db.add_mapping("direct.test", "", backend, "", None, None, None, None, None).unwrap();
+    db.set_egress_proxy(&direct.id, Some("direct")).unwrap();
+    add(&db, "global.test", "", backend, "");
     drop(db);
 
-    let _backend = run_backend_server(backend_port, "PROXIED").await;
-
-    let server = Arc::new(ProxyBuilder::new()
+    // The server-wide egress proxy is unreachable: only its mappings fail
+    let proxy = ProxyBuilder::new()
         .db_path(dir.path().join("test.db"))
         .certs_dir(dir.path().join("certs"))
-        .http_port(proxy_port)
-        .fallback(HelloFallback) // would return "hello from fallback" if called
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .egress_proxy(format!("socks5://127.0.0.1:{}", free_port()).parse().unwrap())
         .build()
-        .unwrap());
-
-    tokio::spawn(async move { let _ = server.run().await; });
-    sleep(Duration::from_millis(150)).await;
+        .unwrap();
+    let proxy_port = serve(Arc::new(proxy)).await;
 
-    let body = reqwest::Client::new()
-        .get(format!("http://127.0.0.1:{}/test", proxy_port))
-        .header("Host", "localhost")
-        .send().await.unwrap().text().await.unwrap();
+    let client = reqwest::Client::new();
+    let get = |host: &'static str| client.get(format!("http://127.0.0.1:{}/via", proxy_port)).header("Host", host).send();
+    let resp = get("own.test").await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert!(resp.text().await.unwrap().starts_with("EGRESS|path=/via"));
+    assert_eq!(tunnels.load(std::sync::atomic::Ordering::SeqCst), 1);
 
-    assert!(body.contains("PROXIED"), "expected proxy response, got: {}", body);
+    assert_eq!(get("direct.test").await.unwrap().status().as_u16(), 200);
+    assert_eq!(get("global.test").await.unwrap().status().as_u16(), 502);
+    assert_eq!(tunnels.load(std::sync::atomic::Ordering::SeqCst), 1);
 }
 
 #[tokio::test]
-async fn test_health_check_bypasses_fallback() {
-    let dir = tempdir().unwrap();
-    let proxy_port = get_unique_port();
+async fn test_forward_proxy_connect_tunnels() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    // A TCP echo server to tunnel to
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = echo.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
 
-    let server = Arc::new(ProxyBuilder::new()
+    let dir = tempdir().unwrap();
+    let forward_addr: std::net::SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let acl = rustproxy::forward_proxy::Acl {
+        clients: vec!["127.0.0.0/8".parse().unwrap()],
+        destinations: vec![format!("127.0.0.1:{}", echo_port).parse().unwrap()],
+    };
+    let proxy = ProxyBuilder::new()
         .db_path(dir.path().join("test.db"))
         .certs_dir(dir.path().join("certs"))
-        .http_port(proxy_port)
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .forward_proxy(forward_addr, acl)
+        .build()
+        .unwrap();
+    serve(Arc::new(proxy)).await;
+    test_util::listening(forward_addr).await;
+
+    async fn connect(addr: std::net::SocketAddr, request: String) -> (tokio::net::TcpStream, String) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        (stream, String::from_utf8(head).unwrap())
+    }
+
+    let target = format!("127.0.0.1:{}", echo_port);
+    let (mut tunnel, head) = connect(forward_addr, format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target)).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    tunnel.write_all(b"ping through the tunnel").await.unwrap();
+    let mut echoed = [0u8; 23];
+    tunnel.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping through the tunnel");
+
+    // Other destinations and other methods are refused
+    let other = format!("127.0.0.1:{}", free_port());
+    let (_, head) = connect(forward_addr, format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", other, other)).await;
+    assert!(head.starts_with("HTTP/1.1 403"), "{}", head);
+    let (_, head) = connect(forward_addr, format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", target, target)).await;
+    assert!(head.starts_with("HTTP/1.1 405") && head.to_ascii_lowercase().contains("allow: connect"), "{}", head);
+}
+
+/// A FastCGI responder echoing a few CGI variables and the body, in two STDOUT records.
+async fn run_fastcgi_backend() -> u16 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+        let mut r = vec![1, kind, 0, 1];
+        r.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        r.extend_from_slice(&[0, 0]);
+        r.extend_from_slice(content);
+        r
+    }
+    fn length(buf: &[u8], i: &mut usize) -> usize {
+        if buf[*i] < 128 {
+            *i += 1;
+            buf[*i - 1] as usize
+        } else {
+            *i += 4;
+            (u32::from_be_bytes(buf[*i - 4..*i].try_into().unwrap()) & 0x7fff_ffff) as usize
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut params, mut stdin) = (Vec::new(), Vec::new());
+                loop {
+                    let mut h = [0u8; 8];
+                    s.read_exact(&mut h).await.unwrap();
+                    let len = u16::from_be_bytes([h[4], h[5]]) as usize;
+                    let mut content = vec![0u8; len + h[6] as usize];
+                    s.read_exact(&mut content).await.unwrap();
+                    content.truncate(len);
+                    match h[1] {
+                        4 => params.extend(content),
+                        5 if len == 0 => break,
+                        5 => stdin.extend(content),
+                        _ => {}
+                    }
+                }
+                let mut vars = std::collections::HashMap::new();
+                let mut i = 0;
+                while i < params.len() {
+                    let (n, v) = (length(&params, &mut i), length(&params, &mut i));
+                    let name = String::from_utf8_lossy(&params[i..i + n]).into_owned();
+                    vars.insert(name, String::from_utf8_lossy(&params[i + n..i + n + v]).into_owned());
+                    i += n + v;
+                }
+                let var = |k: &str| vars.get(k).cloned().unwrap_or_default();
+                let head = format!("Status: 201 Created\r\nContent-Type: text/plain\r\nX-Script: {}\r\n\r\n", var("SCRIPT_FILENAME"));
+                let body = format!("info={}|query={}|method={}|token={}|body={}",
+                    var("PATH_INFO"), var("QUERY_STRING"), var("REQUEST_METHOD"), var("HTTP_X_TOKEN"), String::from_utf8_lossy(&stdin));
+                let mut out = record(6, head.as_bytes());
+                out.extend(record(6, body.as_bytes()));
+                out.extend(record(6, b""));
+                out.extend(record(3, &[0; 8]));
+                s.write_all(&out).await.unwrap();
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_fastcgi_backend_runs_scripts_and_serves_static_files() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("www");
+    std::fs::create_dir_all(root.join("css")).unwrap();
+    std::fs::write(root.join("css/site.css"), "body{}").unwrap();
+    let fcgi_port = run_fastcgi_backend().await;
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("php.test", "", fcgi_port, "", Some("fcgi://127.0.0.1"), None, None, None, None).unwrap();
+    db.set_document_root(&m.id, Some(root.to_str().unwrap())).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let resp = client.post(format!("http://127.0.0.1:{}/api.php/users/7?page=2", proxy_port))
+        .header("Host", "php.test").header("X-Token", "t1").body("name=x").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 201);
+    assert_eq!(resp.headers()["x-script"], format!("{}/api.php", root.display()));
+    assert_eq!(resp.text().await.unwrap(), "info=/users/7|query=page=2|method=POST|token=t1|body=name=x");
+
+    // Front controller for paths without a script
+    let resp = client.get(format!("http://127.0.0.1:{}/users", proxy_port)).header("Host", "php.test").send().await.unwrap();
+    assert_eq!(resp.headers()["x-script"], format!("{}/index.php", root.display()));
+
+    let resp = client.get(format!("http://127.0.0.1:{}/css/site.css", proxy_port)).header("Host", "php.test").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/css; charset=utf-8");
+    assert!(resp.headers().get("vary").is_none());
+    assert_eq!(resp.text().await.unwrap(), "body{}");
+
+    // Pre-compressed copies for clients that take them, the original for others
+    std::fs::write(root.join("css/site.css.br"), "brotli bytes").unwrap();
+    std::fs::write(root.join("css/site.css.gz"), "gzip bytes").unwrap();
+    let get = |accept: &'static str| client.get(format!("http://127.0.0.1:{}/css/site.css", proxy_port))
+        .header("Host", "php.test").header("Accept-Encoding", accept).send();
+    let resp = get("gzip, br").await.unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "br");
+    assert_eq!(resp.headers()["content-type"], "text/css; charset=utf-8");
+    assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+    assert_eq!(resp.text().await.unwrap(), "brotli bytes");
+    let resp = get("gzip").await.unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.text().await.unwrap(), "gzip bytes");
+    let resp = get("identity").await.unwrap();
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+    assert_eq!(resp.text().await.unwrap(), "body{}");
+}
+
+/// Serves `key_authorization` for any http-01 token
+async fn run_challenge_server(key_authorization: Arc<parking_lot::Mutex<String>>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let key_authorization = key_authorization.clone();
+            tokio::spawn(async move {
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |_req: Request<Incoming>| {
+                    let body = key_authorization.lock().clone();
+                    async move { Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body)))) }
+                })).await;
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_internal_acme_server_issues_certificates() {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine as _};
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::{json, Value};
+
+    let dir = tempdir().unwrap();
+    let key_authorization = Arc::new(parking_lot::Mutex::new(String::new()));
+    let challenge_port = run_challenge_server(key_authorization.clone()).await;
+    let proxy = ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .acme_server(true)
+        .acme_server_domains(vec!["localhost".to_string()])
+        .acme_server_challenge_port(challenge_port)
+        .build()
+        .unwrap();
+    let proxy_port = serve(Arc::new(proxy)).await;
+    let base = format!("http://127.0.0.1:{}/_proxy/acme/", proxy_port);
+
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let point = key.public_key().as_ref();
+    let jwk = json!({ "kty": "EC", "crv": "P-256", "x": B64.encode(&point[1..33]), "y": B64.encode(&point[33..]) });
+    let thumbprint = {
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap());
+        B64.encode(ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes()))
+    };
+
+    let client = reqwest::Client::new();
+    let directory: Value = serde_json::from_str(&client.get(format!("{}directory", base)).send().await.unwrap().text().await.unwrap()).unwrap();
+    assert_eq!(directory["newAccount"], format!("{}new-account", base));
+
+    let kid = Arc::new(parking_lot::Mutex::new(None::<String>));
+    // POST a JWS signed with the account key; `None` payload is POST-as-GET
+    let post = |url: String, payload: Option<Value>| {
+        let (client, key, rng, jwk, kid, base) = (client.clone(), &key, &rng, jwk.clone(), kid.clone(), base.clone());
+        async move {
+            let nonce = client.head(format!("{}new-nonce", base)).send().await.unwrap()
+                .headers()["replay-nonce"].to_str().unwrap().to_string();
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match kid.lock().clone() {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = jwk,
+            }
+            let protected = B64.encode(protected.to_string());
+            let payload = payload.map(|p| B64.encode(p.to_string())).unwrap_or_default();
+            let signature = key.sign(rng, format!("{}.{}", protected, payload).as_bytes()).unwrap();
+            let body = json!({ "protected": protected, "payload": payload, "signature": B64.encode(signature) });
+            let resp = client.post(&url).header("Content-Type", "application/jose+json").body(body.to_string()).send().await.unwrap();
+            let status = resp.status().as_u16();
+            let location = resp.headers().get("location").map(|l| l.to_str().unwrap().to_string());
+            (status, location, resp.text().await.unwrap())
+        }
+    };
+
+    let (status, location, _) = post(format!("{}new-account", base), Some(json!({ "termsOfServiceAgreed": true }))).await;
+    assert_eq!(status, 201);
+    *kid.lock() = location;
+
+    // Outside the allowed suffixes
+    let (status, _, body) = post(format!("{}new-order", base), Some(json!({ "identifiers": [{ "type": "dns", "value": "example.com" }] }))).await;
+    assert_eq!(status, 400);
+    assert!(body.contains("rejectedIdentifier"), "{}", body);
+
+    let (status, order_url, body) = post(format!("{}new-order", base), Some(json!({ "identifiers": [{ "type": "dns", "value": "localhost" }] }))).await;
+    assert_eq!(status, 201);
+    let order: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(order["status"], "pending");
+
+    // Finalizing before the challenge passed
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+    params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+    let subject = rcgen::Certificate::from_params(params).unwrap();
+    let csr = B64.encode(subject.serialize_request_der().unwrap());
+    let finalize = order["finalize"].as_str().unwrap().to_string();
+    let (status, _, body) = post(finalize.clone(), Some(json!({ "csr": csr }))).await;
+    assert_eq!(status, 403);
+    assert!(body.contains("orderNotReady"), "{}", body);
+
+    let authz_url = order["authorizations"][0].as_str().unwrap().to_string();
+    let (_, _, body) = post(authz_url.clone(), None).await;
+    let authz: Value = serde_json::from_str(&body).unwrap();
+    let challenge = &authz["challenges"][0];
+    assert_eq!(challenge["type"], "http-01");
+    *key_authorization.lock() = format!("{}.{}", challenge["token"].as_str().unwrap(), thumbprint);
+    post(challenge["url"].as_str().unwrap().to_string(), Some(json!({}))).await;
+
+    let mut valid = false;
+    for _ in 0..50 {
+        let (_, _, body) = post(authz_url.clone(), None).await;
+        if serde_json::from_str::<Value>(&body).unwrap()["status"] == "valid" {
+            valid = true;
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(valid, "http-01 challenge not validated");
+
+    let (status, _, body) = post(finalize, Some(json!({ "csr": csr }))).await;
+    assert_eq!(status, 200, "{}", body);
+    let order: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(order["status"], "valid");
+    let (_, _, body) = post(order_url.unwrap(), None).await;
+    let cert_url = serde_json::from_str::<Value>(&body).unwrap()["certificate"].as_str().unwrap().to_string();
+    let (status, _, chain) = post(cert_url, None).await;
+    assert_eq!(status, 200);
+
+    // The chain verifies for localhost against the published CA certificate
+    use rustls::client::danger::ServerCertVerifier;
+    let ca_pem = client.get(format!("{}ca.pem", base)).send().await.unwrap().text().await.unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut ca_pem.as_bytes()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let verifier = rustls::client::WebPkiServerVerifier::builder(Arc::new(roots)).build().unwrap();
+    let chain: Vec<_> = rustls_pemfile::certs(&mut chain.as_bytes()).map(|c| c.unwrap()).collect();
+    assert_eq!(chain.len(), 2);
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    verifier.verify_server_cert(&chain[0], &chain[1..], &name, &[], rustls::pki_types::UnixTime::now()).unwrap();
+}
+
+#[tokio::test]
+async fn test_outlier_ejected_and_shown_in_status() {
+    let dir = tempdir().unwrap();
+    let b1 = Backend::tagged("GOOD").await;
+    let good1 = b1.port();
+    let b2 = Backend::tagged("GOOD").await;
+    let good2 = b2.port();
+    let bad_backend = Backend::status(500, "DOWN").await;
+    let bad = bad_backend.port();
+
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    db.add_mapping("localhost", "", 0, "", None, Some(&format!("{},{},{}", good1, good2, bad)),
+                   None, None, None).unwrap();
+    let config = ProxyConfig {
+        http_port: 0,
+        admin_token: Some("s3cret".to_string()),
+        outlier_interval: Duration::from_millis(300),
+        ..ProxyConfig::default()
+    };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let get = || client.get(format!("http://127.0.0.1:{}/", proxy_port)).header("Host", "localhost").send();
+    let status = || async {
+        let body = client.get(format!("http://127.0.0.1:{}/_proxy/admin/status", proxy_port))
+            .bearer_auth("s3cret").send().await.unwrap().text().await.unwrap();
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()
+    };
+
+    // Round-robin keeps hitting the failing target until an interval judges it
+    let mut ejected = false;
+    for _ in 0..20 {
+        for _ in 0..45 {
+            get().await.unwrap();
+        }
+        sleep(Duration::from_millis(150)).await;
+        if !status().await["ejections"].as_array().unwrap().is_empty() {
+            ejected = true;
+            break;
+        }
+    }
+    assert!(ejected, "failing target was never ejected");
+
+    for _ in 0..10 {
+        assert_eq!(get().await.unwrap().status().as_u16(), 200);
+    }
+    let status = status().await;
+    let ejections = status["ejections"].as_array().unwrap();
+    assert_eq!(ejections.len(), 1, "{}", status);
+    assert!(ejections[0]["target"].as_str().unwrap().ends_with(&format!(":{}", bad)));
+    assert!(ejections[0]["reason"].as_str().unwrap().starts_with("error rate"));
+}
+
+#[tokio::test]
+async fn test_admin_resets_breakers() {
+    let dir = tempdir().unwrap();
+    let port_dead = free_port();
+    let b2 = Backend::tagged("ALIVE").await;
+    let port_alive = b2.port();
+
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    db.add_mapping("localhost", "", 0, "", None, Some(&format!("{},{}", port_dead, port_alive)),
+                   None, None, None).unwrap();
+
+    let config = ProxyConfig {
+        http_port: 0,
+        admin_token: Some("s3cret".to_string()),
+        ..ProxyConfig::default()
+    };
+    let proxy = Arc::new(ProxyServer::new(
+        config,
+        db,
+        Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+    ));
+    let proxy_port = serve(proxy).await;
+
+    let client = reqwest::Client::new();
+    for _ in 0..2 {
+        client.get(format!("http://127.0.0.1:{}/", proxy_port)).header("Host", "localhost").send().await.unwrap();
+    }
+
+    let admin = |op: &str| client.post(format!("http://127.0.0.1:{}/_proxy/admin/{}", proxy_port, op));
+    assert_eq!(admin("breakers/reset").send().await.unwrap().status().as_u16(), 401);
+    assert_eq!(admin("breakers/reset").bearer_auth("wrong").send().await.unwrap().status().as_u16(), 401);
+
+    let resp = admin("breakers/reset?backend=nowhere:1").bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), r#"{"cleared":0,"operation":"breakers/reset"}"#);
+
+    let resp = admin(&format!("breakers/reset?backend={}", port_dead)).bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.text().await.unwrap(), r#"{"cleared":1,"operation":"breakers/reset"}"#);
+
+    let resp = admin("dns/flush").bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let resp = admin("cache/purge").bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), r#"{"cleared":0,"operation":"cache/purge"}"#);
+}
+
+#[tokio::test]
+async fn test_stale_if_error_serves_last_good_response() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let dir = tempdir().unwrap();
+    let up = Arc::new(AtomicBool::new(true));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let flag = up.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let flag = flag.clone();
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                let up = flag.load(Ordering::SeqCst);
+                async move {
+                    let (status, body) = if up { (200, format!("fresh {}", req.uri())) } else { (503, "down".to_string()) };
+                    Ok::<_, Infallible>(Response::builder().status(status).body(Full::new(Bytes::from(body))).unwrap())
+                }
+            })));
+        }
+    });
+
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_stale_if_error(&m.id, Some(60)).unwrap();
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path)).header("Host", "localhost").send();
+    assert_eq!(get("/page?v=1").await.unwrap().text().await.unwrap(), "fresh /page?v=1");
+
+    up.store(false, Ordering::SeqCst);
+    let resp = get("/page?v=1").await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert!(resp.headers()["warning"].to_str().unwrap().starts_with("110"));
+    assert!(resp.headers().contains_key("age"));
+    assert_eq!(resp.text().await.unwrap(), "fresh /page?v=1");
+    assert_eq!(get("/other").await.unwrap().status().as_u16(), 503);
+
+    let resp = client.post(format!("http://127.0.0.1:{}/_proxy/admin/cache/purge?prefix=/page", proxy_port))
+        .bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), r#"{"cleared":1,"operation":"cache/purge"}"#);
+    assert_eq!(get("/page?v=1").await.unwrap().status().as_u16(), 503);
+}
+
+#[tokio::test]
+async fn test_stale_if_error_varies_and_lists_entries() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let dir = tempdir().unwrap();
+    let up = Arc::new(AtomicBool::new(true));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let flag = up.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let flag = flag.clone();
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                let up = flag.load(Ordering::SeqCst);
+                let lang = req.headers().get("accept-language").map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+                async move {
+                    let resp = match up {
+                        true => Response::builder().header("vary", "Accept-Language").body(Full::new(Bytes::from(format!("hello {}", lang)))),
+                        false => Response::builder().status(502).body(Full::new(Bytes::from("down"))),
+                    };
+                    Ok::<_, Infallible>(resp.unwrap())
+                }
+            })));
+        }
+    });
+
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_stale_if_error(&m.id, Some(60)).unwrap();
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let get = |lang: &str| client.get(format!("http://127.0.0.1:{}/greeting", proxy_port))
+        .header("Host", "localhost").header("Accept-Language", lang).send();
+    assert_eq!(get("en").await.unwrap().text().await.unwrap(), "hello en");
+    assert_eq!(get("nl-NL").await.unwrap().text().await.unwrap(), "hello nl-NL");
+
+    up.store(false, Ordering::SeqCst);
+    assert_eq!(get("nl-NL").await.unwrap().text().await.unwrap(), "hello nl-NL");
+    assert_eq!(get("en").await.unwrap().text().await.unwrap(), "hello en");
+    assert_eq!(get("de").await.unwrap().status().as_u16(), 502);
+
+    let admin = |op: &str| client.get(format!("http://127.0.0.1:{}/_proxy/admin/{}", proxy_port, op)).bearer_auth("s3cret").send();
+    let status: serde_json::Value = serde_json::from_str(&admin("status").await.unwrap().text().await.unwrap()).unwrap();
+    let counters = &status["cache"]["mappings"][&m.id];
+    assert_eq!((counters["stores"].as_u64(), counters["hits"].as_u64(), counters["misses"].as_u64()), (Some(2), Some(2), Some(1)));
+    assert_eq!(status["cache"]["entries"], 2);
+
+    let listed: serde_json::Value = serde_json::from_str(&admin("cache/entries?sort=size&limit=1").await.unwrap().text().await.unwrap()).unwrap();
+    let entries = listed["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["key"], "localhost/greeting");
+    assert_eq!(entries[0]["variant"], "accept-language=nl-NL");
+    assert_eq!(admin("cache/entries?sort=hits").await.unwrap().status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn test_upstream_connections_pooled_per_mapping() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let dir = tempdir().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let count = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            count.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|_req: Request<Incoming>| async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("ok"))))
+            })));
+        }
+    });
+
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "api.test", "", backend_port, "");
+    let admin_route = db.add_mapping("admin.test", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_pool(&admin_route.id, Some("max_idle=0")).unwrap();
+    let limited = db.add_mapping("limited.test", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_pool(&limited.id, Some("max_connections=2")).unwrap();
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let get = |host: &'static str| client.get(format!("http://127.0.0.1:{}/", proxy_port)).header("Host", host).send();
+
+    // Kept alive and reused
+    for _ in 0..5 {
+        assert_eq!(get("api.test").await.unwrap().text().await.unwrap(), "ok");
+    }
+    assert_eq!(accepted.swap(0, Ordering::SeqCst), 1);
+
+    // max_idle=0: a connection per request
+    for _ in 0..3 {
+        assert_eq!(get("admin.test").await.unwrap().status().as_u16(), 200);
+    }
+    assert_eq!(accepted.swap(0, Ordering::SeqCst), 3);
+
+    // max_connections=2: concurrent requests queue for the two connections
+    let all = futures_util::future::join_all((0..6).map(|_| get("limited.test"))).await;
+    assert!(all.iter().all(|r| r.as_ref().unwrap().status().as_u16() == 200));
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    let status = client.get(format!("http://127.0.0.1:{}/_proxy/admin/status", proxy_port)).bearer_auth("s3cret").send().await.unwrap();
+    let status: serde_json::Value = serde_json::from_str(&status.text().await.unwrap()).unwrap();
+    let pool = &status["pools"][format!("{}|localhost:{}", limited.id, backend_port)];
+    assert_eq!((pool["idle"].as_u64(), pool["open"].as_u64(), pool["max_connections"].as_u64()), (Some(2), Some(2), Some(2)), "{}", status);
+}
+
+#[tokio::test]
+async fn test_admin_match_dry_run() {
+    let dir = tempdir().unwrap();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "api.test", "api", 3000, "v1");
+    add(&db, "api.test", "", 4000, "");
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/_proxy/admin/match", proxy_port);
+    let dry_run = |body: &'static str| client.post(&url).bearer_auth("s3cret").body(body).send();
+
+    let resp = dry_run(r#"{"host": "API.test:443", "path": "/api//users?page=2", "method": "DELETE", "headers": {"x-debug": "1"}}"#).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let result: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(result["matched"], true);
+    assert_eq!((result["host"].as_str(), result["path"].as_str(), result["method"].as_str()), (Some("api.test"), Some("/api/users"), Some("DELETE")));
+    assert_eq!(result["mapping"]["front_uri"], "api");
+    assert_eq!(result["upstream_path"], "/v1/users?page=2");
+    assert_eq!(result["backend_url"], "http://localhost:3000/v1/users?page=2");
+
+    let result: serde_json::Value = serde_json::from_str(&dry_run(r#"{"host": "api.test", "path": "/"}"#).await.unwrap().text().await.unwrap()).unwrap();
+    assert_eq!(result["backend_url"], "http://localhost:4000/");
+    let result: serde_json::Value = serde_json::from_str(&dry_run(r#"{"host": "www.test", "path": "/"}"#).await.unwrap().text().await.unwrap()).unwrap();
+    assert_eq!(result["matched"], false);
+
+    assert_eq!(dry_run(r#"{"path": "/"}"#).await.unwrap().status().as_u16(), 400);
+    assert_eq!(client.get(&url).bearer_auth("s3cret").send().await.unwrap().status().as_u16(), 405);
+    assert_eq!(client.post(&url).body("{}").send().await.unwrap().status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_admin_batch_applies_all_or_nothing() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("BATCHED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "old.test", "", backend_port, "");
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/_proxy/admin/mappings:batch", proxy_port);
+    let get = |host: &'static str| client.get(format!("http://127.0.0.1:{}/", proxy_port)).header("Host", host).send();
+    assert_eq!(get("old.test").await.unwrap().status().as_u16(), 200);
+
+    let batch = serde_json::json!({"changes": [
+        {"op": "add", "mapping": {"domain": "new.test", "back_port": backend_port}},
+        {"op": "delete", "domain": "old.test"},
+    ]});
+    let resp = client.put(&url).bearer_auth("s3cret").body(batch.to_string()).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let result: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(result["applied"][0]["op"], "add");
+    assert!(get("new.test").await.unwrap().text().await.unwrap().starts_with("BATCHED|"));
+    assert_eq!(get("old.test").await.unwrap().status().as_u16(), 404);
+
+    // The second change can't apply, so the first doesn't either
+    let batch = serde_json::json!({"changes": [
+        {"op": "delete", "domain": "new.test"},
+        {"op": "update", "domain": "old.test", "version": 1, "set": {"back_port": 1}},
+    ]});
+    let resp = client.put(&url).bearer_auth("s3cret").body(batch.to_string()).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 409);
+    let result: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!((result["error"]["index"].as_u64(), result["error"]["reason"].as_str()), (Some(1), Some("no such mapping")));
+    assert_eq!(get("new.test").await.unwrap().status().as_u16(), 200);
+
+    // An update made against an older version is refused
+    let id = db.list_mappings(Some("new.test")).unwrap()[0].id.clone();
+    db.set_rate_limit(&id, Some("100/1m")).unwrap();
+    let stale = serde_json::json!({"changes": [{"op": "update", "id": id, "version": 1, "set": {"back_port": 1}}]});
+    let resp = client.put(&url).bearer_auth("s3cret").body(stale.to_string()).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 409);
+    assert!(resp.text().await.unwrap().contains("it is at version 2"));
+
+    assert_eq!(client.put(&url).bearer_auth("s3cret").body(r#"{"changes": [{"op": "move"}]}"#).send().await.unwrap().status().as_u16(), 400);
+    assert_eq!(client.post(&url).bearer_auth("s3cret").body(batch.to_string()).send().await.unwrap().status().as_u16(), 405);
+    assert_eq!(client.put(&url).body(batch.to_string()).send().await.unwrap().status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_admin_snapshot() {
+    let dir = tempdir().unwrap();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "api.test", "api", 3000, "v1");
+    let id = db.list_mappings(None).unwrap()[0].id.clone();
+    db.set_url_signing_secret(&id, Some("signing-key")).unwrap();
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let base: hyper::Uri = format!("http://127.0.0.1:{}", proxy_port).parse().unwrap();
+    let text = rustproxy::snapshot::fetch(&base, "s3cret").await.unwrap();
+    assert!(!text.contains("s3cret") && !text.contains("signing-key"));
+    let snapshot: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(snapshot["config"]["admin_token"], "***");
+    assert_eq!(snapshot["mappings"][0]["domain"], "api.test");
+    assert_eq!(snapshot["mappings"][0]["url_signing_secret"], "***");
+    assert!(snapshot["certificates"].as_array().unwrap().iter().any(|c| c["name"] == "localhost"));
+    assert!(snapshot["backends"]["scores"].is_object());
+
+    assert!(rustproxy::snapshot::fetch(&base, "wrong").await.unwrap_err().to_string().contains("401"));
+}
+
+#[tokio::test]
+async fn test_loop_back_to_proxy_detected() {
+    let dir = tempdir().unwrap();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let config = ProxyConfig { http_port: 0, max_proxy_hops: 3, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+    // The mapping's backend is the proxy itself
+    add(&db, "localhost", "", proxy_port, "");
+
+    let resp = reqwest::get(format!("http://localhost:{}/spin", proxy_port)).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 508);
+
+    // A request that arrives having passed too many proxies is refused at once
+    let resp = reqwest::Client::new()
+        .get(format!("http://localhost:{}/spin", proxy_port))
+        .header("Via", "1.1 rustproxy, 1.1 rustproxy, 1.1 rustproxy")
+        .send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 508);
+}
+
+#[tokio::test]
+async fn test_usage_counted_and_quota_enforced() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("METERED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    let id = db.list_mappings(None).unwrap()[0].id.clone();
+    db.set_quota(&id, Some("requests=2")).unwrap();
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/data", proxy_port);
+    for _ in 0..2 {
+        let resp = client.post(&url).body("12345").send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert!(resp.text().await.unwrap().contains("METERED"));
+    }
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 429);
+    assert!(resp.headers().get("retry-after").is_some());
+
+    // The refused request is not counted
+    let resp = client.get(format!("http://localhost:{}/_proxy/admin/usage", proxy_port)).bearer_auth("s3cret").send().await.unwrap();
+    let report: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    let row = &report["mappings"][0];
+    assert_eq!((row["mapping_id"].as_str(), row["requests"].as_u64(), row["bytes_in"].as_u64()), (Some(id.as_str()), Some(2), Some(10)));
+    assert!(row["bytes_out"].as_u64().unwrap() > 0);
+    assert_eq!(row["quota"], "requests=2");
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_tenant_token_scoped_to_own_mappings() {
+    let dir = tempdir().unwrap();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let (_, acme_token) = db.add_tenant("acme", "Acme").unwrap();
+    db.add_tenant("globex", "Globex").unwrap();
+    add(&db, "shop.acme.test", "", 3000, "");
+    add(&db, "globex.test", "", 3001, "");
+    add(&db, "ops.test", "", 3002, "");
+    let mappings = db.list_mappings(None).unwrap();
+    let id = |domain: &str| mappings.iter().find(|m| m.domain == domain).unwrap().id.clone();
+    db.set_owner(&id("shop.acme.test"), Some("acme")).unwrap();
+    db.set_owner(&id("globex.test"), Some("globex")).unwrap();
+    db.set_url_signing_secret(&id("shop.acme.test"), Some("hush")).unwrap();
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let client = reqwest::Client::new();
+    let admin = |op: &str| format!("http://localhost:{}/_proxy/admin/{}", proxy_port, op);
+    let domains = |body: &str| -> Vec<String> {
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        json["mappings"].as_array().unwrap().iter().map(|m| m["domain"].as_str().unwrap().to_string()).collect()
+    };
+
+    let resp = client.get(admin("mappings")).bearer_auth(&acme_token).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let body = resp.text().await.unwrap();
+    assert_eq!(domains(&body), ["shop.acme.test"]);
+    assert!(!body.contains("hush"));
+    let resp = client.get(admin("mappings")).bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(domains(&resp.text().await.unwrap()).len(), 3);
+
+    let reset = |mapping: String| admin(&format!("breakers/reset?mapping={}", mapping));
+    let resp = client.post(reset(id("shop.acme.test"))).bearer_auth(&acme_token).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    for forbidden in [
+        client.post(reset(id("globex.test"))),
+        client.post(admin("breakers/reset")),
+        client.post(admin("cache/purge")),
+        client.get(admin("snapshot")),
+    ] {
+        assert_eq!(forbidden.bearer_auth(&acme_token).send().await.unwrap().status().as_u16(), 403);
+    }
+    let resp = client.get(admin("mappings")).bearer_auth("rpt_unknown").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_unverified_tenant_domain_not_routed() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("CLAIMED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    db.add_tenant("acme", "Acme").unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    add(&db, "localhost", "ops", backend_port, "");
+    let shop = db.find_by_domain_and_uri("localhost", "").unwrap().unwrap();
+    db.set_owner(&shop.id, Some("acme")).unwrap();
+    let config = ProxyConfig { http_port: 0, require_domain_verification: true, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+
+    // The operator's own mapping needs no proof
+    let resp = reqwest::get(format!("http://localhost:{}/ops/x", proxy_port)).await.unwrap();
+    assert!(resp.text().await.unwrap().contains("CLAIMED"));
+    let resp = reqwest::get(format!("http://localhost:{}/", proxy_port)).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+
+    let claim = db.start_domain_verification("localhost", "acme").unwrap();
+    db.mark_domain_verified(&claim.domain, "acme").unwrap();
+    let resp = reqwest::get(format!("http://localhost:{}/", proxy_port)).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert!(resp.text().await.unwrap().contains("CLAIMED"));
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_rate_limit_offender_banned_across_restart() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("BANNED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    let config = ProxyConfig {
+        http_port: 0,
+        rate_limit: Some("2/1m".parse().unwrap()),
+        rate_limit_ban: Some("strikes=2,duration=1h".parse().unwrap()),
+        ..ProxyConfig::default()
+    };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config.clone(), db.clone(), certs.clone()))).await;
+
+    // A new X-Forwarded-For each time doesn't make a new client
+    let url = format!("http://localhost:{}/", proxy_port);
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..5 {
+        let resp = client.get(&url).header("X-Forwarded-For", format!("203.0.113.{}", i)).send().await.unwrap();
+        statuses.push(resp.status().as_u16());
+    }
+    assert_eq!(statuses, [200, 200, 429, 429, 403]);
+    let bans = db.list_bans().unwrap();
+    assert_eq!((bans.len(), bans[0].network.as_str(), bans[0].source.as_str()), (1, "127.0.0.1", "rate_limit"));
+
+    // A fresh instance loads the ban; a forwarded-for address doesn't get around the peer's
+    let proxy_port = serve(Arc::new(ProxyServer::new(config.clone(), db.clone(), certs.clone()))).await;
+    let resp = reqwest::Client::new()
+        .get(format!("http://localhost:{}/", proxy_port))
+        .header("X-Forwarded-For", "203.0.113.9")
+        .send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 403);
+
+    assert_eq!(db.delete_ban(&bans[0].network).unwrap(), 1);
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+    let resp = reqwest::get(format!("http://localhost:{}/", proxy_port)).await.unwrap();
+    assert!(resp.text().await.unwrap().contains("BANNED"));
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_trusted_proxy_forwarded_for_names_client() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("BEHIND_LB").await;
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend.port(), "");
+    let config = ProxyConfig {
+        http_port: 0,
+        rate_limit: Some("1/1m".parse().unwrap()),
+        rate_limit_ban: Some("strikes=1,duration=1h".parse().unwrap()),
+        trusted_proxies: vec!["127.0.0.0/8".parse().unwrap()],
+        ..ProxyConfig::default()
+    };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/", proxy_port);
+    let get = |xff: &'static str| client.get(&url).header("X-Forwarded-For", xff).send();
+
+    assert_eq!(get("198.51.100.7").await.unwrap().status().as_u16(), 200);
+    assert_eq!(get("198.51.100.7").await.unwrap().status().as_u16(), 429);
+    let bans = db.list_bans().unwrap();
+    assert_eq!((bans.len(), bans[0].network.as_str()), (1, "198.51.100.7"));
+    // The balancer's other clients get through; a hop the client made up in front doesn't help
+    assert_eq!(get("198.51.100.8").await.unwrap().status().as_u16(), 200);
+    assert_eq!(get("203.0.113.9, 198.51.100.7").await.unwrap().status().as_u16(), 403);
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_auth_failures_ban_client() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("GUARDED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    let mapping = db.find_by_domain_and_uri("localhost", "").unwrap().unwrap();
+    let (_, key) = db.add_api_key(&mapping.id, "ci", None).unwrap();
+    let config = ProxyConfig { http_port: 0, auth_failure_ban: Some("strikes=3,within=1m".parse().unwrap()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/", proxy_port);
+    let resp = client.get(&url).header("X-Api-Key", &key).send().await.unwrap();
+    assert!(resp.text().await.unwrap().contains("GUARDED"));
+    for _ in 0..3 {
+        assert_eq!(client.get(&url).send().await.unwrap().status().as_u16(), 401);
+    }
+    // Guessing stops working, and so does the right key
+    let resp = client.get(&url).header("X-Api-Key", &key).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 403);
+    let bans = db.list_bans().unwrap();
+    assert_eq!((bans.len(), bans[0].source.as_str()), (1, "auth_failure"));
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_auth_failure_ban_ignores_forged_forwarded_for() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("GUARDED").await;
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend.port(), "");
+    let mapping = db.find_by_domain_and_uri("localhost", "").unwrap().unwrap();
+    db.add_api_key(&mapping.id, "ci", None).unwrap();
+    let config = ProxyConfig { http_port: 0, auth_failure_ban: Some("strikes=3,within=1m".parse().unwrap()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+
+    // Failures blamed on someone else's address, a different one each time
+    let client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/", proxy_port);
+    for i in 0..3 {
+        let resp = client.get(&url).header("X-Forwarded-For", format!("198.51.100.{}", i)).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+    let bans = db.list_bans().unwrap();
+    assert_eq!(bans.iter().map(|b| b.network.as_str()).collect::<Vec<_>>(), ["127.0.0.1"]);
+    let resp = client.get(&url).header("X-Forwarded-For", "198.51.100.9").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 403);
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_response_contract_enforced() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("CONTRACT").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    let mapping = db.find_by_domain_and_uri("localhost", "").unwrap().unwrap();
+    let config = ProxyConfig { http_port: 0, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+    let url = format!("http://localhost:{}/", proxy_port);
+
+    db.set_contract(&mapping.id, Some("status=2xx,latency=10s")).unwrap();
+    assert!(reqwest::get(&url).await.unwrap().text().await.unwrap().contains("CONTRACT"));
+
+    // Only reported, then enforced
+    db.set_contract(&mapping.id, Some("headers=x-request-id")).unwrap();
+    assert_eq!(reqwest::get(&url).await.unwrap().status().as_u16(), 200);
+    db.set_contract(&mapping.id, Some("headers=x-request-id,enforce")).unwrap();
+    assert_eq!(reqwest::get(&url).await.unwrap().status().as_u16(), 502);
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_mapping_header_limits() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("LIMITED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let api = db.add_mapping("localhost", "api", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_header_limits(&api.id, Some("count=8,field=64")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    let config = ProxyConfig { http_port: 0, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+    let client = reqwest::Client::new();
+    let get = |path: &str, cookie: usize| client.get(format!("http://localhost:{}/{}", proxy_port, path))
+        .header("cookie", "c".repeat(cookie)).send();
+
+    assert_eq!(get("api/x", 10).await.unwrap().status().as_u16(), 200);
+    assert_eq!(get("api/x", 100).await.unwrap().status().as_u16(), 431);
+    // Other mappings keep the listener's limits
+    assert_eq!(get("other", 100).await.unwrap().status().as_u16(), 200);
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_forward_headers_allow_list() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                let mut names: Vec<&str> = req.headers().keys().map(|n| n.as_str()).collect();
+                names.sort();
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(names.join(",")))))
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let mapping = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_forward_headers(&mapping.id, Some("accept,x-app-*")).unwrap();
+    let config = ProxyConfig { http_port: 0, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let seen = reqwest::Client::new().get(format!("http://localhost:{}/", proxy_port))
+        .header("accept", "*/*").header("x-app-tenant", "7").header("cookie", "sid=1")
+        .header("authorization", "Bearer t").header("x-internal-user", "alice")
+        .send().await.unwrap().text().await.unwrap();
+    assert_eq!(seen, "accept,host,via,x-app-tenant,x-forwarded-for,x-forwarded-host,x-forwarded-proto,x-rustproxy-hop");
+}
+
+#[tokio::test]
+async fn test_response_headers_scrubbed() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|_req| async move {
+                Ok::<_, Infallible>(Response::builder()
+                    .header("server", "Apache/2.4.41 (Ubuntu)").header("x-powered-by", "PHP/7.4.3")
+                    .body(Full::new(Bytes::from("OK"))).unwrap())
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    let exempt = db.add_mapping("localhost", "debug", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_scrub_headers(&exempt.id, Some("off")).unwrap();
+    let config = ProxyConfig { http_port: 0, scrub_response_headers: rustproxy::scrub::ScrubProfile::Basic, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let resp = reqwest::get(format!("http://localhost:{}/", proxy_port)).await.unwrap();
+    assert!(!resp.headers().contains_key("server") && !resp.headers().contains_key("x-powered-by"));
+    let resp = reqwest::get(format!("http://localhost:{}/debug", proxy_port)).await.unwrap();
+    assert_eq!(resp.headers()["x-powered-by"], "PHP/7.4.3");
+}
+
+#[tokio::test]
+async fn test_debug_trace_header() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port_alive = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                let leaked = req.headers().contains_key("x-rustproxy-debug");
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!("leaked={}", leaked)))))
+            })));
+        }
+    });
+    let port_dead = free_port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let m = db.add_mapping("localhost", "api", 0, "v1", None, Some(&format!("{},{}", port_dead, port_alive)), None, None, None).unwrap();
+    let config = ProxyConfig { http_port: 0, debug_secret: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://localhost:{}/api/users?page=2", proxy_port);
+
+    let resp = client.get(&url).header("X-RustProxy-Debug", "s3cret").send().await.unwrap();
+    let trace = resp.headers()["x-rustproxy-debug"].to_str().unwrap().to_string();
+    assert!(trace.starts_with(&format!("mapping={}; upstream=/v1/users?page=2; backend=", m.id)), "{}", trace);
+    assert!(trace.contains(&format!(":{} 200 ", port_alive)) && trace.contains("; total="), "{}", trace);
+    assert_eq!(resp.text().await.unwrap(), "leaked=false");
+
+    // A wrong secret gets no trace, and the header still stays with the proxy
+    let resp = client.get(&url).header("X-RustProxy-Debug", "guess").send().await.unwrap();
+    assert!(!resp.headers().contains_key("x-rustproxy-debug"));
+    assert_eq!(resp.text().await.unwrap(), "leaked=false");
+}
+
+#[tokio::test]
+async fn test_upstream_protocol_http10() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                let seen = format!(
+                    "{:?} expect={} connection={}",
+                    req.version(),
+                    req.headers().contains_key("expect"),
+                    req.headers().get("connection").and_then(|v| v.to_str().ok()).unwrap_or("-"),
+                );
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(seen))))
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let legacy = db.add_mapping("localhost", "legacy", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_upstream_protocol(&legacy.id, Some("http/1.0")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..2 {
+        let resp = client.post(format!("http://localhost:{}/legacy", proxy_port))
+            .header("Expect", "100-continue")
+            .body("payload")
+            .send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "HTTP/1.0 expect=false connection=close");
+    }
+    // No keep-alive: each request had a connection of its own
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    for _ in 0..2 {
+        let resp = client.get(format!("http://localhost:{}/", proxy_port)).send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "HTTP/1.1 expect=false connection=-");
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_upstream_h2c_prior_knowledge() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            // HTTP/2 only: an HTTP/1.1 request line fails the connection preface
+            let service = service_fn(|req: Request<Incoming>| async move {
+                let seen = format!("{:?} {} {}", req.version(), req.uri().authority().map_or("-", |a| a.as_str()), req.uri().path());
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(seen))))
+            });
+            tokio::spawn(hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let grpc = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_upstream_protocol(&grpc.id, Some("h2c")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::new();
+
+    for _ in 0..3 {
+        let resp = client.get(format!("http://localhost:{}/helloworld.Greeter/SayHello", proxy_port)).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), format!("HTTP/2.0 localhost:{} /helloworld.Greeter/SayHello", proxy_port));
+    }
+    // One multiplexed connection served them all
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_upstream_hmac_signing() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                use http_body_util::BodyExt;
+                let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+                let (signature, timestamp, key_id) = (header("x-signature"), header("x-signature-timestamp"), header("x-signature-key-id"));
+                let target = req.uri().path_and_query().unwrap().to_string();
+                let method = req.method().to_string();
+                let body = req.into_body().collect().await.unwrap().to_bytes();
+                let body_hash: String = ring::digest::digest(&ring::digest::SHA256, &body).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+                let message = format!("{}\n{}\n{}\n{}", method, target, timestamp, body_hash);
+                let tag = ring::hmac::sign(&ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"s3cret"), message.as_bytes());
+                let expected: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+                let valid = signature == format!("sha256={}", expected);
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(format!("key={} valid={}", key_id, valid)))))
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let api = db.add_mapping("localhost", "api", backend_port, "v1", None, None, None, None, None).unwrap();
+    db.set_upstream_signing(&api.id, Some("hmac,key_id=edge,secret=s3cret")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let resp = reqwest::Client::new()
+        .post(format!("http://localhost:{}/api/orders?id=7", proxy_port))
+        .header("X-Signature", "sha256=forged")
+        .body(r#"{"qty":1}"#)
+        .send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "key=edge valid=true");
+}
+
+#[tokio::test]
+async fn test_s3_static_site() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bucket_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                // Path-style bucket "sites" that only answers signed, query-less, cookie-less GETs
+                let signed = req.headers().get("authorization").and_then(|v| v.to_str().ok())
+                    .is_some_and(|a| a.starts_with("AWS4-HMAC-SHA256 Credential=minio/"));
+                let clean = req.uri().query().is_none() && !req.headers().contains_key("cookie");
+                let object = match req.uri().path() {
+                    "/sites/docs/index.html" => Some("<h1>Docs</h1>"),
+                    "/sites/docs/guide/index.html" => Some("<h1>Guide</h1>"),
+                    _ => None,
+                };
+                let resp = match object {
+                    _ if !signed || !clean => Response::builder().status(403),
+                    Some(_) => Response::builder().header("content-type", "text/html").header("x-amz-request-id", "4442587FB7D0A2F9"),
+                    None => Response::builder().status(404).header("content-type", "application/xml"),
+                };
+                Ok::<_, Infallible>(resp.body(Full::new(Bytes::from(object.unwrap_or("<Error><Code>NoSuchKey</Code></Error>")))).unwrap())
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let site = db.add_mapping("localhost", "", bucket_port, "sites/docs", Some("s3+http://127.0.0.1"), None, None, None, None).unwrap();
+    db.set_upstream_signing(&site.id, Some("aws4,access_key=minio,secret_key=s3cret,region=us-east-1,service=s3")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    let url = |path: &str| format!("http://localhost:{}{}", proxy_port, path);
+
+    let resp = client.get(url("/?utm=1")).header("Cookie", "session=abc").send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/html");
+    assert!(!resp.headers().contains_key("x-amz-request-id"));
+    assert_eq!(resp.text().await.unwrap(), "<h1>Docs</h1>");
+
+    let resp = client.get(url("/guide")).send().await.unwrap();
+    assert_eq!(resp.status(), 301);
+    assert_eq!(resp.headers()["location"], "/guide/");
+    assert_eq!(client.get(url("/guide/")).send().await.unwrap().text().await.unwrap(), "<h1>Guide</h1>");
+
+    let resp = client.get(url("/missing.css")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.text().await.unwrap(), "Not Found");
+    let resp = client.put(url("/index.html")).body("x").send().await.unwrap();
+    assert_eq!(resp.status(), 405);
+    assert_eq!(resp.headers()["allow"], "GET, HEAD");
+}
+
+#[tokio::test]
+async fn test_startup_page() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                if req.uri().path().ends_with("/cold") {
+                    sleep(Duration::from_millis(1500)).await;
+                }
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("ready"))))
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let app = db.add_mapping("localhost", "app", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_startup_page(&app.id, Some("after=1,refresh=2")).unwrap();
+    let impatient = db.add_mapping("localhost", "impatient", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_startup_page(&impatient.id, Some("after=1,max_wait=1")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::new();
+    let page_load = |path: &str| client.get(format!("http://localhost:{}{}", proxy_port, path)).header("Accept", "text/html,*/*;q=0.8").send();
+
+    let resp = page_load("/app/cold").await.unwrap();
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers()["refresh"], "2");
+    assert_eq!(resp.headers()["retry-after"], "2");
+    assert!(resp.text().await.unwrap().contains("Starting up"));
+
+    // API calls wait for the backend as before
+    let resp = client.get(format!("http://localhost:{}/app/cold", proxy_port)).send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "ready");
+
+    // Past max_wait the proxy gives up
+    assert_eq!(page_load("/impatient/cold").await.unwrap().status(), 503);
+    assert_eq!(page_load("/impatient/cold").await.unwrap().status(), 504);
+}
+
+#[tokio::test]
+async fn test_deploy_hold() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("DEPLOYED").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let mapping = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    let until = chrono::Utc::now() + chrono::Duration::seconds(30);
+    db.set_hold(&mapping.id, Some(&format!("until={},max_queue=1", until.to_rfc3339()))).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let url = format!("http://localhost:{}/", proxy_port);
+
+    let queued = tokio::spawn(reqwest::get(url.clone()));
+    sleep(Duration::from_millis(500)).await;
+    assert!(!queued.is_finished());
+
+    // The queue is full: told to come back when the hold ends
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 503);
+    let retry: u64 = resp.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!((25..=31).contains(&retry), "{}", retry);
+
+    // Released: the queued request goes through
+    db.set_hold(&mapping.id, None).unwrap();
+    let resp = tokio::time::timeout(Duration::from_secs(5), queued).await.unwrap().unwrap().unwrap();
+    assert!(resp.text().await.unwrap().contains("DEPLOYED"));
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_image_filter() {
+    use image::{GenericImageView, ImageFormat};
+    let dir = tempdir().unwrap();
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgb8(image::RgbImage::new(400, 200)).write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png).unwrap();
+    let png = Bytes::from(png);
+    let queries = Arc::new(parking_lot::Mutex::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let (source, seen) = (png.clone(), queries.clone());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (source, seen) = (source.clone(), seen.clone());
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                seen.lock().push(req.uri().query().unwrap_or("").to_string());
+                let source = source.clone();
+                async move {
+                    Ok::<_, Infallible>(Response::builder().header("content-type", "image/png").header("etag", "\"v1\"").body(Full::new(source)).unwrap())
+                }
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let mapping = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_image_filter(&mapping.id, Some("max_height=40")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let url = |query: &str| format!("http://localhost:{}/logo.png{}", proxy_port, query);
+
+    for _ in 0..2 {
+        let resp = reqwest::get(url("?v=2&w=100&format=webp")).await.unwrap();
+        assert_eq!(resp.headers()["content-type"], "image/webp");
+        assert!(!resp.headers().contains_key("etag"));
+        let body = resp.bytes().await.unwrap();
+        assert_eq!(image::load_from_memory_with_format(&body, ImageFormat::WebP).unwrap().dimensions(), (80, 40));
+    }
+    assert_eq!(queries.lock().as_slice(), ["v=2", "v=2"]);
+
+    assert_eq!(reqwest::get(url("")).await.unwrap().bytes().await.unwrap(), png);
+    assert_eq!(reqwest::get(url("?w=wide")).await.unwrap().status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn test_minify() {
+    let dir = tempdir().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                let (content_type, body) = match req.uri().path() {
+                    "/site.css" => ("text/css", "body {\n  color: #ff0000;\n}\n".to_string()),
+                    "/big.css" => ("text/css", format!("/* {} */\nbody {{ color: red; }}\n", "x".repeat(200))),
+                    _ => ("application/javascript", "var answer = 40 + 2;\n".to_string()),
+                };
+                Ok::<_, Infallible>(Response::builder().header("content-type", content_type).header("etag", "\"v1\"").body(Full::new(Bytes::from(body))).unwrap())
+            })));
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let mapping = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_minify(&mapping.id, Some("html,css,max_bytes=100")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let url = |path: &str| format!("http://localhost:{}{}", proxy_port, path);
+
+    for _ in 0..2 {
+        let resp = reqwest::get(url("/site.css")).await.unwrap();
+        assert_eq!(resp.headers()["etag"], "W/\"v1\"");
+        assert_eq!(resp.text().await.unwrap(), "body{color:red}");
+    }
+    // Over max_bytes, and a type the mapping doesn't minify
+    assert!(reqwest::get(url("/big.css")).await.unwrap().text().await.unwrap().starts_with("/* xxx"));
+    let resp = reqwest::get(url("/app.js")).await.unwrap();
+    assert_eq!(resp.headers()["etag"], "\"v1\"");
+    assert_eq!(resp.text().await.unwrap(), "var answer = 40 + 2;\n");
+}
+
+#[tokio::test]
+async fn test_early_hints() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let dir = tempdir().unwrap();
+    // A backend that hints at its script before rendering the page
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 4096];
+                let mut read = 0;
+                while !buf[..read].windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf[read..]).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => read += n,
+                    }
+                }
+                let _ = stream.write_all(b"HTTP/1.1 103 Early Hints\r\nLink: </app.js>; rel=preload; as=script\r\n\r\n").await;
+                sleep(Duration::from_millis(100)).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 4\r\nConnection: close\r\n\r\npage").await;
+            });
+        }
+    });
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let mapping = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_early_hints(&mapping.id, Some("</app.css>; rel=preload; as=style, forward")).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let mut client = tokio::net::TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: text/html\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with(
+        "HTTP/1.1 103 Early Hints\r\nlink: </app.css>; rel=preload; as=style\r\n\r\n\
+         HTTP/1.1 103 Early Hints\r\nlink: </app.js>; rel=preload; as=script\r\n\r\n\
+         HTTP/1.1 200 OK\r\n"
+    ), "{}", response);
+    assert!(response.contains("link: </app.css>; rel=preload; as=style\r\n") && response.ends_with("page"), "{}", response);
+
+    // Clients read past the 103s to the response
+    let resp = reqwest::Client::new().get(format!("http://localhost:{}/", proxy_port)).header("Accept", "text/html").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.text().await.unwrap(), "page");
+}
+
+/// A WebDAV-style backend: answers with the method, the headers that shape the request,
+/// and the body it received; `207` for `PROPFIND`, `201` for `MKCOL`. The first chunk of
+/// each body goes to `first_chunks` as soon as it arrives.
+async fn run_webdav_backend(first_chunks: tokio::sync::mpsc::UnboundedSender<Bytes>) -> u16 {
+    use http_body_util::BodyExt;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let first_chunks = first_chunks.clone();
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                let first_chunks = first_chunks.clone();
+                async move {
+                    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+                    let summary = format!("{} {} depth={} destination={} te={} cl={}", req.method(), req.uri(),
+                        header("depth"), header("destination"), header("transfer-encoding"), header("content-length"));
+                    let status = match req.method().as_str() {
+                        "PROPFIND" => 207,
+                        "MKCOL" => 201,
+                        _ => 200,
+                    };
+                    let mut body = req.into_body();
+                    let mut out = format!("{}\n", summary).into_bytes();
+                    let mut first = true;
+                    while let Some(frame) = body.frame().await {
+                        let Ok(data) = frame.unwrap().into_data() else { continue };
+                        if std::mem::take(&mut first) {
+                            let _ = first_chunks.send(data.clone());
+                        }
+                        out.extend_from_slice(&data);
+                    }
+                    Ok::<_, Infallible>(Response::builder().status(status)
+                        .header("content-type", "application/xml; charset=utf-8").body(Full::new(Bytes::from(out))).unwrap())
+                }
+            })));
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_webdav_methods_and_chunked_bodies() {
+    use futures_util::StreamExt;
+    let dir = tempdir().unwrap();
+    let (first_chunks, first_chunk) = tokio::sync::mpsc::unbounded_channel();
+    let first_chunk = Arc::new(tokio::sync::Mutex::new(first_chunk));
+    let backend_port = run_webdav_backend(first_chunks).await;
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "dav.test", "files", backend_port, "dav");
+    let ports = format!("{},{}", backend_port, backend_port);
+    db.add_mapping("dav-ha.test", "files", backend_port, "dav", None, Some(&ports), None, None, None).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::new();
+    let request = |method: &str, host: &str, path: &str| client
+        .request(reqwest::Method::from_bytes(method.as_bytes()).unwrap(), format!("http://127.0.0.1:{}{}", proxy_port, path))
+        .header("Host", host);
+
+    let propfind = "<?xml version=\"1.0\"?><propfind xmlns=\"DAV:\"><allprop/></propfind>";
+    let resp = request("PROPFIND", "dav.test", "/files/docs/").header("Depth", "1").body(propfind).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 207);
+    assert_eq!(resp.headers()["content-type"], "application/xml; charset=utf-8");
+    assert_eq!(resp.text().await.unwrap(),
+        format!("PROPFIND /dav/docs/ depth=1 destination=- te=- cl={}\n{}", propfind.len(), propfind));
+
+    let resp = request("MKCOL", "dav.test", "/files/new/").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 201);
+    assert!(resp.text().await.unwrap().starts_with("MKCOL /dav/new/ "));
+
+    let resp = request("MOVE", "dav.test", "/files/a.txt").header("Destination", "http://dav.test/files/b.txt").send().await.unwrap();
+    assert!(resp.text().await.unwrap().starts_with("MOVE /dav/a.txt depth=- destination=http://dav.test/files/b.txt "));
+
+    // A chunked body arrives byte-for-byte. To a single backend it streams: the backend has
+    // the first chunk before the client sends the last one. HA buffers it for retries, so
+    // there it arrives whole, with a Content-Length
+    for (host, framing) in [("dav.test", "te=chunked cl=-"), ("dav-ha.test", "te=- cl=14")] {
+        while first_chunk.lock().await.try_recv().is_ok() {}
+        let streamed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let chunks: Vec<&'static [u8]> = vec![b"first,", &[0, 159, 255], b",last"];
+        let (waiting, seen_first) = (first_chunk.clone(), streamed.clone());
+        let stream = futures_util::stream::iter(chunks.into_iter().enumerate()).then(move |(i, chunk)| {
+            let (first_chunk, streamed) = (waiting.clone(), seen_first.clone());
+            async move {
+                if i == 2 {
+                    let seen = tokio::time::timeout(Duration::from_millis(500), first_chunk.lock().await.recv()).await;
+                    streamed.store(matches!(seen, Ok(Some(ref chunk)) if chunk.starts_with(b"first,")), std::sync::atomic::Ordering::SeqCst);
+                } else {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok::<_, std::io::Error>(chunk.to_vec())
+            }
+        });
+        let resp = request("PATCH", host, "/files/a.bin?v=2")
+            .body(reqwest::Body::wrap_stream(stream)).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200, "{}", host);
+        let body = resp.bytes().await.unwrap();
+        let (summary, received) = body.split_at(body.iter().position(|b| *b == b'\n').unwrap() + 1);
+        assert_eq!(std::str::from_utf8(summary).unwrap(), format!("PATCH /dav/a.bin?v=2 depth=- destination=- {}\n", framing), "{}", host);
+        assert_eq!(received, b"first,\x00\x9f\xff,last", "{}", host);
+        assert_eq!(streamed.load(std::sync::atomic::Ordering::SeqCst), host == "dav.test", "{}", host);
+    }
+}
+
+#[tokio::test]
+async fn test_analytics_sink() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("STATS").await;
+    let backend_port = backend.port();
+    let failing = Backend::status(503, "DOWN").await;
+    let failing_port = failing.port();
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "stats.test", "", backend_port, "");
+    add(&db, "down.test", "", failing_port, "");
+    drop(db);
+    let sink = rustproxy::analytics::Sink::Sqlite(dir.path().join("analytics.db"));
+    let proxy = ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .analytics_sink(sink.clone())
+        .analytics_flush_interval(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    let proxy_port = serve(Arc::new(proxy)).await;
+
+    let client = reqwest::Client::new();
+    for path in ["/a", "/a", "/b"] {
+        let resp = client.get(format!("http://127.0.0.1:{}{}", proxy_port, path)).header("Host", "Stats.test:8080").send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+    }
+    let resp = client.get(format!("http://127.0.0.1:{}/x", proxy_port)).header("Host", "down.test").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 503);
+
+    let mut stats = None;
+    for _ in 0..40 {
+        sleep(Duration::from_millis(100)).await;
+        if let Ok(s) = rustproxy::analytics::stats(&sink, Some("stats.test"), Duration::from_secs(3600)).await {
+            if s.requests == 3 {
+                stats = Some(s);
+                break;
+            }
+        }
+    }
+    let stats = stats.expect("rows written within a few flushes");
+    assert_eq!(stats.statuses.get("2xx"), Some(&3));
+    assert_eq!(stats.top_paths[0].key, "/a");
+    assert_eq!(stats.top_paths[0].requests, 2);
+    assert!(stats.bytes_out > 0);
+
+    let all = rustproxy::analytics::stats(&sink, None, Duration::from_secs(3600)).await.unwrap();
+    assert_eq!(all.requests, 4);
+    assert_eq!(all.statuses.get("5xx"), Some(&1));
+    assert_eq!(all.top_hosts.iter().find(|c| c.key == "down.test").map(|c| c.errors), Some(1));
+}
+
+#[tokio::test]
+async fn test_access_hours() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("HOURS").await;
+    let backend_port = backend.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let mapping = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    let page = dir.path().join("closed.html");
+    std::fs::write(&page, "<h1>Back on Monday</h1>").unwrap();
+    let config = ProxyConfig { http_port: 0, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+    let url = format!("http://localhost:{}/", proxy_port);
+
+    db.set_access_hours(&mapping.id, Some("* * * * *")).unwrap();
+    assert!(reqwest::get(&url).await.unwrap().text().await.unwrap().contains("HOURS"));
+
+    // Closed all year but 31 February
+    db.set_access_hours(&mapping.id, Some("0 0 31 2 *")).unwrap();
+    assert_eq!(reqwest::get(&url).await.unwrap().status().as_u16(), 403);
+    db.set_access_hours(&mapping.id, Some(&format!("0 0 31 2 * status=503 page={}", page.display()))).unwrap();
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 503);
+    assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+    assert_eq!(resp.text().await.unwrap(), "<h1>Back on Monday</h1>");
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_scheduled_mapping_switch() {
+    let dir = tempdir().unwrap();
+    let old = Backend::tagged("OLD").await;
+    let old_port = old.port();
+    let new = Backend::tagged("NEW").await;
+    let new_port = new.port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let switch = (chrono::Utc::now() + chrono::Duration::milliseconds(800)).to_rfc3339();
+    let current = db.add_mapping("localhost", "", old_port, "", None, None, None, None, None).unwrap();
+    db.set_deactivate_at(&current.id, Some(&switch)).unwrap();
+    let next = db.add_mapping("localhost", "", new_port, "", None, None, None, None, None).unwrap();
+    db.set_activate_at(&next.id, Some(&switch)).unwrap();
+    let config = ProxyConfig { http_port: 0, ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+    let url = format!("http://localhost:{}/", proxy_port);
+
+    assert!(reqwest::get(&url).await.unwrap().text().await.unwrap().contains("OLD"));
+    sleep(Duration::from_millis(1000)).await;
+    assert!(reqwest::get(&url).await.unwrap().text().await.unwrap().contains("NEW"));
+    old.abort();
+    new.abort();
+}
+
+#[tokio::test]
+async fn test_probe_history_in_status() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("PROBED").await;
+    let backend_port = backend.port();
+    let dead_port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "localhost", "", backend_port, "");
+    db.add_mapping("dead.localhost", "", dead_port, "", Some("http://127.0.0.1"), None, None, None, None).unwrap();
+    let config = ProxyConfig {
+        http_port: 0,
+        admin_token: Some("s3cret".to_string()),
+        probe_interval: Duration::from_millis(200),
+        ..ProxyConfig::default()
+    };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    sleep(Duration::from_millis(700)).await;
+    let body = reqwest::Client::new().get(format!("http://127.0.0.1:{}/_proxy/admin/status", proxy_port))
+        .bearer_auth("s3cret").send().await.unwrap().text().await.unwrap();
+    let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let probes = status["probes"].as_array().unwrap();
+    let of = |port: u16| probes.iter().find(|p| p["target"].as_str().unwrap().ends_with(&format!(":{}", port))).unwrap();
+    assert_eq!(of(backend_port)["last"]["ok"], true);
+    assert_eq!(of(backend_port)["uptime"]["1h"], 100.0);
+    assert_eq!(of(dead_port)["last"]["ok"], false);
+    assert_eq!(of(dead_port)["uptime"]["24h"], 0.0);
+    assert_eq!(of(dead_port)["latency_ms"]["1h"], serde_json::Value::Null);
+    assert!(!of(backend_port)["hourly"].as_array().unwrap().is_empty());
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_tcp_options_on_both_sides() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("TUNED").await;
+    let backend_port = backend.port();
+    add(&DatabaseManager::new(dir.path().join("test.db")).unwrap(), "localhost", "", backend_port, "");
+    let proxy = ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .client_tcp("nodelay,keepalive=60,recv_buffer=256K,send_buffer=256K".parse().unwrap())
+        .backend_tcp("nodelay,keepalive=30,keepalive_interval=10,keepalive_retries=3,recv_buffer=1M".parse().unwrap())
+        .build()
+        .unwrap();
+    let proxy_port = serve(Arc::new(proxy)).await;
+
+    for _ in 0..3 {
+        let resp = reqwest::get(format!("http://localhost:{}/", proxy_port)).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert!(resp.text().await.unwrap().contains("TUNED"));
+    }
+    backend.abort();
+}
+
+#[tokio::test]
+async fn test_stale_copies_survive_restart_on_disk() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("DISK").await;
+    let backend_port = backend.port();
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_stale_if_error(&m.id, Some(600)).unwrap();
+    drop(db);
+
+    let cache_dir = dir.path().join("cache");
+    let start = || async {
+        let proxy = ProxyBuilder::new()
+            .db_path(dir.path().join("test.db"))
+            .certs_dir(dir.path().join("certs"))
+            .http_port(0)
+            .http_host("127.0.0.1")
+            .response_cache_dir(&cache_dir)
+            .build()
+            .unwrap();
+        serve(Arc::new(proxy)).await
+    };
+    let client = reqwest::Client::new();
+    let get = |port: u16| client.get(format!("http://127.0.0.1:{}/app.js", port)).header("Host", "localhost").send();
+
+    let first = start().await;
+    let fresh = get(first).await.unwrap().text().await.unwrap();
+    assert!(fresh.starts_with("DISK|path=/app.js"), "{}", fresh);
+    for _ in 0..100 {
+        // Written aside as .tmp, then renamed
+        let written = std::fs::read_dir(&cache_dir).unwrap().flatten().any(|f| f.path().extension().is_some_and(|e| e == "entry"));
+        if written {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Another instance on the same directory, with the backend gone
+    backend.abort();
+    let second = start().await;
+    let resp = get(second).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert!(resp.headers()["warning"].to_str().unwrap().starts_with("110"));
+    assert_eq!(resp.text().await.unwrap(), fresh);
+}
+
+#[tokio::test]
+async fn test_admin_event_log_and_stream() {
+    let dir = tempdir().unwrap();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+    let admin = |path: &str| format!("http://127.0.0.1:{}/_proxy/admin/{}", proxy_port, path);
+    let client = reqwest::Client::new();
+
+    let mut stream = client.get(admin("events/stream")).bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(stream.headers()["content-type"], "text/event-stream");
+    assert!(String::from_utf8_lossy(&stream.chunk().await.unwrap().unwrap()).starts_with(": connected"));
+
+    client.post(admin("dns/flush")).bearer_auth("s3cret").send().await.unwrap();
+    let chunk = tokio::time::timeout(Duration::from_secs(5), stream.chunk()).await.unwrap().unwrap().unwrap();
+    let message = String::from_utf8_lossy(&chunk).into_owned();
+    assert!(message.starts_with("id: 1\nevent: admin_action\ndata: {"), "{}", message);
+    assert!(message.contains(r#""operation":"dns/flush""#));
+
+    let log: serde_json::Value = serde_json::from_str(
+        &client.get(admin("events?since=0")).bearer_auth("s3cret").send().await.unwrap().text().await.unwrap(),
+    ).unwrap();
+    assert_eq!(log["events"][0]["event"], "admin_action");
+    assert_eq!(log["events"][0]["id"], 1);
+
+    // Reconnecting with Last-Event-ID replays what was missed
+    let mut resumed = client.get(admin("events/stream")).bearer_auth("s3cret").header("Last-Event-ID", "0").send().await.unwrap();
+    let head = String::from_utf8_lossy(&resumed.chunk().await.unwrap().unwrap()).into_owned();
+    assert!(head.contains("id: 1\nevent: admin_action"), "{}", head);
+    assert_eq!(client.get(admin("events/stream")).send().await.unwrap().status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_tail_follows_traffic() {
+    let dir = tempdir().unwrap();
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    let backend = Backend::tagged("OK").await;
+    let ok_port = backend.port();
+    let failing = Backend::status(503, "DOWN").await;
+    let failing_port = failing.port();
+    add(&db, "ok.test", "", ok_port, "");
+    add(&db, "bad.test", "", failing_port, "");
+    let config = ProxyConfig { http_port: 0, admin_token: Some("s3cret".to_string()), ..ProxyConfig::default() };
+    let certs = Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap());
+    let proxy_port = serve(Arc::new(ProxyServer::new(config, db, certs))).await;
+
+    let base: hyper::Uri = format!("http://127.0.0.1:{}", proxy_port).parse().unwrap();
+    let filter = rustproxy::tail::Filter { domain: None, status: Some("5xx".parse().unwrap()) };
+    let (tx, mut lines) = tokio::sync::mpsc::unbounded_channel();
+    let follower = {
+        let base = base.clone();
+        tokio::spawn(async move { rustproxy::tail::follow(&base, "s3cret", &filter, |line| { let _ = tx.send(line); }).await })
+    };
+
+    // Requests finished before the tail subscribed aren't shown; keep going until one is
+    let client = reqwest::Client::new();
+    let proxy_url = format!("http://127.0.0.1:{}/cart", proxy_port);
+    let mut line = None;
+    for _ in 0..50 {
+        client.get(&proxy_url).header("Host", "ok.test").send().await.unwrap();
+        client.get(&proxy_url).header("Host", "bad.test").send().await.unwrap();
+        if let Ok(Some(shown)) = tokio::time::timeout(Duration::from_millis(100), lines.recv()).await {
+            line = Some(shown);
+            break;
+        }
+    }
+    let line = line.expect("no request shown");
+    assert!(line.contains("GET     bad.test/cart  503  "), "{}", line);
+    assert!(line.contains(&format!(":{}  [", failing_port)), "{}", line);
+    // The 200s of ok.test were filtered out
+    while let Ok(shown) = lines.try_recv() {
+        assert!(shown.contains("bad.test") && !shown.contains("ok.test"), "{}", shown);
+    }
+
+    assert!(rustproxy::tail::follow(&base, "wrong", &Default::default(), |_| {}).await.unwrap_err().to_string().contains("401"));
+    follower.abort();
+}
+
+#[tokio::test]
+async fn test_connection_cap_and_gauges() {
+    use tokio::io::AsyncReadExt;
+
+    let dir = tempdir().unwrap();
+    let config = ProxyConfig {
+        http_port: 0,
+        admin_token: Some("s3cret".to_string()),
+        max_connections: 2,
+        ..ProxyConfig::default()
+    };
+    let proxy = Arc::new(ProxyServer::new(
+        config,
+        Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap()),
+        Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+    ));
+    let proxy_port = serve(proxy.clone()).await;
+
+    let idle = tokio::net::TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/_proxy/admin/connections", proxy_port);
+    let body = client.get(&url).bearer_auth("s3cret").send().await.unwrap().text().await.unwrap();
+    let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(stats["client"], 2);
+    assert_eq!(stats["max_client"], 2);
+
+    // Third connection: turned away at accept
+    let mut extra = tokio::net::TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+    let mut reply = String::new();
+    extra.read_to_string(&mut reply).await.unwrap();
+    assert!(reply.starts_with("HTTP/1.1 503"), "{}", reply);
+    assert_eq!(proxy.connection_stats().rejected, 1);
+
+    drop(idle);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(proxy.connection_stats().client, 1);
+    let resp = reqwest::get(format!("http://127.0.0.1:{}/health", proxy_port)).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+// ── Rate limit tests ──────────────────────────────────────────────────────────
+
+/// Stand-in for Redis speaking just the commands the rate limiter sends: the counting
+/// script (EVAL), SCAN and DEL
+async fn run_fake_redis() -> u16 {
+    use rustproxy::redis::{read_value, Value};
+    use std::collections::HashMap;
+    use tokio::io::{AsyncWriteExt, BufStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let store = Arc::new(parking_lot::Mutex::new(HashMap::<String, i64>::new()));
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            let store = store.clone();
+            tokio::spawn(async move {
+                let mut conn = BufStream::new(stream);
+                while let Ok(Value::Array(args)) = read_value(&mut conn).await {
+                    let args: Vec<String> = args.into_iter()
+                        .map(|a| match a { Value::Bulk(b) => String::from_utf8(b).unwrap(), _ => String::new() })
+                        .collect();
+                    let reply = {
+                        let mut store = store.lock();
+                        match args[0].as_str() {
+                            "EVAL" => {
+                                let current = *store.entry(args[3].clone()).and_modify(|c| *c += 1).or_insert(1);
+                                let previous = store.get(&args[4]).copied().unwrap_or(0);
+                                format!("*2\r\n:{}\r\n:{}\r\n", current, previous)
+                            }
+                            "SCAN" => {
+                                let prefix = args[3].trim_end_matches('*');
+                                let keys: Vec<&String> = store.keys().filter(|k| k.starts_with(prefix)).collect();
+                                let mut reply = format!("*2\r\n$1\r\n0\r\n*{}\r\n", keys.len());
+                                for k in keys {
+                                    reply += &format!("${}\r\n{}\r\n", k.len(), k);
+                                }
+                                reply
+                            }
+                            "DEL" => format!(":{}\r\n", args[1..].iter().filter(|k| store.remove(*k).is_some()).count()),
+                            _ => "-ERR unknown command\r\n".to_string(),
+                        }
+                    };
+                    if conn.write_all(reply.as_bytes()).await.is_err() || conn.flush().await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_rate_limit_shared_across_instances_through_redis() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("LIMITED").await;
+    let backend_port = backend.port();
+    let redis_port = run_fake_redis().await;
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    let open = db.add_mapping("open.test", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_rate_limit(&open.id, Some("off")).unwrap();
+    drop(db);
+
+    // Two instances, one Redis: 3 requests per hour between them
+    let mut ports = Vec::new();
+    for _ in 0..2 {
+        let config = ProxyConfig {
+            http_port: 0,
+            admin_token: Some("s3cret".to_string()),
+            rate_limit: Some("3/1h".parse().unwrap()),
+            redis_url: Some(format!("redis://127.0.0.1:{}", redis_port)),
+            ..ProxyConfig::default()
+        };
+        ports.push(serve(Arc::new(ProxyServer::new(
+            config,
+            Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap()),
+            Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+        ))).await);
+    }
+
+    let client = reqwest::Client::new();
+    let get = |port: u16, host: &'static str| client.get(format!("http://127.0.0.1:{}/", port)).header("Host", host).send();
+    for port in [ports[0], ports[1], ports[0]] {
+        assert_eq!(get(port, "localhost").await.unwrap().status().as_u16(), 200);
+    }
+    let resp = get(ports[1], "localhost").await.unwrap();
+    assert_eq!(resp.status().as_u16(), 429);
+    assert!(resp.headers()["retry-after"].to_str().unwrap().parse::<u64>().unwrap() >= 1);
+    // A mapping with its limit turned off
+    assert_eq!(get(ports[1], "open.test").await.unwrap().status().as_u16(), 200);
+
+    let resp = client.post(format!("http://127.0.0.1:{}/_proxy/admin/ratelimits/reset?key=localhost", ports[0]))
+        .bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), r#"{"cleared":1,"operation":"ratelimits/reset"}"#);
+    assert_eq!(get(ports[1], "localhost").await.unwrap().status().as_u16(), 200);
+}
+
+// ── Cluster tests ─────────────────────────────────────────────────────────────
+
+/// Poll `url` with `host` until it answers `status` (cluster changes arrive asynchronously).
+async fn await_status(url: &str, host: &str, status: u16) -> bool {
+    let client = reqwest::Client::new();
+    for _ in 0..50 {
+        let resp = client.get(url).header("Host", host).send().await.unwrap();
+        if resp.status().as_u16() == status {
+            return true;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+/// Two nodes peering with each other, using the given database files (relative to `dir`)
+/// and certificate directories `certs0`/`certs1`. Returns their ports and databases.
+async fn start_cluster_pair(dir: &std::path::Path, db_files: [&str; 2]) -> ([u16; 2], Vec<Arc<DatabaseManager>>) {
+    let ports = [free_port(), free_port()];
+    let mut dbs = Vec::new();
+    for (i, port) in ports.iter().enumerate() {
+        let db = Arc::new(DatabaseManager::new(dir.join(db_files[i])).unwrap());
+        let config = ProxyConfig {
+            http_port: *port,
+            http_host: "127.0.0.1".to_string(),
+            cluster_peers: vec![format!("127.0.0.1:{}", ports[1 - i])],
+            cluster_secret: Some("s3cret".to_string()),
+            cluster_interval: Duration::from_millis(100),
+            ..ProxyConfig::default()
+        };
+        let certs = Arc::new(CertificateManager::new(dir.join(format!("certs{}", i)), None).unwrap());
+        serve(Arc::new(ProxyServer::new(config, db.clone(), certs))).await;
+        dbs.push(db);
+    }
+    (ports, dbs)
+}
+
+#[tokio::test]
+async fn test_cluster_replicates_mapping_changes() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("CLUSTER").await;
+    let backend_port = backend.port();
+    let (ports, dbs) = start_cluster_pair(dir.path(), ["node0.db", "node1.db"]).await;
+
+    // Added on node 0, served by node 1
+    add(&dbs[0], "shared.test", "", backend_port, "");
+    let url = format!("http://127.0.0.1:{}/", ports[1]);
+    assert!(await_status(&url, "shared.test", 200).await, "mapping not replicated");
+
+    // Deleted on node 1, gone on node 0
+    dbs[1].delete_mapping("shared.test", None).unwrap();
+    let url = format!("http://127.0.0.1:{}/", ports[0]);
+    assert!(await_status(&url, "shared.test", 404).await, "deletion not replicated");
+
+    // Peer endpoints need the secret
+    let resp = reqwest::get(format!("http://127.0.0.1:{}/_proxy/cluster/state", ports[0])).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn test_acme_challenge_answered_by_any_node() {
+    let dir = tempdir().unwrap();
+    let (ports, dbs) = start_cluster_pair(dir.path(), ["node0.db", "node1.db"]).await;
+
+    // Published on node 0 (e.g. by a certbot hook); Let's Encrypt happens to ask node 1
+    dbs[0].put_acme_challenge("tok_123", "tok_123.thumbprint").unwrap();
+    for port in ports {
+        let resp = reqwest::get(format!("http://127.0.0.1:{}/.well-known/acme-challenge/tok_123", port)).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.text().await.unwrap(), "tok_123.thumbprint");
+    }
+    let resp = reqwest::get(format!("http://127.0.0.1:{}/.well-known/acme-challenge/unknown", ports[1])).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_cluster_copies_certificates_from_lease_holder() {
+    let dir = tempdir().unwrap();
+    // One database between them, so its lease elects one certificate leader
+    let (ports, _dbs) = start_cluster_pair(dir.path(), ["shared.db", "shared.db"]).await;
+
+    let client = reqwest::Client::new();
+    let mut leader = None;
+    for _ in 0..50 {
+        for (i, port) in ports.iter().enumerate() {
+            let state = client.get(format!("http://127.0.0.1:{}/_proxy/cluster/state", port))
+                .bearer_auth("s3cret").send().await.unwrap().text().await.unwrap();
+            let state: serde_json::Value = serde_json::from_str(&state).unwrap();
+            if !state["certs"].is_null() {
+                leader = Some(i);
+            }
+        }
+        if leader.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let leader = leader.expect("no certificate leader elected");
+    let follower_dir = dir.path().join(format!("certs{}", 1 - leader));
+
+    // A certificate renewed on the leader reaches the follower
+    CertificateManager::new(dir.path().join(format!("certs{}", leader)), None).unwrap()
+        .generate_self_signed("renewed.test", &["renewed.test"]).unwrap();
+    let expected = std::fs::read_to_string(dir.path().join(format!("certs{}/renewed.test.crt", leader))).unwrap();
+    for _ in 0..50 {
+        if std::fs::read_to_string(follower_dir.join("renewed.test.crt")).ok() == Some(expected.clone()) {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("certificate not copied to the follower");
+}
+
+// ── Fallback / embedded-library tests ────────────────────────────────────────
+
+/// A custom fallback that always returns 200 with a known body.
+struct HelloFallback;
+
+#[async_trait::async_trait]
+impl FallbackHandler for HelloFallback {
+    async fn handle(
+        &self,
+        _req: hyper::Request<hyper::body::Incoming>,
+        _remote_addr: std::net::SocketAddr,
+    ) -> Result<hyper::Response<http_body_util::Full<bytes::Bytes>>> {
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(http_body_util::Full::new(bytes::Bytes::from("hello from fallback")))
+            .unwrap())
+    }
+}
+
+/// A fallback that always returns 500 — used to verify /health bypasses it.
+struct PanicFallback;
+
+#[async_trait::async_trait]
+impl FallbackHandler for PanicFallback {
+    async fn handle(
+        &self,
+        _req: hyper::Request<hyper::body::Incoming>,
+        _remote_addr: std::net::SocketAddr,
+    ) -> Result<hyper::Response<http_body_util::Full<bytes::Bytes>>> {
+        Ok(hyper::Response::builder()
+            .status(500)
+            .body(http_body_util::Full::new(bytes::Bytes::from("fallback error")))
+            .unwrap())
+    }
+}
+
+#[tokio::test]
+async fn test_fallback_called_when_no_mapping() {
+    let dir = tempdir().unwrap();
+
+    let server = Arc::new(ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .fallback(HelloFallback)
+        .build()
+        .unwrap());
+
+    let proxy_port = serve(server).await;
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://127.0.0.1:{}/anything", proxy_port))
+        .header("Host", "myapp.example.com")
+        .send().await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.text().await.unwrap(), "hello from fallback");
+}
+
+#[tokio::test]
+async fn test_proxy_wins_over_fallback() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("PROXIED").await;
+    let backend_port = backend.port();
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    drop(db);
+
+    let server = Arc::new(ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .fallback(HelloFallback) // would return "hello from fallback" if called
+        .build()
+        .unwrap());
+
+    let proxy_port = serve(server).await;
+
+    let body = reqwest::Client::new()
+        .get(format!("http://127.0.0.1:{}/test", proxy_port))
+        .header("Host", "localhost")
+        .send().await.unwrap().text().await.unwrap();
+
+    assert!(body.contains("PROXIED"), "expected proxy response, got: {}", body);
+}
+
+#[tokio::test]
+async fn test_health_check_bypasses_fallback() {
+    let dir = tempdir().unwrap();
+
+    let server = Arc::new(ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
         .fallback(PanicFallback) // returns 500 if called
         .build()
         .unwrap());
 
-    tokio::spawn(async move { let _ = server.run().await; });
-    sleep(Duration::from_millis(150)).await;
+    let proxy_port = serve(server).await;
 
     let resp = reqwest::Client::new()
         .get(format!("http://127.0.0.1:{}/health", proxy_port))
@@ -755,3 +3376,791 @@ async fn test_health_check_bypasses_fallback() {
     assert_eq!(resp.status().as_u16(), 200);
     assert_eq!(resp.text().await.unwrap(), "OK");
 }
+
+// ── Service discovery tests ───────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_consul_discovery_backend() {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let dir = tempdir().unwrap();
+    let b1 = Backend::tagged("INSTANCE1").await;
+    let port1 = b1.port();
+    let b2 = Backend::tagged("INSTANCE2").await;
+    let port2 = b2.port();
+
+    let consul = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/health/service/web"))
+        .respond_with(ResponseTemplate::new(200)
+            .insert_header("X-Consul-Index", "7")
+            .set_body_json(serde_json::json!([
+                {"Node": {"Address": "127.0.0.1"}, "Service": {"Address": "", "Port": port1}},
+                {"Node": {"Address": "127.0.0.1"}, "Service": {"Address": "127.0.0.1", "Port": port2}},
+            ])))
+        .mount(&consul)
+        .await;
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    db.add_mapping("localhost", "", 0, "", Some("consul://web"), None, None, None, None).unwrap();
+    drop(db);
+
+    let server = Arc::new(ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .consul_addr(consul.uri())
+        .build()
+        .unwrap());
+    let proxy_port = serve(server).await;
+
+    let client = reqwest::Client::new();
+    let mut saw = (false, false);
+    for _ in 0..10 {
+        let body = client.get(format!("http://127.0.0.1:{}/test", proxy_port))
+            .header("Host", "localhost").send().await.unwrap().text().await.unwrap();
+        if body.contains("INSTANCE1") { saw.0 = true; }
+        if body.contains("INSTANCE2") { saw.1 = true; }
+    }
+    assert!(saw.0 && saw.1, "expected both discovered instances to receive traffic");
+}
+
+/// A DNS server answering SRV queries for `name` with a `localhost.` target per
+/// (priority, port), TTL 60s; counts the queries it gets.
+async fn run_srv_server(name: &'static str, targets: Vec<(u16, u16)>) -> (std::net::SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::{rdata::SRV, Name, RData, Record, RecordType};
+    use hickory_resolver::proto::serialize::binary::BinEncodable;
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = queries.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let Ok(query) = Message::from_vec(&buf[..n]) else { continue };
+            let mut reply = Message::new();
+            reply.set_id(query.id()).set_message_type(MessageType::Response).set_op_code(query.op_code())
+                .set_recursion_desired(query.recursion_desired()).set_recursion_available(true)
+                .add_queries(query.queries().to_vec());
+            for q in query.queries() {
+                if q.query_type() != RecordType::SRV || q.name().to_string().trim_end_matches('.') != name {
+                    reply.set_response_code(ResponseCode::NXDomain);
+                    continue;
+                }
+                counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                for &(priority, port) in &targets {
+                    let srv = SRV::new(priority, 10, port, Name::from_ascii("localhost.").unwrap());
+                    reply.add_answer(Record::from_rdata(q.name().clone(), 60, RData::SRV(srv)));
+                }
+            }
+            let _ = socket.send_to(&reply.to_bytes().unwrap(), from).await;
+        }
+    });
+    (addr, queries)
+}
+
+#[tokio::test]
+async fn test_srv_discovery_backend() {
+    let primary = Backend::tagged("PRIMARY").await;
+    let backup = Backend::tagged("BACKUP").await;
+    let (dns, queries) = run_srv_server("_http._tcp.web.test", vec![(10, primary.port()), (20, backup.port())]).await;
+    let config = ProxyConfig { srv_nameserver: Some(dns), ..ProxyConfig::default() };
+    let proxy = TestProxy::builder().config(config).start().await;
+    proxy.db.add_mapping("web.test", "", 0, "", Some("srv://_http._tcp.web.test"), None, None, None, None).unwrap();
+
+    // The lower-priority-number target takes the traffic; the answer is cached for its TTL
+    let client = proxy.client("web.test");
+    for _ in 0..5 {
+        let body = client.get(proxy.http_url("web.test", "/x")).send().await.unwrap().text().await.unwrap();
+        assert!(body.starts_with("PRIMARY|path=/x"), "{}", body);
+    }
+    assert_eq!(queries.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+// ── Blue/green tests ──────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_blue_green_switch_and_rollback() {
+    let dir = tempdir().unwrap();
+    let blue = Backend::tagged("BLUE").await;
+    let blue_port = blue.port();
+    let green_port = free_port(); // nothing running: green is broken
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", blue_port, "", None, None, None, None, None).unwrap();
+    db.set_green_target(&m.id, None, green_port).unwrap();
+    db.switch_slot(&m.id, "green", Some(Duration::from_secs(60))).unwrap();
+    drop(db);
+
+    let config = ProxyConfig {
+        http_port: 0,
+        rollback_min_requests: 3,
+        ..ProxyConfig::default()
+    };
+    let proxy = Arc::new(ProxyServer::new(
+        config,
+        Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap()),
+        Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+    ));
+    let proxy_port = serve(proxy).await;
+
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        let resp = client.get(format!("http://127.0.0.1:{}/test", proxy_port))
+            .header("Host", "localhost").send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 502);
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    // Error spike during probation rolled traffic back to blue
+    let body = client.get(format!("http://127.0.0.1:{}/test", proxy_port))
+        .header("Host", "localhost").send().await.unwrap().text().await.unwrap();
+    assert!(body.contains("BLUE"), "expected rollback to blue, got: {}", body);
+}
+
+// ── A/B experiment tests ──────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_experiment_sticky_assignment() {
+    let dir = tempdir().unwrap();
+    let c = Backend::tagged("CONTROL").await;
+    let control_port = c.port();
+    let v = Backend::tagged("V2").await;
+    let v2_port = v.port();
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", control_port, "", None, None, None, None, None).unwrap();
+    db.set_experiment(&m.id, Some(&format!(
+        r#"{{"name":"exp","key":"header:X-User","variants":[{{"name":"control"}},{{"name":"v2","port":{}}}]}}"#,
+        v2_port,
+    ))).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let mut seen = std::collections::HashSet::new();
+    for i in 0..20 {
+        let user = format!("user{}", i);
+        let mut first: Option<String> = None;
+        for _ in 0..3 {
+            let resp = client.get(format!("http://127.0.0.1:{}/test", proxy_port))
+                .header("Host", "localhost").header("X-User", &user).send().await.unwrap();
+            let tag = resp.headers().get("x-experiment").unwrap().to_str().unwrap().to_string();
+            let body = resp.text().await.unwrap();
+            let expected = if tag == "exp=v2" { "V2" } else { "CONTROL" };
+            assert!(body.contains(expected), "{} served by wrong backend: {}", tag, body);
+            assert_eq!(first.get_or_insert_with(|| tag.clone()), &tag, "assignment not sticky for {}", user);
+        }
+        seen.insert(first.unwrap());
+    }
+    assert_eq!(seen.len(), 2, "both variants should get traffic: {:?}", seen);
+}
+
+// ── Body rule tests ───────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_body_rules_reject_at_edge() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("UPLOAD").await;
+    let backend_port = backend.port();
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_body_limits(&m.id, Some("application/json"), Some(16)).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/upload", proxy_port);
+    let post = |ct: &'static str, body: &'static str| client.post(&url)
+        .header("Host", "localhost").header("Content-Type", ct).body(body).send();
+
+    assert_eq!(post("application/json", "{}").await.unwrap().status().as_u16(), 200);
+    assert_eq!(post("text/plain", "hi").await.unwrap().status().as_u16(), 415);
+    assert_eq!(post("application/json", "[1,2,3,4,5,6,7,8,9]").await.unwrap().status().as_u16(), 413);
+
+    // GET without a body is unaffected
+    let resp = client.get(&url).header("Host", "localhost").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn test_body_rewrites_applied_to_html_only() {
+    let dir = tempdir().unwrap();
+    // Serves its own address in HTML at / and in a binary type at /blob, gzipped when allowed
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| async move {
+                        use std::io::Write;
+                        let accepted = req.headers().get("accept-encoding").map(|v| v.to_str().unwrap().to_string());
+                        let ct = if req.uri().path() == "/blob" { "application/octet-stream" } else { "text/html; charset=utf-8" };
+                        let body = format!("<a href=\"http://127.0.0.1:{}/next\">accepted={}</a>",
+                            backend_port, accepted.as_deref().unwrap_or("none").replace(' ', ""));
+                        let mut resp = Response::builder().header("Content-Type", ct);
+                        let body = match accepted.as_deref() {
+                            Some(a) if a.contains("gzip") => {
+                                resp = resp.header("Content-Encoding", "gzip");
+                                let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                                gz.write_all(body.as_bytes()).unwrap();
+                                Bytes::from(gz.finish().unwrap())
+                            }
+                            _ => Bytes::from(body),
+                        };
+                        Ok::<_, Infallible>(resp.body(Full::new(body)).unwrap())
+                    }))
+                    .await;
+            });
+        }
+    });
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "", backend_port, "", None, None, None, None, None).unwrap();
+    let rules = format!(r#"[{{"find": "http://127.0.0.1:{}", "replace": "https://www.example.com"}},
+                           {{"regex": "accepted=(\\S+)<", "replace": "backend-got=$1<"}}]"#, backend_port);
+    db.set_body_rewrites(&m.id, Some(&rules)).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let get = |path: &str, accept: &'static str| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path))
+        .header("Host", "localhost").header("Accept-Encoding", accept).send();
+
+    // The gzipped page is decoded, rewritten and gzipped again; zstd is not offered upstream
+    let resp = get("/", "zstd, gzip").await.unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    let body = resp.bytes().await.unwrap();
+    let text = rustproxy::content_coding::Coding::Gzip.decode(&body, 1024).unwrap();
+    assert_eq!(String::from_utf8(text).unwrap(), r#"<a href="https://www.example.com/next">backend-got=gzip</a>"#);
+
+    let resp = get("/", "identity").await.unwrap();
+    let expected = r#"<a href="https://www.example.com/next">backend-got=none</a>"#;
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.content_length(), Some(expected.len() as u64));
+    assert_eq!(resp.text().await.unwrap(), expected);
+
+    let body = get("/blob", "identity").await.unwrap().text().await.unwrap();
+    assert!(body.contains(&format!("http://127.0.0.1:{}/next", backend_port)), "{}", body);
+}
+
+#[tokio::test]
+async fn test_html_base_links_keep_sub_path_app_under_prefix() {
+    let dir = tempdir().unwrap();
+    // An app written for "/": root-relative links and redirects
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                        let resp = if req.uri().path() == "/old" {
+                            Response::builder().status(302).header("Location", "/login")
+                        } else {
+                            Response::builder().header("Content-Type", "text/html")
+                        };
+                        let html = r#"<html><head></head><a href="/login"><script src="/app.js"></script></html>"#;
+                        Ok::<_, Infallible>(resp.body(Full::new(Bytes::from(html))).unwrap())
+                    }))
+                    .await;
+            });
+        }
+    });
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    let m = db.add_mapping("localhost", "app", backend_port, "", None, None, None, None, None).unwrap();
+    db.set_html_base(&m.id, Some("links")).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    let get = |path: &str| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path)).header("Host", "localhost").send();
+
+    let body = get("/app/").await.unwrap().text().await.unwrap();
+    assert_eq!(body, r#"<html><head></head><a href="/app/login"><script src="/app/app.js"></script></html>"#);
+
+    let resp = get("/app/old").await.unwrap();
+    assert_eq!(resp.status().as_u16(), 302);
+    assert_eq!(resp.headers()["location"], "/app/login");
+}
+
+// ── Path normalization tests ──────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_path_normalized_before_matching() {
+    let dir = tempdir().unwrap();
+    let public = Backend::tagged("PUBLIC").await;
+    let public_port = public.port();
+    let admin = Backend::tagged("ADMIN").await;
+    let admin_port = admin.port();
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "localhost", "", public_port, "");
+    db.add_mapping("localhost", "admin", admin_port, "admin", None, None, Some("10.9.9.9"), None, None).unwrap();
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    // Raw requests: HTTP clients would normalize these paths themselves
+    async fn raw_status(port: u16, path: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes())
+            .await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or("").to_string()
+    }
+
+    for path in ["/public/../admin/users", "//admin/users", "/%61dmin/users", "/./admin/users", "/%2e/admin"] {
+        let status = raw_status(proxy_port, path).await;
+        assert!(status.contains("403"), "{} bypassed the admin allowlist: {}", path, status);
+    }
+    let status = raw_status(proxy_port, "/a/%2e%2e/%2e%2e/etc/passwd").await;
+    assert!(status.contains("400"), "traversal not rejected: {}", status);
+
+    let client = reqwest::Client::new();
+    let body = client.get(format!("http://127.0.0.1:{}/docs//intro", proxy_port))
+        .header("Host", "localhost").send().await.unwrap().text().await.unwrap();
+    assert!(body.contains("path=/docs/intro"), "got: {}", body);
+}
+
+#[tokio::test]
+async fn test_trailing_slash_redirect() {
+    let dir = tempdir().unwrap();
+
+    let config = ProxyConfig {
+        http_port: 0,
+        path_normalization: rustproxy::normalize::PathNormalization {
+            trailing_slash: rustproxy::normalize::TrailingSlash::Add,
+            ..Default::default()
+        },
+        ..ProxyConfig::default()
+    };
+    let proxy = Arc::new(ProxyServer::new(
+        config,
+        Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap()),
+        Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+    ));
+    let proxy_port = serve(proxy).await;
+
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    let resp = client.post(format!("http://127.0.0.1:{}/docs?x=1", proxy_port))
+        .header("Host", "localhost").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 308);
+    assert_eq!(resp.headers()["location"], "/docs/?x=1");
+}
+
+// ── Host parsing tests ────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_ipv6_and_mixed_case_hosts() {
+    let dir = tempdir().unwrap();
+    let v6 = Backend::tagged("V6").await;
+    let v6_port = v6.port();
+    let name = Backend::tagged("NAME").await;
+    let name_port = name.port();
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "[::1]", "", v6_port, "");
+    add(&db, "app.example.com", "", name_port, "");
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let client = reqwest::Client::new();
+    let get = |host: &'static str| client.get(format!("http://127.0.0.1:{}/", proxy_port)).header("Host", host).send();
+
+    assert!(get("[::1]:8080").await.unwrap().text().await.unwrap().contains("V6"));
+    assert!(get("APP.Example.com.:8080").await.unwrap().text().await.unwrap().contains("NAME"));
+    assert_eq!(get("[::1:8080").await.unwrap().status().as_u16(), 400);
+}
+
+// ── WebSocket / draining tests ────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_websocket_session_drains_after_route_removal() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let backend = Backend::websocket_echo().await;
+    let config = ProxyConfig { drain_timeout: Duration::from_secs(2), ..ProxyConfig::default() };
+    let proxy = TestProxy::builder().config(config).start().await;
+    proxy.map("localhost", "", backend.port(), "");
+
+    let mut ws = proxy.websocket("localhost", "/ws").await;
+    ws.send(Message::text("hello")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("hello"));
+
+    // Route removed: the open session keeps working during the drain period...
+    proxy.db.delete_mapping("localhost", None).unwrap();
+    sleep(Duration::from_millis(1200)).await;
+    ws.send(Message::text("still here")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::text("still here"));
+
+    // ...new requests already see the removal...
+    let resp = proxy.client("localhost").get(proxy.http_url("localhost", "/")).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+
+    // ...and the session is cut once the drain timeout has passed
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match ws.next().await {
+                None | Some(Err(_)) | Some(Ok(Message::Close(_))) => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    }).await;
+    assert!(closed.is_ok(), "session was not closed after the drain timeout");
+}
+
+#[tokio::test]
+async fn test_websocket_over_https() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let backend = Backend::websocket_echo().await;
+    let proxy = TestProxy::builder().https("secure.test").start().await;
+    proxy.map("secure.test", "live", backend.port(), "");
+
+    let mut ws = proxy.websocket_tls("secure.test", "/live/socket").await;
+    for text in ["hello", "over tls"] {
+        ws.send(Message::text(text)).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::text(text));
+    }
+    ws.close(None).await.unwrap();
+}
+
+// ── HTTPS / certificate reload tests ──────────────────────────────────────────
+
+#[tokio::test]
+async fn test_https_end_to_end() {
+    let backend = Backend::echo().await;
+    let proxy = TestProxy::builder().https("secure.test").start().await;
+    proxy.map("secure.test", "api", backend.port(), "v1");
+
+    let resp = proxy.client("secure.test").post(proxy.https_url("secure.test", "/api/orders?id=7")).body("{}").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let seen: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!((seen["method"].as_str(), seen["path"].as_str(), seen["query"].as_str()), (Some("POST"), Some("/v1/orders"), Some("id=7")));
+    let headers: Vec<&str> = seen["headers"].as_array().unwrap().iter().filter_map(|h| h.as_str()).collect();
+    assert!(headers.contains(&"x-forwarded-proto: https"), "{:?}", headers);
+
+    // The same mapping over plain HTTP
+    let resp = proxy.client("secure.test").get(proxy.http_url("secure.test", "/api/")).send().await.unwrap();
+    assert!(resp.text().await.unwrap().contains("\"x-forwarded-proto: http\""));
+}
+
+#[tokio::test]
+async fn test_force_https_redirect_uses_https_port() {
+    let dir = tempdir().unwrap();
+    let config = ProxyConfig {
+        http_port: 0,
+        https_port: 8443,
+        force_https: true,
+        ..ProxyConfig::default()
+    };
+    let proxy = Arc::new(ProxyServer::new(
+        config,
+        Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap()),
+        Arc::new(CertificateManager::new(dir.path().join("certs"), None).unwrap()),
+    ));
+    let proxy_port = serve(proxy).await;
+
+    let client = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    let url = format!("http://127.0.0.1:{}/login?next=%2Fhome", proxy_port);
+
+    let resp = client.get(&url).header("Host", "App.example.com:8080").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 301);
+    assert_eq!(resp.headers()["location"], "https://App.example.com:8443/login?next=%2Fhome");
+
+    let resp = client.post(&url).header("Host", "app.example.com").body("x=1").send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 308);
+    assert_eq!(resp.headers()["location"], "https://app.example.com:8443/login?next=%2Fhome");
+
+    // Already HTTPS behind a TLS terminator: no redirect
+    let resp = client.get(&url).header("Host", "app.example.com").header("X-Forwarded-Proto", "https")
+        .send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+}
+
+/// GET / over TLS for `name`, trusting only the certificate in `cert_pem`.
+async fn tls_get(port: u16, name: &str, cert_pem: &[u8]) -> std::io::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut &cert_pem[..]) {
+        roots.add(cert?).unwrap();
+    }
+    let config = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let server_name = rustls::pki_types::ServerName::try_from(name.to_string()).unwrap();
+    let mut tls = connector.connect(server_name, tcp).await?;
+    tls.write_all(format!("GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", name).as_bytes()).await?;
+    let mut out = String::new();
+    tls.read_to_string(&mut out).await?;
+    Ok(out)
+}
+
+#[tokio::test]
+async fn test_https_serves_renewed_certificate_without_restart() {
+    let backend = Backend::tagged("tls").await;
+    let config = ProxyConfig { cert_reload_interval: Duration::from_millis(100), ..ProxyConfig::default() };
+    let proxy = TestProxy::builder().config(config).https("secure.test").start().await;
+    proxy.map("secure.test", "", backend.port(), "");
+    let certs = proxy.dir().join("certs");
+    let first = std::fs::read(certs.join("secure.test.crt")).unwrap();
+    let https_port = proxy.https_addr().port();
+
+    let resp = tls_get(https_port, "secure.test", &first).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.contains("tls|path=/"), "{}", resp);
+
+    // Renewed on disk by "external tooling"
+    proxy.certs.generate_self_signed("secure.test", &["secure.test"]).unwrap();
+    let later = std::time::SystemTime::now() + Duration::from_secs(5);
+    std::fs::File::options().write(true).open(certs.join("secure.test.crt")).unwrap().set_modified(later).unwrap();
+    let renewed = std::fs::read(certs.join("secure.test.crt")).unwrap();
+    sleep(Duration::from_millis(400)).await;
+
+    assert!(tls_get(https_port, "secure.test", &first).await.is_err());
+    let resp = tls_get(https_port, "secure.test", &renewed).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+}
+
+#[tokio::test]
+async fn test_tls_details_forwarded_with_client_certificate() {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempdir().unwrap();
+    let certs = dir.path().join("certs");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(|req: Request<Incoming>| async move {
+                let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+                let body = format!("version={} sni={} dn={}\n{}", header("x-tls-version"), header("x-tls-sni"), header("x-client-cert-dn"), header("x-forwarded-client-cert"));
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body))))
+            })));
+        }
+    });
+
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "Test Client CA");
+    let ca = Certificate::from_params(ca_params).unwrap();
+    std::fs::write(dir.path().join("client-ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    let mut client_params = CertificateParams::new(vec![]);
+    client_params.distinguished_name = rcgen::DistinguishedName::new();
+    client_params.distinguished_name.push(DnType::OrganizationName, "Example");
+    client_params.distinguished_name.push(DnType::CommonName, "alice");
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client = Certificate::from_params(client_params).unwrap();
+    let client_der = client.serialize_der_with_signer(&ca).unwrap();
+    let client_key = client.serialize_private_key_der();
+    let client_hash: String = ring::digest::digest(&ring::digest::SHA256, &client_der).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+
+    let db = Arc::new(DatabaseManager::new(dir.path().join("test.db")).unwrap());
+    add(&db, "secure.test", "", backend_port, "");
+    let cert_manager = Arc::new(CertificateManager::new(&certs, None).unwrap());
+    cert_manager.generate_self_signed("secure.test", &["secure.test"]).unwrap();
+    let server_pem = std::fs::read(certs.join("secure.test.crt")).unwrap();
+    let config = ProxyConfig {
+        http_port: 0,
+        https_port: 0,
+        enable_https: true,
+        http_host: "127.0.0.1".to_string(),
+        tls_client_ca: Some(dir.path().join("client-ca.pem")),
+        forward_tls_headers: true,
+        forward_client_cert: Some("hash,subject".parse().unwrap()),
+        ..ProxyConfig::default()
+    };
+    let handle = Arc::new(ProxyServer::new(config, db, cert_manager)).run().await.unwrap();
+    let https_port = handle.https_addr().unwrap().port();
+
+    let get = |identity: Option<(Vec<u8>, Vec<u8>)>| {
+        let server_pem = server_pem.clone();
+        async move {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut &server_pem[..]) {
+                roots.add(cert.unwrap()).unwrap();
+            }
+            let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+            let config = match identity {
+                Some((cert, key)) => builder.with_client_auth_cert(
+                    vec![rustls::pki_types::CertificateDer::from(cert)],
+                    rustls::pki_types::PrivateKeyDer::Pkcs8(key.into()),
+                ).unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            let tcp = tokio::net::TcpStream::connect(("127.0.0.1", https_port)).await.unwrap();
+            let name = rustls::pki_types::ServerName::try_from("secure.test").unwrap();
+            let mut tls = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, tcp).await.unwrap();
+            tls.write_all(b"GET / HTTP/1.1\r\nHost: secure.test\r\nX-Client-Cert-DN: CN=admin\r\nX-Forwarded-Client-Cert: Hash=forged\r\nConnection: close\r\n\r\n").await.unwrap();
+            let mut out = String::new();
+            let _ = tls.read_to_string(&mut out).await;
+            out
+        }
+    };
+
+    let resp = get(Some((client_der, client_key))).await;
+    let xfcc = format!("Hash={};Subject=\"CN=alice,O=Example\"", client_hash);
+    assert!(resp.ends_with(&format!("version=TLSv1.3 sni=secure.test dn=CN=alice,O=Example\n{}", xfcc)), "{}", resp);
+    // No certificate is fine too; a DN or XFCC the client wrote itself doesn't get through
+    let resp = get(None).await;
+    assert!(resp.ends_with("version=TLSv1.3 sni=secure.test dn=-\n-"), "{}", resp);
+}
+
+#[tokio::test]
+async fn test_replay_access_log() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("replayed").await;
+    let backend_port = backend.port();
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    db.add_mapping("replay.test", "", backend_port, "", None, None, None, None, None).unwrap();
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+
+    let log = "\
+2026-10-16T12:00:00.000000Z  INFO ThreadId(02) access method=GET host=replay.test path=/a status=200 duration_ms=2 client=10.0.0.1 mapping=m experiment=- api_key=-
+2026-10-16T12:00:00.100000Z  INFO ThreadId(02) Proxying request
+2026-10-16T12:00:00.200000Z  INFO ThreadId(03) access method=GET host=replay.test path=\"/b c\" status=404 duration_ms=1 client=10.0.0.2 mapping=m experiment=- api_key=-
+2026-10-16T12:00:00.300000Z  INFO ThreadId(03) access method=POST host=replay.test path=/c status=201 duration_ms=1 client=10.0.0.2 mapping=m experiment=- api_key=-
+";
+    let entries = rustproxy::replay::parse(log);
+    assert_eq!(entries.len(), 3);
+    let config = rustproxy::replay::ReplayConfig {
+        target: format!("http://127.0.0.1:{}", proxy_port).parse().unwrap(),
+        host: None,
+        speed: 2.0,
+        concurrency: 4,
+        all_methods: false,
+    };
+    let report = rustproxy::replay::run(config, entries).await.unwrap();
+    assert_eq!(report.load.requests(), 2);
+    assert_eq!(report.load.errors, 0);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(report.matched, 1);
+    // The backend answers 200 where the log had a 404
+    assert_eq!(report.mismatched.get(&(404, 200)), Some(&1));
+    // The 200ms between the two GETs, halved
+    assert!(report.load.elapsed >= Duration::from_millis(90), "{:?}", report.load.elapsed);
+}
+
+#[tokio::test]
+async fn test_compressed_request_bodies() {
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let dir = tempdir().unwrap();
+    // Reports how the body arrived; Host strict.test refuses encoded bodies with 415
+    let refused = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let counter = refused.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service_fn(move |req: Request<Incoming>| {
+                        let counter = counter.clone();
+                        async move {
+                            use http_body_util::BodyExt;
+                            let strict = req.headers()["host"] == "strict.test";
+                            let encoding = req.headers().get("content-encoding").map(|v| v.to_str().unwrap().to_string());
+                            let body = req.into_body().collect().await.unwrap().to_bytes();
+                            if strict && encoding.is_some() {
+                                counter.fetch_add(1, Ordering::SeqCst);
+                                return Ok::<_, Infallible>(Response::builder().status(415).body(Full::new(Bytes::new())).unwrap());
+                            }
+                            let mut plain = String::new();
+                            match encoding.as_deref() {
+                                Some("gzip") => { flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut plain).unwrap(); }
+                                _ => plain = String::from_utf8(body.to_vec()).unwrap(),
+                            }
+                            let reply = format!("{}|{}|{}", encoding.as_deref().unwrap_or("none"), body.len(), plain.len());
+                            Ok(Response::new(Full::new(Bytes::from(reply))))
+                        }
+                    }))
+                    .await;
+            });
+        }
+    });
+
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    for domain in ["gz.test", "strict.test"] {
+        let m = db.add_mapping(domain, "", backend_port, "", None, None, None, None, None).unwrap();
+        db.set_compress_requests(&m.id, Some(1024)).unwrap();
+    }
+    drop(db);
+    let proxy_port = start_proxy(&dir.path().join("test.db"), &dir.path().join("certs")).await;
+    let client = reqwest::Client::new();
+    let post = |host: &str, body: String| client.post(format!("http://127.0.0.1:{}/upload", proxy_port))
+        .header("Host", host)
+        .header("Content-Type", "application/json")
+        .body(body);
+    let large = "{\"value\": 12345}".repeat(200);
+
+    let reply = post("gz.test", large.clone()).send().await.unwrap().text().await.unwrap();
+    let parts: Vec<&str> = reply.split('|').collect();
+    assert_eq!(parts[0], "gzip");
+    assert!(parts[1].parse::<usize>().unwrap() < large.len() / 4, "{}", reply);
+    assert_eq!(parts[2], large.len().to_string());
+    // Below the threshold: as received
+    let reply = post("gz.test", "{}".to_string()).send().await.unwrap().text().await.unwrap();
+    assert_eq!(reply, "none|2|2");
+
+    // A 415 is answered by resending the body plain, and later bodies go plain right away
+    for _ in 0..2 {
+        let resp = post("strict.test", large.clone()).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.text().await.unwrap(), format!("none|{0}|{0}", large.len()));
+    }
+    assert_eq!(refused.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_generated_etags() {
+    let dir = tempdir().unwrap();
+    let backend = Backend::tagged("static").await;
+    let backend_port = backend.port();
+    let db = DatabaseManager::new(dir.path().join("test.db")).unwrap();
+    add(&db, "localhost", "", backend_port, "");
+    drop(db);
+    let proxy = ProxyBuilder::new()
+        .db_path(dir.path().join("test.db"))
+        .certs_dir(dir.path().join("certs"))
+        .http_port(0)
+        .http_host("127.0.0.1")
+        .generate_etags(true)
+        .build()
+        .unwrap();
+    let proxy_port = serve(Arc::new(proxy)).await;
+    let client = reqwest::Client::new();
+    let get = |path: &str| client.get(format!("http://127.0.0.1:{}{}", proxy_port, path)).header("Host", "localhost");
+
+    let resp = get("/logo.svg").send().await.unwrap();
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    assert!(etag.starts_with("W/\""), "{}", etag);
+    assert!(resp.text().await.unwrap().starts_with("static|path=/logo.svg"));
+
+    let resp = get("/logo.svg").header("If-None-Match", &etag).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 304);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    assert!(resp.bytes().await.unwrap().is_empty());
+
+    // Another body, another tag
+    let resp = get("/other.svg").header("If-None-Match", &etag).send().await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+}